@@ -0,0 +1,182 @@
+//! Writing the helper's answers to git.
+//!
+//! git blocks reading an answer until its last line, so an answer left in a buffer stalls it,
+//! which users see as a push hanging at 100%. Every answer goes through `Answers`, which keeps
+//! what is written until `flush`, then writes it in one go, flushes the file descriptor and tees
+//! it into the transcript. The protocol handler flushes once per command, when the command's
+//! response is complete.
+//!
+//! On unix, `Answers::stdout` keeps the pipe to git under a descriptor of its own and points
+//! stdout elsewhere, so nothing else can write into the protocol: at stderr in release builds,
+//! and in debug builds at a pipe that aborts the helper once anything is written to it.
+
+use crate::transcript;
+use std::io::{self, Write};
+
+pub struct Answers<W: Write> {
+    out: W,
+    /// What was written since the last flush
+    pending: Vec<u8>,
+}
+
+impl<W: Write> Answers<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: vec![],
+        }
+    }
+
+    /// How many bytes were written since the last flush.
+    pub fn unflushed(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Answers<Box<dyn Write>> {
+    /// Answers on stdout, which nothing else may write to from now on.
+    pub fn stdout() -> io::Result<Self> {
+        #[cfg(unix)]
+        let out: Box<dyn Write> = Box::new(take_stdout()?);
+        #[cfg(not(unix))]
+        let out: Box<dyn Write> = Box::new(io::stdout());
+
+        Ok(Self::new(out))
+    }
+}
+
+impl<W: Write> Write for Answers<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Send git the response written since the last flush.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.out.write_all(&self.pending)?;
+        self.out.flush()?;
+        transcript::output(&self.pending);
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Answers<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// The pipe to git, under a descriptor of its own, with stdout pointed elsewhere.
+#[cfg(unix)]
+fn take_stdout() -> io::Result<std::fs::File> {
+    use std::os::unix::io::FromRawFd;
+
+    io::stdout().flush()?;
+    let protocol = unsafe { libc::dup(1) };
+    if protocol == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let protocol = unsafe { std::fs::File::from_raw_fd(protocol) };
+
+    redirect_stray()?;
+    Ok(protocol)
+}
+
+/// Send stray writes to stdout to stderr, where they can't corrupt an answer.
+#[cfg(all(unix, not(debug_assertions)))]
+fn redirect_stray() -> io::Result<()> {
+    match unsafe { libc::dup2(2, 1) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Abort on the first stray write to stdout, so whatever made it is found during development.
+#[cfg(all(unix, debug_assertions))]
+fn redirect_stray() -> io::Result<()> {
+    use std::{io::Read, os::unix::io::FromRawFd};
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = unsafe { libc::dup2(fds[1], 1) };
+    unsafe { libc::close(fds[1]) };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut stray = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+    std::thread::spawn(move || {
+        let mut buf = [0; 256];
+        match stray.read(&mut buf) {
+            Ok(read) if read > 0 => {
+                eprintln!(
+                    "Internal error: {:?} was written to stdout past the protocol writer",
+                    String::from_utf8_lossy(&buf[..read])
+                );
+                std::process::abort();
+            }
+            _ => {}
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps every write and counts the flushes.
+    #[derive(Default)]
+    struct Pipe {
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn responses_are_sent_whole_on_flush() {
+        let mut pipe = Pipe::default();
+        let mut answers = Answers::new(&mut pipe);
+
+        writeln!(answers, "ok refs/heads/main").unwrap();
+        writeln!(answers).unwrap();
+        assert_eq!(answers.unflushed(), 20);
+
+        answers.flush().unwrap();
+        answers.flush().unwrap();
+        assert_eq!(answers.unflushed(), 0);
+        drop(answers);
+
+        assert_eq!(pipe.writes, [b"ok refs/heads/main\n\n".to_vec()]);
+        assert_eq!(pipe.flushes, 1);
+    }
+
+    #[test]
+    fn unflushed_answers_are_sent_when_dropped() {
+        let mut pipe = Pipe::default();
+        writeln!(Answers::new(&mut pipe), "unsupported").unwrap();
+
+        assert_eq!(pipe.writes, [b"unsupported\n".to_vec()]);
+        assert_eq!(pipe.flushes, 1);
+    }
+}
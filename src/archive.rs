@@ -0,0 +1,427 @@
+//! Export a single snapshot of an on-chain repository without cloning its history.
+
+use crate::{
+    error, output,
+    primitives::{
+        BoxResult, GitObject, GitObjectMetadata, MultiObject, MultiObjectIndex, ObjectKind,
+        RepoData, SUBMODULE_TIP_MARKER,
+    },
+    store::ObjectStore,
+    traversal::{Limits, Walk},
+};
+use cid::Cid;
+use git2::Oid;
+use std::{collections::BTreeMap, error::Error, fs::File, io::Write, path::Path, str::FromStr};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const MODE_TREE: u32 = 0o040000;
+const MODE_EXECUTABLE: u32 = 0o100755;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_SUBMODULE: u32 = 0o160000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the format from an output file name, defaulting to tar.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("zip") => Self::Zip,
+            _ => Self::Tar,
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            other => Err(format!("Unknown archive format {:?}, expected tar or zip", other).into()),
+        }
+    }
+}
+
+/// One entry of a raw git tree object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub mode: u32,
+    pub name: String,
+    pub git_hash: String,
+}
+
+/// Parse the raw form of a tree: a sequence of `<octal mode> <name>\0<20 byte sha1>`.
+pub fn parse_tree(mut data: &[u8]) -> BoxResult<Vec<TreeEntry>> {
+    let mut entries = vec![];
+
+    while !data.is_empty() {
+        let space = data.iter().position(|&b| b == b' ');
+        let nul = data.iter().position(|&b| b == 0);
+
+        let (space, nul) = match (space, nul) {
+            (Some(space), Some(nul)) if space < nul && data.len() >= nul + 21 => (space, nul),
+            _ => error!("Malformed tree entry"),
+        };
+
+        entries.push(TreeEntry {
+            mode: u32::from_str_radix(std::str::from_utf8(&data[..space])?, 8)?,
+            name: String::from_utf8(data[space + 1..nul].to_vec())?,
+            git_hash: hex::encode(&data[nul + 1..nul + 21]),
+        });
+
+        data = &data[nul + 21..];
+    }
+
+    Ok(entries)
+}
+
+/// The committer timestamp of a raw commit, which `git archive` uses as every entry's mtime.
+fn commit_time(data: &[u8]) -> u64 {
+    String::from_utf8_lossy(data)
+        .lines()
+        .find_map(|line| line.strip_prefix("committer "))
+        .and_then(|committer| committer.rsplit(' ').nth(1))
+        .and_then(|timestamp| timestamp.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Loads single objects, downloading each MultiObject at most once.
+struct ObjectLoader<'a> {
+    repo_data: &'a RepoData,
+    store: &'a dyn ObjectStore,
+    index: &'a MultiObjectIndex<'a>,
+    multi_objects: BTreeMap<String, MultiObject>,
+}
+
+impl ObjectLoader<'_> {
+    async fn get(&mut self, git_hash: &str) -> BoxResult<GitObject> {
+        let multi_object_hash = self
+            .repo_data
+            .objects
+            .get(git_hash)
+            .filter(|hash| *hash != SUBMODULE_TIP_MARKER)
+            .ok_or_else(|| format!("Could not find object {} in the index", git_hash))?;
+
+        if let Some(blob) = self.repo_data.large_blobs.get(git_hash) {
+            let data = self
+                .store
+                .get(&Cid::try_from(blob.cid.as_slice())?.to_string())
+                .await?;
+
+            return Ok(GitObject {
+                git_hash: git_hash.to_owned(),
+                size: data.len() as u64,
+                data,
+                metadata: GitObjectMetadata::Blob,
+                kind: ObjectKind::Blob,
+            });
+        }
+
+        if !self.multi_objects.contains_key(multi_object_hash) {
+            let multi_object = MultiObject::get(
+                &self
+                    .repo_data
+                    .multi_object_cid(multi_object_hash, self.index)
+                    .await?,
+                self.store,
+            )
+            .await?;
+            self.multi_objects
+                .insert(multi_object_hash.clone(), multi_object);
+        }
+
+        Ok(self.multi_objects[multi_object_hash]
+            .objects
+            .get(git_hash)
+            .ok_or_else(|| format!("Object {} missing from its MultiObject", git_hash))?
+            .clone())
+    }
+}
+
+enum Entry {
+    Dir,
+    File { executable: bool, data: Vec<u8> },
+    Symlink(String),
+}
+
+/// Write the snapshot at `git_hash` (a commit, a tag of one, or a tree) to `output`, returning
+/// the number of archive entries. The trees are walked within `limits`.
+pub async fn export(
+    repo_data: &RepoData,
+    git_hash: &str,
+    store: &dyn ObjectStore,
+    index: &MultiObjectIndex<'_>,
+    format: ArchiveFormat,
+    output: &Path,
+    limits: Limits,
+) -> BoxResult<usize> {
+    let mut loader = ObjectLoader {
+        repo_data,
+        store,
+        index,
+        multi_objects: BTreeMap::new(),
+    };
+
+    // Peel tags down to the root tree, never touching parent commits
+    let mut walk = Walk::new(limits, "export");
+    let mut git_hash = git_hash.to_owned();
+    let mut mtime = 0;
+    let root = loop {
+        walk.expand(Oid::from_str(&git_hash)?)?;
+        let object = loader.get(&git_hash).await?;
+
+        match object.metadata {
+            GitObjectMetadata::Tag { target_git_hash } => git_hash = target_git_hash,
+            GitObjectMetadata::Commit { tree_git_hash, .. } => {
+                mtime = commit_time(&object.data);
+                git_hash = tree_git_hash;
+            }
+            GitObjectMetadata::Tree { .. } => break object,
+            GitObjectMetadata::Blob => error!(format!("{} does not point at a tree", git_hash)),
+        }
+    };
+
+    let mut entries = vec![];
+    for entry in parse_tree(&root.data)?.into_iter().rev() {
+        walk.push(Oid::from_str(&entry.git_hash)?, (String::new(), entry))?;
+    }
+
+    // Depth-first in tree order, so directories precede their contents like in `git archive`
+    while let Some((prefix, entry)) = walk.next() {
+        let path = format!("{}{}", prefix, entry.name);
+        output::status(format!("Exporting {}", path));
+
+        match entry.mode {
+            MODE_TREE => {
+                walk.expand(Oid::from_str(&entry.git_hash)?)?;
+                let tree = loader.get(&entry.git_hash).await?;
+                let dir = format!("{}/", path);
+
+                for child in parse_tree(&tree.data)?.into_iter().rev() {
+                    walk.push(Oid::from_str(&child.git_hash)?, (dir.clone(), child))?;
+                }
+
+                entries.push((dir, Entry::Dir));
+            }
+            MODE_SUBMODULE => {
+                output::warn(format!("Submodule {} exported as an empty directory", path));
+                entries.push((format!("{}/", path), Entry::Dir));
+            }
+            MODE_SYMLINK => {
+                let target = String::from_utf8(loader.get(&entry.git_hash).await?.data)?;
+                entries.push((path, Entry::Symlink(target)));
+            }
+            mode => {
+                let data = loader.get(&entry.git_hash).await?.data;
+                entries.push((
+                    path,
+                    Entry::File {
+                        executable: mode == MODE_EXECUTABLE,
+                        data,
+                    },
+                ));
+            }
+        }
+    }
+
+    let file = File::create(output)?;
+
+    match format {
+        ArchiveFormat::Tar => write_tar(file, &entries, mtime)?,
+        ArchiveFormat::Zip => write_zip(file, &entries)?,
+    }
+
+    output::clear_status();
+
+    Ok(entries.len())
+}
+
+fn write_tar(file: File, entries: &[(String, Entry)], mtime: u64) -> BoxResult<()> {
+    let mut builder = tar::Builder::new(file);
+
+    for (path, entry) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_size(0);
+
+        match entry {
+            Entry::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                builder.append_data(&mut header, path, std::io::empty())?;
+            }
+            Entry::File { executable, data } => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(if *executable { 0o755 } else { 0o644 });
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, path, data.as_slice())?;
+            }
+            Entry::Symlink(target) => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_mode(0o777);
+                builder.append_link(&mut header, path, target)?;
+            }
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+
+    Ok(())
+}
+
+fn write_zip(file: File, entries: &[(String, Entry)]) -> BoxResult<()> {
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for (path, entry) in entries {
+        match entry {
+            Entry::Dir => zip.add_directory(path, options)?,
+            Entry::File { executable, data } => {
+                zip.start_file(
+                    path,
+                    options.unix_permissions(if *executable { 0o755 } else { 0o644 }),
+                )?;
+                zip.write_all(data)?;
+            }
+            Entry::Symlink(target) => zip.add_symlink(path, target, options)?,
+        }
+    }
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::{tests::published, Config},
+        util::tests::{commit_tree, repository},
+    };
+    use git2::{FileMode, Repository};
+    use std::io::Read;
+    use temp_dir::TempDir;
+
+    /// A commit of a README, an executable script, a symlink to the README and a file in a
+    /// directory.
+    fn snapshot(repo: &Repository) -> Oid {
+        let blob = |data: &[u8]| repo.blob(data).unwrap();
+
+        let mut src = repo.treebuilder(None).unwrap();
+        src.insert("main.rs", blob(b"fn main() {}\n"), FileMode::Blob.into())
+            .unwrap();
+        let src = src.write().unwrap();
+
+        let mut root = repo.treebuilder(None).unwrap();
+        root.insert("README", blob(b"hello\n"), FileMode::Blob.into())
+            .unwrap();
+        root.insert("link", blob(b"README"), FileMode::Link.into())
+            .unwrap();
+        root.insert(
+            "run.sh",
+            blob(b"#!/bin/sh\n"),
+            FileMode::BlobExecutable.into(),
+        )
+        .unwrap();
+        root.insert("src", src, FileMode::Tree.into()).unwrap();
+        let root = root.write().unwrap();
+
+        commit_tree(repo, Some("refs/heads/main"), &[], root, "snapshot")
+    }
+
+    #[test]
+    fn format_comes_from_the_name_or_the_extension() {
+        assert_eq!("zip".parse::<ArchiveFormat>().unwrap(), ArchiveFormat::Zip);
+        assert_eq!("tar".parse::<ArchiveFormat>().unwrap(), ArchiveFormat::Tar);
+        assert!("rar".parse::<ArchiveFormat>().is_err());
+
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("out.zip")),
+            ArchiveFormat::Zip
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("out.tar.gz")),
+            ArchiveFormat::Tar
+        );
+    }
+
+    #[test]
+    fn raw_trees_are_parsed() {
+        let (_dir, repo) = repository();
+        let commit = snapshot(&repo);
+        let tree = repo.find_commit(commit).unwrap().tree_id();
+        let data = repo.odb().unwrap().read(tree).unwrap().data().to_vec();
+
+        let entries = parse_tree(&data).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["README", "link", "run.sh", "src"]);
+        assert_eq!(entries[2].mode, MODE_EXECUTABLE);
+        assert_eq!(entries[3].mode, MODE_TREE);
+
+        assert!(parse_tree(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn commit_time_is_the_committer_timestamp() {
+        let data = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author A <a@example.com> 1600000000 +0200\n\
+            committer C <c@example.com> 1700000000 -0500\n\
+            \n\
+            message\n";
+
+        assert_eq!(commit_time(data), 1_700_000_000);
+        assert_eq!(commit_time(b"not a commit"), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_exported_as_tar() {
+        let (_dir, repo) = repository();
+        let commit = snapshot(&repo);
+        let (repo_data, store, index) = published(&repo);
+
+        let out = TempDir::new().unwrap();
+        let path = out.path().join("snapshot.tar");
+        let count = export(
+            &repo_data,
+            &commit.to_string(),
+            &store,
+            &index,
+            ArchiveFormat::Tar,
+            &path,
+            Limits::from_config(&Config::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(count, 5);
+
+        let mut archive = tar::Archive::new(File::open(&path).unwrap());
+        let mut entries = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            entries.push((
+                entry.path().unwrap().to_string_lossy().into_owned(),
+                entry.header().mode().unwrap(),
+                data,
+            ));
+        }
+
+        assert_eq!(
+            entries,
+            [
+                ("README".to_owned(), 0o644, "hello\n".to_owned()),
+                ("link".to_owned(), 0o777, String::new()),
+                ("run.sh".to_owned(), 0o755, "#!/bin/sh\n".to_owned()),
+                ("src/".to_owned(), 0o755, String::new()),
+                ("src/main.rs".to_owned(), 0o644, "fn main() {}\n".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,578 @@
+use crate::{
+    credential::{Credential, Fill, Helpers},
+    error, output,
+    primitives::{BoxResult, IpsId},
+};
+use log::debug;
+use magic_crypt::{new_magic_crypt, MagicCryptError, MagicCryptTrait};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    io::{self, BufRead},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use subxt::ext::sp_core::{crypto::Ss58Codec, sr25519::Pair as Sr25519Pair, Pair};
+use tokio::process::Command;
+
+/// How long an askpass program may take to answer before we give up on it.
+const ASKPASS_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[cfg(target_family = "unix")]
+fn read_input() -> std::io::Result<String> {
+    let mut string = String::new();
+    let tty = std::fs::File::open("/dev/tty")?;
+    let mut reader = io::BufReader::new(tty);
+    reader.read_line(&mut string)?;
+    Ok(string.trim().to_string())
+}
+
+#[cfg(target_family = "windows")]
+fn read_input() -> std::io::Result<String> {
+    let mut string = String::new();
+    let handle = unsafe {
+        CreateFileA(
+            b"CONIN$\x00".as_ptr() as *const i8,
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null_mut(),
+            OPEN_EXISTING,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut stream = BufReader::new(unsafe { std::fs::File::from_raw_handle(handle) });
+
+    let reader_return = reader.read_line(&mut string);
+
+    // Newline for windows which otherwise prints on the same line.
+    // println!();
+
+    if reader_return.is_err() {
+        return Err(reader_return.unwrap_err());
+    }
+
+    Ok(string)
+}
+
+/// The development key helpers run by `inv4-git devnet bootstrap` sign with.
+#[cfg(feature = "devnet")]
+pub const DEVNET_SEED_VAR: &str = "GIT_INV4_DEVNET_SEED";
+
+/// The `auth_cache` config setting: how long an unlocked credential is kept in memory.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthCache {
+    /// Until the helper exits, so further pushes it makes don't ask again
+    Session,
+    /// Not at all, every push asks for the password
+    None,
+}
+
+/// How many `SigningScope`s are alive.
+static SIGNING_SCOPES: AtomicUsize = AtomicUsize::new(0);
+
+/// Lets `auth_flow` ask for credentials while it is alive.
+///
+/// Only `push_batch` and the subcommands that sign enter one, so clones, fetches and capability
+/// probes can never prompt read-only users for a seed phrase or reach the credential helper.
+pub struct SigningScope(());
+
+impl SigningScope {
+    pub fn enter() -> Self {
+        SIGNING_SCOPES.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for SigningScope {
+    fn drop(&mut self) {
+        SIGNING_SCOPES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An answer to a credential prompt, or an error cancelling the signing operation when it is
+/// empty, so hitting enter never leads to a credential being stored.
+fn entered(answer: String, what: &str) -> BoxResult<String> {
+    match answer.trim() {
+        "" => error!(format!("No {} entered, cancelled", what)),
+        answer => Ok(answer.to_owned()),
+    }
+}
+
+/// What a signature is for. It is shown before the password prompt and asked about before
+/// signing, as people keep separate accounts per project and a push signed with the wrong one
+/// is stuck as a multisig vote that is never approved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigningContext {
+    /// What gets signed, e.g. `push`
+    pub action: &'static str,
+    pub ips_id: IpsId,
+    /// The repository's name from its RepoMeta
+    pub repository: Option<String>,
+    /// The chain's name as its node reports it
+    pub chain: String,
+    /// Ask before signing, see `confirm_signing`
+    pub confirm: bool,
+}
+
+impl SigningContext {
+    /// The repository by name and IPS, or by IPS alone.
+    fn target(&self) -> String {
+        match &self.repository {
+            Some(name) => format!("{} (IPS {})", name, self.ips_id),
+            None => format!("IPS {}", self.ips_id),
+        }
+    }
+
+    /// The line shown before asking for the password of the credential `credential`.
+    pub fn banner(&self, credential: &str) -> String {
+        format!(
+            "Signing {} to {} on {} with credential {}",
+            self.action,
+            self.target(),
+            self.chain,
+            credential
+        )
+    }
+
+    /// The question asked before signing as the SS58 address `address`.
+    pub fn confirmation(&self, address: &str) -> String {
+        format!(
+            "Sign {} to {} on {} as {}? [y/N] ",
+            self.action,
+            self.target(),
+            self.chain,
+            address
+        )
+    }
+}
+
+/// How many times the user may retry a mistyped password before we give up.
+const MAX_PASSWORD_ATTEMPTS: usize = 3;
+
+const CORRUPTED: &str = "Stored credential is corrupted; run `git credential reject` for host inv4-tinkernet to re-enter your seed";
+
+/// A key `auth_flow` unlocked, and the stored credential it came from.
+#[derive(Clone)]
+pub struct Unlocked {
+    pub pair: Sr25519Pair,
+    /// `None` for development keys, which aren't stored
+    pub credential: Option<Credential>,
+}
+
+fn pair_from_seed(seed: &str) -> Option<Sr25519Pair> {
+    Sr25519Pair::from_string(seed.trim(), None).ok()
+}
+
+/// The credential stored for `account` (any stored one when `None`), asking the configured
+/// credential helpers only.
+pub fn stored_credential(account: Option<&str>) -> BoxResult<Option<Credential>> {
+    Ok(match Helpers::new("git").fill(account)? {
+        Fill::Found(credential) => Some(credential),
+        Fill::Missing | Fill::Quit => None,
+    })
+}
+
+/// Tell the credential helpers `credential` worked, which renews it with those that let
+/// credentials expire.
+pub fn renew(credential: &Credential) {
+    if let Err(e) = Helpers::new("git").approve(credential) {
+        debug!("Could not renew credential {}: {}", credential.username, e);
+    }
+}
+
+/// Offer to erase `credential` from the credential helpers as `problem` makes it look wrong,
+/// returning whether it was. Without a terminal or askpass program to ask, it is kept.
+pub async fn forget(credential: &Credential, problem: &str) -> BoxResult<bool> {
+    let prompt = format!(
+        "{}. Remove stored credential {} and enter the seed again? [y/N] ",
+        problem, credential.username
+    );
+
+    if ask_yes(&prompt).await? != Some(true) {
+        return Ok(false);
+    }
+
+    Helpers::new("git").reject(credential)?;
+    output::info(format!("Removed stored credential {}", credential.username));
+    Ok(true)
+}
+
+/// The signing key for `context`, from the stored credential for `account` (any stored one when
+/// `None`), or entered and then stored under that name. Unless `context` says otherwise, signing
+/// as its address has to be confirmed.
+///
+/// Fails without prompting when `GIT_INV4_READONLY=1` is set, or outside a `SigningScope`.
+pub async fn auth_flow(account: Option<&str>, context: &SigningContext) -> BoxResult<Unlocked> {
+    check_may_sign()?;
+
+    // Set by `inv4-git devnet bootstrap` for the helpers it runs. Development keys guard nothing,
+    // so they sign without asking
+    #[cfg(feature = "devnet")]
+    if let Ok(seed) = env::var(DEVNET_SEED_VAR) {
+        if !seed.starts_with("//") {
+            error!(format!(
+                "{} only takes development keys like //Alice",
+                DEVNET_SEED_VAR
+            ))
+        }
+
+        return Ok(Unlocked {
+            pair: pair_from_seed(&seed).ok_or_else(|| format!("Invalid {}", DEVNET_SEED_VAR))?,
+            credential: None,
+        });
+    }
+
+    let unlocked = unlock(account, context).await?;
+
+    if context.confirm {
+        confirm_signing(context, &unlocked.pair).await?;
+    }
+
+    Ok(unlocked)
+}
+
+/// The checks of `auth_flow` that don't prompt, for signing again with a key it unlocked earlier.
+pub fn check_may_sign() -> BoxResult<()> {
+    if env::var("GIT_INV4_READONLY").as_deref() == Ok("1") {
+        error!("GIT_INV4_READONLY is set, refusing to sign")
+    }
+
+    if SIGNING_SCOPES.load(Ordering::SeqCst) == 0 {
+        error!("Internal error: credentials were requested by an operation that doesn't sign; please report this as a bug")
+    }
+
+    Ok(())
+}
+
+/// Ask whether to sign for `context` as `pair`'s address.
+async fn confirm_signing(context: &SigningContext, pair: &Sr25519Pair) -> BoxResult<()> {
+    let prompt = context.confirmation(&pair.public().to_ss58check());
+
+    match ask_yes(&prompt).await? {
+        Some(true) => Ok(()),
+        Some(false) => error!("Signing not confirmed"),
+        None => error!(
+            "Can't ask to confirm signing without a terminal; set confirm_signing = false to sign without asking"
+        ),
+    }
+}
+
+/// Ask the yes/no question in `prompt` through askpass or on the terminal, `None` when neither
+/// is there to ask.
+async fn ask_yes(prompt: &str) -> BoxResult<Option<bool>> {
+    let answer = match askpass_program() {
+        Some(program) => askpass(&program, prompt).await?,
+        None if output::stderr_is_tty() => {
+            eprint!("{}", prompt);
+            read_input()?
+        }
+        None => return Ok(None),
+    };
+
+    Ok(Some(matches!(answer.trim(), "y" | "Y" | "yes")))
+}
+
+/// The key of the stored credential for `account`, or of one entered and stored now. A stored
+/// credential that turns out corrupted may be erased, and then the helpers' next candidate is
+/// tried before asking for a seed.
+async fn unlock(account: Option<&str>, context: &SigningContext) -> BoxResult<Unlocked> {
+    let helpers = Helpers::new("git");
+    let mut rejected = vec![];
+
+    loop {
+        let credential = match helpers.fill(account)? {
+            Fill::Found(credential) if !rejected.contains(&credential) => credential,
+            // A helper that ignores being told to erase keeps answering with the same one
+            Fill::Found(_) | Fill::Missing => break,
+            Fill::Quit => error!("A credential helper said to stop, not asking for a seed"),
+        };
+
+        match decrypt(&credential, context).await? {
+            Some(pair) => {
+                return Ok(Unlocked {
+                    pair,
+                    credential: Some(credential),
+                })
+            }
+            None if forget(&credential, "Stored credential is corrupted").await? => {
+                rejected.push(credential)
+            }
+            None => error!(CORRUPTED),
+        }
+    }
+
+    eprintln!("{}", context.banner(account.unwrap_or("not stored yet")));
+
+    let seed = entered(
+        prompt_secret("Enter your private key/seed phrase: ").await?,
+        "private key/seed phrase",
+    )?;
+
+    let pair = pair_from_seed(&seed).ok_or("Invalid private key/seed phrase")?;
+
+    let password = entered(prompt_secret("Create a password: ").await?, "password")?;
+
+    let name = match account {
+        Some(account) => account.to_owned(),
+        None => prompt_nickname(&pair).await?,
+    };
+
+    let mcrypt = new_magic_crypt!(password, 256);
+    let credential = Credential {
+        username: name,
+        password: mcrypt.encrypt_str_to_base64(&seed),
+        expiry: None,
+    };
+
+    if let Err(e) = helpers.approve(&credential) {
+        output::warn(format!("Could not store the credential: {}", e));
+    }
+
+    Ok(Unlocked {
+        pair,
+        credential: Some(credential),
+    })
+}
+
+/// What decrypting a stored seed with a password gave.
+#[derive(Debug, PartialEq, Eq)]
+enum Decrypted {
+    Seed(String),
+    WrongPassword,
+    /// The stored value isn't something `unlock` could have written
+    Corrupted,
+}
+
+fn decrypt_seed(stored: &str, password: &str) -> Decrypted {
+    let mcrypt = new_magic_crypt!(password.trim(), 256);
+
+    match mcrypt.decrypt_base64_to_string(stored.trim()) {
+        Ok(seed) => Decrypted::Seed(seed),
+        Err(MagicCryptError::Base64Error(_)) => Decrypted::Corrupted,
+        Err(e) => {
+            debug!("Decryption failed: {}", e);
+            Decrypted::WrongPassword
+        }
+    }
+}
+
+/// The key in `credential`, asking for its password, or `None` when it holds none.
+async fn decrypt(
+    credential: &Credential,
+    context: &SigningContext,
+) -> BoxResult<Option<Sr25519Pair>> {
+    eprintln!("{}", context.banner(&credential.username));
+
+    for _ in 0..MAX_PASSWORD_ATTEMPTS {
+        let password = entered(
+            prompt_secret(&format!("Enter password for {}: ", credential.username)).await?,
+            "password",
+        )?;
+
+        let seed = match decrypt_seed(&credential.password, &password) {
+            Decrypted::Seed(seed) => seed,
+            Decrypted::Corrupted => return Ok(None),
+            Decrypted::WrongPassword => {
+                eprintln!("Incorrect password");
+                continue;
+            }
+        };
+
+        return Ok(pair_from_seed(&seed));
+    }
+
+    error!(format!(
+        "Incorrect password entered {} times, giving up",
+        MAX_PASSWORD_ATTEMPTS
+    ))
+}
+
+/// Ask on the terminal for `expected` to be typed back, e.g. before a destructive action.
+pub fn confirm(prompt: &str, expected: &str) -> BoxResult<bool> {
+    eprint!("{}", prompt);
+    Ok(read_input()?.trim() == expected)
+}
+
+/// Ask on the terminal for a line of text.
+pub fn ask(prompt: &str) -> BoxResult<String> {
+    eprint!("{}", prompt);
+    Ok(read_input()?)
+}
+
+/// The askpass program configured for git, if any, in the order git itself honors them.
+fn askpass_program() -> Option<String> {
+    env::var("GIT_ASKPASS")
+        .ok()
+        .or_else(|| {
+            git2::Config::open_default()
+                .ok()?
+                .get_string("core.askPass")
+                .ok()
+        })
+        .or_else(|| env::var("SSH_ASKPASS").ok())
+        .filter(|program| !program.is_empty())
+}
+
+/// Ask `program` the question in `prompt`, returning its answer without the trailing newline.
+async fn askpass(program: &str, prompt: &str) -> BoxResult<String> {
+    let child = Command::new(program)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Could not run askpass program {}: {}", program, e))?;
+
+    let output = tokio::time::timeout(ASKPASS_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("Askpass program {} timed out", program))??;
+
+    if !output.status.success() {
+        error!(format!(
+            "Askpass program {} failed with {}",
+            program, output.status
+        ))
+    }
+
+    Ok(String::from_utf8(output.stdout)?
+        .trim_end_matches(&['\r', '\n'][..])
+        .to_string())
+}
+
+/// Prompt for a secret through askpass when configured, or on the terminal otherwise.
+async fn prompt_secret(prompt: &str) -> BoxResult<String> {
+    match askpass_program() {
+        Some(program) => askpass(&program, prompt).await,
+        None => Ok(rpassword::prompt_password(prompt)?),
+    }
+}
+
+/// Ask for a nickname for a new account, defaulting to a name derived from its address when
+/// the user gives none or can't be asked.
+async fn prompt_nickname(pair: &Sr25519Pair) -> BoxResult<String> {
+    let prompt = "Give this account a nickname: ";
+
+    let name = match askpass_program() {
+        Some(program) => askpass(&program, prompt).await?,
+        None => {
+            eprint!("{}", prompt);
+            read_input().unwrap_or_else(|e| {
+                debug!("Could not read nickname from the terminal: {}", e);
+                String::new()
+            })
+        }
+    };
+
+    Ok(if name.trim().is_empty() {
+        let address = pair.public().to_ss58check();
+        let default = format!("inv4-{}", &address[..8]);
+        eprintln!("Using nickname {}", default);
+        default
+    } else {
+        name.trim().to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_seed_decrypts_with_its_password_only() {
+        let stored = new_magic_crypt!("hunter2", 256).encrypt_str_to_base64("//Alice");
+
+        assert_eq!(
+            decrypt_seed(&stored, "hunter2\n"),
+            Decrypted::Seed("//Alice".to_owned())
+        );
+        assert_eq!(decrypt_seed(&stored, "hunter3"), Decrypted::WrongPassword);
+    }
+
+    #[test]
+    fn mangled_credential_is_corrupted() {
+        assert_eq!(
+            decrypt_seed("not base64 at all!", "hunter2"),
+            Decrypted::Corrupted
+        );
+    }
+
+    #[test]
+    fn signing_is_only_allowed_in_a_scope_and_not_read_only() {
+        assert!(check_may_sign().is_err());
+
+        let scope = SigningScope::enter();
+        assert!(check_may_sign().is_ok());
+
+        env::set_var("GIT_INV4_READONLY", "1");
+        assert!(check_may_sign().is_err());
+        env::remove_var("GIT_INV4_READONLY");
+
+        drop(scope);
+        assert!(check_may_sign().is_err());
+    }
+
+    #[test]
+    fn signing_context_names_repository_and_chain() {
+        let mut context = SigningContext {
+            action: "push",
+            ips_id: IpsId(4),
+            repository: Some("infra".to_owned()),
+            chain: "Tinkernet".to_owned(),
+            confirm: true,
+        };
+
+        assert_eq!(
+            context.banner("alice"),
+            "Signing push to infra (IPS 4) on Tinkernet with credential alice"
+        );
+
+        context.repository = None;
+        assert_eq!(
+            context.confirmation("5Grw"),
+            "Sign push to IPS 4 on Tinkernet as 5Grw? [y/N] "
+        );
+    }
+
+    #[test]
+    fn empty_answers_cancel() {
+        assert_eq!(entered(" //Alice\n".to_owned(), "seed").unwrap(), "//Alice");
+        assert!(entered(" \n".to_owned(), "seed").is_err());
+    }
+
+    #[test]
+    fn seeds_are_trimmed_and_checked() {
+        assert_eq!(
+            pair_from_seed(" //Alice\n").map(|pair| pair.public()),
+            pair_from_seed("//Alice").map(|pair| pair.public())
+        );
+        assert!(pair_from_seed("//Alice").is_some());
+        assert!(pair_from_seed("not a seed phrase").is_none());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn askpass_answer_is_its_output_line() {
+        assert_eq!(
+            askpass("echo", "Enter password for alice: ").await.unwrap(),
+            "Enter password for alice: "
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn failing_askpass_is_an_error() {
+        assert!(askpass("false", "Enter password: ").await.is_err());
+        assert!(askpass("/nonexistent/askpass", "Enter password: ")
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Splitting a push's publish call when it would be too heavy for a block.
+//!
+//! A push publishes with one `operate_multisig` call: remove the old RepoData, append the new one
+//! and every pack minted for the push, as one `batch_all`. With the commit-granular packer that
+//! can be dozens of appends, and past the runtime's per-extrinsic weight the whole call fails.
+//!
+//! Such a publish is split. The packs are appended first, in as many calls as needed, and only
+//! the last call swaps the RepoData. Packs appended ahead of their RepoData aren't referenced by
+//! anything yet, so readers see the old repository until that last call lands, and then the new
+//! one, complete.
+
+use crate::{
+    error,
+    primitives::{BoxResult, IpfId},
+};
+
+/// The share of the weight limit, in percent, a call is planned to use at most. The weights are
+/// extrapolated from two samples, so this leaves room for that being off.
+pub const SAFETY_PERCENT: u64 = 75;
+
+/// The weight of a publish call as a function of how many packs it appends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WeightModel {
+    /// Weight of the call without packs: the RepoData swap and the multisig around it
+    pub base: u64,
+    pub per_pack: u64,
+}
+
+impl WeightModel {
+    /// The model through `empty`, the weight without packs, and `full`, the weight with `packs`
+    /// packs, rounding the per-pack weight up.
+    pub fn from_samples(empty: u64, packs: usize, full: u64) -> Self {
+        let per_pack = match packs {
+            0 => 0,
+            packs => {
+                let packs = packs as u64;
+                (full.saturating_sub(empty) + packs - 1) / packs
+            }
+        };
+
+        Self {
+            base: empty,
+            per_pack,
+        }
+    }
+
+    pub fn weight(&self, packs: usize) -> u64 {
+        self.base
+            .saturating_add(self.per_pack.saturating_mul(packs as u64))
+    }
+}
+
+/// How a publish is submitted: each of `appends` on its own, in order, then the call swapping
+/// the RepoData with `swap_packs`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub appends: Vec<Vec<IpfId>>,
+    pub swap_packs: Vec<IpfId>,
+}
+
+impl Plan {
+    /// Everything in one call, as without a limit.
+    pub fn single(packs: &[IpfId]) -> Self {
+        Self {
+            appends: vec![],
+            swap_packs: packs.to_vec(),
+        }
+    }
+
+    pub fn calls(&self) -> usize {
+        self.appends.len() + 1
+    }
+}
+
+/// Plan a publish of `packs` with weights following `model`, under `SAFETY_PERCENT` of
+/// `max_weight`. A publish that fits stays one call; otherwise every pack is appended ahead of
+/// the swap, in calls as full as the limit allows.
+pub fn plan(packs: &[IpfId], model: &WeightModel, max_weight: u64) -> BoxResult<Plan> {
+    let limit = max_weight / 100 * SAFETY_PERCENT;
+    if model.weight(packs.len()) <= limit {
+        return Ok(Plan::single(packs));
+    }
+
+    // An append-only call has no RepoData swap, so this overestimates it slightly
+    let per_call = match limit.checked_sub(model.base) {
+        Some(room) if model.per_pack > 0 => room / model.per_pack,
+        _ => 0,
+    };
+    if per_call == 0 {
+        error!(format!(
+            "A publish call with a single pack weighs {}, over {}% of the limit of {}",
+            model.weight(1),
+            SAFETY_PERCENT,
+            max_weight
+        ))
+    }
+
+    Ok(Plan {
+        appends: packs
+            .chunks(per_call as usize)
+            .map(|chunk| chunk.to_vec())
+            .collect(),
+        swap_packs: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packs(count: u64) -> Vec<IpfId> {
+        (0..count).map(IpfId).collect()
+    }
+
+    #[test]
+    fn per_pack_weight_is_rounded_up() {
+        let model = WeightModel::from_samples(100, 3, 200);
+
+        assert_eq!(model.per_pack, 34);
+        assert_eq!(model.weight(3), 202);
+        assert_eq!(WeightModel::from_samples(100, 0, 100).weight(5), 100);
+    }
+
+    #[test]
+    fn publishes_that_fit_stay_one_call() {
+        let model = WeightModel {
+            base: 100,
+            per_pack: 10,
+        };
+
+        assert_eq!(
+            plan(&packs(65), &model, 1000).unwrap(),
+            Plan::single(&packs(65))
+        );
+    }
+
+    #[test]
+    fn heavy_publishes_append_every_pack_ahead_of_the_swap() {
+        let model = WeightModel {
+            base: 100,
+            per_pack: 10,
+        };
+
+        let plan = plan(&packs(100), &model, 1000).unwrap();
+        assert_eq!(plan.appends, [packs(65), (65..100).map(IpfId).collect()]);
+        assert!(plan.swap_packs.is_empty());
+        assert_eq!(plan.calls(), 3);
+    }
+
+    #[test]
+    fn a_single_pack_over_the_limit_cannot_be_planned() {
+        let model = WeightModel {
+            base: 800,
+            per_pack: 10,
+        };
+
+        assert_eq!(
+            plan(&packs(2), &model, 1000).unwrap_err().to_string(),
+            "A publish call with a single pack weighs 810, over 75% of the limit of 1000"
+        );
+    }
+}
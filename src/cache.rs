@@ -0,0 +1,269 @@
+//! Where state kept outside of a clone lives, e.g. the journals of `migrate`.
+//!
+//! `config.toml` is global, but everything else is kept per chain and per IPS, under
+//! `<cache dir>/INV4-Git/<chain>/<ips id>/`, `<chain>` being the start of the chain's genesis
+//! hash. IPS ids and MultiObject hashes are only unique within a chain, so state for one chain
+//! can't be mistaken for another's. Directories are created when first needed, readable only by
+//! the user on unix, as journals name IPFs and accounts.
+//!
+//! State of a remote in a clone stays in `.git/inv4/<remote>/`, see `remote_helper::state_dir`:
+//! it describes that clone's objects, which another clone of the same IPS doesn't share.
+
+use crate::{
+    config,
+    primitives::{BoxResult, IpsId},
+};
+use dirs::cache_dir;
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use subxt::ext::sp_core::H256;
+
+/// The directory of `object_cache` in `root`, next to the chains' directories.
+const OBJECTS_DIR: &str = "objects";
+
+/// Hex digits of the genesis hash naming a chain's directory. Enough to tell chains apart while
+/// keeping paths well within Windows' 260 character limit.
+const CHAIN_KEY_LEN: usize = 16;
+
+/// `INV4-Git` in the OS cache directory.
+pub fn root() -> BoxResult<PathBuf> {
+    Ok(cache_dir()
+        .ok_or("Operating system's cache directory not found")?
+        .join("INV4-Git"))
+}
+
+/// Where `inv4-git daemon` listens, in `root`, which is created readable only by the user.
+pub fn daemon_socket() -> BoxResult<PathBuf> {
+    let root = root()?;
+    create_private(&root, &root)?;
+
+    Ok(root.join("daemon.sock"))
+}
+
+/// Where downloaded content is cached, see `object_cache`.
+pub fn objects_dir() -> BoxResult<PathBuf> {
+    let root = root()?;
+    let dir = root.join(OBJECTS_DIR);
+    create_private(&root, &dir)?;
+
+    Ok(dir)
+}
+
+/// The directory name of the chain with genesis hash `genesis`.
+pub fn chain_key(genesis: &H256) -> String {
+    format!("{:x}", genesis)[..CHAIN_KEY_LEN].to_owned()
+}
+
+/// The directory of state for IPS `ips_id` on the chain with genesis hash `genesis`, created if
+/// it doesn't exist yet.
+pub fn ips_dir(genesis: &H256, ips_id: IpsId) -> BoxResult<PathBuf> {
+    let root = root()?;
+    let dir = root.join(chain_key(genesis)).join(ips_id.to_string());
+    create_private(&root, &dir)?;
+
+    Ok(dir)
+}
+
+/// The bare repository `inv4-git serve-http` mirrors IPS `ips_id` into.
+pub fn http_mirror_dir(genesis: &H256, ips_id: IpsId) -> BoxResult<PathBuf> {
+    Ok(ips_dir(genesis, ips_id)?.join("http-mirror.git"))
+}
+
+/// The journal directory of `migrate` for IPS `ips_id`. One left by an earlier version next to
+/// the config file, before state was kept per chain, is moved in on first use.
+pub fn migrations_dir(genesis: &H256, ips_id: IpsId) -> BoxResult<PathBuf> {
+    let dir = ips_dir(genesis, ips_id)?.join("migrations");
+
+    let legacy = config::path()?
+        .with_file_name("migrations")
+        .join(ips_id.to_string());
+    if legacy.is_dir() && !dir.exists() {
+        debug!("Moving {} to {}", legacy.display(), dir.display());
+        fs::rename(&legacy, &dir).or_else(|_| copy_dir(&legacy, &dir))?;
+        let _ = fs::remove_dir_all(&legacy);
+        let _ = fs::remove_dir(legacy.parent().unwrap_or(&legacy));
+    }
+
+    create_private(&dir, &dir)?;
+
+    Ok(dir)
+}
+
+/// Create `dir` and the directories leading to it from `base`, restricting each created to the
+/// user on unix.
+fn create_private(base: &Path, dir: &Path) -> BoxResult<()> {
+    fs::create_dir_all(dir)?;
+
+    for ancestor in dir
+        .ancestors()
+        .take_while(|ancestor| ancestor.starts_with(base))
+    {
+        restrict(ancestor)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restrict(dir: &Path) -> BoxResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?)
+}
+
+#[cfg(not(unix))]
+fn restrict(_dir: &Path) -> BoxResult<()> {
+    Ok(())
+}
+
+/// Copy `from` into `to` recursively, for when a rename crosses file systems.
+fn copy_dir(from: &Path, to: &Path) -> BoxResult<()> {
+    fs::create_dir_all(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().ok_or("Unnamed directory entry")?);
+
+        match path.is_dir() {
+            true => copy_dir(&path, &target)?,
+            false => {
+                fs::copy(&path, &target)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The state kept for one IPS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// The chain's directory name, see `chain_key`
+    pub chain: String,
+    pub ips_id: String,
+    pub path: PathBuf,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// The state kept for each IPS of each chain, optionally only of chains whose key starts with
+/// `chain` and of IPS `ips_id`.
+pub fn usage(chain: Option<&str>, ips_id: Option<IpsId>) -> BoxResult<Vec<Usage>> {
+    let root = root()?;
+    let mut usage = vec![];
+
+    if !root.is_dir() {
+        return Ok(usage);
+    }
+
+    for chain_dir in subdirs(&root)? {
+        let key = file_name(&chain_dir);
+        if key == OBJECTS_DIR || chain.map_or(false, |prefix| !key.starts_with(prefix)) {
+            continue;
+        }
+
+        for ips_dir in subdirs(&chain_dir)? {
+            let id = file_name(&ips_dir);
+            if ips_id.map_or(false, |ips_id| id != ips_id.to_string()) {
+                continue;
+            }
+
+            let (files, bytes) = size(&ips_dir)?;
+            usage.push(Usage {
+                chain: key.clone(),
+                ips_id: id,
+                path: ips_dir,
+                files,
+                bytes,
+            });
+        }
+    }
+
+    Ok(usage)
+}
+
+fn subdirs(dir: &Path) -> BoxResult<Vec<PathBuf>> {
+    let mut dirs = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The number of files under `dir` and their total size.
+fn size(dir: &Path) -> BoxResult<(usize, u64)> {
+    let mut total = (0, 0);
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let (files, bytes) = match path.is_dir() {
+            true => size(&path)?,
+            false => (1, path.metadata()?.len()),
+        };
+        total.0 += files;
+        total.1 += bytes;
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn chains_are_keyed_by_the_start_of_their_genesis_hash() {
+        let genesis = H256::repeat_byte(0xab);
+
+        assert_eq!(chain_key(&genesis), "abababababababab");
+        assert_ne!(chain_key(&genesis), chain_key(&H256::repeat_byte(0xac)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn created_directories_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("INV4-Git");
+        let dir = root.join("chain").join("1");
+        create_private(&root, &dir).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&root), 0o700);
+        assert_eq!(mode(&root.join("chain")), 0o700);
+        assert_eq!(mode(&dir), 0o700);
+    }
+
+    #[test]
+    fn directories_are_copied_and_sized_recursively() {
+        let temp = TempDir::new().unwrap();
+        let from = temp.path().join("from");
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join("journal"), b"12345").unwrap();
+        fs::write(from.join("nested").join("entry"), b"123").unwrap();
+
+        let to = temp.path().join("to");
+        copy_dir(&from, &to).unwrap();
+
+        assert_eq!(fs::read(to.join("nested").join("entry")).unwrap(), b"123");
+        assert_eq!(size(&to).unwrap(), (2, 8));
+    }
+}
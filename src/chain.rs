@@ -0,0 +1,1363 @@
+use crate::{
+    error,
+    events::{self, Event},
+    output,
+    primitives::{BoxResult, Config, IpfId, IpsId},
+    push_metadata, redact,
+    runtime::{self, ChainCall, Runtime},
+    throttle::{self, RateLimiter},
+};
+use codec::{Decode, Encode};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use jsonrpsee::{
+    async_client::ClientBuilder,
+    client_transport::ws::{Uri, WsTransportClientBuilder},
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    env,
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subxt::{
+    dynamic,
+    events::{EventDetails, Phase, StaticEvent},
+    ext::{
+        sp_core::{blake2_256, sr25519::Pair, Bytes, H256},
+        sp_runtime::AccountId32,
+    },
+    metadata::DecodeWithMetadata,
+    rpc::rpc_params,
+    storage::address::{StorageAddress, Yes},
+    tx::{Era, PairSigner, PolkadotExtrinsicParamsBuilder, TxPayload},
+    OnlineClient, PolkadotConfig,
+};
+use tokio::sync::RwLock;
+
+/// How many times a single chain request is retried after the connection dropped.
+const MAX_RECONNECTS: usize = 3;
+
+/// How many blocks past submission we scan for our extrinsic when the watch subscription drops.
+const MAX_POLLED_BLOCKS: u32 = 64;
+
+/// Time to wait between polls for new blocks.
+const POLL_INTERVAL: Duration = Duration::from_secs(6);
+
+/// How long to wait for a block to be finalized before settling for it being in a block.
+const MAX_FINALITY_WAIT: Duration = Duration::from_secs(10 * 60);
+
+/// How many blocks a signed transaction stays valid for, counted from the finalized block it is
+/// anchored at.
+const MORTAL_PERIOD: u64 = 64;
+
+/// How far the system clock may be from the latest finalized block's timestamp before signing a
+/// mortal transaction is refused. Finality lags a few blocks behind, so this allows for that.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// The `confirmation` config setting: when a published push counts as done.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Confirmation {
+    /// Once the call is in a block, which a reorg can still drop
+    InBlock,
+    /// Once that block is finalized
+    Finalized,
+}
+
+/// The `chain_auth` config setting: a header the endpoint requires, e.g. an API key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainAuth {
+    /// Name of the header, e.g. `Authorization`
+    pub header: String,
+    /// Environment variable holding its value, so the value stays out of the config file
+    pub value_env: String,
+}
+
+impl ChainAuth {
+    /// The header's name and value, its value registered for redaction.
+    fn resolve(&self) -> BoxResult<(HeaderName, HeaderValue)> {
+        let value = env::var(&self.value_env).map_err(|_| {
+            format!(
+                "chain_auth takes the {} header from ${}, which isn't set",
+                self.header, self.value_env
+            )
+        })?;
+        redact::register(&value);
+
+        let name = HeaderName::from_bytes(self.header.as_bytes()).map_err(|_| {
+            format!(
+                "chain_auth header {:?} isn't a valid header name",
+                self.header
+            )
+        })?;
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("${} isn't a valid header value", self.value_env))?;
+        value.set_sensitive(true);
+
+        Ok((name, value))
+    }
+}
+
+/// A client for `endpoint`, sending `auth` with the websocket handshake when given.
+async fn client(
+    endpoint: &str,
+    auth: Option<&(HeaderName, HeaderValue)>,
+) -> BoxResult<OnlineClient<PolkadotConfig>> {
+    let (name, value) = match auth {
+        Some(auth) => auth.clone(),
+        None => return Ok(OnlineClient::<PolkadotConfig>::from_url(endpoint).await?),
+    };
+
+    let uri: Uri = endpoint
+        .parse()
+        .map_err(|_| format!("{} isn't a valid endpoint URL", endpoint))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(name, value);
+
+    let (sender, receiver) = WsTransportClientBuilder::default()
+        .set_headers(headers)
+        .build(uri)
+        .await
+        .map_err(|e| format!("Could not connect to {}: {}", endpoint, e))?;
+    // The same limit `from_url` sets
+    let rpc = ClientBuilder::default()
+        .max_notifs_per_subscription(4096)
+        .build_with_tokio(sender, receiver);
+
+    Ok(OnlineClient::<PolkadotConfig>::from_rpc_client(rpc).await?)
+}
+
+/// The start of `System::BlockWeights`, with weights before weights v2.
+#[derive(Decode)]
+struct BlockWeights {
+    _base_block: u64,
+    max_block: u64,
+    /// Only the first class, `Normal`, which signed calls are
+    normal: WeightsPerClass,
+}
+
+#[derive(Decode)]
+struct WeightsPerClass {
+    _base_extrinsic: u64,
+    max_extrinsic: Option<u64>,
+}
+
+/// The account INV4 dispatches an IPS's multisig calls from.
+///
+/// Mirrors the pallet's derivation: blake2b-256 over the SCALE encoding of
+/// `(b"modlpy/utilisuba", ips_id)`, used as the account id as is.
+pub fn ips_account(ips_id: IpsId) -> AccountId32 {
+    AccountId32::from((b"modlpy/utilisuba", ips_id).using_encoded(blake2_256))
+}
+
+/// The native token's decimals and symbol, for displaying balances.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub decimals: u32,
+    pub symbol: String,
+}
+
+impl Token {
+    /// `amount` in whole tokens, e.g. `1.5 TNKR`.
+    pub fn format(&self, amount: u128) -> String {
+        let unit = 10u128.pow(self.decimals);
+        let fraction = format!("{:0width$}", amount % unit, width = self.decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+
+        match fraction {
+            "" => format!("{} {}", amount / unit, self.symbol),
+            _ => format!("{}.{} {}", amount / unit, fraction, self.symbol),
+        }
+    }
+}
+
+/// An IPF as stored on-chain.
+#[derive(Clone, Debug)]
+pub struct IpfRecord {
+    pub id: IpfId,
+    pub metadata: Vec<u8>,
+    pub data: H256,
+}
+
+/// The configuration of an IPS that decides whether pushes can append to it.
+#[derive(Clone, Debug)]
+pub struct IpsFlags {
+    /// The IPS this one is a replica of
+    pub replica_of: Option<IpsId>,
+    /// The IPS this one belongs to, if it is a child
+    pub parent: Option<IpsId>,
+    pub allow_replica: bool,
+    /// Whether sub-tokens may operate the IPS without being granted a permission
+    pub default_permission: bool,
+    /// States this build doesn't know the meaning of
+    pub unknown: Vec<String>,
+    /// The fields above as read from the chain, for diagnostics
+    pub raw: String,
+}
+
+impl IpsFlags {
+    /// Why appending to `ips_id` is bound to fail, if its flags say so.
+    pub fn push_blocker(&self, ips_id: IpsId) -> Option<String> {
+        if let Some(original) = self.replica_of {
+            return Some(format!(
+                "IPS {} is a frozen replica of IPS {}; push to the original IPS {} instead",
+                ips_id, original, original
+            ));
+        }
+
+        if let Some(parent) = self.parent {
+            return Some(format!(
+                "IPS {} is a child of IPS {} and can't be appended to on its own; push to IPS {} instead",
+                ips_id, parent, parent
+            ));
+        }
+
+        None
+    }
+}
+
+/// The events emitted by an extrinsic that made it into a block.
+pub struct Included {
+    pub block_hash: H256,
+    pub extrinsic_hash: H256,
+    pub events: Vec<EventDetails>,
+    /// The finalized head the block was found under, once `Chain::finalize` saw it finalized
+    pub finalized_block_hash: Option<H256>,
+}
+
+impl Included {
+    pub fn find_first<E: StaticEvent>(&self) -> BoxResult<Option<E>> {
+        for event in &self.events {
+            if let Some(e) = event.as_event::<E>()? {
+                return Ok(Some(e));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The fee the signer paid, from the `TransactionFeePaid` event of runtimes that have it, or
+    /// else the first `Balances::Withdraw`, which is the fee's.
+    pub fn fee_paid(&self) -> Option<u128> {
+        // (who, actual_fee, tip)
+        self.field("TransactionPayment", "TransactionFeePaid", 1)
+            // (who, amount)
+            .or_else(|| self.field("Balances", "Withdraw", 1))
+    }
+
+    /// What the extrinsic reserved, e.g. storage deposits, from its `Balances::Reserved` events.
+    pub fn reserved(&self) -> u128 {
+        self.events
+            .iter()
+            .filter(|event| event.pallet_name() == "Balances" && event.variant_name() == "Reserved")
+            // (who, amount)
+            .filter_map(|event| event.field_values().ok()?.values().nth(1)?.as_u128())
+            .sum()
+    }
+
+    /// Field `index` of the first `pallet::variant` event, as a number.
+    fn field(&self, pallet: &str, variant: &str, index: usize) -> Option<u128> {
+        let event = self
+            .events
+            .iter()
+            .find(|event| event.pallet_name() == pallet && event.variant_name() == variant)?;
+
+        event.field_values().ok()?.values().nth(index)?.as_u128()
+    }
+}
+
+/// A chain connection that transparently reconnects when the websocket drops.
+///
+/// Storage reads are stateless, so a dropped connection is handled by rebuilding the client and
+/// retrying the read. Requests are paced by `chain_rps`, so public nodes don't cut us off.
+pub struct Chain {
+    endpoint: String,
+    /// Header sent with every connection, from `chain_auth`
+    auth: Option<(HeaderName, HeaderValue)>,
+    api: RwLock<OnlineClient<PolkadotConfig>>,
+    runtime: Box<dyn Runtime>,
+    limiter: Option<RateLimiter>,
+    /// Sign transactions valid forever instead of for `MORTAL_PERIOD` blocks
+    immortal: bool,
+}
+
+impl Chain {
+    /// Connect to `chain_endpoint`, failing unless a runtime matching the chain's is known.
+    pub async fn connect(config: &Config) -> BoxResult<Self> {
+        let endpoint = config.chain_endpoint.clone();
+        redact::register_url(&endpoint);
+        let auth = config
+            .chain_auth
+            .as_ref()
+            .map(ChainAuth::resolve)
+            .transpose()?;
+        let api = client(&endpoint, auth.as_ref()).await?;
+        let runtime = runtime::select(config, &api)?;
+
+        Ok(Self {
+            endpoint,
+            auth,
+            api: RwLock::new(api),
+            runtime,
+            limiter: throttle::chain_rps(config).map(RateLimiter::new),
+            immortal: config.immortal_transactions
+                || env::var("GIT_INV4_IMMORTAL").as_deref() == Ok("1"),
+        })
+    }
+
+    /// Wait until another request may be sent.
+    async fn pace(&self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(1).await;
+        }
+    }
+
+    pub async fn api(&self) -> OnlineClient<PolkadotConfig> {
+        self.api.read().await.clone()
+    }
+
+    pub fn runtime(&self) -> &dyn Runtime {
+        self.runtime.as_ref()
+    }
+
+    async fn reconnect(&self) -> BoxResult<()> {
+        let api = client(&self.endpoint, self.auth.as_ref()).await?;
+        *self.api.write().await = api;
+        Ok(())
+    }
+
+    /// Run `f` against the current client, reconnecting and retrying when the connection dropped.
+    pub async fn with_reconnect<F, Fut, R>(&self, f: F) -> BoxResult<R>
+    where
+        F: Fn(OnlineClient<PolkadotConfig>) -> Fut,
+        Fut: Future<Output = Result<R, subxt::Error>>,
+    {
+        retry_reconnecting(
+            &self.endpoint,
+            || async move {
+                self.pace().await;
+                self.api().await
+            },
+            || self.reconnect(),
+            f,
+        )
+        .await
+    }
+
+    /// The hash of the chain's genesis block, which tells chains apart.
+    pub async fn genesis_hash(&self) -> H256 {
+        self.api().await.genesis_hash()
+    }
+
+    /// The chain's name as its node reports it, or the endpoint when the node doesn't say.
+    pub async fn name(&self) -> String {
+        match self
+            .with_reconnect(|api| async move { api.rpc().system_chain().await })
+            .await
+        {
+            Ok(name) => name,
+            Err(e) => {
+                debug!("Could not ask {} for its name: {}", self.endpoint, e);
+                self.endpoint.clone()
+            }
+        }
+    }
+
+    /// How many peers the node has, from `system_health`, which answers only once the endpoint
+    /// accepted the connection's credentials.
+    pub async fn health(&self) -> BoxResult<usize> {
+        let health = self
+            .with_reconnect(|api| async move { api.rpc().system_health().await })
+            .await?;
+
+        Ok(health.peers)
+    }
+
+    /// The number and hash of the current best block.
+    pub async fn best_block(&self) -> BoxResult<(u32, H256)> {
+        let hash = self
+            .with_reconnect(|api| async move { api.rpc().block_hash(None).await })
+            .await?
+            .ok_or("Chain has no best block")?;
+
+        let header = self
+            .with_reconnect(|api| async move { api.rpc().header(Some(hash)).await })
+            .await?
+            .ok_or(format!("Header of block {:?} not found", hash))?;
+
+        Ok((header.number, hash))
+    }
+
+    /// The most bytes of metadata INV4 accepts, e.g. with `operate_multisig`.
+    pub async fn max_metadata(&self) -> usize {
+        let metadata = self.api().await.metadata();
+
+        metadata
+            .pallet("INV4")
+            .ok()
+            .and_then(|pallet| pallet.constant("MaxMetadata").ok())
+            .and_then(|constant| u32::decode(&mut &constant.value[..]).ok())
+            .map_or(push_metadata::DEFAULT_MAX_METADATA, |max| max as usize)
+    }
+
+    /// Free balance of `account`, zero for accounts that don't exist.
+    pub async fn free_balance(&self, account: &AccountId32) -> BoxResult<u128> {
+        self.runtime.free_balance(self, account).await
+    }
+
+    /// The hash of block `number`, `None` if there is no such block yet.
+    pub async fn block_hash(&self, number: u32) -> BoxResult<Option<H256>> {
+        self.with_reconnect(|api| async move { api.rpc().block_hash(Some(number.into())).await })
+            .await
+    }
+
+    /// Read the storage entry at `address`.
+    pub async fn fetch<Address>(
+        &self,
+        address: &Address,
+    ) -> BoxResult<Option<<Address::Target as DecodeWithMetadata>::Target>>
+    where
+        Address: StorageAddress<IsFetchable = Yes>,
+    {
+        self.fetch_at(address, None).await
+    }
+
+    /// Read the storage entry at `address` as of block `at`, the best block when `None`. Nodes
+    /// that prune state only answer for recent blocks.
+    pub async fn fetch_at<Address>(
+        &self,
+        address: &Address,
+        at: Option<H256>,
+    ) -> BoxResult<Option<<Address::Target as DecodeWithMetadata>::Target>>
+    where
+        Address: StorageAddress<IsFetchable = Yes>,
+    {
+        self.with_reconnect(|api| async move { api.storage().fetch(address, at).await })
+            .await
+    }
+
+    pub async fn token(&self) -> BoxResult<Token> {
+        let properties = self
+            .with_reconnect(|api| async move { api.rpc().system_properties().await })
+            .await?;
+
+        Ok(Token {
+            decimals: properties
+                .get("tokenDecimals")
+                .and_then(|decimals| decimals.as_u64())
+                .unwrap_or(12) as u32,
+            symbol: properties
+                .get("tokenSymbol")
+                .and_then(|symbol| symbol.as_str())
+                .unwrap_or("TNKR")
+                .to_owned(),
+        })
+    }
+
+    /// The weight the node computes for `call` signed by `signer`.
+    pub async fn estimate_weight<Call: TxPayload>(
+        &self,
+        call: &Call,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<u64> {
+        weight_of(&self.query_info(call, signer).await?)
+    }
+
+    /// The fee `signer` would pay for submitting `call` right now and, when the node reports
+    /// it, the call's weight.
+    pub async fn estimate<Call: TxPayload>(
+        &self,
+        call: &Call,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<(u128, Option<u64>)> {
+        let info = self.query_info(call, signer).await?;
+
+        Ok((fee_of(&info)?, weight_of(&info).ok()))
+    }
+
+    /// The SCALE encoding of `call` as the runtime's metadata lays it out, i.e. what `submit`
+    /// signs.
+    pub async fn encode_call(&self, call: &ChainCall) -> BoxResult<Vec<u8>> {
+        let metadata = self.api().await.metadata();
+        let mut encoded = vec![];
+        call.encode_call_data(&metadata, &mut encoded)?;
+
+        Ok(encoded)
+    }
+
+    /// The most weight a normal extrinsic may have, from the `System::BlockWeights` constant, or
+    /// `None` when it can't be read.
+    pub async fn max_extrinsic_weight(&self) -> Option<u64> {
+        let metadata = self.api().await.metadata();
+        let constant = metadata
+            .pallet("System")
+            .ok()?
+            .constant("BlockWeights")
+            .ok()?;
+        let weights = BlockWeights::decode(&mut &constant.value[..]).ok()?;
+
+        Some(weights.normal.max_extrinsic.unwrap_or(weights.max_block))
+    }
+
+    /// What `payment_queryInfo` says about `call` signed by `signer`.
+    async fn query_info<Call: TxPayload>(
+        &self,
+        call: &Call,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<serde_json::Value> {
+        let extrinsic = self
+            .with_reconnect(|api| async move {
+                api.tx()
+                    .create_signed(call, signer, Default::default())
+                    .await
+            })
+            .await?;
+
+        let info: serde_json::Value = self
+            .with_reconnect(|api| {
+                let encoded = Bytes(extrinsic.encoded().to_vec());
+                async move {
+                    api.rpc()
+                        .request("payment_queryInfo", rpc_params![encoded])
+                        .await
+                }
+            })
+            .await?;
+
+        Ok(info)
+    }
+
+    /// The ids of all IPFs in the IPS's data set, or `None` if the IPS doesn't exist.
+    pub async fn ipf_ids(&self, ips_id: IpsId) -> BoxResult<Option<Vec<IpfId>>> {
+        self.ipf_ids_at(ips_id, None).await
+    }
+
+    /// The ids of all IPFs in the IPS's data set as of block `at`, the best block when `None`.
+    pub async fn ipf_ids_at(
+        &self,
+        ips_id: IpsId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<Vec<IpfId>>> {
+        self.runtime.ipf_ids(self, ips_id, at).await
+    }
+
+    /// The flags of the IPS, or `None` if the IPS doesn't exist.
+    pub async fn ips_flags(&self, ips_id: IpsId) -> BoxResult<Option<IpsFlags>> {
+        self.runtime.ips_flags(self, ips_id).await
+    }
+
+    pub async fn ipf(&self, id: IpfId) -> BoxResult<IpfRecord> {
+        self.ipf_at(id, None).await
+    }
+
+    /// The IPF `id` as of block `at`, the best block when `None`.
+    pub async fn ipf_at(&self, id: IpfId, at: Option<H256>) -> BoxResult<IpfRecord> {
+        self.runtime
+            .ipf(self, id, at)
+            .await?
+            .ok_or_else(|| "Internal error: IPF listed from IPS does not exist".into())
+    }
+
+    /// The IPF `id` as of block `at`, failing unless it is in the data set of IPS `ips_id` then.
+    ///
+    /// Anyone can mint an IPF with any metadata, so an id that didn't come from listing the IPS
+    /// must be checked this way before its content is trusted as the repository's.
+    pub async fn ipf_of(&self, ips_id: IpsId, id: IpfId, at: Option<H256>) -> BoxResult<IpfRecord> {
+        let ids = self.runtime.ipf_ids(self, ips_id, at).await?;
+        check_part_of(ids, ips_id, id)?;
+
+        self.ipf_at(id, at).await
+    }
+
+    /// Mint an IPF holding `data`, returning its id.
+    pub async fn mint_ipf(
+        &self,
+        metadata: Vec<u8>,
+        data: H256,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<IpfId> {
+        let included = self
+            .submit(&self.runtime.mint_ipf(metadata, data), signer)
+            .await?;
+
+        self.runtime
+            .minted_ipf(&included)?
+            .ok_or_else(|| "IPF mint did not emit a Minted event".into())
+    }
+
+    /// Create an empty IPS owned by `signer`, returning its id.
+    #[cfg(feature = "devnet")]
+    pub async fn create_ips(
+        &self,
+        metadata: Vec<u8>,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<IpsId> {
+        let included = self
+            .submit(&self.runtime.create_ips(metadata), signer)
+            .await?;
+
+        self.runtime
+            .created_ips(&included)?
+            .ok_or_else(|| "IPS creation did not emit an IPSCreated event".into())
+    }
+
+    /// Find the first IPF in the IPS whose metadata equals `metadata`.
+    pub async fn find_ipf(&self, ips_id: IpsId, metadata: &[u8]) -> BoxResult<Option<IpfRecord>> {
+        let ids = self
+            .ipf_ids(ips_id)
+            .await?
+            .ok_or(format!("IPS {ips_id} does not exist"))?;
+
+        for id in ids {
+            let ipf = self.ipf(id).await?;
+            if ipf.metadata == metadata {
+                return Ok(Some(ipf));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// All IPFs in the IPS whose metadata equals `metadata` as of block `at`, the best block when
+    /// `None`, in data set order.
+    pub async fn find_ipfs_at(
+        &self,
+        ips_id: IpsId,
+        metadata: &[u8],
+        at: Option<H256>,
+    ) -> BoxResult<Vec<IpfRecord>> {
+        let ids = self
+            .runtime
+            .ipf_ids(self, ips_id, at)
+            .await?
+            .ok_or(format!("IPS {ips_id} does not exist"))?;
+
+        let mut found = vec![];
+        for id in ids {
+            let ipf = self.ipf_at(id, at).await?;
+            if ipf.metadata == metadata {
+                found.push(ipf);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// The era to sign a transaction with: `MORTAL_PERIOD` blocks from the latest finalized block,
+    /// or immortal with `immortal_transactions`.
+    ///
+    /// A clock far off the chain's makes the node reject mortal transactions with errors that
+    /// read like a wrong seed, so it is checked against that block's timestamp first.
+    async fn extrinsic_params(&self) -> BoxResult<PolkadotExtrinsicParamsBuilder<PolkadotConfig>> {
+        let (number, hash) = self.finalized_block().await?;
+        let skew = self.clock_skew(hash).await?;
+
+        let params = PolkadotExtrinsicParamsBuilder::new();
+        if self.immortal {
+            if let Some(skew) = skew {
+                output::warn(skew);
+            }
+            return Ok(params);
+        }
+
+        if let Some(skew) = skew {
+            error!(format!(
+                "{}; signing anyway would be rejected like a wrong seed. Sync the clock (e.g. `timedatectl set-ntp true`), or sign an immortal transaction with `immortal_transactions = true`, GIT_INV4_IMMORTAL=1 or --immortal",
+                skew
+            ))
+        }
+
+        Ok(params.era(Era::mortal(MORTAL_PERIOD, number.into()), hash))
+    }
+
+    /// How far the system clock is from the timestamp of block `hash`, when more than
+    /// `MAX_CLOCK_SKEW`.
+    async fn clock_skew(&self, hash: H256) -> BoxResult<Option<String>> {
+        // A chain without the Timestamp pallet has no time to compare with
+        if self.api().await.metadata().pallet("Timestamp").is_err() {
+            return Ok(None);
+        }
+
+        let address = dynamic::storage_root("Timestamp", "Now");
+        let block_ms = match self
+            .fetch_at(&address, Some(hash))
+            .await?
+            .and_then(|now| now.as_u128())
+        {
+            Some(ms) => ms,
+            None => return Ok(None),
+        };
+
+        let local_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+        Ok(skew(local_ms, block_ms, hash))
+    }
+
+    /// Sign and submit `call`, returning once it's in a block and dispatched successfully.
+    ///
+    /// If the watch subscription drops after submission, recent blocks are polled for the
+    /// extrinsic instead of failing the whole operation.
+    pub async fn submit(
+        &self,
+        call: &ChainCall,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<Included> {
+        let params = &self.extrinsic_params().await?;
+        let extrinsic = self
+            .with_reconnect(|api| async move {
+                api.tx().create_signed(call, signer, params.clone()).await
+            })
+            .await?;
+
+        let extrinsic_hash = H256(blake2_256(extrinsic.encoded()));
+
+        let start_block = self
+            .with_reconnect(|api| async move { api.rpc().header(None).await })
+            .await?
+            .map(|header| header.number)
+            .unwrap_or_default();
+
+        self.pace().await;
+        let progress = extrinsic.submit_and_watch().await?;
+        events::emit(Event::Submitted { extrinsic_hash });
+
+        let block_hash = match progress.wait_for_in_block().await {
+            Ok(in_block) => in_block.block_hash(),
+            Err(e) if is_connection_closed(&e) => {
+                output::warn(
+                    "Lost track of submitted transaction, looking for it in new blocks...",
+                );
+                self.reconnect().await?;
+                self.poll_for_extrinsic(start_block, extrinsic_hash).await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        events::emit(Event::InBlock {
+            extrinsic_hash,
+            block_hash,
+        });
+
+        let included = self.included(block_hash, extrinsic_hash).await?;
+        if let Some(fee) = included.fee_paid() {
+            events::emit(Event::FeePaid {
+                extrinsic_hash,
+                call: call.name(),
+                fee,
+                deposit: included.reserved(),
+            });
+        }
+
+        Ok(included)
+    }
+
+    async fn poll_for_extrinsic(&self, start_block: u32, extrinsic_hash: H256) -> BoxResult<H256> {
+        let mut next_block = start_block;
+
+        while next_block <= start_block + MAX_POLLED_BLOCKS {
+            let block_hash = self
+                .with_reconnect(
+                    |api| async move { api.rpc().block_hash(Some(next_block.into())).await },
+                )
+                .await?;
+
+            let block_hash = match block_hash {
+                Some(hash) => hash,
+                None => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            if self
+                .extrinsic_index(block_hash, extrinsic_hash)
+                .await?
+                .is_some()
+            {
+                debug!(
+                    "Found extrinsic {:?} in block {}",
+                    extrinsic_hash, next_block
+                );
+                return Ok(block_hash);
+            }
+
+            next_block += 1;
+        }
+
+        error!(format!(
+            "Transaction {:?} was not found within {} blocks of submission",
+            extrinsic_hash, MAX_POLLED_BLOCKS
+        ))
+    }
+
+    async fn extrinsic_index(
+        &self,
+        block_hash: H256,
+        extrinsic_hash: H256,
+    ) -> BoxResult<Option<u32>> {
+        let block = self
+            .with_reconnect(|api| async move { api.rpc().block(Some(block_hash)).await })
+            .await?
+            .ok_or(format!("Block {:?} not found", block_hash))?;
+
+        Ok(block
+            .block
+            .extrinsics
+            .iter()
+            .position(|ext| H256(blake2_256(&ext.encode())) == extrinsic_hash)
+            .map(|index| index as u32))
+    }
+
+    async fn included(&self, block_hash: H256, extrinsic_hash: H256) -> BoxResult<Included> {
+        let index = self
+            .extrinsic_index(block_hash, extrinsic_hash)
+            .await?
+            .ok_or(format!(
+                "Transaction {:?} not found in block {:?}",
+                extrinsic_hash, block_hash
+            ))?;
+
+        let events = self
+            .with_reconnect(|api| async move { api.events().at(Some(block_hash)).await })
+            .await?;
+
+        let mut extrinsic_events = vec![];
+        for event in events.iter() {
+            let event = event?;
+            if event.phase() != Phase::ApplyExtrinsic(index) {
+                continue;
+            }
+
+            if event.pallet_name() == "System" && event.variant_name() == "ExtrinsicFailed" {
+                error!(format!("Transaction failed: {}", event.field_values()?))
+            }
+
+            extrinsic_events.push(event);
+        }
+
+        Ok(Included {
+            block_hash,
+            extrinsic_hash,
+            events: extrinsic_events,
+            finalized_block_hash: None,
+        })
+    }
+
+    pub async fn block_number(&self, block_hash: H256) -> BoxResult<u32> {
+        Ok(self
+            .with_reconnect(|api| async move { api.rpc().header(Some(block_hash)).await })
+            .await?
+            .ok_or(format!("Header of block {:?} not found", block_hash))?
+            .number)
+    }
+
+    /// The number and hash of the latest finalized block.
+    pub async fn finalized_block(&self) -> BoxResult<(u32, H256)> {
+        let hash = self
+            .with_reconnect(|api| async move { api.rpc().finalized_head().await })
+            .await?;
+
+        Ok((self.block_number(hash).await?, hash))
+    }
+
+    /// Whether the extrinsic `extrinsic_hash` is in `block_hash` and that block is still part of
+    /// the canonical chain, i.e. wasn't dropped by a reorg. Blocks the node no longer knows
+    /// count as dropped.
+    pub async fn block_contains_extrinsic(
+        &self,
+        block_hash: H256,
+        extrinsic_hash: H256,
+    ) -> BoxResult<bool> {
+        let number = match self
+            .with_reconnect(|api| async move { api.rpc().header(Some(block_hash)).await })
+            .await?
+        {
+            Some(header) => header.number,
+            None => return Ok(false),
+        };
+
+        let canonical = self
+            .with_reconnect(|api| async move { api.rpc().block_hash(Some(number.into())).await })
+            .await?;
+
+        if canonical != Some(block_hash) {
+            debug!(
+                "Block {} is {:?} now rather than {:?}",
+                number, canonical, block_hash
+            );
+            return Ok(false);
+        }
+
+        Ok(self
+            .extrinsic_index(block_hash, extrinsic_hash)
+            .await?
+            .is_some())
+    }
+
+    /// Wait until the block `included` is in gets finalized.
+    ///
+    /// When a reorg dropped that block, the finalized chain is searched for the extrinsic, which
+    /// may have landed again in another block. If finality takes longer than `MAX_FINALITY_WAIT`,
+    /// `included` is returned as it is, with no finalized block.
+    pub async fn finalize(&self, included: Included) -> BoxResult<Included> {
+        let number = self.block_number(included.block_hash).await?;
+        let started = tokio::time::Instant::now();
+
+        let (finalized_number, finalized_hash) = loop {
+            let (finalized_number, finalized_hash) = self.finalized_block().await?;
+            if finalized_number >= number {
+                break (finalized_number, finalized_hash);
+            }
+
+            if started.elapsed() >= MAX_FINALITY_WAIT {
+                output::warn(format!(
+                    "Block {} was not finalized within {} minutes; not waiting any longer",
+                    number,
+                    MAX_FINALITY_WAIT.as_secs() / 60
+                ));
+                return Ok(included);
+            }
+
+            output::status(format!(
+                "Waiting for block {} to be finalized, at {} so far...",
+                number, finalized_number
+            ));
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+
+        if self
+            .block_contains_extrinsic(included.block_hash, included.extrinsic_hash)
+            .await?
+        {
+            return Ok(Included {
+                finalized_block_hash: Some(finalized_hash),
+                ..included
+            });
+        }
+
+        output::warn(format!(
+            "Block {:?} was dropped by a reorg; looking for the transaction in the finalized chain...",
+            included.block_hash
+        ));
+
+        // The fork that replaced the block may have started before it
+        for candidate in number.saturating_sub(MAX_POLLED_BLOCKS)..=finalized_number {
+            let block_hash = match self
+                .with_reconnect(
+                    |api| async move { api.rpc().block_hash(Some(candidate.into())).await },
+                )
+                .await?
+            {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            if self
+                .extrinsic_index(block_hash, included.extrinsic_hash)
+                .await?
+                .is_some()
+            {
+                return Ok(Included {
+                    finalized_block_hash: Some(finalized_hash),
+                    ..self.included(block_hash, included.extrinsic_hash).await?
+                });
+            }
+        }
+
+        error!(format!(
+            "Transaction {:?} was dropped by a reorg and is not in the finalized chain",
+            included.extrinsic_hash
+        ))
+    }
+}
+
+/// The block a session reads the IPS at, so the refs it lists and the objects it fetches for
+/// them come from the same state, whatever is pushed in between.
+///
+/// Pushes don't go through it: they check the latest state and `advance` the snapshot to it. A
+/// node that prunes state forgets old blocks after a while, so a snapshot older than that is
+/// moved to the best block, with a warning, and the read retried there.
+pub struct Snapshot {
+    block: Cell<H256>,
+    number: Cell<u32>,
+}
+
+impl Snapshot {
+    /// A snapshot at the current best block.
+    pub async fn take(chain: &Chain) -> BoxResult<Self> {
+        let (number, hash) = chain.best_block().await?;
+        debug!("Reading the IPS as of block {} ({:?})", number, hash);
+
+        Ok(Self {
+            block: Cell::new(hash),
+            number: Cell::new(number),
+        })
+    }
+
+    pub fn block(&self) -> H256 {
+        self.block.get()
+    }
+
+    /// The number of the snapshot's block.
+    pub fn number(&self) -> u32 {
+        self.number.get()
+    }
+
+    /// Move the snapshot to the current best block.
+    pub async fn advance(&self, chain: &Chain) -> BoxResult<()> {
+        let (number, hash) = chain.best_block().await?;
+        self.block.set(hash);
+        self.number.set(number);
+        Ok(())
+    }
+
+    /// Run `read` at the snapshot's block, or at the best block once the node pruned that.
+    pub async fn read<T, F, Fut>(&self, chain: &Chain, read: F) -> BoxResult<T>
+    where
+        F: Fn(H256) -> Fut,
+        Fut: Future<Output = BoxResult<T>>,
+    {
+        match read(self.block()).await {
+            Err(e) if is_pruned(&e.to_string()) => {
+                let (number, hash) = chain.best_block().await?;
+                output::warn(format!(
+                    "The node no longer has the state this fetch started from; continuing from block {}, which may hold a newer version of the repository",
+                    number
+                ));
+                self.block.set(hash);
+                self.number.set(number);
+                read(hash).await
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether `msg`, from a failed storage read, means the node discarded the block's state.
+fn is_pruned(msg: &str) -> bool {
+    let msg = msg.to_lowercase();
+
+    ["state already discarded", "unknown block", "state pruned"]
+        .iter()
+        .any(|pattern| msg.contains(pattern))
+}
+
+/// Say how long requests were held back by `chain_rps`, once the operation is over.
+impl Drop for Chain {
+    fn drop(&mut self) {
+        let limiter = match &self.limiter {
+            Some(limiter) if limiter.waited() >= Duration::from_secs(1) => limiter,
+            _ => return,
+        };
+
+        output::info(format!(
+            "Spent {:.1}s waiting on the chain request limit of {}/s; raise chain_rps or use a node of your own to go faster",
+            limiter.waited().as_secs_f64(),
+            limiter.rate()
+        ));
+    }
+}
+
+/// The fee in a `payment_queryInfo` answer.
+fn fee_of(info: &serde_json::Value) -> BoxResult<u128> {
+    // Depending on the node version the fee is a decimal string or a plain number
+    match &info["partialFee"] {
+        serde_json::Value::String(fee) => Ok(fee.parse()?),
+        serde_json::Value::Number(fee) => {
+            Ok(fee.as_u64().ok_or("Fee estimate out of range")? as u128)
+        }
+        _ => error!("Fee estimate missing from payment_queryInfo"),
+    }
+}
+
+/// The weight in a `payment_queryInfo` answer.
+fn weight_of(info: &serde_json::Value) -> BoxResult<u64> {
+    // A plain number before weights v2, its `ref_time` after
+    let weight = match &info["weight"] {
+        serde_json::Value::Object(fields) => fields
+            .get("ref_time")
+            .or_else(|| fields.get("refTime"))
+            .and_then(|weight| weight.as_u64()),
+        weight => weight.as_u64(),
+    };
+
+    Ok(weight.ok_or("Weight missing from payment_queryInfo")?)
+}
+
+/// Run `f` against the client `current` gives, calling `reconnect` and retrying up to
+/// `MAX_RECONNECTS` times when the connection to `endpoint` dropped.
+async fn retry_reconnecting<C, F, Fut, R, CurrentFut, ReconnectFut>(
+    endpoint: &str,
+    current: impl Fn() -> CurrentFut,
+    reconnect: impl Fn() -> ReconnectFut,
+    f: F,
+) -> BoxResult<R>
+where
+    F: Fn(C) -> Fut,
+    Fut: Future<Output = Result<R, subxt::Error>>,
+    CurrentFut: Future<Output = C>,
+    ReconnectFut: Future<Output = BoxResult<()>>,
+{
+    let mut reconnects = 0;
+
+    loop {
+        match f(current().await).await {
+            Ok(r) => return Ok(r),
+            Err(e) if is_connection_closed(&e) && reconnects < MAX_RECONNECTS => {
+                reconnects += 1;
+                debug!("Chain request failed: {}", e);
+                output::warn(format!(
+                    "Lost connection to {}, reconnecting ({}/{})",
+                    endpoint, reconnects, MAX_RECONNECTS
+                ));
+                reconnect().await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Whether `e` means the websocket went away, as opposed to the request itself failing.
+pub fn is_connection_closed(e: &subxt::Error) -> bool {
+    let msg = e.to_string().to_lowercase();
+
+    [
+        "restart",
+        "connection closed",
+        "connection reset",
+        "networking or low-level protocol error",
+        "subscription dropped",
+        "broken pipe",
+    ]
+    .iter()
+    .any(|pattern| msg.contains(pattern))
+}
+
+/// Check that IPF `id` is in `ids`, the data set of IPS `ips_id`, `None` when the IPS doesn't
+/// exist. Anyone can mint an IPF, so one outside of it can't be trusted to hold the repository.
+fn check_part_of(ids: Option<Vec<IpfId>>, ips_id: IpsId, id: IpfId) -> BoxResult<()> {
+    let ids = ids.ok_or(format!("IPS {ips_id} does not exist"))?;
+
+    if !ids.contains(&id) {
+        error!(format!("IPF {} is not part of IPS {}", id, ips_id))
+    }
+
+    Ok(())
+}
+
+/// How far the system clock at `local_ms` is from block `hash` made at `block_ms`, when more than
+/// `MAX_CLOCK_SKEW`.
+fn skew(local_ms: u128, block_ms: u128, hash: H256) -> Option<String> {
+    let (skew, direction) = match local_ms.checked_sub(block_ms) {
+        Some(ahead) => (ahead, "ahead of"),
+        None => (block_ms - local_ms, "behind"),
+    };
+
+    (skew > MAX_CLOCK_SKEW.as_millis()).then(|| {
+        format!(
+            "The system clock is {}s {} the chain's latest finalized block {:?}",
+            skew / 1000,
+            direction,
+            hash
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Run a request through `retry_reconnecting` that fails its first `failures` attempts with
+    /// `error`. Returns the result, which is the connection that answered, with the number of
+    /// attempts and reconnects made.
+    async fn fail_first(
+        failures: usize,
+        error: fn() -> subxt::Error,
+    ) -> (BoxResult<usize>, usize, usize) {
+        let (attempts, reconnects) = (&Cell::new(0), &Cell::new(0));
+
+        let result = retry_reconnecting(
+            "ws://127.0.0.1:9944",
+            || async move { reconnects.get() },
+            || async move {
+                reconnects.set(reconnects.get() + 1);
+                BoxResult::Ok(())
+            },
+            |connection| {
+                let attempt = attempts.get();
+                attempts.set(attempt + 1);
+                async move {
+                    match attempt < failures {
+                        true => Err(error()),
+                        false => Ok(connection),
+                    }
+                }
+            },
+        )
+        .await;
+
+        (result, attempts.get(), reconnects.get())
+    }
+
+    fn closed() -> subxt::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset by peer").into()
+    }
+
+    #[tokio::test]
+    async fn dropped_connections_are_retried_on_a_new_one() {
+        let (result, attempts, reconnects) = fail_first(MAX_RECONNECTS, closed).await;
+
+        assert_eq!(result.unwrap(), MAX_RECONNECTS);
+        assert_eq!((attempts, reconnects), (MAX_RECONNECTS + 1, MAX_RECONNECTS));
+    }
+
+    #[tokio::test]
+    async fn connections_dropping_past_the_limit_fail_the_request() {
+        let (result, attempts, reconnects) = fail_first(MAX_RECONNECTS + 1, closed).await;
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Connection reset by peer"));
+        assert_eq!((attempts, reconnects), (MAX_RECONNECTS + 1, MAX_RECONNECTS));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_not_retried() {
+        let (result, attempts, reconnects) = fail_first(1, || {
+            codec::Error::from("Not enough data to fill buffer").into()
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!((attempts, reconnects), (1, 0));
+    }
+
+    #[test]
+    fn dropped_websocket_is_told_from_failed_request() {
+        let closed = |kind, msg| is_connection_closed(&io::Error::new(kind, msg).into());
+
+        assert!(closed(
+            io::ErrorKind::ConnectionReset,
+            "Connection reset by peer"
+        ));
+        assert!(closed(io::ErrorKind::BrokenPipe, "Broken pipe"));
+        assert!(!closed(
+            io::ErrorKind::InvalidData,
+            "Unexpected end of data"
+        ));
+        assert!(!is_connection_closed(
+            &codec::Error::from("Not enough data to fill buffer").into()
+        ));
+    }
+
+    #[test]
+    fn ips_accounts_are_derived_like_the_pallet() {
+        let account = |ips_id| hex::encode(ips_account(IpsId(ips_id)));
+
+        assert_eq!(
+            account(0),
+            "4428d02abc62de4ac40093355b0c2dfcac68f02f879e8ad8a3d506cfc5eb03e5"
+        );
+        assert_eq!(
+            account(1),
+            "022f3b279cb92541677c750629aed2037403339ac260b78fea2a04662d6bc130"
+        );
+    }
+
+    #[test]
+    fn balances_are_shown_in_whole_tokens() {
+        let token = Token {
+            decimals: 12,
+            symbol: "TNKR".to_owned(),
+        };
+
+        assert_eq!(token.format(0), "0 TNKR");
+        assert_eq!(token.format(1_500_000_000_000), "1.5 TNKR");
+        assert_eq!(token.format(2_000_000_000_001), "2.000000000001 TNKR");
+    }
+
+    #[test]
+    fn replicas_and_children_block_pushes() {
+        let flags = |replica_of, parent| IpsFlags {
+            replica_of,
+            parent,
+            allow_replica: false,
+            default_permission: false,
+            unknown: vec![],
+            raw: String::new(),
+        };
+
+        assert_eq!(flags(None, None).push_blocker(IpsId(7)), None);
+
+        let blocker = flags(Some(IpsId(3)), None).push_blocker(IpsId(7)).unwrap();
+        assert!(blocker.contains("replica of IPS 3"), "{}", blocker);
+
+        let blocker = flags(None, Some(IpsId(2))).push_blocker(IpsId(7)).unwrap();
+        assert!(blocker.contains("push to IPS 2 instead"), "{}", blocker);
+    }
+
+    #[test]
+    fn confirmation_settings_are_kebab_case() {
+        assert_eq!(
+            serde_json::from_str::<Confirmation>("\"in-block\"").unwrap(),
+            Confirmation::InBlock
+        );
+        assert_eq!(
+            serde_json::to_string(&Confirmation::Finalized).unwrap(),
+            "\"finalized\""
+        );
+    }
+
+    #[test]
+    fn clocks_more_than_five_minutes_off_are_skewed() {
+        let block_ms = 1_700_000_000_000;
+        let hash = H256::zero();
+
+        assert_eq!(skew(block_ms + 300_000, block_ms, hash), None);
+        assert_eq!(skew(block_ms - 300_000, block_ms, hash), None);
+        assert_eq!(
+            skew(block_ms + 301_000, block_ms, hash),
+            Some(format!(
+                "The system clock is 301s ahead of the chain's latest finalized block {:?}",
+                hash
+            ))
+        );
+        assert!(skew(block_ms - 3_600_000, block_ms, hash)
+            .unwrap()
+            .starts_with("The system clock is 3600s behind"));
+    }
+
+    #[test]
+    fn only_ipfs_in_the_data_set_are_read() {
+        let ids = Some(vec![IpfId(3), IpfId(5)]);
+
+        assert!(check_part_of(ids.clone(), IpsId(1), IpfId(5)).is_ok());
+        assert_eq!(
+            check_part_of(ids, IpsId(1), IpfId(4))
+                .unwrap_err()
+                .to_string(),
+            "IPF 4 is not part of IPS 1"
+        );
+        assert_eq!(
+            check_part_of(None, IpsId(2), IpfId(4))
+                .unwrap_err()
+                .to_string(),
+            "IPS 2 does not exist"
+        );
+    }
+
+    #[test]
+    fn reads_of_discarded_state_are_recognized() {
+        assert!(is_pruned(
+            "Rpc error: State already discarded for BlockId::Hash(0x1234)"
+        ));
+        assert!(is_pruned(
+            "Client error: UnknownBlock: Unknown block 0x1234"
+        ));
+        assert!(!is_pruned("IPS 2 does not exist"));
+        assert!(!is_pruned("Connection reset by peer"));
+    }
+}
@@ -0,0 +1,1589 @@
+use crate::{
+    archive::{self, ArchiveFormat},
+    auth::{self, auth_flow, SigningContext, SigningScope},
+    cache,
+    chain::{Chain, Confirmation},
+    config,
+    dedup::dedup_report,
+    diff::{self, CommitReader, RefDiff},
+    doctor::{self, LiveProbes},
+    error,
+    export_call::{self, ExportedCall},
+    filter::SkippedBlobs,
+    format::{self, decode_artifact, Kind},
+    inspect::Artifact,
+    install::{self, Method},
+    journal::PushJournal,
+    meta::RepoMeta,
+    migrate,
+    object_cache::ObjectCache,
+    output,
+    primitives::{
+        BoxResult, Config, IpfId, IpsId, MultiObject, MultiObjectIndex, RepoData, LARGE_BLOB_MARKER,
+    },
+    redact,
+    remote_helper::{self, Session},
+    remote_url::{self, RemoteUrl},
+    repair::Refetch,
+    runtime::{ChainCall, Runtime, BUNDLES},
+    serve_http,
+    store::{default_store, ObjectStore},
+    transcript,
+    traversal::Limits,
+    util::{generate_cid, human_size},
+};
+use git2::{Oid, Repository};
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use subxt::tx::PairSigner;
+
+#[cfg(unix)]
+use crate::daemon;
+#[cfg(feature = "devnet")]
+use crate::devnet;
+
+/// The arguments of an `inv4-git` subcommand: positionals plus `--key value`/`--switch` options,
+/// where single-letter options may also be written `-k value`.
+#[derive(Debug, Default)]
+pub struct Flags {
+    pub positional: Vec<String>,
+    options: BTreeMap<String, Option<String>>,
+}
+
+impl Flags {
+    pub fn parse(args: &[String]) -> Self {
+        let mut flags = Self::default();
+        let mut args = args.iter().peekable();
+
+        while let Some(arg) = args.next() {
+            let option = arg
+                .strip_prefix("--")
+                .or_else(|| arg.strip_prefix('-').filter(|short| short.len() == 1));
+
+            match option {
+                Some(option) => match option.split_once('=') {
+                    Some((key, value)) => {
+                        flags.options.insert(key.to_owned(), Some(value.to_owned()));
+                    }
+                    None => {
+                        let value = args.next_if(|next| !next.starts_with("--")).cloned();
+                        flags.options.insert(option.to_owned(), value);
+                    }
+                },
+                None => flags.positional.push(arg.clone()),
+            }
+        }
+
+        flags
+    }
+
+    pub fn switch(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name).and_then(|value| value.as_deref())
+    }
+
+    pub fn required(&self, name: &str) -> BoxResult<&str> {
+        self.value(name)
+            .ok_or_else(|| format!("Missing required option --{}", name).into())
+    }
+}
+
+const USAGE: &str = "usage: inv4-git <command> [options]
+
+commands:
+    alias add <name> <ips id> | remove <name> | list
+                                                  Manage names usable in place of IPS ids in URLs
+    archive --ips <id> [--ref <name>] [--format tar|zip] -o <file>
+                                                  Export one snapshot without its history
+    cache info | clear [--chain <prefix>] [--ips <id>] [--dry-run] | gc
+                                                  Show or remove state kept per chain and IPS, or shrink the object cache
+    clean-state [--remote <name>] [--dry-run]     Remove what the helper keeps in .git/inv4
+    config init [--force] | validate | get <key> | set <key> <value>
+                                                  Create, check or edit the config file
+    daemon [--idle <seconds>]                     Keep connections open for helpers to forward fetches to
+    dedup-report --ips <id>                       Measure objects stored in more than one MultiObject
+    devnet bootstrap [--endpoint <url>]           Push a test repository to a new IPS on a dev chain (devnet builds)
+    diff --ips <id> --from <block> --to <block> [--commits] [--max-commits <n>] [--json]
+                                                  Show how refs changed between two blocks
+    doctor                                        Check that pushing and fetching can work here
+    dump-repodata (--ips <id> | --cid <cid>) [-o <file.json>]
+                                                  Export a repository index as JSON, e.g. for a bug report
+    fetch-blobs --ips <id> (--oid <sha1>[,...] | --remote <name>)
+                                                  Download blobs a filtered fetch left out
+    history --ips <id> [--ref <name>] [--json]    Show the pushes recorded in a repository
+    info --ips <id>                               Summarize a repository and the size of its index
+    install [--dir <path>] [--check]              Put the helper on PATH as git-remote-inv4
+    inspect-ipf --ipf <id> [--ips <id>] [--json]  Tell what an IPF holds and summarize it
+    ls --ips <id> [--json]                        List refs like `git ls-remote --symref`, with types
+    meta show --ips <id>                          Show a repository's name, description, license and homepage
+    meta set --ips <id> [--subasset <id>] [--name <name>] [--description <text>] [--license <spdx>] [--homepage <url>]
+             [--register <name>=<ips id>] [--unregister <name>] [--immortal]
+                                                  Publish them; an empty value clears a field
+    migrate --ips <id> [--apply] [--remove-legacy] [--subasset <id>] [--immortal]
+                                                  Re-encode artifacts predating format envelopes
+    push-state [show | finish | abandon] [--remote <name>]
+                                                  Show, settle or give up on a push exported for signing
+    repair-local --ips <id>                       Check local objects against a repository, replacing corrupt ones
+    replay-transcript <file>                      Replay a GIT_INV4_TRANSCRIPT recording against its answers
+    restore --repodata-cid <cid> [--index <file>] [--into <dir>]
+                                                  Rebuild a repository from IPFS alone
+    serve-http --ips <id> [--listen <addr>] [--refresh <seconds>] [--max-staleness <seconds>]
+                                                  Serve a read-only mirror over git's dumb HTTP protocol
+    submit-call <file> [--account <name>] [--remote <name>]
+                                                  Sign and submit a publish call a push exported";
+
+const HELP: &str = "git-remote-inv4 lets git push to and fetch from repositories stored on an INV4
+IP Set. git runs it for remotes with inv4:// URLs, which needs it on PATH under this name:
+
+    git remote add origin inv4://<ips id>
+    git push origin main
+
+Run directly, it takes a subcommand instead.";
+
+/// Run an `inv4-git` subcommand; `args` excludes the program name.
+pub async fn run(args: &[String]) -> BoxResult<()> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), Flags::parse(rest)),
+        None => {
+            eprintln!("{}\n\n{}", HELP, USAGE);
+            error!("No command given")
+        }
+    };
+
+    match command {
+        "--help" | "-h" | "help" => {
+            println!("{}\n\n{}", HELP, USAGE);
+            Ok(())
+        }
+        "--version" | "-V" => version(),
+        "alias" => alias(&rest).await,
+        "archive" => archive(&rest).await,
+        "cache" => cache(&rest),
+        "clean-state" => clean_state(&rest),
+        "config" => configure(&rest),
+        "daemon" => daemon(&rest).await,
+        "dedup-report" => dedup(&rest).await,
+        "devnet" => devnet(&rest).await,
+        "diff" => diff(&rest).await,
+        "doctor" => doctor().await,
+        "dump-repodata" => dump_repodata(&rest).await,
+        "fetch-blobs" => fetch_blobs(&rest).await,
+        "history" => history(&rest).await,
+        "info" => info(&rest).await,
+        "inspect-ipf" => inspect_ipf(&rest).await,
+        "install" => install(&rest),
+        "ls" => ls(&rest).await,
+        "meta" => meta(&rest).await,
+        "migrate" => migrate(&rest).await,
+        "push-state" => push_state(&rest).await,
+        "repair-local" => repair_local(&rest).await,
+        "replay-transcript" => replay_transcript(&rest).await,
+        "restore" => restore(&rest).await,
+        "serve-http" => serve_http(&rest).await,
+        "submit-call" => submit_call(&rest).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            error!(format!("Unknown command: {}", command))
+        }
+    }
+}
+
+fn version() -> BoxResult<()> {
+    println!("git-remote-inv4 {}", env!("CARGO_PKG_VERSION"));
+
+    let libgit2 = git2::Version::get().libgit2_version();
+    println!("libgit2 {}.{}.{}", libgit2.0, libgit2.1, libgit2.2);
+
+    for bundle in BUNDLES {
+        match bundle.runtime_version() {
+            Ok((spec_name, spec_version)) => {
+                println!("{} metadata: {} v{}", bundle.name, spec_name, spec_version)
+            }
+            Err(e) => println!("{} metadata: unreadable ({})", bundle.name, e),
+        }
+    }
+
+    Ok(())
+}
+
+async fn doctor() -> BoxResult<()> {
+    let checks = doctor::run(&LiveProbes).await;
+
+    for check in &checks {
+        let (mark, detail) = match &check.result {
+            Ok(detail) => ("ok  ", detail),
+            Err(detail) if check.required => ("FAIL", detail),
+            Err(detail) => ("warn", detail),
+        };
+
+        println!("{} {}: {}", mark, check.name, redact::text(detail));
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.required && check.result.is_err())
+        .count();
+    if failed > 0 {
+        error!(format!("{} required checks failed", failed))
+    }
+
+    Ok(())
+}
+
+/// Write the whole RepoData of `--ips`, or stored at `--cid`, as pretty JSON to stdout or `-o`.
+/// A CID held in the object cache is read from there without going to the network.
+async fn dump_repodata(flags: &Flags) -> BoxResult<()> {
+    let config = Config::load()?;
+
+    let repo_data = match (flags.value("ips"), flags.value("cid")) {
+        (Some(ips_id), None) => {
+            let store = default_store(&config).await?;
+            load_repo_data(ips_id.parse()?, store.as_ref()).await?.1
+        }
+        (None, Some(cid)) => {
+            let cached = match config.cache_max_size {
+                Some(max_size) => ObjectCache::open(max_size)?.get(cid),
+                None => None,
+            };
+
+            match cached {
+                Some(data) => match decode_artifact(data, Some(Kind::RepoData))? {
+                    Artifact::RepoData(repo_data) => repo_data,
+                    other => error!(format!("{} holds a {}, not a RepoData", cid, other.name())),
+                },
+                None => RepoData::from_cid(cid, default_store(&config).await?.as_ref()).await?,
+            }
+        }
+        _ => error!("Give one of --ips and --cid"),
+    };
+
+    let json = serde_json::to_string_pretty(&repo_data)?;
+    match flags.value("o").or_else(|| flags.value("output")) {
+        Some(path) => {
+            fs::write(path, json + "\n")?;
+            output::info(format!("Wrote {} to {}", repo_data.summary(), path));
+        }
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+async fn load_repo_data(ips_id: IpsId, store: &dyn ObjectStore) -> BoxResult<(Chain, RepoData)> {
+    let config = Config::load()?;
+    let chain = Chain::connect(&config).await?;
+    let repo_data =
+        RepoData::chain_get(ips_id, store, &chain, config.repodata_lookback_blocks, None).await?;
+
+    Ok((chain, repo_data))
+}
+
+async fn archive(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let output = Path::new(
+        flags
+            .value("o")
+            .map_or_else(|| flags.required("output"), Ok)?,
+    );
+    let format = match flags.value("format") {
+        Some(format) => format.parse()?,
+        None => ArchiveFormat::from_path(output),
+    };
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let (chain, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+
+    let ref_name = match flags.value("ref") {
+        Some(name) => name,
+        None => repo_data
+            .default_branch()
+            .ok_or("The repository has no branches")?,
+    };
+    let git_hash = repo_data
+        .refs
+        .get(ref_name)
+        .ok_or_else(|| format!("No ref named {}", ref_name))?;
+
+    let entries = archive::export(
+        &repo_data,
+        git_hash,
+        store.as_ref(),
+        &MultiObjectIndex::Chain {
+            chain: &chain,
+            ips_id,
+            at: None,
+        },
+        format,
+        output,
+        Limits::from_config(&config),
+    )
+    .await?;
+
+    println!(
+        "Wrote {} entries of {} ({}) to {}",
+        entries,
+        ref_name,
+        git_hash,
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn configure(flags: &Flags) -> BoxResult<()> {
+    let path = config::path()?;
+    let contents = || -> BoxResult<String> {
+        Ok(match path.exists() {
+            true => fs::read_to_string(&path)?,
+            false => String::new(),
+        })
+    };
+    let write = |contents: &str| -> BoxResult<()> {
+        fs::create_dir_all(
+            path.parent()
+                .ok_or("Config file path has no parent directory")?,
+        )?;
+        fs::write(&path, contents)?;
+        Ok(())
+    };
+
+    match flags
+        .positional
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()[..]
+    {
+        ["init"] => {
+            if path.exists() && !flags.switch("force") {
+                error!(format!(
+                    "{} already exists; pass --force to overwrite it",
+                    path.display()
+                ))
+            }
+
+            write(&config::template())?;
+            output::status(format!("Wrote {}", path.display()));
+        }
+        ["validate"] => {
+            if !path.exists() {
+                output::status(format!(
+                    "{} doesn't exist; the defaults apply",
+                    path.display()
+                ));
+                return Ok(());
+            }
+
+            let problems = config::problems(&contents()?);
+            for (line, problem) in &problems {
+                match line {
+                    Some(line) => eprintln!("{}:{}: {}", path.display(), line, problem),
+                    None => eprintln!("{}: {}", path.display(), problem),
+                }
+            }
+
+            match problems.len() {
+                0 => output::status(format!("{} is valid", path.display())),
+                count => error!(format!("Found {} problem(s) in {}", count, path.display())),
+            }
+        }
+        ["get", key] => {
+            if let Some(value) = config::get(&Config::load()?, key)? {
+                println!("{}", value);
+            }
+        }
+        ["set", key, value] => {
+            let contents = match path.exists() {
+                true => contents()?,
+                false => config::template(),
+            };
+            let updated = config::set(&contents, key, value)?;
+
+            config::parse(&updated, &path)?;
+            write(&updated)?;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            error!("Expected config init, validate, get <key> or set <key> <value>")
+        }
+    }
+
+    Ok(())
+}
+
+/// Manage the `aliases` of the config file, checking that an alias added names an existing IPS.
+async fn alias(flags: &Flags) -> BoxResult<()> {
+    let path = config::path()?;
+    let contents = match path.exists() {
+        true => fs::read_to_string(&path)?,
+        false => String::new(),
+    };
+
+    let updated = match flags
+        .positional
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()[..]
+    {
+        ["add", name, ips_id] => {
+            let ips_id: IpsId = ips_id
+                .parse()
+                .map_err(|_| format!("Invalid IPS id {}", ips_id))?;
+            remote_url::check_name(name)?;
+
+            let config = Config::load()?;
+            let chain = Chain::connect(&config).await?;
+            if chain.ipf_ids(ips_id).await?.is_none() {
+                error!(format!(
+                    "IPS {} does not exist on {}",
+                    ips_id,
+                    chain.name().await
+                ))
+            }
+
+            config::set_alias(&contents, name, Some(ips_id))?
+        }
+        ["remove", name] => {
+            if !Config::load()?.aliases.contains_key(name) {
+                error!(format!("No alias {}", name))
+            }
+
+            config::set_alias(&contents, name, None)?
+        }
+        ["list"] => {
+            for (name, ips_id) in Config::load()?.aliases {
+                println!("{}\t{}", name, ips_id);
+            }
+            return Ok(());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            error!("Expected alias add <name> <ips id>, remove <name> or list")
+        }
+    };
+
+    config::parse(&updated, &path)?;
+    fs::create_dir_all(
+        path.parent()
+            .ok_or("Config file path has no parent directory")?,
+    )?;
+    fs::write(&path, updated)?;
+
+    Ok(())
+}
+
+/// Show how the refs of the repository under `--ips` changed between the blocks `--from` and
+/// `--to`, with `--commits` listing the commits moved refs gained.
+async fn diff(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let max_commits: usize = match flags.value("max-commits") {
+        Some(max) => max.parse()?,
+        None => 100,
+    };
+    let list_commits = flags.switch("commits") || flags.value("max-commits").is_some();
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let from = diff::block_hash(flags.required("from")?, &chain).await?;
+    let to = diff::block_hash(flags.required("to")?, &chain).await?;
+    let old = diff::repo_data_at(ips_id, from, &chain, store.as_ref()).await?;
+    let new = diff::repo_data_at(ips_id, to, &chain, store.as_ref()).await?;
+
+    let mut diffs: Vec<RefDiff> = diff::compare(&old, &new)
+        .into_iter()
+        .map(RefDiff::from)
+        .collect();
+
+    if list_commits {
+        let index = MultiObjectIndex::Chain {
+            chain: &chain,
+            ips_id,
+            at: None,
+        };
+        let mut reader = CommitReader::new(&new, index, store.as_ref());
+
+        for diff in &mut diffs {
+            if let (Some(old), Some(new)) = (&diff.old, &diff.new) {
+                output::status(format!("Listing commits of {}", diff.name));
+                let (commits, truncated) = reader.commits_between(old, new, max_commits).await?;
+                diff.commits = commits;
+                diff.truncated = truncated;
+            }
+        }
+        output::clear_status();
+    }
+
+    if flags.switch("json") {
+        let listing = serde_json::json!({
+            "from": format!("{:?}", from),
+            "to": format!("{:?}", to),
+            "refs": diffs,
+        });
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
+    }
+
+    if diffs.is_empty() {
+        println!("No refs changed");
+    }
+
+    for diff in diffs {
+        println!(
+            "{} {} -> {}",
+            diff.name,
+            diff.old.as_deref().unwrap_or("(new)"),
+            diff.new.as_deref().unwrap_or("(deleted)")
+        );
+
+        for commit in &diff.commits {
+            println!(
+                "    {} {} ({})",
+                &commit.sha[..7],
+                commit.subject,
+                commit.author
+            );
+        }
+
+        if diff.truncated {
+            println!("    ... and more; raise --max-commits to list them");
+        }
+    }
+
+    Ok(())
+}
+
+async fn history(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let ref_filter = flags.value("ref");
+
+    let store = default_store(&Config::load()?).await?;
+    let (_, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+
+    let records: Vec<_> = repo_data
+        .history
+        .iter()
+        .rev()
+        .filter(|record| {
+            ref_filter.map_or(true, |name| {
+                record.refs_changed.iter().any(|change| change.name == name)
+            })
+        })
+        .collect();
+
+    if flags.switch("json") {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+
+    if records.is_empty() {
+        println!("No pushes recorded");
+    }
+
+    for record in records {
+        println!(
+            "block {} ({}) by {}",
+            record.block_number, record.block_hash, record.pusher
+        );
+
+        if let Some(message) = &record.message {
+            for line in message.lines() {
+                println!("    | {}", line);
+            }
+        }
+
+        for change in &record.refs_changed {
+            println!(
+                "    {} {} -> {}",
+                change.name,
+                change.old.as_deref().unwrap_or("(new)"),
+                change.new.as_deref().unwrap_or("(deleted)")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Rebuild a repository from a RepoData CID and a sidecar index of `<MultiObject hash> <CID>`
+/// lines, for when the chain can't be reached.
+/// Check every object the current repository has of the refs of the repository under `--ips`,
+/// downloading corrupt and missing ones again.
+async fn repair_local(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let (chain, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+    let index = MultiObjectIndex::Chain {
+        chain: &chain,
+        ips_id,
+        at: None,
+    };
+
+    let mut repo = Repository::open_from_env()?;
+    store.connect_hints(&repo_data.swarm_hints).await;
+
+    let mut refetch = Refetch::default();
+    for (name, git_hash) in &repo_data.refs {
+        output::status(format!("Checking {}", name));
+
+        repo_data
+            .fetch_to_ref_from_str(
+                git_hash,
+                name,
+                &mut repo,
+                store.as_ref(),
+                &index,
+                None,
+                Some(&mut refetch),
+                None,
+                &config,
+            )
+            .await?;
+    }
+
+    output::clear_status();
+    refetch.report();
+
+    if !refetch.unrepairable.is_empty() {
+        error!("Some corrupt objects could not be repaired")
+    }
+
+    Ok(())
+}
+
+/// Download blobs a filtered fetch left out: those given with `--oid`, or all of those listed for
+/// `--remote`.
+async fn fetch_blobs(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let mut repo = Repository::open_from_env()?;
+    let mut skipped = flags
+        .value("remote")
+        .map(|remote| SkippedBlobs::load(&remote_helper::state_dir(&repo, remote)));
+
+    let oids: Vec<Oid> = match (flags.value("oid"), &skipped) {
+        (Some(list), _) => list
+            .split(',')
+            .map(|oid| oid.trim().parse())
+            .collect::<Result<_, _>>()?,
+        (None, Some(skipped)) => skipped.blobs.keys().copied().collect(),
+        (None, None) => error!("Missing --oid or --remote"),
+    };
+
+    if oids.is_empty() {
+        output::info("No blobs were left out");
+        return Ok(());
+    }
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let (chain, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+    let index = MultiObjectIndex::Chain {
+        chain: &chain,
+        ips_id,
+        at: None,
+    };
+    store.connect_hints(&repo_data.swarm_hints).await;
+
+    for (i, oid) in oids.iter().enumerate() {
+        output::status(format!("[{}/{}] Fetching blob {}", i + 1, oids.len(), oid));
+
+        let git_hash = oid.to_string();
+        repo_data
+            .fetch_to_ref_from_str(
+                &git_hash,
+                &git_hash,
+                &mut repo,
+                store.as_ref(),
+                &index,
+                None,
+                None,
+                None,
+                &config,
+            )
+            .await?;
+    }
+
+    output::clear_status();
+
+    if let Some(skipped) = &mut skipped {
+        skipped.prune(&repo)?;
+        skipped.save()?;
+    }
+
+    output::info(format!("Fetched {} blobs", oids.len()));
+
+    Ok(())
+}
+
+/// Report or remove the state kept per chain and IPS outside of clones, optionally only that of
+/// chains whose directory starts with `--chain` and of IPS `--ips`, or shrink the object cache.
+fn cache(flags: &Flags) -> BoxResult<()> {
+    let ips_id = flags.value("ips").map(str::parse::<IpsId>).transpose()?;
+    let usage = cache::usage(flags.value("chain"), ips_id)?;
+
+    match flags
+        .positional
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()[..]
+    {
+        ["info"] => {
+            println!("{}", cache::root()?.display());
+            let (entries, size) = ObjectCache::open(u64::MAX)?.usage()?;
+            println!(
+                "    objects: {} cached, {}",
+                entries,
+                human_size(size as usize)
+            );
+            if usage.is_empty() {
+                println!("No state kept");
+            }
+            for entry in &usage {
+                println!(
+                    "    chain {}  IPS {:<8} {} files, {}",
+                    entry.chain,
+                    entry.ips_id,
+                    entry.files,
+                    human_size(entry.bytes)
+                );
+            }
+        }
+        ["clear"] => {
+            for entry in &usage {
+                println!("{}", entry.path.display());
+
+                if entry.path.join("migrations").is_dir() {
+                    output::warn(format!(
+                        "{} journals a migration; without it, an interrupted migration of IPS {} starts over",
+                        entry.path.display(),
+                        entry.ips_id
+                    ));
+                }
+            }
+
+            if flags.switch("dry-run") {
+                return Ok(());
+            }
+
+            for entry in &usage {
+                fs::remove_dir_all(&entry.path)?;
+            }
+            output::info(format!(
+                "Removed {} files",
+                usage.iter().map(|entry| entry.files).sum::<usize>()
+            ));
+        }
+        ["gc"] => {
+            let max_size = Config::load()?.cache_max_size.unwrap_or(0);
+            let report = ObjectCache::open(max_size)?.gc()?;
+
+            if report.orphans > 0 {
+                output::info(format!(
+                    "Dropped {} entries left incomplete",
+                    report.orphans
+                ));
+            }
+            output::info(format!(
+                "Evicted {} objects ({}), kept {} ({} of {})",
+                report.evicted,
+                human_size(report.freed as usize),
+                report.kept,
+                human_size(report.size as usize),
+                human_size(max_size as usize)
+            ));
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            error!("usage: inv4-git cache info | clear [--chain <prefix>] [--ips <id>] [--dry-run] | gc")
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the state kept between commands in the current repository, for one remote or all of
+/// them, or only list it with `--dry-run`.
+fn clean_state(flags: &Flags) -> BoxResult<()> {
+    let repo = Repository::open_from_env()?;
+    let dir = match flags.value("remote") {
+        Some(remote) => remote_helper::state_dir(&repo, remote),
+        None => repo.path().join("inv4"),
+    };
+
+    if !dir.exists() {
+        output::info(format!("No state in {}", dir.display()));
+        return Ok(());
+    }
+
+    let mut files = vec![];
+    list_files(&dir, &mut files)?;
+
+    for file in &files {
+        println!("{}", file.display());
+
+        if file.ends_with("pending-ipfs") && fs::metadata(file)?.len() > 0 {
+            output::warn(format!(
+                "{} lists IPFs minted by an unpublished push; without it, the next push won't burn them",
+                file.display()
+            ));
+        }
+    }
+
+    if flags.switch("dry-run") {
+        return Ok(());
+    }
+
+    fs::remove_dir_all(&dir)?;
+    output::info(format!("Removed {} files", files.len()));
+
+    Ok(())
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> BoxResult<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        match path.is_dir() {
+            true => list_files(&path, files)?,
+            false => files.push(path),
+        }
+    }
+
+    Ok(())
+}
+
+async fn restore(flags: &Flags) -> BoxResult<()> {
+    let repo_data_cid = flags.required("repodata-cid")?;
+    // Only needed for MultiObjects pushed before RepoData recorded their CIDs
+    let index = match flags.value("index") {
+        Some(path) => MultiObjectIndex::from_sidecar(&fs::read_to_string(path)?)?,
+        None => MultiObjectIndex::Cids(BTreeMap::new()),
+    };
+    let dir = flags.value("into").unwrap_or(".");
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let repo_data = RepoData::from_cid(repo_data_cid, store.as_ref()).await?;
+
+    let mut repo = Repository::open(dir).or_else(|_| Repository::init(dir))?;
+    store.connect_hints(&repo_data.swarm_hints).await;
+
+    for (name, git_hash) in &repo_data.refs {
+        output::status(format!("Restoring {}", name));
+
+        repo_data
+            .fetch_to_ref_from_str(
+                git_hash,
+                name,
+                &mut repo,
+                store.as_ref(),
+                &index,
+                None,
+                None,
+                None,
+                &config,
+            )
+            .await?;
+
+        // No git fetch is driving us here, so nothing else will create the refs
+        repo.reference(name, Oid::from_str(git_hash)?, true, "inv4-git restore")?;
+    }
+
+    // Once their targets exist
+    for (name, target) in &repo_data.symrefs {
+        if name != "HEAD" && repo_data.refs.contains_key(target) {
+            repo.reference_symbolic(name, target, true, "inv4-git restore")?;
+        }
+    }
+
+    let head = repo_data.default_branch();
+
+    if let Some(head) = head {
+        repo.set_head(head)?;
+    }
+
+    println!(
+        "Restored {} refs ({} objects) into {}{}",
+        repo_data.refs.len(),
+        repo_data.objects.len(),
+        dir,
+        head.map(|head| format!(", HEAD at {}", head))
+            .unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+async fn info(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+
+    let store = default_store(&Config::load()?).await?;
+    let (chain, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+
+    let (_, size) = repo_data.compressed();
+    let multi_objects: BTreeSet<_> = repo_data
+        .objects
+        .values()
+        .filter(|hash| *hash != LARGE_BLOB_MARKER)
+        .collect();
+
+    println!("Repository:     IPS {}", ips_id);
+    match chain.find_ipf(ips_id, b"RepoData").await? {
+        Some(ipf) => println!("Index IPF:      {}", ipf.id),
+        None => println!("Index IPF:      none, nothing pushed yet"),
+    }
+    println!("Index size:     {}", size);
+    println!("Refs:           {}", repo_data.refs.len());
+    println!(
+        "Objects:        {} in {} MultiObjects",
+        repo_data.objects.len(),
+        multi_objects.len()
+    );
+    println!(
+        "Large blobs:    {} ({})",
+        repo_data.large_blobs.len(),
+        human_size(
+            repo_data
+                .large_blobs
+                .values()
+                .map(|blob| blob.size as usize)
+                .sum()
+        )
+    );
+    println!("Pushes logged:  {}", repo_data.history.len());
+
+    Ok(())
+}
+
+/// Put the helper on PATH under the name git runs, or with `--check` only report whether it is.
+fn install(flags: &Flags) -> BoxResult<()> {
+    let state = install::State::probe()?;
+
+    if flags.switch("check") {
+        println!("Running:        {}", state.exe.display());
+        match &state.on_path {
+            Some(path) if state.current => {
+                println!("On PATH:        {}, this executable", path.display())
+            }
+            Some(path) => println!("On PATH:        {}, a different executable", path.display()),
+            None => println!(
+                "On PATH:        no {}; run `inv4-git install`",
+                install::binary_name()
+            ),
+        }
+        return Ok(());
+    }
+
+    let method = Method::native();
+    if method == Method::Symlink && install::is_build_output(&state.exe) {
+        output::warn(format!(
+            "{} is cargo build output, so `cargo clean` breaks the link; `cargo install --path .` installs a lasting copy",
+            state.exe.display()
+        ));
+    }
+
+    let dir = install::target_dir(
+        flags.value("dir").map(Path::new),
+        &install::candidate_dirs(),
+    )?;
+    let dest = install::install(&state.exe, &dir, method)?;
+    output::info(format!("Installed {}", dest.display()));
+
+    if !install::is_on_path(&dir) {
+        let (line, profile) = install::profile_line(&dir);
+        output::warn(format!(
+            "{} is not on PATH; add this line to {}:\n    {}",
+            dir.display(),
+            profile,
+            line
+        ));
+    } else if let Some(first) = install::State::probe()?.on_path {
+        if !install::same_file(&first, &dest) {
+            output::warn(format!(
+                "git runs {} instead, which comes first on PATH",
+                first.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Feed what git sent in each session of a transcript back through the protocol handler, with
+/// the recorded answers standing in for the chain and store, and check the helper still answers
+/// git the same.
+async fn replay_transcript(flags: &Flags) -> BoxResult<()> {
+    let path = match &flags.positional[..] {
+        [path] => path,
+        _ => error!("usage: inv4-git replay-transcript <file>"),
+    };
+
+    let recordings = transcript::parse(&fs::read_to_string(path)?)?;
+    if recordings.is_empty() {
+        error!(format!("{} records no sessions", path))
+    }
+
+    let mut diverged = 0;
+    for (i, recording) in recordings.into_iter().enumerate() {
+        let session = recording.session.clone();
+        let commands = recording.input.len();
+
+        match transcript::replay(recording).await {
+            Ok(None) => println!(
+                "Session {} ({}): {} lines replayed, answers match",
+                i + 1,
+                session,
+                commands
+            ),
+            Ok(Some(difference)) => {
+                diverged += 1;
+                println!("Session {} ({}): differs at {}", i + 1, session, difference);
+            }
+            Err(e) => {
+                diverged += 1;
+                println!("Session {} ({}): failed: {}", i + 1, session, e);
+            }
+        }
+    }
+
+    if diverged > 0 {
+        error!(format!("{} sessions did not replay the same", diverged))
+    }
+
+    Ok(())
+}
+
+/// Tell what an IPF holds and summarize it. With `--ips`, the IPF has to be in that IPS, and the
+/// refs of its repository reaching the objects in it are listed too.
+async fn inspect_ipf(flags: &Flags) -> BoxResult<()> {
+    let ipf_id: IpfId = flags.required("ipf")?.parse()?;
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let ips_id: Option<IpsId> = flags.value("ips").map(str::parse).transpose()?;
+    let ipf = match ips_id {
+        Some(ips_id) => chain.ipf_of(ips_id, ipf_id, None).await?,
+        None => chain
+            .runtime()
+            .ipf(&chain, ipf_id, None)
+            .await?
+            .ok_or_else(|| format!("No IPF {}", ipf_id))?,
+    };
+    let (artifact, format) = Artifact::fetch(&ipf, store.as_ref()).await?;
+
+    let repo_data = match ips_id {
+        Some(ips_id) => Some(
+            RepoData::chain_get(
+                ips_id,
+                store.as_ref(),
+                &chain,
+                config.repodata_lookback_blocks,
+                None,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let index = MultiObjectIndex::Chain {
+        chain: &chain,
+        ips_id: ips_id.unwrap_or_default(),
+        at: None,
+    };
+
+    let summary = artifact
+        .summarize(repo_data.as_ref(), store.as_ref(), &index)
+        .await?;
+
+    let format = match artifact {
+        Artifact::LargeBlob { .. } => "raw UnixFS file".to_owned(),
+        _ => format::describe(format.as_ref()),
+    };
+
+    if flags.switch("json") {
+        let mut summary = serde_json::to_value(&summary)?;
+        summary["format"] = format.into();
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        println!("IPF:            {}", ipf_id);
+        println!("Format:         {}", format);
+        print!("{}", summary);
+    }
+
+    Ok(())
+}
+
+/// Print the refs as `git ls-remote --symref` would, with each ref's type and the block of the
+/// push that set it as extra tab-separated columns.
+async fn ls(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+
+    let store = default_store(&Config::load()?).await?;
+    let (_, repo_data) = load_repo_data(ips_id, store.as_ref()).await?;
+
+    let head = repo_data.default_branch();
+    let refs = repo_data.ref_listing();
+
+    if flags.switch("json") {
+        let listing = serde_json::json!({ "head": head, "refs": refs });
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
+    }
+
+    if let Some(head) = head {
+        println!("ref: {}\tHEAD", head);
+        println!("{}\tHEAD", repo_data.refs[head]);
+    }
+
+    for listing in refs {
+        if let Some(target) = &listing.symref {
+            println!("ref: {}\t{}", target, listing.name);
+        }
+
+        println!(
+            "{}\t{}\t{}\t{}",
+            listing.target,
+            listing.name,
+            listing.kind,
+            listing
+                .block_number
+                .map_or_else(|| "-".to_owned(), |block| block.to_string())
+        );
+
+        if let Some(peeled) = listing.peeled {
+            println!("{}\t{}^{{}}", peeled, listing.name);
+        }
+    }
+
+    Ok(())
+}
+
+async fn meta(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let mut config = Config::load()?;
+    config.immortal_transactions |= flags.switch("immortal");
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    match flags.positional.first().map(String::as_str) {
+        Some("show") => match RepoMeta::load(&chain, store.as_ref(), ips_id).await? {
+            Some(meta) => {
+                let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+
+                println!("Name:         {}", field(meta.name));
+                println!("Description:  {}", field(meta.description));
+                println!("License:      {}", field(meta.license));
+                println!("Homepage:     {}", field(meta.homepage));
+
+                if !meta.names.is_empty() {
+                    println!("Names:");
+                    for (name, ips_id) in &meta.names {
+                        println!("    {}\t{}", name, ips_id);
+                    }
+                }
+            }
+            None => println!("IPS {} has no repository details", ips_id),
+        },
+        Some("set") => {
+            let mut meta = match RepoMeta::load(&chain, store.as_ref(), ips_id).await {
+                Ok(meta) => meta.unwrap_or_default(),
+                Err(e) => {
+                    output::warn(format!("{}; starting from empty details", e));
+                    RepoMeta::default()
+                }
+            };
+            let before = meta.clone();
+
+            for (option, field) in [
+                ("name", &mut meta.name),
+                ("description", &mut meta.description),
+                ("license", &mut meta.license),
+                ("homepage", &mut meta.homepage),
+            ] {
+                if let Some(value) = flags.value(option) {
+                    *field = Some(value.to_owned()).filter(|value| !value.is_empty());
+                }
+            }
+
+            if let Some(entry) = flags.value("register") {
+                let (name, ips_id) = entry
+                    .split_once('=')
+                    .ok_or("Expected --register <name>=<ips id>")?;
+                remote_url::check_name(name)?;
+                meta.names.insert(name.to_owned(), ips_id.parse()?);
+            }
+            if let Some(name) = flags.value("unregister") {
+                meta.names.remove(name);
+            }
+
+            if meta == before {
+                output::info("Repository details unchanged");
+                return Ok(());
+            }
+
+            let subasset_id = flags.value("subasset").map(str::parse).transpose()?;
+            let context = SigningContext {
+                action: "details update",
+                ips_id,
+                repository: before.name.clone(),
+                chain: chain.name().await,
+                confirm: config.confirm_signing,
+            };
+            let _signing = SigningScope::enter();
+            meta.publish(&chain, store.as_ref(), ips_id, subasset_id, &context)
+                .await?;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            error!("Expected meta show or meta set")
+        }
+    }
+
+    Ok(())
+}
+
+/// Show what in a repository predates format envelopes and, with `--apply`, re-encode it.
+async fn migrate(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let remove_legacy = flags.switch("remove-legacy");
+    let mut config = Config::load()?;
+    config.immortal_transactions |= flags.switch("immortal");
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let (repo_data, plan) = migrate::plan(ips_id, &chain, store.as_ref()).await?;
+    print!("{}", plan);
+
+    if plan.is_empty(remove_legacy) {
+        output::info("Nothing to migrate");
+        return Ok(());
+    }
+
+    if !flags.switch("apply") {
+        output::info("Dry run; pass --apply to migrate");
+        return Ok(());
+    }
+
+    // Kept in the cache directory, as a migration needn't run in a clone of the repository
+    let journal = PushJournal::in_dir(&cache::migrations_dir(&chain.genesis_hash().await, ips_id)?);
+
+    let repository = match RepoMeta::load(&chain, store.as_ref(), ips_id).await {
+        Ok(meta) => meta.and_then(|meta| meta.name),
+        Err(e) => {
+            debug!("Could not read RepoMeta: {}", e);
+            None
+        }
+    };
+    let context = SigningContext {
+        action: "migration",
+        ips_id,
+        repository,
+        chain: chain.name().await,
+        confirm: config.confirm_signing,
+    };
+    let _signing = SigningScope::enter();
+
+    migrate::apply(
+        &plan,
+        repo_data,
+        remove_legacy,
+        flags.value("subasset").map(str::parse).transpose()?,
+        &config,
+        &chain,
+        store.as_ref(),
+        &journal,
+        &context,
+    )
+    .await
+}
+
+/// Serve helpers' fetches over connections kept open, until none connected for `--idle` seconds.
+async fn daemon(flags: &Flags) -> BoxResult<()> {
+    #[cfg(unix)]
+    {
+        let idle = match flags.value("idle") {
+            Some(seconds) => Duration::from_secs(seconds.parse()?),
+            None => daemon::DEFAULT_IDLE,
+        };
+
+        daemon::run(idle).await
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = flags;
+        error!("The daemon listens on a unix socket, which this platform doesn't have")
+    }
+}
+
+async fn serve_http(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+    let listen = flags
+        .value("listen")
+        .unwrap_or(serve_http::DEFAULT_LISTEN)
+        .parse()?;
+    let refresh = match flags.value("refresh") {
+        Some(seconds) => Duration::from_secs(seconds.parse()?),
+        None => serve_http::DEFAULT_REFRESH,
+    };
+    let max_staleness = match flags.value("max-staleness") {
+        Some(seconds) => Duration::from_secs(seconds.parse()?),
+        None => serve_http::DEFAULT_MAX_STALENESS,
+    };
+
+    serve_http::run(ips_id, listen, refresh, max_staleness).await
+}
+
+/// Sign and submit the publish call a push exported to the file given, as a signatory of its
+/// IPS, see `export_call`. With `--remote`, the push is then settled in this clone, as
+/// `push-state finish` would.
+async fn submit_call(flags: &Flags) -> BoxResult<()> {
+    let path = Path::new(
+        flags
+            .positional
+            .first()
+            .ok_or("Expected the file a push exported its call to")?,
+    );
+    let exported = ExportedCall::read(path)?;
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let genesis_hash = format!("{:?}", chain.genesis_hash().await);
+    if exported.genesis_hash != genesis_hash {
+        error!(format!(
+            "{} was composed for the chain with genesis hash {}, but {} is {}",
+            path.display(),
+            exported.genesis_hash,
+            config.chain_endpoint,
+            genesis_hash
+        ))
+    }
+
+    println!("Call:      {} {}", exported.call, exported.call_hash);
+    println!("IPS:       {}", exported.ips_id);
+    println!("Pushed by: {}", exported.pusher);
+    for (name, tip) in &exported.refs {
+        println!("    {} -> {}", name, tip.as_deref().unwrap_or("(deleted)"));
+    }
+
+    let repository = match RepoMeta::load(&chain, store.as_ref(), exported.ips_id).await {
+        Ok(meta) => meta.and_then(|meta| meta.name),
+        Err(e) => {
+            debug!("Could not read RepoMeta: {}", e);
+            None
+        }
+    };
+    let context = SigningContext {
+        action: "push",
+        ips_id: exported.ips_id,
+        repository,
+        chain: chain.name().await,
+        confirm: config.confirm_signing,
+    };
+    let _signing = SigningScope::enter();
+    let unlocked = auth_flow(flags.value("account"), &context).await?;
+
+    output::status(format!("Publishing to repository {}...", exported.ips_id));
+    let included = chain
+        .submit(
+            &ChainCall::encoded(export_call::CALL, exported.call_data()?),
+            &PairSigner::new(unlocked.pair),
+        )
+        .await?;
+    if let Some(credential) = &unlocked.credential {
+        auth::renew(credential);
+    }
+
+    let included = match config.confirmation {
+        Confirmation::Finalized => chain.finalize(included).await?,
+        Confirmation::InBlock => included,
+    };
+    output::info(format!("Published in block {:?}", included.block_hash));
+
+    match flags.value("remote") {
+        Some(remote_name) => finish_exported(remote_name).await,
+        None => Ok(()),
+    }
+}
+
+/// Show the push to `--remote` (`origin` by default) that was exported for signing, settle it
+/// once its call landed, or give up on it.
+async fn push_state(flags: &Flags) -> BoxResult<()> {
+    let remote_name = flags.value("remote").unwrap_or("origin");
+    let repo = Repository::open_from_env()?;
+    let journal = PushJournal::open(&repo, &remote_helper::state_dir(&repo, remote_name));
+
+    let exported = match journal.exported() {
+        Some(exported) => exported,
+        None => {
+            output::info(format!(
+                "No push to {} is waiting for its call to land",
+                remote_name
+            ));
+            return Ok(());
+        }
+    };
+
+    match flags.positional.first().map(String::as_str) {
+        None | Some("show") => println!("{}", exported.to_json()?),
+        Some("finish") => finish_exported(remote_name).await?,
+        Some("abandon") => {
+            // Burned by the next push, unless the call lands after all
+            for ipf_id in exported.ipf_ids() {
+                journal.record(ipf_id)?;
+            }
+            journal.clear_exported()?;
+
+            output::info(format!(
+                "Abandoned call {}; its {} IPFs are burned by the next push unless it lands first",
+                exported.call_hash,
+                exported.ipf_ids().len()
+            ));
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            error!("Expected push-state show, finish or abandon")
+        }
+    }
+
+    Ok(())
+}
+
+/// Settle the exported push to `remote_name` of the current repository, failing while its call
+/// hasn't landed.
+async fn finish_exported(remote_name: &str) -> BoxResult<()> {
+    let repo = Repository::open_from_env()?;
+    let url: RemoteUrl = repo
+        .find_remote(remote_name)?
+        .url()
+        .ok_or_else(|| format!("Remote {} has no URL", remote_name))?
+        .parse()?;
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let session = Session::new(remote_name.to_owned(), url, config, store).await?;
+
+    if !session.finish_exported().await? {
+        error!(format!(
+            "The exported call hasn't landed on IPS {} yet; try again once it was submitted",
+            session.url.ips_id
+        ))
+    }
+
+    output::info(format!(
+        "The exported push to {} landed; its remote-tracking refs are updated",
+        remote_name
+    ));
+    Ok(())
+}
+
+/// Create an IPS on a development chain as //Alice, push a fixture repository to it and clone it
+/// back, printing the IPS id.
+async fn devnet(flags: &Flags) -> BoxResult<()> {
+    #[cfg(feature = "devnet")]
+    {
+        if flags
+            .positional
+            .iter()
+            .map(String::as_str)
+            .ne(["bootstrap"])
+        {
+            eprintln!("{}", USAGE);
+            error!("Expected devnet bootstrap")
+        }
+
+        let endpoint = flags.value("endpoint").unwrap_or(devnet::DEFAULT_ENDPOINT);
+        let ips_id = devnet::bootstrap(endpoint).await?;
+        println!("{}", ips_id);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "devnet"))]
+    {
+        let _ = flags;
+        error!("This build leaves devnet out; build with `--features devnet` to use it")
+    }
+}
+
+async fn dedup(flags: &Flags) -> BoxResult<()> {
+    let ips_id: IpsId = flags.required("ips")?.parse()?;
+
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let ipf_ids = chain.ipf_ids(ips_id).await?.unwrap_or_default();
+    let mut stored = vec![];
+
+    // One MultiObject at a time, keeping only hashes and sizes
+    for (i, &id) in ipf_ids.iter().enumerate() {
+        let ipf = chain.ipf(id).await?;
+        // Large blobs are stored once each, and far too big to download only to skip them
+        if ipf.metadata == b"RepoData" || ipf.metadata.starts_with(LARGE_BLOB_MARKER.as_bytes()) {
+            continue;
+        }
+
+        output::status(format!("[{}/{}] Reading IPF {}", i + 1, ipf_ids.len(), id));
+
+        let cid = generate_cid(ipf.data)?.to_string();
+        let header = match MultiObject::get_header(&cid, store.as_ref()).await {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("IPF {} is not a MultiObject: {}", id, e);
+                continue;
+            }
+        };
+
+        let multi_object_hash = header.hash;
+        stored.extend(
+            header.entries.into_iter().map(|(git_hash, entry)| {
+                (multi_object_hash.clone(), git_hash, entry.size as usize)
+            }),
+        );
+    }
+
+    output::clear_status();
+
+    let report = dedup_report(stored);
+
+    println!(
+        "Stored:      {} in {} MultiObjects",
+        human_size(report.total_bytes),
+        report.multi_objects
+    );
+    println!("Unique:      {}", human_size(report.unique_bytes));
+    println!(
+        "Duplicated:  {} (ratio {:.2}x)",
+        human_size(report.total_bytes - report.unique_bytes),
+        report.ratio()
+    );
+
+    if !report.largest_duplicates.is_empty() {
+        println!("\nLargest duplicated objects:");
+    }
+
+    for object in &report.largest_duplicates {
+        println!(
+            "    {}  {:>10}  in {} MultiObjects",
+            object.git_hash,
+            human_size(object.size),
+            object.copies
+        );
+    }
+
+    Ok(())
+}
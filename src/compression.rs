@@ -1,7 +1,6 @@
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 
 pub fn compress_data(data: Vec<u8>) -> Vec<u8> {
-    eprintln!("Compressing data...");
     let mut compressor = brotli::CompressorReader::new(data.as_slice(), 4096, 11u32, 22u32);
 
     let mut v = vec![];
@@ -10,11 +9,33 @@ pub fn compress_data(data: Vec<u8>) -> Vec<u8> {
     v
 }
 
-pub fn decompress_data(data: Vec<u8>) -> Vec<u8> {
-    let mut decompressor = brotli::Decompressor::new(data.as_slice(), 4096);
+/// Decompress `data`, which may not be brotli at all: it is downloaded, so anyone could have
+/// written it.
+pub fn try_decompress_data(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressor = brotli::Decompressor::new(data, 4096);
 
     let mut v = vec![];
-    decompressor.read_to_end(&mut v).unwrap();
+    decompressor.read_to_end(&mut v)?;
 
-    v
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_data_round_trips() {
+        let data = b"tree 0\0".repeat(100);
+        let compressed = compress_data(data.clone());
+
+        assert!(compressed.len() < data.len());
+        assert_eq!(try_decompress_data(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn data_that_isnt_brotli_is_an_error() {
+        assert!(try_decompress_data(b"not brotli").is_err());
+        assert!(try_decompress_data(&[0xff; 16]).is_err());
+    }
 }
@@ -0,0 +1,651 @@
+//! The config file: loading it with errors that point into it, and generating, checking and
+//! editing it for the `config` command.
+//!
+//! The keys come from `Config`'s own `Deserialize` impl and the defaults from `Config::default`,
+//! so the template and the checks follow the struct.
+
+use crate::{
+    error,
+    primitives::{BoxResult, Config, IpsId},
+};
+use dirs::config_dir;
+use serde::{
+    de::{self, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use std::path::{Path, PathBuf};
+
+/// What each key does, and an example value for keys that are unset by default.
+const DOCS: &[(&str, &str, Option<&str>)] = &[
+    ("chain_endpoint", "Websocket endpoint of the chain", None),
+    (
+        "chain_auth",
+        "Header the endpoint requires, e.g. an API key, its value read from the environment variable named; the value is masked in all output",
+        Some("{ header = \"Authorization\", value_env = \"RPC_TOKEN\" }"),
+    ),
+    (
+        "repodata_warn_size",
+        "Warn when the repository index uploaded on each push grows past this many bytes",
+        None,
+    ),
+    (
+        "max_repodata_size",
+        "Refuse to push when the index would grow past this many bytes (unset: no limit)",
+        Some("8388608"),
+    ),
+    (
+        "force_push_confirmation",
+        "Ask for the branch name to be typed back before a force push drops commits from the remote",
+        None,
+    ),
+    (
+        "ipfs_endpoint",
+        "IPFS API to use; when unset, a running node is looked for",
+        Some("\"http://127.0.0.1:5001\""),
+    ),
+    (
+        "auto_spawn_ipfs",
+        "Start `ipfs daemon` when no running node is found",
+        None,
+    ),
+    (
+        "keep_daemon",
+        "Leave that daemon running after git-remote-inv4 exits",
+        None,
+    ),
+    (
+        "packing",
+        "How pushed objects are bundled: \"commits\" groups them by the commit that introduced them, \"single\" uses one bundle per push",
+        None,
+    ),
+    (
+        "pack_target_size",
+        "With \"commits\", merge small neighbouring groups up to this many bytes per bundle",
+        None,
+    ),
+    (
+        "pack_max_size",
+        "With \"commits\", split bundles larger than this many bytes",
+        None,
+    ),
+    (
+        "best_effort_fetch",
+        "Skip objects whose content doesn't match their hash instead of failing the whole fetch",
+        None,
+    ),
+    (
+        "metadata_path",
+        "SCALE-encoded metadata of the chain's runtime, for chains other than Tinkernet",
+        Some("\"/path/to/metadata.scale\""),
+    ),
+    (
+        "upload_rate_limit",
+        "Bytes per second to upload objects at most, e.g. \"2MiB\" (unset or 0: unlimited)",
+        Some("\"2MiB\""),
+    ),
+    (
+        "download_rate_limit",
+        "Bytes per second to download objects at most (unset or 0: unlimited)",
+        Some("\"2MiB\""),
+    ),
+    (
+        "tee_remote",
+        "Push every fetched ref to this repository or URL too, e.g. a bare backup mirror",
+        Some("\"/srv/git/mirror.git\""),
+    ),
+    (
+        "publish_guard_patterns",
+        "Ask before a push publishes files matching these patterns, checked against the path and the file name",
+        None,
+    ),
+    (
+        "large_blob_threshold",
+        "Upload blobs larger than this many bytes on their own, streamed from disk, and resume them after an interrupted push",
+        None,
+    ),
+    (
+        "repodata_lookback_blocks",
+        "When the repository index can't be read, look for an earlier one up to this many blocks back",
+        None,
+    ),
+    (
+        "chain_rps",
+        "Chain requests per second at most, 0 for no limit (unset: 10 for public endpoints, no limit for local nodes)",
+        Some("10"),
+    ),
+    (
+        "protect_against_rewrites",
+        "Leave refs whose history the remote rewrote out of fetches until accepted with GIT_INV4_ACCEPT_REWRITES=1",
+        None,
+    ),
+    (
+        "publish_swarm_hints",
+        "Record your IPFS node's public addresses in each push, so fetchers find your copy of the objects at once",
+        None,
+    ),
+    (
+        "confirmation",
+        "When a push counts as done: \"finalized\" waits until its block can't be reorged away, \"in-block\" returns sooner and checks on the block next time",
+        None,
+    ),
+    (
+        "confirm_signing",
+        "Ask before signing, naming the repository, chain and address; turn off for automation",
+        None,
+    ),
+    (
+        "auth_cache",
+        "How long an unlocked key is kept in memory: \"session\" for the rest of the helper's run, so a second push in it doesn't ask again, \"none\" to ask for every push",
+        None,
+    ),
+    (
+        "offline",
+        "When to serve the refs last read from the chain instead of its current ones: \"auto\" when it can't be reached, \"force\" always, \"never\" to fail instead",
+        None,
+    ),
+    (
+        "upload_concurrency",
+        "Uploads to the object store that may run at once; fewer run while the store reports being busy",
+        None,
+    ),
+    (
+        "require_signed_commits",
+        "Refuse to push commits that aren't signed",
+        None,
+    ),
+    (
+        "protected_refs",
+        "Refs that pushes may not delete or force-push; a trailing * matches every ref starting with what precedes it",
+        None,
+    ),
+    (
+        "commit_message_pattern",
+        "Refuse to push commits whose message doesn't match this regex",
+        Some("'^(feat|fix|docs|chore): '"),
+    ),
+    (
+        "pre_push_hook",
+        "Program run like git's pre-push hook before a push uploads anything; a non-zero exit aborts the push",
+        Some("\"/path/to/pre-push\""),
+    ),
+    (
+        "prompt_push_message",
+        "Ask for a message describing each push, shown to multisig members and in `inv4-git history`, unless GIT_INV4_PUSH_MESSAGE gives one",
+        None,
+    ),
+    (
+        "immortal_transactions",
+        "Sign transactions that never expire instead of ones valid for 64 blocks, for machines whose clock can't be synced",
+        None,
+    ),
+    (
+        "max_ref_name_bytes",
+        "Refuse to push refs whose names are longer than this many bytes; longer ones break on some file systems",
+        None,
+    ),
+    (
+        "aliases",
+        "Names to use in place of IPS ids in URLs, e.g. inv4://team/infra; managed with `inv4-git alias`",
+        Some("{ website = 7, \"team/infra\" = 42 }"),
+    ),
+    (
+        "name_registry_ips",
+        "IPS whose repository details list names for repositories that have no alias",
+        Some("7"),
+    ),
+    (
+        "cache_max_size",
+        "Bytes of downloaded content to keep on disk for later fetches, e.g. \"1GiB\"; 0 turns the cache off",
+        None,
+    ),
+    (
+        "verify_fetch",
+        "Check that every object the fetched refs need is present before telling git the fetch succeeded; turn off to save time on huge fetches",
+        None,
+    ),
+    (
+        "traversal_max_objects",
+        "Fail a push, fetch or export whose walk over the repository's objects visits more than this many, which malformed repository data can cause",
+        None,
+    ),
+    (
+        "traversal_max_depth",
+        "Likewise for walks going deeper than this many objects, e.g. a line of history this long",
+        None,
+    ),
+    (
+        "traversal_budget_secs",
+        "Likewise for walks taking longer than this many seconds",
+        None,
+    ),
+];
+
+/// `INV4-Git/config.toml` in the OS config directory.
+pub fn path() -> BoxResult<PathBuf> {
+    Ok(config_dir()
+        .ok_or("Operating system's configs directory not found")?
+        .join("INV4-Git/config.toml"))
+}
+
+/// The keys `Config` accepts.
+pub fn keys() -> &'static [&'static str] {
+    /// Records the field names serde asks a struct's deserializer for, then bails out.
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only the field names were needed"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = Config::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// A config file with every key, set to its default or commented out when unset by default.
+pub fn template() -> String {
+    let defaults = toml::Value::try_from(Config::default()).unwrap_or(toml::Value::Boolean(false));
+    let mut template = String::from(
+        "# git-remote-inv4 configuration. Every key is optional; the values shown are the defaults.\n",
+    );
+
+    for key in keys() {
+        let (doc, example) = DOCS
+            .iter()
+            .find(|(name, ..)| name == key)
+            .map_or(("", None), |(_, doc, example)| (*doc, *example));
+
+        template.push('\n');
+        if !doc.is_empty() {
+            template.push_str(&format!("# {}\n", doc));
+        }
+
+        match defaults.get(key) {
+            Some(value) => template.push_str(&format!("{} = {}\n", key, value)),
+            None => template.push_str(&format!("# {} = {}\n", key, example.unwrap_or("\"\""))),
+        }
+    }
+
+    template
+}
+
+/// Parse the contents of the config file at `path`, pointing errors at the offending line.
+pub fn parse(contents: &str, path: &Path) -> BoxResult<Config> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+    toml::from_str(contents).map_err(|e| {
+        let location = match e.line_col() {
+            Some((line, column)) => format!(
+                "{}:{}:{}: {}\n    {}",
+                path.display(),
+                line + 1,
+                column + 1,
+                e,
+                contents.lines().nth(line).unwrap_or_default().trim()
+            ),
+            None => format!("{}: {}", path.display(), e),
+        };
+
+        location.into()
+    })
+}
+
+/// Everything wrong with the config file's `contents`, each with its 1-based line if known.
+pub fn problems(contents: &str) -> Vec<(Option<usize>, String)> {
+    let mut problems = vec![];
+
+    if contents.starts_with('\u{feff}') {
+        problems.push((
+            Some(1),
+            "the file starts with a byte order mark, which other TOML tools may reject".to_owned(),
+        ));
+    }
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+    let table = match contents.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return vec![(None, "expected a table of keys".to_owned())],
+        Err(e) => return vec![(e.line_col().map(|(line, _)| line + 1), e.to_string())],
+    };
+
+    for key in table.keys().filter(|key| !keys().contains(&key.as_str())) {
+        let message = match closest_key(key) {
+            Some(known) => format!("unknown key `{}`, did you mean `{}`?", key, known),
+            None => format!("unknown key `{}`", key),
+        };
+        problems.push((line_of(contents, key), message));
+    }
+
+    if let Err(e) = toml::from_str::<Config>(contents) {
+        problems.push((e.line_col().map(|(line, _)| line + 1), e.to_string()));
+    }
+
+    problems
+}
+
+/// The value of `key` in `config`, `None` when unset.
+pub fn get(config: &Config, key: &str) -> BoxResult<Option<toml::Value>> {
+    check_key(key)?;
+
+    Ok(toml::Value::try_from(config)?.get(key).cloned())
+}
+
+/// `contents` with `key` set to `value`, which is taken as TOML or else as a plain string.
+///
+/// The key's line is replaced in place, uncommenting it if need be, so the rest of the file is
+/// left as it was.
+pub fn set(contents: &str, key: &str, value: &str) -> BoxResult<String> {
+    check_key(key)?;
+
+    let value = match format!("value = {}", value).parse::<toml::Value>() {
+        Ok(parsed) => parsed["value"].to_string(),
+        Err(_) => toml::Value::String(value.to_owned()).to_string(),
+    };
+    let line = format!("{} = {}", key, value);
+
+    let mut lines: Vec<String> = contents.lines().map(str::to_owned).collect();
+    // Keys after a table header, such as `[aliases]`, belong to that table
+    let top_level = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    let assigns = |line: &str, commented: bool| {
+        let line = line.trim_start();
+        let line = match commented {
+            true => line.strip_prefix('#').unwrap_or("").trim_start(),
+            false => line,
+        };
+
+        line.strip_prefix(key)
+            .map_or(false, |rest| rest.trim_start().starts_with('='))
+    };
+
+    match lines[..top_level]
+        .iter()
+        .position(|line| assigns(line, false))
+        .or_else(|| {
+            lines[..top_level]
+                .iter()
+                .position(|line| assigns(line, true))
+        }) {
+        Some(index) => lines[index] = line,
+        None => lines.insert(top_level, line),
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+
+    Ok(updated)
+}
+
+/// `contents` with the alias `name` set to `ips_id`, or removed when `None`.
+///
+/// Aliases are rewritten as an `[aliases]` table at the end of the file, which `set` keeps
+/// clear of. Comments within the table are lost.
+pub fn set_alias(contents: &str, name: &str, ips_id: Option<IpsId>) -> BoxResult<String> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+    let mut aliases = match contents.parse::<toml::Value>()?.get("aliases") {
+        Some(toml::Value::Table(aliases)) => aliases.clone(),
+        Some(_) => error!("aliases is not a table"),
+        None => toml::value::Table::new(),
+    };
+    match ips_id {
+        Some(ips_id) => {
+            aliases.insert(name.to_owned(), toml::Value::Integer(ips_id.0.into()));
+        }
+        None => {
+            aliases.remove(name);
+        }
+    }
+
+    // Drop the table, or an inline `aliases = { .. }`, to write it anew
+    let mut lines = vec![];
+    let mut in_aliases = false;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') {
+            in_aliases = trimmed
+                .trim_start_matches('[')
+                .split(']')
+                .next()
+                .map_or(false, |table| table.trim() == "aliases");
+        }
+        let inline = trimmed
+            .strip_prefix("aliases")
+            .map_or(false, |rest| rest.trim_start().starts_with('='));
+
+        if !in_aliases && !inline {
+            lines.push(line);
+        }
+    }
+    while lines.last().map_or(false, |line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let mut updated = lines.join("\n");
+    if !aliases.is_empty() {
+        if !updated.is_empty() {
+            updated.push_str("\n\n");
+        }
+        updated.push_str("[aliases]");
+        for (name, ips_id) in &aliases {
+            let bare = name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            let key = match bare {
+                true => name.clone(),
+                false => toml::Value::String(name.clone()).to_string(),
+            };
+            updated.push_str(&format!("\n{} = {}", key, ips_id));
+        }
+    }
+    updated.push('\n');
+
+    Ok(updated)
+}
+
+fn check_key(key: &str) -> BoxResult<()> {
+    if keys().contains(&key) {
+        return Ok(());
+    }
+
+    match closest_key(key) {
+        Some(known) => error!(format!("Unknown key {}, did you mean {}?", key, known)),
+        None => error!(format!("Unknown key {}", key)),
+    }
+}
+
+/// The known key `key` is most likely a typo of.
+fn closest_key(key: &str) -> Option<&'static str> {
+    keys()
+        .iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, known)| *distance <= (known.len() / 3).max(1))
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// The 1-based line `key` is assigned on.
+fn line_of(contents: &str, key: &str) -> Option<usize> {
+    contents
+        .lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .map_or(false, |rest| rest.trim_start().starts_with('='))
+        })
+        .map(|index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::AuthCache, offline::OfflineMode};
+
+    #[test]
+    fn template_documents_every_key_and_parses_to_the_defaults() {
+        let template = template();
+
+        for key in keys() {
+            assert!(
+                DOCS.iter().any(|(name, ..)| name == key),
+                "{} is undocumented",
+                key
+            );
+            assert!(template.contains(&format!("{} = ", key)), "{}", key);
+        }
+
+        let parsed = parse(&template, Path::new("config.toml")).unwrap();
+        assert_eq!(
+            toml::Value::try_from(parsed).unwrap(),
+            toml::Value::try_from(Config::default()).unwrap()
+        );
+        assert!(problems(&template).is_empty());
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_line() {
+        let e = parse(
+            "\u{feff}packing = \"commits\"\nauto_spawn_ipfs = maybe\n",
+            Path::new("config.toml"),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(e.starts_with("config.toml:2:"), "{}", e);
+        assert!(e.ends_with("\n    auto_spawn_ipfs = maybe"), "{}", e);
+    }
+
+    #[test]
+    fn typos_and_bad_values_are_problems() {
+        let problems = problems("auto_spawn_ipf = true\npack_max_size = \"big\"\n");
+
+        assert_eq!(
+            problems[0],
+            (
+                Some(1),
+                "unknown key `auto_spawn_ipf`, did you mean `auto_spawn_ipfs`?".to_owned()
+            )
+        );
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[1].0, Some(2));
+    }
+
+    #[test]
+    fn set_replaces_or_uncomments_the_key_in_place() {
+        let contents = "# Comment\nkeep_daemon = false\n# ipfs_endpoint = \"\"\n";
+
+        let updated = set(contents, "keep_daemon", "true").unwrap();
+        assert_eq!(
+            updated,
+            "# Comment\nkeep_daemon = true\n# ipfs_endpoint = \"\"\n"
+        );
+
+        let updated = set(&updated, "ipfs_endpoint", "http://127.0.0.1:5001").unwrap();
+        assert_eq!(
+            updated,
+            "# Comment\nkeep_daemon = true\nipfs_endpoint = \"http://127.0.0.1:5001\"\n"
+        );
+
+        let updated = set("[aliases]\ninfra = 3\n", "pack_max_size", "1024").unwrap();
+        assert_eq!(updated, "pack_max_size = 1024\n[aliases]\ninfra = 3\n");
+
+        assert!(set(contents, "keep_deamon", "true")
+            .unwrap_err()
+            .to_string()
+            .contains("did you mean keep_daemon?"));
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("packing", "packing"), 0);
+        assert_eq!(edit_distance("pakcing", "packing"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(closest_key("zzzzzz"), None);
+    }
+
+    #[test]
+    fn aliases_are_rewritten_as_a_table_at_the_end() {
+        let contents = "aliases = { old = 1 }\nkeep_daemon = true\n";
+
+        let updated = set_alias(contents, "team/infra", Some(IpsId(42))).unwrap();
+        assert_eq!(
+            updated,
+            "keep_daemon = true\n\n[aliases]\nold = 1\n\"team/infra\" = 42\n"
+        );
+        assert_eq!(
+            parse(&updated, Path::new("config.toml"))
+                .unwrap()
+                .aliases
+                .len(),
+            2
+        );
+
+        let updated = set_alias(&updated, "old", None).unwrap();
+        let updated = set_alias(&updated, "team/infra", None).unwrap();
+        assert_eq!(updated, "keep_daemon = true\n");
+    }
+
+    #[test]
+    fn credentials_are_kept_for_the_session_unless_configured_not_to() {
+        let path = Path::new("config.toml");
+
+        assert_eq!(parse("", path).unwrap().auth_cache, AuthCache::Session);
+        assert_eq!(
+            parse("auth_cache = \"none\"\n", path).unwrap().auth_cache,
+            AuthCache::None
+        );
+        assert!(parse("auth_cache = \"forever\"\n", path).is_err());
+    }
+
+    #[test]
+    fn offline_mode_defaults_to_auto() {
+        let path = Path::new("config.toml");
+
+        assert_eq!(parse("", path).unwrap().offline, OfflineMode::Auto);
+        assert_eq!(
+            parse("offline = \"force\"\n", path).unwrap().offline,
+            OfflineMode::Force
+        );
+        assert!(parse("offline = true\n", path).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! Storing seeds with git's credential helpers, through `git credential`.
+//!
+//! A seed is stored encrypted with the user's password, as the password of a credential for
+//! `https://inv4-tinkernet` whose username is the account's nickname. Reading one is a `fill`
+//! with terminal prompts and askpass turned off, so git only asks its helpers. A helper answering
+//! `quit=1` stops git asking the others, and then we don't ask for a seed either. Every helper is
+//! told to `approve` a credential that just worked, which renews it with helpers that let
+//! credentials expire, and to `reject` one that turned out wrong, after which another helper's
+//! candidate is the next `fill`'s answer.
+//!
+//! Every exchange goes through `Helpers`, which runs `git` or a shim standing in for it.
+
+use crate::{error, primitives::BoxResult};
+use log::debug;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The host seeds are stored under.
+pub const HOST: &str = "inv4-tinkernet";
+
+/// A stored seed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credential {
+    /// The account's nickname
+    pub username: String,
+    /// The seed, encrypted with the user's password
+    pub password: String,
+    /// When a helper stops holding it, in seconds since the Unix epoch
+    pub expiry: Option<u64>,
+}
+
+impl Credential {
+    fn expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.expiry.map_or(false, |expiry| expiry <= now)
+    }
+}
+
+/// What the helpers answered a `fill` with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fill {
+    Found(Credential),
+    /// No helper holds a credential, or only an expired one
+    Missing,
+    /// A helper told git to stop asking
+    Quit,
+}
+
+/// The credential helpers, asked through `program`'s `credential` subcommand.
+pub struct Helpers {
+    program: String,
+}
+
+impl Helpers {
+    /// The helpers as `program` answers for them: `git` for the ones git is configured with, or
+    /// a shim script standing in for it.
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_owned(),
+        }
+    }
+
+    /// The credential stored for `account`, or for any account when `None`.
+    pub fn fill(&self, account: Option<&str>) -> BoxResult<Fill> {
+        let output = self.run("fill", &request(account, None))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if !output.status.success() {
+            if stderr.contains("told us to quit") {
+                return Ok(Fill::Quit);
+            }
+            // With prompts off, git fails when no helper answers
+            debug!("git credential fill found nothing: {}", stderr.trim());
+            return Ok(Fill::Missing);
+        }
+
+        let mut username = None;
+        let mut password = None;
+        let mut expiry = None;
+        for line in String::from_utf8(output.stdout)?.lines() {
+            match line.split_once('=') {
+                Some(("username", value)) => username = Some(value.to_owned()),
+                Some(("password", value)) => password = Some(value.to_owned()),
+                Some(("password_expiry_utc", value)) => expiry = value.parse().ok(),
+                Some(("quit", value)) if is_true(value) => return Ok(Fill::Quit),
+                _ => {}
+            }
+        }
+
+        let credential = match (username, password) {
+            (Some(username), Some(password)) => Credential {
+                username,
+                password,
+                expiry,
+            },
+            _ => return Ok(Fill::Missing),
+        };
+
+        // Git before 2.41 hands out what a helper holds past its expiry
+        match credential.expired() {
+            true => Ok(Fill::Missing),
+            false => Ok(Fill::Found(credential)),
+        }
+    }
+
+    /// Have the helpers store `credential`, or renew it when they hold it already.
+    pub fn approve(&self, credential: &Credential) -> BoxResult<()> {
+        self.update("approve", credential)
+    }
+
+    /// Have the helpers erase `credential`.
+    pub fn reject(&self, credential: &Credential) -> BoxResult<()> {
+        self.update("reject", credential)
+    }
+
+    fn update(&self, action: &str, credential: &Credential) -> BoxResult<()> {
+        let output = self.run(
+            action,
+            &request(Some(&credential.username), Some(credential)),
+        )?;
+
+        if !output.status.success() {
+            error!(format!(
+                "git credential {} failed: {}",
+                action,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+
+        Ok(())
+    }
+
+    /// Run `credential <action>`, writing `input` to it.
+    fn run(&self, action: &str, input: &str) -> BoxResult<std::process::Output> {
+        let mut child = Command::new(&self.program)
+            .args(["credential", action])
+            // Only ever ask the helpers; the seed prompts are ours
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env("GIT_ASKPASS", "")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not run {} credential: {}", self.program, e))?;
+
+        child
+            .stdin
+            .take()
+            .ok_or("git credential did not have a handle to stdin")?
+            .write_all(input.as_bytes())?;
+
+        Ok(child.wait_with_output()?)
+    }
+}
+
+/// The attributes describing `credential`, or only which one is wanted for `account` without.
+fn request(account: Option<&str>, credential: Option<&Credential>) -> String {
+    let mut request = format!("protocol=https\nhost={}\n", HOST);
+
+    if let Some(account) = account {
+        request.push_str(&format!("username={}\n", account));
+    }
+    if let Some(credential) = credential {
+        request.push_str(&format!("password={}\n", credential.password));
+        if let Some(expiry) = credential.expiry {
+            request.push_str(&format!("password_expiry_utc={}\n", expiry));
+        }
+    }
+
+    request.push('\n');
+    request
+}
+
+/// Whether a boolean attribute is set the way git reads them.
+fn is_true(value: &str) -> bool {
+    matches!(
+        value.to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_family = "unix")]
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+    #[cfg(target_family = "unix")]
+    use temp_dir::TempDir;
+
+    /// Stands in for `git`: `fill` answers with the first of the candidates `cand.0` to `cand.2`
+    /// in its directory, or fails like git without an answer, `approve` stores `cand.0` and
+    /// `reject` erases the first candidate. Every action is logged to `calls`, the first line of
+    /// its input with it.
+    #[cfg(target_family = "unix")]
+    const SHIM: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+input=$(cat)
+echo "$2 $(echo "$input" | head -n 1)" >> "$dir/calls"
+first=$(ls "$dir"/cand.* 2>/dev/null | head -n 1)
+case "$2" in
+fill)
+    if [ -f "$dir/quit" ]; then
+        echo "fatal: credential helper 'store' told us to quit" >&2
+        exit 128
+    fi
+    if [ -z "$first" ]; then
+        echo "fatal: could not read Username for 'https://inv4-tinkernet': terminal prompts disabled" >&2
+        exit 128
+    fi
+    cat "$first" ;;
+approve)
+    printf '%s\n' "$input" > "$dir/cand.0" ;;
+reject)
+    rm -f "$first" ;;
+esac
+"#;
+
+    #[cfg(target_family = "unix")]
+    fn shim(dir: &Path) -> Helpers {
+        let path = dir.join("git");
+        fs::write(&path, SHIM).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Helpers::new(path.to_str().unwrap())
+    }
+
+    fn credential(username: &str, expiry: Option<u64>) -> Credential {
+        Credential {
+            username: username.to_owned(),
+            password: format!("{}-encrypted-seed", username),
+            expiry,
+        }
+    }
+
+    #[test]
+    fn requests_describe_the_credential() {
+        assert_eq!(
+            request(Some("alice"), None),
+            "protocol=https\nhost=inv4-tinkernet\nusername=alice\n\n"
+        );
+        assert_eq!(
+            request(Some("alice"), Some(&credential("alice", Some(7)))),
+            "protocol=https\nhost=inv4-tinkernet\nusername=alice\npassword=alice-encrypted-seed\npassword_expiry_utc=7\n\n"
+        );
+        assert!(is_true("Yes") && is_true("1") && !is_true("0"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn rejected_credentials_give_way_to_the_next_candidate() {
+        let dir = TempDir::new().unwrap();
+        let helpers = shim(dir.path());
+        assert_eq!(helpers.fill(Some("alice")).unwrap(), Fill::Missing);
+
+        fs::write(
+            dir.child("cand.1"),
+            "username=alice\npassword=alice-encrypted-seed\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.child("cand.2"),
+            "protocol=https\nusername=bob\npassword=bob-encrypted-seed\n",
+        )
+        .unwrap();
+        assert_eq!(
+            helpers.fill(None).unwrap(),
+            Fill::Found(credential("alice", None))
+        );
+
+        helpers.reject(&credential("alice", None)).unwrap();
+        assert_eq!(
+            helpers.fill(None).unwrap(),
+            Fill::Found(credential("bob", None))
+        );
+
+        let replacement = credential("carol", Some(u64::MAX));
+        helpers.approve(&replacement).unwrap();
+        assert_eq!(helpers.fill(None).unwrap(), Fill::Found(replacement));
+
+        assert_eq!(
+            fs::read_to_string(dir.child("calls")).unwrap(),
+            "fill protocol=https\nfill protocol=https\nreject protocol=https\nfill protocol=https\napprove protocol=https\nfill protocol=https\n"
+        );
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn expired_credentials_are_missing_and_quitting_stops_the_fill() {
+        let dir = TempDir::new().unwrap();
+        let helpers = shim(dir.path());
+
+        fs::write(
+            dir.child("cand.1"),
+            "username=alice\npassword=seed\npassword_expiry_utc=1\n",
+        )
+        .unwrap();
+        assert_eq!(helpers.fill(None).unwrap(), Fill::Missing);
+
+        fs::write(dir.child("cand.1"), "quit=true\n").unwrap();
+        assert_eq!(helpers.fill(None).unwrap(), Fill::Quit);
+
+        fs::write(dir.child("quit"), "").unwrap();
+        assert_eq!(helpers.fill(None).unwrap(), Fill::Quit);
+    }
+
+    #[test]
+    fn a_missing_program_is_an_error() {
+        let helpers = Helpers::new("/nonexistent/git");
+
+        assert!(helpers.fill(None).is_err());
+        assert!(helpers.approve(&credential("alice", None)).is_err());
+    }
+}
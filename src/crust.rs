@@ -1,5 +1,6 @@
 use crate::compression;
 use crate::primitives::BoxResult;
+use crate::throttle::RateLimiter;
 use serde::{Deserialize, Serialize};
 use subxt::ext::sp_core::sr25519::Pair as Sr25519Pair;
 use subxt::ext::sp_core::Pair;
@@ -73,16 +74,36 @@ pub async fn send_to_crust(
     Ok(cid)
 }
 
-pub async fn get_from_crust(cid: String) -> BoxResult<Vec<u8>> {
+pub async fn get_from_crust(cid: String, limiter: Option<&RateLimiter>) -> BoxResult<Vec<u8>> {
+    let mut data = vec![];
+    stream_from_crust(&cid, limiter, |chunk| {
+        data.extend_from_slice(chunk);
+        Ok(())
+    })
+    .await?;
+
+    Ok(data)
+}
+
+/// Pass the gateway's response for `cid` to `sink` chunk by chunk, as it arrives.
+pub async fn stream_from_crust(
+    cid: &str,
+    limiter: Option<&RateLimiter>,
+    mut sink: impl FnMut(&[u8]) -> BoxResult<()>,
+) -> BoxResult<()> {
     let client = reqwest::Client::new();
 
-    let data = client
+    let mut response = client
         .get(format!("https://gw.crustfiles.app/ipfs/{}", cid))
         .send()
-        .await?
-        .bytes()
-        .await?
-        .to_vec();
+        .await?;
 
-    Ok(data)
+    while let Some(chunk) = response.chunk().await? {
+        if let Some(limiter) = limiter {
+            limiter.acquire(chunk.len()).await;
+        }
+        sink(&chunk)?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,558 @@
+//! `inv4-git daemon`: keeping the chain connection, the object store and repository indexes
+//! around for the helper invocations to come.
+//!
+//! The daemon listens on `daemon.sock` in the cache directory, which only the user can reach. A
+//! helper finding it there forwards `list` and `fetch` to it as one JSON message per line, and
+//! the daemon answers them for the helper's repository like a session of the helper's own would.
+//! Pushes never go through it, as they sign and seeds stay in the process that asked for them;
+//! neither do fetches of a partial clone, whose filter is an option of the helper's process. A
+//! daemon that isn't running, doesn't answer or can't serve the remote leaves the helper working
+//! on its own.
+//!
+//! An IPS's RepoData is reused for as long as the IPS holds the IPFs it was loaded with, which
+//! one storage read tells, since each push swaps in a new RepoData IPF.
+
+use crate::{
+    cache,
+    chain::{Chain, Snapshot},
+    error, output,
+    primitives::{BoxResult, Config, IpfId, IpsId, RepoData},
+    remote_helper::{self, Backend, FetchReport, FetchRequest, PushOutcome, PushRequest, Session},
+    remote_url::RemoteUrl,
+    shutdown,
+    store::{default_store, ObjectStore},
+};
+use async_trait::async_trait;
+use git2::Repository;
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    task::{self, LocalSet},
+    time,
+};
+
+/// How long the daemon waits for a connection before exiting, unless `--idle` says otherwise.
+pub const DEFAULT_IDLE: Duration = Duration::from_secs(15 * 60);
+
+/// How long a helper waits to connect before working on its own.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a helper asks of the daemon.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum Request {
+    /// The first message of a connection, for the remote the helper was started for
+    Session {
+        version: String,
+        git_dir: PathBuf,
+        remote_name: String,
+        url: String,
+    },
+    List {
+        for_push: bool,
+    },
+    /// `(sha, name)` of each ref to fetch
+    Fetch {
+        refs: Vec<(String, String)>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum Response {
+    Ok,
+    Refs {
+        refs: Vec<(String, String)>,
+    },
+    Fetched {
+        results: Vec<(String, Result<(), String>)>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+type Reader = Lines<BufReader<OwnedReadHalf>>;
+
+async fn send(writer: &mut OwnedWriteHalf, message: &impl Serialize) -> BoxResult<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    Ok(())
+}
+
+/// The next message, `None` once the other side hung up.
+async fn receive<T: DeserializeOwned>(reader: &mut Reader) -> BoxResult<Option<T>> {
+    match reader.next_line().await? {
+        Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+        None => Ok(None),
+    }
+}
+
+/// Serve helpers until none connected for `idle`.
+pub async fn run(idle: Duration) -> BoxResult<()> {
+    let path = cache::daemon_socket()?;
+    if UnixStream::connect(&path).await.is_ok() {
+        error!(format!(
+            "A daemon is already listening on {}",
+            path.display()
+        ))
+    }
+    // Left behind by a daemon that was killed
+    let _ = fs::remove_file(&path);
+
+    let config = Config::load()?;
+    let store: Rc<dyn ObjectStore> = default_store(&config).await?.into();
+    let chain = Rc::new(Chain::connect(&config).await?);
+
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    let _socket = shutdown::register({
+        let path = path.clone();
+        move || {
+            let _ = fs::remove_file(&path);
+        }
+    });
+
+    output::info(format!(
+        "Serving helpers for {} on {}",
+        config.chain_endpoint,
+        path.display()
+    ));
+
+    let daemon = Rc::new(Daemon {
+        config,
+        chain,
+        store,
+        indexes: RefCell::new(HashMap::new()),
+        connections: Cell::new(0),
+    });
+
+    LocalSet::new()
+        .run_until(accept(listener, daemon, idle))
+        .await
+}
+
+async fn accept(listener: UnixListener, daemon: Rc<Daemon>, idle: Duration) -> BoxResult<()> {
+    loop {
+        let stream = match time::timeout(idle, listener.accept()).await {
+            Ok(accepted) => accepted?.0,
+            Err(_) if daemon.connections.get() == 0 => {
+                output::info(format!("No helper connected for {:?}, exiting", idle));
+                return Ok(());
+            }
+            Err(_) => continue,
+        };
+
+        let daemon = daemon.clone();
+        task::spawn_local(async move {
+            daemon.connections.set(daemon.connections.get() + 1);
+            if let Err(e) = daemon.serve(stream).await {
+                output::warn(format!("A helper's connection failed: {}", e));
+            }
+            daemon.connections.set(daemon.connections.get() - 1);
+        });
+    }
+}
+
+struct Daemon {
+    config: Config,
+    chain: Rc<Chain>,
+    store: Rc<dyn ObjectStore>,
+    /// The RepoData of each IPS, with the IPFs the IPS held when it was loaded
+    indexes: RefCell<HashMap<IpsId, (Vec<IpfId>, RepoData)>>,
+    connections: Cell<usize>,
+}
+
+impl Daemon {
+    /// Answer one helper's requests until it hangs up.
+    async fn serve(&self, stream: UnixStream) -> BoxResult<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader).lines();
+
+        let session = match receive(&mut reader).await? {
+            Some(Request::Session {
+                version,
+                git_dir,
+                remote_name,
+                url,
+            }) => self.session(&version, &git_dir, remote_name, &url).await,
+            Some(request) => error!(format!("Expected a session, got {:?}", request)),
+            None => return Ok(()),
+        };
+        let mut session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                return send(
+                    &mut writer,
+                    &Response::Error {
+                        message: e.to_string(),
+                    },
+                )
+                .await
+            }
+        };
+        send(&mut writer, &Response::Ok).await?;
+
+        while let Some(request) = receive(&mut reader).await? {
+            let response = match request {
+                Request::List { for_push } => session
+                    .list(for_push)
+                    .await
+                    .map(|refs| Response::Refs { refs }),
+                Request::Fetch { refs } => {
+                    let requests: Vec<_> = refs
+                        .into_iter()
+                        .map(|(sha, name)| FetchRequest { sha, name })
+                        .collect();
+
+                    session
+                        .fetch_batch(&requests)
+                        .await
+                        .map(|reports| Response::Fetched {
+                            results: reports
+                                .into_iter()
+                                .map(|report| (report.name, report.result))
+                                .collect(),
+                        })
+                }
+                Request::Session { .. } => Err("A connection serves a single session".into()),
+            };
+
+            let response = response.unwrap_or_else(|e| Response::Error {
+                message: e.to_string(),
+            });
+            send(&mut writer, &response).await?;
+        }
+
+        session.push_tee();
+
+        Ok(())
+    }
+
+    /// A session for `remote_name` of the repository at `git_dir`.
+    async fn session(
+        &self,
+        version: &str,
+        git_dir: &Path,
+        remote_name: String,
+        raw_url: &str,
+    ) -> BoxResult<Session> {
+        if version != env!("CARGO_PKG_VERSION") {
+            error!(format!(
+                "The daemon runs version {}, the helper {}",
+                env!("CARGO_PKG_VERSION"),
+                version
+            ))
+        }
+
+        let repo = Repository::open(git_dir)?;
+        let (config, account) =
+            remote_helper::remote_config(&repo, &remote_name, self.config.clone())?;
+        if config.chain_endpoint != self.config.chain_endpoint {
+            error!(format!(
+                "The remote uses {}, the daemon {}",
+                config.chain_endpoint, self.config.chain_endpoint
+            ))
+        }
+
+        let mut url: RemoteUrl = raw_url.parse()?;
+        url.resolve(&config, &self.chain, self.store.as_ref())
+            .await?;
+        let snapshot = Snapshot::take(&self.chain).await?;
+        let remote_repo = self.repo_data(url.ips_id, &config, &snapshot).await?;
+
+        Ok(Session::attach(
+            repo,
+            remote_name,
+            account,
+            url,
+            config,
+            self.chain.clone(),
+            self.store.clone(),
+            remote_repo,
+            snapshot,
+        )
+        .await)
+    }
+
+    /// The RepoData of IPS `ips_id` as of `snapshot`, loaded again only when the IPS's IPFs
+    /// changed.
+    async fn repo_data(
+        &self,
+        ips_id: IpsId,
+        config: &Config,
+        snapshot: &Snapshot,
+    ) -> BoxResult<RepoData> {
+        let ipf_ids = self
+            .chain
+            .ipf_ids_at(ips_id, Some(snapshot.block()))
+            .await?
+            .unwrap_or_default();
+
+        if let Some((loaded_with, repo_data)) = self.indexes.borrow().get(&ips_id) {
+            if *loaded_with == ipf_ids {
+                debug!("Reusing the RepoData of IPS {}", ips_id);
+                return Ok(repo_data.clone());
+            }
+        }
+
+        let repo_data = RepoData::chain_get(
+            ips_id,
+            self.store.as_ref(),
+            &self.chain,
+            config.repodata_lookback_blocks,
+            Some(snapshot),
+        )
+        .await?;
+        self.indexes
+            .borrow_mut()
+            .insert(ips_id, (ipf_ids, repo_data.clone()));
+
+        Ok(repo_data)
+    }
+}
+
+/// The helper's side: `list` and `fetch` go to the daemon, anything else to a session of the
+/// helper's own, started when first needed.
+pub struct Forwarded {
+    reader: Reader,
+    writer: OwnedWriteHalf,
+    remote_name: String,
+    url: String,
+    config: Config,
+    local: Option<Session>,
+}
+
+impl Forwarded {
+    /// A session with a running daemon, `None` when there is none or it can't serve the remote.
+    pub async fn connect(remote_name: &str, raw_url: &str, config: &Config) -> Option<Self> {
+        match Self::try_connect(remote_name, raw_url, config).await {
+            Ok(forwarded) => Some(forwarded),
+            Err(e) => {
+                debug!("Not using the daemon: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn try_connect(remote_name: &str, raw_url: &str, config: &Config) -> BoxResult<Self> {
+        let path = cache::daemon_socket()?;
+        if !path.exists() {
+            error!("no daemon is running")
+        }
+
+        let stream = time::timeout(CONNECT_TIMEOUT, UnixStream::connect(&path))
+            .await
+            .map_err(|_| format!("{} didn't accept the connection", path.display()))??;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader).lines();
+
+        send(
+            &mut writer,
+            &Request::Session {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                git_dir: Repository::open_from_env()?.path().to_owned(),
+                remote_name: remote_name.to_owned(),
+                url: raw_url.to_owned(),
+            },
+        )
+        .await?;
+
+        match receive(&mut reader).await? {
+            Some(Response::Ok) => Ok(Self {
+                reader,
+                writer,
+                remote_name: remote_name.to_owned(),
+                url: raw_url.to_owned(),
+                config: config.clone(),
+                local: None,
+            }),
+            Some(Response::Error { message }) => Err(message.into()),
+            response => error!(format!("Unexpected answer {:?}", response)),
+        }
+    }
+
+    async fn request(&mut self, request: &Request) -> BoxResult<Response> {
+        send(&mut self.writer, request).await?;
+
+        match receive(&mut self.reader).await? {
+            Some(Response::Error { message }) => Err(message.into()),
+            Some(response) => Ok(response),
+            None => error!("The daemon hung up"),
+        }
+    }
+
+    async fn local(&mut self) -> BoxResult<&mut Session> {
+        let session = match self.local.take() {
+            Some(session) => session,
+            None => {
+                let store = default_store(&self.config).await?;
+                Session::new(
+                    self.remote_name.clone(),
+                    self.url.parse()?,
+                    self.config.clone(),
+                    store,
+                )
+                .await?
+            }
+        };
+
+        Ok(self.local.insert(session))
+    }
+
+    /// Finish what the helper's own session left to do once git was answered.
+    pub fn finish(&self) {
+        if let Some(session) = &self.local {
+            session.push_tee();
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Forwarded {
+    async fn list(&mut self, for_push: bool) -> BoxResult<Vec<(String, String)>> {
+        match self.request(&Request::List { for_push }).await? {
+            Response::Refs { refs } => Ok(refs),
+            response => error!(format!("Unexpected answer from the daemon {:?}", response)),
+        }
+    }
+
+    async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+        if remote_helper::blob_limit_option().is_some() {
+            return Backend::fetch_batch(self.local().await?, requests).await;
+        }
+
+        let refs = requests
+            .iter()
+            .map(|request| (request.sha.clone(), request.name.clone()))
+            .collect();
+
+        match self.request(&Request::Fetch { refs }).await? {
+            Response::Fetched { results } => Ok(results
+                .into_iter()
+                .map(|(name, result)| FetchReport { name, result })
+                .collect()),
+            response => error!(format!("Unexpected answer from the daemon {:?}", response)),
+        }
+    }
+
+    async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        Backend::push_batch(self.local().await?, requests).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A helper's end of a connection, and the daemon's.
+    fn connection() -> (Forwarded, Reader, OwnedWriteHalf) {
+        let (helper, daemon) = UnixStream::pair().unwrap();
+        let (reader, writer) = helper.into_split();
+        let (daemon_reader, daemon_writer) = daemon.into_split();
+
+        let forwarded = Forwarded {
+            reader: BufReader::new(reader).lines(),
+            writer,
+            remote_name: "origin".to_owned(),
+            url: "inv4://1".to_owned(),
+            config: Config::default(),
+            local: None,
+        };
+
+        (
+            forwarded,
+            BufReader::new(daemon_reader).lines(),
+            daemon_writer,
+        )
+    }
+
+    #[test]
+    fn messages_are_kebab_case_json() {
+        assert_eq!(
+            serde_json::to_string(&Request::List { for_push: true }).unwrap(),
+            r#"{"list":{"for_push":true}}"#
+        );
+        assert_eq!(serde_json::to_string(&Response::Ok).unwrap(), r#""ok""#);
+    }
+
+    #[tokio::test]
+    async fn list_and_fetch_are_answered_by_the_daemon() {
+        let (mut forwarded, mut reader, mut writer) = connection();
+        let refs = vec![("a".repeat(40), "refs/heads/main".to_owned())];
+
+        let daemon = async {
+            match receive(&mut reader).await.unwrap() {
+                Some(Request::List { for_push: false }) => {}
+                request => panic!("{:?}", request),
+            }
+            send(&mut writer, &Response::Refs { refs: refs.clone() })
+                .await
+                .unwrap();
+
+            match receive(&mut reader).await.unwrap() {
+                Some(Request::Fetch { refs }) => assert_eq!(refs.len(), 1),
+                request => panic!("{:?}", request),
+            }
+            let results = vec![("refs/heads/main".to_owned(), Err("corrupt".to_owned()))];
+            send(&mut writer, &Response::Fetched { results })
+                .await
+                .unwrap();
+        };
+        let helper = async {
+            let listed = forwarded.list(false).await.unwrap();
+            let fetched = forwarded
+                .fetch_batch(&[FetchRequest {
+                    sha: "a".repeat(40),
+                    name: "refs/heads/main".to_owned(),
+                }])
+                .await
+                .unwrap();
+            (listed, fetched)
+        };
+
+        let ((), (listed, fetched)) = tokio::join!(daemon, helper);
+        assert_eq!(listed, refs);
+        assert_eq!(fetched[0].result, Err("corrupt".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn daemon_errors_and_hanging_up_fail_the_command() {
+        let (mut forwarded, mut reader, mut writer) = connection();
+
+        let daemon = async {
+            receive::<Request>(&mut reader).await.unwrap();
+            let message = "IPS 1 does not exist".to_owned();
+            send(&mut writer, &Response::Error { message })
+                .await
+                .unwrap();
+            receive::<Request>(&mut reader).await.unwrap();
+            drop(writer);
+        };
+        let helper = async {
+            let first = forwarded.list(false).await.unwrap_err().to_string();
+            let second = forwarded.list(false).await.unwrap_err().to_string();
+            (first, second)
+        };
+
+        let ((), (first, second)) = tokio::join!(daemon, helper);
+        assert_eq!(first, "IPS 1 does not exist");
+        assert_eq!(second, "The daemon hung up");
+    }
+}
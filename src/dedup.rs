@@ -0,0 +1,138 @@
+//! How much of a repository's stored bytes are copies of git objects stored elsewhere.
+
+use std::collections::{BTreeSet, HashMap};
+
+/// How many of the largest duplicated objects a report lists.
+pub const TOP_DUPLICATES: usize = 10;
+
+/// A git object stored in more than one MultiObject.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicatedObject {
+    pub git_hash: String,
+    pub size: usize,
+    /// How many MultiObjects contain it
+    pub copies: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    pub multi_objects: usize,
+    /// Bytes of object data across all MultiObjects
+    pub total_bytes: usize,
+    /// Bytes of object data if every object were stored once
+    pub unique_bytes: usize,
+    /// The `TOP_DUPLICATES` largest duplicated objects, largest first
+    pub largest_duplicates: Vec<DuplicatedObject>,
+}
+
+impl DedupReport {
+    /// Stored bytes per unique byte; 1.0 means nothing is duplicated.
+    pub fn ratio(&self) -> f64 {
+        match self.unique_bytes {
+            0 => 1.0,
+            unique => self.total_bytes as f64 / unique as f64,
+        }
+    }
+}
+
+/// Aggregate `(MultiObject hash, git hash, size)` triples, one per object stored in a
+/// MultiObject.
+///
+/// Only hashes are kept, so callers can feed in MultiObjects one at a time and drop their data.
+pub fn dedup_report(objects: impl IntoIterator<Item = (String, String, usize)>) -> DedupReport {
+    let mut containers: HashMap<String, (usize, BTreeSet<String>)> = HashMap::new();
+    let mut multi_objects = BTreeSet::new();
+
+    for (multi_object_hash, git_hash, size) in objects {
+        multi_objects.insert(multi_object_hash.clone());
+        containers
+            .entry(git_hash)
+            .or_insert_with(|| (size, BTreeSet::new()))
+            .1
+            .insert(multi_object_hash);
+    }
+
+    let mut report = DedupReport {
+        multi_objects: multi_objects.len(),
+        ..Default::default()
+    };
+
+    for (git_hash, (size, holders)) in containers {
+        report.total_bytes += size * holders.len();
+        report.unique_bytes += size;
+
+        if holders.len() > 1 {
+            report.largest_duplicates.push(DuplicatedObject {
+                git_hash,
+                size,
+                copies: holders.len(),
+            });
+        }
+    }
+
+    report.largest_duplicates.sort_by(|a, b| {
+        b.size
+            .cmp(&a.size)
+            .then_with(|| a.git_hash.cmp(&b.git_hash))
+    });
+    report.largest_duplicates.truncate(TOP_DUPLICATES);
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(multi_object: &str, git_hash: &str, size: usize) -> (String, String, usize) {
+        (multi_object.to_owned(), git_hash.to_owned(), size)
+    }
+
+    #[test]
+    fn duplicated_objects_are_counted_once_per_multi_object() {
+        let report = dedup_report([
+            stored("m1", "small", 10),
+            stored("m1", "large", 100),
+            stored("m2", "large", 100),
+            stored("m2", "small", 10),
+            stored("m3", "large", 100),
+            stored("m3", "unique", 5),
+        ]);
+
+        assert_eq!(report.multi_objects, 3);
+        assert_eq!(report.total_bytes, 325);
+        assert_eq!(report.unique_bytes, 115);
+        assert_eq!(
+            report.largest_duplicates,
+            [
+                DuplicatedObject {
+                    git_hash: "large".to_owned(),
+                    size: 100,
+                    copies: 3,
+                },
+                DuplicatedObject {
+                    git_hash: "small".to_owned(),
+                    size: 10,
+                    copies: 2,
+                },
+            ]
+        );
+        assert!((report.ratio() - 325.0 / 115.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn only_the_largest_duplicates_are_listed() {
+        let report = dedup_report((0..TOP_DUPLICATES + 5).flat_map(|i| {
+            let git_hash = format!("{:02}", i);
+            [stored("m1", &git_hash, i), stored("m2", &git_hash, i)]
+        }));
+
+        assert_eq!(report.largest_duplicates.len(), TOP_DUPLICATES);
+        assert_eq!(report.largest_duplicates[0].size, TOP_DUPLICATES + 4);
+    }
+
+    #[test]
+    fn nothing_stored_is_not_duplicated() {
+        assert_eq!(dedup_report([]).ratio(), 1.0);
+    }
+}
@@ -0,0 +1,207 @@
+//! `inv4-git devnet bootstrap`: a test repository on a local development chain in one command.
+//!
+//! Signs as //Alice, so it refuses to run against a chain that doesn't call itself a development
+//! or local one. An IPS is created, a fixture repository generated by `fixtures` pushed to it and
+//! cloned back through git, running this executable as the helper, and the clone checked against
+//! the fixture. Only built with the `devnet` feature, so release binaries don't carry it.
+
+use crate::{
+    auth::DEVNET_SEED_VAR,
+    chain::Chain,
+    error,
+    fixtures::{self, FixtureRepo, Seed},
+    install::{self, Method},
+    output,
+    primitives::{BoxResult, Config, IpsId},
+};
+use git2::{Oid, Repository};
+use std::{env, fs, path::Path, process::Stdio};
+use subxt::{
+    ext::{
+        sp_core::{sr25519::Pair, Pair as _},
+        sp_runtime::AccountId32,
+    },
+    tx::PairSigner,
+};
+use temp_dir::TempDir;
+use tokio::process::Command;
+
+pub const DEFAULT_ENDPOINT: &str = "ws://127.0.0.1:9944";
+
+/// The well-known development key, funded in the genesis of every dev chain spec.
+const DEV_SEED: &str = "//Alice";
+
+/// Metadata of the created IPS, the same as the README's example.
+const IPS_METADATA: &[u8] = b"git repository";
+
+/// Size of the binary file in the fixture, enough to span a few zlib blocks.
+const BINARY_SIZE: usize = 64 * 1024;
+
+/// Create an IPS on the dev chain at `endpoint`, push a fixture repository to it and check a
+/// fresh clone matches, returning the IPS id.
+pub async fn bootstrap(endpoint: &str) -> BoxResult<IpsId> {
+    let mut config = Config::load()?;
+    config.chain_endpoint = endpoint.to_owned();
+    let chain = Chain::connect(&config).await?;
+
+    let name = chain
+        .with_reconnect(|api| async move { api.rpc().system_chain().await })
+        .await?;
+    if !is_dev_chain(&name) {
+        error!(format!(
+            "{} runs {:?}, not a development chain; refusing to sign as {}",
+            endpoint, name, DEV_SEED
+        ))
+    }
+
+    let pair = Pair::from_string(DEV_SEED, None).map_err(|e| format!("{:?}", e))?;
+    let account = AccountId32::from(pair.public());
+    let balance = chain.free_balance(&account).await?;
+    if balance == 0 {
+        error!(format!(
+            "{} has no funds on {}; was the chain started from a dev chain spec?",
+            DEV_SEED, name
+        ))
+    }
+    output::info(format!(
+        "Signing as {} on {} ({})",
+        DEV_SEED,
+        name,
+        chain.token().await?.format(balance)
+    ));
+
+    output::status("Creating an IP Set...");
+    let ips_id = chain
+        .create_ips(IPS_METADATA.to_vec(), &PairSigner::new(pair))
+        .await?;
+    output::info(format!("Created IPS {}", ips_id));
+
+    let workdir = TempDir::new()?;
+    let bin = workdir.child("bin");
+    fs::create_dir(&bin)?;
+    install::install(&env::current_exe()?, &bin, Method::native())?;
+    let path = env::join_paths(
+        [bin]
+            .into_iter()
+            .chain(env::split_paths(&env::var_os("PATH").unwrap_or_default())),
+    )?;
+    let git = |dir: &Path| {
+        let mut command = Command::new("git");
+        command
+            .current_dir(dir)
+            .env("PATH", &path)
+            .env(DEVNET_SEED_VAR, DEV_SEED)
+            // stdout is for the IPS id
+            .stdout(Stdio::null());
+        command
+    };
+
+    let source = workdir.child("fixture");
+    let refs = FixtureRepo::new()
+        .commits(3)
+        .blob("assets/noise.bin", BINARY_SIZE, Seed(42))
+        .tag_annotated("v0.1.0")
+        .build(&source)?;
+    let url = format!("inv4://{}", ips_id);
+    let endpoint_setting = format!("remote.origin.inv4-endpoint={}", endpoint);
+
+    run(git(&source).args(["remote", "add", "origin", &url])).await?;
+    run(git(&source).args(["config", "remote.origin.inv4-endpoint", endpoint])).await?;
+    run(git(&source).args(["push", "origin", "--all"])).await?;
+    run(git(&source).args(["push", "origin", "--tags"])).await?;
+
+    let clone = workdir.child("clone");
+    run(git(workdir.path())
+        .args(["clone", "-c", &endpoint_setting, &url])
+        .arg(&clone))
+    .await?;
+    verify(&clone, &source, &refs)?;
+    output::info(format!("A fresh clone of {} matches the fixture", url));
+
+    Ok(ips_id)
+}
+
+/// Whether a chain named `name` is a development one: dev chain specs are named e.g.
+/// `Development` and `Local Testnet`.
+fn is_dev_chain(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.contains("dev") || name.contains("local")
+}
+
+async fn run(command: &mut Command) -> BoxResult<()> {
+    let status = command.status().await?;
+    if !status.success() {
+        error!(format!("{:?} failed ({})", command.as_std(), status))
+    }
+
+    Ok(())
+}
+
+/// Check the clone at `dir` has the fixture's refs, as remote-tracking branches and tags, and
+/// the same objects.
+fn verify(dir: &Path, fixture: &Path, refs: &[(String, Oid)]) -> BoxResult<()> {
+    let repo = Repository::open(dir)?;
+
+    for (name, expected) in refs {
+        let name = match name.strip_prefix("refs/heads/") {
+            Some(branch) => format!("refs/remotes/origin/{}", branch),
+            None => name.clone(),
+        };
+
+        match repo.refname_to_id(&name) {
+            Ok(oid) if oid == *expected => {}
+            Ok(oid) => error!(format!(
+                "{} is {} in the clone, but {} in the fixture",
+                name, oid, expected
+            )),
+            Err(_) => error!(format!("{} is missing from the clone", name)),
+        }
+    }
+
+    fixtures::same_objects(&Repository::open(fixture)?, &repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_development_chains_are_signed_for() {
+        assert!(is_dev_chain("Development"));
+        assert!(is_dev_chain("Local Testnet"));
+        assert!(is_dev_chain("Tinkernet Dev"));
+        assert!(!is_dev_chain("Tinkernet"));
+    }
+
+    #[test]
+    fn clones_are_checked_against_the_fixture() {
+        let dir = TempDir::new().unwrap();
+        let (source, clone) = (dir.child("fixture"), dir.child("clone"));
+        let refs = FixtureRepo::new()
+            .commits(2)
+            .tag_annotated("v0.1.0")
+            .build(&source)
+            .unwrap();
+        let cloned = Repository::clone(source.to_str().unwrap(), &clone).unwrap();
+
+        verify(&clone, &source, &refs).unwrap();
+
+        // A branch left behind
+        let (name, tip) = refs
+            .iter()
+            .find(|(name, _)| name.starts_with("refs/heads/"))
+            .unwrap();
+        let branch = name.replace("refs/heads/", "refs/remotes/origin/");
+        let parent = cloned.find_commit(*tip).unwrap().parent_id(0).unwrap();
+        cloned.reference(&branch, parent, true, "test").unwrap();
+
+        let e = verify(&clone, &source, &refs).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "{} is {} in the clone, but {} in the fixture",
+                branch, parent, tip
+            )
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! `inv4-git diff`: how the refs of a repository changed between two blocks.
+//!
+//! The RepoData each block's state pointed at is loaded and their refs compared. For refs that
+//! moved, the commits in between can be listed too. Only the MultiObjects holding those commits
+//! are downloaded, never a whole clone's worth.
+
+use crate::{
+    chain::Chain,
+    error,
+    primitives::{
+        header_values, BoxResult, GitObjectMetadata, IpsId, MultiObject, MultiObjectIndex,
+        ObjectKind, RefChange, RepoData, LARGE_BLOB_MARKER, SUBMODULE_TIP_MARKER,
+    },
+    store::ObjectStore,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use subxt::ext::sp_core::H256;
+
+/// A block given as a number or a `0x`-prefixed hash, resolved to its hash.
+pub async fn block_hash(block: &str, chain: &Chain) -> BoxResult<H256> {
+    if block.starts_with("0x") {
+        return Ok(block
+            .parse()
+            .map_err(|_| format!("Invalid block hash {}", block))?);
+    }
+
+    let number: u32 = block
+        .parse()
+        .map_err(|_| format!("Invalid block {}; give a number or a 0x hash", block))?;
+
+    chain
+        .block_hash(number)
+        .await?
+        .ok_or_else(|| format!("Block {} is not known to the node yet", number).into())
+}
+
+/// The RepoData of the repository under `ips_id` as of block `at`, empty when nothing was
+/// pushed yet then. Reading an earlier block's state needs a node that hasn't pruned it.
+pub async fn repo_data_at(
+    ips_id: IpsId,
+    at: H256,
+    chain: &Chain,
+    store: &dyn ObjectStore,
+) -> BoxResult<RepoData> {
+    let ipfs = chain
+        .find_ipfs_at(ips_id, b"RepoData", Some(at))
+        .await
+        .map_err(|e| format!("Could not read IPS {} as of block {:?}: {}", ips_id, at, e))?;
+
+    match ipfs.first() {
+        Some(ipf) => RepoData::from_ipfs(ipf.data, store).await,
+        None => Ok(RepoData::default()),
+    }
+}
+
+/// The refs that differ between `from` and `to`, by name. A ref only in `from` was deleted, one
+/// only in `to` created.
+pub fn compare(from: &RepoData, to: &RepoData) -> Vec<RefChange> {
+    let names: BTreeSet<&String> = from.refs.keys().chain(to.refs.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (from.refs.get(name), to.refs.get(name));
+
+            (old != new).then(|| RefChange {
+                name: name.clone(),
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// A commit between the old and new tip of a ref.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CommitSummary {
+    pub sha: String,
+    /// `Name <email>`
+    pub author: String,
+    /// The first line of the message
+    pub subject: String,
+}
+
+/// A ref that changed, with the commits it gained when it moved.
+#[derive(Serialize, Clone, Debug)]
+pub struct RefDiff {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commits: Vec<CommitSummary>,
+    /// Whether there are more commits than were listed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl From<RefChange> for RefDiff {
+    fn from(change: RefChange) -> Self {
+        Self {
+            name: change.name,
+            old: change.old,
+            new: change.new,
+            commits: vec![],
+            truncated: false,
+        }
+    }
+}
+
+/// Reads commits out of the MultiObjects of a RepoData, downloading each at most once.
+pub struct CommitReader<'a> {
+    repo_data: &'a RepoData,
+    index: MultiObjectIndex<'a>,
+    store: &'a dyn ObjectStore,
+    downloaded: BTreeMap<String, MultiObject>,
+}
+
+impl<'a> CommitReader<'a> {
+    pub fn new(
+        repo_data: &'a RepoData,
+        index: MultiObjectIndex<'a>,
+        store: &'a dyn ObjectStore,
+    ) -> Self {
+        Self {
+            repo_data,
+            index,
+            store,
+            downloaded: BTreeMap::new(),
+        }
+    }
+
+    /// The commit `sha` with its parents, `None` when it isn't a commit stored in a MultiObject.
+    async fn read(&mut self, sha: &str) -> BoxResult<Option<(CommitSummary, Vec<String>)>> {
+        let repo_data = self.repo_data;
+        let hash = match repo_data.objects.get(sha) {
+            Some(hash) if hash != LARGE_BLOB_MARKER && hash != SUBMODULE_TIP_MARKER => hash,
+            Some(_) => return Ok(None),
+            None => error!(format!("Object {} is not in the repository index", sha)),
+        };
+
+        if !self.downloaded.contains_key(hash) {
+            let cid = repo_data.multi_object_cid(hash, &self.index).await?;
+            let multi_object = MultiObject::get(&cid, self.store).await?;
+            self.downloaded.insert(hash.clone(), multi_object);
+        }
+
+        let object = self.downloaded[hash]
+            .objects
+            .get(sha)
+            .ok_or_else(|| format!("MultiObject {} doesn't hold object {}", hash, sha))?;
+
+        let parents = match (&object.kind, &object.metadata) {
+            (
+                ObjectKind::Commit,
+                GitObjectMetadata::Commit {
+                    parent_git_hashes, ..
+                },
+            ) => parent_git_hashes.iter().cloned().collect(),
+            _ => return Ok(None),
+        };
+
+        let author = header_values(&object.data, "author")
+            .next()
+            .map(|author| match author.find('>') {
+                Some(end) => author[..=end].to_owned(),
+                None => author,
+            })
+            .unwrap_or_default();
+
+        let message = object
+            .data
+            .windows(2)
+            .position(|pair| pair == b"\n\n")
+            .map_or(&[][..], |start| &object.data[start + 2..]);
+        let subject = String::from_utf8_lossy(message)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+
+        Ok(Some((
+            CommitSummary {
+                sha: sha.to_owned(),
+                author,
+                subject,
+            },
+            parents,
+        )))
+    }
+
+    /// The commits reachable from `new` but not through `old`, walking down from `new`, at most
+    /// `max` of them; the flag says whether there were more. After a force push `old` is never reached,
+    /// so the walk only ends at `max` or the root.
+    pub async fn commits_between(
+        &mut self,
+        old: &str,
+        new: &str,
+        max: usize,
+    ) -> BoxResult<(Vec<CommitSummary>, bool)> {
+        let mut commits = vec![];
+        let mut seen = HashSet::from([old.to_owned()]);
+        let mut queue = vec![new.to_owned()];
+
+        while let Some(sha) = queue.pop() {
+            if !seen.insert(sha.clone()) {
+                continue;
+            }
+
+            let (commit, parents) = match self.read(&sha).await? {
+                Some(commit) => commit,
+                None => continue,
+            };
+
+            if commits.len() == max {
+                return Ok((commits, true));
+            }
+            commits.push(commit);
+            queue.extend(parents);
+        }
+
+        Ok((commits, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::tests::published,
+        util::tests::{commit, repository},
+    };
+
+    fn refs(refs: &[(&str, &str)]) -> RepoData {
+        RepoData {
+            refs: refs
+                .iter()
+                .map(|&(name, target)| (name.to_owned(), target.to_owned()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn created_deleted_and_moved_refs_are_listed() {
+        let from = refs(&[
+            ("refs/heads/gone", "a"),
+            ("refs/heads/main", "a"),
+            ("refs/tags/v1", "b"),
+        ]);
+        let to = refs(&[
+            ("refs/heads/main", "c"),
+            ("refs/heads/new", "d"),
+            ("refs/tags/v1", "b"),
+        ]);
+
+        let change = |name: &str, old: Option<&str>, new: Option<&str>| RefChange {
+            name: name.to_owned(),
+            old: old.map(str::to_owned),
+            new: new.map(str::to_owned),
+        };
+        assert_eq!(
+            compare(&from, &to),
+            [
+                change("refs/heads/gone", Some("a"), None),
+                change("refs/heads/main", Some("a"), Some("c")),
+                change("refs/heads/new", None, Some("d")),
+            ]
+        );
+        assert!(compare(&to, &to).is_empty());
+    }
+
+    #[tokio::test]
+    async fn commits_are_listed_down_to_the_old_tip_or_the_limit() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let old = commit(&repo, None, &[base], "old\n\nbody");
+        let middle = commit(&repo, None, &[old], "middle");
+        let new = commit(&repo, None, &[middle], "new");
+        let elsewhere = commit(&repo, None, &[base], "elsewhere");
+        let (repo_data, store, index) = published(&repo);
+        let mut reader = CommitReader::new(&repo_data, index, &store);
+
+        let shas = |commits: &[CommitSummary]| -> Vec<String> {
+            commits.iter().map(|commit| commit.sha.clone()).collect()
+        };
+
+        let (commits, truncated) = reader
+            .commits_between(&old.to_string(), &new.to_string(), 100)
+            .await
+            .unwrap();
+        assert_eq!(shas(&commits), [new.to_string(), middle.to_string()]);
+        assert!(!truncated);
+        assert_eq!(commits[0].author, "Test <test@example.com>");
+        assert_eq!(commits[0].subject, "new");
+
+        let (commits, truncated) = reader
+            .commits_between(&old.to_string(), &new.to_string(), 1)
+            .await
+            .unwrap();
+        assert_eq!(shas(&commits), [new.to_string()]);
+        assert!(truncated);
+
+        // A force push never reaches the old tip
+        let (commits, truncated) = reader
+            .commits_between(&elsewhere.to_string(), &new.to_string(), 100)
+            .await
+            .unwrap();
+        assert_eq!(commits.len(), 4);
+        assert_eq!(commits[2].subject, "old");
+        assert!(!truncated);
+    }
+}
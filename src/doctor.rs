@@ -0,0 +1,299 @@
+//! `inv4-git doctor`: checks that this machine can push to and fetch from INV4 repositories.
+//!
+//! The checks only judge what `Probes` report, so they can run against anything implementing
+//! it rather than the real environment.
+
+use crate::{
+    auth,
+    chain::Chain,
+    config,
+    primitives::{BoxResult, Config},
+    store::default_store,
+    util::find_on_path,
+};
+use async_trait::async_trait;
+use std::{path::PathBuf, time::Duration};
+use tokio::time::Instant;
+
+/// The name git looks for to handle `inv4://` URLs.
+pub const HELPER_BINARY: &str = "git-remote-inv4";
+
+/// The outcome of one check.
+pub struct Check {
+    pub name: &'static str,
+    /// Whether pushing or fetching can't work at all when this check fails
+    pub required: bool,
+    pub result: Result<String, String>,
+}
+
+/// What the checks look at.
+#[async_trait(?Send)]
+pub trait Probes {
+    fn helper_on_path(&self) -> Option<PathBuf>;
+
+    fn config(&self) -> BoxResult<Config>;
+
+    /// How long the chain took to answer for its best block.
+    async fn chain(&self, config: &Config) -> BoxResult<Duration>;
+
+    /// How many peers the node reports, asked with the `chain_auth` header.
+    async fn chain_auth(&self, config: &Config) -> BoxResult<usize>;
+
+    async fn store(&self, config: &Config) -> BoxResult<()>;
+
+    /// The nickname of a stored credential, if there is one. It is never decrypted.
+    fn credential(&self) -> BoxResult<Option<String>>;
+}
+
+/// The environment the helper runs in.
+pub struct LiveProbes;
+
+#[async_trait(?Send)]
+impl Probes for LiveProbes {
+    fn helper_on_path(&self) -> Option<PathBuf> {
+        find_on_path(HELPER_BINARY).or_else(|| find_on_path(&format!("{}.exe", HELPER_BINARY)))
+    }
+
+    fn config(&self) -> BoxResult<Config> {
+        Config::load()
+    }
+
+    async fn chain(&self, config: &Config) -> BoxResult<Duration> {
+        let chain = Chain::connect(config).await?;
+        let started = Instant::now();
+        chain.best_block().await?;
+
+        Ok(started.elapsed())
+    }
+
+    async fn chain_auth(&self, config: &Config) -> BoxResult<usize> {
+        Chain::connect(config).await?.health().await
+    }
+
+    async fn store(&self, config: &Config) -> BoxResult<()> {
+        default_store(config).await?.check().await
+    }
+
+    fn credential(&self) -> BoxResult<Option<String>> {
+        Ok(auth::stored_credential(None)?.map(|credential| credential.username))
+    }
+}
+
+/// Run every check. A config file that doesn't parse fails its check, and the others go on with
+/// the defaults.
+pub async fn run(probes: &dyn Probes) -> Vec<Check> {
+    let mut checks = vec![Check {
+        name: "helper",
+        required: true,
+        result: match probes.helper_on_path() {
+            Some(path) => Ok(format!("{} found at {}", HELPER_BINARY, path.display())),
+            None => Err(format!(
+                "No {} on PATH, so git can't use inv4:// remotes; run `inv4-git install`",
+                HELPER_BINARY
+            )),
+        },
+    }];
+
+    let config_path = config::path()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|e| e.to_string());
+    let config = match probes.config() {
+        Ok(config) => {
+            checks.push(Check {
+                name: "config",
+                required: true,
+                result: Ok(format!("{} parses", config_path)),
+            });
+            config
+        }
+        Err(e) => {
+            checks.push(Check {
+                name: "config",
+                required: true,
+                result: Err(e.to_string()),
+            });
+            Config::default()
+        }
+    };
+
+    checks.push(Check {
+        name: "chain",
+        required: true,
+        result: match probes.chain(&config).await {
+            Ok(latency) => Ok(format!(
+                "{} answered in {} ms",
+                config.chain_endpoint,
+                latency.as_millis()
+            )),
+            Err(e) => Err(format!("{}: {}", config.chain_endpoint, e)),
+        },
+    });
+
+    if let Some(auth) = &config.chain_auth {
+        checks.push(Check {
+            name: "chain auth",
+            required: true,
+            result: match probes.chain_auth(&config).await {
+                Ok(peers) => Ok(format!(
+                    "{} from ${} accepted, system_health reports {} peers",
+                    auth.header, auth.value_env, peers
+                )),
+                Err(e) => Err(format!(
+                    "{} from ${} not accepted: {}",
+                    auth.header, auth.value_env, e
+                )),
+            },
+        });
+    }
+
+    checks.push(Check {
+        name: "store",
+        required: true,
+        result: match probes.store(&config).await {
+            Ok(()) => Ok("The object store responds".to_owned()),
+            Err(e) => Err(e.to_string()),
+        },
+    });
+
+    checks.push(Check {
+        name: "credential",
+        required: false,
+        result: match probes.credential() {
+            Ok(Some(name)) => Ok(format!("Stored for {}", name)),
+            Ok(None) => Err("None stored yet; the first push asks for a seed phrase".to_owned()),
+            Err(e) => Err(format!("Could not ask the credential helpers: {}", e)),
+        },
+    });
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainAuth;
+
+    /// Probes with a working environment, save for what is switched off.
+    #[derive(Default)]
+    struct FakeProbes {
+        no_helper: bool,
+        broken_config: bool,
+        chain_auth: bool,
+        store_down: bool,
+        no_credential: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl Probes for FakeProbes {
+        fn helper_on_path(&self) -> Option<PathBuf> {
+            (!self.no_helper).then(|| PathBuf::from("/usr/bin").join(HELPER_BINARY))
+        }
+
+        fn config(&self) -> BoxResult<Config> {
+            match self.broken_config {
+                true => Err("line 3: expected a value".into()),
+                false => Ok(Config {
+                    chain_auth: self.chain_auth.then(|| ChainAuth {
+                        header: "Authorization".to_owned(),
+                        value_env: "NODE_TOKEN".to_owned(),
+                    }),
+                    ..Config::default()
+                }),
+            }
+        }
+
+        async fn chain(&self, _config: &Config) -> BoxResult<Duration> {
+            Ok(Duration::from_millis(42))
+        }
+
+        async fn chain_auth(&self, _config: &Config) -> BoxResult<usize> {
+            Ok(8)
+        }
+
+        async fn store(&self, _config: &Config) -> BoxResult<()> {
+            match self.store_down {
+                true => Err("connection refused".into()),
+                false => Ok(()),
+            }
+        }
+
+        fn credential(&self) -> BoxResult<Option<String>> {
+            Ok((!self.no_credential).then(|| "alice".to_owned()))
+        }
+    }
+
+    /// The names of the checks that failed.
+    async fn failed(probes: FakeProbes) -> Vec<&'static str> {
+        run(&probes)
+            .await
+            .into_iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn healthy_setup_passes_every_check() {
+        let checks = run(&FakeProbes::default()).await;
+
+        let names: Vec<_> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["helper", "config", "chain", "store", "credential"]);
+        assert!(checks.iter().all(|check| check.result.is_ok()));
+        assert_eq!(
+            checks[2].result.as_deref(),
+            Ok("wss://tinker.invarch.network:443 answered in 42 ms")
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_per_check() {
+        let probes = FakeProbes {
+            no_helper: true,
+            store_down: true,
+            no_credential: true,
+            ..FakeProbes::default()
+        };
+        assert_eq!(failed(probes).await, ["helper", "store", "credential"]);
+
+        let checks = run(&FakeProbes {
+            no_credential: true,
+            ..FakeProbes::default()
+        })
+        .await;
+        assert!(
+            !checks
+                .iter()
+                .find(|check| check.name == "credential")
+                .unwrap()
+                .required
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_config_fails_and_the_rest_use_the_defaults() {
+        let probes = FakeProbes {
+            broken_config: true,
+            chain_auth: true,
+            ..FakeProbes::default()
+        };
+        assert_eq!(failed(probes).await, ["config"]);
+    }
+
+    #[tokio::test]
+    async fn chain_auth_is_checked_when_configured() {
+        let checks = run(&FakeProbes {
+            chain_auth: true,
+            ..FakeProbes::default()
+        })
+        .await;
+
+        let auth = checks
+            .iter()
+            .find(|check| check.name == "chain auth")
+            .unwrap();
+        assert_eq!(
+            auth.result.as_deref(),
+            Ok("Authorization from $NODE_TOKEN accepted, system_health reports 8 peers")
+        );
+    }
+}
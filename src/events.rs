@@ -0,0 +1,203 @@
+//! Structured progress of pushes and fetches.
+//!
+//! Deep code reports what happens as an `Event` instead of formatting messages itself, and
+//! `emit` turns events into status output. That keeps a single source of progress for anything
+//! that wants to render it differently.
+
+use crate::{
+    fees::{self, Breakdown, Reconciled},
+    metrics, output, redact,
+    util::human_size,
+};
+use log::debug;
+use subxt::ext::sp_core::H256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The objects a push or fetch of `ref_name` has to move
+    Enumerated {
+        ref_name: String,
+        objects: usize,
+        bytes: u64,
+        pushing: bool,
+    },
+    /// A MultiObject, the `index`th of `total`, started uploading
+    UploadStarted {
+        index: usize,
+        total: usize,
+        objects: usize,
+        bytes: u64,
+    },
+    /// An upload finished as `cid`, `bytes` shrunk to `compressed` on the way
+    UploadFinished {
+        cid: String,
+        bytes: u64,
+        compressed: u64,
+    },
+    /// A MultiObject started downloading
+    DownloadStarted { multi_object: String },
+    /// A MultiObject finished downloading, `bytes` before decompression
+    DownloadFinished { multi_object: String, bytes: u64 },
+    /// `written` of `total` bytes of fetched objects are in the object database
+    Written { written: u64, total: u64 },
+    /// A transaction was handed to the node
+    Submitted { extrinsic_hash: H256 },
+    /// A transaction made it into `block_hash`
+    InBlock {
+        extrinsic_hash: H256,
+        block_hash: H256,
+    },
+    /// The transaction `extrinsic_hash`, a `call`, cost its signer `fee` and reserved `deposit`
+    FeePaid {
+        extrinsic_hash: H256,
+        call: &'static str,
+        fee: u128,
+        deposit: u128,
+    },
+    /// What a push's calls are estimated to cost, before anything is submitted
+    FeesEstimated { breakdown: Breakdown },
+    /// What a push's calls cost against the estimate, once it landed
+    FeesReconciled { calls: Vec<Reconciled> },
+    /// A push of `name` ended, successfully or not
+    RefPushed {
+        name: String,
+        result: Result<(), String>,
+    },
+    /// The operation was cut short by Ctrl-C, after cleaning up
+    Interrupted,
+    /// The operation failed with `message`, `code` naming its entry in the error catalog
+    Failed {
+        code: Option<&'static str>,
+        message: String,
+    },
+}
+
+/// Report `event`.
+pub fn emit(event: Event) {
+    debug!("{}", redact::text(&format!("{:?}", event)));
+    metrics::record(&event);
+    fees::record(&event);
+
+    match &event {
+        Event::Interrupted => output::warn("Interrupted"),
+        event => {
+            if let Some(line) = status_line(event) {
+                output::status(line)
+            }
+        }
+    }
+}
+
+/// The status line shown for `event`, if it gets one.
+fn status_line(event: &Event) -> Option<String> {
+    Some(match event {
+        Event::Enumerated {
+            ref_name,
+            objects,
+            bytes,
+            pushing,
+        } => format!(
+            "{} {} objects ({}) for {}...",
+            if *pushing { "Pushing" } else { "Fetching" },
+            objects,
+            human_size(*bytes as usize),
+            ref_name
+        ),
+        Event::UploadStarted {
+            index,
+            total,
+            objects,
+            bytes,
+        } => format!(
+            "Uploading pack {}/{}: {} objects ({})...",
+            index,
+            total,
+            objects,
+            human_size(*bytes as usize)
+        ),
+        Event::UploadFinished {
+            bytes, compressed, ..
+        } => format!(
+            "Uploaded {}, compressed {:.1}x",
+            human_size(*compressed as usize),
+            *bytes as f64 / (*compressed).max(1) as f64
+        ),
+        Event::DownloadStarted { multi_object } => format!(
+            "Downloading pack {}...",
+            &multi_object[..multi_object.len().min(12)]
+        ),
+        Event::Written { written, total } => format!(
+            "Writing objects: {}% ({}/{})",
+            written * 100 / (*total).max(1),
+            human_size(*written as usize),
+            human_size(*total as usize)
+        ),
+        Event::Submitted { extrinsic_hash } => format!(
+            "Submitted transaction {:?}, waiting for a block...",
+            extrinsic_hash
+        ),
+        Event::Interrupted
+        | Event::DownloadFinished { .. }
+        | Event::InBlock { .. }
+        | Event::FeePaid { .. }
+        | Event::FeesEstimated { .. }
+        | Event::FeesReconciled { .. }
+        | Event::RefPushed { .. }
+        | Event::Failed { .. } => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_events_get_status_lines() {
+        assert_eq!(
+            status_line(&Event::UploadStarted {
+                index: 2,
+                total: 3,
+                objects: 40,
+                bytes: 2048,
+            })
+            .as_deref(),
+            Some("Uploading pack 2/3: 40 objects (2.00 KiB)...")
+        );
+        assert_eq!(
+            status_line(&Event::UploadFinished {
+                cid: "Qm".to_owned(),
+                bytes: 3000,
+                compressed: 1000,
+            })
+            .as_deref(),
+            Some("Uploaded 1000 B, compressed 3.0x")
+        );
+        assert_eq!(
+            status_line(&Event::Written {
+                written: 0,
+                total: 0
+            })
+            .as_deref(),
+            Some("Writing objects: 0% (0 B/0 B)")
+        );
+        assert_eq!(
+            status_line(&Event::DownloadStarted {
+                multi_object: "ab".to_owned()
+            })
+            .as_deref(),
+            Some("Downloading pack ab...")
+        );
+    }
+
+    #[test]
+    fn outcomes_are_left_to_their_own_reports() {
+        assert_eq!(
+            status_line(&Event::RefPushed {
+                name: "refs/heads/main".to_owned(),
+                result: Ok(()),
+            }),
+            None
+        );
+        assert_eq!(status_line(&Event::Interrupted), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Pushes published by another account than the one pushing, e.g. an IPS whose multisig needs a
+//! cold-storage account operated by someone else.
+//!
+//! With `GIT_INV4_EXPORT_CALL=<path>`, or `git push -o export-call=<path>`, a push uploads and
+//! mints its packs and RepoData as usual, but writes the `operate_multisig` call publishing them
+//! to `<path>` instead of signing it: the SCALE-encoded call, byte for byte what the push would
+//! have submitted, and a JSON rendering of it at `<path>.json`. Whoever operates the signing
+//! account submits it with `inv4-git submit-call <path>` or a wallet such as polkadot.js.
+//!
+//! The minted IPFs are held back from the next push's cleanup, and further pushes from the clone
+//! are refused, until `inv4-git push-state finish` finds the call landed and moves the
+//! remote-tracking refs, or `inv4-git push-state abandon` leaves the IPFs to be burned.
+
+use crate::{
+    error,
+    primitives::{BoxResult, IpfId, IpsId, SubassetId},
+    remote_helper,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use subxt::ext::sp_core::blake2_256;
+
+/// The call a push exports.
+pub const CALL: &str = "INV4.operate_multisig";
+
+/// A publish call written out for another account to sign, as rendered in `<path>.json`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExportedCall {
+    /// `<pallet>.<call>`
+    pub call: String,
+    /// blake2-256 of the call, as multisig tools show it
+    pub call_hash: String,
+    /// The SCALE-encoded call, as in the file this one renders
+    pub call_data: String,
+    /// The chain it was composed for
+    pub genesis_hash: String,
+    pub ips_id: IpsId,
+    pub subasset_id: Option<SubassetId>,
+    /// The account that minted the packs and RepoData, which hold them until the call lands
+    pub pusher: String,
+    pub pack_ipf_ids: Vec<IpfId>,
+    pub new_repo_data: IpfId,
+    pub old_repo_data: Option<IpfId>,
+    /// The refs the call moves, to `None` for deleted ones
+    pub refs: BTreeMap<String, Option<String>>,
+    /// The multisig metadata describing the push
+    pub metadata: String,
+    pub exported_to: PathBuf,
+}
+
+impl ExportedCall {
+    /// The rendering of `call_data` for the rest of the fields.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        call_data: &[u8],
+        genesis_hash: String,
+        ips_id: IpsId,
+        subasset_id: Option<SubassetId>,
+        pusher: String,
+        pack_ipf_ids: Vec<IpfId>,
+        new_repo_data: IpfId,
+        old_repo_data: Option<IpfId>,
+        refs: BTreeMap<String, Option<String>>,
+        metadata: &[u8],
+        exported_to: PathBuf,
+    ) -> Self {
+        Self {
+            call: CALL.to_owned(),
+            call_hash: format!("0x{}", hex::encode(blake2_256(call_data))),
+            call_data: format!("0x{}", hex::encode(call_data)),
+            genesis_hash,
+            ips_id,
+            subasset_id,
+            pusher,
+            pack_ipf_ids,
+            new_repo_data,
+            old_repo_data,
+            refs,
+            metadata: String::from_utf8_lossy(metadata).into_owned(),
+            exported_to,
+        }
+    }
+
+    pub fn call_data(&self) -> BoxResult<Vec<u8>> {
+        Ok(hex::decode(self.call_data.trim_start_matches("0x"))?)
+    }
+
+    /// The IPFs the call appends, which are the pusher's until it lands.
+    pub fn ipf_ids(&self) -> Vec<IpfId> {
+        let mut ipf_ids = self.pack_ipf_ids.clone();
+        ipf_ids.push(self.new_repo_data);
+        ipf_ids
+    }
+
+    /// Write the call to `exported_to` and its rendering next to it.
+    pub fn write(&self) -> BoxResult<()> {
+        fs::write(&self.exported_to, self.call_data()?).map_err(|e| {
+            format!(
+                "Could not export the call to {}: {}",
+                self.exported_to.display(),
+                e
+            )
+        })?;
+        fs::write(json_path(&self.exported_to), self.to_json()?)?;
+
+        Ok(())
+    }
+
+    /// The call exported to `path`, checked against its rendering.
+    pub fn read(path: &Path) -> BoxResult<Self> {
+        let call_data = fs::read(path)
+            .map_err(|e| format!("Could not read the call in {}: {}", path.display(), e))?;
+        let json = fs::read_to_string(json_path(path)).map_err(|e| {
+            format!(
+                "Could not read {}, which describes the call: {}",
+                json_path(path).display(),
+                e
+            )
+        })?;
+        let exported = Self::from_json(&json)?;
+
+        if exported.call_data()? != call_data {
+            error!(format!(
+                "{} doesn't hold the call {} describes",
+                path.display(),
+                json_path(path).display()
+            ))
+        }
+
+        Ok(exported)
+    }
+
+    pub fn to_json(&self) -> BoxResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> BoxResult<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Where the JSON rendering of the call exported to `path` goes.
+pub fn json_path(path: &Path) -> PathBuf {
+    let mut json = OsString::from(path.as_os_str());
+    json.push(".json");
+    PathBuf::from(json)
+}
+
+/// Where this push exports its call to, if it does.
+pub fn path() -> Option<PathBuf> {
+    remote_helper::export_call_option().or_else(|| {
+        env::var_os("GIT_INV4_EXPORT_CALL")
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    /// A call publishing two packs to IPS 4, exported to `path`.
+    pub fn exported(path: PathBuf) -> ExportedCall {
+        ExportedCall::new(
+            &[0x47, 0x00, 0x04],
+            format!("0x{}", "ab".repeat(32)),
+            IpsId(4),
+            None,
+            "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_owned(),
+            vec![IpfId(10), IpfId(11)],
+            IpfId(12),
+            Some(IpfId(9)),
+            BTreeMap::from([
+                ("refs/heads/main".to_owned(), Some("a".repeat(40))),
+                ("refs/heads/old".to_owned(), None),
+            ]),
+            b"{\"type\":\"push\"}",
+            path,
+        )
+    }
+
+    #[test]
+    fn calls_are_rendered_with_their_hash_and_ipfs() {
+        let exported = exported(PathBuf::from("publish.call"));
+
+        assert_eq!(exported.call, "INV4.operate_multisig");
+        assert_eq!(exported.call_data, "0x470004");
+        assert_eq!(exported.call_data().unwrap(), [0x47, 0x00, 0x04]);
+        assert!(exported.call_hash.starts_with("0x") && exported.call_hash.len() == 66);
+        assert_eq!(exported.ipf_ids(), [IpfId(10), IpfId(11), IpfId(12)]);
+        assert_eq!(
+            json_path(&exported.exported_to),
+            Path::new("publish.call.json")
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&exported.to_json().unwrap()).unwrap();
+        assert_eq!(json["ips_id"], 4);
+        assert_eq!(json["refs"]["refs/heads/old"], serde_json::Value::Null);
+        assert_eq!(json["metadata"], "{\"type\":\"push\"}");
+    }
+
+    #[test]
+    fn written_calls_are_read_back_unless_they_were_changed() {
+        let dir = TempDir::new().unwrap();
+        let exported = exported(dir.child("publish.call"));
+        exported.write().unwrap();
+
+        assert_eq!(
+            fs::read(dir.child("publish.call")).unwrap(),
+            [0x47, 0x00, 0x04]
+        );
+        assert_eq!(ExportedCall::read(&exported.exported_to).unwrap(), exported);
+
+        fs::write(dir.child("publish.call"), [0x47, 0x00, 0x05]).unwrap();
+        let e = ExportedCall::read(&exported.exported_to)
+            .unwrap_err()
+            .to_string();
+        assert!(e.ends_with("publish.call.json describes"), "{}", e);
+
+        fs::remove_file(dir.child("publish.call.json")).unwrap();
+        let e = ExportedCall::read(&exported.exported_to)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("which describes the call"), "{}", e);
+    }
+}
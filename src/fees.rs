@@ -0,0 +1,323 @@
+//! Itemized fees of a push, for repositories paid for from a treasury.
+//!
+//! Before a push, every kind of call it submits is estimated with `payment_queryInfo`: the pack
+//! and RepoData mints, and the `operate_multisig` publishing them. The remove and append calls
+//! inside that multisig are estimated as if submitted on their own. The signer doesn't pay for
+//! them apart from the multisig, so they show what share of it they make up but don't count
+//! towards the total.
+//!
+//! Once the push landed, the fees and deposits of its calls are tallied from their `FeePaid`
+//! events and set against the estimate, scaled to how many calls of each kind were made.
+//! `payment_queryInfo` knows nothing of deposits, so those only show up then.
+
+use crate::{chain::Token, events::Event};
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// One line of a `Breakdown`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    /// What the calls are for, e.g. "pack mints"
+    pub label: &'static str,
+    /// The call, as `ChainCall::name` gives it
+    pub call: &'static str,
+    pub count: usize,
+    /// Estimated fee of one call
+    pub fee: u128,
+    pub weight: Option<u64>,
+    /// Inside the multisig batch, so paid for with it rather than on its own
+    pub batched: bool,
+}
+
+impl Item {
+    pub fn total(&self) -> u128 {
+        self.fee * self.count as u128
+    }
+}
+
+/// The estimated fees of a push, per kind of call.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Breakdown {
+    pub items: Vec<Item>,
+}
+
+impl Breakdown {
+    /// What the signer is estimated to pay: every item but the batched ones.
+    pub fn total(&self) -> u128 {
+        self.items
+            .iter()
+            .filter(|item| !item.batched)
+            .map(Item::total)
+            .sum()
+    }
+
+    /// The estimated fee of one `call`, averaged over the items submitting it on its own.
+    fn per_call(&self, call: &str) -> Option<u128> {
+        let (fees, count) = self
+            .items
+            .iter()
+            .filter(|item| !item.batched && item.call == call)
+            .fold((0, 0), |(fees, count), item| {
+                (fees + item.total(), count + item.count as u128)
+            });
+
+        (count > 0).then(|| fees / count)
+    }
+
+    /// The breakdown as table rows, the header first.
+    pub fn table(&self, token: &Token) -> Vec<String> {
+        let mut rows = vec![format!(
+            "{:<28} {:<22} {:>5} {:>18} {:>18} {:>14}",
+            "item", "call", "count", "fee each", "total", "weight"
+        )];
+
+        for item in &self.items {
+            let label = match item.batched {
+                true => format!("  {} (in batch)", item.label),
+                false => item.label.to_owned(),
+            };
+            rows.push(format!(
+                "{:<28} {:<22} {:>5} {:>18} {:>18} {:>14}",
+                label,
+                item.call,
+                item.count,
+                token.format(item.fee),
+                match item.batched {
+                    true => "-".to_owned(),
+                    false => token.format(item.total()),
+                },
+                item.weight
+                    .map_or_else(|| "?".to_owned(), |weight| weight.to_string())
+            ));
+        }
+
+        rows.push(format!(
+            "{:<28} {:<22} {:>5} {:>18} {:>18}",
+            "total",
+            "",
+            "",
+            "",
+            token.format(self.total())
+        ));
+        rows
+    }
+}
+
+/// What the calls named `call` of a push were estimated to cost and what they cost.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reconciled {
+    pub call: &'static str,
+    pub count: usize,
+    /// `None` for calls the estimate didn't cover, e.g. burning what an earlier push left
+    pub estimated: Option<u128>,
+    pub paid: u128,
+    /// Reserved by the calls, e.g. storage deposits
+    pub deposit: u128,
+}
+
+impl Reconciled {
+    /// How much more was paid than estimated, negative when less.
+    pub fn delta(&self) -> Option<i128> {
+        self.estimated
+            .map(|estimated| self.paid as i128 - estimated as i128)
+    }
+}
+
+/// A fee taken from a `FeePaid` event.
+struct Paid {
+    call: &'static str,
+    fee: u128,
+    deposit: u128,
+}
+
+static PAID: Mutex<Vec<Paid>> = Mutex::new(vec![]);
+
+/// Keep the fee of a `FeePaid` event for `reconcile`.
+pub fn record(event: &Event) {
+    if let Event::FeePaid {
+        call, fee, deposit, ..
+    } = event
+    {
+        PAID.lock().unwrap_or_else(|e| e.into_inner()).push(Paid {
+            call: *call,
+            fee: *fee,
+            deposit: *deposit,
+        });
+    }
+}
+
+/// Where the fees paid so far end, for `reconcile` to start from.
+pub fn mark() -> usize {
+    PAID.lock().unwrap_or_else(|e| e.into_inner()).len()
+}
+
+/// The fees paid since `mark`, per call, against `estimate` scaled to the calls made.
+pub fn reconcile(estimate: &Breakdown, mark: usize) -> Vec<Reconciled> {
+    let mut calls: BTreeMap<&'static str, Reconciled> = BTreeMap::new();
+
+    for paid in PAID
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .skip(mark)
+    {
+        let reconciled = calls.entry(paid.call).or_insert(Reconciled {
+            call: paid.call,
+            count: 0,
+            estimated: None,
+            paid: 0,
+            deposit: 0,
+        });
+        reconciled.count += 1;
+        reconciled.paid += paid.fee;
+        reconciled.deposit += paid.deposit;
+    }
+
+    for reconciled in calls.values_mut() {
+        reconciled.estimated = estimate
+            .per_call(reconciled.call)
+            .map(|fee| fee * reconciled.count as u128);
+    }
+
+    calls.into_values().collect()
+}
+
+/// `reconciled` as table rows, the header first.
+pub fn reconciliation_table(reconciled: &[Reconciled], token: &Token) -> Vec<String> {
+    let mut rows = vec![format!(
+        "{:<22} {:>5} {:>18} {:>18} {:>18} {:>18}",
+        "call", "count", "estimated", "paid", "delta", "deposits"
+    )];
+
+    for call in reconciled {
+        rows.push(format!(
+            "{:<22} {:>5} {:>18} {:>18} {:>18} {:>18}",
+            call.call,
+            call.count,
+            call.estimated
+                .map_or_else(|| "-".to_owned(), |fee| token.format(fee)),
+            token.format(call.paid),
+            call.delta()
+                .map_or_else(|| "-".to_owned(), |delta| signed(delta, token)),
+            token.format(call.deposit)
+        ));
+    }
+
+    rows
+}
+
+fn signed(amount: i128, token: &Token) -> String {
+    match amount < 0 {
+        true => format!("-{}", token.format(amount.unsigned_abs())),
+        false => format!("+{}", token.format(amount as u128)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use subxt::ext::sp_core::H256;
+
+    fn token() -> Token {
+        Token {
+            decimals: 3,
+            symbol: "TNKR".to_owned(),
+        }
+    }
+
+    fn item(label: &'static str, call: &'static str, count: usize, fee: u128) -> Item {
+        Item {
+            label,
+            call,
+            count,
+            fee,
+            weight: Some(1000),
+            batched: false,
+        }
+    }
+
+    fn estimate() -> Breakdown {
+        Breakdown {
+            items: vec![
+                item("pack mints", "Ipf.mint", 3, 100),
+                item("RepoData mint", "Ipf.mint", 1, 120),
+                item("publish", "INV4.operate_multisig", 1, 500),
+                Item {
+                    batched: true,
+                    ..item("pack appends", "INV4.append", 3, 40)
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn batched_calls_are_listed_but_not_totalled() {
+        let estimate = estimate();
+        assert_eq!(estimate.total(), 920);
+        assert_eq!(estimate.per_call("Ipf.mint"), Some(105));
+        assert_eq!(estimate.per_call("INV4.append"), None);
+
+        let rows = estimate.table(&token());
+        assert_eq!(rows.len(), 6);
+        assert!(
+            rows[4].starts_with("  pack appends (in batch)"),
+            "{}",
+            rows[4]
+        );
+        assert!(rows[4].contains(" 0.04 TNKR "), "{}", rows[4]);
+        assert!(rows[5].starts_with("total") && rows[5].ends_with(" 0.92 TNKR"));
+    }
+
+    #[test]
+    fn fees_paid_are_set_against_the_estimate_per_call() {
+        let mark = mark();
+        for (call, fee, deposit) in [
+            ("Ipf.mint", 110, 10),
+            ("Ipf.mint", 110, 10),
+            ("INV4.operate_multisig", 450, 0),
+            ("Ipf.burn", 30, 0),
+        ] {
+            record(&Event::FeePaid {
+                extrinsic_hash: H256::zero(),
+                call,
+                fee,
+                deposit,
+            });
+        }
+        record(&Event::Interrupted);
+
+        let reconciled = reconcile(&estimate(), mark);
+        assert_eq!(
+            reconciled,
+            [
+                Reconciled {
+                    call: "INV4.operate_multisig",
+                    count: 1,
+                    estimated: Some(500),
+                    paid: 450,
+                    deposit: 0,
+                },
+                Reconciled {
+                    call: "Ipf.burn",
+                    count: 1,
+                    estimated: None,
+                    paid: 30,
+                    deposit: 0,
+                },
+                Reconciled {
+                    call: "Ipf.mint",
+                    count: 2,
+                    estimated: Some(210),
+                    paid: 220,
+                    deposit: 20,
+                },
+            ]
+        );
+        assert_eq!(reconciled[0].delta(), Some(-50));
+        assert_eq!(reconciled[2].delta(), Some(10));
+
+        let rows = reconciliation_table(&reconciled, &token());
+        assert!(rows[1].contains(" -0.05 TNKR "), "{}", rows[1]);
+        assert!(rows[2].contains(" - "), "{}", rows[2]);
+        assert!(rows[3].contains(" +0.01 TNKR "), "{}", rows[3]);
+    }
+}
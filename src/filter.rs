@@ -0,0 +1,283 @@
+//! Fetches that leave out large blobs, like `git clone --filter=blob:limit=<size>`.
+//!
+//! git checks that everything a fetch brings in is complete, except for what objects from a
+//! promisor remote reference. So when blobs are left out, the objects that were fetched are
+//! also copied into a pack marked as a promisor pack, and the remote is configured as one.
+
+use crate::{error, primitives::BoxResult, util::parse_size};
+use git2::{Buf, Oid, Repository};
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Blobs a fetch leaves out, and the ones it did.
+#[derive(Debug)]
+pub struct BlobFilter {
+    /// Blobs of at least this many bytes are left out
+    pub limit: u64,
+    /// A {sha1 -> size} map of the blobs left out
+    pub skipped: BTreeMap<Oid, u64>,
+}
+
+impl BlobFilter {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// The limit of a git filter spec, `blob:none` or `blob:limit=<size>`, or of the
+    /// `blob-limit:<size>` URL parameter.
+    pub fn parse_limit(spec: &str) -> BoxResult<u64> {
+        match spec {
+            "blob:none" => Ok(0),
+            _ => match spec
+                .strip_prefix("blob:limit=")
+                .or_else(|| spec.strip_prefix("blob-limit:"))
+            {
+                Some(size) => parse_size(size),
+                None => error!(format!(
+                    "Unsupported filter {:?}; only blob:none and blob:limit=<size> are",
+                    spec
+                )),
+            },
+        }
+    }
+
+    /// Leave out the blob `oid` if it is too large, returning whether it was.
+    pub fn skip(&mut self, oid: Oid, size: u64) -> bool {
+        if size < self.limit {
+            return false;
+        }
+
+        self.skipped.insert(oid, size);
+        true
+    }
+
+    /// The git filter spec equivalent to this filter.
+    pub fn spec(&self) -> String {
+        match self.limit {
+            0 => "blob:none".to_owned(),
+            limit => format!("blob:limit={}", limit),
+        }
+    }
+}
+
+/// The blobs fetches from a remote left out, kept in `skipped-blobs` in its `state_dir` as
+/// `<sha1> <size>` lines.
+pub struct SkippedBlobs {
+    path: PathBuf,
+    /// A {sha1 -> size} map
+    pub blobs: BTreeMap<Oid, u64>,
+}
+
+impl SkippedBlobs {
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join("skipped-blobs");
+
+        let blobs = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(oid, size)| Some((oid.parse().ok()?, size.trim().parse().ok()?)))
+            .collect();
+
+        Self { path, blobs }
+    }
+
+    /// Forget the blobs that are in `repo` by now.
+    pub fn prune(&mut self, repo: &Repository) -> BoxResult<()> {
+        let odb = repo.odb()?;
+        self.blobs.retain(|&oid, _| !odb.exists(oid));
+
+        Ok(())
+    }
+
+    pub fn save(&self) -> BoxResult<()> {
+        if self.blobs.is_empty() {
+            if self.path.exists() {
+                fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+
+        fs::create_dir_all(
+            self.path
+                .parent()
+                .ok_or("Skipped blobs path has no parent")?,
+        )?;
+        fs::write(
+            &self.path,
+            self.blobs
+                .iter()
+                .map(|(oid, size)| format!("{} {}\n", oid, size))
+                .collect::<String>(),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Make `remote_name` a promisor remote of `repo`, as `git clone --filter` would have.
+pub fn configure_promisor(
+    repo: &Repository,
+    remote_name: &str,
+    filter: &BlobFilter,
+) -> BoxResult<()> {
+    let mut config = repo.config()?;
+
+    config.set_bool(&format!("remote.{}.promisor", remote_name), true)?;
+    config.set_str(
+        &format!("remote.{}.partialclonefilter", remote_name),
+        &filter.spec(),
+    )?;
+
+    if config.get_string("extensions.partialclone").is_err() {
+        config.set_str("extensions.partialclone", remote_name)?;
+    }
+
+    Ok(())
+}
+
+/// Copy the objects of `oids` that `repo` has into a new pack marked as a promisor pack.
+pub fn write_promisor_pack(repo: &Repository, oids: &HashSet<Oid>) -> BoxResult<()> {
+    let odb = repo.odb()?;
+    let mut builder = repo.packbuilder()?;
+
+    for &oid in oids.iter().filter(|&&oid| odb.exists(oid)) {
+        builder.insert_object(oid, None)?;
+    }
+
+    if builder.object_count() == 0 {
+        return Ok(());
+    }
+
+    let mut pack = Buf::new();
+    builder.write_buf(&mut pack)?;
+
+    // The indexer names the pack, so look for it among the packs that weren't there before
+    let pack_dir = repo.path().join("objects").join("pack");
+    let before = packs(&pack_dir);
+
+    let mut writer = odb.packwriter()?;
+    writer.write_all(&pack)?;
+    writer.commit()?;
+
+    for pack in packs(&pack_dir).difference(&before) {
+        debug!("Marking {} as a promisor pack", pack.display());
+        fs::write(pack.with_extension("promisor"), "")?;
+    }
+
+    Ok(())
+}
+
+fn packs(dir: &Path) -> BTreeSet<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().map_or(false, |ext| ext == "pack"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::{commit, repository};
+
+    #[test]
+    fn limits_come_from_git_specs_and_url_parameters() {
+        assert_eq!(BlobFilter::parse_limit("blob:none").unwrap(), 0);
+        assert_eq!(BlobFilter::parse_limit("blob:limit=1k").unwrap(), 1024);
+        assert_eq!(BlobFilter::parse_limit("blob-limit:2MiB").unwrap(), 2 << 20);
+        assert!(BlobFilter::parse_limit("tree:0").is_err());
+
+        assert_eq!(BlobFilter::new(0).spec(), "blob:none");
+        assert_eq!(BlobFilter::new(1000).spec(), "blob:limit=1000");
+    }
+
+    #[test]
+    fn blobs_at_the_limit_are_skipped() {
+        let mut filter = BlobFilter::new(100);
+
+        assert!(!filter.skip(Oid::from_bytes(&[1; 20]).unwrap(), 99));
+        assert!(filter.skip(Oid::from_bytes(&[2; 20]).unwrap(), 100));
+        assert_eq!(
+            filter.skipped,
+            BTreeMap::from([(Oid::from_bytes(&[2; 20]).unwrap(), 100)])
+        );
+
+        assert!(BlobFilter::new(0).skip(Oid::from_bytes(&[3; 20]).unwrap(), 0));
+    }
+
+    #[test]
+    fn skipped_blobs_are_pruned_once_fetched() {
+        let (dir, repo) = repository();
+        let fetched = repo.blob(b"fetched").unwrap();
+        let missing = Oid::from_bytes(&[4; 20]).unwrap();
+        let state_dir = dir.path().join(".git/inv4/origin");
+
+        let mut skipped = SkippedBlobs::load(&state_dir);
+        skipped.blobs.insert(fetched, 7);
+        skipped.blobs.insert(missing, 9);
+        skipped.save().unwrap();
+
+        let mut skipped = SkippedBlobs::load(&state_dir);
+        assert_eq!(skipped.blobs.len(), 2);
+        skipped.prune(&repo).unwrap();
+        skipped.save().unwrap();
+        assert_eq!(
+            SkippedBlobs::load(&state_dir).blobs,
+            BTreeMap::from([(missing, 9)])
+        );
+
+        skipped.blobs.clear();
+        skipped.save().unwrap();
+        assert!(!skipped.path().exists());
+    }
+
+    #[test]
+    fn fetched_objects_go_into_a_promisor_pack_of_a_promisor_remote() {
+        let (_dir, repo) = repository();
+        let tip = commit(&repo, None, &[], "tip");
+
+        write_promisor_pack(&repo, &HashSet::from([tip])).unwrap();
+        let pack_dir = repo.path().join("objects").join("pack");
+        let packs = packs(&pack_dir);
+        assert_eq!(packs.len(), 1);
+        assert!(packs
+            .iter()
+            .next()
+            .unwrap()
+            .with_extension("promisor")
+            .is_file());
+
+        configure_promisor(&repo, "origin", &BlobFilter::new(0)).unwrap();
+        let config = repo.config().unwrap().snapshot().unwrap();
+        assert!(config.get_bool("remote.origin.promisor").unwrap());
+        assert_eq!(
+            config.get_str("remote.origin.partialclonefilter").unwrap(),
+            "blob:none"
+        );
+        assert_eq!(config.get_str("extensions.partialclone").unwrap(), "origin");
+    }
+
+    #[test]
+    fn nothing_present_writes_no_pack() {
+        let (_dir, repo) = repository();
+
+        write_promisor_pack(&repo, &HashSet::from([Oid::from_bytes(&[5; 20]).unwrap()])).unwrap();
+        assert!(packs(&repo.path().join("objects").join("pack")).is_empty());
+    }
+}
@@ -0,0 +1,417 @@
+//! Generated repositories of a chosen shape, for trying pushes and fetches out.
+//!
+//! `FixtureRepo` describes the shape: how much history, branches, big blobs, tags, submodules,
+//! wide trees or unicode names. `build` writes it as a bare repository. Every author, committer
+//! and tagger time is fixed and all content comes from seeded generators, so the same
+//! description gives the same object ids on every machine. `same_objects` tells whether two
+//! repositories hold the same objects, e.g. a fixture and a clone of it.
+//!
+//! Built for tests, and with the `fixtures` feature, which `devnet` turns on.
+
+use crate::{error, primitives::BoxResult};
+use git2::{FileMode, ObjectType, Oid, Repository, Signature, Time};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+/// When the first commit is made; each commit and tag after it comes a minute later.
+const EPOCH: i64 = 1_600_000_000;
+
+/// The branch the history given to `commits` is made on.
+pub const MAIN_BRANCH: &str = "main";
+
+/// Seeds the content generated for a blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Seed(pub u64);
+
+impl Seed {
+    /// `len` bytes from an xorshift sequence, the same for every run but incompressible.
+    pub fn bytes(self, len: usize) -> Vec<u8> {
+        // xorshift would never leave 0
+        let mut state = (self.0 ^ 0x2545_f491_4f6c_dd1d) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+}
+
+/// An entry of a tree being built.
+#[derive(Clone, Copy)]
+enum Node {
+    Blob(Oid),
+    Gitlink(Oid),
+}
+
+/// The shape of a fixture repository.
+#[derive(Clone, Debug, Default)]
+pub struct FixtureRepo {
+    commits: usize,
+    branches: Vec<(String, usize)>,
+    blobs: Vec<(String, usize, Seed)>,
+    tags: Vec<String>,
+    submodules: Vec<(String, String)>,
+    wide: usize,
+    unicode_names: bool,
+}
+
+impl FixtureRepo {
+    pub fn new() -> Self {
+        Self {
+            commits: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Make `count` commits on `MAIN_BRANCH`, at least one.
+    pub fn commits(mut self, count: usize) -> Self {
+        self.commits = count.max(1);
+        self
+    }
+
+    /// Branch `name` off the tip of `MAIN_BRANCH` with `commits` of its own.
+    pub fn branch(mut self, name: &str, commits: usize) -> Self {
+        self.branches.push((name.to_owned(), commits));
+        self
+    }
+
+    /// Add a blob of `size` bytes at `path` in the last commit of `MAIN_BRANCH`.
+    pub fn blob(mut self, path: &str, size: usize, seed: Seed) -> Self {
+        self.blobs.push((path.to_owned(), size, seed));
+        self
+    }
+
+    /// Tag the tip of `MAIN_BRANCH` with an annotated tag.
+    pub fn tag_annotated(mut self, name: &str) -> Self {
+        self.tags.push(name.to_owned());
+        self
+    }
+
+    /// Add a submodule at `path` cloned from `url`, in the last commit of `MAIN_BRANCH`. Its
+    /// commit is derived from the URL and isn't in the repository, as in any superproject.
+    pub fn submodule(mut self, path: &str, url: &str) -> Self {
+        self.submodules.push((path.to_owned(), url.to_owned()));
+        self
+    }
+
+    /// Add `files` small files to a single directory in the first commit.
+    pub fn wide(mut self, files: usize) -> Self {
+        self.wide = files;
+        self
+    }
+
+    /// Add files whose names aren't ASCII, in precomposed and decomposed forms.
+    pub fn unicode_names(mut self) -> Self {
+        self.unicode_names = true;
+        self
+    }
+
+    /// Write the repository, bare, to `dir`, returning the refs made and their targets.
+    pub fn build(&self, dir: &Path) -> BoxResult<Vec<(String, Oid)>> {
+        let repo = Repository::init_bare(dir)?;
+        let mut builder = Builder {
+            repo: &repo,
+            files: BTreeMap::new(),
+            clock: 0,
+        };
+        let mut refs = vec![];
+
+        let mut tip = None;
+        for i in 0..self.commits {
+            if i == 0 {
+                builder.first_files(self)?;
+            }
+            if i + 1 == self.commits {
+                builder.last_files(self)?;
+            }
+
+            builder.write(
+                &format!("src/module_{}.txt", i % 8),
+                format!("revision {}\n", i).as_bytes(),
+            )?;
+            tip = Some(builder.commit(&format!("Commit {}", i), tip)?);
+        }
+        let main = tip.ok_or("Fixture has no commits")?;
+        refs.push((format!("refs/heads/{}", MAIN_BRANCH), main));
+
+        let main_files = builder.files.clone();
+        for (name, commits) in &self.branches {
+            builder.files = main_files.clone();
+
+            let mut tip = main;
+            for i in 0..*commits {
+                builder.write(
+                    &format!("branches/{}.txt", name),
+                    format!("{} revision {}\n", name, i).as_bytes(),
+                )?;
+                tip = builder.commit(&format!("{}: commit {}", name, i), Some(tip))?;
+            }
+            refs.push((format!("refs/heads/{}", name), tip));
+        }
+
+        for name in &self.tags {
+            let target = repo.find_object(main, Some(ObjectType::Commit))?;
+            let tag = repo.tag(
+                name,
+                &target,
+                &builder.signature()?,
+                &format!("Release {}", name),
+                false,
+            )?;
+            refs.push((format!("refs/tags/{}", name), tag));
+        }
+
+        for (name, target) in &refs {
+            repo.reference(name, *target, true, "fixture")?;
+        }
+        repo.set_head(&format!("refs/heads/{}", MAIN_BRANCH))?;
+
+        Ok(refs)
+    }
+}
+
+struct Builder<'a> {
+    repo: &'a Repository,
+    /// Paths of the next commit's tree
+    files: BTreeMap<String, Node>,
+    /// Minutes since `EPOCH` of the next signature
+    clock: i64,
+}
+
+impl Builder<'_> {
+    fn write(&mut self, path: &str, contents: &[u8]) -> BoxResult<()> {
+        let oid = self.repo.blob(contents)?;
+        self.files.insert(path.to_owned(), Node::Blob(oid));
+        Ok(())
+    }
+
+    fn first_files(&mut self, shape: &FixtureRepo) -> BoxResult<()> {
+        self.write("README.md", b"# Fixture\n\nA generated repository.\n")?;
+
+        for i in 0..shape.wide {
+            self.write(
+                &format!("wide/file_{:06}.txt", i),
+                format!("file {}\n", i).as_bytes(),
+            )?;
+        }
+
+        if shape.unicode_names {
+            // "café" precomposed and decomposed, which some file systems treat as one name
+            self.write("names/caf\u{e9}.txt", b"precomposed\n")?;
+            self.write("names/cafe\u{301}.txt", b"decomposed\n")?;
+            self.write("names/\u{65e5}\u{672c}\u{8a9e}.txt", b"cjk\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn last_files(&mut self, shape: &FixtureRepo) -> BoxResult<()> {
+        for (path, size, seed) in &shape.blobs {
+            let contents = seed.bytes(*size);
+            self.write(path, &contents)?;
+        }
+
+        if !shape.submodules.is_empty() {
+            let mut gitmodules = String::new();
+            for (path, url) in &shape.submodules {
+                gitmodules.push_str(&format!(
+                    "[submodule \"{}\"]\n\tpath = {}\n\turl = {}\n",
+                    path, path, url
+                ));
+                let target = Oid::hash_object(ObjectType::Commit, url.as_bytes())?;
+                self.files.insert(path.clone(), Node::Gitlink(target));
+            }
+            self.write(".gitmodules", gitmodules.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn signature(&mut self) -> BoxResult<Signature<'static>> {
+        let time = Time::new(EPOCH + self.clock * 60, 0);
+        self.clock += 1;
+
+        Ok(Signature::new(
+            "INV4 fixture",
+            "fixture@inv4.invalid",
+            &time,
+        )?)
+    }
+
+    fn commit(&mut self, message: &str, parent: Option<Oid>) -> BoxResult<Oid> {
+        let tree = self
+            .repo
+            .find_tree(write_tree(self.repo, &self.files, "")?)?;
+        let parents = match parent {
+            Some(parent) => vec![self.repo.find_commit(parent)?],
+            None => vec![],
+        };
+        let signature = self.signature()?;
+
+        Ok(self.repo.commit(
+            None,
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )?)
+    }
+}
+
+/// Write the tree of the entries of `files` under `prefix`, with its subtrees.
+fn write_tree(repo: &Repository, files: &BTreeMap<String, Node>, prefix: &str) -> BoxResult<Oid> {
+    let mut tree = repo.treebuilder(None)?;
+    let mut subdirs = BTreeSet::new();
+
+    for (path, node) in files.range(prefix.to_owned()..) {
+        let rest = match path.strip_prefix(prefix) {
+            Some(rest) => rest,
+            None => break,
+        };
+
+        match rest.split_once('/') {
+            Some((dir, _)) => {
+                subdirs.insert(dir.to_owned());
+            }
+            None => {
+                let (oid, mode) = match *node {
+                    Node::Blob(oid) => (oid, FileMode::Blob),
+                    Node::Gitlink(oid) => (oid, FileMode::Commit),
+                };
+                tree.insert(rest, oid, mode.into())?;
+            }
+        }
+    }
+
+    for dir in subdirs {
+        let subtree = write_tree(repo, files, &format!("{}{}/", prefix, dir))?;
+        tree.insert(&dir, subtree, FileMode::Tree.into())?;
+    }
+
+    Ok(tree.write()?)
+}
+
+/// Fail, naming how they differ, unless `a` and `b` hold the same objects.
+pub fn same_objects(a: &Repository, b: &Repository) -> BoxResult<()> {
+    let (a_objects, b_objects) = (objects(a)?, objects(b)?);
+
+    let missing: Vec<_> = a_objects.difference(&b_objects).collect();
+    let extra: Vec<_> = b_objects.difference(&a_objects).collect();
+    if !missing.is_empty() || !extra.is_empty() {
+        error!(format!(
+            "{} objects missing, e.g. {:?}, and {} extra, e.g. {:?}",
+            missing.len(),
+            missing.first(),
+            extra.len(),
+            extra.first()
+        ))
+    }
+
+    Ok(())
+}
+
+/// The ids of the objects in `repo`, each read once, which also checks they're intact.
+fn objects(repo: &Repository) -> BoxResult<BTreeSet<Oid>> {
+    let odb = repo.odb()?;
+    let mut oids = BTreeSet::new();
+    odb.foreach(|oid| {
+        oids.insert(*oid);
+        true
+    })?;
+
+    for oid in &oids {
+        odb.read(*oid)
+            .map_err(|e| format!("Object {} can't be read: {}", oid, e))?;
+    }
+
+    Ok(oids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn seeds_always_give_the_same_bytes() {
+        let bytes = Seed(42).bytes(64);
+
+        assert_eq!(bytes, Seed(42).bytes(64));
+        assert_eq!(&bytes[..16], Seed(42).bytes(16));
+        assert_ne!(bytes, Seed(43).bytes(64));
+        assert_ne!(Seed(0).bytes(8), vec![0; 8]);
+    }
+
+    #[test]
+    fn same_shape_gives_the_same_objects() {
+        let shape = FixtureRepo::new()
+            .commits(3)
+            .branch("dev", 2)
+            .blob("assets/big.bin", 4096, Seed(42))
+            .tag_annotated("v1.0")
+            .submodule("lib", "https://example.com/lib.git")
+            .wide(10)
+            .unicode_names();
+        let dir = TempDir::new().unwrap();
+
+        let refs = shape.build(&dir.child("a")).unwrap();
+        assert_eq!(refs, shape.build(&dir.child("b")).unwrap());
+        let names: Vec<_> = refs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["refs/heads/main", "refs/heads/dev", "refs/tags/v1.0"]
+        );
+
+        let a = Repository::open_bare(dir.child("a")).unwrap();
+        let b = Repository::open_bare(dir.child("b")).unwrap();
+        same_objects(&a, &b).unwrap();
+
+        let main = a.find_commit(refs[0].1).unwrap();
+        let dev = a.find_commit(refs[1].1).unwrap();
+        assert_eq!(dev.parent(0).unwrap().parent_id(0).unwrap(), main.id());
+        assert_eq!(a.find_tag(refs[2].1).unwrap().target_id(), main.id());
+        assert_eq!(a.head().unwrap().target(), Some(main.id()));
+
+        let tree = main.tree().unwrap();
+        let lib = tree.get_name("lib").unwrap();
+        assert_eq!(lib.filemode(), i32::from(FileMode::Commit));
+        assert!(a.find_object(lib.id(), None).is_err());
+        assert_eq!(
+            a.find_blob(tree.get_path(Path::new("assets/big.bin")).unwrap().id())
+                .unwrap()
+                .content(),
+            Seed(42).bytes(4096)
+        );
+        assert!(tree.get_path(Path::new("names/caf\u{e9}.txt")).is_ok());
+        assert!(tree.get_path(Path::new("names/cafe\u{301}.txt")).is_ok());
+
+        let wide = tree.get_path(Path::new("wide")).unwrap();
+        assert_eq!(a.find_tree(wide.id()).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn differing_objects_are_counted() {
+        let dir = TempDir::new().unwrap();
+        FixtureRepo::new().build(&dir.child("a")).unwrap();
+        FixtureRepo::new().build(&dir.child("b")).unwrap();
+        let a = Repository::open_bare(dir.child("a")).unwrap();
+        let b = Repository::open_bare(dir.child("b")).unwrap();
+
+        b.blob(b"only in b").unwrap();
+        let e = same_objects(&a, &b).unwrap_err().to_string();
+        assert!(
+            e.starts_with("0 objects missing, e.g. None, and 1 extra"),
+            "{}",
+            e
+        );
+
+        let e = same_objects(&b, &a).unwrap_err().to_string();
+        assert!(e.starts_with("1 objects missing"), "{}", e);
+    }
+}
@@ -0,0 +1,444 @@
+//! The envelope artifacts are uploaded in, saying how the bytes after it are encoded.
+//!
+//! A long-lived repository holds artifacts written by many versions of this helper. The envelope
+//! lets a reader tell what it has in front of it without trying decoders in turn: it is
+//! `ARTIFACT_MAGIC`, the envelope version, the kind of artifact and a flags byte naming the
+//! compression, whether the body is encrypted and how it is laid out. Artifacts predating it
+//! start right away with their body, and are decoded the way they always were.
+//!
+//! Large blobs are not wrapped: they are stored as plain UnixFS files so they can be streamed,
+//! and the metadata of their IPFs already says what they are.
+
+use crate::{
+    compression::{compress_data, try_decompress_data},
+    error,
+    inspect::Artifact,
+    primitives::{BoxResult, MultiObject, RepoData},
+};
+use codec::Decode;
+use std::fmt;
+
+/// Marks an artifact starting with a format envelope.
+const ARTIFACT_MAGIC: &[u8; 8] = b"INV4-AF\0";
+
+/// Version of the envelope written by this build.
+const ARTIFACT_VERSION: u8 = 1;
+
+/// Bytes of the envelope: magic, version, kind and flags.
+pub const ENVELOPE_LEN: usize = ARTIFACT_MAGIC.len() + 3;
+
+/// The two low bits of the flags: 0 uncompressed, 1 brotli.
+const COMPRESSION_MASK: u8 = 0b0011;
+/// The body is encrypted.
+const ENCRYPTED: u8 = 0b0100;
+/// The body is laid out as a separately readable header and data, see `MultiObject::to_bytes`.
+const SPLIT: u8 = 0b1000;
+/// Flags this build knows. Any other bit set means a newer writer.
+const KNOWN_FLAGS: u8 = COMPRESSION_MASK | ENCRYPTED | SPLIT;
+
+/// What an artifact holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    RepoData = 1,
+    MultiObject = 2,
+    RepoMeta = 3,
+}
+
+impl Kind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::RepoData),
+            2 => Some(Self::MultiObject),
+            3 => Some(Self::RepoMeta),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RepoData => "RepoData",
+            Self::MultiObject => "MultiObject",
+            Self::RepoMeta => "RepoMeta",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Brotli = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// The body is the artifact's encoding, compressed as a whole
+    Whole,
+    /// The body compresses its header and data separately, so the header can be read alone
+    Split,
+}
+
+/// How an artifact is encoded, as its envelope says.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub version: u8,
+    pub kind: Kind,
+    pub compression: Compression,
+    pub encrypted: bool,
+    pub layout: Layout,
+}
+
+impl Format {
+    /// The format this build writes artifacts of `kind` in.
+    fn current(kind: Kind) -> Self {
+        let (compression, layout) = match kind {
+            Kind::RepoData => (Compression::Brotli, Layout::Whole),
+            Kind::MultiObject => (Compression::Brotli, Layout::Split),
+            // Small, and left readable for anyone looking at the IPF
+            Kind::RepoMeta => (Compression::None, Layout::Whole),
+        };
+
+        Self {
+            version: ARTIFACT_VERSION,
+            kind,
+            compression,
+            encrypted: false,
+            layout,
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = self.compression as u8;
+        if self.encrypted {
+            flags |= ENCRYPTED;
+        }
+        if self.layout == Layout::Split {
+            flags |= SPLIT;
+        }
+
+        flags
+    }
+
+    /// The format in the envelope `bytes` start with, `None` for an artifact predating
+    /// envelopes.
+    pub fn parse(bytes: &[u8]) -> BoxResult<Option<Self>> {
+        if !bytes.starts_with(ARTIFACT_MAGIC) {
+            return Ok(None);
+        }
+
+        if bytes.len() < ENVELOPE_LEN {
+            error!("Artifact format envelope is truncated")
+        }
+
+        let [version, kind, flags] = [
+            bytes[ARTIFACT_MAGIC.len()],
+            bytes[ARTIFACT_MAGIC.len() + 1],
+            bytes[ARTIFACT_MAGIC.len() + 2],
+        ];
+
+        if version != ARTIFACT_VERSION {
+            error!(format!(
+                "Unsupported artifact format version {}; a newer git-remote-inv4 is needed",
+                version
+            ))
+        }
+
+        let kind = Kind::from_byte(kind).ok_or_else(|| {
+            format!(
+                "Unknown artifact kind {}; a newer git-remote-inv4 is needed",
+                kind
+            )
+        })?;
+
+        if flags & !KNOWN_FLAGS != 0 {
+            error!(format!(
+                "Unknown artifact format flags {:#010b}; a newer git-remote-inv4 is needed",
+                flags
+            ))
+        }
+
+        let compression = match flags & COMPRESSION_MASK {
+            0 => Compression::None,
+            1 => Compression::Brotli,
+            other => error!(format!(
+                "Unknown artifact compression {}; a newer git-remote-inv4 is needed",
+                other
+            )),
+        };
+
+        let layout = match flags & SPLIT {
+            0 => Layout::Whole,
+            _ => Layout::Split,
+        };
+
+        if layout == Layout::Split && kind != Kind::MultiObject {
+            error!(format!("A {} can't have a split layout", kind))
+        }
+
+        Ok(Some(Self {
+            version,
+            kind,
+            compression,
+            encrypted: flags & ENCRYPTED != 0,
+            layout,
+        }))
+    }
+
+    /// Undo the compression of a body compressed as a whole.
+    fn decompress(&self, body: &[u8]) -> BoxResult<Vec<u8>> {
+        Ok(match self.compression {
+            Compression::None => body.to_vec(),
+            Compression::Brotli => try_decompress_data(body)?,
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in format {}, {}, {}",
+            self.kind,
+            self.version,
+            match self.compression {
+                Compression::None => "uncompressed",
+                Compression::Brotli => "brotli",
+            },
+            match self.layout {
+                Layout::Whole => "in one piece",
+                Layout::Split => "header and data split",
+            }
+        )?;
+
+        if self.encrypted {
+            write!(f, ", encrypted")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Describe the format of an artifact, `None` being one predating envelopes.
+pub fn describe(format: Option<&Format>) -> String {
+    match format {
+        Some(format) => format.to_string(),
+        None => "legacy, without a format envelope".to_owned(),
+    }
+}
+
+/// Wrap `body`, the encoding of an artifact of `kind`, for upload in the format this build
+/// writes. Every upload of an artifact goes through here, so that a change of format is made in
+/// one place.
+///
+/// Bodies with a split layout are laid out, and their sections compressed, by their writer.
+pub fn encode_artifact(kind: Kind, body: Vec<u8>) -> Vec<u8> {
+    let format = Format::current(kind);
+
+    let body = match (format.layout, format.compression) {
+        (Layout::Whole, Compression::Brotli) => compress_data(body),
+        _ => body,
+    };
+
+    let mut bytes = Vec::with_capacity(ENVELOPE_LEN + body.len());
+    bytes.extend_from_slice(ARTIFACT_MAGIC);
+    bytes.extend_from_slice(&[format.version, format.kind as u8, format.flags()]);
+    bytes.extend(body);
+
+    bytes
+}
+
+/// Decode the artifact `bytes`, going by its envelope.
+///
+/// Bytes without one are a legacy artifact of the kind `legacy`, decoded the way it was
+/// written. When that isn't known either, each kind is tried in turn, and only accepted when it
+/// decodes in full.
+pub fn decode_artifact(bytes: Vec<u8>, legacy: Option<Kind>) -> BoxResult<Artifact> {
+    let format = match Format::parse(&bytes)? {
+        Some(format) => format,
+        None => return decode_legacy(bytes, legacy),
+    };
+
+    if format.encrypted {
+        error!(format!(
+            "{} is encrypted, which this git-remote-inv4 can't read",
+            format.kind
+        ))
+    }
+
+    let body = &bytes[ENVELOPE_LEN..];
+
+    Ok(match (format.kind, format.layout) {
+        (Kind::RepoData, _) => Artifact::RepoData(decode_repo_data(&format.decompress(body)?)?),
+        (Kind::RepoMeta, _) => {
+            Artifact::RepoMeta(serde_json::from_slice(&format.decompress(body)?)?)
+        }
+        (Kind::MultiObject, Layout::Whole) => Artifact::MultiObject {
+            layout: 0,
+            multi_object: MultiObject::decode(&mut format.decompress(body)?.as_slice())?,
+        },
+        (Kind::MultiObject, Layout::Split) => Artifact::MultiObject {
+            layout: MultiObject::layout_version(body).ok_or("Not a MultiObject")?,
+            multi_object: MultiObject::from_bytes(body.to_vec())?,
+        },
+    })
+}
+
+fn decode_legacy(bytes: Vec<u8>, kind: Option<Kind>) -> BoxResult<Artifact> {
+    match kind {
+        Some(Kind::RepoData) => Ok(Artifact::RepoData(decode_repo_data(&try_decompress_data(
+            &bytes,
+        )?)?)),
+        Some(Kind::RepoMeta) => Ok(Artifact::RepoMeta(serde_json::from_slice(&bytes)?)),
+        Some(Kind::MultiObject) => Ok(Artifact::MultiObject {
+            layout: MultiObject::layout_version(&bytes).ok_or("Not a MultiObject")?,
+            multi_object: MultiObject::from_bytes(bytes)?,
+        }),
+        None => {
+            if let Ok(artifact) = decode_verified_multi_object(bytes.clone()) {
+                return Ok(artifact);
+            }
+            if let Ok(decompressed) = try_decompress_data(&bytes) {
+                if let Ok(repo_data) = decode_repo_data(&decompressed) {
+                    return Ok(Artifact::RepoData(repo_data));
+                }
+            }
+            match serde_json::from_slice::<serde_json::Value>(&bytes) {
+                Ok(serde_json::Value::Object(_)) => {
+                    Ok(Artifact::RepoMeta(serde_json::from_slice(&bytes)?))
+                }
+                _ => error!(format!("Unrecognized artifact ({} bytes)", bytes.len())),
+            }
+        }
+    }
+}
+
+/// Decode the SCALE encoding of a RepoData, which must take up all of `bytes`.
+fn decode_repo_data(bytes: &[u8]) -> BoxResult<RepoData> {
+    let mut rest = bytes;
+    let repo_data = RepoData::decode(&mut rest)?;
+
+    if !rest.is_empty() {
+        error!(format!("{} bytes left over after a RepoData", rest.len()))
+    }
+
+    Ok(repo_data)
+}
+
+/// Decode a legacy MultiObject of any layout, checking its objects against their hashes so that
+/// other data that happens to decode isn't taken for one.
+fn decode_verified_multi_object(bytes: Vec<u8>) -> BoxResult<Artifact> {
+    let layout = MultiObject::layout_version(&bytes).ok_or("Not a MultiObject")?;
+    let multi_object = MultiObject::from_bytes(bytes)?;
+
+    if multi_object.objects.is_empty() {
+        error!("MultiObject holds no objects")
+    }
+
+    multi_object.verify()?;
+
+    Ok(Artifact::MultiObject {
+        layout,
+        multi_object,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{meta::RepoMeta, primitives::GitObject, util::tests::repository};
+    use codec::Encode;
+
+    fn envelope(version: u8, kind: u8, flags: u8) -> Vec<u8> {
+        let mut bytes = ARTIFACT_MAGIC.to_vec();
+        bytes.extend_from_slice(&[version, kind, flags]);
+        bytes
+    }
+
+    #[test]
+    fn artifacts_round_trip_through_their_envelope() {
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+        let bytes = encode_artifact(Kind::RepoData, repo_data.encode());
+        assert_eq!(
+            Format::parse(&bytes).unwrap(),
+            Some(Format::current(Kind::RepoData))
+        );
+        match decode_artifact(bytes, None).unwrap() {
+            Artifact::RepoData(decoded) => assert_eq!(decoded.refs, repo_data.refs),
+            other => panic!("decoded a {}", other.name()),
+        }
+
+        let (_dir, repo) = repository();
+        let blob = repo.blob(b"contents").unwrap();
+        let mut multi_object = MultiObject {
+            hash: "mo".to_owned(),
+            git_hashes: vec![],
+            objects: Default::default(),
+            total_size: 0,
+        };
+        multi_object.add(GitObject::read(blob, &repo.odb().unwrap()).unwrap());
+        match decode_artifact(multi_object.to_bytes(), None).unwrap() {
+            Artifact::MultiObject {
+                layout,
+                multi_object: decoded,
+            } => {
+                assert_eq!(layout, 2);
+                assert_eq!(decoded.objects[&blob.to_string()].data, b"contents");
+            }
+            other => panic!("decoded a {}", other.name()),
+        }
+
+        // RepoMeta stays readable JSON after the envelope
+        let bytes = encode_artifact(Kind::RepoMeta, br#"{"name":"infra"}"#.to_vec());
+        assert!(bytes.ends_with(br#"{"name":"infra"}"#));
+    }
+
+    #[test]
+    fn envelopes_from_newer_writers_are_refused() {
+        let newer = |bytes: Vec<u8>| Format::parse(&bytes).unwrap_err().to_string();
+
+        assert!(newer(envelope(2, 1, 1)).contains("version 2"));
+        assert!(newer(envelope(1, 9, 1)).contains("kind 9"));
+        assert!(newer(envelope(1, 1, 0b1_0000)).contains("flags"));
+        assert!(newer(envelope(1, 1, 0b0010)).contains("compression 2"));
+        assert!(newer(envelope(1, 1, SPLIT)).contains("split layout"));
+        assert!(newer(ARTIFACT_MAGIC.to_vec()).contains("truncated"));
+
+        let mut encrypted = envelope(1, 3, ENCRYPTED);
+        encrypted.extend_from_slice(b"{}");
+        assert!(decode_artifact(encrypted, None)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("encrypted"));
+    }
+
+    #[test]
+    fn legacy_artifacts_are_decoded_as_they_were_written() {
+        assert_eq!(Format::parse(b"{}").unwrap(), None);
+
+        let mut repo_data = RepoData::default();
+        repo_data.objects.insert("a".repeat(40), "mo".to_owned());
+        let legacy = compress_data(repo_data.encode());
+        match decode_artifact(legacy, Some(Kind::RepoData)).unwrap() {
+            Artifact::RepoData(decoded) => assert_eq!(decoded.objects, repo_data.objects),
+            other => panic!("decoded a {}", other.name()),
+        }
+
+        match decode_artifact(br#"{"name":"infra"}"#.to_vec(), None).unwrap() {
+            Artifact::RepoMeta(meta) => assert_eq!(
+                meta,
+                RepoMeta {
+                    name: Some("infra".to_owned()),
+                    ..RepoMeta::default()
+                }
+            ),
+            other => panic!("decoded a {}", other.name()),
+        }
+
+        assert!(decode_artifact(b"\x01\x02\x03".to_vec(), None).is_err());
+    }
+}
@@ -0,0 +1,494 @@
+//! What an IPF holds, told apart by its format envelope, by its metadata for artifacts predating
+//! envelopes and, failing both, by which decoder accepts its contents. Only chain and store
+//! access is needed, so explorers can summarize an IPS's IPFs the same way
+//! `inv4-git inspect-ipf` does.
+
+use crate::{
+    chain::IpfRecord,
+    format::{decode_artifact, Format, Kind},
+    meta::RepoMeta,
+    primitives::{
+        BoxResult, GitObjectMetadata, MultiObject, MultiObjectHeader, MultiObjectIndex, ObjectKind,
+        RepoData, LARGE_BLOB_MARKER, SUBMODULE_TIP_MARKER,
+    },
+    store::ObjectStore,
+    util::{generate_cid, human_size},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+};
+
+/// The contents of an IPF, decoded.
+pub enum Artifact {
+    RepoData(RepoData),
+    RepoMeta(RepoMeta),
+    MultiObject {
+        /// See `MultiObject::layout_version`
+        layout: u8,
+        multi_object: MultiObject,
+    },
+    /// A blob stored on its own, which isn't downloaded
+    LargeBlob {
+        git_hash: String,
+    },
+}
+
+impl Artifact {
+    /// Download and decode `ipf`, along with the format it was stored in. Large blobs have none.
+    pub async fn fetch(
+        ipf: &IpfRecord,
+        store: &dyn ObjectStore,
+    ) -> BoxResult<(Self, Option<Format>)> {
+        if let Some(git_hash) = large_blob_hash(&ipf.metadata) {
+            return Ok((Self::LargeBlob { git_hash }, None));
+        }
+
+        let bytes = store.get(&generate_cid(ipf.data)?.to_string()).await?;
+        let format = Format::parse(&bytes)?;
+
+        Ok((Self::detect(&ipf.metadata, bytes)?, format))
+    }
+
+    /// Decode `bytes`, the contents of an IPF with `metadata`.
+    ///
+    /// The metadata of the IPFs this helper mints says what legacy artifacts without a format
+    /// envelope hold. Anything else is decoded as `decode_artifact` would.
+    pub fn detect(metadata: &[u8], bytes: Vec<u8>) -> BoxResult<Self> {
+        if let Some(git_hash) = large_blob_hash(metadata) {
+            return Ok(Self::LargeBlob { git_hash });
+        }
+
+        let legacy = match metadata {
+            b"RepoData" => Some(Kind::RepoData),
+            b"RepoMeta" => Some(Kind::RepoMeta),
+            _ if is_multi_object_hash(metadata) => Some(Kind::MultiObject),
+            _ => None,
+        };
+
+        decode_artifact(bytes, legacy).map_err(|e| match legacy {
+            Some(kind) => format!("IPF metadata says {}, but: {}", kind, e).into(),
+            None => e,
+        })
+    }
+
+    /// What the artifact is, for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RepoData(_) => "RepoData",
+            Self::RepoMeta(_) => "RepoMeta",
+            Self::MultiObject { .. } => "MultiObject",
+            Self::LargeBlob { .. } => "large blob",
+        }
+    }
+
+    /// Summarize the artifact. The refs reaching its objects are taken from `repo_data`, the
+    /// repository's current RepoData, and not listed without it.
+    pub async fn summarize(
+        &self,
+        repo_data: Option<&RepoData>,
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+    ) -> BoxResult<Summary> {
+        Ok(match self {
+            Self::RepoData(repo_data) => Summary::RepoData {
+                refs: repo_data.refs.clone(),
+                objects: repo_data.objects.len(),
+                multi_objects: repo_data
+                    .objects
+                    .values()
+                    .filter(|hash| *hash != LARGE_BLOB_MARKER && *hash != SUBMODULE_TIP_MARKER)
+                    .collect::<BTreeSet<_>>()
+                    .len(),
+                large_blobs: repo_data.large_blobs.len(),
+                pushes: repo_data.history.len(),
+                size: repo_data.compressed().1.compressed,
+            },
+            Self::RepoMeta(meta) => Summary::RepoMeta(meta.clone()),
+            Self::MultiObject {
+                layout,
+                multi_object,
+            } => {
+                let mut objects = BTreeMap::new();
+                for object in multi_object.objects.values() {
+                    *objects.entry(object.kind.object_type().str()).or_insert(0) += 1;
+                }
+
+                let commits = multi_object
+                    .objects
+                    .values()
+                    .filter(|object| object.kind == ObjectKind::Commit)
+                    .map(|object| CommitLine {
+                        git_hash: object.git_hash.clone(),
+                        subject: subject(&object.data),
+                    })
+                    .collect();
+
+                Summary::MultiObject {
+                    hash: multi_object.hash.clone(),
+                    layout: *layout,
+                    objects,
+                    total_size: multi_object.total_size,
+                    commits,
+                    refs: refs_reaching(
+                        repo_data,
+                        &multi_object.objects.keys().cloned().collect(),
+                        store,
+                        index,
+                    )
+                    .await?,
+                }
+            }
+            Self::LargeBlob { git_hash } => Summary::LargeBlob {
+                git_hash: git_hash.clone(),
+                size: repo_data
+                    .and_then(|repo_data| repo_data.large_blobs.get(git_hash))
+                    .map(|blob| blob.size),
+                refs: refs_reaching(repo_data, &BTreeSet::from([git_hash.clone()]), store, index)
+                    .await?,
+            },
+        })
+    }
+}
+
+/// A commit in a MultiObject.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommitLine {
+    pub git_hash: String,
+    pub subject: String,
+}
+
+/// What `inv4-git inspect-ipf` prints about an artifact.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Summary {
+    RepoData {
+        refs: BTreeMap<String, String>,
+        objects: usize,
+        multi_objects: usize,
+        large_blobs: usize,
+        /// Pushes in the history
+        pushes: usize,
+        /// Compressed size in bytes
+        size: usize,
+    },
+    RepoMeta(RepoMeta),
+    MultiObject {
+        hash: String,
+        layout: u8,
+        /// A {type -> count} map
+        objects: BTreeMap<&'static str, usize>,
+        total_size: u64,
+        commits: Vec<CommitLine>,
+        /// The refs whose history includes any of the objects, `null` without a RepoData
+        refs: Option<Vec<String>>,
+    },
+    LargeBlob {
+        git_hash: String,
+        size: Option<u64>,
+        refs: Option<Vec<String>>,
+    },
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RepoData {
+                refs,
+                objects,
+                multi_objects,
+                large_blobs,
+                pushes,
+                size,
+            } => {
+                writeln!(f, "Type:           RepoData")?;
+                writeln!(f, "Size:           {}", human_size(*size))?;
+                writeln!(
+                    f,
+                    "Objects:        {} in {} MultiObjects",
+                    objects, multi_objects
+                )?;
+                writeln!(f, "Large blobs:    {}", large_blobs)?;
+                writeln!(f, "Pushes logged:  {}", pushes)?;
+                writeln!(f, "Refs:           {}", refs.len())?;
+                for (name, git_hash) in refs {
+                    writeln!(f, "    {} {}", git_hash, name)?;
+                }
+            }
+            Self::RepoMeta(meta) => {
+                writeln!(f, "Type:           RepoMeta")?;
+                for (label, value) in [
+                    ("Name", &meta.name),
+                    ("Description", &meta.description),
+                    ("License", &meta.license),
+                    ("Homepage", &meta.homepage),
+                ] {
+                    if let Some(value) = value {
+                        writeln!(f, "{:<16}{}", format!("{}:", label), value)?;
+                    }
+                }
+            }
+            Self::MultiObject {
+                hash,
+                layout,
+                objects,
+                total_size,
+                commits,
+                refs,
+            } => {
+                writeln!(f, "Type:           MultiObject {}", hash)?;
+                match layout {
+                    0 => writeln!(f, "Layout:         legacy, unversioned")?,
+                    layout => writeln!(f, "Layout:         version {}", layout)?,
+                }
+                writeln!(
+                    f,
+                    "Objects:        {} ({})",
+                    objects
+                        .iter()
+                        .map(|(kind, count)| format!("{} {}s", count, kind))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    human_size(*total_size as usize)
+                )?;
+                writeln!(f, "Commits:        {}", commits.len())?;
+                for commit in commits {
+                    writeln!(f, "    {} {}", &commit.git_hash[..10], commit.subject)?;
+                }
+                write_refs(f, refs)?;
+            }
+            Self::LargeBlob {
+                git_hash,
+                size,
+                refs,
+            } => {
+                writeln!(f, "Type:           large blob {}", git_hash)?;
+                if let Some(size) = size {
+                    writeln!(f, "Size:           {}", human_size(*size as usize))?;
+                }
+                write_refs(f, refs)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_refs(f: &mut fmt::Formatter<'_>, refs: &Option<Vec<String>>) -> fmt::Result {
+    match refs {
+        Some(refs) if refs.is_empty() => writeln!(f, "Reachable from: no ref"),
+        Some(refs) => writeln!(f, "Reachable from: {}", refs.join(", ")),
+        None => writeln!(f, "Reachable from: unknown; pass --ips to check"),
+    }
+}
+
+/// The git hash in the metadata of an IPF holding a large blob.
+fn large_blob_hash(metadata: &[u8]) -> Option<String> {
+    let metadata = std::str::from_utf8(metadata).ok()?;
+
+    Some(
+        metadata
+            .strip_prefix(LARGE_BLOB_MARKER)?
+            .strip_prefix(':')?
+            .to_owned(),
+    )
+}
+
+/// Whether `metadata` is a MultiObject key: hex blake2b-256, or the 64-bit xxh3 of older pushes.
+fn is_multi_object_hash(metadata: &[u8]) -> bool {
+    matches!(metadata.len(), 16 | 64) && metadata.iter().all(u8::is_ascii_hexdigit)
+}
+
+/// The first line of the message of the raw commit `data`.
+fn subject(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+
+    text.split_once("\n\n")
+        .and_then(|(_, message)| message.lines().next())
+        .unwrap_or_default()
+        .trim()
+        .to_owned()
+}
+
+/// The refs of `repo_data` whose history includes any of `targets`, walking the object graph
+/// through MultiObject headers. `None` without a RepoData to take the refs from.
+async fn refs_reaching(
+    repo_data: Option<&RepoData>,
+    targets: &BTreeSet<String>,
+    store: &dyn ObjectStore,
+    index: &MultiObjectIndex<'_>,
+) -> BoxResult<Option<Vec<String>>> {
+    let repo_data = match repo_data {
+        Some(repo_data) => repo_data,
+        None => return Ok(None),
+    };
+
+    let mut headers: BTreeMap<String, MultiObjectHeader> = BTreeMap::new();
+    let mut reaching = vec![];
+
+    for (name, tip) in &repo_data.refs {
+        let mut seen = HashSet::new();
+        let mut stack = vec![tip.clone()];
+
+        while let Some(git_hash) = stack.pop() {
+            if targets.contains(&git_hash) {
+                reaching.push(name.clone());
+                break;
+            }
+
+            if !seen.insert(git_hash.clone()) {
+                continue;
+            }
+
+            let multi_object_hash = match repo_data.objects.get(&git_hash) {
+                Some(hash) if hash != SUBMODULE_TIP_MARKER && hash != LARGE_BLOB_MARKER => {
+                    hash.clone()
+                }
+                _ => continue,
+            };
+
+            if !headers.contains_key(&multi_object_hash) {
+                let header = MultiObject::get_header(
+                    &repo_data
+                        .multi_object_cid(&multi_object_hash, index)
+                        .await?,
+                    store,
+                )
+                .await?;
+                headers.insert(multi_object_hash.clone(), header);
+            }
+
+            match headers[&multi_object_hash]
+                .entries
+                .get(&git_hash)
+                .map(|entry| &entry.metadata)
+            {
+                Some(GitObjectMetadata::Commit {
+                    parent_git_hashes,
+                    tree_git_hash,
+                }) => {
+                    stack.extend(parent_git_hashes.iter().cloned());
+                    stack.push(tree_git_hash.clone());
+                }
+                Some(GitObjectMetadata::Tree { entry_git_hashes }) => {
+                    stack.extend(entry_git_hashes.iter().cloned())
+                }
+                Some(GitObjectMetadata::Tag { target_git_hash }) => {
+                    stack.push(target_git_hash.clone())
+                }
+                Some(GitObjectMetadata::Blob) | None => {}
+            }
+        }
+    }
+
+    Ok(Some(reaching))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::compress_data,
+        primitives::tests::published,
+        util::tests::{commit, repository},
+    };
+    use codec::Encode;
+
+    #[test]
+    fn legacy_artifacts_are_told_apart_by_their_metadata() {
+        let artifact =
+            Artifact::detect(format!("{}:abc", LARGE_BLOB_MARKER).as_bytes(), vec![]).unwrap();
+        assert!(matches!(artifact, Artifact::LargeBlob { git_hash } if git_hash == "abc"));
+
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+        let bytes = compress_data(repo_data.encode());
+        match Artifact::detect(b"RepoData", bytes.clone()).unwrap() {
+            Artifact::RepoData(decoded) => assert_eq!(decoded.refs, repo_data.refs),
+            other => panic!("detected a {}", other.name()),
+        }
+        assert_eq!(Artifact::detect(b"", bytes).unwrap().name(), "RepoData");
+
+        let e = Artifact::detect(b"RepoData", b"garbage".to_vec()).unwrap_err();
+        assert!(
+            e.to_string().starts_with("IPF metadata says RepoData"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn multi_object_keys_are_recognized() {
+        assert!(is_multi_object_hash(&[b'a'; 64]));
+        assert!(is_multi_object_hash(b"0123456789abcdef"));
+        assert!(!is_multi_object_hash(b"RepoData"));
+        assert!(!is_multi_object_hash(&[b'g'; 64]));
+    }
+
+    #[tokio::test]
+    async fn multi_object_summary_lists_commits_and_the_refs_reaching_them() {
+        let (_dir, repo) = repository();
+        let first = commit(&repo, Some("refs/heads/main"), &[], "First\n\nBody");
+        commit(&repo, Some("refs/heads/other"), &[], "Other");
+        let (repo_data, store, index) = published(&repo);
+
+        let multi_object = MultiObject::get("mo-cid", &store).await.unwrap();
+        let artifact = Artifact::detect(b"mo", multi_object.to_bytes()).unwrap();
+        let only_first = Artifact::MultiObject {
+            layout: 2,
+            multi_object: MultiObject {
+                hash: "first".to_owned(),
+                git_hashes: vec![first.to_string()],
+                objects: BTreeMap::from([(
+                    first.to_string(),
+                    multi_object.objects[&first.to_string()].clone(),
+                )]),
+                total_size: 0,
+            },
+        };
+
+        match artifact
+            .summarize(Some(&repo_data), &store, &index)
+            .await
+            .unwrap()
+        {
+            Summary::MultiObject { objects, refs, .. } => {
+                assert_eq!(objects, BTreeMap::from([("commit", 2), ("tree", 1)]));
+                assert_eq!(
+                    refs,
+                    Some(vec![
+                        "refs/heads/main".to_owned(),
+                        "refs/heads/other".to_owned()
+                    ])
+                );
+            }
+            other => panic!("summarized as {:?}", other),
+        }
+
+        match only_first
+            .summarize(Some(&repo_data), &store, &index)
+            .await
+            .unwrap()
+        {
+            Summary::MultiObject { commits, refs, .. } => {
+                assert_eq!(
+                    commits,
+                    [CommitLine {
+                        git_hash: first.to_string(),
+                        subject: "First".to_owned(),
+                    }]
+                );
+                assert_eq!(refs, Some(vec!["refs/heads/main".to_owned()]));
+            }
+            other => panic!("summarized as {:?}", other),
+        }
+
+        match only_first.summarize(None, &store, &index).await.unwrap() {
+            Summary::MultiObject { refs, .. } => assert_eq!(refs, None),
+            other => panic!("summarized as {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! `inv4-git install`: puts the helper on PATH under the name git looks for, `git-remote-inv4`.
+//!
+//! On unix it is a symlink to the running executable, so rebuilding updates it. Windows can't
+//! count on symlinks being allowed, so it gets a copy of the .exe instead.
+
+use crate::{doctor::HELPER_BINARY, error, primitives::BoxResult, util::find_on_path};
+use dirs::home_dir;
+use std::{
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Symlink,
+    Copy,
+}
+
+impl Method {
+    /// How this platform installs the helper.
+    pub fn native() -> Self {
+        match cfg!(windows) {
+            true => Self::Copy,
+            false => Self::Symlink,
+        }
+    }
+}
+
+/// The file name git runs for `inv4://` remotes on this platform.
+pub fn binary_name() -> String {
+    format!("{}{}", HELPER_BINARY, env::consts::EXE_SUFFIX)
+}
+
+/// Directories the helper is installed to without `--dir`, in order of preference.
+pub fn candidate_dirs() -> Vec<PathBuf> {
+    home_dir()
+        .map(|home| vec![home.join(".local").join("bin"), home.join("bin")])
+        .unwrap_or_default()
+}
+
+/// Where to install: `dir` when given, else the first candidate already on PATH, else the first
+/// candidate.
+pub fn target_dir(dir: Option<&Path>, candidates: &[PathBuf]) -> BoxResult<PathBuf> {
+    if let Some(dir) = dir {
+        return Ok(dir.to_owned());
+    }
+
+    candidates
+        .iter()
+        .find(|candidate| is_on_path(candidate))
+        .or_else(|| candidates.first())
+        .cloned()
+        .ok_or_else(|| "No home directory to install to; pass --dir".into())
+}
+
+/// Whether `dir` is one of the directories of `PATH`.
+pub fn is_on_path(dir: &Path) -> bool {
+    let path = match env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+
+    env::split_paths(&path).any(|entry| same_file(&entry, dir))
+}
+
+/// Whether `a` and `b` are the same file or directory once links are resolved.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Whether `exe` lies in a cargo `target/debug` or `target/release` directory, which
+/// `cargo clean` removes.
+pub fn is_build_output(exe: &Path) -> bool {
+    let components: Vec<_> = exe.components().collect();
+
+    components.windows(2).any(|pair| {
+        pair[0] == Component::Normal("target".as_ref())
+            && ["debug", "release"]
+                .iter()
+                .any(|profile| pair[1] == Component::Normal(profile.as_ref()))
+    })
+}
+
+/// Install `exe` into `dir` with `method`, replacing an earlier install. Returns the installed
+/// path.
+pub fn install(exe: &Path, dir: &Path, method: Method) -> BoxResult<PathBuf> {
+    let dest = dir.join(binary_name());
+
+    if same_file(&dest, exe) {
+        return Ok(dest);
+    }
+
+    fs::create_dir_all(dir)?;
+
+    if dest.symlink_metadata().is_ok() {
+        if dest.is_dir() {
+            error!(format!("{} is a directory", dest.display()))
+        }
+        fs::remove_file(&dest)?;
+    }
+
+    match method {
+        Method::Copy => {
+            fs::copy(exe, &dest)?;
+        }
+        Method::Symlink => symlink(exe, &dest)?,
+    }
+
+    Ok(dest)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> BoxResult<()> {
+    Ok(std::os::unix::fs::symlink(target, link)?)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> BoxResult<()> {
+    Ok(std::os::windows::fs::symlink_file(target, link)?)
+}
+
+/// The line that puts `dir` on PATH, for the profile of the user's shell, along with where it
+/// goes.
+pub fn profile_line(dir: &Path) -> (String, String) {
+    if cfg!(windows) {
+        return (
+            format!("setx PATH \"%PATH%;{}\"", dir.display()),
+            "a new terminal".to_owned(),
+        );
+    }
+
+    let shell = env::var("SHELL").unwrap_or_default();
+    match Path::new(&shell).file_name().and_then(|name| name.to_str()) {
+        Some("fish") => (
+            format!("fish_add_path {}", dir.display()),
+            "~/.config/fish/config.fish".to_owned(),
+        ),
+        Some("zsh") => (
+            format!("export PATH=\"{}:$PATH\"", dir.display()),
+            "~/.zshrc".to_owned(),
+        ),
+        Some("bash") => (
+            format!("export PATH=\"{}:$PATH\"", dir.display()),
+            "~/.bashrc".to_owned(),
+        ),
+        _ => (
+            format!("export PATH=\"{}:$PATH\"", dir.display()),
+            "~/.profile".to_owned(),
+        ),
+    }
+}
+
+/// What `inv4-git install --check` reports.
+pub struct State {
+    /// The running executable
+    pub exe: PathBuf,
+    /// The helper git would run, if any
+    pub on_path: Option<PathBuf>,
+    /// Whether that is the running executable, or a link to it
+    pub current: bool,
+}
+
+impl State {
+    pub fn probe() -> BoxResult<Self> {
+        let exe = env::current_exe()?;
+        let on_path = find_on_path(&binary_name());
+        let current = on_path
+            .as_deref()
+            .map_or(false, |path| same_file(path, &exe));
+
+        Ok(Self {
+            exe,
+            on_path,
+            current,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn given_dir_wins_then_candidates_in_order() {
+        let given = Path::new("/opt/inv4/bin");
+        let candidates = [
+            PathBuf::from("/nonexistent/a"),
+            PathBuf::from("/nonexistent/b"),
+        ];
+
+        assert_eq!(target_dir(Some(given), &candidates).unwrap(), given);
+        assert_eq!(target_dir(None, &candidates).unwrap(), candidates[0]);
+        assert!(target_dir(None, &[]).is_err());
+    }
+
+    #[test]
+    fn cargo_build_output_is_recognized() {
+        assert!(is_build_output(Path::new(
+            "/src/inv4-git/target/release/inv4-git"
+        )));
+        assert!(is_build_output(Path::new("target/debug/inv4-git")));
+        assert!(!is_build_output(Path::new("/usr/local/bin/inv4-git")));
+        assert!(!is_build_output(Path::new("/home/me/target/inv4-git")));
+    }
+
+    #[test]
+    fn install_replaces_an_earlier_one() {
+        let dir = TempDir::new().unwrap();
+        let exe = dir.child("inv4-git");
+        fs::write(&exe, "new").unwrap();
+        let bin = dir.child("bin");
+        fs::create_dir(&bin).unwrap();
+        fs::write(bin.join(binary_name()), "old").unwrap();
+
+        let dest = install(&exe, &bin, Method::Copy).unwrap();
+        assert_eq!(dest, bin.join(binary_name()));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+
+        let dest = install(&exe, &bin, Method::native()).unwrap();
+        assert!(same_file(&dest, &exe) || cfg!(windows));
+
+        // Installing over itself leaves it alone
+        assert_eq!(install(&dest, &bin, Method::Copy).unwrap(), dest);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+    }
+}
@@ -0,0 +1,421 @@
+//! Finding an IPFS node to talk to, or starting one when none is running, and adding content to
+//! it the same way whatever its version and config.
+//!
+//! `ipfs add` lays content out by defaults that vary between releases and nodes, e.g. raw leaves
+//! or CIDv1 set in `Import`, and the same bytes then get another CID, or one that can't be
+//! recorded on-chain. Uploads pass every parameter the CID depends on instead, see `add_options`.
+//! Nodes older than the releases this was checked against may not know all of them, which
+//! `check_compatibility` warns about.
+
+use crate::{
+    error, output,
+    primitives::{BoxResult, Config},
+    shutdown::{self, Cleanup},
+    util::find_on_path,
+};
+use dirs::{config_dir, home_dir};
+use ipfs_api::{request, IpfsApi, IpfsClient, TryFromUri};
+use log::debug;
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    process::{Child, Command},
+    time::{sleep, timeout, Instant},
+};
+
+/// Where `ipfs daemon` and ipfs-desktop serve the API by default.
+const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:5001";
+
+/// Brave's embedded node, one port per release channel.
+const ALTERNATE_PORTS: &[u16] = &[45001, 45002, 45003, 45004];
+
+/// How long a probe may take before the endpoint counts as down.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a spawned daemon gets to initialize its repo and open the API.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The oldest go-ipfs release uploads were checked against. Older ones may ignore add
+/// parameters they don't know and use their own defaults for them.
+const MIN_TESTED_VERSION: (u64, u64, u64) = (0, 8, 0);
+
+/// How content is added: the layout `ipfs add` has defaulted to from the start. Only dag-pb
+/// sha2-256 CIDs fit on-chain, see `util::cid_digest`, and raw leaves would make small content a
+/// raw CID.
+const CID_VERSION: u32 = 0;
+const RAW_LEAVES: bool = false;
+const HASH: &str = "sha2-256";
+
+/// The chunker for content that doesn't ask for another, as `ipfs add` defaults to.
+pub const DEFAULT_CHUNKER: &str = "size-262144";
+
+/// The add parameters uploads pass rather than leave to the node, with `chunker`.
+pub fn add_options(chunker: &str) -> request::Add<'_> {
+    request::Add {
+        chunker: Some(chunker),
+        cid_version: Some(CID_VERSION),
+        raw_leaves: Some(RAW_LEAVES),
+        hash: Some(HASH),
+        pin: Some(true),
+        ..Default::default()
+    }
+}
+
+/// Warn about a node running `version` that may add content differently, despite
+/// `add_options`. Best-effort: a config that can't be read is only logged.
+pub async fn check_compatibility(client: &IpfsClient, version: &str) {
+    let differing = match timeout(PROBE_TIMEOUT, client.config_show()).await {
+        Ok(Ok(config)) => differing_defaults(&config),
+        Ok(Err(e)) => {
+            debug!("Could not read the IPFS node's config: {}", e);
+            vec![]
+        }
+        Err(_) => {
+            debug!("Timed out reading the IPFS node's config");
+            vec![]
+        }
+    };
+
+    let tested = parse_version(version).map_or(false, |version| version >= MIN_TESTED_VERSION);
+    if tested {
+        // Overridden on every add, so they only matter to content added some other way
+        if !differing.is_empty() {
+            debug!(
+                "The IPFS node's add defaults differ: {}",
+                differing.join(", ")
+            );
+        }
+        return;
+    }
+
+    let (major, minor, patch) = MIN_TESTED_VERSION;
+    let mut warning = format!(
+        "The IPFS node runs {}, older than {}.{}.{}, which uploads are tested with; it may ignore the add parameters that keep CIDs the same on every node",
+        version, major, minor, patch
+    );
+    if !differing.is_empty() {
+        warning.push_str(&format!(", and its config sets {}", differing.join(", ")));
+    }
+    output::warn(warning);
+}
+
+/// The `Import` settings in the node's `config`, as JSON, that lay content out other than
+/// `add_options`, e.g. `Import.CidVersion = 1`.
+fn differing_defaults(config: &str) -> Vec<String> {
+    let import = match serde_json::from_str::<serde_json::Value>(config) {
+        Ok(config) => config["Import"].clone(),
+        Err(e) => {
+            debug!("Could not parse the IPFS node's config: {}", e);
+            return vec![];
+        }
+    };
+
+    let mut differing = vec![];
+    if let Some(cid_version) = import["CidVersion"]
+        .as_u64()
+        .filter(|&cid_version| cid_version != CID_VERSION as u64)
+    {
+        differing.push(format!("Import.CidVersion = {}", cid_version));
+    }
+    if let Some(raw_leaves) = import["UnixFSRawLeaves"]
+        .as_bool()
+        .filter(|&raw_leaves| raw_leaves != RAW_LEAVES)
+    {
+        differing.push(format!("Import.UnixFSRawLeaves = {}", raw_leaves));
+    }
+    if let Some(hash) = import["HashFunction"].as_str().filter(|&hash| hash != HASH) {
+        differing.push(format!("Import.HashFunction = {}", hash));
+    }
+
+    differing
+}
+
+/// `0.18.1` or `0.4.23-rc1` -> `(0, 18, 1)` and `(0, 4, 23)`
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut numbers = version.split(|c: char| !c.is_ascii_digit()).map(str::parse);
+
+    Some((
+        numbers.next()?.ok()?,
+        numbers.next()?.ok()?,
+        numbers.next()?.ok()?,
+    ))
+}
+
+/// Pick the node to use: the configured endpoint as is, otherwise the first healthy one found,
+/// otherwise a daemon spawned for us if `auto_spawn_ipfs` allows it.
+///
+/// Without any of those the default endpoint is returned, so the store check reports it as down.
+pub async fn connect(config: &Config) -> BoxResult<(IpfsClient, Option<Daemon>)> {
+    if let Some(endpoint) = &config.ipfs_endpoint {
+        return Ok((IpfsClient::from_str(endpoint)?, None));
+    }
+
+    for endpoint in candidates() {
+        let client = IpfsClient::from_str(&endpoint)?;
+
+        if is_healthy(&client).await {
+            output::status(format!("Using the IPFS node at {}", endpoint));
+            return Ok((client, None));
+        }
+    }
+
+    if config.auto_spawn_ipfs {
+        let (daemon, client) = Daemon::spawn(config.keep_daemon).await?;
+        return Ok((client, Some(daemon)));
+    }
+
+    Ok((IpfsClient::from_str(DEFAULT_ENDPOINT)?, None))
+}
+
+/// API endpoints to probe, in order and without duplicates.
+fn candidates() -> Vec<String> {
+    let mut candidates = vec![DEFAULT_ENDPOINT.to_owned()];
+    candidates.extend(api_file_endpoint());
+    candidates.extend(
+        ALTERNATE_PORTS
+            .iter()
+            .map(|port| format!("http://127.0.0.1:{}", port)),
+    );
+
+    let mut unique = vec![];
+    for candidate in candidates {
+        if !unique.contains(&candidate) {
+            unique.push(candidate);
+        }
+    }
+
+    unique
+}
+
+/// The endpoint a running node advertises in `$IPFS_PATH/api`, `~/.ipfs/api` by default.
+fn api_file_endpoint() -> Option<String> {
+    let repo = env::var_os("IPFS_PATH")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".ipfs")))?;
+
+    multiaddr_to_url(&fs::read_to_string(repo.join("api")).ok()?)
+}
+
+/// `/ip4/127.0.0.1/tcp/5001` -> `http://127.0.0.1:5001`
+fn multiaddr_to_url(multiaddr: &str) -> Option<String> {
+    match multiaddr.trim().split('/').collect::<Vec<_>>()[..] {
+        ["", "ip4" | "dns" | "dns4", host, "tcp", port, ..] => {
+            Some(format!("http://{}:{}", host, port))
+        }
+        ["", "ip6", host, "tcp", port, ..] => Some(format!("http://[{}]:{}", host, port)),
+        _ => None,
+    }
+}
+
+async fn is_healthy(client: &IpfsClient) -> bool {
+    matches!(timeout(PROBE_TIMEOUT, client.version()).await, Ok(Ok(_)))
+}
+
+/// Whether the multiaddr `address` can be reached from elsewhere, unlike loopback, private and
+/// link-local ones, which would only tell others about our network.
+pub fn is_public_address(address: &str) -> bool {
+    let mut parts = address.split('/').skip(1);
+
+    match (parts.next(), parts.next()) {
+        (Some("ip4"), Some(ip)) => match ip.parse::<Ipv4Addr>() {
+            Ok(ip) => {
+                !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified())
+            }
+            Err(_) => false,
+        },
+        (Some("ip6"), Some(ip)) => match ip.parse::<Ipv6Addr>() {
+            // fe80::/10 is link-local, fc00::/7 unique local
+            Ok(ip) => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+                    || ip.segments()[0] & 0xfe00 == 0xfc00)
+            }
+            Err(_) => false,
+        },
+        (Some("dns" | "dns4" | "dns6" | "dnsaddr"), Some(_)) => true,
+        _ => false,
+    }
+}
+
+/// An `ipfs daemon` started by the helper. Stopped on drop, or when the helper is interrupted,
+/// unless it should be kept.
+pub struct Daemon {
+    child: Child,
+    stop: Option<Cleanup>,
+}
+
+impl Daemon {
+    async fn spawn(keep: bool) -> BoxResult<(Self, IpfsClient)> {
+        let binary = find_on_path("ipfs")
+            .ok_or("No IPFS node is running and no ipfs binary was found on PATH")?;
+
+        let log_path = config_dir()
+            .ok_or("Operating system's configs directory not found")?
+            .join("INV4-Git/ipfs-daemon.log");
+        fs::create_dir_all(
+            log_path
+                .parent()
+                .ok_or("Log path has no parent directory")?,
+        )?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+
+        output::status("Starting an IPFS daemon...");
+
+        let child = Command::new(binary)
+            .args(["daemon", "--init"])
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()?;
+
+        let stop = match keep {
+            true => None,
+            false => stop_on_exit(&child),
+        };
+        let mut daemon = Self { child, stop };
+        let deadline = Instant::now() + READY_TIMEOUT;
+
+        loop {
+            if let Some(status) = daemon.child.try_wait()? {
+                error!(format!(
+                    "ipfs daemon exited with {}, see {}",
+                    status,
+                    log_path.display()
+                ))
+            }
+
+            // The daemon writes its API address once it listens
+            let endpoint = api_file_endpoint().unwrap_or_else(|| DEFAULT_ENDPOINT.to_owned());
+            let client = IpfsClient::from_str(&endpoint)?;
+
+            if is_healthy(&client).await {
+                output::info(format!("Started an IPFS daemon at {}", endpoint));
+                return Ok((daemon, client));
+            }
+
+            if Instant::now() >= deadline {
+                // Don't leave a half-started daemon behind
+                if daemon.stop.is_none() {
+                    daemon.stop = stop_on_exit(&daemon.child);
+                }
+                error!(format!(
+                    "ipfs daemon did not become ready within {}s, see {}",
+                    READY_TIMEOUT.as_secs(),
+                    log_path.display()
+                ))
+            }
+
+            sleep(Duration::from_millis(250)).await;
+        }
+    }
+}
+
+fn stop_on_exit(child: &Child) -> Option<Cleanup> {
+    let pid = child.id()?;
+
+    // SIGTERM rather than SIGKILL, so the daemon releases its repo lock
+    Some(shutdown::register(move || unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_multiaddrs_become_urls() {
+        assert_eq!(
+            multiaddr_to_url("/ip4/127.0.0.1/tcp/5001\n").as_deref(),
+            Some("http://127.0.0.1:5001")
+        );
+        assert_eq!(
+            multiaddr_to_url("/ip6/::1/tcp/45001").as_deref(),
+            Some("http://[::1]:45001")
+        );
+        assert_eq!(
+            multiaddr_to_url("/dns4/ipfs.local/tcp/5001/http").as_deref(),
+            Some("http://ipfs.local:5001")
+        );
+        assert_eq!(multiaddr_to_url("/unix/run/ipfs.sock"), None);
+    }
+
+    #[test]
+    fn default_endpoint_is_probed_first() {
+        let candidates = candidates();
+
+        assert_eq!(candidates[0], DEFAULT_ENDPOINT);
+        assert!(candidates.contains(&"http://127.0.0.1:45001".to_owned()));
+        assert_eq!(
+            candidates.iter().filter(|c| *c == DEFAULT_ENDPOINT).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn only_public_addresses_are_hinted() {
+        assert!(is_public_address("/ip4/203.0.113.7/tcp/4001"));
+        assert!(is_public_address("/ip6/2001:db8::1/udp/4001/quic"));
+        assert!(is_public_address("/dns4/ipfs.example.com/tcp/4001"));
+
+        assert!(!is_public_address("/ip4/127.0.0.1/tcp/4001"));
+        assert!(!is_public_address("/ip4/192.168.1.20/tcp/4001"));
+        assert!(!is_public_address("/ip4/169.254.0.9/tcp/4001"));
+        assert!(!is_public_address("/ip6/::1/tcp/4001"));
+        assert!(!is_public_address("/ip6/fe80::1/tcp/4001"));
+        assert!(!is_public_address("/ip6/fd00::1/tcp/4001"));
+        assert!(!is_public_address("/ip4/not-an-ip/tcp/4001"));
+        assert!(!is_public_address("/p2p-circuit"));
+    }
+
+    #[test]
+    fn adds_fix_the_layout_cids_depend_on() {
+        let options = add_options(DEFAULT_CHUNKER);
+
+        assert_eq!(options.chunker, Some("size-262144"));
+        assert_eq!(options.cid_version, Some(0));
+        assert_eq!(options.raw_leaves, Some(false));
+        assert_eq!(options.hash, Some("sha2-256"));
+        assert_eq!(options.pin, Some(true));
+    }
+
+    #[test]
+    fn import_settings_laying_content_out_differently_are_named() {
+        assert_eq!(
+            differing_defaults(
+                r#"{"Import": {"CidVersion": 1, "UnixFSRawLeaves": true, "HashFunction": "blake3"}}"#
+            ),
+            [
+                "Import.CidVersion = 1",
+                "Import.UnixFSRawLeaves = true",
+                "Import.HashFunction = blake3"
+            ]
+        );
+        assert!(differing_defaults(
+            r#"{"Import": {"CidVersion": 0, "UnixFSRawLeaves": false, "HashFunction": "sha2-256"}}"#
+        )
+        .is_empty());
+        assert!(differing_defaults(r#"{"Import": {}}"#).is_empty());
+        assert!(differing_defaults("{}").is_empty());
+        assert!(differing_defaults("not json").is_empty());
+    }
+
+    #[test]
+    fn versions_are_read_from_release_names() {
+        assert_eq!(parse_version("0.18.1"), Some((0, 18, 1)));
+        assert_eq!(parse_version("0.4.23-rc1"), Some((0, 4, 23)));
+        assert_eq!(parse_version("0.7"), None);
+        assert_eq!(parse_version("unknown"), None);
+        assert!(parse_version("0.7.0").unwrap() < MIN_TESTED_VERSION);
+    }
+}
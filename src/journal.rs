@@ -0,0 +1,611 @@
+//! IPFs minted for a push that hasn't been published yet.
+//!
+//! A push mints its object packs and RepoData before the call that appends them to the IPS. If
+//! that call fails, or the process dies first, the minted IPFs stay with the signer; recording
+//! them here lets the next push burn them.
+//!
+//! It also keeps the CIDs of large blobs uploaded for an unpublished push, so pushing again
+//! doesn't upload them again while the store still holds them.
+//!
+//! Pushes published with `Confirmation::InBlock` are kept as well, until their block is
+//! finalized or turns out to have been dropped by a reorg.
+//!
+//! `inv4-git migrate` keeps the MultiObjects it minted again here, so an interrupted migration
+//! resumes with them.
+//!
+//! A publish split into several calls records the packs each call appends ahead of the RepoData.
+//! Should the RepoData never follow, the next push removes them from the IPS again and burns
+//! them.
+//!
+//! A push exported for another account to sign, see `export_call`, keeps the call here instead of
+//! its IPFs among the pending ones, until it landed or was abandoned.
+//!
+//! Entries are single appended lines, so a push interrupted while writing one leaves at most a
+//! torn last line, which is skipped.
+
+use crate::{
+    export_call::ExportedCall,
+    primitives::{BoxResult, IpfId},
+};
+use git2::Repository;
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use subxt::ext::sp_core::H256;
+
+#[derive(Clone)]
+pub struct PushJournal {
+    path: PathBuf,
+    /// `<blob oid> <cid>` lines
+    blobs_path: PathBuf,
+    /// Present while a push that didn't get to clean up after itself is pending
+    unfinished_path: PathBuf,
+    /// `<block hash> <extrinsic hash> <ref>=[<sha1>][,...]` lines
+    unconfirmed_path: PathBuf,
+    /// `<MultiObject hash> <IPF id> <cid>` lines
+    migrated_path: PathBuf,
+    /// `<RepoData IPF id> <IPF id>[,...]` lines
+    appended_path: PathBuf,
+    /// The JSON rendering of an exported call that hasn't landed yet
+    exported_path: PathBuf,
+}
+
+/// A push whose block wasn't finalized when it was reported done.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnconfirmedPush {
+    pub block_hash: H256,
+    pub extrinsic_hash: H256,
+    /// The refs it changed, with their new tips; `None` for deleted refs
+    pub refs: Vec<(String, Option<String>)>,
+}
+
+impl UnconfirmedPush {
+    fn parse(line: &str) -> Option<Self> {
+        let hash = |hex: &str| {
+            Some(H256::from_slice(
+                &hex::decode(hex).ok().filter(|bytes| bytes.len() == 32)?,
+            ))
+        };
+        let mut fields = line.split_whitespace();
+
+        Some(Self {
+            block_hash: hash(fields.next()?)?,
+            extrinsic_hash: hash(fields.next()?)?,
+            refs: fields
+                .next()?
+                .split(',')
+                .map(|field| {
+                    let (name, tip) = field.split_once('=')?;
+                    Some((
+                        name.to_owned(),
+                        Some(tip.to_owned()).filter(|tip| !tip.is_empty()),
+                    ))
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+impl std::fmt::Display for UnconfirmedPush {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            hex::encode(self.block_hash),
+            hex::encode(self.extrinsic_hash),
+            self.refs
+                .iter()
+                .map(|(name, tip)| format!("{}={}", name, tip.as_deref().unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+impl PushJournal {
+    /// The journal kept in a remote's `state_dir`. The first remote to push takes over the
+    /// journal of builds that kept one for all remotes, as its IPFs are the signer's either way.
+    pub fn open(repo: &Repository, state_dir: &Path) -> Self {
+        let path = state_dir.join("pending-ipfs");
+        let shared = repo.path().join("inv4").join("pending-ipfs");
+
+        if shared.is_file() && !path.exists() {
+            if let Err(e) = fs::create_dir_all(state_dir).and_then(|_| fs::rename(&shared, &path)) {
+                debug!(
+                    "Could not move {} to {}: {}",
+                    shared.display(),
+                    path.display(),
+                    e
+                );
+            }
+        }
+
+        Self::at(path, state_dir)
+    }
+
+    /// The journal kept in `state_dir` alone, for work on a repository outside a clone of it.
+    pub fn in_dir(state_dir: &Path) -> Self {
+        Self::at(state_dir.join("pending-ipfs"), state_dir)
+    }
+
+    fn at(path: PathBuf, state_dir: &Path) -> Self {
+        Self {
+            path,
+            blobs_path: state_dir.join("uploaded-blobs"),
+            unfinished_path: state_dir.join("unfinished-push"),
+            unconfirmed_path: state_dir.join("unconfirmed-pushes"),
+            migrated_path: state_dir.join("migrated-multi-objects"),
+            appended_path: state_dir.join("appended-ipfs"),
+            exported_path: state_dir.join("exported-call.json"),
+        }
+    }
+
+    pub fn record(&self, ipf_id: IpfId) -> BoxResult<()> {
+        fs::create_dir_all(self.path.parent().ok_or("Journal path has no parent")?)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", ipf_id)?;
+
+        Ok(())
+    }
+
+    /// Remember that the large blob `oid` was uploaded as `cid`.
+    pub fn record_blob(&self, oid: &str, cid: &str) -> BoxResult<()> {
+        fs::create_dir_all(
+            self.blobs_path
+                .parent()
+                .ok_or("Journal path has no parent")?,
+        )?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.blobs_path)?;
+        writeln!(file, "{} {}", oid, cid)?;
+
+        Ok(())
+    }
+
+    /// The CID the large blob `oid` was last uploaded as, if any.
+    pub fn uploaded_blob(&self, oid: &str) -> Option<String> {
+        self.uploaded_blobs().remove(oid)
+    }
+
+    fn uploaded_blobs(&self) -> BTreeMap<String, String> {
+        fs::read_to_string(&self.blobs_path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(oid, cid)| (oid.to_owned(), cid.trim().to_owned()))
+            .collect()
+    }
+
+    /// Forget the uploaded blobs once a push published them.
+    pub fn clear_blobs(&self) -> BoxResult<()> {
+        if self.blobs_path.exists() {
+            fs::remove_file(&self.blobs_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remember that the MultiObject `hash` was minted again as IPF `ipf_id`, stored at `cid`.
+    pub fn record_migrated(&self, hash: &str, ipf_id: IpfId, cid: &str) -> BoxResult<()> {
+        fs::create_dir_all(
+            self.migrated_path
+                .parent()
+                .ok_or("Journal path has no parent")?,
+        )?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.migrated_path)?;
+        writeln!(file, "{} {} {}", hash, ipf_id, cid)?;
+
+        Ok(())
+    }
+
+    /// The MultiObjects minted again by a migration that wasn't published yet; a
+    /// {MultiObject hash -> (IPF id, CID)} map.
+    pub fn migrated(&self) -> BTreeMap<String, (IpfId, String)> {
+        fs::read_to_string(&self.migrated_path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let entry = (
+                    fields.next()?.to_owned(),
+                    (fields.next()?.parse().ok()?, fields.next()?.to_owned()),
+                );
+                Some(entry)
+            })
+            .collect()
+    }
+
+    /// Forget the migrated MultiObjects once a migration published them.
+    pub fn clear_migrated(&self) -> BoxResult<()> {
+        if self.migrated_path.exists() {
+            fs::remove_file(&self.migrated_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remember that the IPFs `ipf_ids` are being appended ahead of the call that appends the
+    /// RepoData IPF `repo_data`, see `batching`.
+    pub fn record_appended(&self, repo_data: IpfId, ipf_ids: &[IpfId]) -> BoxResult<()> {
+        fs::create_dir_all(
+            self.appended_path
+                .parent()
+                .ok_or("Journal path has no parent")?,
+        )?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.appended_path)?;
+        writeln!(
+            file,
+            "{} {}",
+            repo_data,
+            ipf_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+
+        Ok(())
+    }
+
+    /// The IPFs appended ahead of a RepoData that may never have followed them; a
+    /// {RepoData IPF id -> IPF ids} map.
+    pub fn appended(&self) -> BTreeMap<IpfId, Vec<IpfId>> {
+        let mut appended: BTreeMap<IpfId, Vec<IpfId>> = BTreeMap::new();
+
+        for line in fs::read_to_string(&self.appended_path)
+            .unwrap_or_default()
+            .lines()
+        {
+            let parsed = line.split_once(' ').and_then(|(repo_data, ids)| {
+                let ids = ids
+                    .split(',')
+                    .map(|id| id.trim().parse().ok())
+                    .collect::<Option<Vec<IpfId>>>()?;
+                Some((repo_data.parse().ok()?, ids))
+            });
+
+            match parsed {
+                Some((repo_data, ids)) => appended.entry(repo_data).or_default().extend(ids),
+                None => debug!("Skipping torn journal line {:?}", line),
+            }
+        }
+
+        appended
+    }
+
+    /// Forget the appended IPFs once their RepoData was published or they were removed again.
+    pub fn clear_appended(&self) -> BoxResult<()> {
+        if self.appended_path.exists() {
+            fs::remove_file(&self.appended_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remember the call a push exported, whose IPFs aren't to be burned while it may land.
+    pub fn record_exported(&self, exported: &ExportedCall) -> BoxResult<()> {
+        fs::create_dir_all(
+            self.exported_path
+                .parent()
+                .ok_or("Journal path has no parent")?,
+        )?;
+        fs::write(&self.exported_path, exported.to_json()?)?;
+
+        Ok(())
+    }
+
+    /// The call a push exported that didn't land or wasn't abandoned yet.
+    pub fn exported(&self) -> Option<ExportedCall> {
+        let json = fs::read_to_string(&self.exported_path).ok()?;
+
+        ExportedCall::from_json(&json)
+            .map_err(|e| debug!("Skipping unreadable exported call: {}", e))
+            .ok()
+    }
+
+    /// Forget the exported call once it landed or was abandoned.
+    pub fn clear_exported(&self) -> BoxResult<()> {
+        if self.exported_path.exists() {
+            fs::remove_file(&self.exported_path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn pending(&self) -> BoxResult<Vec<IpfId>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        Ok(fs::read_to_string(&self.path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match line.trim().parse() {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    debug!("Skipping torn journal line {:?}: {}", line, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Note that a push ended, e.g. by Ctrl-C, before it could clean up after itself.
+    pub fn mark_unfinished(&self) {
+        let written = fs::create_dir_all(self.path.parent().unwrap_or(&self.path))
+            .and_then(|_| fs::write(&self.unfinished_path, ""));
+
+        if let Err(e) = written {
+            debug!("Could not mark the push as unfinished: {}", e);
+        }
+    }
+
+    /// Whether an earlier push was marked unfinished, forgetting it.
+    pub fn take_unfinished(&self) -> bool {
+        fs::remove_file(&self.unfinished_path).is_ok()
+    }
+
+    /// Remember a push reported done before its block was finalized.
+    pub fn record_unconfirmed(&self, push: &UnconfirmedPush) -> BoxResult<()> {
+        fs::create_dir_all(self.path.parent().ok_or("Journal path has no parent")?)?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.unconfirmed_path)?;
+        writeln!(file, "{}", push)?;
+
+        Ok(())
+    }
+
+    pub fn unconfirmed(&self) -> Vec<UnconfirmedPush> {
+        fs::read_to_string(&self.unconfirmed_path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let push = UnconfirmedPush::parse(line);
+                if push.is_none() {
+                    debug!("Skipping torn journal line {:?}", line);
+                }
+                push
+            })
+            .collect()
+    }
+
+    /// Replace the unconfirmed pushes with `pushes`, the ones still to check on.
+    pub fn reset_unconfirmed(&self, pushes: &[UnconfirmedPush]) -> BoxResult<()> {
+        if pushes.is_empty() {
+            if self.unconfirmed_path.exists() {
+                fs::remove_file(&self.unconfirmed_path)?;
+            }
+            return Ok(());
+        }
+
+        fs::write(
+            &self.unconfirmed_path,
+            pushes
+                .iter()
+                .map(|push| format!("{}\n", push))
+                .collect::<String>(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Replace the pending ids with `ipf_ids`, e.g. the ones that could not be burned.
+    pub fn reset(&self, ipf_ids: &[IpfId]) -> BoxResult<()> {
+        if ipf_ids.is_empty() {
+            if self.path.exists() {
+                fs::remove_file(&self.path)?;
+            }
+            return Ok(());
+        }
+
+        fs::write(
+            &self.path,
+            ipf_ids
+                .iter()
+                .map(|id| format!("{}\n", id))
+                .collect::<String>(),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn journal() -> (TempDir, PushJournal) {
+        let dir = TempDir::new().unwrap();
+        let journal = PushJournal::at(dir.path().join("pending-ipfs"), dir.path());
+        (dir, journal)
+    }
+
+    #[test]
+    fn recorded_ipfs_are_pending_until_reset() {
+        let (_dir, journal) = journal();
+        assert!(journal.pending().unwrap().is_empty());
+
+        journal.record(IpfId(3)).unwrap();
+        journal.record(IpfId(7)).unwrap();
+        assert_eq!(journal.pending().unwrap(), vec![IpfId(3), IpfId(7)]);
+
+        journal.reset(&[IpfId(7)]).unwrap();
+        assert_eq!(journal.pending().unwrap(), vec![IpfId(7)]);
+
+        journal.reset(&[]).unwrap();
+        assert!(journal.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remote_takes_over_the_shared_journal() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let shared = repo.path().join("inv4").join("pending-ipfs");
+        fs::create_dir_all(shared.parent().unwrap()).unwrap();
+        fs::write(&shared, "5\n").unwrap();
+        let state_dir = repo.path().join("inv4").join("origin");
+
+        let journal = PushJournal::open(&repo, &state_dir);
+
+        assert_eq!(journal.pending().unwrap(), vec![IpfId(5)]);
+        assert!(!shared.exists());
+        assert!(state_dir.join("pending-ipfs").is_file());
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let (dir, journal) = journal();
+        journal.record(IpfId(3)).unwrap();
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("pending-ipfs"))
+            .unwrap();
+        write!(file, "4x").unwrap();
+
+        assert_eq!(journal.pending().unwrap(), vec![IpfId(3)]);
+    }
+
+    #[test]
+    fn uploaded_blobs_are_kept_until_cleared() {
+        let (_dir, journal) = journal();
+        assert_eq!(journal.uploaded_blob("aa"), None);
+
+        journal.record_blob("aa", "QmOld").unwrap();
+        journal.record_blob("bb", "QmOther").unwrap();
+        journal.record_blob("aa", "QmNew").unwrap();
+        assert_eq!(journal.uploaded_blob("aa").as_deref(), Some("QmNew"));
+        assert_eq!(journal.uploaded_blob("bb").as_deref(), Some("QmOther"));
+
+        journal.clear_blobs().unwrap();
+        journal.clear_blobs().unwrap();
+        assert_eq!(journal.uploaded_blob("aa"), None);
+    }
+
+    #[test]
+    fn unfinished_mark_is_taken_once() {
+        let (_dir, journal) = journal();
+        assert!(!journal.take_unfinished());
+
+        journal.mark_unfinished();
+        assert!(journal.take_unfinished());
+        assert!(!journal.take_unfinished());
+    }
+
+    #[test]
+    fn unconfirmed_pushes_round_trip_until_reset() {
+        let (_dir, journal) = journal();
+        let push = UnconfirmedPush {
+            block_hash: H256::repeat_byte(1),
+            extrinsic_hash: H256::repeat_byte(2),
+            refs: vec![
+                ("refs/heads/main".to_owned(), Some("a".repeat(40))),
+                ("refs/heads/gone".to_owned(), None),
+            ],
+        };
+        assert_eq!(
+            UnconfirmedPush::parse(&push.to_string()),
+            Some(push.clone())
+        );
+        assert_eq!(UnconfirmedPush::parse("0101 0202 refs/heads/main="), None);
+
+        journal.record_unconfirmed(&push).unwrap();
+        let other = UnconfirmedPush {
+            block_hash: H256::repeat_byte(3),
+            ..push.clone()
+        };
+        journal.record_unconfirmed(&other).unwrap();
+        assert_eq!(journal.unconfirmed(), vec![push.clone(), other]);
+
+        journal.reset_unconfirmed(&[push.clone()]).unwrap();
+        assert_eq!(journal.unconfirmed(), vec![push]);
+
+        journal.reset_unconfirmed(&[]).unwrap();
+        assert!(journal.unconfirmed().is_empty());
+    }
+
+    #[test]
+    fn migrated_multi_objects_are_kept_until_cleared() {
+        let (_dir, journal) = journal();
+        journal.record_migrated("mo1", IpfId(8), "QmOne").unwrap();
+        journal.record_migrated("mo2", IpfId(9), "QmTwo").unwrap();
+
+        assert_eq!(
+            journal.migrated(),
+            BTreeMap::from([
+                ("mo1".to_owned(), (IpfId(8), "QmOne".to_owned())),
+                ("mo2".to_owned(), (IpfId(9), "QmTwo".to_owned())),
+            ])
+        );
+
+        journal.clear_migrated().unwrap();
+        assert!(journal.migrated().is_empty());
+    }
+
+    #[test]
+    fn appended_ipfs_are_kept_by_their_repo_data_until_cleared() {
+        let (dir, journal) = journal();
+        assert!(journal.appended().is_empty());
+
+        journal
+            .record_appended(IpfId(9), &[IpfId(1), IpfId(2)])
+            .unwrap();
+        journal.record_appended(IpfId(9), &[IpfId(3)]).unwrap();
+        journal.record_appended(IpfId(12), &[IpfId(4)]).unwrap();
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("appended-ipfs"))
+            .unwrap();
+        write!(file, "13 5,").unwrap();
+
+        assert_eq!(
+            journal.appended(),
+            BTreeMap::from([
+                (IpfId(9), vec![IpfId(1), IpfId(2), IpfId(3)]),
+                (IpfId(12), vec![IpfId(4)]),
+            ])
+        );
+
+        journal.clear_appended().unwrap();
+        assert!(journal.appended().is_empty());
+    }
+
+    #[test]
+    fn exported_calls_are_kept_until_cleared() {
+        let (dir, journal) = journal();
+        assert_eq!(journal.exported(), None);
+
+        let exported = crate::export_call::tests::exported(dir.child("publish.call"));
+        journal.record_exported(&exported).unwrap();
+        assert_eq!(journal.exported(), Some(exported));
+
+        fs::write(dir.child("exported-call.json"), "{").unwrap();
+        assert_eq!(journal.exported(), None);
+
+        journal.clear_exported().unwrap();
+        assert!(!dir.child("exported-call.json").exists());
+    }
+}
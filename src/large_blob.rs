@@ -0,0 +1,71 @@
+//! Blobs too large to bundle into a MultiObject, stored on their own as UnixFS files.
+//!
+//! They pass through a staging file on both ends. Uploads are streamed from it and downloads
+//! streamed to it and on into the odb, so only staging a pushed blob reads it into memory.
+
+use crate::primitives::BoxResult;
+use codec::{Decode, Encode};
+use git2::{ObjectType, Odb, Oid};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// What RepoData records about a large blob.
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LargeBlob {
+    /// Binary CID of the blob's content
+    #[serde(with = "crate::util::cid_string")]
+    pub cid: Vec<u8>,
+    pub size: u64,
+}
+
+/// The metadata of the IPF minted for the large blob `git_hash`.
+pub fn ipf_metadata(git_hash: &str) -> Vec<u8> {
+    format!("{}:{}", crate::primitives::LARGE_BLOB_MARKER, git_hash).into_bytes()
+}
+
+/// Copy the blob `oid` from `odb` to a new file at `path`, returning its size.
+///
+/// The blob is read whole: git2's `OdbReader` reports every read as filling the buffer, so it
+/// can't tell where a streamed object ends.
+pub fn stage(odb: &Odb, oid: Oid, path: &Path) -> BoxResult<u64> {
+    let object = odb.read(oid)?;
+    File::create(path)?.write_all(object.data())?;
+
+    Ok(object.len() as u64)
+}
+
+/// Write the blob staged at `path` into `odb`, returning its oid.
+pub fn insert(odb: &Odb, path: &Path) -> BoxResult<Oid> {
+    let size = path.metadata()?.len();
+    let mut writer = odb.writer(size as usize, ObjectType::Blob)?;
+    io::copy(&mut File::open(path)?, &mut writer)?;
+
+    Ok(writer.finalize()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::repository;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn staged_blob_goes_back_into_the_odb_unchanged() {
+        let (_dir, repo) = repository();
+        let odb = repo.odb().unwrap();
+        let content = vec![7u8; 3 * 1024 * 1024 + 5];
+        let oid = repo.blob(&content).unwrap();
+
+        let staging = TempDir::new().unwrap();
+        let path = staging.child("blob");
+        assert_eq!(stage(&odb, oid, &path).unwrap(), content.len() as u64);
+
+        let (_clone_dir, clone) = repository();
+        assert_eq!(insert(&clone.odb().unwrap(), &path).unwrap(), oid);
+        assert_eq!(clone.find_blob(oid).unwrap().content(), &content[..]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Human-readable details of a repository, kept as JSON in an IPF of their own next to RepoData.
+
+use crate::{
+    auth::{auth_flow, SigningContext},
+    chain::Chain,
+    error,
+    format::{decode_artifact, encode_artifact, Kind},
+    inspect::Artifact,
+    output,
+    primitives::{BoxResult, IpsId, SubassetId},
+    push_metadata,
+    runtime::Publish,
+    store::ObjectStore,
+    util::{cid_digest, generate_cid},
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use subxt::tx::PairSigner;
+
+/// IPF metadata marking the IPF that holds a repository's `RepoMeta`.
+const REPO_META: &[u8] = b"RepoMeta";
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RepoMeta {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// SPDX identifier of the license, e.g. `MIT`
+    pub license: Option<String>,
+    pub homepage: Option<String>,
+    /// Repository names and their IPS ids, when this IPS serves as a `name_registry_ips`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<String, IpsId>,
+}
+
+impl RepoMeta {
+    /// The details of the repository under `ips_id`, `None` when none were set.
+    pub async fn load(
+        chain: &Chain,
+        store: &dyn ObjectStore,
+        ips_id: IpsId,
+    ) -> BoxResult<Option<Self>> {
+        let ipf = match chain.find_ipf(ips_id, REPO_META).await? {
+            Some(ipf) => ipf,
+            None => return Ok(None),
+        };
+
+        let data = store.get(&generate_cid(ipf.data)?.to_string()).await?;
+
+        match decode_artifact(data, Some(Kind::RepoMeta))
+            .map_err(|e| format!("RepoMeta IPF {} is malformed: {}", ipf.id, e))?
+        {
+            Artifact::RepoMeta(meta) => Ok(Some(meta)),
+            other => error!(format!("RepoMeta IPF {} holds a {}", ipf.id, other.name())),
+        }
+    }
+
+    /// A line introducing the repository, if it has a name or description. Problems reading
+    /// them are only logged, as they must not get in the way of a fetch.
+    pub async fn banner(chain: &Chain, store: &dyn ObjectStore, ips_id: IpsId) -> Option<String> {
+        let meta = match Self::load(chain, store, ips_id).await {
+            Ok(meta) => meta?,
+            Err(e) => {
+                debug!("Could not read RepoMeta: {}", e);
+                return None;
+            }
+        };
+
+        meta.introduction()
+    }
+
+    /// `<name>: <description>`, or whichever of them is set.
+    fn introduction(self) -> Option<String> {
+        match (self.name, self.description) {
+            (Some(name), Some(description)) => Some(format!("{}: {}", name, description)),
+            (Some(line), None) | (None, Some(line)) => Some(line),
+            (None, None) => None,
+        }
+    }
+
+    /// Store these details and swap them in for the repository's current ones, in the same
+    /// kind of multisig batch that publishes pushes, signed for `context`.
+    pub async fn publish(
+        &self,
+        chain: &Chain,
+        store: &dyn ObjectStore,
+        ips_id: IpsId,
+        subasset_id: Option<SubassetId>,
+        context: &SigningContext,
+    ) -> BoxResult<()> {
+        let signer = PairSigner::new(auth_flow(None, context).await?.pair);
+
+        let ipfs_hash = store
+            .put(
+                encode_artifact(Kind::RepoMeta, serde_json::to_vec(self)?),
+                &signer,
+            )
+            .await?;
+
+        output::status("Minting repository details IPF...");
+        let new_id = chain
+            .mint_ipf(REPO_META.to_vec(), cid_digest(&ipfs_hash)?, &signer)
+            .await?;
+        debug!("Minted RepoMeta with IPF ID {}", new_id);
+
+        // Looked up only now, so details published meanwhile are replaced rather than duplicated
+        let old_id = chain.find_ipf(ips_id, REPO_META).await?.map(|ipf| ipf.id);
+
+        let call = chain.runtime().publish(&Publish {
+            ips_id,
+            subasset_id,
+            caller: signer.account_id().clone(),
+            pack_ipf_ids: vec![],
+            new_repo_data: Some(new_id),
+            old_repo_data: old_id,
+            removed_ipf_ids: vec![],
+            metadata: push_metadata::meta_update(),
+        });
+
+        output::status(format!("Publishing to repository {}...", ips_id));
+        if let Err(e) = chain.submit(&call, &signer).await {
+            if let Err(burn_error) = chain
+                .submit(&chain.runtime().burn_ipf(new_id), &signer)
+                .await
+            {
+                output::warn(format!("Could not burn IPF {}: {}", new_id, burn_error));
+            }
+            return Err(e);
+        }
+
+        output::info(format!("Published repository details in IPF {}", new_id));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn introduction_uses_what_is_set() {
+        let meta = |name: Option<&str>, description: Option<&str>| RepoMeta {
+            name: name.map(str::to_owned),
+            description: description.map(str::to_owned),
+            ..RepoMeta::default()
+        };
+
+        assert_eq!(
+            meta(Some("infra"), Some("Deployment scripts")).introduction(),
+            Some("infra: Deployment scripts".to_owned())
+        );
+        assert_eq!(
+            meta(None, Some("Deployment scripts")).introduction(),
+            Some("Deployment scripts".to_owned())
+        );
+        assert_eq!(meta(None, None).introduction(), None);
+    }
+
+    #[test]
+    fn details_round_trip_through_their_artifact() {
+        let meta = RepoMeta {
+            name: Some("infra".to_owned()),
+            license: Some("MIT".to_owned()),
+            ..RepoMeta::default()
+        };
+        let json = serde_json::to_vec(&meta).unwrap();
+        assert!(!String::from_utf8_lossy(&json).contains("names"));
+
+        let bytes = encode_artifact(Kind::RepoMeta, json);
+
+        match decode_artifact(bytes, Some(Kind::RepoMeta)).unwrap() {
+            Artifact::RepoMeta(decoded) => assert_eq!(decoded, meta),
+            other => panic!("decoded a {}", other.name()),
+        }
+
+        // Keys a newer build left out are unset
+        let partial: RepoMeta = serde_json::from_str(r#"{"name":"infra"}"#).unwrap();
+        assert_eq!(partial.description, None);
+    }
+}
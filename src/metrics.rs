@@ -0,0 +1,648 @@
+//! OpenMetrics snapshots of what the helper did, for monitoring scheduled fetches and pushes.
+//!
+//! With `GIT_INV4_METRICS_FILE=<path>`, the file is rewritten at the end of each list, fetch and
+//! push: operations and refs by outcome, how long operations and their upload, download and
+//! inclusion phases took, bytes moved, fees paid and the size of the repository. Counters and
+//! histograms already in the file are added to, so they keep counting across invocations, the
+//! way node_exporter's textfile collector expects of the files it scrapes.
+//!
+//! The snapshot is written next to the file and renamed over it, so a scrape never sees half of
+//! it. Two invocations finishing at the same moment may lose one's increments, but the file stays
+//! whole.
+
+use crate::{
+    events::Event,
+    output,
+    primitives::{IpsId, RepoData},
+};
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    process,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Upper bounds of the duration histograms' buckets, in seconds
+const BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+struct Family {
+    name: &'static str,
+    kind: Kind,
+    help: &'static str,
+}
+
+/// Every metric written, in the order written. Dashboards and alerts depend on these names and
+/// their labels, so they must not change.
+const FAMILIES: &[Family] = &[
+    Family {
+        name: "inv4_git_operations",
+        kind: Kind::Counter,
+        help: "Commands served, by operation (list, fetch, push) and outcome (success, failure)",
+    },
+    Family {
+        name: "inv4_git_refs",
+        kind: Kind::Counter,
+        help: "Refs fetched or pushed, by operation (fetch, push) and outcome (success, failure)",
+    },
+    Family {
+        name: "inv4_git_operation_duration_seconds",
+        kind: Kind::Histogram,
+        help: "How long commands took, by operation",
+    },
+    Family {
+        name: "inv4_git_phase_duration_seconds",
+        kind: Kind::Histogram,
+        help: "How long each upload, download and wait for a transaction to be included took, by phase (upload, download, inclusion)",
+    },
+    Family {
+        name: "inv4_git_uploaded_bytes",
+        kind: Kind::Counter,
+        help: "Compressed bytes uploaded to the object store",
+    },
+    Family {
+        name: "inv4_git_downloaded_bytes",
+        kind: Kind::Counter,
+        help: "Bytes downloaded from the object store",
+    },
+    Family {
+        name: "inv4_git_fees_paid",
+        kind: Kind::Counter,
+        help: "Transaction fees paid, in the smallest unit of the chain's token",
+    },
+    Family {
+        name: "inv4_git_last_success_timestamp_seconds",
+        kind: Kind::Gauge,
+        help: "When a command last succeeded, by operation",
+    },
+    Family {
+        name: "inv4_git_repository_refs",
+        kind: Kind::Gauge,
+        help: "Refs in the repository, by IPS",
+    },
+    Family {
+        name: "inv4_git_repository_objects",
+        kind: Kind::Gauge,
+        help: "Git objects in the repository, by IPS",
+    },
+    Family {
+        name: "inv4_git_repository_multi_objects",
+        kind: Kind::Gauge,
+        help: "MultiObjects holding the repository's objects, by IPS",
+    },
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Histogram {
+    /// Observations up to each of `BUCKETS`, cumulative like the `le` buckets written
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn merge(mut self, other: &Self) -> Self {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    /// The value of a counter or gauge
+    Number(f64),
+    Histogram(Histogram),
+}
+
+/// Samples by family, as an index into `FAMILIES`, and rendered labels.
+type Samples = BTreeMap<(usize, String), Value>;
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+struct Recorder {
+    path: PathBuf,
+    /// What changed since the file was last written
+    changes: Samples,
+    /// When phases still under way started, by phase and what they are about
+    started: BTreeMap<String, Instant>,
+}
+
+impl Recorder {
+    fn add(&mut self, family: &str, labels: String, amount: f64) {
+        let value = self
+            .changes
+            .entry((index(family), labels))
+            .or_insert(Value::Number(0.0));
+
+        if let Value::Number(value) = value {
+            *value += amount;
+        }
+    }
+
+    fn set(&mut self, family: &str, labels: String, value: f64) {
+        self.changes
+            .insert((index(family), labels), Value::Number(value));
+    }
+
+    fn observe(&mut self, family: &str, labels: String, duration: Duration) {
+        let value = self
+            .changes
+            .entry((index(family), labels))
+            .or_insert_with(|| Value::Histogram(Histogram::default()));
+
+        if let Value::Histogram(histogram) = value {
+            histogram.observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Record how long the phase `phase` of `subject` took, if it was seen starting.
+    fn finish_phase(&mut self, phase: &str, subject: &str) {
+        if let Some(started) = self.started.remove(&format!("{} {}", phase, subject)) {
+            self.observe(
+                "inv4_git_phase_duration_seconds",
+                labels(&[("phase", phase)]),
+                started.elapsed(),
+            );
+        }
+    }
+
+    /// Add the changes to the file's samples and write it anew.
+    fn write(&mut self) -> std::io::Result<()> {
+        let mut samples = match fs::read_to_string(&self.path) {
+            Ok(text) => parse(&text),
+            Err(_) => Samples::new(),
+        };
+
+        for (key, change) in std::mem::take(&mut self.changes) {
+            let merged = match (samples.remove(&key), change) {
+                (Some(Value::Number(old)), Value::Number(new))
+                    if FAMILIES[key.0].kind == Kind::Counter =>
+                {
+                    Value::Number(old + new)
+                }
+                (Some(Value::Histogram(old)), Value::Histogram(new)) => {
+                    Value::Histogram(old.merge(&new))
+                }
+                (_, change) => change,
+            };
+            samples.insert(key, merged);
+        }
+
+        let file_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let temp = self
+            .path
+            .with_file_name(format!(".{}.{}.tmp", file_name, process::id()));
+
+        fs::write(&temp, render(&samples))?;
+        fs::rename(&temp, &self.path).map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            e
+        })
+    }
+}
+
+fn with_recorder(f: impl FnOnce(&mut Recorder)) {
+    if let Some(recorder) = RECORDER.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(recorder)
+    }
+}
+
+fn index(family: &str) -> usize {
+    FAMILIES
+        .iter()
+        .position(|known| known.name == family)
+        .unwrap_or_else(|| panic!("Unknown metric {}", family))
+}
+
+/// `name="value"` pairs as written between the braces of a sample.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Start recording, when `GIT_INV4_METRICS_FILE` names a file.
+pub fn start() {
+    let path = match env::var_os("GIT_INV4_METRICS_FILE") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => return,
+    };
+
+    *RECORDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Recorder {
+        path,
+        changes: Samples::new(),
+        started: BTreeMap::new(),
+    });
+}
+
+/// Take the phases and amounts of data and fees of `event` into account.
+pub fn record(event: &Event) {
+    with_recorder(|recorder| match event {
+        Event::UploadStarted { .. } => {
+            // Uploads run one after the other, so there is only ever one to pair
+            recorder
+                .started
+                .insert("upload ".to_owned(), Instant::now());
+        }
+        Event::UploadFinished { compressed, .. } => {
+            recorder.add("inv4_git_uploaded_bytes", String::new(), *compressed as f64);
+            recorder.finish_phase("upload", "");
+        }
+        Event::DownloadStarted { multi_object } => {
+            recorder
+                .started
+                .insert(format!("download {}", multi_object), Instant::now());
+        }
+        Event::DownloadFinished {
+            multi_object,
+            bytes,
+        } => {
+            recorder.add("inv4_git_downloaded_bytes", String::new(), *bytes as f64);
+            recorder.finish_phase("download", multi_object);
+        }
+        Event::Submitted { extrinsic_hash } => {
+            recorder
+                .started
+                .insert(format!("inclusion {:?}", extrinsic_hash), Instant::now());
+        }
+        Event::InBlock { extrinsic_hash, .. } => {
+            recorder.finish_phase("inclusion", &format!("{:?}", extrinsic_hash));
+        }
+        Event::FeePaid { fee, .. } => {
+            recorder.add("inv4_git_fees_paid", String::new(), *fee as f64);
+        }
+        _ => {}
+    })
+}
+
+/// Count the refs an `operation` moved and failed to.
+pub fn refs(operation: &str, succeeded: usize, failed: usize) {
+    with_recorder(|recorder| {
+        for (outcome, count) in [("success", succeeded), ("failure", failed)] {
+            recorder.add(
+                "inv4_git_refs",
+                labels(&[("operation", operation), ("outcome", outcome)]),
+                count as f64,
+            );
+        }
+    })
+}
+
+/// Record the size of the repository under `ips_id`.
+pub fn repository(ips_id: IpsId, repo_data: &RepoData) {
+    with_recorder(|recorder| {
+        let ips = labels(&[("ips", &ips_id.to_string())]);
+
+        recorder.set(
+            "inv4_git_repository_refs",
+            ips.clone(),
+            repo_data.refs.len() as f64,
+        );
+        recorder.set(
+            "inv4_git_repository_objects",
+            ips.clone(),
+            repo_data.objects.len() as f64,
+        );
+        recorder.set(
+            "inv4_git_repository_multi_objects",
+            ips,
+            repo_data.cids.len() as f64,
+        );
+    })
+}
+
+/// Record that an `operation` ended after `elapsed`, and write the snapshot. Failing to write it
+/// is only warned about, as it must not get in the way of git.
+pub fn operation(operation: &str, elapsed: Duration, succeeded: bool) {
+    with_recorder(|recorder| {
+        let outcome = match succeeded {
+            true => "success",
+            false => "failure",
+        };
+        recorder.add(
+            "inv4_git_operations",
+            labels(&[("operation", operation), ("outcome", outcome)]),
+            1.0,
+        );
+        recorder.observe(
+            "inv4_git_operation_duration_seconds",
+            labels(&[("operation", operation)]),
+            elapsed,
+        );
+
+        if succeeded {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            recorder.set(
+                "inv4_git_last_success_timestamp_seconds",
+                labels(&[("operation", operation)]),
+                now.as_secs() as f64,
+            );
+        }
+
+        if let Err(e) = recorder.write() {
+            output::warn(format!(
+                "Could not write metrics to {}: {}",
+                recorder.path.display(),
+                e
+            ));
+        }
+    })
+}
+
+/// `samples` in the OpenMetrics text format.
+fn render(samples: &Samples) -> String {
+    let mut text = String::new();
+
+    for (index, family) in FAMILIES.iter().enumerate() {
+        let mut family_samples = samples
+            .range((index, String::new())..(index + 1, String::new()))
+            .peekable();
+        if family_samples.peek().is_none() {
+            continue;
+        }
+
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(text, "# TYPE {} {}", family.name, kind);
+        let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+
+        for ((_, labels), value) in family_samples {
+            let braced = |labels: &str| match labels.is_empty() {
+                true => String::new(),
+                false => format!("{{{}}}", labels),
+            };
+            let with_bound = |bound: &str| {
+                let le = format!("le=\"{}\"", bound);
+                match labels.is_empty() {
+                    true => le,
+                    false => format!("{},{}", labels, le),
+                }
+            };
+
+            match value {
+                Value::Number(value) => {
+                    let suffix = match family.kind {
+                        Kind::Counter => "_total",
+                        _ => "",
+                    };
+                    let _ = writeln!(
+                        text,
+                        "{}{}{} {}",
+                        family.name,
+                        suffix,
+                        braced(labels),
+                        value
+                    );
+                }
+                Value::Histogram(histogram) => {
+                    for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                        let _ = writeln!(
+                            text,
+                            "{}_bucket{} {}",
+                            family.name,
+                            braced(&with_bound(&format!("{:?}", bound))),
+                            count
+                        );
+                    }
+                    let _ = writeln!(
+                        text,
+                        "{}_bucket{} {}",
+                        family.name,
+                        braced(&with_bound("+Inf")),
+                        histogram.count
+                    );
+                    let _ = writeln!(
+                        text,
+                        "{}_sum{} {}",
+                        family.name,
+                        braced(labels),
+                        histogram.sum
+                    );
+                    let _ = writeln!(
+                        text,
+                        "{}_count{} {}",
+                        family.name,
+                        braced(labels),
+                        histogram.count
+                    );
+                }
+            }
+        }
+    }
+
+    text.push_str("# EOF\n");
+    text
+}
+
+/// The samples of a snapshot written by `render`. Lines it doesn't recognize are dropped.
+fn parse(text: &str) -> Samples {
+    let mut samples = Samples::new();
+
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let (series, value) = match line.rsplit_once(' ') {
+            Some((series, value)) => match value.parse::<f64>() {
+                Ok(value) => (series, value),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        let (name, labels) = match series.split_once('{') {
+            Some((name, labels)) => (name, labels.strip_suffix('}').unwrap_or(labels)),
+            None => (series, ""),
+        };
+
+        let (index, family, suffix) =
+            match FAMILIES.iter().enumerate().find_map(|(index, family)| {
+                let suffix = name.strip_prefix(family.name)?;
+                let known = matches!(
+                    (family.kind, suffix),
+                    (Kind::Counter, "_total")
+                        | (Kind::Gauge, "")
+                        | (Kind::Histogram, "_bucket" | "_sum" | "_count")
+                );
+                known.then(|| (index, family, suffix))
+            }) {
+                Some(found) => found,
+                None => {
+                    debug!("Dropping unknown metric line {:?}", line);
+                    continue;
+                }
+            };
+
+        if family.kind != Kind::Histogram {
+            samples.insert((index, labels.to_owned()), Value::Number(value));
+            continue;
+        }
+
+        let (labels, bound) = match suffix {
+            "_bucket" => match labels.rsplit_once("le=\"") {
+                Some((labels, bound)) => {
+                    (labels.trim_end_matches(','), bound.trim_end_matches('"'))
+                }
+                None => continue,
+            },
+            _ => (labels, ""),
+        };
+
+        let histogram = match samples
+            .entry((index, labels.to_owned()))
+            .or_insert_with(|| Value::Histogram(Histogram::default()))
+        {
+            Value::Histogram(histogram) => histogram,
+            Value::Number(_) => continue,
+        };
+
+        match suffix {
+            "_sum" => histogram.sum = value,
+            "_count" => histogram.count = value as u64,
+            // The +Inf bucket is the count
+            _ => {
+                if let Some(bucket) = BUCKETS
+                    .iter()
+                    .position(|known| format!("{:?}", known) == bound)
+                {
+                    histogram.buckets[bucket] = value as u64;
+                }
+            }
+        }
+    }
+
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn recorder(path: PathBuf) -> Recorder {
+        Recorder {
+            path,
+            changes: Samples::new(),
+            started: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(
+            labels(&[("operation", "push"), ("ref", "a\"b\\c\nd")]),
+            r#"operation="push",ref="a\"b\\c\nd""#
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.25);
+        histogram.observe(4.0);
+        histogram.observe(1000.0);
+
+        assert_eq!(histogram.buckets, [0, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.merge(&Histogram::default()).sum, 1004.25);
+    }
+
+    #[test]
+    fn snapshots_round_trip_through_their_text() {
+        let mut samples = Samples::new();
+        let operation = labels(&[("operation", "push"), ("outcome", "success")]);
+        samples.insert(
+            (index("inv4_git_operations"), operation),
+            Value::Number(2.0),
+        );
+        samples.insert(
+            (index("inv4_git_fees_paid"), String::new()),
+            Value::Number(5.0),
+        );
+        let mut histogram = Histogram::default();
+        histogram.observe(0.7);
+        samples.insert(
+            (
+                index("inv4_git_operation_duration_seconds"),
+                labels(&[("operation", "push")]),
+            ),
+            Value::Histogram(histogram),
+        );
+
+        let text = render(&samples);
+        assert!(text.contains("# TYPE inv4_git_operations counter\n"));
+        assert!(
+            text.contains("inv4_git_operations_total{operation=\"push\",outcome=\"success\"} 2\n")
+        );
+        assert!(text.contains(
+            "inv4_git_operation_duration_seconds_bucket{operation=\"push\",le=\"1.0\"} 1\n"
+        ));
+        assert!(text.ends_with("# EOF\n"));
+        assert_eq!(parse(&text), samples);
+
+        assert!(parse("unknown_metric 1\n# EOF\n").is_empty());
+    }
+
+    #[test]
+    fn counters_accumulate_across_writes_and_gauges_are_replaced() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.child("metrics.prom");
+        let ips = labels(&[("ips", "1")]);
+
+        for refs in [3.0, 4.0] {
+            let mut recorder = recorder(path.clone());
+            recorder.add("inv4_git_uploaded_bytes", String::new(), 100.0);
+            recorder.set("inv4_git_repository_refs", ips.clone(), refs);
+            recorder.observe(
+                "inv4_git_phase_duration_seconds",
+                labels(&[("phase", "upload")]),
+                Duration::from_secs(2),
+            );
+            recorder.write().unwrap();
+        }
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("inv4_git_uploaded_bytes_total 200\n"));
+        assert!(text.contains("inv4_git_repository_refs{ips=\"1\"} 4\n"));
+        assert!(text.contains("inv4_git_phase_duration_seconds_count{phase=\"upload\"} 2\n"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
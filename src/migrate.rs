@@ -0,0 +1,337 @@
+//! `inv4-git migrate`: re-uploads the artifacts of a repository that predate format envelopes in
+//! the current formats.
+//!
+//! The RepoData and the MultiObjects it refers to are probed for an envelope. Legacy
+//! MultiObjects are read with the compatibility decoders, minted again in the current layout and
+//! recorded in RepoData's `cids`, which lookups go by first. One multisig batch then swaps in the
+//! re-encoded RepoData and appends the new IPFs, leaving the legacy ones in the IPS unless asked
+//! to remove them. MultiObjects minted again are journaled, so an interrupted migration resumes
+//! with them, and a finished one finds nothing left to do.
+
+use crate::{
+    auth::{auth_flow, SigningContext},
+    chain::{Chain, Confirmation},
+    format::{Format, ENVELOPE_LEN},
+    journal::PushJournal,
+    output,
+    primitives::{
+        BoxResult, Config, IpfId, IpsId, MultiObject, MultiObjectIndex, RepoData, SubassetId,
+        LARGE_BLOB_MARKER, SUBMODULE_TIP_MARKER,
+    },
+    push_metadata,
+    runtime::Publish,
+    store::ObjectStore,
+    util::{cid_digest, generate_cid},
+};
+use cid::Cid;
+use log::debug;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+use subxt::tx::PairSigner;
+
+/// What a migration would do.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub ips_id: IpsId,
+    /// The IPF of the RepoData, when it predates format envelopes
+    pub repo_data: Option<IpfId>,
+    /// MultiObjects the RepoData refers to that are stored in a legacy format; a
+    /// {MultiObject hash -> CID} map
+    pub multi_objects: BTreeMap<String, String>,
+    /// MultiObjects the RepoData refers to that are stored in the current format
+    pub current: usize,
+    /// IPFs in the IPS holding one of those MultiObjects in a legacy format
+    pub legacy_ipfs: Vec<IpfId>,
+}
+
+impl Plan {
+    /// Whether there is nothing to do, including removing legacy IPFs with `remove_legacy`.
+    pub fn is_empty(&self, remove_legacy: bool) -> bool {
+        self.repo_data.is_none()
+            && self.multi_objects.is_empty()
+            && (!remove_legacy || self.legacy_ipfs.is_empty())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "IPS:            {}", self.ips_id)?;
+        match self.repo_data {
+            Some(id) => writeln!(f, "RepoData:       IPF {} is legacy, re-encoded", id)?,
+            None => writeln!(f, "RepoData:       current")?,
+        }
+        writeln!(
+            f,
+            "MultiObjects:   {} legacy to mint again, {} current",
+            self.multi_objects.len(),
+            self.current
+        )?;
+        writeln!(
+            f,
+            "Legacy IPFs:    {}, kept in the IPS unless --remove-legacy is given",
+            self.legacy_ipfs.len()
+        )
+    }
+}
+
+/// Find what in the repository under `ips_id` predates format envelopes, returning its current
+/// RepoData along with the plan. An unreadable RepoData fails rather than falling back to an
+/// older one, which a migration would publish over the latest pushes.
+pub async fn plan(
+    ips_id: IpsId,
+    chain: &Chain,
+    store: &dyn ObjectStore,
+) -> BoxResult<(RepoData, Plan)> {
+    let ids = chain
+        .ipf_ids(ips_id)
+        .await?
+        .ok_or(format!("IPS {} does not exist", ips_id))?;
+
+    let mut ipfs = vec![];
+    for (i, id) in ids.iter().enumerate() {
+        output::status(format!("[{}/{}] Reading IPF {}", i + 1, ids.len(), id));
+        ipfs.push(chain.ipf(*id).await?);
+    }
+
+    let repo_data_ipf = ipfs
+        .iter()
+        .find(|ipf| ipf.metadata == b"RepoData")
+        .ok_or(format!("Nothing was pushed to IPS {}", ips_id))?;
+    let repo_data = RepoData::from_ipfs(repo_data_ipf.data, store).await?;
+
+    let mut probed = BTreeMap::new();
+    let mut plan = Plan {
+        ips_id,
+        ..Default::default()
+    };
+
+    if is_legacy(
+        &generate_cid(repo_data_ipf.data)?.to_string(),
+        store,
+        &mut probed,
+    )
+    .await?
+    {
+        plan.repo_data = Some(repo_data_ipf.id);
+    }
+
+    let referenced: BTreeSet<&str> = repo_data
+        .objects
+        .values()
+        .map(String::as_str)
+        .filter(|hash| *hash != LARGE_BLOB_MARKER && *hash != SUBMODULE_TIP_MARKER)
+        .collect();
+
+    // The first IPF of a MultiObject is the one found for it when no CID is recorded
+    let mut first_cids = BTreeMap::new();
+
+    for ipf in &ipfs {
+        let hash = match std::str::from_utf8(&ipf.metadata) {
+            Ok(hash) if referenced.contains(hash) => hash,
+            _ => continue,
+        };
+        let cid = generate_cid(ipf.data)?.to_string();
+
+        output::status(format!("Probing IPF {}", ipf.id));
+        if is_legacy(&cid, store, &mut probed).await? {
+            plan.legacy_ipfs.push(ipf.id);
+        }
+
+        first_cids.entry(hash.to_owned()).or_insert(cid);
+    }
+
+    let index = MultiObjectIndex::Cids(first_cids);
+
+    for hash in referenced {
+        let cid = repo_data.multi_object_cid(hash, &index).await?;
+
+        output::status(format!("Probing MultiObject {}", hash));
+        match is_legacy(&cid, store, &mut probed).await? {
+            true => {
+                plan.multi_objects.insert(hash.to_owned(), cid);
+            }
+            false => plan.current += 1,
+        }
+    }
+
+    output::clear_status();
+
+    Ok((repo_data, plan))
+}
+
+/// Whether the artifact at `cid` predates format envelopes, reading only the envelope's worth of
+/// it. `probed` keeps the answers by CID.
+async fn is_legacy(
+    cid: &str,
+    store: &dyn ObjectStore,
+    probed: &mut BTreeMap<String, bool>,
+) -> BoxResult<bool> {
+    if let Some(&legacy) = probed.get(cid) {
+        return Ok(legacy);
+    }
+
+    let start = store.get_range(cid, 0, ENVELOPE_LEN).await?;
+    let legacy = Format::parse(&start)?.is_none();
+    probed.insert(cid.to_owned(), legacy);
+
+    Ok(legacy)
+}
+
+/// Carry out `plan` on `repo_data`, signed for `context`, removing the legacy IPFs from the IPS
+/// with `remove_legacy`.
+pub async fn apply(
+    plan: &Plan,
+    mut repo_data: RepoData,
+    remove_legacy: bool,
+    subasset_id: Option<SubassetId>,
+    config: &Config,
+    chain: &Chain,
+    store: &dyn ObjectStore,
+    journal: &PushJournal,
+    context: &SigningContext,
+) -> BoxResult<()> {
+    let signer = PairSigner::new(auth_flow(None, context).await?.pair);
+    let resumed = journal.migrated();
+    let mut minted = vec![];
+
+    for (i, (hash, cid)) in plan.multi_objects.iter().enumerate() {
+        output::status(format!(
+            "[{}/{}] Migrating MultiObject {}",
+            i + 1,
+            plan.multi_objects.len(),
+            hash
+        ));
+
+        let resumable = match resumed.get(hash) {
+            Some((ipf_id, new_cid)) => {
+                let digest = cid_digest(new_cid)?;
+                match chain.ipf(*ipf_id).await {
+                    Ok(ipf) if ipf.data == digest => Some((*ipf_id, new_cid.clone())),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+
+        let (ipf_id, new_cid) = match resumable {
+            Some(minted) => {
+                debug!("Resuming with IPF {} for MultiObject {}", minted.0, hash);
+                minted
+            }
+            None => {
+                let multi_object = MultiObject::get(cid, store).await?;
+                multi_object.verify()?;
+
+                let new_cid = store.put(multi_object.to_bytes(), &signer).await?;
+                let ipf_id = chain
+                    .mint_ipf(hash.as_bytes().to_vec(), cid_digest(&new_cid)?, &signer)
+                    .await?;
+                journal.record_migrated(hash, ipf_id, &new_cid)?;
+
+                (ipf_id, new_cid)
+            }
+        };
+
+        repo_data
+            .cids
+            .insert(hash.clone(), Cid::try_from(new_cid.as_str())?.to_bytes());
+        minted.push(ipf_id);
+    }
+
+    output::clear_status();
+
+    let (new_repo_data, old_repo_data) = repo_data
+        .mint_return_new_old_id(config, store, chain, &signer, plan.ips_id)
+        .await?;
+
+    let call = chain.runtime().publish(&Publish {
+        ips_id: plan.ips_id,
+        subasset_id,
+        caller: signer.account_id().clone(),
+        pack_ipf_ids: minted,
+        new_repo_data: Some(new_repo_data),
+        old_repo_data,
+        removed_ipf_ids: match remove_legacy {
+            true => plan.legacy_ipfs.clone(),
+            false => vec![],
+        },
+        metadata: push_metadata::migration(),
+    });
+
+    output::status(format!("Publishing to repository {}...", plan.ips_id));
+    let included = match chain.submit(&call, &signer).await {
+        Ok(included) => included,
+        Err(e) => {
+            // The MultiObjects stay journaled for the next attempt, the RepoData is minted anew
+            if let Err(burn_error) = chain
+                .submit(&chain.runtime().burn_ipf(new_repo_data), &signer)
+                .await
+            {
+                output::warn(format!(
+                    "Could not burn IPF {}: {}",
+                    new_repo_data, burn_error
+                ));
+            }
+            return Err(e);
+        }
+    };
+
+    if config.confirmation == Confirmation::Finalized {
+        chain.finalize(included).await?;
+    }
+
+    journal.clear_migrated()?;
+
+    output::info(format!(
+        "Migrated {} MultiObjects and the RepoData of IPS {}",
+        plan.multi_objects.len(),
+        plan.ips_id
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::compress_data,
+        format::{encode_artifact, Kind},
+        store::tests::MemoryStore,
+    };
+    use codec::Encode;
+
+    #[tokio::test]
+    async fn artifacts_without_an_envelope_are_legacy() {
+        let store = MemoryStore::default();
+        store.insert("legacy", compress_data(RepoData::default().encode()));
+        store.insert(
+            "current",
+            encode_artifact(Kind::RepoData, RepoData::default().encode()),
+        );
+        let mut probed = BTreeMap::new();
+
+        assert!(is_legacy("legacy", &store, &mut probed).await.unwrap());
+        assert!(!is_legacy("current", &store, &mut probed).await.unwrap());
+
+        // Answers are kept, so each artifact is probed once
+        store.insert("legacy", vec![]);
+        assert!(is_legacy("legacy", &store, &mut probed).await.unwrap());
+        assert!(is_legacy("missing", &store, &mut probed).await.is_err());
+    }
+
+    #[test]
+    fn legacy_ipfs_only_count_when_removed() {
+        let mut plan = Plan {
+            legacy_ipfs: vec![IpfId(3)],
+            ..Plan::default()
+        };
+        assert!(plan.is_empty(false));
+        assert!(!plan.is_empty(true));
+
+        plan.repo_data = Some(IpfId(1));
+        assert!(!plan.is_empty(false));
+    }
+}
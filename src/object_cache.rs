@@ -0,0 +1,508 @@
+//! Downloaded content kept on disk, so fetching a MultiObject again doesn't go to the network.
+//!
+//! Entries live in `<cache dir>/INV4-Git/objects/`, named by CID. Content is addressed by hash,
+//! so unlike other state they aren't kept per chain. `index.json` records the size and digest of
+//! each entry, when it was last read, how long it took to fetch and whether it came from a node
+//! on this machine or a gateway.
+//!
+//! When the entries outgrow `cache_max_size`, the ones cheapest to fetch again go first, rather
+//! than simply the least recently used: a huge pack that took minutes from a gateway is kept
+//! over a quick one from the local node. Entries are checked against their digest when read, and
+//! a corrupt one is deleted and fetched again.
+//!
+//! Several helpers may use the cache at once. The index is only changed while holding
+//! `index.lock`. Entries are written under a temporary name and renamed in, and evicted by
+//! renaming them out before deleting them, so a reader opens either a whole entry or none.
+
+use crate::{cache, output, primitives::BoxResult};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subxt::ext::sp_core::hashing::blake2_256;
+
+const INDEX_FILE: &str = "index.json";
+const LOCK_FILE: &str = "index.lock";
+
+/// How long to wait for another process to release the index.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A lock older than this was left by a process that died holding it.
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// How many times cheaper fetching from a node on this machine counts as than from a gateway,
+/// for the same recorded fetch time.
+const LOCAL_DISCOUNT: f64 = 4.0;
+
+/// Where an entry was fetched from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// A node on this machine
+    Local,
+    /// A gateway or remote node
+    Gateway,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub size: u64,
+    /// Hex blake2-256 of the content
+    pub digest: String,
+    /// Seconds since the epoch
+    pub last_access: u64,
+    /// How long downloading it took
+    pub fetch_ms: u64,
+    pub source: Source,
+}
+
+impl Entry {
+    /// How much evicting the entry is worth as of `now`; the highest goes first.
+    ///
+    /// That's the bytes it frees per millisecond it would take to fetch again, weighted by how
+    /// long it went unread. Entries from a node on this machine count as `LOCAL_DISCOUNT` times
+    /// cheaper to fetch.
+    pub fn eviction_score(&self, now: u64) -> f64 {
+        let idle = now.saturating_sub(self.last_access) as f64 + 1.0;
+        let cost = match self.source {
+            Source::Local => self.fetch_ms as f64 / LOCAL_DISCOUNT,
+            Source::Gateway => self.fetch_ms as f64,
+        };
+
+        self.size as f64 * idle / cost.max(1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub struct Index {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    pub fn size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    /// The CIDs to evict, best first, for the entries to fit in `max_size`.
+    pub fn evictions(&self, max_size: u64, now: u64) -> Vec<String> {
+        let mut scored: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(cid, entry)| (entry.eviction_score(now), cid, entry.size))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut size = self.size();
+        let mut evictions = vec![];
+        for (_, cid, entry_size) in scored {
+            if size <= max_size {
+                break;
+            }
+            size -= entry_size;
+            evictions.push(cid.clone());
+        }
+
+        evictions
+    }
+
+    pub fn encode(&self) -> BoxResult<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> BoxResult<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// What `ObjectCache::gc` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub evicted: usize,
+    pub freed: u64,
+    /// Entries without a file, or files without an entry, e.g. left by a crash
+    pub orphans: usize,
+    pub kept: usize,
+    pub size: u64,
+}
+
+pub struct ObjectCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// Held while the index may change; removes the lock file when dropped.
+struct IndexLock(PathBuf);
+
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl ObjectCache {
+    /// The cache in the OS cache directory, kept under `max_size` bytes.
+    pub fn open(max_size: u64) -> BoxResult<Self> {
+        Self::at(cache::objects_dir()?, max_size)
+    }
+
+    fn at(dir: PathBuf, max_size: u64) -> BoxResult<Self> {
+        for subdir in ["entries", "tmp", "trash"] {
+            fs::create_dir_all(dir.join(subdir))?;
+        }
+
+        Ok(Self { dir, max_size })
+    }
+
+    fn entry_path(&self, cid: &str) -> PathBuf {
+        self.dir.join("entries").join(cid)
+    }
+
+    /// The content cached under `cid`, `None` when it isn't, or is corrupt and was deleted.
+    pub fn get(&self, cid: &str) -> Option<Vec<u8>> {
+        if !is_cid(cid) {
+            return None;
+        }
+
+        let data = match fs::read(self.entry_path(cid)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                debug!("Could not read cached {}: {}", cid, e);
+                return None;
+            }
+        };
+        let digest = hex::encode(blake2_256(&data));
+
+        let intact = self.with_index(|index| {
+            let intact = match index.entries.get_mut(cid) {
+                Some(entry) if entry.digest == digest => {
+                    entry.last_access = now();
+                    true
+                }
+                Some(_) => {
+                    output::warn(format!("Cached {} is corrupt, fetching it again", cid));
+                    false
+                }
+                // Written by a process that died before recording it
+                None => false,
+            };
+
+            if !intact {
+                index.entries.remove(cid);
+                self.evict(cid);
+            }
+            Ok(intact)
+        });
+
+        match intact {
+            Ok(true) => Some(data),
+            Ok(false) => None,
+            Err(e) => {
+                debug!("Could not update the object cache index: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Keep `data`, fetched as `cid` from `source` in `fetched_in`, evicting other entries when
+    /// the cache outgrows its size. Failures are only logged, as the cache is an optimization.
+    pub fn put(&self, cid: &str, data: &[u8], fetched_in: Duration, source: Source) {
+        if !is_cid(cid) || data.len() as u64 > self.max_size {
+            return;
+        }
+
+        if let Err(e) = self.try_put(cid, data, fetched_in, source) {
+            debug!("Could not cache {}: {}", cid, e);
+        }
+    }
+
+    fn try_put(
+        &self,
+        cid: &str,
+        data: &[u8],
+        fetched_in: Duration,
+        source: Source,
+    ) -> BoxResult<()> {
+        let staged = self
+            .dir
+            .join("tmp")
+            .join(format!("{}.{}", cid, process::id()));
+        fs::write(&staged, data)?;
+        fs::rename(&staged, self.entry_path(cid))?;
+
+        self.with_index(|index| {
+            index.entries.insert(
+                cid.to_owned(),
+                Entry {
+                    size: data.len() as u64,
+                    digest: hex::encode(blake2_256(data)),
+                    last_access: now(),
+                    fetch_ms: fetched_in.as_millis() as u64,
+                    source,
+                },
+            );
+
+            for cid in index.evictions(self.max_size, now()) {
+                debug!("Evicting {} from the object cache", cid);
+                index.entries.remove(&cid);
+                self.evict(&cid);
+            }
+            Ok(())
+        })
+    }
+
+    /// Drop index entries without a file and files without an entry, then evict entries until
+    /// the rest fit in the cache's size.
+    pub fn gc(&self) -> BoxResult<GcReport> {
+        self.with_index(|index| {
+            let mut report = GcReport::default();
+
+            index.entries.retain(|cid, _| {
+                let present = self.entry_path(cid).is_file();
+                report.orphans += usize::from(!present);
+                present
+            });
+
+            for file in fs::read_dir(self.dir.join("entries"))? {
+                let name = file?.file_name().to_string_lossy().into_owned();
+                if !index.entries.contains_key(&name) {
+                    self.evict(&name);
+                    report.orphans += 1;
+                }
+            }
+
+            for cid in index.evictions(self.max_size, now()) {
+                if let Some(entry) = index.entries.remove(&cid) {
+                    self.evict(&cid);
+                    report.evicted += 1;
+                    report.freed += entry.size;
+                }
+            }
+
+            report.kept = index.entries.len();
+            report.size = index.size();
+            Ok(report)
+        })
+    }
+
+    /// The entries and their total size, without changing anything.
+    pub fn usage(&self) -> BoxResult<(usize, u64)> {
+        let index = self.read_index()?;
+        Ok((index.entries.len(), index.size()))
+    }
+
+    /// Move the entry out of the way, then delete it. A process that opened it already keeps
+    /// reading it where the OS allows that, and a rename that fails because it's open leaves it
+    /// for the next `gc`.
+    fn evict(&self, cid: &str) {
+        let trashed = self
+            .dir
+            .join("trash")
+            .join(format!("{}.{}", cid, process::id()));
+
+        match fs::rename(self.entry_path(cid), &trashed) {
+            Ok(()) => {
+                let _ = fs::remove_file(&trashed);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => debug!("Could not evict {}: {}", cid, e),
+        }
+    }
+
+    fn read_index(&self) -> BoxResult<Index> {
+        match fs::read(self.dir.join(INDEX_FILE)) {
+            Ok(bytes) => Index::decode(&bytes).or_else(|e| {
+                // Entries it loses are dropped as orphans by the next gc
+                output::warn(format!(
+                    "The object cache index is unreadable, starting over: {}",
+                    e
+                ));
+                Ok(Index::default())
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Run `f` on the index while holding the lock, saving what it changed.
+    fn with_index<R>(&self, f: impl FnOnce(&mut Index) -> BoxResult<R>) -> BoxResult<R> {
+        let _lock = self.lock()?;
+
+        let mut index = self.read_index()?;
+        let result = f(&mut index)?;
+
+        let staged = self
+            .dir
+            .join("tmp")
+            .join(format!("{}.{}", INDEX_FILE, process::id()));
+        fs::write(&staged, index.encode()?)?;
+        fs::rename(&staged, self.dir.join(INDEX_FILE))?;
+
+        Ok(result)
+    }
+
+    fn lock(&self) -> BoxResult<IndexLock> {
+        let path = self.dir.join(LOCK_FILE);
+        let started = SystemTime::now();
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(IndexLock(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if is_stale(&path) {
+                debug!("Removing stale {}", path.display());
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+
+            if started.elapsed().unwrap_or_default() > LOCK_TIMEOUT {
+                return Err(format!(
+                    "{} is held by another process; remove it if none is running",
+                    path.display()
+                )
+                .into());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+fn is_stale(lock: &Path) -> bool {
+    fs::metadata(lock)
+        .and_then(|metadata| metadata.modified())
+        .map_or(false, |modified| {
+            modified.elapsed().unwrap_or_default() > STALE_LOCK
+        })
+}
+
+/// Whether `cid` can name a file as is, as any CID the stores return can.
+fn is_cid(cid: &str) -> bool {
+    !cid.is_empty() && cid.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    const CID: &str = "bafkreiabc";
+
+    fn entry(size: u64, last_access: u64, fetch_ms: u64, source: Source) -> Entry {
+        Entry {
+            size,
+            digest: String::new(),
+            last_access,
+            fetch_ms,
+            source,
+        }
+    }
+
+    #[test]
+    fn entries_cheap_to_fetch_again_are_evicted_first() {
+        let slow_pack = entry(100, 1000, 60_000, Source::Gateway);
+        let quick_pack = entry(100, 1000, 60_000, Source::Local);
+        let unread_pack = entry(100, 0, 60_000, Source::Gateway);
+
+        assert!(quick_pack.eviction_score(1000) > slow_pack.eviction_score(1000));
+        assert!(unread_pack.eviction_score(1000) > slow_pack.eviction_score(1000));
+
+        let index = Index {
+            entries: BTreeMap::from([
+                ("slow".to_owned(), slow_pack),
+                ("quick".to_owned(), quick_pack),
+                ("unread".to_owned(), unread_pack),
+            ]),
+        };
+        assert_eq!(index.size(), 300);
+        assert!(index.evictions(300, 1000).is_empty());
+        assert_eq!(index.evictions(200, 1000), ["unread"]);
+        assert_eq!(index.evictions(150, 1000), ["unread", "quick"]);
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let index = Index {
+            entries: BTreeMap::from([(CID.to_owned(), entry(3, 1, 2, Source::Local))]),
+        };
+        let encoded = index.encode().unwrap();
+
+        assert!(String::from_utf8_lossy(&encoded).contains("\"source\":\"local\""));
+        assert_eq!(Index::decode(&encoded).unwrap(), index);
+    }
+
+    #[test]
+    fn content_is_served_until_it_is_corrupted() {
+        let dir = TempDir::new().unwrap();
+        let cache = ObjectCache::at(dir.path().to_owned(), 1024).unwrap();
+
+        assert_eq!(cache.get(CID), None);
+        cache.put(CID, b"pack", Duration::from_millis(5), Source::Gateway);
+        assert_eq!(cache.get(CID).as_deref(), Some(&b"pack"[..]));
+        assert_eq!(cache.usage().unwrap(), (1, 4));
+        assert!(!dir.path().join(LOCK_FILE).exists());
+
+        fs::write(cache.entry_path(CID), b"kcap").unwrap();
+        assert_eq!(cache.get(CID), None);
+        assert!(!cache.entry_path(CID).exists());
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn what_cannot_fit_or_name_a_file_is_not_cached() {
+        let dir = TempDir::new().unwrap();
+        let cache = ObjectCache::at(dir.path().to_owned(), 4).unwrap();
+
+        cache.put(CID, b"too large", Duration::ZERO, Source::Local);
+        cache.put("../escape", b"x", Duration::ZERO, Source::Local);
+        assert_eq!(cache.usage().unwrap(), (0, 0));
+        assert_eq!(cache.get("../escape"), None);
+
+        cache.put("first", b"abc", Duration::ZERO, Source::Local);
+        cache.put("second", b"de", Duration::ZERO, Source::Local);
+        assert_eq!(cache.usage().unwrap().1, 2);
+        assert!(cache.get("second").is_some());
+    }
+
+    #[test]
+    fn gc_drops_orphans_and_evicts_down_to_the_size() {
+        let dir = TempDir::new().unwrap();
+        let cache = ObjectCache::at(dir.path().to_owned(), 1024).unwrap();
+
+        cache.put("kept", b"abc", Duration::from_secs(1), Source::Gateway);
+        cache.put("missing", b"abc", Duration::from_secs(1), Source::Gateway);
+        fs::remove_file(cache.entry_path("missing")).unwrap();
+        fs::write(cache.entry_path("unindexed"), b"abc").unwrap();
+
+        assert_eq!(
+            cache.gc().unwrap(),
+            GcReport {
+                evicted: 0,
+                freed: 0,
+                orphans: 2,
+                kept: 1,
+                size: 3,
+            }
+        );
+        assert!(!cache.entry_path("unindexed").exists());
+
+        let small = ObjectCache::at(dir.path().to_owned(), 2).unwrap();
+        assert_eq!(small.gc().unwrap().freed, 3);
+        assert_eq!(small.get("kept"), None);
+    }
+}
@@ -0,0 +1,326 @@
+//! Serving a remote's refs from the last RepoData read, when the chain can't be reached.
+//!
+//! Every session that reads the remote's RepoData from the chain keeps a copy in the remote's
+//! `state_dir`, as `last-known-repodata` with the block it was read at and when in
+//! `last-known-repodata.meta`. With `offline = "auto"`, a helper that can't connect serves `list`
+//! from that copy, warning how old it is, and fetches the refs whose objects are in the local
+//! repository, the object cache or a reachable object store. With `offline = "force"` it doesn't
+//! try the chain at all. Pushes need the chain and are always refused offline.
+
+use crate::{
+    error,
+    format::{decode_artifact, Kind},
+    inspect::Artifact,
+    output,
+    primitives::{BoxResult, Config, MultiObjectIndex, RepoData},
+    remote_helper::{
+        advertised_refs, remote_config, state_dir, Backend, FetchReport, FetchRequest, PushOutcome,
+        PushRequest,
+    },
+    remote_url::RemoteUrl,
+    store::default_store,
+};
+use async_trait::async_trait;
+use git2::{Oid, Repository};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The `offline` config setting: when to serve the last known refs instead of the chain's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflineMode {
+    /// When connecting to the chain or reading the RepoData fails
+    Auto,
+    /// Never, a helper that can't connect fails
+    Never,
+    /// Always, without trying the chain
+    Force,
+}
+
+/// The RepoData a remote last had on chain, as kept in its `state_dir`.
+pub struct LastKnown {
+    pub repo_data: RepoData,
+    /// The block it was read at
+    pub block_number: u32,
+    /// When it was read, in seconds since the Unix epoch
+    pub saved_at: u64,
+}
+
+impl LastKnown {
+    /// Keep `repo_data`, read at `block_number`, unless the copy in `state_dir` is the same.
+    pub fn save(state_dir: &Path, repo_data: &RepoData, block_number: u32) -> BoxResult<()> {
+        let identity = repo_data.identity();
+        let meta_path = state_dir.join("last-known-repodata.meta");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let unchanged = fs::read_to_string(&meta_path).map_or(false, |meta| {
+            meta.split_whitespace().nth(2) == Some(&identity)
+        });
+
+        fs::create_dir_all(state_dir)?;
+        if !unchanged {
+            fs::write(
+                state_dir.join("last-known-repodata"),
+                repo_data.compressed().0,
+            )?;
+        }
+        // Still current as of this block, which is what the warning offline tells
+        fs::write(
+            &meta_path,
+            format!("{} {} {}\n", block_number, now, identity),
+        )?;
+
+        Ok(())
+    }
+
+    /// The copy kept in `state_dir`, if there is a readable one.
+    pub fn load(state_dir: &Path) -> Option<Self> {
+        let meta = fs::read_to_string(state_dir.join("last-known-repodata.meta")).ok()?;
+        let (block_number, saved_at) = match meta.split_whitespace().collect::<Vec<_>>()[..] {
+            [block_number, saved_at, _] => (block_number.parse().ok()?, saved_at.parse().ok()?),
+            _ => return None,
+        };
+
+        let bytes = fs::read(state_dir.join("last-known-repodata")).ok()?;
+        let repo_data = match decode_artifact(bytes, Some(Kind::RepoData)) {
+            Ok(Artifact::RepoData(repo_data)) => repo_data,
+            Ok(other) => {
+                debug!("last-known-repodata holds a {}", other.name());
+                return None;
+            }
+            Err(e) => {
+                debug!("Could not decode last-known-repodata: {}", e);
+                return None;
+            }
+        };
+
+        Some(Self {
+            repo_data,
+            block_number,
+            saved_at,
+        })
+    }
+
+    /// How long ago it was read, e.g. "2h 5m ago".
+    pub fn age(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let secs = now.saturating_sub(self.saved_at);
+
+        match secs {
+            0..=59 => "just now".to_owned(),
+            60..=3599 => format!("{}m ago", secs / 60),
+            3600..=86399 => format!("{}h {}m ago", secs / 3600, secs % 3600 / 60),
+            _ => format!("{}d {}h ago", secs / 86400, secs % 86400 / 3600),
+        }
+    }
+}
+
+/// Answers git's commands from a remote's last known RepoData, without the chain.
+pub struct Offline {
+    remote_name: String,
+    branch: Option<String>,
+    config: Config,
+    repo: Repository,
+    last_known: LastKnown,
+    /// Why the chain isn't used, shown with the warning
+    reason: String,
+    warned: bool,
+}
+
+impl Offline {
+    /// Serve `remote_name` from its last known RepoData, failing when there is none.
+    pub fn open(
+        remote_name: &str,
+        url: &RemoteUrl,
+        config: Config,
+        reason: String,
+    ) -> BoxResult<Self> {
+        let repo = Repository::open_from_env()?;
+        let (config, _) = remote_config(&repo, remote_name, config)?;
+
+        let last_known = match LastKnown::load(&state_dir(&repo, remote_name)) {
+            Some(last_known) => last_known,
+            None => error!(format!(
+                "Can't work offline: {} was never read from the chain in this repository",
+                remote_name
+            )),
+        };
+
+        Ok(Self {
+            remote_name: remote_name.to_owned(),
+            branch: url.branch.clone(),
+            config,
+            repo,
+            last_known,
+            reason,
+            warned: false,
+        })
+    }
+
+    /// Tell the user once that what they see may be out of date.
+    fn warn(&mut self) {
+        if self.warned {
+            return;
+        }
+        self.warned = true;
+
+        output::warn(format!(
+            "OFFLINE: showing refs of {} as of block {} ({}), as {}",
+            self.remote_name,
+            self.last_known.block_number,
+            self.last_known.age(),
+            self.reason
+        ));
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Offline {
+    async fn list(&mut self, for_push: bool) -> BoxResult<Vec<(String, String)>> {
+        if for_push {
+            error!(format!(
+                "Can't push to {} offline ({}); pushes need the chain",
+                self.remote_name, self.reason
+            ))
+        }
+
+        self.warn();
+        advertised_refs(
+            &self.last_known.repo_data,
+            self.branch.as_deref(),
+            &[],
+            false,
+        )
+    }
+
+    async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+        self.warn();
+
+        // Without the chain, MultiObjects are only found through the RepoData's CIDs
+        let index = MultiObjectIndex::Cids(BTreeMap::new());
+        let store = match default_store(&self.config).await {
+            Ok(store) => Some(store),
+            Err(e) => {
+                debug!("No object store offline: {}", e);
+                None
+            }
+        };
+
+        let mut reports = vec![];
+        for request in requests {
+            let result = match &store {
+                // Objects already in the repository are skipped, so this only downloads the rest
+                Some(store) => self
+                    .last_known
+                    .repo_data
+                    .fetch_to_ref_from_str(
+                        &request.sha,
+                        &request.name,
+                        &mut self.repo,
+                        store.as_ref(),
+                        &index,
+                        None,
+                        None,
+                        None,
+                        &self.config,
+                    )
+                    .await
+                    .map_err(|e| e.to_string()),
+                None if self.repo.odb()?.exists(Oid::from_str(&request.sha)?) => Ok(()),
+                None => Err("the object store is unreachable too".to_owned()),
+            };
+
+            let result = result.map_err(|e| {
+                let e = format!("{} isn't available offline: {}", request.name, e);
+                output::warn(&e);
+                e
+            });
+            reports.push(FetchReport {
+                name: request.name.clone(),
+                result,
+            });
+        }
+
+        Ok(reports)
+    }
+
+    async fn push_batch(&mut self, _requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        error!(format!(
+            "Can't push to {} offline ({}); pushes need the chain",
+            self.remote_name, self.reason
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn repo_data(tip: char) -> RepoData {
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), tip.to_string().repeat(40));
+        repo_data
+    }
+
+    #[test]
+    fn last_known_repo_data_round_trips_with_its_block() {
+        let dir = TempDir::new().unwrap();
+        assert!(LastKnown::load(dir.path()).is_none());
+
+        LastKnown::save(dir.path(), &repo_data('a'), 120).unwrap();
+        let last_known = LastKnown::load(dir.path()).unwrap();
+        assert_eq!(last_known.repo_data.refs, repo_data('a').refs);
+        assert_eq!(last_known.block_number, 120);
+        assert_eq!(last_known.age(), "just now");
+    }
+
+    #[test]
+    fn unchanged_repo_data_is_only_marked_current() {
+        let dir = TempDir::new().unwrap();
+        LastKnown::save(dir.path(), &repo_data('a'), 120).unwrap();
+        fs::write(dir.child("last-known-repodata"), b"mangled").unwrap();
+
+        // The copy isn't written again, so the mangled one stays and can't be loaded
+        LastKnown::save(dir.path(), &repo_data('a'), 130).unwrap();
+        assert!(LastKnown::load(dir.path()).is_none());
+        assert!(fs::read_to_string(dir.child("last-known-repodata.meta"))
+            .unwrap()
+            .starts_with("130 "));
+
+        LastKnown::save(dir.path(), &repo_data('b'), 140).unwrap();
+        let last_known = LastKnown::load(dir.path()).unwrap();
+        assert_eq!(last_known.repo_data.refs, repo_data('b').refs);
+        assert_eq!(last_known.block_number, 140);
+    }
+
+    #[test]
+    fn age_is_given_in_the_largest_units() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let age = |secs: u64| {
+            LastKnown {
+                repo_data: RepoData::default(),
+                block_number: 0,
+                saved_at: now - secs,
+            }
+            .age()
+        };
+
+        assert_eq!(age(5 * 60 + 10), "5m ago");
+        assert_eq!(age(2 * 3600 + 5 * 60), "2h 5m ago");
+        assert_eq!(age(3 * 86400 + 4 * 3600), "3d 4h ago");
+    }
+}
@@ -0,0 +1,226 @@
+//! User-facing status output on stderr.
+//!
+//! git renders its own progress on the same terminal, so on a TTY we keep a single status line
+//! that each update overwrites; otherwise every message becomes a timestamped line. Status
+//! updates coming faster than `STATUS_INTERVAL` are coalesced: only the latest is kept, and it
+//! is drawn by the next update after the interval or before any other output.
+
+use crate::redact;
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The least time between two status updates being drawn, so per-object progress doesn't drown
+/// out git's own.
+const STATUS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// git's default; `-q` lowers it to 0 and each `-v` raises it by one.
+static VERBOSITY: AtomicUsize = AtomicUsize::new(1);
+
+static STDERR: Mutex<Option<Renderer<io::Stderr>>> = Mutex::new(None);
+
+pub fn set_verbosity(level: usize) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> usize {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+pub fn stderr_is_tty() -> bool {
+    unsafe { libc::isatty(2) == 1 }
+}
+
+/// `HH:MM:SS` in UTC.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+        % 86400;
+
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Writes messages to `out`, which is a terminal when `tty` is set.
+struct Renderer<W> {
+    out: W,
+    tty: bool,
+    timestamp: fn() -> String,
+    /// When a status update was last drawn
+    drawn_at: Option<Instant>,
+    /// The latest status update, while the interval keeps it from being drawn
+    pending: Option<String>,
+    /// Whether a status line is displayed and must be cleared before other output
+    shown: bool,
+}
+
+// Output is best-effort: a failed write to stderr has nowhere left to be reported
+impl<W: Write> Renderer<W> {
+    fn new(out: W, tty: bool, timestamp: fn() -> String) -> Self {
+        Self {
+            out,
+            tty,
+            timestamp,
+            drawn_at: None,
+            pending: None,
+            shown: false,
+        }
+    }
+
+    fn status(&mut self, message: String, now: Instant) {
+        let recent = self.drawn_at.map_or(false, |drawn_at| {
+            now.duration_since(drawn_at) < STATUS_INTERVAL
+        });
+        if recent {
+            self.pending = Some(message);
+            return;
+        }
+
+        self.pending = None;
+        self.draw_status(&message);
+        self.drawn_at = Some(now);
+    }
+
+    fn draw_status(&mut self, message: &str) {
+        if self.tty {
+            let _ = write!(self.out, "\r{}\x1b[K", message);
+            self.shown = true;
+        } else {
+            let _ = writeln!(self.out, "[{}] {}", (self.timestamp)(), message);
+        }
+        let _ = self.out.flush();
+    }
+
+    /// Draw the status update the interval held back, if any.
+    fn flush_status(&mut self) {
+        if let Some(message) = self.pending.take() {
+            self.draw_status(&message);
+        }
+    }
+
+    fn clear_status(&mut self) {
+        self.flush_status();
+        if mem::take(&mut self.shown) {
+            let _ = write!(self.out, "\r\x1b[K");
+        }
+    }
+
+    fn line(&mut self, message: &str) {
+        if self.tty {
+            self.clear_status();
+            let _ = writeln!(self.out, "{}", message);
+        } else {
+            self.flush_status();
+            let _ = writeln!(self.out, "[{}] {}", (self.timestamp)(), message);
+        }
+    }
+}
+
+fn with_stderr(f: impl FnOnce(&mut Renderer<io::Stderr>)) {
+    let mut stderr = STDERR.lock().unwrap_or_else(|e| e.into_inner());
+    f(stderr.get_or_insert_with(|| Renderer::new(io::stderr(), stderr_is_tty(), timestamp)));
+}
+
+/// Remove the status line so git's own output starts on a clean line.
+pub fn clear_status() {
+    with_stderr(Renderer::clear_status);
+}
+
+fn line(message: impl Display) {
+    let message = redact::text(&message.to_string());
+    with_stderr(|stderr| stderr.line(&message));
+}
+
+/// Progress that is only interesting until the next update.
+pub fn status(message: impl Display) {
+    if verbosity() == 0 {
+        return;
+    }
+    let message = redact::text(&message.to_string());
+
+    with_stderr(|stderr| stderr.status(message, Instant::now()));
+}
+
+/// A message that stays visible, such as the outcome of a step.
+pub fn info(message: impl Display) {
+    if verbosity() > 0 {
+        line(message);
+    }
+}
+
+/// Shown even with `--quiet`.
+pub fn warn(message: impl Display) {
+    line(format!("warning: {}", message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renderer(tty: bool) -> Renderer<Vec<u8>> {
+        Renderer::new(vec![], tty, || "12:00:00".to_owned())
+    }
+
+    fn rendered(renderer: Renderer<Vec<u8>>) -> String {
+        String::from_utf8(renderer.out).unwrap()
+    }
+
+    #[test]
+    fn push_off_a_terminal_is_timestamped_lines_without_the_coalesced_updates() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut output = renderer(false);
+
+        output.status("Uploading 3 objects (1.5 KiB)...".to_owned(), at(0));
+        output.status("Uploading blob 1111111".to_owned(), at(150));
+        output.status("Uploading blob 2222222".to_owned(), at(160));
+        output.status("Uploading blob 3333333".to_owned(), at(170));
+        output.line("Uploaded 3 objects");
+        output.status("Minting object pack IPF...".to_owned(), at(300));
+        output.line("Repository index is 2.0 KiB");
+        output.status("Updating on-chain repository index...".to_owned(), at(350));
+        output.clear_status();
+
+        assert_eq!(
+            rendered(output),
+            "[12:00:00] Uploading 3 objects (1.5 KiB)...\n\
+             [12:00:00] Uploading blob 1111111\n\
+             [12:00:00] Uploading blob 3333333\n\
+             [12:00:00] Uploaded 3 objects\n\
+             [12:00:00] Minting object pack IPF...\n\
+             [12:00:00] Repository index is 2.0 KiB\n\
+             [12:00:00] Updating on-chain repository index...\n"
+        );
+    }
+
+    #[test]
+    fn status_on_a_terminal_is_one_line_redrawn_at_most_every_interval() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut output = renderer(true);
+
+        output.status("Uploading blob 1111111".to_owned(), at(0));
+        output.status("Uploading blob 2222222".to_owned(), at(10));
+        output.status("Uploading blob 3333333".to_owned(), at(20));
+        output.line("Uploaded 3 objects");
+        output.status("Minting object pack IPF...".to_owned(), at(200));
+        output.clear_status();
+        output.clear_status();
+
+        assert_eq!(
+            rendered(output),
+            "\rUploading blob 1111111\x1b[K\
+             \rUploading blob 3333333\x1b[K\r\x1b[K\
+             Uploaded 3 objects\n\
+             \rMinting object pack IPF...\x1b[K\r\x1b[K"
+        );
+    }
+}
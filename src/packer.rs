@@ -0,0 +1,257 @@
+//! How the objects of a push are split into MultiObjects, each minted as its own IPF.
+//!
+//! Fetches download whole MultiObjects, so the packing decides what a fetch of a single ref has
+//! to download along with it.
+
+use crate::primitives::{BoxResult, Config};
+use git2::{ObjectType, Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    mem,
+};
+
+/// The `packing` config setting.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Packing {
+    /// One MultiObject per push
+    Single,
+    /// MultiObjects following the commits that introduced their objects
+    Commits,
+}
+
+pub trait Packer {
+    /// Split `oids` into packs, placing every oid in exactly one of them.
+    fn pack(&self, oids: &HashSet<Oid>, repo: &Repository) -> BoxResult<Vec<Vec<Oid>>>;
+}
+
+/// The packer selected in `config`.
+pub fn from_config(config: &Config) -> Box<dyn Packer> {
+    match config.packing {
+        Packing::Single => Box::new(SinglePacker),
+        Packing::Commits => Box::new(CommitPacker {
+            target_size: config.pack_target_size,
+            max_size: config.pack_max_size,
+        }),
+    }
+}
+
+/// Everything a push uploads in a single MultiObject.
+pub struct SinglePacker;
+
+impl Packer for SinglePacker {
+    fn pack(&self, oids: &HashSet<Oid>, _repo: &Repository) -> BoxResult<Vec<Vec<Oid>>> {
+        let mut pack: Vec<_> = oids.iter().copied().collect();
+        pack.sort();
+
+        Ok(vec![pack])
+    }
+}
+
+/// Groups each new commit with the trees and blobs it introduced, oldest commit first.
+///
+/// Adjacent groups are merged while they fit in `target_size` bytes, and packs are cut at
+/// `max_size` bytes, so a fetch only downloads packs around the history it needs.
+pub struct CommitPacker {
+    pub target_size: u64,
+    pub max_size: u64,
+}
+
+impl Packer for CommitPacker {
+    fn pack(&self, oids: &HashSet<Oid>, repo: &Repository) -> BoxResult<Vec<Vec<Oid>>> {
+        let odb = repo.odb()?;
+        let mut headers = HashMap::new();
+
+        for &oid in oids {
+            let (size, kind) = odb.read_header(oid)?;
+            headers.insert(oid, (size as u64, kind));
+        }
+
+        let mut remaining: BTreeSet<Oid> = oids.iter().copied().collect();
+        let mut groups = vec![];
+
+        for commit_id in topological_commits(&headers, repo)? {
+            remaining.remove(&commit_id);
+
+            let mut group = vec![commit_id];
+            let mut trees = vec![repo.find_commit(commit_id)?.tree_id()];
+
+            // Trees that aren't new, or were claimed by an earlier commit, hold nothing new
+            while let Some(tree_id) = trees.pop() {
+                if !remaining.remove(&tree_id) {
+                    continue;
+                }
+                group.push(tree_id);
+
+                for entry in repo.find_tree(tree_id)?.iter() {
+                    match entry.kind() {
+                        Some(ObjectType::Tree) => trees.push(entry.id()),
+                        Some(ObjectType::Blob) if remaining.remove(&entry.id()) => {
+                            group.push(entry.id())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            groups.push(group);
+        }
+
+        // Tags, and objects pushed without a new commit above them
+        if !remaining.is_empty() {
+            groups.push(remaining.into_iter().collect());
+        }
+
+        let mut packs = vec![];
+        let mut pack = vec![];
+        let mut pack_size = 0;
+
+        for group in groups {
+            let group_size: u64 = group.iter().map(|oid| headers[oid].0).sum();
+
+            if !pack.is_empty() && pack_size + group_size > self.target_size {
+                packs.push(mem::take(&mut pack));
+                pack_size = 0;
+            }
+
+            for oid in group {
+                let size = headers[&oid].0;
+
+                if !pack.is_empty() && pack_size + size > self.max_size {
+                    packs.push(mem::take(&mut pack));
+                    pack_size = 0;
+                }
+
+                pack.push(oid);
+                pack_size += size;
+            }
+        }
+
+        if !pack.is_empty() {
+            packs.push(pack);
+        }
+
+        Ok(packs)
+    }
+}
+
+/// The commits among `headers`, parents before children.
+fn topological_commits(
+    headers: &HashMap<Oid, (u64, ObjectType)>,
+    repo: &Repository,
+) -> BoxResult<Vec<Oid>> {
+    let commits: BTreeSet<Oid> = headers
+        .iter()
+        .filter(|(_, (_, kind))| *kind == ObjectType::Commit)
+        .map(|(oid, _)| *oid)
+        .collect();
+
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+
+    for &start in &commits {
+        // `true` marks a commit whose parents have all been placed
+        let mut stack = vec![(start, false)];
+
+        while let Some((commit_id, placed_parents)) = stack.pop() {
+            if placed_parents {
+                order.push(commit_id);
+                continue;
+            }
+
+            if !visited.insert(commit_id) {
+                continue;
+            }
+
+            stack.push((commit_id, true));
+
+            for parent_id in repo.find_commit(commit_id)?.parent_ids() {
+                if commits.contains(&parent_id) && !visited.contains(&parent_id) {
+                    stack.push((parent_id, false));
+                }
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::{commit_tree, repository};
+
+    /// A tree holding a single blob with `contents`, and that blob.
+    fn tree_with(repo: &Repository, contents: &[u8]) -> (Oid, Oid) {
+        let blob = repo.blob(contents).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+
+        (builder.write().unwrap(), blob)
+    }
+
+    #[test]
+    fn commits_are_packed_oldest_first_with_what_they_introduced() {
+        let (_dir, repo) = repository();
+        let (first_tree, first_blob) = tree_with(&repo, b"first");
+        let first = commit_tree(&repo, None, &[], first_tree, "first");
+        let (second_tree, second_blob) = tree_with(&repo, b"second");
+        let second = commit_tree(&repo, None, &[first], second_tree, "second");
+        let oids = HashSet::from([
+            first,
+            first_tree,
+            first_blob,
+            second,
+            second_tree,
+            second_blob,
+        ]);
+
+        let packer = CommitPacker {
+            target_size: 0,
+            max_size: u64::MAX,
+        };
+        let packs = packer.pack(&oids, &repo).unwrap();
+
+        assert_eq!(
+            packs,
+            vec![
+                vec![first, first_tree, first_blob],
+                vec![second, second_tree, second_blob],
+            ]
+        );
+
+        let packer = CommitPacker {
+            target_size: u64::MAX,
+            max_size: u64::MAX,
+        };
+        assert_eq!(packer.pack(&oids, &repo).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn packs_are_cut_at_the_max_size() {
+        let (_dir, repo) = repository();
+        let (tree, blob) = tree_with(&repo, b"a blob of some size");
+        let commit = commit_tree(&repo, None, &[], tree, "only");
+        let oids = HashSet::from([commit, tree, blob]);
+
+        let packer = CommitPacker {
+            target_size: u64::MAX,
+            max_size: 1,
+        };
+        let packs = packer.pack(&oids, &repo).unwrap();
+
+        assert_eq!(packs, vec![vec![commit], vec![tree], vec![blob]]);
+    }
+
+    #[test]
+    fn single_packer_keeps_everything_together() {
+        let (_dir, repo) = repository();
+        let oids = HashSet::from([repo.blob(b"a").unwrap(), repo.blob(b"b").unwrap()]);
+
+        let packs = SinglePacker.pack(&oids, &repo).unwrap();
+
+        assert_eq!(packs.len(), 1);
+        assert_eq!(packs[0].len(), 2);
+    }
+}
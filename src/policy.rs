@@ -0,0 +1,393 @@
+//! Repository policies checked before a push uploads anything.
+//!
+//! There is no server to run hooks on, so the pushing helper is the only place to enforce them.
+//! The built-ins cover signed commits, protected refs and a commit message pattern. Anything
+//! else can be checked by `pre_push_hook`, run like git's pre-push hook.
+
+use crate::{
+    error,
+    primitives::{BoxResult, Config},
+    util::is_fast_forward,
+};
+use git2::{ErrorCode, Oid, Repository, Sort};
+use log::debug;
+use regex::Regex;
+use std::{fmt::Write as _, path::PathBuf, process::Stdio};
+use temp_dir::TempDir;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// A ref a push is about to change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The local ref or expression pushed, empty for a deletion
+    pub src: String,
+    pub dst: String,
+    /// The remote's tip, `None` for a new ref
+    pub old: Option<Oid>,
+    /// The pushed tip, `None` for a deletion
+    pub new: Option<Oid>,
+    /// Commits the push adds to the remote, newest first
+    pub commits: Vec<Oid>,
+}
+
+impl RefUpdate {
+    /// A line of git's pre-push hook input: `<local ref> <local sha1> <remote ref> <remote sha1>`.
+    fn hook_line(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            match self.new {
+                Some(_) => self.src.as_str(),
+                None => "(delete)",
+            },
+            self.new.unwrap_or_else(Oid::zero),
+            self.dst,
+            self.old.unwrap_or_else(Oid::zero)
+        )
+    }
+}
+
+pub struct Policy {
+    require_signed_commits: bool,
+    protected_refs: Vec<String>,
+    commit_message: Option<Regex>,
+    hook: Option<PathBuf>,
+}
+
+impl Policy {
+    pub fn from_config(config: &Config) -> BoxResult<Self> {
+        let commit_message = match &config.commit_message_pattern {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid commit_message_pattern: {}", e))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            require_signed_commits: config.require_signed_commits,
+            protected_refs: config.protected_refs.clone(),
+            commit_message,
+            hook: config.pre_push_hook.clone(),
+        })
+    }
+
+    /// Whether `name` is protected: listed, or under a listed prefix ending in `*`.
+    pub fn is_protected(&self, name: &str) -> bool {
+        self.protected_refs
+            .iter()
+            .any(|protected| match protected.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == protected,
+            })
+    }
+
+    /// Check `update` against the built-in policies.
+    pub fn check(&self, repo: &Repository, update: &RefUpdate) -> BoxResult<()> {
+        if self.is_protected(&update.dst) {
+            match (update.old, update.new) {
+                (Some(_), None) => {
+                    error!(format!("{} is protected and can't be deleted", update.dst))
+                }
+                (Some(old), Some(new)) => {
+                    let fast_forward =
+                        repo.odb()?.read_header(old).is_ok() && is_fast_forward(repo, old, new)?;
+
+                    if !fast_forward {
+                        error!(format!(
+                            "{} is protected and can't be force-pushed",
+                            update.dst
+                        ))
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for &oid in &update.commits {
+            if self.require_signed_commits {
+                match repo.extract_signature(&oid, None) {
+                    Ok(_) => {}
+                    Err(e) if e.code() == ErrorCode::NotFound => error!(format!(
+                        "Commit {} is not signed, and this remote requires signed commits",
+                        oid
+                    )),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if let Some(pattern) = &self.commit_message {
+                let commit = repo.find_commit(oid)?;
+                let message = commit.message().unwrap_or_default();
+
+                if !pattern.is_match(message) {
+                    error!(format!(
+                        "The message of commit {} ({:?}) doesn't match commit_message_pattern {}",
+                        oid,
+                        commit.summary().unwrap_or_default(),
+                        pattern
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `pre_push_hook`, if set, on `updates`, failing when it exits non-zero.
+    ///
+    /// Like git's pre-push hook, it is run in the repository with the remote's name and URL as
+    /// arguments and a line per ref on stdin. `GIT_INV4_NEW_COMMITS` names a file listing the
+    /// commits each ref adds, as `<remote ref> <sha1>` lines.
+    pub async fn run_hook(
+        &self,
+        repo: &Repository,
+        remote_name: &str,
+        url: &str,
+        updates: &[RefUpdate],
+    ) -> BoxResult<()> {
+        let hook = match &self.hook {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+
+        let mut input = String::new();
+        let mut commits = String::new();
+        for update in updates {
+            writeln!(input, "{}", update.hook_line())?;
+            for oid in &update.commits {
+                writeln!(commits, "{} {}", update.dst, oid)?;
+            }
+        }
+
+        let staging = TempDir::new()?;
+        let commits_path = staging.child("new-commits");
+        std::fs::write(&commits_path, commits)?;
+
+        debug!("Running pre_push_hook {}", hook.display());
+        let mut child = Command::new(hook)
+            .arg(remote_name)
+            .arg(url)
+            .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+            .env("GIT_INV4_NEW_COMMITS", &commits_path)
+            .stdin(Stdio::piped())
+            // stdout is git's protocol stream, so the hook's output is passed on to stderr
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Could not run pre_push_hook {}: {}", hook.display(), e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook that doesn't read its input closes the pipe early, which is fine
+            if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                debug!("pre_push_hook didn't read all of its input: {}", e);
+            }
+        }
+
+        let output = child.wait_with_output().await?;
+        eprint!("{}", String::from_utf8_lossy(&output.stdout));
+
+        if !output.status.success() {
+            error!(format!(
+                "pre_push_hook {} rejected the push ({})",
+                hook.display(),
+                output.status
+            ))
+        }
+
+        Ok(())
+    }
+}
+
+/// The commits reachable from `new` but not from `old` or `hide`, newest first.
+pub fn new_commits(
+    repo: &Repository,
+    old: Option<Oid>,
+    new: Oid,
+    hide: &[Oid],
+) -> BoxResult<Vec<Oid>> {
+    let new = match repo.find_object(new, None)?.peel_to_commit() {
+        Ok(commit) => commit.id(),
+        // Trees and blobs pushed on their own bring no commits
+        Err(_) => return Ok(vec![]),
+    };
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL)?;
+    walk.push(new)?;
+    for &tip in old.iter().chain(hide) {
+        // Tips that aren't local commits can't be hidden, and aren't in the history anyway
+        let _ = walk.hide(tip);
+    }
+
+    Ok(walk.collect::<Result<_, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::{commit, repository};
+
+    fn policy(protected_refs: &[&str]) -> Policy {
+        Policy {
+            require_signed_commits: false,
+            protected_refs: protected_refs.iter().map(|&name| name.to_owned()).collect(),
+            commit_message: None,
+            hook: None,
+        }
+    }
+
+    fn update(old: Option<Oid>, new: Option<Oid>, commits: &[Oid]) -> RefUpdate {
+        RefUpdate {
+            src: "refs/heads/main".to_owned(),
+            dst: "refs/heads/main".to_owned(),
+            old,
+            new,
+            commits: commits.to_vec(),
+        }
+    }
+
+    #[test]
+    fn protected_refs_match_by_name_or_prefix() {
+        let policy = policy(&["refs/heads/main", "refs/tags/*"]);
+
+        assert!(policy.is_protected("refs/heads/main"));
+        assert!(policy.is_protected("refs/tags/v1"));
+        assert!(!policy.is_protected("refs/heads/main-old"));
+        assert!(!policy.is_protected("refs/heads/topic"));
+    }
+
+    #[test]
+    fn protected_refs_only_move_forward() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let ahead = commit(&repo, None, &[base], "ahead");
+        let diverged = commit(&repo, None, &[base], "diverged");
+        let protected = policy(&["refs/heads/main"]);
+
+        assert!(protected
+            .check(&repo, &update(None, Some(base), &[base]))
+            .is_ok());
+        assert!(protected
+            .check(&repo, &update(Some(base), Some(ahead), &[ahead]))
+            .is_ok());
+
+        let e = protected
+            .check(&repo, &update(Some(ahead), Some(diverged), &[diverged]))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "refs/heads/main is protected and can't be force-pushed"
+        );
+        let e = protected
+            .check(&repo, &update(Some(ahead), None, &[]))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "refs/heads/main is protected and can't be deleted"
+        );
+
+        // Anything goes elsewhere
+        assert!(policy(&[])
+            .check(&repo, &update(Some(ahead), Some(diverged), &[diverged]))
+            .is_ok());
+    }
+
+    #[test]
+    fn new_commits_are_checked_for_signatures_and_messages() {
+        let (_dir, repo) = repository();
+        let good = commit(&repo, None, &[], "feat: something");
+        let bad = commit(&repo, None, &[good], "wip");
+
+        let mut policy = policy(&[]);
+        policy.commit_message = Some(Regex::new("^(feat|fix): ").unwrap());
+        assert!(policy
+            .check(&repo, &update(None, Some(good), &[good]))
+            .is_ok());
+        let e = policy
+            .check(&repo, &update(Some(good), Some(bad), &[bad]))
+            .unwrap_err();
+        assert!(e.to_string().contains("(\"wip\") doesn't match"));
+
+        policy.commit_message = None;
+        policy.require_signed_commits = true;
+        let e = policy
+            .check(&repo, &update(None, Some(good), &[good]))
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .ends_with("is not signed, and this remote requires signed commits"));
+    }
+
+    #[test]
+    fn new_commits_leave_out_what_the_remote_has() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let old = commit(&repo, None, &[base], "old");
+        let new = commit(&repo, None, &[old], "new");
+        let known = commit(&repo, None, &[], "known");
+        let merge = commit(&repo, None, &[new, known], "merge");
+
+        assert_eq!(new_commits(&repo, Some(old), new, &[]).unwrap(), [new]);
+        assert_eq!(new_commits(&repo, None, old, &[]).unwrap(), [old, base]);
+        assert_eq!(
+            new_commits(&repo, Some(old), merge, &[known]).unwrap(),
+            [merge, new]
+        );
+        // Tips the repository doesn't have are skipped
+        assert_eq!(
+            new_commits(&repo, Some(Oid::zero()), old, &[]).unwrap(),
+            [old, base]
+        );
+    }
+
+    #[test]
+    fn hook_lines_are_like_gits() {
+        let (old, new) = (
+            Oid::from_bytes(&[1; 20]).unwrap(),
+            Oid::from_bytes(&[2; 20]).unwrap(),
+        );
+
+        assert_eq!(
+            update(Some(old), Some(new), &[]).hook_line(),
+            format!("refs/heads/main {} refs/heads/main {}", new, old)
+        );
+        assert_eq!(
+            update(Some(old), None, &[]).hook_line(),
+            format!("(delete) {} refs/heads/main {}", Oid::zero(), old)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hook_gets_the_refs_and_can_refuse_them() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, repo) = repository();
+        let tip = commit(&repo, None, &[], "tip");
+        let updates = [update(None, Some(tip), &[tip])];
+
+        let hook = dir.path().join("hook");
+        let script = format!(
+            "test \"$1 $2\" = \"origin inv4://1\" && grep -q \"^refs/heads/main {}\" \"$GIT_INV4_NEW_COMMITS\" && read line && test \"$line\" = \"{}\"",
+            tip,
+            updates[0].hook_line()
+        );
+        std::fs::write(&hook, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut policy = policy(&[]);
+        policy.hook = Some(hook);
+        policy
+            .run_hook(&repo, "origin", "inv4://1", &updates)
+            .await
+            .unwrap();
+
+        policy.hook = Some(PathBuf::from("false"));
+        let e = policy
+            .run_hook(&repo, "origin", "inv4://1", &updates)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("rejected the push"));
+    }
+}
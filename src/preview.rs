@@ -0,0 +1,315 @@
+//! What a push is about to publish, shown before anything is uploaded.
+
+use crate::{output, primitives::BoxResult, util::human_size};
+use git2::{Delta, Diff, Oid, Pathspec, PathspecFlags, Repository, Sort, Tree};
+use std::path::Path;
+
+/// How many commits and files are listed before the rest is only counted.
+const LISTED: usize = 10;
+
+pub struct FileChange {
+    pub path: String,
+    /// `A`, `M`, `D`, `R` etc. as in `git diff --name-status`
+    pub status: char,
+    /// Size of the new blob, zero for deletions
+    pub size: usize,
+}
+
+/// A file matching a `publish_guard_patterns` entry in one of the pushed commits.
+pub struct SensitiveFile {
+    pub path: String,
+    pub pattern: String,
+    pub commit: Oid,
+}
+
+#[derive(Default)]
+pub struct Preview {
+    /// Short ids and subjects of the commits to upload, newest first
+    pub commits: Vec<(String, String)>,
+    /// Changes between the remote's tip and the pushed one
+    pub files: Vec<FileChange>,
+    pub sensitive: Vec<SensitiveFile>,
+}
+
+impl Preview {
+    /// Compare the pushed `new` with the remote's `old` tip, using local data only.
+    ///
+    /// The commits listed are those reachable from `new` but not from `old` or `hide`, and not
+    /// `uploaded` already. Every one of them is checked against `patterns`, so a file added and
+    /// removed again before the push is still caught. Without an `old` commit present locally,
+    /// the files are compared with an empty tree.
+    pub fn new(
+        repo: &Repository,
+        old: Option<Oid>,
+        new: Oid,
+        hide: &[Oid],
+        uploaded: impl Fn(Oid) -> bool,
+        patterns: &[String],
+    ) -> BoxResult<Self> {
+        let new_commit = match repo.find_object(new, None)?.peel_to_commit() {
+            Ok(commit) => commit,
+            // Trees and blobs pushed on their own have no history to describe
+            Err(_) => return Ok(Self::default()),
+        };
+        let old_commit = old.and_then(|old| repo.find_commit(old).ok());
+
+        let mut preview = Self {
+            files: file_changes(
+                repo,
+                &diff(
+                    repo,
+                    old_commit.map(|commit| commit.tree()).transpose()?,
+                    &new_commit.tree()?,
+                )?,
+            )?,
+            ..Default::default()
+        };
+
+        let guard = Pathspec::new(patterns.iter())?;
+
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL)?;
+        walk.push(new_commit.id())?;
+        for &tip in old.iter().chain(hide) {
+            // Tips that aren't local commits can't be hidden, and aren't in the history anyway
+            let _ = walk.hide(tip);
+        }
+
+        for oid in walk {
+            let oid = oid?;
+            if uploaded(oid) {
+                continue;
+            }
+
+            let commit = repo.find_commit(oid)?;
+            preview.commits.push((
+                commit
+                    .as_object()
+                    .short_id()?
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+                commit.summary().unwrap_or_default().to_owned(),
+            ));
+
+            if patterns.is_empty() {
+                continue;
+            }
+
+            let parent_tree = commit
+                .parent(0)
+                .ok()
+                .map(|parent| parent.tree())
+                .transpose()?;
+            for delta in diff(repo, parent_tree, &commit.tree()?)?.deltas() {
+                if delta.status() == Delta::Deleted {
+                    continue;
+                }
+
+                let path = match delta.new_file().path() {
+                    Some(path) => path,
+                    None => continue,
+                };
+
+                if let Some(pattern) = matching_pattern(&guard, patterns, path) {
+                    let path = path.to_string_lossy().into_owned();
+                    if !preview.sensitive.iter().any(|file| file.path == path) {
+                        preview.sensitive.push(SensitiveFile {
+                            path,
+                            pattern,
+                            commit: oid,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(preview)
+    }
+
+    /// Print the summary for a push to `dst`.
+    pub fn show(&self, dst: &str) {
+        let (added, modified, deleted) =
+            self.files
+                .iter()
+                .fold((0, 0, 0), |(a, m, d), file| match file.status {
+                    'A' => (a + 1, m, d),
+                    'D' => (a, m, d + 1),
+                    _ => (a, m + 1, d),
+                });
+
+        output::info(format!(
+            "About to publish {}: {} new commit(s), {} file(s) changed ({} added, {} modified, {} deleted)",
+            dst,
+            self.commits.len(),
+            self.files.len(),
+            added,
+            modified,
+            deleted
+        ));
+
+        for (id, subject) in self.commits.iter().take(LISTED) {
+            output::info(format!("    {} {}", id, subject));
+        }
+        if self.commits.len() > LISTED {
+            output::info(format!(
+                "    ... and {} more commits",
+                self.commits.len() - LISTED
+            ));
+        }
+
+        for file in self.files.iter().take(LISTED) {
+            match file.status {
+                'D' => output::info(format!("    D {}", file.path)),
+                status => output::info(format!(
+                    "    {} {} ({})",
+                    status,
+                    file.path,
+                    human_size(file.size)
+                )),
+            }
+        }
+        if self.files.len() > LISTED {
+            output::info(format!(
+                "    ... and {} more files",
+                self.files.len() - LISTED
+            ));
+        }
+
+        for file in &self.sensitive {
+            output::warn(format!(
+                "{} matches the publish guard pattern {:?} (commit {})",
+                file.path, file.pattern, file.commit
+            ));
+        }
+    }
+}
+
+fn diff<'a>(repo: &'a Repository, old: Option<Tree>, new: &Tree) -> BoxResult<Diff<'a>> {
+    let mut diff = repo.diff_tree_to_tree(old.as_ref(), Some(new), None)?;
+    diff.find_similar(None)?;
+
+    Ok(diff)
+}
+
+fn file_changes(repo: &Repository, diff: &Diff) -> BoxResult<Vec<FileChange>> {
+    let odb = repo.odb()?;
+
+    diff.deltas()
+        .map(|delta| {
+            let file = match delta.status() {
+                Delta::Deleted => delta.old_file(),
+                _ => delta.new_file(),
+            };
+
+            Ok(FileChange {
+                path: file
+                    .path()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                status: match delta.status() {
+                    Delta::Added => 'A',
+                    Delta::Deleted => 'D',
+                    Delta::Renamed => 'R',
+                    Delta::Copied => 'C',
+                    Delta::Typechange => 'T',
+                    _ => 'M',
+                },
+                size: match delta.status() {
+                    Delta::Deleted => 0,
+                    // Submodule commits aren't in this repository's odb
+                    _ => odb.read_header(file.id()).map_or(0, |(size, _)| size),
+                },
+            })
+        })
+        .collect()
+}
+
+/// The pattern `path` matches, checked against the whole path and against its file name, so
+/// `id_rsa` also catches `keys/id_rsa`.
+fn matching_pattern(guard: &Pathspec, patterns: &[String], path: &Path) -> Option<String> {
+    let candidates = [Some(path), path.file_name().map(Path::new)];
+
+    if !candidates
+        .iter()
+        .flatten()
+        .any(|candidate| guard.matches_path(candidate, PathspecFlags::DEFAULT))
+    {
+        return None;
+    }
+
+    // A Pathspec doesn't say which of its patterns matched
+    patterns.iter().find_map(|pattern| {
+        let single = Pathspec::new([pattern].iter()).ok()?;
+        candidates
+            .iter()
+            .flatten()
+            .any(|candidate| single.matches_path(candidate, PathspecFlags::DEFAULT))
+            .then(|| pattern.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::{commit_tree, repository};
+
+    /// A tree with a README holding `readme`, and a `keys/id_rsa` if `key` is set.
+    fn tree(repo: &Repository, readme: &[u8], key: bool) -> Oid {
+        let mut root = repo.treebuilder(None).unwrap();
+        root.insert("README", repo.blob(readme).unwrap(), 0o100644)
+            .unwrap();
+
+        if key {
+            let mut keys = repo.treebuilder(None).unwrap();
+            keys.insert("id_rsa", repo.blob(b"secret").unwrap(), 0o100644)
+                .unwrap();
+            root.insert("keys", keys.write().unwrap(), 0o040000)
+                .unwrap();
+        }
+
+        root.write().unwrap()
+    }
+
+    #[test]
+    fn files_added_and_removed_before_the_push_are_still_guarded() {
+        let (_dir, repo) = repository();
+        let old = commit_tree(&repo, None, &[], tree(&repo, b"v1", false), "old");
+        let leak = commit_tree(&repo, None, &[old], tree(&repo, b"v1", true), "leak");
+        let new = commit_tree(&repo, None, &[leak], tree(&repo, b"v2", false), "fix");
+
+        let preview = Preview::new(
+            &repo,
+            Some(old),
+            new,
+            &[],
+            |_| false,
+            &["id_rsa".to_owned()],
+        )
+        .unwrap();
+
+        let subjects: Vec<_> = preview.commits.iter().map(|(_, s)| s.as_str()).collect();
+        assert_eq!(subjects, ["fix", "leak"]);
+        assert_eq!(preview.files.len(), 1);
+        assert_eq!(
+            (preview.files[0].path.as_str(), preview.files[0].status),
+            ("README", 'M')
+        );
+        assert_eq!(preview.files[0].size, 2);
+        assert_eq!(preview.sensitive.len(), 1);
+        assert_eq!(preview.sensitive[0].path, "keys/id_rsa");
+        assert_eq!(preview.sensitive[0].commit, leak);
+    }
+
+    #[test]
+    fn uploaded_commits_are_not_listed() {
+        let (_dir, repo) = repository();
+        let first = commit_tree(&repo, None, &[], tree(&repo, b"v1", false), "first");
+        let second = commit_tree(&repo, None, &[first], tree(&repo, b"v2", false), "second");
+
+        let preview = Preview::new(&repo, None, second, &[], |oid| oid == first, &[]).unwrap();
+
+        assert_eq!(preview.commits.len(), 1);
+        assert_eq!(preview.files[0].status, 'A');
+    }
+}
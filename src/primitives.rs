@@ -1,98 +1,701 @@
 use crate::{
-    compression::{compress_data, decompress_data},
-    error,
-    tinkernet::{self, runtime_types::pallet_inv4::pallet::AnyId},
-    util::generate_cid,
+    archive::parse_tree,
+    auth::AuthCache,
+    chain::{Chain, ChainAuth, Confirmation, Snapshot},
+    compression::{compress_data, try_decompress_data},
+    config, error,
+    events::{self, Event},
+    filter::{self, BlobFilter},
+    format::{decode_artifact, encode_artifact, Format, Kind, Layout, ENVELOPE_LEN},
+    inspect::Artifact,
+    journal::PushJournal,
+    large_blob::{self, LargeBlob},
+    offline::OfflineMode,
+    output,
+    packer::{Packer, Packing},
+    quarantine::{Quarantine, QuarantinedObject},
+    repair::{self, Refetch},
+    shutdown::{self, Cancelled},
+    store::ObjectStore,
+    throttle,
+    traversal::{self, Limits, Walk},
+    util::{
+        cid_digest, generate_cid, human_size, is_fast_forward, reachable_from_any,
+        resolve_push_source, shallow_boundary,
+    },
 };
 use cid::Cid;
-use codec::{Decode, Encode};
-use futures::TryStreamExt;
-use git2::{Blob, Commit, Object, ObjectType, Odb, Oid, Repository, Tag, Tree};
-use ipfs_api::{IpfsApi, IpfsClient};
+use codec::{Decode, Encode, Input, Output};
+use git2::{FileMode, Object, ObjectType, Odb, Oid, Repository};
 use log::debug;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, BTreeSet, HashSet},
     error::Error,
+    fmt,
+    fs::File,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 use subxt::{
-    ext::sp_core::{sr25519::Pair, H256},
+    ext::sp_core::{blake2_256, sr25519::Pair, H256},
     tx::PairSigner,
-    OnlineClient, PolkadotConfig,
+    PolkadotConfig,
 };
-use twox_hash::xxh3;
+use temp_dir::TempDir;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     pub chain_endpoint: String,
+    /// Header the endpoint requires, e.g. an API key, with its value from the environment
+    pub chain_auth: Option<ChainAuth>,
+    /// Warn when a push's compressed RepoData is larger than this many bytes
+    pub repodata_warn_size: usize,
+    /// Refuse to push when the compressed RepoData would be larger than this many bytes
+    pub max_repodata_size: Option<usize>,
+    /// Ask before a force push drops commits from a remote ref
+    pub force_push_confirmation: bool,
+    /// IPFS API to use, e.g. `http://127.0.0.1:5001`; when unset a running node is looked for
+    pub ipfs_endpoint: Option<String>,
+    /// Start `ipfs daemon` when no running node is found
+    pub auto_spawn_ipfs: bool,
+    /// Leave a daemon started by `auto_spawn_ipfs` running after exiting
+    pub keep_daemon: bool,
+    /// How a push splits its objects into MultiObjects
+    pub packing: Packing,
+    /// With `Packing::Commits`, merge adjacent commits' objects up to this many bytes
+    pub pack_target_size: u64,
+    /// With `Packing::Commits`, start a new MultiObject past this many bytes
+    pub pack_max_size: u64,
+    /// Skip objects whose data doesn't match their hash instead of failing the whole fetch
+    pub best_effort_fetch: bool,
+    /// Metadata of the chain's runtime, for chains without bundled metadata
+    pub metadata_path: Option<PathBuf>,
+    /// Cap on the bytes per second sent to the object store, unlimited when unset
+    #[serde(deserialize_with = "throttle::deserialize_rate")]
+    pub upload_rate_limit: Option<u64>,
+    /// Cap on the bytes per second received from the object store, unlimited when unset
+    #[serde(deserialize_with = "throttle::deserialize_rate")]
+    pub download_rate_limit: Option<u64>,
+    /// Repository or URL that fetched refs are also pushed to, e.g. a bare backup mirror
+    pub tee_remote: Option<String>,
+    /// Paths a push asks before publishing, as git pathspecs matched against the whole path and
+    /// the file name
+    pub publish_guard_patterns: Vec<String>,
+    /// Blobs larger than this many bytes are uploaded on their own, streamed from disk
+    pub large_blob_threshold: u64,
+    /// How many blocks back to look for an earlier RepoData when the current one is unreadable
+    pub repodata_lookback_blocks: u32,
+    /// Chain requests per second at most; when unset, a conservative limit for public endpoints
+    /// and none for local ones. 0 means no limit.
+    pub chain_rps: Option<u64>,
+    /// Leave refs the remote rewrote out of fetches until accepted with `GIT_INV4_ACCEPT_REWRITES=1`
+    pub protect_against_rewrites: bool,
+    /// Record our IPFS node's public addresses in pushes, so fetchers connect to it directly
+    pub publish_swarm_hints: bool,
+    /// When a published push counts as done
+    pub confirmation: Confirmation,
+    /// Ask before signing, naming the repository, chain and address
+    pub confirm_signing: bool,
+    /// Keep the unlocked key for the rest of a helper's run, or ask for each push
+    pub auth_cache: AuthCache,
+    /// Serve the refs last read from the chain when it can't be reached, never, or always
+    pub offline: OfflineMode,
+    /// Uploads to the object store that may run at once
+    pub upload_concurrency: usize,
+    /// Refuse to push commits without a signature
+    pub require_signed_commits: bool,
+    /// Refs that can't be deleted or force-pushed; a trailing `*` matches a prefix
+    pub protected_refs: Vec<String>,
+    /// Regex every pushed commit's message has to match
+    pub commit_message_pattern: Option<String>,
+    /// Program run like git's pre-push hook before anything is uploaded; non-zero exit aborts
+    pub pre_push_hook: Option<PathBuf>,
+    /// Ask on a terminal for a message describing each push, unless `GIT_INV4_PUSH_MESSAGE` gives one
+    pub prompt_push_message: bool,
+    /// Sign transactions that never expire, instead of ones valid for 64 blocks
+    pub immortal_transactions: bool,
+    /// Refuse to push refs whose names are longer than this many bytes
+    pub max_ref_name_bytes: usize,
+    /// Repository names usable in place of IPS ids in URLs, e.g. `inv4://website`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, IpsId>,
+    /// IPS whose RepoMeta lists names for repositories without an alias
+    pub name_registry_ips: Option<IpsId>,
+    /// Bytes of downloaded content to keep on disk at most, no cache when 0
+    #[serde(deserialize_with = "throttle::deserialize_rate")]
+    pub cache_max_size: Option<u64>,
+    /// Check every object fetched refs reach is present before reporting the fetch done
+    pub verify_fetch: bool,
+    /// Most objects a single walk over the repository's objects may visit
+    pub traversal_max_objects: usize,
+    /// How deep a single walk may go
+    pub traversal_max_depth: usize,
+    /// How long a single walk may take, in seconds
+    pub traversal_budget_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chain_endpoint: String::from("wss://tinker.invarch.network:443"),
+            chain_auth: None,
+            repodata_warn_size: 1024 * 1024,
+            max_repodata_size: None,
+            force_push_confirmation: true,
+            ipfs_endpoint: None,
+            auto_spawn_ipfs: false,
+            keep_daemon: false,
+            packing: Packing::Commits,
+            pack_target_size: 4 * 1024 * 1024,
+            pack_max_size: 16 * 1024 * 1024,
+            best_effort_fetch: false,
+            metadata_path: None,
+            upload_rate_limit: None,
+            download_rate_limit: None,
+            tee_remote: None,
+            publish_guard_patterns: [".env", ".env.*", "*.pem", "*.key", "id_rsa", "id_ed25519"]
+                .map(String::from)
+                .to_vec(),
+            large_blob_threshold: 64 * 1024 * 1024,
+            repodata_lookback_blocks: 256,
+            chain_rps: None,
+            protect_against_rewrites: false,
+            publish_swarm_hints: true,
+            confirmation: Confirmation::Finalized,
+            confirm_signing: true,
+            auth_cache: AuthCache::Session,
+            offline: OfflineMode::Auto,
+            upload_concurrency: 4,
+            require_signed_commits: false,
+            protected_refs: vec![],
+            commit_message_pattern: None,
+            pre_push_hook: None,
+            prompt_push_message: false,
+            immortal_transactions: false,
+            max_ref_name_bytes: 255,
+            aliases: BTreeMap::new(),
+            name_registry_ips: None,
+            cache_max_size: Some(1024 * 1024 * 1024),
+            verify_fetch: true,
+            traversal_max_objects: traversal::DEFAULT_MAX_OBJECTS,
+            traversal_max_depth: traversal::DEFAULT_MAX_DEPTH,
+            traversal_budget_secs: traversal::DEFAULT_BUDGET_SECS,
+        }
+    }
+}
+
+impl Config {
+    /// Load the global config file, falling back to the defaults when there is none.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let config_file_path = config::path()?;
+
+        std::fs::create_dir_all(
+            config_file_path
+                .parent()
+                .ok_or("Config file path has no parent directory")?,
+        )?;
+
+        Ok(if config_file_path.exists() {
+            config::parse(
+                &std::fs::read_to_string(&config_file_path)?,
+                &config_file_path,
+            )?
+        } else {
+            Self::default()
+        })
+    }
 }
 
 /// A magic value used to signal that a hash is a submodule tip (to be obtained by git on its own).
 pub static SUBMODULE_TIP_MARKER: &str = "submodule-tip";
 
+/// Stands in for the MultiObject hash of a blob stored on its own, see `RepoData::large_blobs`.
+pub static LARGE_BLOB_MARKER: &str = "large-blob";
+
 pub type BoxResult<T> = Result<T, Box<dyn Error>>;
 
-#[derive(Clone, Debug, Encode, Decode)]
+/// Declares an id the chain hands out as a type of its own, so one kind can't be passed where
+/// another is expected. It is SCALE-encoded, serialized and shown as the bare number it wraps,
+/// which keeps calls, storage keys and the files ids are kept in unchanged.
+macro_rules! chain_id {
+    ($(#[$doc:meta])* $name:ident($inner:ty)) => {
+        $(#[$doc])*
+        #[derive(
+            Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Encode, Decode,
+            Serialize, Deserialize,
+        )]
+        #[serde(transparent)]
+        pub struct $name(pub $inner);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+    };
+}
+
+chain_id!(
+    /// An IP Set, which holds a repository.
+    IpsId(u32)
+);
+chain_id!(
+    /// A sub-token of an IP Set, whose holders operate the IPS's multisig with it.
+    SubassetId(u32)
+);
+chain_id!(
+    /// An IP File: a MultiObject, large blob or RepoData minted onto the chain.
+    IpfId(u64)
+);
+
+/// Layout version of MultiObjects written by this build.
+///
+/// They start with `MULTI_OBJECT_MAGIC`, this version and the length of the header as a
+/// little-endian u32. The brotli-compressed `MultiObjectHeader` follows, then the
+/// brotli-compressed object data concatenated in header order, so an object's offset into the
+/// data is the sum of the sizes before it. Only the header has to be downloaded to walk
+/// history, using a ranged read.
+const MULTI_OBJECT_VERSION: u8 = 2;
+
+/// Marks a MultiObject laid out by version 2 onwards. Older ones are compressed as a whole.
+const MULTI_OBJECT_MAGIC: &[u8; 8] = b"INV4-MO\0";
+
+/// Bytes before the header: magic, version and header length.
+const MULTI_OBJECT_PREFIX_LEN: usize = MULTI_OBJECT_MAGIC.len() + 1 + 4;
+
+/// How much of a MultiObject to read when only its header is wanted. Larger headers take a
+/// second read.
+const HEADER_PROBE_LEN: usize = 64 * 1024;
+
+/// Version 1 MultiObjects are brotli over the SCALE encoding of an empty string where legacy
+/// ones have their (never empty) hash, followed by this version, a `MultiObjectHeader` and the
+/// object data in header order.
+const MULTI_OBJECT_V1: u8 = 1;
+
+#[derive(Clone, Debug)]
 pub struct MultiObject {
     pub hash: String,
     pub git_hashes: Vec<String>,
     pub objects: BTreeMap<String, GitObject>,
+    /// Sum of the sizes of `objects`
+    pub total_size: u64,
+}
+
+/// The leading section of a MultiObject: everything but the object data.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct MultiObjectHeader {
+    pub hash: String,
+    /// A {sha1 -> entry} map, in the same order as the data section
+    pub entries: BTreeMap<String, ObjectEntry>,
+    pub total_size: u64,
+}
+
+/// What a MultiObject header knows about one object.
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct ObjectEntry {
+    pub kind: ObjectKind,
+    pub size: u64,
+    pub metadata: GitObjectMetadata,
+}
+
+/// MultiObjects predating `MULTI_OBJECT_V1` stored objects without kind and size.
+#[derive(Decode)]
+struct LegacyGitObject {
+    git_hash: String,
+    data: Vec<u8>,
+    metadata: GitObjectMetadata,
+}
+
+impl From<LegacyGitObject> for GitObject {
+    fn from(legacy: LegacyGitObject) -> Self {
+        Self {
+            git_hash: legacy.git_hash,
+            kind: ObjectKind::of(&legacy.metadata),
+            size: legacy.data.len() as u64,
+            data: legacy.data,
+            metadata: legacy.metadata,
+        }
+    }
+}
+
+/// Decodes MultiObjects predating `MULTI_OBJECT_MAGIC`, once decompressed.
+impl Decode for MultiObject {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let hash = String::decode(input)?;
+
+        if !hash.is_empty() {
+            let git_hashes = Decode::decode(input)?;
+            let objects: BTreeMap<String, LegacyGitObject> = Decode::decode(input)?;
+
+            return Ok(Self::from_parts(
+                hash,
+                git_hashes,
+                objects
+                    .into_iter()
+                    .map(|(git_hash, object)| (git_hash, object.into()))
+                    .collect(),
+            ));
+        }
+
+        let header = MultiObjectHeader::decode_versioned(input)?;
+        let data: Vec<Vec<u8>> = Decode::decode(input)?;
+
+        if data.len() != header.entries.len() {
+            return Err("MultiObject data does not match its header".into());
+        }
+
+        let objects = header
+            .entries
+            .into_iter()
+            .zip(data)
+            .map(|((git_hash, entry), data)| {
+                let object = GitObject {
+                    git_hash: git_hash.clone(),
+                    data,
+                    metadata: entry.metadata,
+                    kind: entry.kind,
+                    size: entry.size,
+                };
+                (git_hash, object)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(Self::from_parts(
+            header.hash,
+            objects.keys().cloned().collect(),
+            objects,
+        ))
+    }
+}
+
+impl MultiObjectHeader {
+    /// Decode the header of a version 1 MultiObject, past its leading empty string.
+    fn decode_versioned<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        match u8::decode(input)? {
+            MULTI_OBJECT_V1 => Self::decode(input),
+            _ => Err("Unsupported MultiObject version; a newer git-remote-inv4 is needed".into()),
+        }
+    }
+
+    /// Decode only the header of a decompressed MultiObject predating `MULTI_OBJECT_MAGIC`.
+    /// Legacy MultiObjects have no separate header, so they are decoded whole.
+    fn decode_from(bytes: &[u8]) -> Result<Self, codec::Error> {
+        let mut rest = bytes;
+
+        if String::decode(&mut rest)?.is_empty() {
+            return Self::decode_versioned(&mut rest);
+        }
+
+        Ok(MultiObject::decode(&mut &bytes[..])?.header())
+    }
+
+    /// Decode the compressed header section of a current MultiObject.
+    fn from_section(section: &[u8]) -> BoxResult<Self> {
+        Ok(Self::decode(&mut try_decompress_data(section)?.as_slice())?)
+    }
+}
+
+/// The length of the header of a current MultiObject starting with `bytes`, `None` for a
+/// MultiObject predating `MULTI_OBJECT_MAGIC`.
+fn header_len(bytes: &[u8]) -> BoxResult<Option<usize>> {
+    if !bytes.starts_with(MULTI_OBJECT_MAGIC) {
+        return Ok(None);
+    }
+
+    if bytes.len() < MULTI_OBJECT_PREFIX_LEN {
+        error!("MultiObject is truncated")
+    }
+
+    match bytes[MULTI_OBJECT_MAGIC.len()] {
+        MULTI_OBJECT_VERSION => {}
+        _ => error!("Unsupported MultiObject version; a newer git-remote-inv4 is needed"),
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&bytes[MULTI_OBJECT_MAGIC.len() + 1..MULTI_OBJECT_PREFIX_LEN]);
+
+    Ok(Some(u32::from_le_bytes(len) as usize))
 }
 
 impl MultiObject {
+    /// The key a MultiObject is minted and looked up under: blake2b-256 over its sorted git
+    /// hashes, hex-encoded.
+    ///
+    /// Older pushes used a 64-bit xxh3 of the unsorted list; those keys stay valid because
+    /// lookups only compare them against IPF metadata.
+    pub fn identity(git_hashes: &[String]) -> String {
+        let mut sorted = git_hashes.to_vec();
+        sorted.sort();
+
+        hex::encode(blake2_256(&sorted.encode()))
+    }
+
+    fn from_parts(
+        hash: String,
+        git_hashes: Vec<String>,
+        objects: BTreeMap<String, GitObject>,
+    ) -> Self {
+        Self {
+            hash,
+            git_hashes,
+            total_size: objects.values().map(|object| object.size).sum(),
+            objects,
+        }
+    }
+
     pub fn add(&mut self, object: GitObject) {
         let hash = object.git_hash.clone();
+        self.total_size += object.size;
         self.objects.insert(hash.clone(), object);
         self.git_hashes.push(hash);
     }
 
-    pub async fn chain_get(
-        hash: String,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        ips_id: u32,
-    ) -> Result<Self, Box<dyn Error>> {
-        let ips_info_address = tinkernet::storage().inv4().ip_storage(&ips_id);
+    pub fn header(&self) -> MultiObjectHeader {
+        MultiObjectHeader {
+            hash: self.hash.clone(),
+            entries: self
+                .objects
+                .iter()
+                .map(|(git_hash, object)| {
+                    let entry = ObjectEntry {
+                        kind: object.kind,
+                        size: object.size,
+                        metadata: object.metadata.clone(),
+                    };
+                    (git_hash.clone(), entry)
+                })
+                .collect(),
+            total_size: self.total_size,
+        }
+    }
 
-        let ips_info = chain_api
-            .storage()
-            .fetch(&ips_info_address, None)
-            .await?
-            .ok_or(format!("IPS {ips_id} does not exist"))?;
+    /// The form a MultiObject is stored in: its `MULTI_OBJECT_VERSION` layout in a format
+    /// envelope.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_artifact(Kind::MultiObject, self.split_layout())
+    }
+
+    /// The MultiObject laid out as described at `MULTI_OBJECT_VERSION`.
+    fn split_layout(&self) -> Vec<u8> {
+        let header = compress_data(self.header().encode());
+        let mut data = Vec::with_capacity(self.total_size as usize);
+        for object in self.objects.values() {
+            data.extend_from_slice(&object.data);
+        }
 
-        for file in ips_info.data.0 {
-            if let AnyId::IpfId(id) = file {
-                let ipf_info_address = tinkernet::storage().ipf().ipf_storage(&id);
+        let mut bytes = MULTI_OBJECT_MAGIC.to_vec();
+        bytes.push(MULTI_OBJECT_VERSION);
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend(header);
+        bytes.extend(compress_data(data));
 
-                let ipf_info = chain_api
-                    .storage()
-                    .fetch(&ipf_info_address, None)
-                    .await?
-                    .ok_or("Internal error: IPF listed from IPS does not exist")?;
-
-                if String::from_utf8(ipf_info.metadata.0.clone())? == *hash {
-                    #[cfg(not(feature = "crust"))]
-                    let data = ipfs
-                        .cat(&generate_cid(ipf_info.data.0.into())?.to_string())
-                        .map_ok(|c| c.to_vec())
-                        .try_concat()
-                        .await?;
+        bytes
+    }
 
-                    #[cfg(feature = "crust")]
-                    let data = crate::crust::get_from_crust(
-                        generate_cid(ipf_info.data.0.into())?.to_string(),
-                    )
-                    .await?;
+    /// Decode a MultiObject of any layout version, as stored before format envelopes or as the
+    /// body of one.
+    pub fn from_bytes(bytes: Vec<u8>) -> BoxResult<Self> {
+        let header_len = match header_len(&bytes)? {
+            Some(len) => len,
+            None => return Ok(Self::decode(&mut try_decompress_data(&bytes)?.as_slice())?),
+        };
+
+        let data_start = MULTI_OBJECT_PREFIX_LEN + header_len;
+        if bytes.len() < data_start {
+            error!("MultiObject is truncated")
+        }
+
+        let header = MultiObjectHeader::from_section(&bytes[MULTI_OBJECT_PREFIX_LEN..data_start])?;
+        let data = try_decompress_data(&bytes[data_start..])?;
+
+        if data.len() as u64 != header.total_size {
+            error!("MultiObject data does not match its header")
+        }
+
+        // Each object's data starts where the previous one's ends, so the sizes must add up
+        let entries_size = header
+            .entries
+            .values()
+            .try_fold(0u64, |sum, entry| sum.checked_add(entry.size));
+        if entries_size != Some(data.len() as u64) {
+            error!("MultiObject header entries do not match data")
+        }
+
+        let mut rest = data.as_slice();
+        let mut objects = BTreeMap::new();
+
+        for (git_hash, entry) in header.entries {
+            let (data, tail) = rest.split_at(entry.size as usize);
+            rest = tail;
+
+            let object = GitObject {
+                git_hash: git_hash.clone(),
+                data: data.to_vec(),
+                metadata: entry.metadata,
+                kind: entry.kind,
+                size: entry.size,
+            };
+            objects.insert(git_hash, object);
+        }
+
+        Ok(Self::from_parts(
+            header.hash,
+            objects.keys().cloned().collect(),
+            objects,
+        ))
+    }
+
+    /// The layout version of the stored MultiObject `bytes`, 0 for those predating
+    /// `MULTI_OBJECT_V1`. `None` when `bytes` doesn't look like a MultiObject at all.
+    pub fn layout_version(bytes: &[u8]) -> Option<u8> {
+        if bytes.starts_with(MULTI_OBJECT_MAGIC) {
+            return bytes.get(MULTI_OBJECT_MAGIC.len()).copied();
+        }
+
+        let decompressed = try_decompress_data(bytes).ok()?;
+        let mut rest = decompressed.as_slice();
+
+        match String::decode(&mut rest).ok()?.is_empty() {
+            true => u8::decode(&mut rest).ok(),
+            false => Some(0),
+        }
+    }
+
+    /// Check that every object hashes to its git hash.
+    pub fn verify(&self) -> BoxResult<()> {
+        for object in self.objects.values() {
+            let hash = Oid::hash_object(object.kind.object_type(), &object.data)?;
+            if hash.to_string() != object.git_hash {
+                error!(format!("Object {} hashes to {}", object.git_hash, hash))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Download the MultiObject at `cid`, see `RepoData::multi_object_cid`.
+    pub async fn get(cid: &str, store: &dyn ObjectStore) -> Result<Self, Box<dyn Error>> {
+        match decode_artifact(store.get(cid).await?, Some(Kind::MultiObject))? {
+            Artifact::MultiObject { multi_object, .. } => Ok(multi_object),
+            other => error!(format!(
+                "{} holds a {}, not a MultiObject",
+                cid,
+                other.name()
+            )),
+        }
+    }
+
+    /// Like `get`, but only downloads the header where the MultiObject's layout allows.
+    pub async fn get_header(cid: &str, store: &dyn ObjectStore) -> BoxResult<MultiObjectHeader> {
+        let probe = store.get_range(cid, 0, HEADER_PROBE_LEN).await?;
+
+        // The split layout starts right after the envelope
+        let offset = match Format::parse(&probe)? {
+            Some(format) if format.kind == Kind::MultiObject && format.layout == Layout::Split => {
+                ENVELOPE_LEN
+            }
+            Some(_) => return Ok(Self::get(cid, store).await?.header()),
+            None => 0,
+        };
+        let start = &probe[offset..];
+
+        let header_len = match header_len(start)? {
+            Some(len) => len,
+            // Compressed as a whole, so there is nothing to do but to download all of it
+            None if probe.len() < HEADER_PROBE_LEN => {
+                return Ok(MultiObjectHeader::decode_from(&try_decompress_data(
+                    &probe,
+                )?)?)
+            }
+            None => {
+                return Ok(MultiObjectHeader::decode_from(&try_decompress_data(
+                    &store.get(cid).await?,
+                )?)?)
+            }
+        };
+
+        let header_end = MULTI_OBJECT_PREFIX_LEN + header_len;
+        match start.get(MULTI_OBJECT_PREFIX_LEN..header_end) {
+            Some(section) => MultiObjectHeader::from_section(section),
+            None => MultiObjectHeader::from_section(
+                &store
+                    .get_range(cid, offset + MULTI_OBJECT_PREFIX_LEN, header_len)
+                    .await?,
+            ),
+        }
+    }
+}
+
+/// Where the CID behind a MultiObject hash is looked up.
+pub enum MultiObjectIndex<'a> {
+    /// Scan the IPS's IPFs for one whose metadata is the hash, as of the snapshot's block or the
+    /// best block without one
+    Chain {
+        chain: &'a Chain,
+        ips_id: IpsId,
+        at: Option<&'a Snapshot>,
+    },
+    /// A {MultiObject hash -> CID} map obtained without the chain
+    Cids(BTreeMap<String, String>),
+}
 
-                    return Ok(Self::decode(&mut decompress_data(data).as_slice())?);
+impl MultiObjectIndex<'_> {
+    /// Parse a sidecar index file of `<MultiObject hash> <CID>` lines.
+    pub fn from_sidecar(contents: &str) -> BoxResult<Self> {
+        let mut cids = BTreeMap::new();
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [hash, cid] => {
+                    cids.insert(hash.to_owned(), cid.to_owned());
                 }
+                _ => error!(format!("Malformed index line: {:?}", line)),
             }
         }
-        error!("git_hash ipf not found")
+
+        Ok(Self::Cids(cids))
+    }
+
+    pub async fn cid(&self, hash: &str) -> BoxResult<Option<String>> {
+        match self {
+            Self::Chain {
+                chain,
+                ips_id,
+                at: Some(snapshot),
+            } => snapshot
+                .read(chain, |block| {
+                    chain.find_ipfs_at(*ips_id, hash.as_bytes(), Some(block))
+                })
+                .await?
+                .first()
+                .map(|ipf| Ok(generate_cid(ipf.data)?.to_string()))
+                .transpose(),
+            Self::Chain {
+                chain,
+                ips_id,
+                at: None,
+            } => chain
+                .find_ipf(*ips_id, hash.as_bytes())
+                .await?
+                .map(|ipf| Ok(generate_cid(ipf.data)?.to_string()))
+                .transpose(),
+            Self::Cids(cids) => Ok(cids.get(hash).cloned()),
+        }
     }
 }
 
-#[derive(Clone, Debug, Encode, Decode)]
+#[derive(Clone, Debug)]
 pub struct GitObject {
     /// The git hash of the underlying git object
     pub git_hash: String,
@@ -100,6 +703,48 @@ pub struct GitObject {
     pub data: Vec<u8>,
     /// Object-type-specific metadata
     pub metadata: GitObjectMetadata,
+    pub kind: ObjectKind,
+    /// Size of the raw object according to the odb header
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl ObjectKind {
+    pub fn of(metadata: &GitObjectMetadata) -> Self {
+        match metadata {
+            GitObjectMetadata::Commit { .. } => Self::Commit,
+            GitObjectMetadata::Tag { .. } => Self::Tag,
+            GitObjectMetadata::Tree { .. } => Self::Tree,
+            GitObjectMetadata::Blob => Self::Blob,
+        }
+    }
+
+    pub fn from_object_type(kind: ObjectType) -> BoxResult<Self> {
+        Ok(match kind {
+            ObjectType::Commit => Self::Commit,
+            ObjectType::Tree => Self::Tree,
+            ObjectType::Blob => Self::Blob,
+            ObjectType::Tag => Self::Tag,
+            other => error!(format!("{} is not a storable object type", other)),
+        })
+    }
+
+    pub fn object_type(self) -> ObjectType {
+        match self {
+            Self::Commit => ObjectType::Commit,
+            Self::Tree => ObjectType::Tree,
+            Self::Blob => ObjectType::Blob,
+            Self::Tag => ObjectType::Tag,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Encode, Decode)]
@@ -118,657 +763,3308 @@ pub enum GitObjectMetadata {
 }
 
 impl GitObject {
-    pub fn from_git_blob(blob: &Blob, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(blob.id())?;
+    /// Read `oid` from `odb`, taking its links from the raw data so it is only read once.
+    pub fn read(oid: Oid, odb: &Odb) -> BoxResult<Self> {
+        let odb_obj = odb.read(oid)?;
+        let data = odb_obj.data();
+        debug!("Pushing {:?} {}", odb_obj.kind(), oid);
+
+        let (kind, metadata) = match odb_obj.kind() {
+            ObjectType::Commit => (
+                ObjectKind::Commit,
+                GitObjectMetadata::Commit {
+                    parent_git_hashes: header_values(data, "parent").collect(),
+                    tree_git_hash: header_values(data, "tree")
+                        .next()
+                        .ok_or_else(|| format!("Commit {} has no tree", oid))?,
+                },
+            ),
+            ObjectType::Tree => (
+                ObjectKind::Tree,
+                GitObjectMetadata::Tree {
+                    entry_git_hashes: parse_tree(data)?
+                        .into_iter()
+                        .map(|entry| entry.git_hash)
+                        .collect(),
+                },
+            ),
+            ObjectType::Blob => (ObjectKind::Blob, GitObjectMetadata::Blob),
+            ObjectType::Tag => (
+                ObjectKind::Tag,
+                GitObjectMetadata::Tag {
+                    target_git_hash: header_values(data, "object")
+                        .next()
+                        .ok_or_else(|| format!("Tag {} has no target", oid))?,
+                },
+            ),
+            other => error!(format!(
+                "Don't know how to push object {} of type {:?}",
+                oid, other
+            )),
+        };
 
         Ok(Self {
-            git_hash: blob.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Blob,
+            git_hash: oid.to_string(),
+            data: data.to_vec(),
+            metadata,
+            kind,
+            size: data.len() as u64,
         })
     }
+}
 
-    pub fn from_git_commit(commit: &Commit, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(commit.id())?;
-
-        let parent_git_hashes: BTreeSet<String> = commit
-            .parent_ids()
-            .map(|parent_id| format!("{}", parent_id))
-            .collect();
+/// The values of `key` in the header of a raw commit or tag, which ends at the first blank line.
+pub fn header_values<'a>(data: &'a [u8], key: &'a str) -> impl Iterator<Item = String> + 'a {
+    data.split(|&byte| byte == b'\n')
+        .take_while(|line| !line.is_empty())
+        .filter_map(move |line| {
+            let value = line.strip_prefix(key.as_bytes())?.strip_prefix(b" ")?;
+            Some(String::from_utf8_lossy(value).into_owned())
+        })
+}
 
-        let tree_git_hash = format!("{}", commit.tree()?.id());
+/// How many pushes the RepoData history remembers.
+pub const PUSH_HISTORY_LIMIT: usize = 50;
 
-        Ok(Self {
-            git_hash: commit.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Commit {
-                parent_git_hashes,
-                tree_git_hash,
-            },
-        })
-    }
+/// A ref moved by a push; `None` on either side means the ref was created or deleted.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RefChange {
+    pub name: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
 
-    pub fn from_git_tag(tag: &Tag, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(tag.id())?;
+/// One entry of the push history kept in RepoData.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PushRecord {
+    /// The best block when the push was prepared. RepoData has to be minted before the call
+    /// that publishes it, so the push itself lands a few blocks after this one.
+    pub block_number: u32,
+    /// Hex-encoded hash of `block_number`
+    pub block_hash: String,
+    /// SS58 address of the signer
+    pub pusher: String,
+    pub refs_changed: Vec<RefChange>,
+    /// Why the push was made, if the pusher said. Stored apart from the record, see
+    /// `RepoData::encode_to`, so older helpers can still read the history.
+    #[codec(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
 
-        Ok(Self {
-            git_hash: tag.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Tag {
-                target_git_hash: format!("{}", tag.target_id()),
-            },
-        })
-    }
+/// What a ref points at, beyond its sha1.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RefTarget {
+    pub kind: ObjectKind,
+    /// The first object behind an annotated tag that isn't a tag itself
+    pub peeled: Option<String>,
+}
 
-    pub fn from_git_tree(tree: &Tree, odb: &Odb) -> Result<Self, Box<dyn Error>> {
-        let odb_obj = odb.read(tree.id())?;
+/// How many IPFS nodes `RepoData::swarm_hints` remembers.
+pub const SWARM_HINT_LIMIT: usize = 10;
 
-        let entry_git_hashes: BTreeSet<String> =
-            tree.iter().map(|entry| format!("{}", entry.id())).collect();
+/// The IPFS node of someone who pushed, which holds the objects they pushed.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SwarmHint {
+    pub peer_id: String,
+    /// Public multiaddrs of the node, each ending in `/p2p/<peer_id>`
+    pub addresses: Vec<String>,
+}
 
-        Ok(Self {
-            git_hash: tree.id().to_string(),
-            data: odb_obj.data().to_vec(),
-            metadata: GitObjectMetadata::Tree { entry_git_hashes },
-        })
-    }
+/// One ref as listed by `inv4-git ls --json`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RefListing {
+    pub name: String,
+    pub target: String,
+    /// `commit`, `tree`, `blob` or `tag`, or `unknown` for refs pushed before types were recorded
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// What an annotated tag ultimately points at, `null` for other refs
+    pub peeled: Option<String>,
+    /// The block of the push that set the ref, `null` when that push is no longer in the history
+    pub block_number: Option<u32>,
+    /// The ref a symbolic ref points at, `null` for direct refs
+    pub symref: Option<String>,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+/// The repository index. Its JSON form, as `inv4-git dump-repodata` writes it, has a key per
+/// field, binary CIDs as strings and object kinds in lowercase; fields missing from it are
+/// empty, like in RepoData encoded before they existed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct RepoData {
     /// All refs this repository knows; a {name -> sha1} map
     pub refs: BTreeMap<String, String>,
     /// All objects this repository contains; a {sha1 -> MultiObject hash} map
     pub objects: BTreeMap<String, String>,
+    /// The last `PUSH_HISTORY_LIMIT` pushes, oldest first
+    pub history: Vec<PushRecord>,
+    /// Binary CIDs of the MultiObjects pushed since they are recorded; a {MultiObject hash ->
+    /// CID} map. Older MultiObjects are only found through the digest in their IPF.
+    #[serde(with = "crate::util::cid_string_map")]
+    pub cids: BTreeMap<String, Vec<u8>>,
+    /// Blobs stored on their own rather than in a MultiObject; a {sha1 -> LargeBlob} map. Their
+    /// `objects` entry is `LARGE_BLOB_MARKER`.
+    pub large_blobs: BTreeMap<String, LargeBlob>,
+    /// The kind and peeled target of refs pushed since they are recorded; a {name -> RefTarget}
+    /// map. Older refs have no entry.
+    pub ref_targets: BTreeMap<String, RefTarget>,
+    /// The IPFS nodes of the last `SWARM_HINT_LIMIT` pushers that published one, most recent
+    /// first
+    pub swarm_hints: Vec<SwarmHint>,
+    /// Symbolic refs, `HEAD` among them; a {name -> target ref} map. Except for `HEAD`, `refs`
+    /// also maps each to its target's sha1, so readers that don't know about them see a direct
+    /// ref.
+    pub symrefs: BTreeMap<String, String>,
 }
 
-impl RepoData {
-    pub async fn from_ipfs(ipfs_hash: H256, ipfs: &mut IpfsClient) -> Result<Self, Box<dyn Error>> {
-        let refs_cid = generate_cid(ipfs_hash)?.to_string();
-
-        #[cfg(feature = "crust")]
-        let refs_content = crate::crust::get_from_crust(refs_cid.clone()).await?;
-
-        #[cfg(not(feature = "crust"))]
-        let refs_content = ipfs
-            .cat(&refs_cid)
-            .map_ok(|c| c.to_vec())
-            .try_concat()
-            .await?;
+/// Decode a field appended to a format after its first release, defaulting it for older data.
+fn decode_trailing<T: Decode + Default, I: Input>(input: &mut I) -> Result<T, codec::Error> {
+    match input.remaining_len()? {
+        Some(0) => Ok(T::default()),
+        _ => T::decode(input),
+    }
+}
 
-        Ok(Self::decode(&mut decompress_data(refs_content).as_slice())?)
+impl Encode for RepoData {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.refs.encode_to(dest);
+        self.objects.encode_to(dest);
+        self.history.encode_to(dest);
+        self.cids.encode_to(dest);
+        self.large_blobs.encode_to(dest);
+        self.ref_targets.encode_to(dest);
+        self.swarm_hints.encode_to(dest);
+
+        // Push messages follow as a {history index -> message} map, as a field added to
+        // PushRecord would break decoding the history for older helpers
+        let messages: BTreeMap<u32, &String> = self
+            .history
+            .iter()
+            .enumerate()
+            .filter_map(|(i, record)| Some((i as u32, record.message.as_ref()?)))
+            .collect();
+        messages.encode_to(dest);
+        self.symrefs.encode_to(dest);
     }
+}
 
-    pub async fn push_ref_from_str(
-        &mut self,
-        ref_src: &str,
-        ref_dst: &str,
-        force: bool,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        signer: &PairSigner<PolkadotConfig, Pair>,
-        ips_id: u32,
-    ) -> Result<u64, Box<dyn Error>> {
-        // Deleting `ref_dst` was requested
-        if ref_src.is_empty() {
-            debug!("Removing ref {} from index", ref_dst);
-            if self.refs.remove(ref_dst).is_none() {
-                debug!(
-                    "Nothing to delete, ref {} not part of the index ref set",
-                    ref_dst
-                );
-                debug!("Available refs:\n{:#?}", self.refs);
+impl Decode for RepoData {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let mut repo_data = Self {
+            refs: Decode::decode(input)?,
+            objects: Decode::decode(input)?,
+            history: decode_trailing(input)?,
+            cids: decode_trailing(input)?,
+            large_blobs: decode_trailing(input)?,
+            ref_targets: decode_trailing(input)?,
+            swarm_hints: decode_trailing(input)?,
+            symrefs: BTreeMap::new(),
+        };
+
+        let messages: BTreeMap<u32, String> = decode_trailing(input)?;
+        for (i, message) in messages {
+            if let Some(record) = repo_data.history.get_mut(i as usize) {
+                record.message = Some(message);
             }
-            error!("ref_srd empty")
         }
-        let reference = repo.find_reference(ref_src)?.resolve()?;
+        repo_data.symrefs = decode_trailing(input)?;
 
-        // Differentiate between annotated tags and their commit representation
-        let obj = reference
-            .peel(ObjectType::Tag)
-            .unwrap_or(reference.peel(ObjectType::Commit)?);
+        Ok(repo_data)
+    }
+}
 
-        debug!(
-            "{:?} dereferenced to {:?} {}",
-            reference.shorthand(),
-            obj.kind(),
-            obj.id()
-        );
+/// The error for `oid` failing to read after it was enumerated: most likely a background
+/// `git gc` pruned it in between.
+fn vanished(oid: Oid, e: impl fmt::Display) -> Box<dyn Error> {
+    format!(
+        "Object {} can no longer be read ({}); a background `git gc` probably pruned it while the push was counting objects, before its objects were uploaded. Push again, and if it keeps happening, run `git config gc.autoDetach false` so auto-gc finishes before git returns",
+        oid, e
+    )
+    .into()
+}
 
-        if force {
-            eprintln!("This push will be forced");
-        } else {
-            eprintln!("Checking for work ahead of us...");
-
-            if let Some(dst_git_hash) = self.refs.get(ref_dst) {
-                let mut missing_objects = HashSet::new();
-                self.enumerate_for_fetch(
-                    dst_git_hash.parse()?,
-                    &mut missing_objects,
-                    repo,
-                    ipfs,
-                    chain_api,
-                    ips_id,
-                )
-                .await?;
+/// Point out that `repo_data` is an earlier version of the repository index, read from `source`.
+fn recovered(repo_data: RepoData, source: String) -> RepoData {
+    output::warn(format!(
+        "Using the repository index from {}; its refs may be stale, so check them before relying on them. The next push replaces the unreadable index.",
+        source
+    ));
 
-                if !missing_objects.is_empty() {
-                    eprintln!(
-                        "There's {} objects in {} not present locally. Please fetch first or force-push.",
-                        missing_objects.len(),
-                        ref_dst
-                        );
+    repo_data
+}
 
-                    debug!("Missing objects:\n{:#?}", missing_objects);
-                    return Err("There's objects in the index not present in the local repo - a pull is needed".into());
-                }
+/// `oids` ordered so each comes after the objects in `oids` it references, per the metadata in
+/// `objects`. Objects not in `objects`, i.e. large blobs, reference nothing.
+fn write_order(oids: &HashSet<Oid>, objects: &BTreeMap<String, GitObject>) -> BoxResult<Vec<Oid>> {
+    let mut roots: Vec<Oid> = oids.iter().copied().collect();
+    roots.sort();
+
+    let mut order = Vec::with_capacity(oids.len());
+    let mut expanded = HashSet::new();
+    // `true` once the object's references are on the stack above it
+    let mut stack: Vec<(Oid, bool)> = roots.into_iter().rev().map(|oid| (oid, false)).collect();
+
+    while let Some((oid, ready)) = stack.pop() {
+        if ready {
+            order.push(oid);
+            continue;
+        }
+        if !expanded.insert(oid) {
+            continue;
+        }
+        stack.push((oid, true));
+
+        let references = match objects.get(&oid.to_string()).map(|object| &object.metadata) {
+            Some(GitObjectMetadata::Commit {
+                parent_git_hashes,
+                tree_git_hash,
+            }) => parent_git_hashes
+                .iter()
+                .chain(std::iter::once(tree_git_hash))
+                .collect(),
+            Some(GitObjectMetadata::Tag { target_git_hash }) => vec![target_git_hash],
+            Some(GitObjectMetadata::Tree { entry_git_hashes }) => entry_git_hashes.iter().collect(),
+            Some(GitObjectMetadata::Blob) | None => vec![],
+        };
+
+        for reference in references {
+            let reference = Oid::from_str(reference)?;
+            if oids.contains(&reference) && !expanded.contains(&reference) {
+                stack.push((reference, false));
             }
         }
+    }
 
-        let mut objs_for_push = HashSet::new();
-        let mut submodules_for_push = HashSet::new();
+    Ok(order)
+}
 
-        self.enumerate_for_push(
-            &obj.clone(),
-            &mut objs_for_push,
-            &mut submodules_for_push,
-            repo,
-        )?;
+/// The blocks 1, 2, 4, ... before `best`, up to `lookback` blocks back, where earlier RepoData
+/// versions are looked for.
+fn lookback_blocks(best: u32, lookback: u32) -> impl Iterator<Item = u32> {
+    std::iter::successors(Some(1u32), |distance| distance.checked_mul(2))
+        .take_while(move |&distance| distance <= lookback.min(best))
+        .map(move |distance| best - distance)
+}
 
-        let ipf_id = self
-            .push_git_objects(&objs_for_push, repo, ipfs, chain_api, signer)
-            .await?;
+/// How much space a RepoData takes, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepoDataSize {
+    pub encoded: usize,
+    pub compressed: usize,
+}
 
-        for submod_oid in submodules_for_push {
-            self.objects
-                .insert(submod_oid.to_string(), SUBMODULE_TIP_MARKER.to_owned());
+impl RepoDataSize {
+    /// Enforce the configured limits, warning past the soft one and failing past the hard one.
+    pub fn check(&self, config: &Config) -> BoxResult<()> {
+        if let Some(max) = config.max_repodata_size {
+            if self.compressed > max {
+                error!(format!(
+                    "Repository index would be {}, over the max_repodata_size limit of {}; delete stale refs or raise the limit",
+                    human_size(self.compressed),
+                    human_size(max)
+                ))
+            }
         }
 
-        self.refs
-            .insert(ref_dst.to_owned(), format!("{}", obj.id()));
-        Ok(ipf_id)
+        if self.compressed > config.repodata_warn_size {
+            output::warn(format!(
+                "Repository index is {}, over {}; every push re-uploads it, so consider deleting stale refs",
+                human_size(self.compressed),
+                human_size(config.repodata_warn_size)
+            ));
+        }
+
+        Ok(())
     }
+}
 
-    pub fn enumerate_for_push(
+impl std::fmt::Display for RepoDataSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} uncompressed)",
+            human_size(self.compressed),
+            human_size(self.encoded)
+        )
+    }
+}
+
+impl RepoData {
+    /// The CID of the MultiObject `hash`, as recorded here or else as looked up in `index`.
+    pub async fn multi_object_cid(
         &self,
-        obj: &Object,
-        push_todo: &mut HashSet<Oid>,
-        submodules: &mut HashSet<Oid>,
-        repo: &Repository,
-    ) -> Result<(), Box<dyn Error>> {
-        // Object tree traversal state
-        let mut stack = vec![obj.clone()];
+        hash: &str,
+        index: &MultiObjectIndex<'_>,
+    ) -> BoxResult<String> {
+        if let Some(cid) = self.cids.get(hash) {
+            return Ok(Cid::try_from(cid.as_slice())?.to_string());
+        }
 
-        let mut obj_cnt = 1;
-        while let Some(obj) = stack.pop() {
-            if self.objects.contains_key(&obj.id().to_string()) {
-                debug!("Object {} already in RepoData", obj.id());
-                continue;
-            }
+        index
+            .cid(hash)
+            .await?
+            .ok_or_else(|| format!("No IPF holds MultiObject {}", hash).into())
+    }
 
-            if push_todo.contains(&obj.id()) {
-                debug!("Object {} already in state", obj.id());
-                continue;
+    /// Load the repository's current RepoData, or an empty one if nothing was pushed yet.
+    ///
+    /// When it can't be read, the other RepoData IPFs in the IPS are tried, then the RepoData at
+    /// blocks 1, 2, 4, ... up to `lookback` blocks back. A recovered RepoData may lack the latest
+    /// pushes, which is pointed out.
+    ///
+    /// The current RepoData is the one as of `at`'s block, or the best block without a snapshot.
+    pub async fn chain_get(
+        ips_id: IpsId,
+        store: &dyn ObjectStore,
+        chain: &Chain,
+        lookback: u32,
+        at: Option<&Snapshot>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let current = match at {
+            Some(snapshot) => {
+                snapshot
+                    .read(chain, |block| {
+                        chain.find_ipfs_at(ips_id, b"RepoData", Some(block))
+                    })
+                    .await?
             }
+            None => chain.find_ipfs_at(ips_id, b"RepoData", None).await?,
+        };
 
-            let obj_type = obj.kind().ok_or_else(|| {
-                let msg = format!("Cannot determine type of object {}", obj.id());
-                debug!("{}", msg);
-                msg
-            })?;
-
-            push_todo.insert(obj.id());
-
-            match obj_type {
-                ObjectType::Commit => {
-                    let commit = obj
-                        .as_commit()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a commit", obj))
-                        .unwrap();
-                    debug!("[{}] Counting commit {:?}", obj_cnt, commit);
+        let primary = match current.first() {
+            Some(ipf) => ipf,
+            None => return Ok(RepoData::default()),
+        };
 
-                    let tree_obj = obj.peel(ObjectType::Tree)?;
-                    debug!("Commit {}: Handling tree {}", commit.id(), tree_obj.id());
+        let e = match Self::from_ipfs(primary.data, store).await {
+            Ok(repo_data) => return Ok(repo_data),
+            Err(e) => e,
+        };
 
-                    stack.push(tree_obj);
+        output::warn(format!(
+            "Could not read the repository index in IPF {}: {}; looking for an earlier version",
+            primary.id, e
+        ));
 
-                    for parent in commit.parents() {
-                        debug!(
-                            "Commit {}: Pushing parent commit {}",
-                            commit.id(),
-                            parent.id()
-                        );
-                        stack.push(parent.into_object());
-                    }
-                }
-                ObjectType::Tree => {
-                    let tree = obj
-                        .as_tree()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a tree", obj))
-                        .unwrap();
-                    debug!("[{}] Counting tree {:?}", obj_cnt, tree);
+        let mut failures = vec![format!("IPF {}: {}", primary.id, e)];
+        let mut tried = HashSet::from([primary.data]);
 
-                    for entry in tree.into_iter() {
-                        // Weed out submodules (Implicitly known as commit children of tree objects)
-                        if let Some(ObjectType::Commit) = entry.kind() {
-                            debug!("Skipping submodule at {}", entry.id());
+        for ipf in &current[1..] {
+            tried.insert(ipf.data);
 
-                            submodules.insert(entry.id());
+            match Self::from_ipfs(ipf.data, store).await {
+                Ok(repo_data) => return Ok(recovered(repo_data, format!("IPF {}", ipf.id))),
+                Err(e) => failures.push(format!("IPF {}: {}", ipf.id, e)),
+            }
+        }
 
-                            continue;
-                        }
+        let (best, _) = chain.best_block().await?;
 
-                        debug!(
-                            "Tree {}: Pushing tree entry {} ({:?})",
-                            tree.id(),
-                            entry.id(),
-                            entry.kind()
-                        );
+        for number in lookback_blocks(best, lookback) {
+            let historical = match chain.block_hash(number).await {
+                Ok(Some(hash)) => chain.find_ipfs_at(ips_id, b"RepoData", Some(hash)).await,
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
 
-                        stack.push(entry.to_object(repo)?);
-                    }
-                }
-                ObjectType::Blob => {
-                    let blob = obj
-                        .as_blob()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a blob", obj))
-                        .unwrap();
-                    debug!("[{}] Counting blob {:?}", obj_cnt, blob);
+            let ipfs = match historical {
+                Ok(ipfs) => ipfs,
+                Err(e) => {
+                    failures.push(format!("block {}: {}", number, e));
+                    continue;
                 }
-                ObjectType::Tag => {
-                    let tag = obj
-                        .as_tag()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a tag", obj))
-                        .unwrap();
-                    debug!("[{}] Counting tag {:?}", obj_cnt, tag);
+            };
 
-                    stack.push(tag.target()?);
+            for ipf in ipfs {
+                if !tried.insert(ipf.data) {
+                    continue;
                 }
-                other => {
-                    return Err(format!("Don't know how to traverse a {}", other).into());
+
+                match Self::from_ipfs(ipf.data, store).await {
+                    Ok(repo_data) => {
+                        return Ok(recovered(
+                            repo_data,
+                            format!("IPF {} as of block {}", ipf.id, number),
+                        ))
+                    }
+                    Err(e) => {
+                        failures.push(format!("IPF {} as of block {}: {}", ipf.id, number, e))
+                    }
                 }
             }
-
-            obj_cnt += 1;
         }
-        Ok(())
-    }
-
-    pub async fn fetch_to_ref_from_str(
-        &self,
-        git_hash: &str,
-        ref_name: &str,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        ips_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        debug!("Fetching {} for {}", git_hash, ref_name);
-
-        let git_hash_oid = Oid::from_str(git_hash)?;
-        let mut oids_for_fetch = HashSet::new();
 
-        self.enumerate_for_fetch(
-            git_hash_oid,
-            &mut oids_for_fetch,
-            repo,
-            ipfs,
-            chain_api,
+        error!(format!(
+            "Could not read any version of the repository index of IPS {}:\n    {}",
             ips_id,
-        )
-        .await?;
+            failures.join("\n    ")
+        ))
+    }
 
-        self.fetch_git_objects(&oids_for_fetch, repo, ipfs, chain_api, ips_id)
-            .await?;
+    pub async fn from_ipfs(
+        ipfs_hash: H256,
+        store: &dyn ObjectStore,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::from_cid(&generate_cid(ipfs_hash)?.to_string(), store).await
+    }
 
-        match repo.odb()?.read_header(git_hash_oid)?.1 {
-            ObjectType::Commit if ref_name.starts_with("refs/tags") => {
-                debug!("Not setting ref for lightweight tag {}", ref_name);
-            }
-            ObjectType::Commit => {
-                repo.reference(ref_name, git_hash_oid, true, "inv4-git fetch")?;
-            }
-            // Somehow git is upset when we set tag refs for it
-            ObjectType::Tag => {
-                debug!("Not setting ref for tag {}", ref_name);
-            }
-            other_type => {
-                let msg = format!("New tip turned out to be a {} after fetch", other_type);
-                debug!("{}", msg);
-                return Err(msg.into());
-            }
+    pub async fn from_cid(cid: &str, store: &dyn ObjectStore) -> Result<Self, Box<dyn Error>> {
+        match decode_artifact(store.get(cid).await?, Some(Kind::RepoData))? {
+            Artifact::RepoData(repo_data) => Ok(repo_data),
+            other => error!(format!("{} holds a {}, not a RepoData", cid, other.name())),
         }
+    }
 
-        debug!("Fetched {} for {} OK.", git_hash, ref_name);
-        Ok(())
+    /// The branch a fresh clone would check out: what the `HEAD` symref points at, else `main`,
+    /// `master`, or else the first branch.
+    pub fn default_branch(&self) -> Option<&str> {
+        let head = self
+            .symrefs
+            .get("HEAD")
+            .map(String::as_str)
+            .filter(|target| self.refs.contains_key(*target));
+
+        head.or_else(|| {
+            ["refs/heads/main", "refs/heads/master"]
+                .into_iter()
+                .find(|name| self.refs.contains_key(*name))
+        })
+        .or_else(|| {
+            self.refs
+                .keys()
+                .map(String::as_str)
+                .find(|name| name.starts_with("refs/heads/"))
+        })
     }
 
-    pub async fn enumerate_for_fetch(
+    /// Every ref with its type, peeled target and the block of the push that set it.
+    pub fn ref_listing(&self) -> Vec<RefListing> {
+        self.refs
+            .iter()
+            .map(|(name, git_hash)| {
+                let target = self.ref_targets.get(name);
+                let block_number = self
+                    .history
+                    .iter()
+                    .rev()
+                    .find(|record| {
+                        record.refs_changed.iter().any(|change| {
+                            change.name == *name && change.new.as_ref() == Some(git_hash)
+                        })
+                    })
+                    .map(|record| record.block_number);
+
+                RefListing {
+                    name: name.clone(),
+                    target: git_hash.clone(),
+                    kind: target.map_or_else(
+                        || "unknown".to_owned(),
+                        |target| target.kind.object_type().to_string(),
+                    ),
+                    peeled: target.and_then(|target| target.peeled.clone()),
+                    block_number,
+                    symref: self.symrefs.get(name).cloned(),
+                }
+            })
+            .collect()
+    }
+
+    /// How many commits reachable from `old` are not reachable from `new`, i.e. what moving a ref
+    /// from `old` to `new` would drop.
+    ///
+    /// Uses local history when `old` is present, and the commit metadata in RepoData otherwise.
+    pub async fn unreachable_commits(
         &self,
-        oid: Oid,
-        fetch_todo: &mut HashSet<Oid>,
+        old: Oid,
+        new: Oid,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        ips_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut stack = vec![oid];
-        let mut multi_objects: BTreeMap<String, MultiObject> = BTreeMap::new();
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+    ) -> BoxResult<usize> {
+        if repo.odb()?.read_header(old).is_ok() {
+            let mut walk = repo.revwalk()?;
+            walk.push(old)?;
+            walk.hide(new)?;
+
+            return Ok(walk.count());
+        }
 
-        while let Some(oid) = stack.pop() {
-            if repo.odb()?.read_header(oid).is_ok() {
-                debug!("Object {} already present locally!", oid);
+        let mut headers: BTreeMap<String, MultiObjectHeader> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![old.to_string()];
+        let mut unreachable = 0;
+
+        while let Some(git_hash) = stack.pop() {
+            if !seen.insert(git_hash.clone()) {
                 continue;
             }
 
-            if fetch_todo.contains(&oid) {
-                debug!("Object {} already present in state!", oid);
+            let oid: Oid = git_hash.parse()?;
+            if repo.odb()?.read_header(oid).is_ok() && reachable_from_any(repo, oid, &[new]) {
                 continue;
             }
 
-            let multi_object_hash = self
-                .objects
-                .get(&format!("{}", oid))
-                .ok_or_else(|| {
-                    let msg = format!("Could not find object {} in the index", oid);
-                    debug!("{}", msg);
-                    msg
-                })?
-                .clone();
+            let multi_object_hash = match self.objects.get(&git_hash) {
+                Some(hash) if hash != SUBMODULE_TIP_MARKER && hash != LARGE_BLOB_MARKER => {
+                    hash.clone()
+                }
+                _ => continue,
+            };
 
-            if multi_object_hash == SUBMODULE_TIP_MARKER {
-                debug!("Ommitting submodule {}", oid.to_string());
-                return Ok(());
+            if !headers.contains_key(&multi_object_hash) {
+                let header = MultiObject::get_header(
+                    &self.multi_object_cid(&multi_object_hash, index).await?,
+                    store,
+                )
+                .await?;
+                headers.insert(multi_object_hash.clone(), header);
             }
 
-            fetch_todo.insert(oid);
-
-            let multi_object = if let Some(m) = multi_objects.get(&multi_object_hash) {
-                m.clone()
-            } else {
-                let m = MultiObject::chain_get(multi_object_hash.clone(), ipfs, chain_api, ips_id)
-                    .await?;
-                multi_objects.insert(multi_object_hash, m.clone());
-                m
-            };
-
-            match multi_object
-                .objects
-                .get(&oid.to_string())
-                .expect("Oid not found in MultiObject")
-                .clone()
-                .metadata
+            match headers[&multi_object_hash]
+                .entries
+                .get(&git_hash)
+                .map(|entry| &entry.metadata)
             {
-                GitObjectMetadata::Commit {
-                    parent_git_hashes,
-                    tree_git_hash,
-                } => {
-                    stack.push(Oid::from_str(&tree_git_hash)?);
-
-                    for parent_git_hash in parent_git_hashes {
-                        stack.push(Oid::from_str(&parent_git_hash)?);
-                    }
+                Some(GitObjectMetadata::Commit {
+                    parent_git_hashes, ..
+                }) => {
+                    unreachable += 1;
+                    stack.extend(parent_git_hashes.iter().cloned());
                 }
-                GitObjectMetadata::Tag { target_git_hash } => {
-                    stack.push(Oid::from_str(&target_git_hash)?);
-                }
-                GitObjectMetadata::Tree { entry_git_hashes } => {
-                    for entry_git_hash in entry_git_hashes {
-                        stack.push(Oid::from_str(&entry_git_hash)?);
-                    }
+                Some(GitObjectMetadata::Tag { target_git_hash }) => {
+                    stack.push(target_git_hash.clone())
                 }
-                GitObjectMetadata::Blob => {}
+                _ => {}
             }
         }
 
-        Ok(())
+        Ok(unreachable)
     }
 
-    pub async fn push_git_objects(
-        &mut self,
-        oids: &HashSet<Oid>,
+    /// Whether `ancestor` is `tip` or in its history, like `git merge-base --is-ancestor`.
+    ///
+    /// Local objects are used where present and the commit metadata in RepoData otherwise, so
+    /// `tip` needn't be fetched yet. Annotated tags are looked through.
+    pub async fn is_ancestor(
+        &self,
+        ancestor: Oid,
+        tip: Oid,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        signer: &PairSigner<PolkadotConfig, Pair>,
-    ) -> Result<u64, Box<dyn Error>> {
-        eprintln!("Minting 2 IPFs");
-
-        let mut multi_object = MultiObject {
-            hash: String::new(),
-            git_hashes: vec![],
-            objects: BTreeMap::new(),
-        };
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+    ) -> BoxResult<bool> {
+        let ancestor_local = repo.odb()?.read_header(ancestor).is_ok();
+        let mut headers: BTreeMap<String, MultiObjectHeader> = BTreeMap::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![tip];
 
-        for oid in oids {
-            let obj = repo.find_object(*oid, None)?;
-            debug!("Current object: {:?} at {}", obj.kind(), obj.id());
+        while let Some(oid) = stack.pop() {
+            if oid == ancestor {
+                return Ok(true);
+            }
 
-            if self.objects.contains_key(&obj.id().to_string()) {
-                debug!("push_objects: Object {} already in RepoData", obj.id());
+            if !seen.insert(oid) {
                 continue;
             }
 
-            let obj_type = obj.kind().ok_or_else(|| {
-                let msg = format!("Cannot determine type of object {}", obj.id());
-                debug!("{}", msg);
-                msg
-            })?;
-
-            match obj_type {
-                ObjectType::Commit => {
-                    let commit = obj
-                        .as_commit()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a commit", obj))
-                        .unwrap();
-                    debug!("Pushing commit {:?}", commit);
-
-                    multi_object.add(GitObject::from_git_commit(commit, &repo.odb()?)?);
+            if let Ok(object) = repo.find_object(oid, None) {
+                // Local history is complete, so the graph answers for all of it at once
+                if ancestor_local {
+                    if is_fast_forward(repo, ancestor, oid)? {
+                        return Ok(true);
+                    }
+                    continue;
                 }
-                ObjectType::Tree => {
-                    let tree = obj
-                        .as_tree()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a tree", obj))
-                        .unwrap();
-                    debug!("Pushing tree {:?}", tree);
 
-                    multi_object.add(GitObject::from_git_tree(tree, &repo.odb()?)?);
+                if let Some(commit) = object.as_commit() {
+                    stack.extend(commit.parent_ids());
+                } else if let Some(tag) = object.as_tag() {
+                    stack.push(tag.target_id());
                 }
-                ObjectType::Blob => {
-                    let blob = obj
-                        .as_blob()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a blob", obj))
-                        .unwrap();
-                    debug!("Pushing blob {:?}", blob);
+                continue;
+            }
 
-                    multi_object.add(GitObject::from_git_blob(blob, &repo.odb()?)?);
+            let multi_object_hash = match self.objects.get(&oid.to_string()) {
+                Some(hash) if hash != SUBMODULE_TIP_MARKER && hash != LARGE_BLOB_MARKER => {
+                    hash.clone()
                 }
-                ObjectType::Tag => {
-                    let tag = obj
-                        .as_tag()
-                        .ok_or_else(|| eprintln!("Could not view {:?} as a tag", obj))
-                        .unwrap();
-                    debug!("Pushing tag {:?}", tag);
+                _ => continue,
+            };
+
+            if !headers.contains_key(&multi_object_hash) {
+                let header = MultiObject::get_header(
+                    &self.multi_object_cid(&multi_object_hash, index).await?,
+                    store,
+                )
+                .await?;
+                headers.insert(multi_object_hash.clone(), header);
+            }
 
-                    multi_object.add(GitObject::from_git_tag(tag, &repo.odb()?)?);
+            match headers[&multi_object_hash]
+                .entries
+                .get(&oid.to_string())
+                .map(|entry| &entry.metadata)
+            {
+                Some(GitObjectMetadata::Commit {
+                    parent_git_hashes, ..
+                }) => {
+                    for parent in parent_git_hashes {
+                        stack.push(parent.parse()?);
+                    }
                 }
-                other => {
-                    return Err(format!("Don't know how to traverse a {}", other).into());
+                Some(GitObjectMetadata::Tag { target_git_hash }) => {
+                    stack.push(target_git_hash.parse()?)
                 }
+                _ => {}
             }
         }
 
-        multi_object.hash = xxh3::hash64(multi_object.git_hashes.encode().as_slice()).to_string();
+        Ok(false)
+    }
 
-        for oid in multi_object.git_hashes.clone() {
-            self.objects.insert(oid, multi_object.hash.clone());
+    /// How many commits `local` has that the remote lacks, and how many the remote ref at
+    /// `remote` has that `local` lacks.
+    ///
+    /// Exact when `remote` is present locally. Otherwise the remote side is counted from the
+    /// commit metadata in RepoData, and local commits count as lacking only if the remote has
+    /// them under no ref at all.
+    pub async fn divergence(
+        &self,
+        local: Oid,
+        remote: Oid,
+        repo: &Repository,
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+    ) -> BoxResult<(usize, usize)> {
+        if repo.odb()?.read_header(remote).is_ok() {
+            return Ok(repo.graph_ahead_behind(local, remote)?);
         }
 
-        debug!("Pushing MultiObject to IPFS");
-
-        let data = compress_data(multi_object.encode());
-
-        #[cfg(not(feature = "crust"))]
-        let ipfs_hash = ipfs.add(std::io::Cursor::new(data)).await?.hash;
+        let behind = self
+            .unreachable_commits(remote, local, repo, store, index)
+            .await?;
 
-        #[cfg(feature = "crust")]
-        let ipfs_hash = crate::crust::send_to_crust(signer, data).await?;
+        let mut walk = repo.revwalk()?;
+        walk.push(local)?;
 
-        debug!("Sending MultiObject to the chain");
+        let mut ahead = 0;
+        for oid in walk {
+            if !self.objects.contains_key(&oid?.to_string()) {
+                ahead += 1;
+            }
+        }
 
-        let ipf_mint_tx = tinkernet::tx().ipf().mint(
-            multi_object.hash.as_bytes().to_vec(),
-            H256::from_slice(&Cid::try_from(ipfs_hash)?.to_bytes()[2..]),
-        );
+        Ok((ahead, behind))
+    }
 
-        let events = chain_api
-            .tx()
-            .sign_and_submit_then_watch_default(&ipf_mint_tx, signer)
-            .await?
-            .wait_for_in_block()
-            .await?;
+    /// Hex blake2-256 of the SCALE encoding, which tells two versions apart.
+    pub fn identity(&self) -> String {
+        hex::encode(blake2_256(&self.encode()))
+    }
 
-        let ipf_id = events
-            .fetch_events()
-            .await?
-            .find_first::<tinkernet::ipf::events::Minted>()?
-            .unwrap()
-            .1;
+    /// What's worth logging about it: counts, identity and HEAD's tip, but no ref names, which
+    /// some consider private.
+    pub fn summary(&self) -> String {
+        let head = self
+            .default_branch()
+            .and_then(|head| self.refs.get(head))
+            .map_or("none", String::as_str);
+
+        format!(
+            "{} refs, {} objects, {} pushes logged, identity {}, HEAD at {}",
+            self.refs.len(),
+            self.objects.len(),
+            self.history.len(),
+            self.identity(),
+            head
+        )
+    }
 
-        events.wait_for_success().await?;
+    /// The compressed form that gets uploaded, along with its size.
+    pub fn compressed(&self) -> (Vec<u8>, RepoDataSize) {
+        let encoded = self.encode();
+        let encoded_len = encoded.len();
+        let data = encode_artifact(Kind::RepoData, encoded);
 
-        eprintln!("Minted Git Objects on-chain with IPF ID: {}", ipf_id);
+        let size = RepoDataSize {
+            encoded: encoded_len,
+            compressed: data.len(),
+        };
 
-        Ok(ipf_id)
+        (data, size)
     }
 
-    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
-    pub async fn fetch_git_objects(
-        &self,
-        oids: &HashSet<Oid>,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
-        ips_id: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut fetched_objects = BTreeMap::new();
-
-        let objects_deduped = {
-            let mut o = self.objects.values().collect::<Vec<&String>>();
-            o.sort();
-            o.dedup();
-            o
-        };
+    /// Append a push to the history, dropping the oldest entries past the limit.
+    pub fn record_push(&mut self, record: PushRecord) {
+        self.history.push(record);
 
-        for object_hash in objects_deduped {
-            let mut multi_object =
-                MultiObject::chain_get(object_hash.clone(), ipfs, chain_api, ips_id).await?;
+        let excess = self.history.len().saturating_sub(PUSH_HISTORY_LIMIT);
+        self.history.drain(..excess);
+    }
 
-            fetched_objects.append(&mut multi_object.objects)
+    /// Keep `ref_dst` symbolic when the pushed `ref_src` is, e.g. `refs/remotes/origin/HEAD` in a
+    /// mirror, and direct otherwise.
+    ///
+    /// `HEAD` itself is never pushed, so it is recorded when the branch the local `HEAD` is on is
+    /// pushed and would be the default branch anyway, which keeps it from moving later.
+    fn record_symref(&mut self, repo: &Repository, ref_src: &str, ref_dst: &str) {
+        let target = repo
+            .find_reference(ref_src)
+            .ok()
+            .filter(|reference| reference.kind() == Some(git2::ReferenceType::Symbolic))
+            .and_then(|reference| reference.symbolic_target().map(str::to_owned));
+
+        match target {
+            Some(target) if ref_src != "HEAD" => {
+                debug!("{} is a symbolic ref to {}", ref_dst, target);
+                self.symrefs.insert(ref_dst.to_owned(), target);
+            }
+            _ => {
+                self.symrefs.remove(ref_dst);
+            }
         }
 
-        for (i, &oid) in oids.iter().enumerate() {
-            debug!("[{}/{}] Fetching object {}", i + 1, oids.len(), oid);
-
-            let git_object = fetched_objects
-                .get(&format!("{}", oid))
-                .ok_or_else(|| {
-                    let msg = format!("Could not find object {} in the index", oid);
-                    debug!("{}", msg);
-                    msg
-                })?
-                .clone();
+        let on_head = ref_src == "HEAD"
+            || repo
+                .find_reference("HEAD")
+                .ok()
+                .and_then(|head| head.symbolic_target().map(|target| target == ref_src))
+                .unwrap_or(false);
+
+        if !self.symrefs.contains_key("HEAD")
+            && on_head
+            && ref_dst.starts_with("refs/heads/")
+            && self
+                .default_branch()
+                .map_or(true, |branch| branch == ref_dst)
+        {
+            debug!("Recording HEAD as {}", ref_dst);
+            self.symrefs.insert("HEAD".to_owned(), ref_dst.to_owned());
+        }
+    }
 
-            if repo.odb()?.read_header(oid).is_ok() {
-                debug!("fetch objects: Object {} already present locally!", oid);
+    /// Point each symbolic ref but `HEAD` at its target's sha1 again after a push moved targets,
+    /// for readers that only know direct refs.
+    pub fn follow_symrefs(&mut self) {
+        for (name, target) in &self.symrefs {
+            if name == "HEAD" {
                 continue;
             }
 
-            let written_oid = repo.odb()?.write(
-                match git_object.metadata {
-                    GitObjectMetadata::Blob => ObjectType::Blob,
-                    GitObjectMetadata::Commit { .. } => ObjectType::Commit,
-                    GitObjectMetadata::Tag { .. } => ObjectType::Tag,
-                    GitObjectMetadata::Tree { .. } => ObjectType::Tree,
-                },
-                &git_object.data,
-            )?;
-            if written_oid != oid {
-                let msg = format!(
-                    "Object tree inconsistency detected: fetched {}, but write result hashes to {}",
-                    oid, written_oid
-                );
-                debug!("{}", msg);
-                return Err(msg.into());
+            if let Some(git_hash) = self.refs.get(target).cloned() {
+                self.refs.insert(name.clone(), git_hash);
             }
-            debug!("Fetched object {}", written_oid);
         }
-        Ok(())
     }
 
-    pub async fn mint_return_new_old_id(
-        &self,
-        ipfs: &mut IpfsClient,
-        chain_api: &OnlineClient<PolkadotConfig>,
+    /// Put `hint` first, replacing an earlier hint for the same node and dropping the least
+    /// recent ones past the limit.
+    pub fn record_swarm_hint(&mut self, hint: SwarmHint) {
+        self.swarm_hints
+            .retain(|other| other.peer_id != hint.peer_id);
+        self.swarm_hints.insert(0, hint);
+        self.swarm_hints.truncate(SWARM_HINT_LIMIT);
+    }
+
+    /// Drop `name` from the index, with its kind and symref target if it has them.
+    pub fn delete_ref(&mut self, name: &str) {
+        debug!("Removing ref {} from index", name);
+        self.ref_targets.remove(name);
+        self.symrefs.remove(name);
+        if self.refs.remove(name).is_none() {
+            debug!(
+                "Nothing to delete, ref {} not part of the index ref set",
+                name
+            );
+            debug!("{} refs available", self.refs.len());
+        }
+    }
+
+    pub async fn push_ref_from_str(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &mut Repository,
+        store: &dyn ObjectStore,
+        chain: &Chain,
         signer: &PairSigner<PolkadotConfig, Pair>,
-        ips_id: u32,
-    ) -> Result<(u64, Option<u64>), Box<dyn Error>> {
-        let data = compress_data(self.encode());
+        ips_id: IpsId,
+        known_tips: &[Oid],
+        packer: &dyn Packer,
+        large_blob_threshold: u64,
+        limits: Limits,
+        journal: &PushJournal,
+        minted: &mut Vec<IpfId>,
+    ) -> Result<(), Box<dyn Error>> {
+        // Deleting `ref_dst` was requested
+        if ref_src.is_empty() {
+            self.delete_ref(ref_dst);
+            return Ok(());
+        }
+        // Push exactly what the source points at: an annotated tag (possibly of another tag), or
+        // the commit, tree or blob behind a lightweight ref, a detached HEAD or a bare oid
+        let obj = repo.find_object(resolve_push_source(repo, ref_src)?, None)?;
 
-        #[cfg(not(feature = "crust"))]
-        let ipfs_hash = ipfs.add(std::io::Cursor::new(data)).await?.hash;
+        debug!("{} dereferenced to {:?} {}", ref_src, obj.kind(), obj.id());
 
-        #[cfg(feature = "crust")]
-        let ipfs_hash = crate::crust::send_to_crust(signer, data).await?;
+        self.record_symref(repo, ref_src, ref_dst);
 
-        let ipf_mint_tx = tinkernet::tx().ipf().mint(
-            b"RepoData".to_vec(),
-            H256::from_slice(&Cid::try_from(ipfs_hash)?.to_bytes()[2..]),
-        );
+        if self.refs.get(ref_dst) == Some(&obj.id().to_string()) {
+            debug!("{} is already at {}", ref_dst, obj.id());
+            return Ok(());
+        }
 
-        let events = chain_api
-            .tx()
-            .sign_and_submit_then_watch_default(&ipf_mint_tx, signer)
-            .await?
-            .wait_for_in_block()
-            .await?;
+        // History below a shallow boundary can't be uploaded, so the remote must already have it
+        for boundary in shallow_boundary(repo)? {
+            let on_remote = self.objects.contains_key(&boundary.to_string())
+                || repo
+                    .find_commit(boundary)?
+                    .parent_ids()
+                    .all(|parent| self.objects.contains_key(&parent.to_string()));
 
-        let new_ipf_id = events
-            .fetch_events()
-            .await?
-            .find_first::<tinkernet::ipf::events::Minted>()?
+            if !on_remote {
+                error!("cannot push from a shallow clone; run git fetch --unshallow")
+            }
+        }
+
+        if force {
+            output::status(format!("Force-pushing {}", ref_dst));
+        }
+
+        let mut objs_for_push = HashSet::new();
+        let mut submodules_for_push = HashSet::new();
+
+        self.enumerate_for_push(
+            &obj.clone(),
+            &mut objs_for_push,
+            &mut submodules_for_push,
+            repo,
+            known_tips,
+            limits,
+        )?;
+
+        let odb = repo.odb()?;
+        events::emit(Event::Enumerated {
+            ref_name: ref_dst.to_owned(),
+            objects: objs_for_push.len(),
+            bytes: objs_for_push
+                .iter()
+                .filter_map(|&oid| odb.read_header(oid).ok())
+                .map(|(size, _)| size as u64)
+                .sum(),
+            pushing: true,
+        });
+
+        self.push_git_objects(
+            &objs_for_push,
+            repo,
+            store,
+            chain,
+            signer,
+            ips_id,
+            packer,
+            large_blob_threshold,
+            journal,
+            minted,
+        )
+        .await?;
+
+        for submod_oid in submodules_for_push {
+            self.objects
+                .insert(submod_oid.to_string(), SUBMODULE_TIP_MARKER.to_owned());
+        }
+
+        let kind = obj
+            .kind()
+            .ok_or_else(|| format!("Cannot determine type of object {}", obj.id()))?;
+        let peeled = match kind {
+            ObjectType::Tag => Some(obj.peel(ObjectType::Any)?.id().to_string()),
+            _ => None,
+        };
+
+        self.refs
+            .insert(ref_dst.to_owned(), format!("{}", obj.id()));
+        self.ref_targets.insert(
+            ref_dst.to_owned(),
+            RefTarget {
+                kind: ObjectKind::from_object_type(kind)?,
+                peeled,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn enumerate_for_push(
+        &self,
+        obj: &Object,
+        push_todo: &mut HashSet<Oid>,
+        submodules: &mut HashSet<Oid>,
+        repo: &Repository,
+        known_tips: &[Oid],
+        limits: Limits,
+    ) -> Result<(), Box<dyn Error>> {
+        // Object tree traversal state
+        let mut walk = Walk::new(limits, "push");
+        walk.push(obj.id(), obj.clone())?;
+
+        let mut obj_cnt = 1;
+        while let Some(obj) = walk.next() {
+            if self.objects.contains_key(&obj.id().to_string()) {
+                debug!("Object {} already in RepoData", obj.id());
+                continue;
+            }
+
+            if push_todo.contains(&obj.id()) {
+                debug!("Object {} already in state", obj.id());
+                continue;
+            }
+
+            let obj_type = obj.kind().ok_or_else(|| {
+                let msg = format!("Cannot determine type of object {}", obj.id());
+                debug!("{}", msg);
+                msg
+            })?;
+
+            // The remote already has these commits and everything behind them
+            if obj_type == ObjectType::Commit && reachable_from_any(repo, obj.id(), known_tips) {
+                debug!("Commit {} is known to the remote", obj.id());
+                continue;
+            }
+
+            push_todo.insert(obj.id());
+            walk.expand(obj.id())?;
+
+            match obj_type {
+                ObjectType::Commit => {
+                    let commit = obj
+                        .as_commit()
+                        .ok_or_else(|| format!("Could not view {} as a commit", obj.id()))?;
+                    debug!("[{}] Counting commit {:?}", obj_cnt, commit);
+
+                    let tree_obj = obj.peel(ObjectType::Tree)?;
+                    debug!("Commit {}: Handling tree {}", commit.id(), tree_obj.id());
+
+                    walk.push(tree_obj.id(), tree_obj)?;
+
+                    for parent_id in commit.parent_ids() {
+                        match repo.find_object(parent_id, None) {
+                            Ok(parent) => {
+                                debug!(
+                                    "Commit {}: Pushing parent commit {}",
+                                    commit.id(),
+                                    parent_id
+                                );
+                                walk.push(parent_id, parent)?;
+                            }
+                            // Shallow boundaries and grafts: like submodule tips, present
+                            // remotely but not traversed locally
+                            Err(_) if self.objects.contains_key(&parent_id.to_string()) => {
+                                debug!(
+                                    "Commit {}: Parent {} is missing locally but already in RepoData",
+                                    commit.id(),
+                                    parent_id
+                                );
+                            }
+                            Err(_) => error!(format!(
+                                "Parent {} of commit {} is missing locally and on the remote; cannot push from a shallow clone; run git fetch --unshallow",
+                                parent_id,
+                                commit.id()
+                            )),
+                        }
+                    }
+                }
+                ObjectType::Tree => {
+                    let tree = obj
+                        .as_tree()
+                        .ok_or_else(|| format!("Could not view {} as a tree", obj.id()))?;
+                    debug!("[{}] Counting tree {:?}", obj_cnt, tree);
+
+                    for entry in tree.into_iter() {
+                        match entry.kind() {
+                            // Weed out submodules (Implicitly known as commit children of tree objects)
+                            Some(ObjectType::Commit) => {
+                                debug!("Skipping submodule at {}", entry.id());
+
+                                submodules.insert(entry.id());
+
+                                continue;
+                            }
+                            // Blobs link to nothing, so they are only read once packed
+                            Some(ObjectType::Blob) => {
+                                if !self.objects.contains_key(&entry.id().to_string()) {
+                                    push_todo.insert(entry.id());
+                                }
+
+                                continue;
+                            }
+                            _ => {}
+                        }
+
+                        debug!(
+                            "Tree {}: Pushing tree entry {} ({:?})",
+                            tree.id(),
+                            entry.id(),
+                            entry.kind()
+                        );
+
+                        walk.push(
+                            entry.id(),
+                            entry.to_object(repo).map_err(|e| vanished(entry.id(), e))?,
+                        )?;
+                    }
+                }
+                ObjectType::Blob => {
+                    let blob = obj
+                        .as_blob()
+                        .ok_or_else(|| format!("Could not view {} as a blob", obj.id()))?;
+                    debug!("[{}] Counting blob {:?}", obj_cnt, blob);
+                }
+                ObjectType::Tag => {
+                    let tag = obj
+                        .as_tag()
+                        .ok_or_else(|| format!("Could not view {} as a tag", obj.id()))?;
+                    debug!("[{}] Counting tag {:?}", obj_cnt, tag);
+
+                    walk.push(tag.target_id(), tag.target()?)?;
+                }
+                other => {
+                    return Err(format!("Don't know how to traverse a {}", other).into());
+                }
+            }
+
+            obj_cnt += 1;
+        }
+        Ok(())
+    }
+
+    /// Fetch the objects behind `git_hash`, failing on the first corrupt object unless a
+    /// `quarantine` collects them. In that case `ref_name` is recorded as failed when it needs
+    /// a quarantined object.
+    ///
+    /// With `refetch`, objects already present are checked and corrupt ones replaced too. With
+    /// `filter`, blobs it leaves out aren't fetched, and the objects that are go into a
+    /// promisor pack as well. With `verify_fetch`, what was fetched is checked to be complete
+    /// with `verify_fetched` before the fetch counts as done. Both walks stay within the
+    /// traversal limits of `config`.
+    pub async fn fetch_to_ref_from_str(
+        &self,
+        git_hash: &str,
+        ref_name: &str,
+        repo: &mut Repository,
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+        mut quarantine: Option<&mut Quarantine>,
+        mut refetch: Option<&mut Refetch>,
+        mut filter: Option<&mut BlobFilter>,
+        config: &Config,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!("Fetching {} for {}", git_hash, ref_name);
+
+        let git_hash_oid = Oid::from_str(git_hash)?;
+        let limits = Limits::from_config(config);
+        let mut oids_for_fetch = HashSet::new();
+        let repaired_before = refetch.as_ref().map_or(0, |refetch| refetch.repaired);
+
+        let size = self
+            .enumerate_for_fetch(
+                git_hash_oid,
+                &mut oids_for_fetch,
+                repo,
+                store,
+                index,
+                refetch.as_deref_mut(),
+                filter.as_deref_mut(),
+                limits,
+            )
+            .await?;
+
+        // Everything collected that wasn't corrupt was missing
+        if let Some(refetch) = refetch.as_deref_mut() {
+            refetch.downloaded += oids_for_fetch.len() - (refetch.repaired - repaired_before);
+        }
+
+        events::emit(Event::Enumerated {
+            ref_name: ref_name.to_owned(),
+            objects: oids_for_fetch.len(),
+            bytes: size,
+            pushing: false,
+        });
+
+        self.fetch_git_objects(
+            &oids_for_fetch,
+            repo,
+            store,
+            index,
+            quarantine.as_deref_mut(),
+        )
+        .await?;
+
+        if filter.is_some() {
+            filter::write_promisor_pack(repo, &oids_for_fetch)?;
+        }
+
+        if let Some(quarantine) = quarantine {
+            if quarantine.affects(&oids_for_fetch) {
+                quarantine.failed_refs.push(ref_name.to_owned());
+                return Ok(());
+            }
+        }
+
+        // Only objects are written: git updates its own refs, remote-tracking or not, from the
+        // fetch results, so creating `ref_name` here would invent refs in the wrong namespace
+        let kind = repo.odb()?.read_header(git_hash_oid)?.1;
+        if ref_name.starts_with("refs/heads/") && kind != ObjectType::Commit {
+            error!(format!(
+                "{} points at a {} rather than a commit",
+                ref_name, kind
+            ))
+        }
+
+        if config.verify_fetch {
+            self.verify_fetched(
+                git_hash_oid,
+                ref_name,
+                &oids_for_fetch,
+                repo,
+                filter.is_some(),
+                limits,
+            )?;
+        }
+
+        debug!("Fetched {} for {} OK.", git_hash, ref_name);
+        Ok(())
+    }
+
+    /// Check that every object `root` reaches through the objects just fetched is in `repo`,
+    /// so a ref isn't reported fetched when git would find a hole in it at checkout.
+    ///
+    /// Only the region in `fetched` is walked: objects outside it were present before, and just
+    /// their presence is checked. Blobs are checked by their header. With `filtered`, missing
+    /// blobs were left out on purpose and are skipped.
+    fn verify_fetched(
+        &self,
+        root: Oid,
+        ref_name: &str,
+        fetched: &HashSet<Oid>,
+        repo: &Repository,
+        filtered: bool,
+        limits: Limits,
+    ) -> BoxResult<()> {
+        let odb = repo.odb()?;
+        let mut walk: Walk<(Oid, Option<ObjectType>, Option<Oid>)> =
+            Walk::new(limits, "fetch check");
+        walk.push(root, (root, None, None))?;
+        let mut seen = HashSet::new();
+
+        while let Some((oid, kind, referrer)) = walk.next() {
+            if !seen.insert(oid) {
+                continue;
+            }
+
+            let present = match kind {
+                Some(ObjectType::Blob) => odb.read_header(oid).is_ok(),
+                _ if oid != root && !fetched.contains(&oid) => odb.exists(oid),
+                _ => {
+                    let object = match repo.find_object(oid, kind) {
+                        Ok(object) => object,
+                        Err(_) => return Err(self.missing_object(oid, kind, referrer, ref_name)),
+                    };
+
+                    walk.expand(oid)?;
+                    if let Some(commit) = object.as_commit() {
+                        let tree = commit.tree_id();
+                        walk.push(tree, (tree, Some(ObjectType::Tree), Some(oid)))?;
+                        for parent in commit.parent_ids() {
+                            walk.push(parent, (parent, Some(ObjectType::Commit), Some(oid)))?;
+                        }
+                    } else if let Some(tree) = object.as_tree() {
+                        for entry in tree.iter() {
+                            // Submodule commits live in another repository
+                            if entry.filemode() == i32::from(FileMode::Commit) {
+                                continue;
+                            }
+                            walk.push(entry.id(), (entry.id(), entry.kind(), Some(oid)))?;
+                        }
+                    } else if let Some(tag) = object.as_tag() {
+                        let target = tag.target_id();
+                        walk.push(target, (target, tag.target_type(), Some(oid)))?;
+                    }
+                    true
+                }
+            };
+
+            if !present && !(filtered && kind == Some(ObjectType::Blob)) {
+                return Err(self.missing_object(oid, kind, referrer, ref_name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The error for `oid` missing after a fetch of `ref_name`, naming where it should have come
+    /// from.
+    fn missing_object(
+        &self,
+        oid: Oid,
+        kind: Option<ObjectType>,
+        referrer: Option<Oid>,
+        ref_name: &str,
+    ) -> Box<dyn Error> {
+        let kind = kind.map_or("object".to_owned(), |kind| kind.to_string());
+        let referrer = referrer.map_or(String::new(), |referrer| {
+            format!(", referenced by {},", referrer)
+        });
+        let source = match self.objects.get(&oid.to_string()) {
+            Some(hash) if hash == LARGE_BLOB_MARKER => {
+                "the repository index lists it as a large blob uploaded on its own".to_owned()
+            }
+            Some(hash) => format!("it should have come from MultiObject {}", hash),
+            None => "the repository index doesn't list it, so no MultiObject holds it".to_owned(),
+        };
+
+        format!(
+            "{}: {} {}{} is missing after the fetch; {}",
+            ref_name, kind, oid, referrer, source
+        )
+        .into()
+    }
+
+    /// Collect the objects reachable from `oid` that are missing locally into `fetch_todo`,
+    /// returning their total size. Only MultiObject headers are decoded.
+    ///
+    /// Without `refetch`, the walk stops at objects already present, trusting that whatever they
+    /// reach is present too. With it, present objects are hashed, and the walk goes on past the
+    /// intact ones. Corrupt loose objects are removed and collected like missing ones.
+    ///
+    /// Blobs `filter` leaves out are skipped, unless `oid` itself is one.
+    pub async fn enumerate_for_fetch(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashSet<Oid>,
+        repo: &Repository,
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+        mut refetch: Option<&mut Refetch>,
+        mut filter: Option<&mut BlobFilter>,
+        limits: Limits,
+    ) -> Result<u64, Box<dyn Error>> {
+        let root = oid;
+        let mut walk = Walk::new(limits, "fetch");
+        walk.push(oid, oid)?;
+        let mut headers: BTreeMap<String, MultiObjectHeader> = BTreeMap::new();
+        let mut size = 0;
+        let mut checked = HashSet::new();
+
+        while let Some(oid) = walk.next() {
+            let mut intact = false;
+
+            if repo.odb()?.read_header(oid).is_ok() {
+                let refetch = match refetch.as_deref_mut() {
+                    Some(refetch) => refetch,
+                    None => {
+                        debug!("Object {} already present locally!", oid);
+                        continue;
+                    }
+                };
+
+                if !checked.insert(oid) {
+                    continue;
+                }
+
+                if repair::is_intact(repo, oid) {
+                    refetch.verified += 1;
+                    intact = true;
+                } else if repair::remove_loose_object(repo, oid)? {
+                    output::warn(format!("Object {} is corrupt; downloading it again", oid));
+                    refetch.repaired += 1;
+                } else {
+                    refetch.unrepairable.push(oid);
+                    continue;
+                }
+            }
+
+            if fetch_todo.contains(&oid) {
+                debug!("Object {} already present in state!", oid);
+                continue;
+            }
+
+            let multi_object_hash = self
+                .objects
+                .get(&format!("{}", oid))
+                .ok_or_else(|| {
+                    let msg = format!("Could not find object {} in the index", oid);
+                    debug!("{}", msg);
+                    msg
+                })?
+                .clone();
+
+            if multi_object_hash == SUBMODULE_TIP_MARKER {
+                debug!("Ommitting submodule {}", oid.to_string());
+                continue;
+            }
+
+            if multi_object_hash == LARGE_BLOB_MARKER {
+                let blob_size = self
+                    .large_blobs
+                    .get(&oid.to_string())
+                    .ok_or_else(|| format!("Large blob {} missing from the index", oid))?
+                    .size;
+                let skipped = match filter.as_deref_mut() {
+                    Some(filter) if oid != root && !intact => filter.skip(oid, blob_size),
+                    _ => false,
+                };
+
+                if !intact && !skipped {
+                    fetch_todo.insert(oid);
+                    size += blob_size;
+                }
+                continue;
+            }
+
+            if !headers.contains_key(&multi_object_hash) {
+                let header = MultiObject::get_header(
+                    &self.multi_object_cid(&multi_object_hash, index).await?,
+                    store,
+                )
+                .await?;
+                headers.insert(multi_object_hash.clone(), header);
+            }
+
+            let entry = headers[&multi_object_hash]
+                .entries
+                .get(&oid.to_string())
+                .ok_or_else(|| format!("Object {} missing from its MultiObject", oid))?
+                .clone();
+
+            if let (Some(filter), ObjectKind::Blob) = (filter.as_deref_mut(), &entry.kind) {
+                if oid != root && !intact && filter.skip(oid, entry.size) {
+                    continue;
+                }
+            }
+
+            if !intact {
+                fetch_todo.insert(oid);
+                size += entry.size;
+            }
+
+            // The references come from the header, unchecked until the objects are written
+            walk.expand(oid)?;
+            let references = match entry.metadata {
+                GitObjectMetadata::Commit {
+                    parent_git_hashes,
+                    tree_git_hash,
+                } => std::iter::once(tree_git_hash)
+                    .chain(parent_git_hashes)
+                    .collect(),
+                GitObjectMetadata::Tag { target_git_hash } => vec![target_git_hash],
+                GitObjectMetadata::Tree { entry_git_hashes } => entry_git_hashes,
+                GitObjectMetadata::Blob => vec![],
+            };
+            for reference in references {
+                let reference = Oid::from_str(&reference)?;
+                walk.push(reference, reference)?;
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// Upload the objects in `oids` that RepoData doesn't have yet, in the MultiObjects chosen
+    /// by `packer`, recording each minted IPF in `minted` as soon as it exists.
+    ///
+    /// Blobs over `large_blob_threshold` bytes are uploaded on their own instead, with their
+    /// CIDs kept in `journal` until the push is published.
+    ///
+    /// Every object is read before the first upload, so one deleted in the meantime, e.g. by a
+    /// background `git gc` pruning, fails the push before anything is uploaded.
+    pub async fn push_git_objects(
+        &mut self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+        store: &dyn ObjectStore,
+        chain: &Chain,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        ips_id: IpsId,
+        packer: &dyn Packer,
+        large_blob_threshold: u64,
+        journal: &PushJournal,
+        minted: &mut Vec<IpfId>,
+    ) -> Result<(), Box<dyn Error>> {
+        let odb = repo.odb()?;
+
+        let (large_blobs, new_oids): (HashSet<Oid>, HashSet<Oid>) = oids
+            .iter()
+            .filter(|oid| !self.objects.contains_key(&oid.to_string()))
+            .partition(|&&oid| match odb.read_header(oid) {
+                Ok((size, ObjectType::Blob)) => size as u64 > large_blob_threshold,
+                _ => false,
+            });
+
+        let mut large_blobs: Vec<_> = large_blobs.into_iter().collect();
+        large_blobs.sort();
+
+        // Nothing new, e.g. a new branch at an existing commit, means nothing to mint
+        let mut packs = packer.pack(&new_oids, repo)?;
+        packs.retain(|pack| !pack.is_empty());
+
+        let mut multi_objects = Vec::with_capacity(packs.len());
+        for pack in &packs {
+            let mut multi_object = MultiObject::from_parts(String::new(), vec![], BTreeMap::new());
+
+            for &oid in pack {
+                multi_object.add(GitObject::read(oid, &odb).map_err(|e| vanished(oid, e))?);
+            }
+
+            multi_object.hash = MultiObject::identity(&multi_object.git_hashes);
+            multi_objects.push(multi_object);
+        }
+
+        // Blobs an earlier push uploaded are only staged again if the store lost them
+        let staging = TempDir::new()?;
+        let mut staged = BTreeMap::new();
+        for &oid in &large_blobs {
+            match journal.uploaded_blob(&oid.to_string()) {
+                Some(cid) if store.has(&cid).await? => {}
+                _ => {
+                    let path = staging.child(oid.to_string());
+                    large_blob::stage(&odb, oid, &path).map_err(|e| vanished(oid, e))?;
+                    staged.insert(oid, path);
+                }
+            }
+        }
+
+        for (i, &oid) in large_blobs.iter().enumerate() {
+            output::status(format!(
+                "Uploading large blob {}/{}...",
+                i + 1,
+                large_blobs.len()
+            ));
+            self.push_large_blob(
+                oid,
+                staged.get(&oid).map(PathBuf::as_path),
+                &odb,
+                store,
+                chain,
+                signer,
+                journal,
+                minted,
+            )
+            .await?;
+        }
+
+        for (i, multi_object) in multi_objects.iter().enumerate() {
+            events::emit(Event::UploadStarted {
+                index: i + 1,
+                total: packs.len(),
+                objects: multi_object.objects.len(),
+                bytes: multi_object.total_size,
+            });
+
+            let (ipf_id, cid) =
+                Self::mint_multi_object(multi_object, store, chain, signer, ips_id).await?;
+            minted.push(ipf_id);
+            self.cids.insert(multi_object.hash.clone(), cid.to_bytes());
+
+            for oid in &multi_object.git_hashes {
+                self.objects.insert(oid.clone(), multi_object.hash.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upload the blob `oid` on its own, unless `journal` says an earlier push uploaded it and
+    /// the store still holds it, then mint its IPF. `staged` is where it was copied to already,
+    /// if it was.
+    async fn push_large_blob(
+        &mut self,
+        oid: Oid,
+        staged: Option<&Path>,
+        odb: &Odb<'_>,
+        store: &dyn ObjectStore,
+        chain: &Chain,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        journal: &PushJournal,
+        minted: &mut Vec<IpfId>,
+    ) -> BoxResult<()> {
+        let git_hash = oid.to_string();
+        let (size, _) = odb.read_header(oid)?;
+
+        let ipfs_hash = match journal.uploaded_blob(&git_hash) {
+            Some(cid) if store.has(&cid).await? => {
+                output::info(format!(
+                    "Blob {} ({}) was already uploaded as {}",
+                    git_hash,
+                    human_size(size),
+                    cid
+                ));
+                cid
+            }
+            _ => {
+                let staging = TempDir::new()?;
+                let path = match staged {
+                    Some(path) => path.to_owned(),
+                    None => {
+                        let path = staging.child("blob");
+                        large_blob::stage(odb, oid, &path)?;
+                        path
+                    }
+                };
+
+                let cid = store
+                    .put_file(&path, &format!("Uploading blob {}", &git_hash[..7]), signer)
+                    .await?;
+
+                if let Err(e) = journal.record_blob(&git_hash, &cid) {
+                    output::warn(format!("Could not journal blob {}: {}", git_hash, e));
+                }
+                cid
+            }
+        };
+
+        output::status("Minting large blob IPF...");
+
+        let ipf_id = chain
+            .mint_ipf(
+                large_blob::ipf_metadata(&git_hash),
+                cid_digest(&ipfs_hash)?,
+                signer,
+            )
+            .await?;
+        minted.push(ipf_id);
+
+        debug!("Minted large blob {} with IPF ID {}", git_hash, ipf_id);
+
+        self.large_blobs.insert(
+            git_hash.clone(),
+            LargeBlob {
+                cid: Cid::try_from(ipfs_hash.as_str())?.to_bytes(),
+                size: size as u64,
+            },
+        );
+        self.objects.insert(git_hash, LARGE_BLOB_MARKER.to_owned());
+
+        Ok(())
+    }
+
+    async fn mint_multi_object(
+        multi_object: &MultiObject,
+        store: &dyn ObjectStore,
+        chain: &Chain,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        ips_id: IpsId,
+    ) -> BoxResult<(IpfId, Cid)> {
+        let data = multi_object.to_bytes();
+        let bytes = multi_object.total_size;
+        let compressed = data.len() as u64;
+
+        let ipfs_hash = store.put(data, signer).await?;
+        let digest = cid_digest(&ipfs_hash)?;
+
+        events::emit(Event::UploadFinished {
+            cid: ipfs_hash.clone(),
+            bytes,
+            compressed,
+        });
+
+        // Minting over a different MultiObject with the same key would make fetches ambiguous
+        if let Some(existing) = chain.find_ipf(ips_id, multi_object.hash.as_bytes()).await? {
+            if existing.data != digest {
+                error!(format!(
+                    "MultiObject key {} collides with IPF {} holding different content",
+                    multi_object.hash, existing.id
+                ))
+            }
+        }
+
+        output::status("Minting object pack IPF...");
+
+        let ipf_id = chain
+            .mint_ipf(multi_object.hash.as_bytes().to_vec(), digest, signer)
+            .await?;
+
+        debug!("Minted object pack with IPF ID {}", ipf_id);
+
+        Ok((ipf_id, Cid::try_from(ipfs_hash.as_str())?))
+    }
+
+    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
+    ///
+    /// Objects are written after the objects they reference, so a fetch stopped partway, e.g.
+    /// when Ctrl-C cancels it, never leaves one whose references are missing: the next fetch's
+    /// walk stops at present objects, and would take it as complete.
+    pub async fn fetch_git_objects(
+        &self,
+        oids: &HashSet<Oid>,
+        repo: &mut Repository,
+        store: &dyn ObjectStore,
+        index: &MultiObjectIndex<'_>,
+        mut quarantine: Option<&mut Quarantine>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut fetched_objects = BTreeMap::new();
+
+        // Only the MultiObjects holding a requested object
+        let multi_object_hashes: BTreeSet<&String> = oids
+            .iter()
+            .filter_map(|oid| self.objects.get(&oid.to_string()))
+            .filter(|hash| *hash != SUBMODULE_TIP_MARKER && *hash != LARGE_BLOB_MARKER)
+            .collect();
+        let mut progress = Cancelled {
+            multi_objects: multi_object_hashes.len(),
+            objects: oids.len(),
+            ..Default::default()
+        };
+
+        for object_hash in multi_object_hashes {
+            if shutdown::cancelled() {
+                return Err(progress.into());
+            }
+
+            events::emit(Event::DownloadStarted {
+                multi_object: object_hash.clone(),
+            });
+
+            let mut multi_object =
+                MultiObject::get(&self.multi_object_cid(object_hash, index).await?, store).await?;
+
+            events::emit(Event::DownloadFinished {
+                multi_object: object_hash.clone(),
+                bytes: multi_object.total_size,
+            });
+
+            fetched_objects.append(&mut multi_object.objects);
+            progress.multi_objects_downloaded += 1;
+        }
+
+        let total_size: u64 = oids
+            .iter()
+            .filter_map(|oid| fetched_objects.get(&oid.to_string()))
+            .map(|object| object.size)
+            .sum();
+        let mut written_size = 0;
+
+        for (i, oid) in write_order(oids, &fetched_objects)?.into_iter().enumerate() {
+            if shutdown::cancelled() {
+                progress.objects_written = i;
+                return Err(progress.into());
+            }
+            debug!("[{}/{}] Fetching object {}", i + 1, oids.len(), oid);
+
+            if let Some(blob) = self.large_blobs.get(&oid.to_string()) {
+                if repo.odb()?.read_header(oid).is_err() {
+                    self.fetch_large_blob(oid, blob, repo, store, quarantine.as_deref_mut())
+                        .await?;
+                }
+                continue;
+            }
+
+            let git_object = fetched_objects
+                .get(&format!("{}", oid))
+                .ok_or_else(|| {
+                    let msg = format!("Could not find object {} in the index", oid);
+                    debug!("{}", msg);
+                    msg
+                })?
+                .clone();
+
+            if repo.odb()?.read_header(oid).is_ok() {
+                debug!("fetch objects: Object {} already present locally!", oid);
+                continue;
+            }
+
+            let kind = git_object.kind.object_type();
+            let actual_oid = Oid::hash_object(kind, &git_object.data)?;
+
+            if actual_oid != oid {
+                let msg = format!(
+                    "Object tree inconsistency detected: fetched {}, but its data hashes to {}",
+                    oid, actual_oid
+                );
+                debug!("{}", msg);
+
+                match quarantine.as_deref_mut() {
+                    Some(quarantine) => {
+                        output::warn(format!("{}; skipping it", msg));
+                        quarantine.add(QuarantinedObject {
+                            expected: oid.to_string(),
+                            actual: actual_oid.to_string(),
+                            multi_object: self
+                                .objects
+                                .get(&oid.to_string())
+                                .cloned()
+                                .unwrap_or_default(),
+                        });
+                        continue;
+                    }
+                    None => return Err(msg.into()),
+                }
+            }
+
+            let written_oid = repo.odb()?.write(kind, &git_object.data)?;
+            debug!("Fetched object {}", written_oid);
+
+            let percent_before = written_size * 100 / total_size.max(1);
+            written_size += git_object.size;
+            let percent = written_size * 100 / total_size.max(1);
+
+            // One event per percent keeps the rate bounded however many objects there are
+            if percent != percent_before {
+                events::emit(Event::Written {
+                    written: written_size,
+                    total: total_size,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Download the large blob `oid` to a staging file and stream it into `repo`'s odb, or
+    /// quarantine it if `quarantine` is given and its content doesn't match.
+    async fn fetch_large_blob(
+        &self,
+        oid: Oid,
+        blob: &LargeBlob,
+        repo: &Repository,
+        store: &dyn ObjectStore,
+        quarantine: Option<&mut Quarantine>,
+    ) -> BoxResult<()> {
+        let staging = TempDir::new()?;
+        let path = staging.child("blob");
+
+        output::status(format!(
+            "Downloading blob {} ({})...",
+            oid,
+            human_size(blob.size as usize)
+        ));
+        store
+            .get_to_file(
+                &Cid::try_from(blob.cid.as_slice())?.to_string(),
+                &mut File::create(&path)?,
+            )
+            .await?;
+
+        let actual_oid = Oid::hash_file(ObjectType::Blob, &path)?;
+
+        if actual_oid != oid {
+            let msg = format!(
+                "Object tree inconsistency detected: fetched {}, but its data hashes to {}",
+                oid, actual_oid
+            );
+            debug!("{}", msg);
+
+            return match quarantine {
+                Some(quarantine) => {
+                    output::warn(format!("{}; skipping it", msg));
+                    quarantine.add(QuarantinedObject {
+                        expected: oid.to_string(),
+                        actual: actual_oid.to_string(),
+                        multi_object: LARGE_BLOB_MARKER.to_owned(),
+                    });
+                    Ok(())
+                }
+                None => Err(msg.into()),
+            };
+        }
+
+        let written_oid = large_blob::insert(&repo.odb()?, &path)?;
+        debug!("Fetched large blob {}", written_oid);
+
+        Ok(())
+    }
+
+    pub async fn mint_return_new_old_id(
+        &self,
+        config: &Config,
+        store: &dyn ObjectStore,
+        chain: &Chain,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        ips_id: IpsId,
+    ) -> Result<(IpfId, Option<IpfId>), Box<dyn Error>> {
+        let (data, size) = self.compressed();
+        size.check(config)?;
+
+        output::info(format!("Repository index is {}", size));
+        output::status("Updating on-chain repository index...");
+
+        let ipfs_hash = store.put(data, signer).await?;
+
+        let new_ipf_id = chain
+            .mint_ipf(b"RepoData".to_vec(), cid_digest(&ipfs_hash)?, signer)
+            .await?;
+
+        debug!("Minted RepoData with IPF ID {}", new_ipf_id);
+
+        let old_ipf_id = chain.find_ipf(ips_id, b"RepoData").await?.map(|ipf| ipf.id);
+
+        Ok((new_ipf_id, old_ipf_id))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{self, FixtureRepo, Seed},
+        store::tests::{CountingStore, MemoryStore},
+        util::tests::{commit, commit_tree, repository},
+    };
+    use git2::Signature;
+
+    /// `repo`'s objects in a single MultiObject in a `MemoryStore`, and a RepoData with its refs,
+    /// as if all of it had been pushed.
+    pub fn published(repo: &Repository) -> (RepoData, MemoryStore, MultiObjectIndex<'static>) {
+        let odb = repo.odb().unwrap();
+        let mut oids = vec![];
+        odb.foreach(|oid| {
+            oids.push(*oid);
+            true
+        })
+        .unwrap();
+
+        let mut repo_data = RepoData::default();
+        let mut multi_object = MultiObject::from_parts("mo".to_owned(), vec![], BTreeMap::new());
+        for oid in oids {
+            multi_object.add(GitObject::read(oid, &odb).unwrap());
+            repo_data.objects.insert(oid.to_string(), "mo".to_owned());
+        }
+
+        for reference in repo.references().unwrap() {
+            let reference = reference.unwrap();
+            if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+                repo_data.refs.insert(name.to_owned(), target.to_string());
+            }
+        }
+
+        let store = MemoryStore::default();
+        store.insert("mo-cid", multi_object.to_bytes());
+        let index =
+            MultiObjectIndex::Cids(BTreeMap::from([("mo".to_owned(), "mo-cid".to_owned())]));
+
+        (repo_data, store, index)
+    }
+
+    /// Fetch `git_hash` for `ref_name` into `repo` from what `published` returned.
+    async fn fetch(
+        (repo_data, store, index): &(RepoData, MemoryStore, MultiObjectIndex<'static>),
+        git_hash: Oid,
+        ref_name: &str,
+        repo: &mut Repository,
+    ) -> BoxResult<()> {
+        repo_data
+            .fetch_to_ref_from_str(
+                &git_hash.to_string(),
+                ref_name,
+                repo,
+                store,
+                index,
+                None,
+                None,
+                None,
+                &Config::default(),
+            )
+            .await
+    }
+
+    fn tag(repo: &Repository, name: &str, target: Oid) -> Oid {
+        let tagger = Signature::now("Test", "test@example.com").unwrap();
+        let target = repo.find_object(target, None).unwrap();
+
+        repo.tag(name, &target, &tagger, name, false).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tags_of_tags_and_blobs_are_fetched() {
+        let (_dir, source) = repository();
+        let commit = commit(&source, Some("refs/heads/main"), &[], "base");
+        let tag_of_commit = tag(&source, "v1", commit);
+        let tag_of_tag = tag(&source, "v1-again", tag_of_commit);
+        let blob = source.blob(b"a key").unwrap();
+        let tag_of_blob = tag(&source, "key", blob);
+        let published = published(&source);
+
+        let (_dir, mut repo) = repository();
+        fetch(&published, tag_of_tag, "refs/tags/v1-again", &mut repo)
+            .await
+            .unwrap();
+        fetch(&published, tag_of_blob, "refs/tags/key", &mut repo)
+            .await
+            .unwrap();
+
+        let odb = repo.odb().unwrap();
+        for oid in [tag_of_tag, tag_of_commit, commit, tag_of_blob, blob] {
+            assert!(odb.exists(oid), "{} wasn't fetched", oid);
+        }
+    }
+
+    #[tokio::test]
+    async fn branch_must_point_at_a_commit() {
+        let (_dir, source) = repository();
+        let blob = source.blob(b"not a commit").unwrap();
+        let published = published(&source);
+
+        let (_dir, mut repo) = repository();
+        let e = fetch(&published, blob, "refs/heads/main", &mut repo)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "refs/heads/main points at a blob rather than a commit"
+        );
+    }
+
+    #[test]
+    fn repo_data_size_limits_are_enforced() {
+        let size = RepoDataSize {
+            encoded: 4096,
+            compressed: 2048,
+        };
+        assert_eq!(size.to_string(), "2.00 KiB (4.00 KiB uncompressed)");
+
+        let mut config = Config {
+            max_repodata_size: Some(2048),
+            ..Config::default()
+        };
+        assert!(size.check(&config).is_ok());
+
+        config.max_repodata_size = Some(1024);
+        let e = size.check(&config).unwrap_err().to_string();
+        assert!(
+            e.contains("over the max_repodata_size limit of 1.00 KiB"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn compressed_size_is_measured() {
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+
+        let (bytes, size) = repo_data.compressed();
+        assert_eq!(size.compressed, bytes.len());
+        assert_eq!(size.encoded, repo_data.encode().len());
+    }
+
+    #[tokio::test]
+    async fn fetch_writes_objects_but_no_refs() {
+        let (_dir, source) = repository();
+        let tip = commit(&source, Some("refs/heads/main"), &[], "tip");
+        let published = published(&source);
+
+        let (_dir, mut repo) = repository();
+        fetch(&published, tip, "refs/heads/main", &mut repo)
+            .await
+            .unwrap();
+
+        assert!(repo.odb().unwrap().exists(tip));
+        assert_eq!(repo.references().unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn force_push_counts_the_commits_it_drops() {
+        let (_dir, source) = repository();
+        let base = commit(&source, None, &[], "base");
+        let dropped = commit(&source, None, &[base], "dropped");
+        let old = commit(&source, None, &[dropped], "old");
+        let new = commit(&source, None, &[base], "new");
+        let published = published(&source);
+        let (repo_data, store, index) = &published;
+
+        // With the old tip at hand, from local history
+        let unreachable = repo_data
+            .unreachable_commits(old, new, &source, store, index)
+            .await
+            .unwrap();
+        assert_eq!(unreachable, 2);
+
+        // Without it, from the MultiObject headers, down to what the new tip reaches
+        let (_dir, mut repo) = repository();
+        fetch(&published, new, "refs/heads/main", &mut repo)
+            .await
+            .unwrap();
+        let unreachable = repo_data
+            .unreachable_commits(old, new, &repo, store, index)
+            .await
+            .unwrap();
+        assert_eq!(unreachable, 2);
+    }
+
+    #[test]
+    fn published_history_has_nothing_to_push() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, Some("refs/heads/main"), &[], "base");
+        let (repo_data, _, _) = published(&repo);
+        let tip = commit(&repo, None, &[base], "tip");
+        let limits = Limits::from_config(&Config::default());
+
+        // A new branch at a published commit, which mints nothing
+        let mut push_todo = HashSet::new();
+        let object = repo.find_object(base, None).unwrap();
+        repo_data
+            .enumerate_for_push(
+                &object,
+                &mut push_todo,
+                &mut HashSet::new(),
+                &repo,
+                &[],
+                limits,
+            )
+            .unwrap();
+        assert!(push_todo.is_empty());
+
+        // Only the new commit, as its tree is the published empty one
+        let object = repo.find_object(tip, None).unwrap();
+        repo_data
+            .enumerate_for_push(
+                &object,
+                &mut push_todo,
+                &mut HashSet::new(),
+                &repo,
+                &[],
+                limits,
+            )
+            .unwrap();
+        assert_eq!(push_todo, HashSet::from([tip]));
+    }
+
+    #[test]
+    fn objects_pruned_while_counting_point_at_gc() {
+        let (dir, repo) = repository();
+        let blob = repo.blob(b"contents").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+        let subtree = builder.write().unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("dir", subtree, 0o040000).unwrap();
+        let tree = builder.write().unwrap();
+        let tip = commit_tree(&repo, Some("refs/heads/main"), &[], tree, "tip");
+
+        let hex = subtree.to_string();
+        std::fs::remove_file(
+            dir.path()
+                .join(".git/objects")
+                .join(&hex[..2])
+                .join(&hex[2..]),
+        )
+        .unwrap();
+        // Without objects libgit2 cached while writing
+        let repo = Repository::open(dir.path()).unwrap();
+
+        let object = repo.find_object(tip, None).unwrap();
+        let e = RepoData::default()
+            .enumerate_for_push(
+                &object,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+                &repo,
+                &[],
+                Limits::from_config(&Config::default()),
+            )
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with(&format!("Object {} can no longer be read", subtree)));
+        assert!(e.contains("gc.autoDetach false"));
+    }
+
+    #[tokio::test]
+    async fn corrupt_objects_are_quarantined_in_best_effort_fetches() {
+        let (_dir, source) = repository();
+        let good = commit(&source, Some("refs/heads/good"), &[], "good");
+        let blob = source.blob(b"intact").unwrap();
+        let mut builder = source.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+        let bad = commit_tree(&source, Some("refs/heads/bad"), &[], tree, "bad");
+        let published = published(&source);
+        let (repo_data, store, index) = &published;
+
+        // Same size, so only the hash gives it away
+        let mut multi_object = MultiObject::get("mo-cid", store).await.unwrap();
+        multi_object
+            .objects
+            .get_mut(&blob.to_string())
             .unwrap()
-            .1;
+            .data = b"broken".to_vec();
+        store.insert("mo-cid", multi_object.to_bytes());
+
+        let (_dir, mut repo) = repository();
+        assert!(fetch(&published, bad, "refs/heads/bad", &mut repo)
+            .await
+            .is_err());
+
+        let (_dir, mut repo) = repository();
+        let mut quarantine = Quarantine::default();
+        for (oid, name) in [(bad, "refs/heads/bad"), (good, "refs/heads/good")] {
+            repo_data
+                .fetch_to_ref_from_str(
+                    &oid.to_string(),
+                    name,
+                    &mut repo,
+                    store,
+                    index,
+                    Some(&mut quarantine),
+                    None,
+                    None,
+                    &Config::default(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(quarantine.failed_refs, ["refs/heads/bad"]);
+        assert_eq!(quarantine.objects.len(), 1);
+        assert_eq!(quarantine.objects[0].expected, blob.to_string());
+        assert_eq!(quarantine.objects[0].multi_object, "mo");
+        assert!(!repo.odb().unwrap().exists(blob));
+        assert!(repo.odb().unwrap().exists(good));
+    }
+
+    #[tokio::test]
+    async fn refetch_replaces_corrupt_loose_objects() {
+        let (_dir, source) = repository();
+        let blob = source.blob(b"intact").unwrap();
+        let mut builder = source.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+        let tip = commit_tree(&source, Some("refs/heads/main"), &[], tree, "tip");
+        let published = published(&source);
+        let (repo_data, store, index) = &published;
+
+        let (dir, mut repo) = repository();
+        fetch(&published, tip, "refs/heads/main", &mut repo)
+            .await
+            .unwrap();
+
+        // Another object's data under the blob's name
+        let loose = |oid: Oid| {
+            let hex = oid.to_string();
+            dir.path()
+                .join(".git/objects")
+                .join(&hex[..2])
+                .join(&hex[2..])
+        };
+        std::fs::remove_file(loose(blob)).unwrap();
+        std::fs::copy(loose(tree), loose(blob)).unwrap();
+        // Without objects libgit2 cached while fetching
+        let mut repo = Repository::open(dir.path()).unwrap();
+        assert!(!repair::is_intact(&repo, blob));
+
+        let mut refetch = Refetch::default();
+        repo_data
+            .fetch_to_ref_from_str(
+                &tip.to_string(),
+                "refs/heads/main",
+                &mut repo,
+                store,
+                index,
+                None,
+                Some(&mut refetch),
+                None,
+                &Config::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            (refetch.verified, refetch.repaired, refetch.downloaded),
+            (2, 1, 0)
+        );
+        assert!(refetch.unrepairable.is_empty());
+        assert!(repair::is_intact(&repo, blob));
+        assert!(!repair::remove_loose_object(&repo, Oid::zero()).unwrap());
+    }
 
-        events.wait_for_success().await?;
+    #[tokio::test]
+    async fn divergence_is_counted_with_or_without_the_remote_tip() {
+        let (_dir, source) = repository();
+        let base = commit(&source, None, &[], "base");
+        let first = commit(&source, None, &[base], "remote 1");
+        let remote = commit(&source, Some("refs/heads/main"), &[first], "remote 2");
+        let published = published(&source);
+        let (repo_data, store, index) = &published;
+
+        let (_dir, mut repo) = repository();
+        fetch(&published, base, "refs/heads/main", &mut repo)
+            .await
+            .unwrap();
+        let local = commit(&repo, None, &[base], "local");
+
+        // From the MultiObject headers
+        let divergence = repo_data
+            .divergence(local, remote, &repo, store, index)
+            .await
+            .unwrap();
+        assert_eq!(divergence, (1, 2));
+
+        // From local history, once the remote tip was fetched
+        fetch(&published, remote, "refs/heads/main", &mut repo)
+            .await
+            .unwrap();
+        let divergence = repo_data
+            .divergence(local, remote, &repo, store, index)
+            .await
+            .unwrap();
+        assert_eq!(divergence, (1, 2));
+        assert!(repo_data
+            .is_ancestor(base, remote, &repo, store, index)
+            .await
+            .unwrap());
+    }
 
-        eprintln!("Minted Repo Data on-chain with IPF ID: {}", new_ipf_id);
+    fn record(block_number: u32) -> PushRecord {
+        PushRecord {
+            block_number,
+            block_hash: hex::encode([block_number as u8; 32]),
+            pusher: "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_owned(),
+            refs_changed: vec![RefChange {
+                name: "refs/heads/main".to_owned(),
+                old: None,
+                new: Some("a".repeat(40)),
+            }],
+            message: None,
+        }
+    }
 
-        let ips_info_address = tinkernet::storage().inv4().ip_storage(&ips_id);
+    #[test]
+    fn repo_data_without_history_decodes_with_an_empty_one() {
+        let refs = BTreeMap::from([("refs/heads/main".to_owned(), "a".repeat(40))]);
+        let objects = BTreeMap::from([("a".repeat(40), "mo".to_owned())]);
 
-        let ips_info = chain_api
-            .storage()
-            .fetch(&ips_info_address, None)
-            .await?
-            .ok_or(format!("IPS {ips_id} does not exist"))?;
+        let decoded = RepoData::decode(&mut &(&refs, &objects).encode()[..]).unwrap();
 
-        for file in ips_info.data.0 {
-            if let AnyId::IpfId(id) = file {
-                let ipf_info_address = tinkernet::storage().ipf().ipf_storage(&id);
+        assert_eq!(decoded.refs, refs);
+        assert_eq!(decoded.objects, objects);
+        assert!(decoded.history.is_empty());
+    }
 
-                let ipf_info = chain_api
-                    .storage()
-                    .fetch(&ipf_info_address, None)
-                    .await?
-                    .ok_or("Internal error: IPF listed from IPS does not exist")?;
+    #[test]
+    fn push_history_round_trips_and_keeps_the_latest() {
+        let mut repo_data = RepoData::default();
+        for block_number in 0..PUSH_HISTORY_LIMIT as u32 + 2 {
+            repo_data.record_push(record(block_number));
+        }
 
-                if String::from_utf8(ipf_info.metadata.0.clone())? == *"RepoData" {
-                    return Ok((new_ipf_id, Some(id)));
-                }
+        let decoded = RepoData::decode(&mut &repo_data.encode()[..]).unwrap();
+
+        assert_eq!(decoded.history, repo_data.history);
+        assert_eq!(decoded.history.len(), PUSH_HISTORY_LIMIT);
+        assert_eq!(decoded.history[0].block_number, 2);
+    }
+
+    #[test]
+    fn push_messages_round_trip_apart_from_the_history() {
+        let mut repo_data = RepoData::default();
+        repo_data.record_push(record(1));
+        repo_data.record_push(PushRecord {
+            message: Some("Release 1.0".to_owned()),
+            ..record(2)
+        });
+        let encoded = repo_data.encode();
+
+        let decoded = RepoData::decode(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded.history, repo_data.history);
+
+        // Older helpers read the history up to the messages, and only miss those
+        type Older = (
+            BTreeMap<String, String>,
+            BTreeMap<String, String>,
+            Vec<PushRecord>,
+        );
+        let (_, _, history): Older = Decode::decode(&mut &encoded[..]).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].message, None);
+        assert_eq!(history[1].block_number, 2);
+    }
+
+    #[tokio::test]
+    async fn recorded_cids_round_trip_and_are_used_before_the_index() {
+        let cid = Cid::try_from("QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG").unwrap();
+        let mut repo_data = RepoData::default();
+        repo_data.record_push(record(1));
+        repo_data.cids.insert("mo".to_owned(), cid.to_bytes());
+
+        let decoded = RepoData::decode(&mut &repo_data.encode()[..]).unwrap();
+        assert_eq!(decoded.cids, repo_data.cids);
+
+        let index = MultiObjectIndex::Cids(BTreeMap::new());
+        assert_eq!(
+            decoded.multi_object_cid("mo", &index).await.unwrap(),
+            cid.to_string()
+        );
+        assert!(decoded.multi_object_cid("other", &index).await.is_err());
+
+        // Written before CIDs were recorded
+        let older = (&repo_data.refs, &repo_data.objects, &repo_data.history).encode();
+        assert!(RepoData::decode(&mut &older[..]).unwrap().cids.is_empty());
+    }
+
+    #[test]
+    fn objects_are_read_with_the_links_libgit2_sees() {
+        let (_dir, repo) = repository();
+        let blob = repo.blob(b"contents").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+        let first = commit(&repo, None, &[], "first");
+        let second = commit(&repo, None, &[], "second");
+        let merge = commit_tree(
+            &repo,
+            None,
+            &[first, second],
+            tree,
+            "merge\n\ntree 00 isn't a header",
+        );
+        let tag = tag(&repo, "v1", merge);
+        let odb = repo.odb().unwrap();
+
+        match GitObject::read(merge, &odb).unwrap().metadata {
+            GitObjectMetadata::Commit {
+                parent_git_hashes,
+                tree_git_hash,
+            } => {
+                assert_eq!(
+                    parent_git_hashes,
+                    BTreeSet::from([first.to_string(), second.to_string()])
+                );
+                assert_eq!(tree_git_hash, tree.to_string());
+            }
+            other => panic!("{:?}", other),
+        }
+
+        match GitObject::read(tree, &odb).unwrap().metadata {
+            GitObjectMetadata::Tree { entry_git_hashes } => {
+                assert_eq!(entry_git_hashes, BTreeSet::from([blob.to_string()]))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let object = GitObject::read(tag, &odb).unwrap();
+        assert_eq!(object.kind, ObjectKind::Tag);
+        assert_eq!(object.size, odb.read_header(tag).unwrap().0 as u64);
+        match object.metadata {
+            GitObjectMetadata::Tag { target_git_hash } => {
+                assert_eq!(target_git_hash, merge.to_string())
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let object = GitObject::read(blob, &odb).unwrap();
+        assert_eq!(
+            (object.kind, object.data),
+            (ObjectKind::Blob, b"contents".to_vec())
+        );
+    }
+
+    #[test]
+    fn refs_are_listed_with_their_kind_peeled_target_and_push() {
+        let mut repo_data = RepoData::default();
+        repo_data.record_push(record(4));
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+        repo_data
+            .refs
+            .insert("refs/tags/v1".to_owned(), "b".repeat(40));
+        repo_data.ref_targets.insert(
+            "refs/tags/v1".to_owned(),
+            RefTarget {
+                kind: ObjectKind::Tag,
+                peeled: Some("c".repeat(40)),
+            },
+        );
+
+        let decoded = RepoData::decode(&mut &repo_data.encode()[..]).unwrap();
+        assert_eq!(decoded.ref_targets, repo_data.ref_targets);
+
+        assert_eq!(
+            decoded.ref_listing(),
+            vec![
+                RefListing {
+                    name: "refs/heads/main".to_owned(),
+                    target: "a".repeat(40),
+                    kind: "unknown".to_owned(),
+                    peeled: None,
+                    block_number: Some(4),
+                    symref: None,
+                },
+                RefListing {
+                    name: "refs/tags/v1".to_owned(),
+                    target: "b".repeat(40),
+                    kind: "tag".to_owned(),
+                    peeled: Some("c".repeat(40)),
+                    block_number: None,
+                    symref: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn earlier_versions_are_looked_for_at_doubling_distances() {
+        assert_eq!(
+            lookback_blocks(1000, 256).collect::<Vec<_>>(),
+            vec![999, 998, 996, 992, 984, 968, 936, 872, 744]
+        );
+        assert_eq!(lookback_blocks(5, 256).collect::<Vec<_>>(), vec![4, 3, 1]);
+        assert_eq!(lookback_blocks(1000, 0).count(), 0);
+        assert_eq!(lookback_blocks(u32::MAX, u32::MAX).count(), 32);
+    }
+
+    #[tokio::test]
+    async fn ancestry_is_read_locally_or_from_the_published_metadata() {
+        let (_dir, source) = repository();
+        let old = commit(&source, None, &[], "old");
+        let new = commit(&source, None, &[old], "new");
+        let rewritten = commit(&source, None, &[], "rewritten");
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let tag = source
+            .tag(
+                "v1",
+                &source.find_object(new, None).unwrap(),
+                &signature,
+                "v1",
+                false,
+            )
+            .unwrap();
+        let (repo_data, store, index) = published(&source);
+
+        let (_empty_dir, empty) = repository();
+        for repo in [&source, &empty] {
+            let is_ancestor =
+                |ancestor, tip| repo_data.is_ancestor(ancestor, tip, repo, &store, &index);
+
+            assert!(is_ancestor(old, new).await.unwrap());
+            assert!(is_ancestor(old, old).await.unwrap());
+            assert!(!is_ancestor(old, rewritten).await.unwrap());
+            assert!(!is_ancestor(new, old).await.unwrap());
+            assert!(is_ancestor(old, tag).await.unwrap());
+        }
+    }
+
+    #[test]
+    fn swarm_hints_keep_the_latest_pusher_first_and_once() {
+        let hint = |peer_id: &str| SwarmHint {
+            peer_id: peer_id.to_owned(),
+            addresses: vec![format!("/dns4/ipfs.example.com/tcp/4001/p2p/{}", peer_id)],
+        };
+        let mut repo_data = RepoData::default();
+
+        for peer in 0..SWARM_HINT_LIMIT + 2 {
+            repo_data.record_swarm_hint(hint(&format!("peer{}", peer)));
+        }
+        repo_data.record_swarm_hint(hint("peer5"));
+
+        let peers: Vec<_> = repo_data
+            .swarm_hints
+            .iter()
+            .map(|hint| hint.peer_id.as_str())
+            .collect();
+        assert_eq!(peers.len(), SWARM_HINT_LIMIT);
+        assert_eq!(peers[..3], ["peer5", "peer11", "peer10"]);
+        assert!(!peers.contains(&"peer1"));
+
+        let decoded = RepoData::decode(&mut &repo_data.encode()[..]).unwrap();
+        assert_eq!(decoded.swarm_hints, repo_data.swarm_hints);
+    }
+
+    #[test]
+    fn symbolic_sources_are_recorded_and_direct_ones_clear_them() {
+        let (_dir, repo) = repository();
+        let tip = commit(&repo, Some("refs/remotes/origin/main"), &[], "tip");
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            true,
+            "test",
+        )
+        .unwrap();
+        let mut repo_data = RepoData::default();
+
+        repo_data.record_symref(
+            &repo,
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/HEAD",
+        );
+        assert_eq!(
+            repo_data.symrefs,
+            BTreeMap::from([(
+                "refs/remotes/origin/HEAD".to_owned(),
+                "refs/remotes/origin/main".to_owned()
+            )])
+        );
+
+        // Older readers see the target's sha1 once the push moved it
+        repo_data
+            .refs
+            .insert("refs/remotes/origin/main".to_owned(), tip.to_string());
+        repo_data.follow_symrefs();
+        assert_eq!(repo_data.refs["refs/remotes/origin/HEAD"], tip.to_string());
+
+        repo.reference("refs/remotes/origin/HEAD", tip, true, "test")
+            .unwrap();
+        repo_data.record_symref(
+            &repo,
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/HEAD",
+        );
+        assert!(repo_data.symrefs.is_empty());
+    }
+
+    #[test]
+    fn head_is_recorded_for_the_checked_out_default_branch() {
+        let (_dir, repo) = repository();
+        commit(&repo, Some("refs/heads/topic"), &[], "topic");
+        commit(&repo, Some("refs/heads/main"), &[], "main");
+        repo.set_head("refs/heads/topic").unwrap();
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+
+        // Recording HEAD as topic would change what clones check out
+        repo_data.record_symref(&repo, "refs/heads/topic", "refs/heads/topic");
+        assert!(repo_data.symrefs.is_empty());
+        assert_eq!(repo_data.default_branch(), Some("refs/heads/main"));
+
+        repo.set_head("refs/heads/main").unwrap();
+        repo_data.record_symref(&repo, "refs/heads/main", "refs/heads/main");
+        assert_eq!(repo_data.symrefs["HEAD"], "refs/heads/main");
+
+        // HEAD wins over main and master once recorded
+        repo_data
+            .refs
+            .insert("refs/heads/topic".to_owned(), "b".repeat(40));
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/topic".to_owned());
+        assert_eq!(repo_data.default_branch(), Some("refs/heads/topic"));
+    }
+
+    #[test]
+    fn symrefs_round_trip_after_the_other_trailing_fields() {
+        let mut repo_data = RepoData::default();
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/main".to_owned());
+
+        let decoded = RepoData::decode(&mut &repo_data.encode()[..]).unwrap();
+        assert_eq!(decoded.symrefs, repo_data.symrefs);
+
+        // Written before symrefs existed
+        let older = (
+            &repo_data.refs,
+            &repo_data.objects,
+            &repo_data.history,
+            &repo_data.cids,
+            &repo_data.large_blobs,
+            &repo_data.ref_targets,
+            &repo_data.swarm_hints,
+            BTreeMap::<u32, String>::new(),
+        )
+            .encode();
+        assert!(RepoData::decode(&mut &older[..])
+            .unwrap()
+            .symrefs
+            .is_empty());
+    }
+
+    #[test]
+    fn delete_ref_drops_the_ref_its_kind_and_symref() {
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/gone".to_owned(), "a".repeat(40));
+        repo_data
+            .refs
+            .insert("refs/heads/kept".to_owned(), "b".repeat(40));
+        repo_data.ref_targets.insert(
+            "refs/heads/gone".to_owned(),
+            RefTarget {
+                kind: ObjectKind::Commit,
+                peeled: None,
+            },
+        );
+        repo_data
+            .symrefs
+            .insert("refs/heads/gone".to_owned(), "refs/heads/kept".to_owned());
+
+        repo_data.delete_ref("refs/heads/gone");
+        // Deleting a ref that isn't there is a no-op, not an error
+        repo_data.delete_ref("refs/heads/missing");
+
+        assert_eq!(
+            repo_data.refs.keys().collect::<Vec<_>>(),
+            ["refs/heads/kept"]
+        );
+        assert!(repo_data.ref_targets.is_empty());
+        assert!(repo_data.symrefs.is_empty());
+    }
+
+    #[test]
+    fn multi_object_identity_ignores_order() {
+        let hashes = [oid(1), oid(2), oid(3)].map(|oid| oid.to_string());
+        let identity = MultiObject::identity(&hashes);
+
+        assert_eq!(identity.len(), 64);
+        assert!(identity.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(
+            MultiObject::identity(&[hashes[2].clone(), hashes[0].clone(), hashes[1].clone()]),
+            identity
+        );
+        assert_ne!(MultiObject::identity(&hashes[..2]), identity);
+    }
+
+    #[tokio::test]
+    async fn sidecar_index_maps_hashes_to_cids() {
+        let index = MultiObjectIndex::from_sidecar("abc QmFirst\n\n  def   QmSecond  \n").unwrap();
+
+        assert_eq!(index.cid("abc").await.unwrap().as_deref(), Some("QmFirst"));
+        assert_eq!(index.cid("def").await.unwrap().as_deref(), Some("QmSecond"));
+        assert_eq!(index.cid("ghi").await.unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_sidecar_line_is_rejected() {
+        assert!(MultiObjectIndex::from_sidecar("abc QmFirst\nabc\n").is_err());
+        assert!(MultiObjectIndex::from_sidecar("abc QmFirst extra\n").is_err());
+    }
+
+    /// A MultiObject holding objects of the given ids, kinds and data.
+    fn multi_object(objects: &[(Oid, GitObjectMetadata, &[u8])]) -> MultiObject {
+        let mut multi_object = MultiObject::from_parts("mo".to_owned(), vec![], BTreeMap::new());
+        for (oid, metadata, data) in objects {
+            multi_object.add(GitObject {
+                git_hash: oid.to_string(),
+                data: data.to_vec(),
+                kind: ObjectKind::of(metadata),
+                size: data.len() as u64,
+                metadata: metadata.clone(),
+            });
+        }
+
+        multi_object
+    }
+
+    fn oid(byte: u8) -> Oid {
+        Oid::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_walk_goes_past_a_submodule() {
+        let (first, submodule, last, tree, commit) = (oid(1), oid(2), oid(3), oid(4), oid(5));
+        let multi_object = multi_object(&[
+            (
+                commit,
+                GitObjectMetadata::Commit {
+                    parent_git_hashes: BTreeSet::new(),
+                    tree_git_hash: tree.to_string(),
+                },
+                b"commit",
+            ),
+            (
+                tree,
+                GitObjectMetadata::Tree {
+                    entry_git_hashes: [first, submodule, last]
+                        .iter()
+                        .map(Oid::to_string)
+                        .collect(),
+                },
+                b"tree",
+            ),
+            (first, GitObjectMetadata::Blob, b"first"),
+            (last, GitObjectMetadata::Blob, b"last"),
+        ]);
+
+        let mut repo_data = RepoData::default();
+        for oid in [commit, tree, first, last] {
+            repo_data.objects.insert(oid.to_string(), "mo".to_owned());
+        }
+        repo_data
+            .objects
+            .insert(submodule.to_string(), SUBMODULE_TIP_MARKER.to_owned());
+
+        let store = MemoryStore::default();
+        store.insert("mo-cid", multi_object.to_bytes());
+        let index =
+            MultiObjectIndex::Cids(BTreeMap::from([("mo".to_owned(), "mo-cid".to_owned())]));
+
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+
+        // The gitlink sits between the blobs, so a walk ending at it would miss one of them
+        let mut fetch_todo = HashSet::new();
+        let size = repo_data
+            .enumerate_for_fetch(
+                commit,
+                &mut fetch_todo,
+                &repo,
+                &store,
+                &index,
+                None,
+                None,
+                Limits::from_config(&Config::default()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(fetch_todo, HashSet::from([commit, tree, first, last]));
+        assert_eq!(size, multi_object.total_size);
+    }
+
+    /// Metadata of a commit of `tree` on top of `parents`.
+    fn commit_metadata(tree: Oid, parents: &[Oid]) -> GitObjectMetadata {
+        GitObjectMetadata::Commit {
+            parent_git_hashes: parents.iter().map(Oid::to_string).collect(),
+            tree_git_hash: tree.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fetching_commits_whose_headers_make_a_cycle_fails_before_downloading_them() {
+        let (tree, first, second) = (oid(1), oid(2), oid(3));
+        let multi_object = multi_object(&[
+            (
+                tree,
+                GitObjectMetadata::Tree {
+                    entry_git_hashes: BTreeSet::new(),
+                },
+                b"tree",
+            ),
+            (first, commit_metadata(tree, &[second]), b"first"),
+            (second, commit_metadata(tree, &[first]), b"second"),
+        ]);
+
+        let mut repo_data = RepoData::default();
+        for oid in [tree, first, second] {
+            repo_data.objects.insert(oid.to_string(), "mo".to_owned());
+        }
+        let store = CountingStore::default();
+        store.inner.insert("mo-cid", multi_object.to_bytes());
+        let index =
+            MultiObjectIndex::Cids(BTreeMap::from([("mo".to_owned(), "mo-cid".to_owned())]));
+        let (_dir, mut repo) = repository();
+
+        let e = repo_data
+            .fetch_to_ref_from_str(
+                &first.to_string(),
+                "refs/heads/main",
+                &mut repo,
+                &store,
+                &index,
+                None,
+                None,
+                None,
+                &Config::default(),
+            )
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "The repository data is malformed: its objects reference each other in a cycle, {} -> {} -> {}",
+                first, second, first
+            )
+        );
+        // The header was read once, and none of the objects were downloaded
+        assert_eq!((store.gets.get(), store.ranges.get()), (0, 1));
+        assert!(!repo.odb().unwrap().exists(first));
+    }
+
+    #[tokio::test]
+    async fn fetch_enumeration_stops_reading_headers_at_the_traversal_limits() {
+        // Ten commits in a line, each in a MultiObject of its own, sharing one tree
+        let tree = oid(100);
+        let commits: Vec<_> = (1..=10).map(oid).collect();
+        let store = CountingStore::default();
+        let mut repo_data = RepoData::default();
+        let mut cids = BTreeMap::new();
+
+        let mut add = |hash: &str, object: (Oid, GitObjectMetadata, &[u8])| {
+            store.inner.insert(
+                &format!("{}-cid", hash),
+                multi_object(&[object.clone()]).to_bytes(),
+            );
+            repo_data
+                .objects
+                .insert(object.0.to_string(), hash.to_owned());
+            cids.insert(hash.to_owned(), format!("{}-cid", hash));
+        };
+        add(
+            "mo-tree",
+            (
+                tree,
+                GitObjectMetadata::Tree {
+                    entry_git_hashes: BTreeSet::new(),
+                },
+                b"tree",
+            ),
+        );
+        for (i, &commit) in commits.iter().enumerate() {
+            let parents = commits.get(i + 1).map_or(&[][..], std::slice::from_ref);
+            add(
+                &format!("mo{}", i),
+                (commit, commit_metadata(tree, parents), b"commit"),
+            );
+        }
+        let index = MultiObjectIndex::Cids(cids);
+        let (_dir, repo) = repository();
+        let defaults = Limits::from_config(&Config::default());
+
+        let enumerate = |limits| {
+            let (repo_data, repo, store, index) = (&repo_data, &repo, &store, &index);
+            async move {
+                store.ranges.set(0);
+                let result = repo_data
+                    .enumerate_for_fetch(
+                        commits[0],
+                        &mut HashSet::new(),
+                        repo,
+                        store,
+                        index,
+                        None,
+                        None,
+                        limits,
+                    )
+                    .await;
+                (result, store.gets.get(), store.ranges.get())
             }
+        };
+
+        // Within them, the tree every commit shares is no cycle, and each header is read once
+        let (result, gets, ranges) = enumerate(defaults).await;
+        assert!(result.is_ok());
+        assert_eq!((gets, ranges), (0, 11));
+
+        let (result, gets, ranges) = enumerate(Limits {
+            max_objects: 3,
+            ..defaults
+        })
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Repository exceeds traversal limits: the fetch walks more than 3 objects; raise traversal_max_objects if intentional"
+        );
+        assert_eq!((gets, ranges), (0, 4));
+
+        let (result, gets, ranges) = enumerate(Limits {
+            max_depth: 3,
+            ..defaults
+        })
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Repository exceeds traversal limits: the fetch goes more than 3 objects deep; raise traversal_max_depth if intentional"
+        );
+        assert_eq!((gets, ranges), (0, 4));
+    }
+
+    #[test]
+    fn push_enumeration_stops_at_the_traversal_limits() {
+        let (_dir, repo) = repository();
+        let mut tip = commit(&repo, None, &[], "0");
+        for i in 1..10 {
+            tip = commit(&repo, None, &[tip], &i.to_string());
+        }
+        let object = repo.find_object(tip, None).unwrap();
+        let mut push_todo = HashSet::new();
+
+        let e = RepoData::default()
+            .enumerate_for_push(
+                &object,
+                &mut push_todo,
+                &mut HashSet::new(),
+                &repo,
+                &[],
+                Limits {
+                    max_objects: 3,
+                    ..Limits::from_config(&Config::default())
+                },
+            )
+            .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "Repository exceeds traversal limits: the push walks more than 3 objects; raise traversal_max_objects if intentional"
+        );
+        // Of the ten commits and their tree, only the ones walked before failing
+        assert_eq!(push_todo.len(), 4);
+    }
+
+    #[test]
+    fn split_multi_object_round_trips() {
+        let multi_object = multi_object(&[
+            (oid(1), GitObjectMetadata::Blob, b"first"),
+            (oid(2), GitObjectMetadata::Blob, b""),
+            (oid(3), GitObjectMetadata::Blob, b"third"),
+        ]);
+
+        let bytes = multi_object.split_layout();
+        assert_eq!(
+            MultiObject::layout_version(&bytes),
+            Some(MULTI_OBJECT_VERSION)
+        );
+
+        let decoded = MultiObject::from_bytes(bytes).unwrap();
+        assert_eq!(decoded.hash, "mo");
+        assert_eq!(decoded.total_size, 10);
+        assert_eq!(decoded.objects[&oid(1).to_string()].data, b"first");
+        assert_eq!(decoded.objects[&oid(2).to_string()].data, b"");
+        assert_eq!(decoded.objects[&oid(3).to_string()].data, b"third");
+    }
+
+    #[test]
+    fn multi_object_with_mismatched_entry_sizes_is_rejected() {
+        let mut multi_object = multi_object(&[
+            (oid(1), GitObjectMetadata::Blob, b"first"),
+            (oid(2), GitObjectMetadata::Blob, b"second"),
+        ]);
+        // The entries claim 6 bytes more than there is data, while the total matches the data
+        for object in multi_object.objects.values_mut() {
+            object.size += 2;
+        }
+        multi_object
+            .objects
+            .get_mut(&oid(2).to_string())
+            .unwrap()
+            .data = b"seco".to_vec();
+        multi_object.total_size = 9;
+
+        let e = MultiObject::from_bytes(multi_object.split_layout()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "MultiObject header entries do not match data"
+        );
+    }
+
+    #[test]
+    fn truncated_multi_object_is_rejected() {
+        let multi_object = multi_object(&[(oid(1), GitObjectMetadata::Blob, b"first")]);
+        let bytes = multi_object.split_layout();
+
+        assert!(MultiObject::from_bytes(bytes[..MULTI_OBJECT_PREFIX_LEN + 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn version_1_multi_object_keeps_kinds_and_sizes() {
+        let multi_object = multi_object(&[
+            (oid(1), GitObjectMetadata::Blob, b"first"),
+            (
+                oid(2),
+                GitObjectMetadata::Tree {
+                    entry_git_hashes: BTreeSet::from([oid(1).to_string()]),
+                },
+                b"tree",
+            ),
+        ]);
+        let data: Vec<Vec<u8>> = multi_object
+            .objects
+            .values()
+            .map(|object| object.data.clone())
+            .collect();
+        let bytes =
+            compress_data((String::new(), MULTI_OBJECT_V1, multi_object.header(), data).encode());
+        assert_eq!(MultiObject::layout_version(&bytes), Some(MULTI_OBJECT_V1));
+
+        let decoded = MultiObject::from_bytes(bytes).unwrap();
+        let tree = &decoded.objects[&oid(2).to_string()];
+        assert_eq!((tree.kind, tree.size), (ObjectKind::Tree, 4));
+        assert_eq!(tree.data, b"tree");
+        assert_eq!(decoded.total_size, 9);
+    }
+
+    #[test]
+    fn legacy_multi_object_gets_kinds_and_sizes_from_its_data() {
+        let objects = BTreeMap::from([(
+            oid(1).to_string(),
+            (
+                oid(1).to_string(),
+                b"first".to_vec(),
+                GitObjectMetadata::Blob,
+            ),
+        )]);
+        let bytes =
+            compress_data(("legacy".to_owned(), vec![oid(1).to_string()], objects).encode());
+        assert_eq!(MultiObject::layout_version(&bytes), Some(0));
+
+        let decoded = MultiObject::from_bytes(bytes).unwrap();
+        let blob = &decoded.objects[&oid(1).to_string()];
+        assert_eq!(decoded.hash, "legacy");
+        assert_eq!((blob.kind, blob.size), (ObjectKind::Blob, 5));
+    }
+
+    #[tokio::test]
+    async fn header_is_read_without_the_data() {
+        let multi_object = multi_object(&[
+            (oid(1), GitObjectMetadata::Blob, b"first"),
+            (oid(2), GitObjectMetadata::Blob, b"second"),
+        ]);
+        let store = MemoryStore::default();
+        store.insert("mo-cid", multi_object.to_bytes());
+
+        let header = MultiObject::get_header("mo-cid", &store).await.unwrap();
+
+        assert_eq!(header.hash, "mo");
+        assert_eq!(header.total_size, 11);
+        assert_eq!(
+            header
+                .entries
+                .values()
+                .map(|entry| (entry.kind, entry.size))
+                .collect::<Vec<_>>(),
+            [(ObjectKind::Blob, 5), (ObjectKind::Blob, 6)]
+        );
+    }
+
+    #[test]
+    fn sections_that_are_not_brotli_are_rejected() {
+        let bytes = multi_object(&[(oid(1), GitObjectMetadata::Blob, b"first")]).split_layout();
+        let data_start = MULTI_OBJECT_PREFIX_LEN + header_len(&bytes).unwrap().unwrap();
+
+        let mut header = bytes.clone();
+        header[MULTI_OBJECT_PREFIX_LEN..data_start].fill(0xff);
+        assert!(MultiObject::from_bytes(header).is_err());
+        assert!(MultiObjectHeader::from_section(&[0xff; 16]).is_err());
+
+        let mut data = bytes[..data_start].to_vec();
+        data.extend_from_slice(b"not brotli");
+        assert!(MultiObject::from_bytes(data).is_err());
+
+        // Without the magic, the bytes are taken for a MultiObject compressed as a whole
+        assert!(MultiObject::from_bytes(b"not brotli".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn header_reads_of_what_is_not_brotli_fail() {
+        let mut bytes = multi_object(&[(oid(1), GitObjectMetadata::Blob, b"first")]).split_layout();
+        let data_start = MULTI_OBJECT_PREFIX_LEN + header_len(&bytes).unwrap().unwrap();
+        bytes[MULTI_OBJECT_PREFIX_LEN..data_start].fill(0xff);
+
+        let store = MemoryStore::default();
+        store.insert("header", bytes);
+        // A split envelope around a body without the magic
+        store.insert(
+            "split",
+            encode_artifact(Kind::MultiObject, b"not brotli".to_vec()),
+        );
+        store.insert("legacy", b"not brotli".to_vec());
+
+        for cid in ["header", "split", "legacy"] {
+            assert!(
+                MultiObject::get_header(cid, &store).await.is_err(),
+                "{}",
+                cid
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_past_the_probe_are_read_with_or_without_ranged_reads() {
+        let mut multi_object = MultiObject::from_parts("mo".to_owned(), vec![], BTreeMap::new());
+        for i in 0..6000u32 {
+            let data = i.to_string().into_bytes();
+            multi_object.add(GitObject {
+                git_hash: Oid::hash_object(ObjectType::Blob, &data)
+                    .unwrap()
+                    .to_string(),
+                kind: ObjectKind::Blob,
+                size: data.len() as u64,
+                metadata: GitObjectMetadata::Blob,
+                data,
+            });
+        }
+        let bytes = multi_object.to_bytes();
+        assert!(header_len(&bytes[ENVELOPE_LEN..]).unwrap().unwrap() > HEADER_PROBE_LEN);
+
+        // The probe, then the rest of the header
+        let ranged = CountingStore::default();
+        ranged.inner.insert("mo-cid", bytes.clone());
+        let header = MultiObject::get_header("mo-cid", &ranged).await.unwrap();
+        assert_eq!(header.entries.len(), 6000);
+        assert_eq!((ranged.gets.get(), ranged.ranges.get()), (0, 2));
+
+        // A store without ranged reads falls back to downloading all of it
+        let whole = MemoryStore::default();
+        whole.insert("mo-cid", bytes);
+        let header = MultiObject::get_header("mo-cid", &whole).await.unwrap();
+        assert_eq!(header.entries.len(), 6000);
+        assert_eq!(header.total_size, multi_object.total_size);
+    }
+
+    #[tokio::test]
+    async fn fixture_round_trips_through_a_fetch() {
+        let dir = TempDir::new().unwrap();
+        let refs = FixtureRepo::new()
+            .commits(5)
+            .branch("dev", 2)
+            .blob("assets/big.bin", 64 * 1024, Seed(42))
+            .tag_annotated("v1.0")
+            .wide(50)
+            .unicode_names()
+            .build(dir.path())
+            .unwrap();
+        let source = Repository::open_bare(dir.path()).unwrap();
+        let published = published(&source);
+
+        let (_dir, mut repo) = repository();
+        for (name, target) in &refs {
+            fetch(&published, *target, name, &mut repo).await.unwrap();
+        }
+
+        fixtures::same_objects(&source, &repo).unwrap();
+    }
+
+    #[test]
+    fn holes_left_by_a_fetch_name_the_object_and_where_it_was_listed() {
+        let (dir, repo) = repository();
+        let blob = repo.blob(b"contents").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("file", blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+        let tip = commit_tree(&repo, None, &[], tree, "tip");
+        let fetched = HashSet::from([tip, tree, blob]);
+        let limits = Limits::from_config(&Config::default());
+
+        let mut repo_data = RepoData::default();
+        for oid in [tip, tree] {
+            repo_data.objects.insert(oid.to_string(), "mo".to_owned());
         }
+        repo_data
+            .verify_fetched(tip, "refs/heads/main", &fetched, &repo, false, limits)
+            .unwrap();
+
+        let remove = |oid: Oid| {
+            let hex = oid.to_string();
+            std::fs::remove_file(
+                dir.path()
+                    .join(".git/objects")
+                    .join(&hex[..2])
+                    .join(&hex[2..]),
+            )
+            .unwrap();
+            Repository::open(dir.path()).unwrap()
+        };
+
+        let repo = remove(blob);
+        let e = repo_data
+            .verify_fetched(tip, "refs/heads/main", &fetched, &repo, false, limits)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "refs/heads/main: blob {}, referenced by {}, is missing after the fetch; the \
+                 repository index doesn't list it, so no MultiObject holds it",
+                blob, tree
+            )
+        );
+        // Left out by a partial clone's filter
+        repo_data
+            .verify_fetched(tip, "refs/heads/main", &fetched, &repo, true, limits)
+            .unwrap();
+
+        let repo = remove(tree);
+        let e = repo_data
+            .verify_fetched(tip, "refs/heads/main", &fetched, &repo, true, limits)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "refs/heads/main: tree {}, referenced by {}, is missing after the fetch; it \
+                 should have come from MultiObject mo",
+                tree, tip
+            )
+        );
+    }
 
-        Ok((new_ipf_id, None))
+    #[test]
+    fn repo_data_json_has_cid_strings_and_lowercase_kinds() {
+        let cid = Cid::try_from("QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG").unwrap();
+        let blob = "b".repeat(40);
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), "a".repeat(40));
+        repo_data.record_push(record(1));
+        repo_data.cids.insert("mo".to_owned(), cid.to_bytes());
+        repo_data.large_blobs.insert(
+            blob.clone(),
+            LargeBlob {
+                cid: cid.to_bytes(),
+                size: 7,
+            },
+        );
+        repo_data.ref_targets.insert(
+            "refs/heads/main".to_owned(),
+            RefTarget {
+                kind: ObjectKind::Commit,
+                peeled: None,
+            },
+        );
+
+        let json = serde_json::to_value(&repo_data).unwrap();
+        assert_eq!(json["cids"]["mo"], cid.to_string());
+        assert_eq!(json["large_blobs"][&blob]["cid"], cid.to_string());
+        assert_eq!(json["ref_targets"]["refs/heads/main"]["kind"], "commit");
+
+        let parsed: RepoData = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.encode(), repo_data.encode());
+
+        let parsed: RepoData =
+            serde_json::from_str(r#"{"refs": {"refs/heads/main": "aa"}}"#).unwrap();
+        assert_eq!(parsed.refs.len(), 1);
+        assert!(parsed.objects.is_empty() && parsed.history.is_empty());
+        assert!(serde_json::from_str::<RepoData>(r#"{"branches": {}}"#).is_err());
+        assert!(serde_json::from_str::<RepoData>(r#"{"cids": {"mo": "not a cid"}}"#).is_err());
+    }
+
+    #[test]
+    fn summary_leaves_ref_names_out() {
+        let mut repo_data = RepoData::default();
+        assert!(repo_data.summary().ends_with("HEAD at none"));
+
+        repo_data
+            .refs
+            .insert("refs/heads/secret-project".to_owned(), "a".repeat(40));
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/secret-project".to_owned());
+        repo_data.record_push(record(1));
+
+        assert_eq!(
+            repo_data.summary(),
+            format!(
+                "1 refs, 0 objects, 1 pushes logged, identity {}, HEAD at {}",
+                repo_data.identity(),
+                "a".repeat(40)
+            )
+        );
+    }
+
+    #[test]
+    fn chain_ids_are_their_bare_numbers() {
+        assert_eq!(IpsId(4).to_string(), "4");
+        assert_eq!("4".parse(), Ok(IpsId(4)));
+        assert!("-1".parse::<IpsId>().is_err());
+        assert!("IPS 4".parse::<IpsId>().is_err());
+        assert_eq!(
+            IpfId(u64::MAX).to_string().parse::<IpfId>(),
+            Ok(IpfId(u64::MAX))
+        );
+
+        assert_eq!(IpsId(4).encode(), 4u32.encode());
+        assert_eq!(SubassetId(2).encode(), 2u32.encode());
+        assert_eq!(IpfId::decode(&mut &7u64.encode()[..]).unwrap(), IpfId(7));
+
+        assert_eq!(serde_json::to_string(&SubassetId(2)).unwrap(), "2");
+        assert_eq!(serde_json::from_str::<IpfId>("9").unwrap(), IpfId(9));
+        assert_eq!(
+            serde_json::to_string(&[IpfId(1), IpfId(2)]).unwrap(),
+            "[1,2]"
+        );
     }
 }
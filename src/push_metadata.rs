@@ -0,0 +1,279 @@
+//! The metadata of the `operate_multisig` call that publishes a push, which tells indexers what
+//! the push did to each ref.
+
+use crate::{
+    error,
+    primitives::{BoxResult, RefChange},
+};
+use serde::Serialize;
+
+/// INV4's `MaxMetadata` on Tinkernet, for nodes whose metadata doesn't state it.
+pub const DEFAULT_MAX_METADATA: usize = 10_000;
+
+/// The most characters a push message may have.
+pub const MESSAGE_LIMIT: usize = 500;
+
+/// `raw` as a push message: tabs turned into spaces, other control characters but newlines
+/// dropped and surrounding whitespace trimmed. `None` when nothing is left.
+pub fn push_message(raw: &str) -> BoxResult<Option<String>> {
+    let message: String = raw
+        .chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .filter(|&c| c == '\n' || !c.is_control())
+        .collect();
+    let message = message.trim();
+
+    let length = message.chars().count();
+    if length > MESSAGE_LIMIT {
+        error!(format!(
+            "The push message has {} characters; it may have {} at most",
+            length, MESSAGE_LIMIT
+        ))
+    }
+
+    Ok(Some(message.to_owned()).filter(|message| !message.is_empty()))
+}
+
+/// How a push moved a ref.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    Create,
+    Delete,
+    FastForward,
+    Force,
+    /// Any tag that isn't deleted, as tags are expected to be created once and left alone
+    Tag,
+}
+
+impl ChangeKind {
+    /// Classify `change`, where `fast_forward` says whether its new tip descends from the old.
+    pub fn of(change: &RefChange, fast_forward: bool) -> Self {
+        match (&change.old, &change.new) {
+            (_, None) => Self::Delete,
+            _ if change.name.starts_with("refs/tags/") => Self::Tag,
+            (None, Some(_)) => Self::Create,
+            _ if fast_forward => Self::FastForward,
+            _ => Self::Force,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Change<'a> {
+    #[serde(rename = "ref")]
+    name: &'a str,
+    old: Option<&'a str>,
+    new: Option<&'a str>,
+    kind: ChangeKind,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    protocol: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<Change<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_count: Option<usize>,
+}
+
+/// The metadata for an update of the repository's `RepoMeta`.
+pub fn meta_update() -> Vec<u8> {
+    without_changes("meta")
+}
+
+/// The metadata for `inv4-git migrate` swapping in re-encoded artifacts.
+pub fn migration() -> Vec<u8> {
+    without_changes("migrate")
+}
+
+fn without_changes(kind: &'static str) -> Vec<u8> {
+    serde_json::to_vec(&Metadata {
+        protocol: "inv4-git",
+        kind,
+        message: None,
+        changes: None,
+        change_count: None,
+    })
+    .unwrap_or_default()
+}
+
+/// The metadata for a push of `changes` described by `message`, as JSON of at most `max_len`
+/// bytes.
+///
+/// When listing every change would be too long, only their number is given. The message tells
+/// multisig members why they are asked to sign, so it is kept over the list, and cut short
+/// before it is left out. When even that is too long, only what marks the call as a push is
+/// given. Its length never fails a push.
+pub fn encode(
+    changes: &[(RefChange, ChangeKind)],
+    message: Option<&str>,
+    max_len: usize,
+) -> Vec<u8> {
+    let listed = changes
+        .iter()
+        .map(|(change, kind)| Change {
+            name: &change.name,
+            old: change.old.as_deref(),
+            new: change.new.as_deref(),
+            kind: *kind,
+        })
+        .collect();
+
+    let count = Some(changes.len());
+
+    [
+        push(Some(listed), None, message),
+        push(None, count, message),
+    ]
+    .into_iter()
+    .find(|metadata| metadata.len() <= max_len)
+    .or_else(|| {
+        truncated(message?, max_len, |message| {
+            push(None, count, Some(message))
+        })
+    })
+    .or_else(|| Some(push(None, count, None)).filter(|metadata| metadata.len() <= max_len))
+    .unwrap_or_else(|| push(None, None, None))
+}
+
+fn push(
+    changes: Option<Vec<Change>>,
+    change_count: Option<usize>,
+    message: Option<&str>,
+) -> Vec<u8> {
+    serde_json::to_vec(&Metadata {
+        protocol: "inv4-git",
+        kind: "push",
+        message,
+        changes,
+        change_count,
+    })
+    .unwrap_or_default()
+}
+
+/// `metadata` of the longest start of `message` that fits in `max_len` bytes, marked with `…`
+/// as cut short. `None` when not even its first character fits.
+fn truncated(message: &str, max_len: usize, metadata: impl Fn(&str) -> Vec<u8>) -> Option<Vec<u8>> {
+    let ends: Vec<usize> = message.char_indices().map(|(i, _)| i).collect();
+    let mut best = None;
+
+    // Binary search for the most characters to keep, fewer than all of them
+    let (mut low, mut high) = (1, ends.len().saturating_sub(1));
+    while low <= high {
+        let keep = (low + high) / 2;
+        let encoded = metadata(&format!("{}…", &message[..ends[keep]]));
+
+        if encoded.len() <= max_len {
+            best = Some(encoded);
+            low = keep + 1;
+        } else {
+            high = keep - 1;
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(name: &str, old: Option<&str>, new: Option<&str>) -> RefChange {
+        RefChange {
+            name: name.to_owned(),
+            old: old.map(str::to_owned),
+            new: new.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn changes_are_classified() {
+        let of =
+            |name, old, new, fast_forward| ChangeKind::of(&change(name, old, new), fast_forward);
+
+        assert_eq!(
+            of("refs/heads/main", None, Some("a"), false),
+            ChangeKind::Create
+        );
+        assert_eq!(
+            of("refs/heads/main", Some("a"), None, false),
+            ChangeKind::Delete
+        );
+        assert_eq!(
+            of("refs/tags/v1", Some("a"), None, false),
+            ChangeKind::Delete
+        );
+        assert_eq!(
+            of("refs/tags/v1", Some("a"), Some("b"), false),
+            ChangeKind::Tag
+        );
+        assert_eq!(
+            of("refs/heads/main", Some("a"), Some("b"), true),
+            ChangeKind::FastForward
+        );
+        assert_eq!(
+            of("refs/heads/main", Some("a"), Some("b"), false),
+            ChangeKind::Force
+        );
+    }
+
+    #[test]
+    fn changes_are_listed_while_they_fit() {
+        let changes = [(
+            change("refs/heads/main", Some("a"), Some("b")),
+            ChangeKind::FastForward,
+        )];
+
+        assert_eq!(
+            String::from_utf8(encode(&changes, None, DEFAULT_MAX_METADATA)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push","changes":[{"ref":"refs/heads/main","old":"a","new":"b","kind":"fast-forward"}]}"#
+        );
+        assert_eq!(
+            String::from_utf8(encode(&changes, None, 60)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push","change_count":1}"#
+        );
+        assert_eq!(
+            String::from_utf8(encode(&changes, None, 10)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push"}"#
+        );
+    }
+
+    #[test]
+    fn push_messages_are_cleaned_up_and_limited() {
+        assert_eq!(
+            push_message("  Fix\tthe build\r\n\u{7}second line \n").unwrap(),
+            Some("Fix the build\nsecond line".to_owned())
+        );
+        assert_eq!(push_message(" \t\n").unwrap(), None);
+
+        assert!(push_message(&"é".repeat(MESSAGE_LIMIT)).is_ok());
+        assert!(push_message(&"é".repeat(MESSAGE_LIMIT + 1)).is_err());
+    }
+
+    #[test]
+    fn messages_are_kept_over_the_changes_and_cut_short_last() {
+        let changes = [(
+            change("refs/heads/main", Some("a"), Some("b")),
+            ChangeKind::FastForward,
+        )];
+        let message = Some("Release 1.0 after the audit");
+
+        assert_eq!(
+            String::from_utf8(encode(&changes, message, 94)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push","message":"Release 1.0 after the audit","change_count":1}"#
+        );
+        assert_eq!(
+            String::from_utf8(encode(&changes, message, 80)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push","message":"Release 1.…","change_count":1}"#
+        );
+        assert_eq!(
+            String::from_utf8(encode(&changes, message, 60)).unwrap(),
+            r#"{"protocol":"inv4-git","type":"push","change_count":1}"#
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Objects a best-effort fetch skipped because their data didn't match their hash.
+
+use crate::primitives::BoxResult;
+use git2::Oid;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct QuarantinedObject {
+    /// The hash RepoData lists the object under
+    pub expected: String,
+    /// What its data actually hashes to
+    pub actual: String,
+    /// The MultiObject it came from
+    pub multi_object: String,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Quarantine {
+    /// The remote the fetch was from
+    pub remote: String,
+    pub objects: Vec<QuarantinedObject>,
+    /// Refs that could not be fetched because they reach a quarantined object
+    pub failed_refs: Vec<String>,
+}
+
+impl Quarantine {
+    pub fn add(&mut self, object: QuarantinedObject) {
+        self.objects.push(object);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Whether any of `oids` is quarantined.
+    pub fn affects(&self, oids: &HashSet<Oid>) -> bool {
+        self.objects
+            .iter()
+            .filter_map(|object| object.expected.parse().ok())
+            .any(|oid| oids.contains(&oid))
+    }
+
+    /// Write the report into the remote's `state_dir`, returning its path.
+    pub fn save(&self, state_dir: &Path) -> BoxResult<PathBuf> {
+        let path = state_dir.join("quarantine.json");
+        fs::create_dir_all(path.parent().ok_or("Report path has no parent")?)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn object(expected: &str) -> QuarantinedObject {
+        QuarantinedObject {
+            expected: expected.into(),
+            actual: "0".repeat(40),
+            multi_object: "1".repeat(40),
+        }
+    }
+
+    #[test]
+    fn only_listed_objects_are_affected() {
+        let listed = Oid::from_str(&"a".repeat(40)).unwrap();
+        let other = Oid::from_str(&"b".repeat(40)).unwrap();
+
+        let mut quarantine = Quarantine::default();
+        assert!(quarantine.is_empty());
+        assert!(!quarantine.affects(&HashSet::from([listed])));
+
+        quarantine.add(object(&listed.to_string()));
+        quarantine.add(object("not a hash"));
+        assert!(!quarantine.is_empty());
+        assert!(quarantine.affects(&HashSet::from([other, listed])));
+        assert!(!quarantine.affects(&HashSet::from([other])));
+    }
+
+    #[test]
+    fn reports_are_saved_as_json_in_the_state_dir() {
+        let dir = TempDir::new().unwrap();
+        let state_dir = dir.child("inv4").join("origin");
+
+        let mut quarantine = Quarantine {
+            remote: "origin".into(),
+            ..Default::default()
+        };
+        quarantine.add(object(&"a".repeat(40)));
+        quarantine.failed_refs.push("refs/heads/main".into());
+
+        let path = quarantine.save(&state_dir).unwrap();
+        assert_eq!(path, state_dir.join("quarantine.json"));
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(saved["remote"], "origin");
+        assert_eq!(saved["objects"][0]["expected"], "a".repeat(40));
+        assert_eq!(saved["failed_refs"][0], "refs/heads/main");
+    }
+}
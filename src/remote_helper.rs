@@ -0,0 +1,3333 @@
+use crate::{
+    answers::Answers,
+    auth::{self, auth_flow, confirm, AuthCache, SigningContext, SigningScope},
+    batching::{self, Plan, WeightModel},
+    chain::{self, Chain, Confirmation, Included, IpsFlags, Snapshot},
+    credential::Credential,
+    error,
+    events::{self, Event},
+    export_call::{self, ExportedCall},
+    fees::{self, Breakdown, Item},
+    filter::{self, BlobFilter, SkippedBlobs},
+    guidance,
+    journal::{PushJournal, UnconfirmedPush},
+    meta::RepoMeta,
+    metrics,
+    offline::{LastKnown, Offline, OfflineMode},
+    output, packer,
+    policy::{self, Policy, RefUpdate},
+    preview::Preview,
+    primitives::{BoxResult, Config, IpfId, MultiObjectIndex, PushRecord, RefChange, RepoData},
+    push_metadata::{self, ChangeKind},
+    quarantine::Quarantine,
+    remote_url::RemoteUrl,
+    repair::Refetch,
+    runtime::{ChainCall, Publish, PublishPart},
+    seen_tips::SeenTips,
+    shutdown,
+    store::{default_store, ObjectStore},
+    transcript,
+    traversal::Limits,
+    util::{human_size, is_fast_forward, quote_c_style, resolve_push_source, unquote_c_style},
+};
+use async_trait::async_trait;
+use git2::{Direction, Oid, PushOptions, Reference, RemoteCallbacks, Repository};
+use log::debug;
+use std::{
+    env,
+    error::Error,
+    fmt,
+    future::Future,
+    io::{self, Write},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+    sync::Mutex,
+    time::Instant,
+};
+use subxt::{
+    ext::sp_core::{sr25519::Pair, H256},
+    tx::PairSigner,
+    PolkadotConfig,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
+
+#[cfg(unix)]
+use crate::daemon;
+
+/// Ref namespaces stored in RepoData, mapped onto themselves on the remote.
+const NAMESPACES: &[&str] = &["refs/heads", "refs/tags"];
+
+/// The blob limit of the filter git asked for with `option filter`, for `--filter` clones and
+/// fetches.
+static OPTION_BLOB_LIMIT: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Where `git push -o export-call=<path>` asked the publish call to be exported to.
+static OPTION_EXPORT_CALL: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// What git is told the helper supports in answer to `capabilities`.
+///
+/// Needs no network, so git can be answered before the session is connected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub option: bool,
+    pub push: bool,
+    pub fetch: bool,
+    /// `<src>:<dst>` refspecs constraining the refs git hands us
+    pub refspecs: Vec<String>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            option: true,
+            push: true,
+            fetch: true,
+            refspecs: NAMESPACES
+                .iter()
+                .map(|namespace| format!("{0}/*:{0}/*", namespace))
+                .collect(),
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn lines(&self) -> Vec<String> {
+        let flags = [
+            (self.option, "option"),
+            (self.push, "push"),
+            (self.fetch, "fetch"),
+        ];
+
+        flags
+            .into_iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, name)| name.to_owned())
+            .chain(
+                self.refspecs
+                    .iter()
+                    .map(|refspec| format!("refspec {}", refspec)),
+            )
+            .collect()
+    }
+}
+
+/// The exact protocol response: one capability per line and a single blank line to finish.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines() {
+            writeln!(f, "{}", line)?;
+        }
+
+        writeln!(f)
+    }
+}
+
+/// A single `push` command: `[+]<src>:<dst>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushRequest {
+    pub src: String,
+    pub dst: String,
+    pub force: bool,
+}
+
+impl FromStr for PushRequest {
+    type Err = Box<dyn Error>;
+
+    fn from_str(refspec: &str) -> Result<Self, Self::Err> {
+        // Separate source, destination and the force flag
+        let (src, dst) = refspec
+            .split_once(':')
+            .ok_or_else(|| format!("Could not read destination ref from refspec: {:?}", refspec))?;
+
+        let (src, force) = match src.strip_prefix('+') {
+            Some(src) => (src, true),
+            None => (src, false),
+        };
+
+        Ok(Self {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            force,
+        })
+    }
+}
+
+/// A single `fetch` command: `<sha> <name>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchRequest {
+    pub sha: String,
+    pub name: String,
+}
+
+/// The outcome of one `push` command, reported back to git by `push_answer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushOutcome {
+    pub dst: String,
+    pub result: Result<Updated, PushError>,
+}
+
+/// Where a push left a ref: its tips before and after, `None` where it didn't exist. git is only
+/// told `ok`, so replayed transcripts leave both out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Updated {
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Why a ref wasn't pushed, as the user should read it, over as many lines as it takes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushError(String);
+
+impl PushError {
+    pub fn new(message: impl fmt::Display) -> Self {
+        Self(message.to_string())
+    }
+
+    /// The message on one line, its lines joined with `; `.
+    fn flattened(&self) -> String {
+        self.0
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The outcome of one `fetch` command. git has no per-ref fetch results, so failures are only
+/// reported to the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchReport {
+    pub name: String,
+    pub result: Result<(), String>,
+}
+
+/// What answers the commands that need the chain and store: a `Session`, a daemon it is
+/// forwarded to, or the recorded answers of a transcript being replayed.
+#[async_trait(?Send)]
+pub trait Backend {
+    async fn list(&mut self, for_push: bool) -> BoxResult<Vec<(String, String)>>;
+    async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>>;
+    async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>>;
+}
+
+/// Everything a remote-helper invocation needs to serve git's commands.
+pub struct Session {
+    /// The name git knows the remote by; the URL itself when pushing to a bare URL
+    pub remote_name: String,
+    /// The stored credential to sign with, from `remote.<name>.inv4-account`
+    pub account: Option<String>,
+    pub url: RemoteUrl,
+    pub config: Config,
+    pub chain: Rc<Chain>,
+    pub store: Rc<dyn ObjectStore>,
+    pub repo: Repository,
+    pub remote_repo: RepoData,
+    /// The block `remote_repo` was read at, which fetches look MultiObjects up at too
+    pub snapshot: Snapshot,
+    /// Refs fetched so far, to push to `tee_remote` before exiting, as `(name, tip, force)`
+    pub tee: Vec<(String, Oid, bool)>,
+    /// Whether the repository's name and description were shown already
+    pub banner_shown: bool,
+    /// The key the first push unlocked, kept for later ones with `auth_cache = "session"`
+    unlocked: Option<Pair>,
+    /// The stored credential pushes were last signed with, to renew or erase once the chain
+    /// accepted or refused its key
+    credential: Option<Credential>,
+}
+
+impl Session {
+    pub async fn new(
+        remote_name: String,
+        mut url: RemoteUrl,
+        config: Config,
+        store: Box<dyn ObjectStore>,
+    ) -> BoxResult<Self> {
+        let repo = Repository::open_from_env()?;
+        let (config, account) = remote_config(&repo, &remote_name, config)?;
+
+        let store: Rc<dyn ObjectStore> = store.into();
+        let (chain, health) = tokio::join!(Chain::connect(&config), store.check());
+
+        if let Err(e) = health {
+            output::warn(format!("object store is unreachable: {}", e));
+        }
+
+        let chain = Rc::new(chain?);
+
+        url.resolve(&config, &chain, store.as_ref()).await?;
+
+        let snapshot = Snapshot::take(&chain).await?;
+        let remote_repo = RepoData::chain_get(
+            url.ips_id,
+            store.as_ref(),
+            &chain,
+            config.repodata_lookback_blocks,
+            Some(&snapshot),
+        )
+        .await?;
+
+        Ok(Self::attach(
+            repo,
+            remote_name,
+            account,
+            url,
+            config,
+            chain,
+            store,
+            remote_repo,
+            snapshot,
+        )
+        .await)
+    }
+
+    /// A session over a chain connection and store that are already open, as the daemon keeps
+    /// them, for `url` resolved and its RepoData loaded already, as of `snapshot`.
+    pub async fn attach(
+        repo: Repository,
+        remote_name: String,
+        account: Option<String>,
+        url: RemoteUrl,
+        config: Config,
+        chain: Rc<Chain>,
+        store: Rc<dyn ObjectStore>,
+        remote_repo: RepoData,
+        snapshot: Snapshot,
+    ) -> Self {
+        debug!(
+            "Remote {}: IPS {} on {}",
+            remote_name, url.ips_id, config.chain_endpoint
+        );
+        debug!("RepoData: {}", remote_repo.summary());
+        metrics::repository(url.ips_id, &remote_repo);
+
+        let session = Self {
+            remote_name,
+            account,
+            url,
+            config,
+            chain,
+            store,
+            repo,
+            remote_repo,
+            snapshot,
+            tee: vec![],
+            banner_shown: false,
+            unlocked: None,
+            credential: None,
+        };
+        session.remember_repo_data(session.snapshot.number());
+        session.check_unconfirmed().await;
+
+        session
+    }
+
+    /// Where state kept between invocations for this remote lives, see `state_dir`.
+    pub fn state_dir(&self) -> PathBuf {
+        state_dir(&self.repo, &self.remote_name)
+    }
+
+    /// Keep `remote_repo`, as of `block_number`, to serve when the chain can't be reached.
+    fn remember_repo_data(&self, block_number: u32) {
+        if let Err(e) = LastKnown::save(&self.state_dir(), &self.remote_repo, block_number) {
+            debug!("Could not keep the RepoData for offline use: {}", e);
+        }
+    }
+
+    /// The refs to advertise to git as `(value, name)` pairs, where the value is a sha or an
+    /// `@<ref>` symref, and annotated tags are followed by a peeled `<tag>^{}` line for fetches.
+    /// A branch-filtered URL only advertises that branch, and HEAD if it is the
+    /// default branch.
+    ///
+    /// Fetch lists warn about refs the remote rewrote, and leave them out with
+    /// `protect_against_rewrites`.
+    pub async fn list(&self, for_push: bool) -> BoxResult<Vec<(String, String)>> {
+        // A fetch would otherwise fail on the first object, or look like an empty clone
+        if !for_push && !self.remote_repo.refs.is_empty() && self.remote_repo.objects.is_empty() {
+            error!("The repository index lists refs but no objects; it is corrupt or was wiped");
+        }
+
+        let withheld = match for_push {
+            true => vec![],
+            false => self.check_rewrites().await?,
+        };
+
+        advertised_refs(
+            &self.remote_repo,
+            self.url.branch.as_deref(),
+            &withheld,
+            for_push,
+        )
+    }
+
+    /// Warn about every ref the remote moved to a tip that doesn't contain the one last fetched
+    /// or pushed, returning those to withhold from git under `protect_against_rewrites`.
+    async fn check_rewrites(&self) -> BoxResult<Vec<String>> {
+        let seen = SeenTips::load(&self.state_dir());
+        let index = MultiObjectIndex::Chain {
+            chain: &self.chain,
+            ips_id: self.url.ips_id,
+            at: Some(&self.snapshot),
+        };
+        let protect = self.config.protect_against_rewrites
+            && env::var("GIT_INV4_ACCEPT_REWRITES").as_deref() != Ok("1");
+        let mut withheld = vec![];
+
+        for (name, tip) in self
+            .remote_repo
+            .refs
+            .iter()
+            .filter(|(name, _)| self.accepts_ref(name))
+        {
+            // Clones from before the tips were kept still have their remote-tracking refs
+            let old = match seen.tips.get(name) {
+                Some(old) => old.parse().ok(),
+                None => self
+                    .tracking_ref(name)
+                    .and_then(|tracking| self.repo.refname_to_id(&tracking).ok()),
+            };
+            let (old, new): (Oid, Oid) = match old {
+                Some(old) if old.to_string() != *tip => (old, tip.parse()?),
+                _ => continue,
+            };
+
+            if self
+                .remote_repo
+                .is_ancestor(old, new, &self.repo, self.store.as_ref(), &index)
+                .await?
+            {
+                continue;
+            }
+
+            let pusher = self
+                .remote_repo
+                .history
+                .iter()
+                .rev()
+                .find(|record| {
+                    record
+                        .refs_changed
+                        .iter()
+                        .any(|change| change.name == *name && change.new.as_ref() == Some(tip))
+                })
+                .map(|record| format!(" by {} in block {}", record.pusher, record.block_number))
+                .unwrap_or_default();
+
+            output::warn(format!(
+                "{} was rewritten on the remote{}: it moved from {} to {}, which doesn't contain it",
+                name, pusher, old, new
+            ));
+
+            if protect {
+                withheld.push(name.clone());
+            }
+        }
+
+        if !withheld.is_empty() {
+            output::warn(format!(
+                "Not fetching {} because of protect_against_rewrites; inspect the new history, then fetch with GIT_INV4_ACCEPT_REWRITES=1 to accept it",
+                withheld.join(", ")
+            ));
+        }
+
+        Ok(withheld)
+    }
+
+    /// Remember where `changes` left refs, so later fetches notice when they are rewritten.
+    fn remember_tips<'a>(&self, changes: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) {
+        let mut seen = SeenTips::load(&self.state_dir());
+
+        for (name, git_hash) in changes {
+            seen.set(name, git_hash);
+        }
+
+        if let Err(e) = seen.save() {
+            output::warn(format!("Could not record the fetched tips: {}", e));
+        }
+    }
+
+    /// Whether `name` may be advertised and pushed to through this URL.
+    fn accepts_ref(&self, name: &str) -> bool {
+        accepts_ref(self.url.branch.as_deref(), name)
+    }
+
+    pub async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+        // git only updates refs once the whole batch succeeded, so stopping between objects
+        // leaves nothing half-done
+        let _cancellable = shutdown::cancellable();
+        let index = MultiObjectIndex::Chain {
+            chain: &self.chain,
+            ips_id: self.url.ips_id,
+            at: Some(&self.snapshot),
+        };
+
+        let best_effort =
+            self.config.best_effort_fetch || env::var("GIT_INV4_BEST_EFFORT").as_deref() == Ok("1");
+        let refetching = env::var("GIT_INV4_REFETCH").as_deref() == Ok("1");
+        let mut refetch = Refetch::default();
+        let mut filter = self
+            .url
+            .blob_limit
+            .or_else(blob_limit_option)
+            .map(BlobFilter::new);
+        let mut quarantine = Quarantine {
+            remote: self.remote_name.clone(),
+            ..Default::default()
+        };
+
+        if !self.banner_shown {
+            self.banner_shown = true;
+
+            if let Some(banner) =
+                RepoMeta::banner(&self.chain, self.store.as_ref(), self.url.ips_id).await
+            {
+                output::info(banner);
+            }
+        }
+
+        // Nodes of recent pushers have the objects, and bitswap only asks connected peers
+        // before searching the network
+        self.store
+            .connect_hints(&self.remote_repo.swarm_hints)
+            .await;
+
+        if let Some(filter) = &filter {
+            filter::configure_promisor(&self.repo, &self.remote_name, filter)?;
+        }
+
+        let reports = fetch_requests(
+            &self.remote_repo,
+            &self.remote_name,
+            &mut self.repo,
+            self.store.as_ref(),
+            &index,
+            requests,
+            best_effort.then_some(&mut quarantine),
+            refetching.then_some(&mut refetch),
+            filter.as_mut(),
+            &self.config,
+        )
+        .await?;
+        let fetched: Vec<_> = requests
+            .iter()
+            .zip(&reports)
+            .filter(|(_, report)| report.result.is_ok())
+            .map(|(request, _)| request.clone())
+            .collect();
+
+        if refetching {
+            refetch.report();
+        }
+
+        if let Some(filter) = &filter {
+            self.report_skipped(filter)?;
+        }
+
+        // Lazy fetches of missing objects name them by their hash rather than a ref
+        self.remember_tips(
+            fetched
+                .iter()
+                .filter(|request| request.name.starts_with("refs/"))
+                .map(|request| (request.name.as_str(), Some(request.sha.as_str()))),
+        );
+
+        if self.config.tee_remote.is_some() {
+            self.queue_tee(&fetched)?;
+        }
+
+        if quarantine.is_empty() {
+            return Ok(reports);
+        }
+
+        let report = quarantine.save(&self.state_dir())?;
+        output::warn(format!(
+            "{} corrupt objects were skipped, see {}",
+            quarantine.objects.len(),
+            report.display()
+        ));
+
+        // git has no per-ref fetch results, but the other refs' objects are in place now
+        if !quarantine.failed_refs.is_empty() {
+            error!(format!(
+                "Could not fetch {}, which need corrupt objects; the other refs can be fetched on their own",
+                quarantine.failed_refs.join(", ")
+            ))
+        }
+
+        Ok(reports)
+    }
+
+    /// Remember the blobs `filter` left out, and say how to get them.
+    fn report_skipped(&self, filter: &BlobFilter) -> BoxResult<()> {
+        let mut skipped = SkippedBlobs::load(&self.state_dir());
+        skipped.blobs.extend(&filter.skipped);
+        skipped.prune(&self.repo)?;
+        skipped.save()?;
+
+        if filter.skipped.is_empty() {
+            return Ok(());
+        }
+
+        output::warn(format!(
+            "Left out {} blobs of {} or more, {} in all; they are listed in {}",
+            filter.skipped.len(),
+            human_size(filter.limit as usize),
+            human_size(filter.skipped.values().sum::<u64>() as usize),
+            skipped.path().display()
+        ));
+        output::info(format!(
+            "git fetches them one at a time when a checkout needs them; limit the checkout with `git sparse-checkout set <dirs>`, or download them all with `git-remote-inv4 fetch-blobs --ips {} --remote {}`",
+            self.url.ips_id, self.remote_name
+        ));
+
+        Ok(())
+    }
+
+    /// Remember the refs `requests` fetched for `push_tee`, force-pushing those that didn't move
+    /// forward from their remote-tracking ref.
+    fn queue_tee(&mut self, requests: &[FetchRequest]) -> BoxResult<()> {
+        for request in requests
+            .iter()
+            .filter(|request| request.name.starts_with("refs/"))
+        {
+            let tip: Oid = request.sha.parse()?;
+            let force = self
+                .tracking_ref(&request.name)
+                .and_then(|tracking| self.repo.refname_to_id(&tracking).ok())
+                .map_or(false, |old| {
+                    !is_fast_forward(&self.repo, old, tip).unwrap_or(false)
+                });
+
+            self.tee.retain(|(name, ..)| name != &request.name);
+            self.tee.push((request.name.clone(), tip, force));
+        }
+
+        Ok(())
+    }
+
+    /// Push the refs fetched in this session to `tee_remote`. Runs once git has been answered,
+    /// and only warns when it fails, as the fetch itself succeeded.
+    pub fn push_tee(&self) {
+        let url = match &self.config.tee_remote {
+            Some(url) if !self.tee.is_empty() => url,
+            _ => return,
+        };
+
+        match push_tee_refs(&self.repo, url, &self.tee) {
+            Ok(()) => output::info(format!("Copied {} fetched refs to {}", self.tee.len(), url)),
+            Err(e) => output::warn(format!("Could not copy fetched refs to {}: {}", url, e)),
+        }
+    }
+
+    /// Commits git's remote-tracking refs say the remote had at last contact, provided the remote
+    /// still has them.
+    fn known_remote_tips(&self) -> BoxResult<Vec<Oid>> {
+        let mut tips = vec![];
+
+        for reference in self
+            .repo
+            .references_glob(&format!("refs/remotes/{}/*", self.remote_name))?
+        {
+            let oid = match reference?.peel_to_commit() {
+                Ok(commit) => commit.id(),
+                Err(_) => continue,
+            };
+
+            let git_hash = oid.to_string();
+            if self.remote_repo.objects.contains_key(&git_hash)
+                || self.remote_repo.refs.values().any(|tip| *tip == git_hash)
+            {
+                tips.push(oid);
+            }
+        }
+
+        debug!("Known remote tips: {:?}", tips);
+        Ok(tips)
+    }
+
+    /// Whether the remote already has everything `request` would push, i.e. `dst` at the object
+    /// `src` points at.
+    fn already_pushed(&self, request: &PushRequest) -> bool {
+        if request.src.is_empty() || !self.accepts_ref(&request.dst) {
+            return false;
+        }
+
+        self.pushed_oid(request)
+            .map_or(false, |oid| landed(&self.remote_repo, &request.dst, oid))
+    }
+
+    /// What `request.src` points at.
+    fn pushed_oid(&self, request: &PushRequest) -> BoxResult<Oid> {
+        resolve_push_source(&self.repo, &request.src)
+    }
+
+    /// The remote-tracking ref `dst` is fetched into, per the remote's fetch refspecs. Pushes to a
+    /// bare URL have none.
+    fn tracking_ref(&self, dst: &str) -> Option<String> {
+        let remote = self.repo.find_remote(&self.remote_name).ok()?;
+        let tracking_ref = remote
+            .refspecs()
+            .filter(|refspec| refspec.direction() == Direction::Fetch)
+            .find(|refspec| refspec.src_matches(dst))?
+            .transform(dst)
+            .ok()?
+            .as_str()?
+            .to_owned();
+
+        Some(tracking_ref)
+    }
+
+    /// Make sure a regular push only adds to the remote ref. When it doesn't, show how far the two
+    /// sides diverged and how to catch up, offering to fetch the remote side right away.
+    async fn check_fast_forward(&mut self, request: &PushRequest) -> BoxResult<()> {
+        let remote: Oid = match self.remote_repo.refs.get(&request.dst) {
+            Some(git_hash) if !request.force && !request.src.is_empty() => git_hash.parse()?,
+            _ => return Ok(()),
+        };
+
+        let local = self.pushed_oid(request)?;
+        if local == remote {
+            return Ok(());
+        }
+
+        output::status(format!("Checking {} for work ahead of us...", request.dst));
+
+        // The remote tip is known locally, so ancestry can be decided without the network
+        if self.repo.odb()?.read_header(remote).is_ok()
+            && is_fast_forward(&self.repo, remote, local)?
+        {
+            return Ok(());
+        }
+
+        let index = MultiObjectIndex::Chain {
+            chain: &self.chain,
+            ips_id: self.url.ips_id,
+            at: None,
+        };
+
+        // Only commits can be rebased or merged; remote branches hold nothing else
+        let local_commit = self.repo.find_object(local, None)?.peel_to_commit().ok();
+        let remote_commit = match self.repo.find_object(remote, None) {
+            Ok(object) => object.peel_to_commit().ok().map(|commit| commit.id()),
+            Err(_) => request.dst.starts_with("refs/heads/").then_some(remote),
+        };
+
+        if let (Some(local_commit), Some(remote_commit)) = (local_commit, remote_commit) {
+            let (ahead, behind) = self
+                .remote_repo
+                .divergence(
+                    local_commit.id(),
+                    remote_commit,
+                    &self.repo,
+                    self.store.as_ref(),
+                    &index,
+                )
+                .await?;
+
+            output::info(format!(
+                "{} has diverged: {} has {} commit(s) the remote lacks, the remote's {} has {} commit(s) missing locally",
+                request.dst, local, ahead, remote, behind
+            ));
+        } else {
+            output::info(format!(
+                "{} has diverged: the remote is at {}, which {} doesn't contain",
+                request.dst, remote, local
+            ));
+        }
+
+        let tracking_ref = match self.tracking_ref(&request.dst) {
+            Some(tracking_ref) => tracking_ref,
+            None => {
+                output::info(format!(
+                    "To catch up, run `git fetch {} {}` and rebase onto or merge FETCH_HEAD, then push again",
+                    self.remote_name, request.dst
+                ));
+                error!("non-fast-forward; fetch first")
+            }
+        };
+        let tracking = tracking_ref
+            .strip_prefix("refs/remotes/")
+            .unwrap_or(&tracking_ref)
+            .to_owned();
+
+        let auto_fetch = match env::var("GIT_INV4_AUTO_FETCH").as_deref() {
+            Ok("0") => false,
+            Ok("1") => true,
+            _ if output::stderr_is_tty() => confirm(
+                &format!(
+                    "Fetch the remote side into {} now? Type y to confirm: ",
+                    tracking
+                ),
+                "y",
+            )?,
+            _ => false,
+        };
+
+        if auto_fetch {
+            self.remote_repo
+                .fetch_to_ref_from_str(
+                    &remote.to_string(),
+                    &tracking_ref,
+                    &mut self.repo,
+                    self.store.as_ref(),
+                    &index,
+                    None,
+                    None,
+                    None,
+                    &self.config,
+                )
+                .await?;
+            self.repo.reference(
+                &tracking_ref,
+                remote,
+                true,
+                "inv4: fetch diverged remote branch",
+            )?;
+
+            output::info(format!(
+                "Fetched {} into {}; run `git rebase {}` (or `git merge {}`), then push again",
+                remote, tracking, tracking, tracking
+            ));
+        } else {
+            output::info(format!(
+                "To catch up, run `git fetch {} && git rebase {}` (or `git merge {}`), then push again",
+                self.remote_name, tracking, tracking
+            ));
+        }
+
+        error!("non-fast-forward; fetch first")
+    }
+
+    /// Summarize what `request` publishes, and unless allowed, stop before files matching
+    /// `publish_guard_patterns` are uploaded for anyone to read.
+    fn check_publish(&self, request: &PushRequest, known_tips: &[Oid]) -> BoxResult<()> {
+        if request.src.is_empty() {
+            return Ok(());
+        }
+
+        let old = self
+            .remote_repo
+            .refs
+            .get(&request.dst)
+            .and_then(|git_hash| git_hash.parse().ok());
+        let preview = Preview::new(
+            &self.repo,
+            old,
+            self.pushed_oid(request)?,
+            known_tips,
+            |oid| self.remote_repo.objects.contains_key(&oid.to_string()),
+            &self.config.publish_guard_patterns,
+        )?;
+        preview.show(&request.dst);
+
+        if preview.sensitive.is_empty()
+            || env::var("GIT_INV4_ALLOW_SENSITIVE").as_deref() == Ok("1")
+        {
+            return Ok(());
+        }
+
+        if !output::stderr_is_tty() {
+            error!(format!(
+                "Refusing to publish {} file(s) matching publish_guard_patterns; set GIT_INV4_ALLOW_SENSITIVE=1 to allow it",
+                preview.sensitive.len()
+            ))
+        }
+
+        if !confirm("Type publish to publish these files anyway: ", "publish")? {
+            error!("Publishing sensitive files not confirmed")
+        }
+
+        Ok(())
+    }
+
+    /// Make sure a force push that drops commits from the remote ref is intended.
+    async fn check_force_push(&self, request: &PushRequest) -> BoxResult<()> {
+        let old: Oid = match self.remote_repo.refs.get(&request.dst) {
+            Some(git_hash) if request.force && !request.src.is_empty() => git_hash.parse()?,
+            _ => return Ok(()),
+        };
+
+        let new = self.pushed_oid(request)?;
+
+        // Anything not present locally can't be in the new tip's history
+        if self.repo.odb()?.read_header(old).is_ok() && is_fast_forward(&self.repo, old, new)? {
+            return Ok(());
+        }
+
+        let index = MultiObjectIndex::Chain {
+            chain: &self.chain,
+            ips_id: self.url.ips_id,
+            at: None,
+        };
+        let unreachable = self
+            .remote_repo
+            .unreachable_commits(old, new, &self.repo, self.store.as_ref(), &index)
+            .await?;
+
+        output::info(format!(
+            "Force-pushing {} from {} to {} leaves {} commit(s) unreachable",
+            request.dst, old, new, unreachable
+        ));
+
+        if !self.config.force_push_confirmation
+            || env::var("GIT_INV4_ALLOW_FORCE").as_deref() == Ok("1")
+        {
+            return Ok(());
+        }
+
+        if !output::stderr_is_tty() {
+            error!(format!(
+                "Refusing to force-push {} without confirmation; set GIT_INV4_ALLOW_FORCE=1 to allow it",
+                request.dst
+            ))
+        }
+
+        let name = request
+            .dst
+            .strip_prefix("refs/heads/")
+            .or_else(|| request.dst.strip_prefix("refs/tags/"))
+            .unwrap_or(&request.dst);
+
+        if !confirm(&format!("Type {} to confirm the force push: ", name), name)? {
+            error!("Force push not confirmed")
+        }
+
+        Ok(())
+    }
+
+    /// Check `requests` against the repository's policies before anything is signed or uploaded,
+    /// reporting those breaking one. Returns the others, or none when `pre_push_hook` rejects
+    /// the push.
+    async fn check_policy<'a>(
+        &self,
+        requests: Vec<&'a PushRequest>,
+        reports: &mut Vec<PushOutcome>,
+    ) -> BoxResult<Vec<&'a PushRequest>> {
+        let policy = Policy::from_config(&self.config)?;
+        let known_tips = self.known_remote_tips()?;
+
+        let mut accepted = vec![];
+        let mut updates = vec![];
+
+        for request in requests {
+            let checked = check_ref_name(request, self.config.max_ref_name_bytes)
+                .and_then(|()| self.ref_update(request, &known_tips))
+                .and_then(|update| {
+                    policy.check(&self.repo, &update)?;
+                    Ok(update)
+                });
+
+            match checked {
+                Ok(update) => {
+                    accepted.push(request);
+                    updates.push(update);
+                }
+                Err(e) => reports.push(PushOutcome {
+                    dst: request.dst.clone(),
+                    result: Err(PushError::new(e)),
+                }),
+            }
+        }
+
+        if updates.is_empty() {
+            return Ok(accepted);
+        }
+
+        let url = self
+            .repo
+            .find_remote(&self.remote_name)
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_owned))
+            .unwrap_or_else(|| self.remote_name.clone());
+
+        if let Err(e) = policy
+            .run_hook(&self.repo, &self.remote_name, &url, &updates)
+            .await
+        {
+            for request in accepted.drain(..) {
+                reports.push(PushOutcome {
+                    dst: request.dst.clone(),
+                    result: Err(PushError::new(&e)),
+                });
+            }
+        }
+
+        Ok(accepted)
+    }
+
+    /// How `request` would change its ref, with the commits it adds to the remote.
+    fn ref_update(&self, request: &PushRequest, known_tips: &[Oid]) -> BoxResult<RefUpdate> {
+        let old = match self.remote_repo.refs.get(&request.dst) {
+            Some(git_hash) => Some(git_hash.parse()?),
+            None => None,
+        };
+
+        let (new, commits) = match request.src.is_empty() {
+            true => (None, vec![]),
+            false => {
+                let new = self.pushed_oid(request)?;
+                let commits = policy::new_commits(&self.repo, old, new, known_tips)?
+                    .into_iter()
+                    .filter(|oid| !self.remote_repo.objects.contains_key(&oid.to_string()))
+                    .collect();
+                (Some(new), commits)
+            }
+        };
+
+        Ok(RefUpdate {
+            src: request.src.clone(),
+            dst: request.dst.clone(),
+            old,
+            new,
+            commits,
+        })
+    }
+
+    /// The key to sign a push with, asking for the password only the first time in a session
+    /// unless `auth_cache = "none"`. The first push's confirmation covers later ones, as they
+    /// sign for the same repository and chain.
+    async fn signer(
+        &mut self,
+        context: &SigningContext,
+    ) -> BoxResult<PairSigner<PolkadotConfig, Pair>> {
+        let pair = match self.unlocked.take() {
+            Some(pair) => {
+                auth::check_may_sign()?;
+                pair
+            }
+            None => {
+                let unlocked = auth_flow(self.account.as_deref(), context).await?;
+                self.credential = unlocked.credential;
+                unlocked.pair
+            }
+        };
+
+        if self.config.auth_cache == AuthCache::Session {
+            self.unlocked = Some(pair.clone());
+        }
+
+        Ok(PairSigner::new(pair))
+    }
+
+    /// Push every request, then publish all successfully uploaded refs in one multisig call.
+    ///
+    /// A key the chain rejected is forgotten, so the next push asks for the password again. The
+    /// stored credential it came from may then be erased, and the push is retried with the seed
+    /// entered in its place. Once a push went through, the credential is renewed instead.
+    pub async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        let result = self.push_requests(requests).await;
+
+        // Only the chain refusing the key counts, never a transient error
+        let rejected = match &result {
+            Ok(reports) => reports
+                .iter()
+                .any(|report| matches!(&report.result, Err(e) if rejects_key(&e.to_string()))),
+            Err(e) => rejects_key(&e.to_string()),
+        };
+        if !rejected {
+            let pushed = matches!(&result, Ok(reports) if reports.iter().any(|report| report.result.is_ok()));
+            if let (true, Some(credential)) = (pushed, &self.credential) {
+                auth::renew(credential);
+            }
+            return result;
+        }
+
+        self.unlocked = None;
+        match self.credential.take() {
+            Some(credential)
+                if auth::forget(&credential, "The chain refused the stored credential's key")
+                    .await? =>
+            {
+                output::info("Pushing again with the new credential");
+                self.push_requests(requests).await
+            }
+            _ => result,
+        }
+    }
+
+    async fn push_requests(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        // A push that seemed to fail, e.g. by timing out before its block, may have landed since
+        // the session started. Retrying it must not mint and publish everything a second time.
+        self.snapshot.advance(&self.chain).await?;
+        self.remote_repo = RepoData::chain_get(
+            self.url.ips_id,
+            self.store.as_ref(),
+            &self.chain,
+            self.config.repodata_lookback_blocks,
+            Some(&self.snapshot),
+        )
+        .await?;
+
+        let (landed, requests): (Vec<_>, Vec<_>) = requests
+            .iter()
+            .partition(|request| self.already_pushed(request));
+        let mut reports: Vec<_> = landed
+            .into_iter()
+            .map(|request| {
+                let tip = self.remote_repo.refs.get(&request.dst).cloned();
+                PushOutcome {
+                    dst: request.dst.clone(),
+                    result: Ok(Updated {
+                        old: tip.clone(),
+                        new: tip,
+                    }),
+                }
+            })
+            .collect();
+
+        if requests.is_empty() {
+            output::info("Everything up-to-date");
+            return Ok(reports);
+        }
+
+        // Its RepoData would be swapped out from under it, so it has to be settled first
+        if let Some(exported) = PushJournal::open(&self.repo, &self.state_dir()).exported() {
+            error!(format!(
+                "The push exported as call {} to {} is still waiting to land; once it did, run `inv4-git push-state finish`, or `inv4-git push-state abandon` to push anew",
+                exported.call_hash,
+                exported.exported_to.display()
+            ))
+        }
+        let export = export_call::path();
+
+        let requests = self.check_policy(requests, &mut reports).await?;
+        if requests.is_empty() {
+            return Ok(reports);
+        }
+
+        let message = self.push_message()?;
+
+        let flags = self.check_ips_flags().await?;
+
+        let _signing = SigningScope::enter();
+        let repository = RepoMeta::load(&self.chain, self.store.as_ref(), self.url.ips_id)
+            .await
+            .unwrap_or_else(|e| {
+                debug!("Could not read RepoMeta: {}", e);
+                None
+            })
+            .and_then(|meta| meta.name);
+        let context = self.signing_context(repository, self.chain.name().await);
+        let signer = self.signer(&context).await?;
+
+        // Clean up after an earlier push that died before publishing
+        let journal = PushJournal::open(&self.repo, &self.state_dir());
+        if journal.take_unfinished() {
+            output::info("Cleaning up after an interrupted push...");
+        }
+        self.burn_pending(&signer, &journal).await;
+
+        // Marks the push unfinished if it ends, by an error or Ctrl-C, before cleaning up
+        let unfinished = shutdown::register({
+            let journal = journal.clone();
+            move || journal.mark_unfinished()
+        });
+
+        let estimate = self.check_funds(&signer, requests.len()).await?;
+        let fees_mark = fees::mark();
+
+        let known_tips = self.known_remote_tips()?;
+        let packer = packer::from_config(&self.config);
+        let previous_repo = self.remote_repo.clone();
+        let mut pack_ipf_ids = vec![];
+
+        for request in requests {
+            if !self.accepts_ref(&request.dst) {
+                reports.push(PushOutcome {
+                    dst: request.dst.clone(),
+                    result: Err(PushError::new(format!(
+                        "this remote only accepts pushes to {}",
+                        self.url.branch.as_deref().unwrap_or_default()
+                    ))),
+                });
+                continue;
+            }
+
+            let checked = match self.check_force_push(request).await {
+                Ok(()) => self.check_fast_forward(request).await,
+                Err(e) => Err(e),
+            }
+            .and_then(|()| self.check_publish(request, &known_tips));
+
+            if let Err(e) = checked {
+                reports.push(PushOutcome {
+                    dst: request.dst.clone(),
+                    result: Err(PushError::new(e)),
+                });
+                continue;
+            }
+
+            // Upload the object tree, forgetting its objects again if it fails part way
+            let before = self.remote_repo.clone();
+            let mut minted = vec![];
+            let result = self
+                .remote_repo
+                .push_ref_from_str(
+                    &request.src,
+                    &request.dst,
+                    request.force,
+                    &mut self.repo,
+                    self.store.as_ref(),
+                    &self.chain,
+                    &signer,
+                    self.url.ips_id,
+                    &known_tips,
+                    packer.as_ref(),
+                    self.config.large_blob_threshold,
+                    Limits::from_config(&self.config),
+                    &journal,
+                    &mut minted,
+                )
+                .await;
+
+            for &ipf_id in &minted {
+                if let Err(e) = journal.record(ipf_id) {
+                    output::warn(format!("Could not journal IPF {}: {}", ipf_id, e));
+                }
+            }
+
+            reports.push(PushOutcome {
+                dst: request.dst.clone(),
+                result: match result {
+                    Ok(()) => {
+                        pack_ipf_ids.extend(minted);
+                        Ok(Updated {
+                            old: before.refs.get(&request.dst).cloned(),
+                            new: self.remote_repo.refs.get(&request.dst).cloned(),
+                        })
+                    }
+                    Err(e) => {
+                        self.remote_repo = before;
+                        Err(PushError::new(e))
+                    }
+                },
+            });
+        }
+
+        self.remote_repo.follow_symrefs();
+
+        // Without ref changes there is no new RepoData to publish, and packs can only belong to
+        // failed refs
+        if self.remote_repo.refs == previous_repo.refs {
+            self.burn_pending(&signer, &journal).await;
+            unfinished.dismiss();
+
+            if reports.iter().all(|report| report.result.is_ok()) {
+                output::info("Everything up-to-date");
+            }
+
+            return Ok(reports);
+        }
+
+        let (block_number, block_hash) = self.chain.best_block().await?;
+
+        let refs_changed: Vec<_> = reports
+            .iter()
+            .filter(|report| report.result.is_ok())
+            .map(|report| RefChange {
+                name: report.dst.clone(),
+                old: previous_repo.refs.get(&report.dst).cloned(),
+                new: self.remote_repo.refs.get(&report.dst).cloned(),
+            })
+            .collect();
+        let metadata = self.push_metadata(&refs_changed, message.as_deref()).await;
+        let new_tips: Vec<_> = refs_changed
+            .iter()
+            .map(|change| (change.name.clone(), change.new.clone()))
+            .collect();
+
+        if self.config.publish_swarm_hints {
+            match self.store.swarm_hint().await {
+                Ok(Some(hint)) => self.remote_repo.record_swarm_hint(hint),
+                Ok(None) => debug!("The store has no public addresses to publish"),
+                Err(e) => debug!("Could not look up our swarm hint: {}", e),
+            }
+        }
+
+        self.remote_repo.record_push(PushRecord {
+            block_number,
+            block_hash: format!("{:?}", block_hash),
+            pusher: signer.account_id().to_string(),
+            refs_changed,
+            message,
+        });
+
+        if let Some(path) = export {
+            let result = self
+                .export_publish(&signer, pack_ipf_ids, metadata, &journal, new_tips, path)
+                .await;
+            self.remote_repo = previous_repo;
+
+            // The refs only move once the call lands, so git mustn't move them yet either
+            let e = match result {
+                Ok(exported) => {
+                    output::info(format!(
+                        "Exported the publish call {} to {}, described in {}; once the IPS's signatory submitted it, run `inv4-git push-state finish`",
+                        exported.call_hash,
+                        exported.exported_to.display(),
+                        export_call::json_path(&exported.exported_to).display()
+                    ));
+                    format!("exported for signing as call {}", exported.call_hash)
+                }
+                Err(e) => {
+                    self.burn_pending(&signer, &journal).await;
+                    e.to_string()
+                }
+            };
+            for report in reports.iter_mut().filter(|report| report.result.is_ok()) {
+                report.result = Err(PushError::new(&e));
+            }
+
+            unfinished.dismiss();
+            return Ok(reports);
+        }
+
+        if let Err(e) = self
+            .publish(&signer, pack_ipf_ids, metadata, &journal, new_tips)
+            .await
+        {
+            self.remote_repo = previous_repo;
+            self.burn_pending(&signer, &journal).await;
+
+            // A state we couldn't interpret up front may be what made the batch fail
+            let e = match flags.filter(|flags| !flags.unknown.is_empty()) {
+                Some(flags) => format!("{} (IPS {} flags: {})", e, self.url.ips_id, flags.raw),
+                None => e.to_string(),
+            };
+
+            for report in reports.iter_mut().filter(|report| report.result.is_ok()) {
+                report.result = Err(PushError::new(&e));
+            }
+        } else {
+            self.remember_tips(reports.iter().filter_map(|report| {
+                let updated = report.result.as_ref().ok()?;
+                Some((report.dst.as_str(), updated.new.as_deref()))
+            }));
+            self.remember_repo_data(block_number);
+
+            // Published IPFs are skipped, which leaves the packs of failed refs
+            self.burn_pending(&signer, &journal).await;
+
+            if let Err(e) = journal.clear_blobs() {
+                output::warn(format!("Could not update the push journal: {}", e));
+            }
+
+            if let Some(estimate) = &estimate {
+                self.reconcile_fees(estimate, fees_mark).await;
+            }
+        }
+
+        unfinished.dismiss();
+
+        Ok(reports)
+    }
+
+    /// What a push through this session signs for, given the repository's name from its
+    /// RepoMeta and the chain's name.
+    fn signing_context(&self, repository: Option<String>, chain: String) -> SigningContext {
+        SigningContext {
+            action: "push",
+            ips_id: self.url.ips_id,
+            repository,
+            chain,
+            confirm: self.config.confirm_signing,
+        }
+    }
+
+    /// Stop before anything is uploaded when the IPS's configuration rules out appending to it,
+    /// returning its flags for diagnostics.
+    async fn check_ips_flags(&self) -> BoxResult<Option<IpsFlags>> {
+        let ips_id = self.url.ips_id;
+        let flags = match self.chain.ips_flags(ips_id).await {
+            Ok(Some(flags)) => flags,
+            // A missing IPS fails on publishing like before
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                output::warn(format!("Could not read the flags of IPS {}: {}", ips_id, e));
+                return Ok(None);
+            }
+        };
+        debug!("IPS {} flags: {}", ips_id, flags.raw);
+
+        if let Some(blocker) = flags.push_blocker(ips_id) {
+            error!(blocker)
+        }
+
+        if let (Some(subasset_id), false) = (self.url.subasset_id, flags.default_permission) {
+            output::warn(format!(
+                "Sub-tokens of IPS {} have no permissions by default; the push fails unless sub-token {} was granted operate_multisig",
+                ips_id, subasset_id
+            ));
+        }
+
+        if !flags.unknown.is_empty() {
+            output::warn(format!(
+                "IPS {} is in a state this version doesn't know ({}); pushing anyway",
+                ips_id,
+                flags.unknown.join(", ")
+            ));
+        }
+
+        Ok(Some(flags))
+    }
+
+    /// Show who pays for a push of `refs` refs and what for, and stop before anything is
+    /// uploaded when the signer can't cover the estimated fees. Returns the estimate, unless the
+    /// fees couldn't be estimated.
+    ///
+    /// Every extrinsic, `operate_multisig` included, is paid for by the signer. The batch inside
+    /// it is dispatched from the IPS account, which pays no fees for it.
+    async fn check_funds(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        refs: usize,
+    ) -> BoxResult<Option<Breakdown>> {
+        let token = self.chain.token().await?;
+        let signer_account = signer.account_id();
+        let signer_balance = self.chain.free_balance(signer_account).await?;
+        let ips_account = chain::ips_account(self.url.ips_id);
+        let ips_balance = self.chain.free_balance(&ips_account).await?;
+
+        output::info(format!(
+            "IPS {} account {} holds {}; it executes the published batch",
+            self.url.ips_id,
+            ips_account,
+            token.format(ips_balance)
+        ));
+
+        let breakdown = match self.estimate_fees(signer, refs).await {
+            Ok(breakdown) => breakdown,
+            Err(e) => {
+                output::warn(format!("Could not estimate the push fees: {}", e));
+                output::info(format!(
+                    "Signer {} holds {} and pays all fees",
+                    signer_account,
+                    token.format(signer_balance)
+                ));
+                return Ok(None);
+            }
+        };
+        let needed = breakdown.total();
+        events::emit(Event::FeesEstimated {
+            breakdown: breakdown.clone(),
+        });
+
+        output::info(format!(
+            "Signer {} holds {} and pays all fees, at least {} for this push:",
+            signer_account,
+            token.format(signer_balance),
+            token.format(needed)
+        ));
+        for row in breakdown.table(&token) {
+            output::info(row);
+        }
+
+        if signer_balance < needed {
+            error!(format!(
+                "The signer can't cover the push fees of at least {}; send funds to {} and push again",
+                token.format(needed),
+                signer_account
+            ))
+        }
+
+        Ok(Some(breakdown))
+    }
+
+    /// Estimate each call of a push of `refs` refs, which mints at least one pack per ref and
+    /// the RepoData, then publishes them.
+    async fn estimate_fees(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        refs: usize,
+    ) -> BoxResult<Breakdown> {
+        let runtime = self.chain.runtime();
+        let publish = self.publish_spec(signer, vec![0; refs], Some(0), Some(0), vec![], vec![]);
+
+        let mut calls = vec![
+            (
+                "pack mints",
+                runtime.mint_ipf(vec![0; 64], H256::zero()),
+                refs,
+                false,
+            ),
+            (
+                "RepoData mint",
+                runtime.mint_ipf(b"RepoData".to_vec(), H256::zero()),
+                1,
+                false,
+            ),
+            ("publish", runtime.publish(&publish), 1, false),
+        ];
+        for part in publish.parts() {
+            let label = match &part {
+                PublishPart::Remove(_) => "remove old RepoData",
+                PublishPart::Append(_) => "append packs and RepoData",
+            };
+            calls.push((label, runtime.publish_part(&publish, &part), 1, true));
+        }
+
+        let mut items = vec![];
+        for (label, call, count, batched) in calls {
+            let (fee, weight) = self.chain.estimate(&call, signer).await?;
+            items.push(Item {
+                label,
+                call: call.name(),
+                count,
+                fee,
+                weight,
+                batched,
+            });
+        }
+
+        Ok(Breakdown { items })
+    }
+
+    /// Set what the push's calls since `mark` cost against `estimate`.
+    async fn reconcile_fees(&self, estimate: &Breakdown, mark: usize) {
+        let calls = fees::reconcile(estimate, mark);
+        if calls.is_empty() {
+            return;
+        }
+        events::emit(Event::FeesReconciled {
+            calls: calls.clone(),
+        });
+
+        match self.chain.token().await {
+            Ok(token) => {
+                output::info("Fees paid for this push against the estimate:");
+                for row in fees::reconciliation_table(&calls, &token) {
+                    output::info(row);
+                }
+            }
+            Err(e) => debug!("Could not format the fees paid: {}", e),
+        }
+    }
+
+    /// Burn the journaled IPFs that never made it into the repository. Best-effort: failures are
+    /// reported and the ids stay journaled for the next push.
+    async fn burn_pending(&self, signer: &PairSigner<PolkadotConfig, Pair>, journal: &PushJournal) {
+        let pending = match journal.pending() {
+            Ok(pending) => pending,
+            Err(e) => return output::warn(format!("Could not read the push journal: {}", e)),
+        };
+
+        if pending.is_empty() {
+            return;
+        }
+
+        // Anything that was appended belongs to the repository now
+        let published = match self.chain.ipf_ids(self.url.ips_id).await {
+            Ok(ids) => ids.unwrap_or_default(),
+            Err(e) => {
+                return output::warn(format!(
+                    "Could not check which journaled IPFs were published: {}",
+                    e
+                ))
+            }
+        };
+
+        let mut remaining = vec![];
+
+        for id in pending.into_iter().filter(|id| !published.contains(id)) {
+            output::status(format!("Burning unpublished IPF {}...", id));
+
+            if let Err(e) = self
+                .chain
+                .submit(&self.chain.runtime().burn_ipf(id), signer)
+                .await
+            {
+                output::warn(format!("Could not burn IPF {}: {}", id, e));
+                remaining.push(id);
+            }
+        }
+
+        if let Err(e) = journal.reset(&remaining) {
+            output::warn(format!("Could not update the push journal: {}", e));
+        }
+    }
+
+    /// Swap in a freshly minted RepoData and append the new object packs to the IPS, which moves
+    /// refs to `new_tips`. A publish too heavy for one call is split, see `batching`.
+    async fn publish(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        pack_ipf_ids: Vec<IpfId>,
+        metadata: Vec<u8>,
+        journal: &PushJournal,
+        new_tips: Vec<(String, Option<String>)>,
+    ) -> BoxResult<()> {
+        let ips_id = self.url.ips_id;
+
+        let (new_repo_data, old_repo_data) = self
+            .remote_repo
+            .mint_return_new_old_id(
+                &self.config,
+                self.store.as_ref(),
+                &self.chain,
+                signer,
+                ips_id,
+            )
+            .await?;
+        journal.record(new_repo_data)?;
+
+        let stranded = self.stranded_appends(journal).await;
+        let plan = self
+            .plan_publish(
+                signer,
+                &pack_ipf_ids,
+                new_repo_data,
+                old_repo_data,
+                &stranded,
+            )
+            .await;
+
+        for (i, ipf_ids) in plan.appends.iter().enumerate() {
+            output::status(format!(
+                "Appending packs to repository {} ({}/{})...",
+                ips_id,
+                i + 1,
+                plan.calls()
+            ));
+
+            journal.record_appended(new_repo_data, ipf_ids)?;
+            self.chain
+                .submit(
+                    &self.publish_call(
+                        signer,
+                        ipf_ids.clone(),
+                        None,
+                        None,
+                        vec![],
+                        metadata.clone(),
+                    ),
+                    signer,
+                )
+                .await?;
+        }
+
+        output::status(format!("Publishing to repository {}...", ips_id));
+
+        let included = self
+            .chain
+            .submit(
+                &self.publish_call(
+                    signer,
+                    plan.swap_packs,
+                    Some(new_repo_data),
+                    old_repo_data,
+                    stranded.clone(),
+                    metadata,
+                ),
+                signer,
+            )
+            .await?;
+
+        // Removed from the IPS again, the stranded packs are the signer's to burn
+        for &ipf_id in &stranded {
+            if let Err(e) = journal.record(ipf_id) {
+                output::warn(format!("Could not journal IPF {}: {}", ipf_id, e));
+            }
+        }
+        if let Err(e) = journal.clear_appended() {
+            output::warn(format!("Could not update the push journal: {}", e));
+        }
+
+        let included = match self.config.confirmation {
+            Confirmation::Finalized => self.chain.finalize(included).await?,
+            Confirmation::InBlock => included,
+        };
+
+        match included.finalized_block_hash {
+            Some(finalized) => output::info(format!(
+                "Published in block {:?}, finalized as of block {:?}",
+                included.block_hash, finalized
+            )),
+            None => {
+                output::info(format!(
+                    "Published in block {:?}, not finalized yet",
+                    included.block_hash
+                ));
+                self.record_unconfirmed(journal, &included, new_tips);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mint the RepoData, then export the call publishing it with `pack_ipf_ids` to `path`
+    /// instead of submitting it, see `export_call`. The call is never split, and packs stranded
+    /// by an earlier split publish are left to the next push that publishes itself. Its IPFs
+    /// move from the pending ones to the exported call.
+    async fn export_publish(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        pack_ipf_ids: Vec<IpfId>,
+        metadata: Vec<u8>,
+        journal: &PushJournal,
+        new_tips: Vec<(String, Option<String>)>,
+        path: PathBuf,
+    ) -> BoxResult<ExportedCall> {
+        let (new_repo_data, old_repo_data) = self
+            .remote_repo
+            .mint_return_new_old_id(
+                &self.config,
+                self.store.as_ref(),
+                &self.chain,
+                signer,
+                self.url.ips_id,
+            )
+            .await?;
+        journal.record(new_repo_data)?;
+
+        let call = self.publish_call(
+            signer,
+            pack_ipf_ids.clone(),
+            Some(new_repo_data),
+            old_repo_data,
+            vec![],
+            metadata.clone(),
+        );
+        let exported = ExportedCall::new(
+            &self.chain.encode_call(&call).await?,
+            format!("{:?}", self.chain.genesis_hash().await),
+            self.url.ips_id,
+            self.url.subasset_id,
+            signer.account_id().to_string(),
+            pack_ipf_ids,
+            new_repo_data,
+            old_repo_data,
+            new_tips.into_iter().collect(),
+            &metadata,
+            path,
+        );
+        exported.write()?;
+        journal.record_exported(&exported)?;
+
+        let ipf_ids = exported.ipf_ids();
+        let pending: Vec<_> = journal
+            .pending()?
+            .into_iter()
+            .filter(|id| !ipf_ids.contains(id))
+            .collect();
+        journal.reset(&pending)?;
+
+        Ok(exported)
+    }
+
+    /// Settle the call a push exported: once it landed, move the remote-tracking refs as the
+    /// push would have, and forget it. Returns whether it landed.
+    pub async fn finish_exported(&self) -> BoxResult<bool> {
+        let journal = PushJournal::open(&self.repo, &self.state_dir());
+        let exported = match journal.exported() {
+            Some(exported) => exported,
+            None => error!("No exported push is waiting to land"),
+        };
+
+        let in_ips = self
+            .chain
+            .ipf_ids(self.url.ips_id)
+            .await?
+            .unwrap_or_default();
+        // A later push by someone else may have swapped its RepoData out already
+        let landed = in_ips.contains(&exported.new_repo_data)
+            || exported
+                .refs
+                .iter()
+                .all(|(name, tip)| self.remote_repo.refs.get(name) == tip.as_ref());
+        if !landed {
+            return Ok(false);
+        }
+
+        for (name, tip) in &exported.refs {
+            let tracking_ref = match self.tracking_ref(name) {
+                Some(tracking_ref) => tracking_ref,
+                None => continue,
+            };
+            match tip {
+                Some(tip) => {
+                    self.repo.reference(
+                        &tracking_ref,
+                        tip.parse()?,
+                        true,
+                        "inv4-git push-state finish",
+                    )?;
+                }
+                None => {
+                    if let Ok(mut reference) = self.repo.find_reference(&tracking_ref) {
+                        reference.delete()?;
+                    }
+                }
+            }
+        }
+
+        self.remember_tips(
+            exported
+                .refs
+                .iter()
+                .map(|(name, tip)| (name.as_str(), tip.as_deref())),
+        );
+        journal.clear_exported()?;
+
+        Ok(true)
+    }
+
+    /// Split the publish of `pack_ipf_ids` when the call would be too heavy for a block. When
+    /// the weights can't be estimated, it's left whole, as before splitting existed.
+    async fn plan_publish(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        pack_ipf_ids: &[IpfId],
+        new_repo_data: IpfId,
+        old_repo_data: Option<IpfId>,
+        stranded: &[IpfId],
+    ) -> Plan {
+        let max_weight = match self.chain.max_extrinsic_weight().await {
+            Some(max_weight) => max_weight,
+            None => {
+                debug!("The runtime's weight limit is unknown; publishing in one call");
+                return Plan::single(pack_ipf_ids);
+            }
+        };
+
+        let weigh = |packs: Vec<IpfId>| {
+            let call = self.publish_call(
+                signer,
+                packs,
+                Some(new_repo_data),
+                old_repo_data,
+                stranded.to_vec(),
+                vec![],
+            );
+            async move { self.chain.estimate_weight(&call, signer).await }
+        };
+        let model = match (weigh(vec![]).await, weigh(pack_ipf_ids.to_vec()).await) {
+            (Ok(empty), Ok(full)) => WeightModel::from_samples(empty, pack_ipf_ids.len(), full),
+            (Err(e), _) | (_, Err(e)) => {
+                debug!("Could not estimate the publish call's weight: {}", e);
+                return Plan::single(pack_ipf_ids);
+            }
+        };
+
+        match batching::plan(pack_ipf_ids, &model, max_weight) {
+            Ok(plan) => {
+                if plan.calls() > 1 {
+                    output::info(format!(
+                        "Publishing {} packs in {} calls, as one would weigh {} of the {} a call may",
+                        pack_ipf_ids.len(),
+                        plan.calls(),
+                        model.weight(pack_ipf_ids.len()),
+                        max_weight
+                    ));
+                }
+                plan
+            }
+            Err(e) => {
+                output::warn(format!("{}; trying a single call", e));
+                Plan::single(pack_ipf_ids)
+            }
+        }
+    }
+
+    /// Packs appended by an earlier split publish whose RepoData never followed, still in the
+    /// IPS. A RepoData that never landed was burned by `burn_pending`, while one that landed
+    /// exists even once a later push swapped it out.
+    async fn stranded_appends(&self, journal: &PushJournal) -> Vec<IpfId> {
+        let appended = journal.appended();
+        if appended.is_empty() {
+            return vec![];
+        }
+
+        let in_ips = match self.chain.ipf_ids(self.url.ips_id).await {
+            Ok(ids) => ids.unwrap_or_default(),
+            Err(e) => {
+                output::warn(format!(
+                    "Could not check on packs an interrupted push appended: {}",
+                    e
+                ));
+                return vec![];
+            }
+        };
+
+        let mut stranded = vec![];
+        for (repo_data, ipf_ids) in appended {
+            let landed = in_ips.contains(&repo_data)
+                || !matches!(
+                    self.chain.runtime().ipf(&self.chain, repo_data, None).await,
+                    Ok(None)
+                );
+            if !landed {
+                stranded.extend(ipf_ids.into_iter().filter(|id| in_ips.contains(id)));
+            }
+        }
+
+        if !stranded.is_empty() {
+            output::info(format!(
+                "Removing {} packs an interrupted push appended without its RepoData",
+                stranded.len()
+            ));
+        }
+
+        stranded
+    }
+
+    /// Remember a push whose block may still be dropped, for `check_unconfirmed`.
+    fn record_unconfirmed(
+        &self,
+        journal: &PushJournal,
+        included: &Included,
+        refs: Vec<(String, Option<String>)>,
+    ) {
+        let push = UnconfirmedPush {
+            block_hash: included.block_hash,
+            extrinsic_hash: included.extrinsic_hash,
+            refs,
+        };
+
+        if let Err(e) = journal.record_unconfirmed(&push) {
+            output::warn(format!("Could not journal the unconfirmed push: {}", e));
+        }
+    }
+
+    /// Check on pushes reported done before their block was finalized. Those whose block a reorg
+    /// dropped are reported with the refs to push again, unless the remote has the refs where
+    /// they pushed them anyway. Pushes that can't be checked yet are kept for next time.
+    async fn check_unconfirmed(&self) {
+        let journal = PushJournal::open(&self.repo, &self.state_dir());
+        let pushes = journal.unconfirmed();
+        if pushes.is_empty() {
+            return;
+        }
+
+        let finalized = match self.chain.finalized_block().await {
+            Ok((number, _)) => number,
+            Err(e) => return debug!("Could not check on unconfirmed pushes: {}", e),
+        };
+
+        let mut remaining = vec![];
+
+        for push in pushes {
+            let present = self
+                .chain
+                .block_contains_extrinsic(push.block_hash, push.extrinsic_hash)
+                .await;
+
+            match present {
+                Ok(true) => match self.chain.block_number(push.block_hash).await {
+                    Ok(number) if number <= finalized => {
+                        debug!("Push in block {:?} is finalized", push.block_hash)
+                    }
+                    _ => remaining.push(push),
+                },
+                Ok(false) => {
+                    let landed = push
+                        .refs
+                        .iter()
+                        .all(|(name, tip)| self.remote_repo.refs.get(name) == tip.as_ref());
+                    if landed {
+                        continue;
+                    }
+
+                    let names: Vec<_> = push.refs.iter().map(|(name, _)| name.as_str()).collect();
+                    output::warn(format!(
+                        "The push of {} in block {:?} was dropped by a reorg and isn't on IPS {}; push again with `git push {} {}`",
+                        names.join(", "),
+                        push.block_hash,
+                        self.url.ips_id,
+                        self.remote_name,
+                        names.join(" ")
+                    ));
+                }
+                Err(e) => {
+                    debug!(
+                        "Could not check on the push in block {:?}: {}",
+                        push.block_hash, e
+                    );
+                    remaining.push(push);
+                }
+            }
+        }
+
+        if let Err(e) = journal.reset_unconfirmed(&remaining) {
+            output::warn(format!("Could not update the push journal: {}", e));
+        }
+    }
+
+    /// The multisig batch that swaps in the new RepoData, removing `removed_ipf_ids` with the
+    /// old one, and appends the new packs. Without `new_repo_data` it only appends the packs.
+    fn publish_call(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        pack_ipf_ids: Vec<IpfId>,
+        new_repo_data: Option<IpfId>,
+        old_repo_data: Option<IpfId>,
+        removed_ipf_ids: Vec<IpfId>,
+        metadata: Vec<u8>,
+    ) -> ChainCall {
+        if let Some(old_id) = old_repo_data {
+            debug!("Removing old RepoData with IPF ID {}", old_id);
+        }
+
+        self.chain.runtime().publish(&self.publish_spec(
+            signer,
+            pack_ipf_ids,
+            new_repo_data,
+            old_repo_data,
+            removed_ipf_ids,
+            metadata,
+        ))
+    }
+
+    /// What `publish_call` builds the call from, to look into before it's wrapped.
+    fn publish_spec(
+        &self,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+        pack_ipf_ids: Vec<IpfId>,
+        new_repo_data: Option<IpfId>,
+        old_repo_data: Option<IpfId>,
+        removed_ipf_ids: Vec<IpfId>,
+        metadata: Vec<u8>,
+    ) -> Publish {
+        Publish {
+            ips_id: self.url.ips_id,
+            subasset_id: self.url.subasset_id,
+            caller: signer.account_id().clone(),
+            pack_ipf_ids,
+            new_repo_data,
+            old_repo_data,
+            removed_ipf_ids,
+            metadata,
+        }
+    }
+
+    /// The multisig metadata for a push of `refs_changed`, classifying each change with the
+    /// same ancestry check as the fast-forward guard.
+    async fn push_metadata(&self, refs_changed: &[RefChange], message: Option<&str>) -> Vec<u8> {
+        let changes: Vec<_> = refs_changed
+            .iter()
+            .filter(|change| change.old != change.new)
+            .map(|change| {
+                let fast_forward = match (&change.old, &change.new) {
+                    (Some(old), Some(new)) => match (old.parse(), new.parse()) {
+                        (Ok(old), Ok(new)) => {
+                            is_fast_forward(&self.repo, old, new).unwrap_or(false)
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                };
+
+                (change.clone(), ChangeKind::of(change, fast_forward))
+            })
+            .collect();
+
+        push_metadata::encode(&changes, message, self.chain.max_metadata().await)
+    }
+
+    /// The message describing this push: `GIT_INV4_PUSH_MESSAGE`, or asked for on a terminal
+    /// with `prompt_push_message`.
+    fn push_message(&self) -> BoxResult<Option<String>> {
+        let raw = match env::var("GIT_INV4_PUSH_MESSAGE") {
+            Ok(message) => message,
+            Err(_) if self.config.prompt_push_message && output::stderr_is_tty() => {
+                output::clear_status();
+                auth::ask(
+                    "Describe this push for multisig members and the history (Enter for none): ",
+                )?
+            }
+            Err(_) => return Ok(None),
+        };
+
+        push_metadata::push_message(&raw)
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Session {
+    async fn list(&mut self, for_push: bool) -> BoxResult<Vec<(String, String)>> {
+        Session::list(self, for_push).await
+    }
+
+    async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+        Session::fetch_batch(self, requests).await
+    }
+
+    async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        let reports = Session::push_batch(self, requests).await?;
+        metrics::repository(self.url.ips_id, &self.remote_repo);
+
+        Ok(reports)
+    }
+}
+
+/// The refs of `repo_data` to advertise to git, see `Session::list`, leaving out `withheld` and,
+/// with `branch`, every other branch.
+pub fn advertised_refs(
+    repo_data: &RepoData,
+    branch: Option<&str>,
+    withheld: &[String],
+    for_push: bool,
+) -> BoxResult<Vec<(String, String)>> {
+    // A name git can't read back, e.g. one with a newline pushed by another tool, would break
+    // the protocol for every ref after it
+    for name in repo_data.refs.keys() {
+        if !Reference::is_valid_name(name) {
+            output::warn(format!(
+                "Leaving out {:?}, which is not a valid git ref name",
+                name
+            ));
+        }
+    }
+
+    let refs: Vec<_> = repo_data
+        .refs
+        .iter()
+        .filter(|(name, _)| {
+            Reference::is_valid_name(name) && accepts_ref(branch, name) && !withheld.contains(name)
+        })
+        .flat_map(|(name, git_hash)| {
+            // Symbolic refs are advertised as such when git is also told about their target
+            let symref = repo_data.symrefs.get(name).filter(|target| {
+                repo_data.refs.contains_key(*target)
+                    && accepts_ref(branch, target)
+                    && !withheld.contains(*target)
+            });
+            if let Some(target) = symref {
+                return vec![(format!("@{}", target), name.clone())];
+            }
+
+            // Annotated tags are followed by their peeled target, like upload-pack does;
+            // receive-pack advertises none
+            let peeled = repo_data
+                .ref_targets
+                .get(name)
+                .and_then(|target| target.peeled.clone())
+                .filter(|_| !for_push)
+                .map(|peeled| (peeled, format!("{}^{{}}", name)));
+
+            std::iter::once((git_hash.clone(), name.clone()))
+                .chain(peeled)
+                .collect()
+        })
+        .collect();
+
+    if let Some(branch) = branch {
+        // Pushing may create the branch, so it only has to exist for fetches
+        if refs.is_empty() && !for_push {
+            error!(format!(
+                "Branch {} does not exist in this repository",
+                branch
+            ));
+        }
+    }
+
+    let head = repo_data
+        .default_branch()
+        .filter(|name| {
+            accepts_ref(branch, name) && !withheld.iter().any(|ref_name| ref_name == name)
+        })
+        .map(|name| (format!("@{}", name), "HEAD".to_owned()));
+
+    Ok(refs.into_iter().chain(head).collect())
+}
+
+/// Whether `name` may be advertised and pushed to through a URL filtered to `branch`.
+fn accepts_ref(branch: Option<&str>, name: &str) -> bool {
+    branch.map_or(true, |branch| branch == name)
+}
+
+/// Refuse to create or move a ref whose name git wouldn't accept, or one longer than
+/// `max_bytes`. Deleting one is fine, so a bad name can be cleaned up.
+fn check_ref_name(request: &PushRequest, max_bytes: usize) -> BoxResult<()> {
+    let name = &request.dst;
+    if request.src.is_empty() {
+        return Ok(());
+    }
+
+    if !name.starts_with("refs/") || !Reference::is_valid_name(name) {
+        error!(format!("{:?} is not a valid ref name", name))
+    }
+
+    if name.len() > max_bytes {
+        error!(format!(
+            "the name is {} bytes long, over max_ref_name_bytes ({})",
+            name.len(),
+            max_bytes
+        ))
+    }
+
+    Ok(())
+}
+
+/// Push `refs`, as `(name, tip, force)`, from `repo` to `url`.
+fn push_tee_refs(repo: &Repository, url: &str, refs: &[(String, Oid, bool)]) -> BoxResult<()> {
+    // libgit2 only pushes refs, so the tips are staged under refs of our own for the push
+    let mut staged = vec![];
+    let mut refspecs = vec![];
+
+    for (name, tip, force) in refs {
+        let staged_name = format!("refs/inv4-tee/{}", name.trim_start_matches("refs/"));
+        repo.reference(&staged_name, *tip, true, "inv4: stage fetched ref for tee")?;
+
+        refspecs.push(format!(
+            "{}{}:{}",
+            if *force { "+" } else { "" },
+            staged_name,
+            name
+        ));
+        staged.push(staged_name);
+    }
+
+    let mut rejected = vec![];
+    let pushed = {
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.push_update_reference(|name, status| {
+            if let Some(status) = status {
+                rejected.push(format!("{} ({})", name, status));
+            }
+            Ok(())
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        repo.remote_anonymous(url)
+            .and_then(|mut remote| remote.push(&refspecs, Some(&mut options)))
+    };
+
+    for name in staged {
+        if let Ok(mut reference) = repo.find_reference(&name) {
+            if let Err(e) = reference.delete() {
+                debug!("Could not delete {}: {}", name, e);
+            }
+        }
+    }
+
+    pushed?;
+    if !rejected.is_empty() {
+        error!(format!("{} were rejected", rejected.join(", ")))
+    }
+
+    Ok(())
+}
+
+/// Fail a fetch of a tip RepoData doesn't have, typically one a stale remote-tracking ref
+/// remembers from before the remote was force-pushed.
+fn check_known_tip(
+    repo_data: &RepoData,
+    remote_name: &str,
+    request: &FetchRequest,
+) -> Result<(), String> {
+    if repo_data.objects.contains_key(&request.sha) {
+        return Ok(());
+    }
+
+    match repo_data.refs.get(&request.name) {
+        Some(tip) => Err(format!(
+            "skipping {}: {} is no longer on the remote, which rewrote it to {}; fetch again, or run `git remote prune {}` if stale refs remain",
+            request.name, request.sha, tip, remote_name
+        )),
+        None => Err(format!(
+            "skipping {}: {} is not on the remote; it was deleted or force-pushed away",
+            request.name, request.sha
+        )),
+    }
+}
+
+/// Fetch the objects of each of `requests` from `remote_repo` into `repo`, reporting those whose
+/// tips it no longer has. git updates the refs itself once the batch is done, so none are written.
+async fn fetch_requests(
+    remote_repo: &RepoData,
+    remote_name: &str,
+    repo: &mut Repository,
+    store: &dyn ObjectStore,
+    index: &MultiObjectIndex<'_>,
+    requests: &[FetchRequest],
+    mut quarantine: Option<&mut Quarantine>,
+    mut refetch: Option<&mut Refetch>,
+    mut filter: Option<&mut BlobFilter>,
+    config: &Config,
+) -> BoxResult<Vec<FetchReport>> {
+    let mut reports = vec![];
+
+    for request in requests {
+        // One stale tip shouldn't keep the other refs from being fetched
+        if let Err(e) = check_known_tip(remote_repo, remote_name, request) {
+            output::warn(&e);
+            reports.push(FetchReport {
+                name: request.name.clone(),
+                result: Err(e),
+            });
+            continue;
+        }
+
+        remote_repo
+            .fetch_to_ref_from_str(
+                &request.sha,
+                &request.name,
+                repo,
+                store,
+                index,
+                quarantine.as_deref_mut(),
+                refetch.as_deref_mut(),
+                filter.as_deref_mut(),
+                config,
+            )
+            .await?;
+
+        reports.push(FetchReport {
+            name: request.name.clone(),
+            result: Ok(()),
+        });
+    }
+
+    Ok(reports)
+}
+
+/// Whether `repo_data` has `dst` at `oid` with `oid` indexed, as a push of it that landed left it.
+fn landed(repo_data: &RepoData, dst: &str, oid: Oid) -> bool {
+    let oid = oid.to_string();
+
+    repo_data.refs.get(dst) == Some(&oid) && repo_data.objects.contains_key(&oid)
+}
+
+/// Where state kept between invocations for `remote_name` lives: `.git/inv4/<remote name>`,
+/// with characters that don't belong in a file name replaced.
+pub fn state_dir(repo: &Repository, remote_name: &str) -> PathBuf {
+    let name: String = remote_name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "-_.".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect();
+
+    repo.path().join("inv4").join(name)
+}
+
+/// Read the next line git sent, recording it in the transcript.
+async fn next_line<R: AsyncBufRead + Unpin>(input: &mut Lines<R>) -> io::Result<Option<String>> {
+    let line = input.next_line().await?;
+
+    if let Some(line) = &line {
+        transcript::input(line);
+    }
+
+    Ok(line)
+}
+
+/// Read the rest of a `fetch`/`push` batch, which git terminates with a blank line.
+async fn read_batch<R: AsyncBufRead + Unpin>(
+    first_line: &str,
+    input: &mut Lines<R>,
+) -> BoxResult<Vec<String>> {
+    let mut lines = vec![first_line.trim().to_owned()];
+
+    while let Some(line) = next_line(input).await? {
+        if line.trim().is_empty() {
+            break;
+        }
+
+        debug!("{}", line);
+        lines.push(line.trim().to_owned());
+    }
+
+    Ok(lines)
+}
+
+/// The line answering git's `push` command for `outcome`: `ok <dst>`, or `error <dst> <why>` with
+/// the reason on one line and quoted, so nothing in it can break the framing git parses.
+fn push_answer(outcome: &PushOutcome) -> String {
+    match &outcome.result {
+        Ok(_) => format!("ok {}", outcome.dst),
+        Err(e) => format!(
+            "error {} {}",
+            outcome.dst,
+            quote_c_style(&guidance::tagged(&e.flattened()))
+        ),
+    }
+}
+
+/// Answer an `option <name> <value>` command.
+fn set_option(name: &str, value: &str, out: &mut dyn Write) -> io::Result<()> {
+    match (name, value.parse::<usize>()) {
+        ("verbosity", Ok(level)) => {
+            output::set_verbosity(level);
+            writeln!(out, "ok")
+        }
+        ("filter", _) => match BlobFilter::parse_limit(value) {
+            Ok(limit) => {
+                *OPTION_BLOB_LIMIT.lock().unwrap_or_else(|e| e.into_inner()) = Some(limit);
+                writeln!(out, "ok")
+            }
+            Err(e) => writeln!(out, "error {}", e),
+        },
+        ("push-option", _) => {
+            // git quotes values that need it, and fails the push on anything but `ok`
+            let value = unquote_c_style(value).unwrap_or_else(|| value.to_owned());
+            match value.split_once('=') {
+                Some(("export-call", path)) if !path.is_empty() => {
+                    *OPTION_EXPORT_CALL.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some(PathBuf::from(path));
+                }
+                _ => output::warn(format!("Ignoring unknown push option {:?}", value)),
+            }
+            writeln!(out, "ok")
+        }
+        _ => writeln!(out, "unsupported"),
+    }
+}
+
+/// Answer the commands that need no session, returning whether `input` was one of them.
+fn answer_offline(input: &str, out: &mut dyn Write) -> io::Result<bool> {
+    let mut args = input.split_ascii_whitespace();
+
+    match (args.next(), args.next(), args.next()) {
+        (Some("capabilities"), None, None) => write!(out, "{}", Capabilities::default())?,
+        (Some("option"), Some(name), Some(_)) => {
+            // The value runs to the end of the line, spaces included
+            let value = input.trim().splitn(3, ' ').nth(2).unwrap_or_default();
+            set_option(name, value, out)?
+        }
+        // A blank line asks git to fall back to the fetch and push commands
+        (Some("connect" | "stateless-connect"), Some(_), None) => writeln!(out)?,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// The limit git asked for with `option filter`, for a partial clone.
+pub fn blob_limit_option() -> Option<u64> {
+    *OPTION_BLOB_LIMIT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Where `git push -o export-call=<path>` asked the publish call to be exported to.
+pub fn export_call_option() -> Option<PathBuf> {
+    OPTION_EXPORT_CALL
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Whether `error` says the chain refused the signing key itself: a bad signature, or an account
+/// without permission on the IPS.
+fn rejects_key(error: &str) -> bool {
+    matches!(
+        guidance::classify(error).map(|entry| entry.code),
+        Some("INV4-E008" | "INV4-E010")
+    )
+}
+
+/// `config` as it applies to `remote_name` of `repo`, with the remote's settings in git config
+/// taking precedence, and the stored credential to sign with.
+pub fn remote_config(
+    repo: &Repository,
+    remote_name: &str,
+    mut config: Config,
+) -> BoxResult<(Config, Option<String>)> {
+    if let Some(endpoint) = remote_setting(repo, remote_name, "inv4-endpoint") {
+        config.chain_endpoint = endpoint;
+    }
+    let account = remote_setting(repo, remote_name, "inv4-account");
+    apply_policy_settings(repo, remote_name, &mut config)?;
+
+    Ok((config, account))
+}
+
+/// Let the policy settings of `remote.<remote_name>` in git config override `config`'s:
+/// `inv4-require-signed-commits`, `inv4-protected-refs` (comma-separated),
+/// `inv4-commit-message-pattern` and `inv4-pre-push-hook`.
+fn apply_policy_settings(
+    repo: &Repository,
+    remote_name: &str,
+    config: &mut Config,
+) -> BoxResult<()> {
+    if let Some(value) = remote_setting(repo, remote_name, "inv4-require-signed-commits") {
+        config.require_signed_commits = git2::Config::parse_bool(value)?;
+    }
+    if let Some(refs) = remote_setting(repo, remote_name, "inv4-protected-refs") {
+        config.protected_refs = refs
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+    if let Some(pattern) = remote_setting(repo, remote_name, "inv4-commit-message-pattern") {
+        config.commit_message_pattern = Some(pattern);
+    }
+    if let Some(hook) = remote_setting(repo, remote_name, "inv4-pre-push-hook") {
+        config.pre_push_hook = Some(hook.into());
+    }
+
+    Ok(())
+}
+
+/// `remote.<remote_name>.<key>` from git config, which takes precedence over config.toml for
+/// that remote.
+fn remote_setting(repo: &Repository, remote_name: &str, key: &str) -> Option<String> {
+    repo.config()
+        .ok()?
+        .get_string(&format!("remote.{}.{}", remote_name, key))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Serve git's remote-helper protocol on stdin/stdout until git hangs up.
+pub async fn run(remote_name: &str, raw_url: &str) -> BoxResult<()> {
+    let url: RemoteUrl = raw_url.parse()?;
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = Answers::stdout()?;
+    transcript::start(remote_name, raw_url)?;
+    metrics::start();
+
+    // Connecting and resolving RepoData takes seconds, so start right away and answer
+    // `capabilities` while it is in flight. A failure to connect is only reported once a command
+    // needs the session, so git sees it as the answer to e.g. `list` rather than a helper that
+    // died during the handshake.
+    let config = Config::load()?;
+
+    if config.offline == OfflineMode::Force {
+        let mut offline = Offline::open(
+            remote_name,
+            &url,
+            config,
+            "offline = \"force\" is set".to_owned(),
+        )?;
+        transcript::note("serving offline");
+
+        if let Some(first) = first_command(&mut stdin, &mut stdout).await? {
+            serve(&mut offline, first, &mut stdin, &mut stdout).await?;
+        }
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    if let Some(mut forwarded) = daemon::Forwarded::connect(remote_name, raw_url, &config).await {
+        transcript::note("forwarding to the daemon");
+
+        if let Some(first) = first_command(&mut stdin, &mut stdout).await? {
+            serve(&mut forwarded, first, &mut stdin, &mut stdout).await?;
+        }
+        forwarded.finish();
+
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let offline_url = url.clone();
+    let connecting = async {
+        let store = default_store(&config).await?;
+        Session::new(remote_name.to_owned(), url, config.clone(), store).await
+    };
+
+    let (input, connected) = match handshake(connecting, &mut stdin, &mut stdout).await? {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let mut session = match connected {
+        Ok(session) => session,
+        Err(e) if config.offline == OfflineMode::Auto => {
+            let reason = format!("the chain can't be reached: {}", e);
+            let mut offline = match Offline::open(remote_name, &offline_url, config.clone(), reason)
+            {
+                Ok(offline) => offline,
+                Err(no_copy) => {
+                    debug!("{}", no_copy);
+                    return Err(e);
+                }
+            };
+            transcript::note("serving offline");
+
+            serve(&mut offline, input, &mut stdin, &mut stdout).await?;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    transcript::note(format!("connected in {:?}", started.elapsed()));
+
+    serve(&mut session, input, &mut stdin, &mut stdout).await?;
+
+    session.push_tee();
+
+    Ok(())
+}
+
+/// Answer what needs no backend until the first command that does, `None` if git hung up first.
+async fn first_command<R: AsyncBufRead + Unpin>(
+    input: &mut Lines<R>,
+    out: &mut dyn Write,
+) -> BoxResult<Option<String>> {
+    loop {
+        match next_line(input).await? {
+            Some(line) if answer_offline(&line, out)? => out.flush()?,
+            Some(line) => return Ok(Some(line)),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Answer what needs no backend while `connecting` is in flight, until the first command that
+/// does. That command is returned with what `connecting` came to, so a failure to connect is only
+/// reported then; `None` if git hung up first.
+async fn handshake<R: AsyncBufRead + Unpin, T>(
+    connecting: impl Future<Output = T>,
+    input: &mut Lines<R>,
+    out: &mut dyn Write,
+) -> BoxResult<Option<(String, T)>> {
+    tokio::pin!(connecting);
+
+    let mut connected = None;
+    let first = loop {
+        tokio::select! {
+            outcome = &mut connecting, if connected.is_none() => connected = Some(outcome),
+            line = next_line(input) => match line? {
+                Some(line) => {
+                    if !answer_offline(&line, out)? {
+                        break line;
+                    }
+                    out.flush()?;
+                }
+                None => return Ok(None),
+            },
+        }
+    };
+
+    let connected = match connected {
+        Some(outcome) => outcome,
+        None => connecting.await,
+    };
+    Ok(Some((first, connected)))
+}
+
+/// Answer git's commands, `first` and then those read from `input`, until git hangs up.
+pub async fn serve<R: AsyncBufRead + Unpin>(
+    backend: &mut dyn Backend,
+    first: String,
+    input: &mut Lines<R>,
+    out: &mut dyn Write,
+) -> BoxResult<()> {
+    let mut line = first;
+
+    loop {
+        debug!("{}", &line);
+
+        let mut args = line.split_ascii_whitespace();
+
+        match (args.next(), args.next(), args.next()) {
+            _ if answer_offline(&line, out)? => {}
+            (Some("list"), for_push, None) => {
+                let started = Instant::now();
+                let refs = backend.list(for_push == Some("for-push")).await;
+                transcript::note(format!("list took {:?}", started.elapsed()));
+                metrics::operation("list", started.elapsed(), refs.is_ok());
+
+                for (value, name) in refs? {
+                    writeln!(out, "{} {}", value, name)?;
+                }
+                writeln!(out)?;
+            }
+            (Some("fetch"), Some(_), Some(_)) => {
+                let requests = read_batch(&line, input)
+                    .await?
+                    .iter()
+                    .map(
+                        |line| match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+                            ["fetch", sha, name] => Ok(FetchRequest {
+                                sha: sha.to_owned(),
+                                name: name.to_owned(),
+                            }),
+                            _ => Err(format!("Malformed fetch command: {:?}", line)),
+                        },
+                    )
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let started = Instant::now();
+                let reports = backend.fetch_batch(&requests).await;
+                transcript::note(format!(
+                    "fetch of {} refs took {:?}",
+                    requests.len(),
+                    started.elapsed()
+                ));
+
+                let failed = reports.as_ref().map_or(0, |reports| {
+                    reports
+                        .iter()
+                        .filter(|report| report.result.is_err())
+                        .count()
+                });
+                if let Ok(reports) = &reports {
+                    metrics::refs("fetch", reports.len() - failed, failed);
+                }
+                metrics::operation("fetch", started.elapsed(), reports.is_ok());
+                let reports = reports?;
+
+                if failed > 0 {
+                    output::warn(format!(
+                        "{} of {} refs could not be fetched",
+                        failed,
+                        reports.len()
+                    ));
+                }
+                output::clear_status();
+                writeln!(out)?;
+            }
+            (Some("push"), Some(_), None) => {
+                let requests = read_batch(&line, input)
+                    .await?
+                    .iter()
+                    .map(|line| match line.strip_prefix("push ") {
+                        Some(refspec) => refspec.parse(),
+                        None => Err(format!("Malformed push command: {:?}", line).into()),
+                    })
+                    .collect::<BoxResult<Vec<PushRequest>>>()?;
+
+                let started = Instant::now();
+                let reports = backend.push_batch(&requests).await;
+                transcript::note(format!(
+                    "push of {} refs took {:?}",
+                    requests.len(),
+                    started.elapsed()
+                ));
+
+                if let Ok(reports) = &reports {
+                    let failed = reports
+                        .iter()
+                        .filter(|report| report.result.is_err())
+                        .count();
+                    metrics::refs("push", reports.len() - failed, failed);
+                }
+                metrics::operation("push", started.elapsed(), reports.is_ok());
+                let reports = reports?;
+                output::clear_status();
+
+                for report in reports {
+                    writeln!(out, "{}", push_answer(&report))?;
+
+                    events::emit(Event::RefPushed {
+                        name: report.dst,
+                        result: report.result.map(|_| ()).map_err(|e| e.to_string()),
+                    });
+                }
+                writeln!(out)?;
+            }
+            (None, None, None) => {}
+            // Still terminate the response, so git isn't left waiting on us
+            _ => {
+                output::warn(format!("unsupported command {:?}", line));
+                writeln!(out)?;
+            }
+        }
+        // The response is complete, and git waits for it
+        out.flush()?;
+
+        line = match next_line(input).await? {
+            Some(line) => line,
+            None => break,
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::tests::published,
+        store::tests::MemoryStore,
+        util::tests::{commit, repository},
+    };
+    use git2::build::RepoBuilder;
+    use temp_dir::TempDir;
+
+    /// Answers from canned refs, keeping the batches it is handed.
+    #[derive(Default)]
+    struct Fake {
+        refs: Vec<(String, String)>,
+        fetched: Vec<Vec<FetchRequest>>,
+        pushed: Vec<Vec<PushRequest>>,
+        /// Why pushing a ref fails, by its name
+        push_errors: Vec<(String, String)>,
+    }
+
+    #[async_trait(?Send)]
+    impl Backend for Fake {
+        async fn list(&mut self, _for_push: bool) -> BoxResult<Vec<(String, String)>> {
+            Ok(self.refs.clone())
+        }
+
+        async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+            self.fetched.push(requests.to_vec());
+
+            Ok(requests
+                .iter()
+                .map(|request| FetchReport {
+                    name: request.name.clone(),
+                    result: Ok(()),
+                })
+                .collect())
+        }
+
+        async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+            self.pushed.push(requests.to_vec());
+
+            Ok(requests
+                .iter()
+                .map(|request| {
+                    let error = self.push_errors.iter().find(|(dst, _)| *dst == request.dst);
+                    PushOutcome {
+                        dst: request.dst.clone(),
+                        result: match error {
+                            Some((_, e)) => Err(PushError::new(e)),
+                            None => Ok(Updated::default()),
+                        },
+                    }
+                })
+                .collect())
+        }
+    }
+
+    /// Serves a repository `published` without the chain, fetching the way a `Session` does.
+    struct Published {
+        remote: (RepoData, MemoryStore, MultiObjectIndex<'static>),
+        repo: Repository,
+    }
+
+    #[async_trait(?Send)]
+    impl Backend for Published {
+        async fn list(&mut self, for_push: bool) -> BoxResult<Vec<(String, String)>> {
+            advertised_refs(&self.remote.0, None, &[], for_push)
+        }
+
+        async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+            let (repo_data, store, index) = &self.remote;
+
+            fetch_requests(
+                repo_data,
+                "origin",
+                &mut self.repo,
+                store,
+                index,
+                requests,
+                None,
+                None,
+                None,
+                &Config::default(),
+            )
+            .await
+        }
+
+        async fn push_batch(&mut self, _requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+            error!("published repositories are read-only")
+        }
+    }
+
+    /// What `serve` answers when git sends `input`.
+    async fn answers(backend: &mut dyn Backend, input: &str) -> String {
+        let mut input = BufReader::new(input.as_bytes()).lines();
+        let first = input.next_line().await.unwrap().unwrap();
+        let mut out = vec![];
+
+        serve(backend, first, &mut input, &mut out).await.unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn push_refspecs_are_parsed() {
+        let request: PushRequest = "+refs/heads/main:refs/heads/trunk".parse().unwrap();
+        assert_eq!(
+            request,
+            PushRequest {
+                src: "refs/heads/main".to_owned(),
+                dst: "refs/heads/trunk".to_owned(),
+                force: true,
+            }
+        );
+
+        let delete: PushRequest = ":refs/heads/gone".parse().unwrap();
+        assert_eq!((delete.src.as_str(), delete.force), ("", false));
+
+        assert!("refs/heads/main".parse::<PushRequest>().is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_are_handed_over_in_batches() {
+        let mut backend = Fake {
+            refs: vec![("a".repeat(40), "refs/heads/main".to_owned())],
+            ..Fake::default()
+        };
+
+        let out = answers(
+            &mut backend,
+            "list\n\
+             fetch 1111111111111111111111111111111111111111 refs/heads/main\n\
+             fetch 2222222222222222222222222222222222222222 refs/tags/v1\n\
+             \n\
+             push refs/heads/main:refs/heads/main\n\
+             push +refs/heads/topic:refs/heads/topic\n\
+             \n",
+        )
+        .await;
+
+        assert_eq!(
+            out,
+            format!(
+                "{} refs/heads/main\n\n\nok refs/heads/main\nok refs/heads/topic\n\n",
+                "a".repeat(40)
+            )
+        );
+        assert_eq!(backend.fetched.len(), 1);
+        assert_eq!(backend.fetched[0].len(), 2);
+        assert_eq!(backend.fetched[0][1].name, "refs/tags/v1");
+        assert_eq!(backend.pushed.len(), 1);
+        assert_eq!(backend.pushed[0].len(), 2);
+        assert!(backend.pushed[0][1].force);
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_answered_before_a_backend_is_needed() {
+        let mut input =
+            BufReader::new(&b"capabilities\noption progress true\nlist\nfetch"[..]).lines();
+        let mut out = vec![];
+
+        let first = first_command(&mut input, &mut out).await.unwrap();
+
+        assert_eq!(first.as_deref(), Some("list"));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}unsupported\n", Capabilities::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn hanging_up_before_a_command_needs_no_backend() {
+        let mut input = BufReader::new(&b"capabilities\n"[..]).lines();
+
+        assert_eq!(
+            first_command(&mut input, &mut Vec::new()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_to_connect_is_only_reported_to_the_first_command_needing_it() {
+        let connecting = async { Err::<(), _>("unreachable") };
+        let mut input = BufReader::new(&b"capabilities\nlist\n"[..]).lines();
+        let mut out = vec![];
+
+        let (first, connected) = handshake(connecting, &mut input, &mut out)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(first, "list");
+        assert_eq!(connected, Err("unreachable"));
+        assert_eq!(out, Capabilities::default().to_string().as_bytes());
+
+        // Nothing needed it
+        let mut input = BufReader::new(&b"capabilities\n"[..]).lines();
+        let connecting = async { Err::<(), _>("unreachable") };
+        assert!(handshake(connecting, &mut input, &mut Vec::new())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn handshake_is_answered_while_connecting() {
+        let mut input = BufReader::new(&b"capabilities\noption progress true\nlist\n"[..]).lines();
+        let mut out = vec![];
+
+        let (first, connected) = handshake(async { 7 }, &mut input, &mut out)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!((first.as_str(), connected), ("list", 7));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{}unsupported\n", Capabilities::default())
+        );
+    }
+
+    #[test]
+    fn symrefs_are_advertised_when_their_target_is() {
+        let mut repo_data = RepoData::default();
+        for (name, git_hash) in [
+            ("refs/heads/main", "a"),
+            ("refs/heads/topic", "b"),
+            ("refs/remotes/origin/HEAD", "a"),
+        ] {
+            repo_data.refs.insert(name.to_owned(), git_hash.repeat(40));
+        }
+        repo_data.symrefs.insert(
+            "refs/remotes/origin/HEAD".to_owned(),
+            "refs/heads/main".to_owned(),
+        );
+        repo_data
+            .symrefs
+            .insert("HEAD".to_owned(), "refs/heads/topic".to_owned());
+
+        let refs = advertised_refs(&repo_data, None, &[], false).unwrap();
+        assert!(refs.contains(&(
+            "@refs/heads/main".to_owned(),
+            "refs/remotes/origin/HEAD".to_owned()
+        )));
+        assert_eq!(
+            refs.last(),
+            Some(&("@refs/heads/topic".to_owned(), "HEAD".to_owned()))
+        );
+
+        // Without its target, git only learns the sha1
+        let refs =
+            advertised_refs(&repo_data, None, &["refs/heads/main".to_owned()], false).unwrap();
+        assert!(refs.contains(&("a".repeat(40), "refs/remotes/origin/HEAD".to_owned())));
+    }
+
+    #[test]
+    fn connect_probes_are_declined() {
+        let mut out = vec![];
+
+        assert!(answer_offline("connect git-upload-pack", &mut out).unwrap());
+        assert!(answer_offline("stateless-connect git-upload-pack", &mut out).unwrap());
+        assert_eq!(out, b"\n\n");
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_still_end_their_response() {
+        let mut backend = Fake {
+            refs: vec![("a".repeat(40), "refs/heads/main".to_owned())],
+            ..Fake::default()
+        };
+
+        let out = answers(&mut backend, "frobnicate now\nlist\n").await;
+
+        assert_eq!(out, format!("\n{} refs/heads/main\n\n", "a".repeat(40)));
+    }
+
+    #[test]
+    fn capabilities_list_flags_then_refspecs() {
+        assert_eq!(
+            Capabilities::default().to_string(),
+            "option\npush\nfetch\n\
+             refspec refs/heads/*:refs/heads/*\n\
+             refspec refs/tags/*:refs/tags/*\n\n"
+        );
+
+        let fetch_only = Capabilities {
+            option: false,
+            push: false,
+            fetch: true,
+            refspecs: vec![],
+        };
+        assert_eq!(fetch_only.lines(), vec!["fetch"]);
+        assert_eq!(fetch_only.to_string(), "fetch\n\n");
+    }
+
+    #[test]
+    fn remotes_keep_state_apart_under_file_safe_names() {
+        let (_dir, repo) = repository();
+
+        assert_eq!(
+            state_dir(&repo, "origin"),
+            repo.path().join("inv4").join("origin")
+        );
+        assert_eq!(
+            state_dir(&repo, "../team/main repo"),
+            repo.path().join("inv4").join(".._team_main_repo")
+        );
+    }
+
+    #[test]
+    fn remote_settings_come_from_git_config() {
+        let (_dir, repo) = repository();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str("remote.origin.inv4-account", "treasury")
+            .unwrap();
+        config.set_str("remote.origin.inv4-endpoint", "").unwrap();
+
+        assert_eq!(
+            remote_setting(&repo, "origin", "inv4-account").as_deref(),
+            Some("treasury")
+        );
+        assert_eq!(remote_setting(&repo, "origin", "inv4-endpoint"), None);
+        assert_eq!(remote_setting(&repo, "backup", "inv4-account"), None);
+    }
+
+    #[test]
+    fn retried_push_is_landed_once_ref_and_object_are_there() {
+        let oid = Oid::from_bytes(&[1; 20]).unwrap();
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), oid.to_string());
+
+        // A ref without its objects indexed is no proof of a landed push
+        assert!(!landed(&repo_data, "refs/heads/main", oid));
+
+        repo_data.objects.insert(oid.to_string(), "mo".to_owned());
+        assert!(landed(&repo_data, "refs/heads/main", oid));
+        assert!(!landed(&repo_data, "refs/heads/topic", oid));
+        assert!(!landed(
+            &repo_data,
+            "refs/heads/main",
+            Oid::from_bytes(&[2; 20]).unwrap()
+        ));
+    }
+
+    #[test]
+    fn fetched_refs_are_copied_to_the_tee_remote() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let rewritten = commit(&repo, None, &[], "rewritten");
+        let mirror_dir = TempDir::new().unwrap();
+        let mirror = Repository::init_bare(mirror_dir.path()).unwrap();
+        let url = mirror_dir.path().to_str().unwrap();
+        let main = |tip, force| [("refs/heads/main".to_owned(), tip, force)];
+
+        push_tee_refs(&repo, url, &main(base, false)).unwrap();
+        assert_eq!(mirror.refname_to_id("refs/heads/main").unwrap(), base);
+        // The staging refs don't outlive the push
+        assert!(repo.find_reference("refs/inv4-tee/heads/main").is_err());
+
+        assert!(push_tee_refs(&repo, url, &main(rewritten, false)).is_err());
+        push_tee_refs(&repo, url, &main(rewritten, true)).unwrap();
+        assert_eq!(mirror.refname_to_id("refs/heads/main").unwrap(), rewritten);
+    }
+
+    #[test]
+    fn fetches_of_tips_the_remote_lost_are_skipped() {
+        let (old, tip) = ("a".repeat(40), "b".repeat(40));
+        let mut repo_data = RepoData::default();
+        repo_data
+            .refs
+            .insert("refs/heads/main".to_owned(), tip.clone());
+        repo_data.objects.insert(tip.clone(), "mo".to_owned());
+        let check = |sha: &str, name: &str| {
+            let request = FetchRequest {
+                sha: sha.to_owned(),
+                name: name.to_owned(),
+            };
+            check_known_tip(&repo_data, "origin", &request)
+        };
+
+        assert!(check(&tip, "refs/heads/main").is_ok());
+
+        let e = check(&old, "refs/heads/main").unwrap_err();
+        assert!(e.contains(&format!("which rewrote it to {}", tip)), "{}", e);
+        assert!(e.contains("git remote prune origin"), "{}", e);
+
+        let e = check(&old, "refs/heads/gone").unwrap_err();
+        assert!(e.ends_with("it was deleted or force-pushed away"), "{}", e);
+    }
+
+    #[test]
+    fn delete_push_answers_ok() {
+        let outcome = PushOutcome {
+            dst: "refs/heads/gone".to_owned(),
+            result: Ok(Updated {
+                old: Some("a".repeat(40)),
+                new: None,
+            }),
+        };
+
+        assert_eq!(push_answer(&outcome), "ok refs/heads/gone");
+    }
+
+    #[test]
+    fn multi_line_push_errors_read_as_one_tagged_reason() {
+        let error = PushError::new("Publishing failed:\n    Transaction has a bad signature\n\n");
+        assert_eq!(
+            error.flattened(),
+            "Publishing failed; Transaction has a bad signature"
+        );
+        assert_eq!(
+            error.to_string(),
+            "Publishing failed:\n    Transaction has a bad signature\n\n"
+        );
+
+        let outcome = PushOutcome {
+            dst: "refs/heads/main".to_owned(),
+            result: Err(error),
+        };
+        assert_eq!(
+            push_answer(&outcome),
+            r#"error refs/heads/main "Publishing failed; Transaction has a bad signature (INV4-E008)""#
+        );
+    }
+
+    #[test]
+    fn ref_names_are_checked_unless_deleted() {
+        let request = |src: &str, dst: &str| PushRequest {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+            force: false,
+        };
+
+        assert!(check_ref_name(&request("main", "refs/heads/fünf-✓"), 255).is_ok());
+        assert!(check_ref_name(&request("main", "refs/heads/a\nb"), 255).is_err());
+        assert!(check_ref_name(&request("main", "refs/heads/a..b"), 255).is_err());
+        assert!(check_ref_name(&request("main", "heads/main"), 255).is_err());
+
+        let e = check_ref_name(&request("main", "refs/heads/long"), 10).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the name is 15 bytes long, over max_ref_name_bytes (10)"
+        );
+
+        assert!(check_ref_name(&request("", "refs/heads/a\nb"), 255).is_ok());
+    }
+
+    #[test]
+    fn push_errors_are_quoted_for_git() {
+        let outcome = PushOutcome {
+            dst: "refs/heads/main".to_owned(),
+            result: Err(PushError::new("hook said \"no\" at C:\\hooks\nsee above")),
+        };
+
+        let answer = push_answer(&outcome);
+        assert_eq!(
+            answer,
+            r#"error refs/heads/main "hook said \"no\" at C:\\hooks; see above""#
+        );
+        let quoted = answer.strip_prefix("error refs/heads/main ").unwrap();
+        assert_eq!(
+            unquote_c_style(quoted).as_deref(),
+            Some("hook said \"no\" at C:\\hooks; see above")
+        );
+    }
+
+    #[test]
+    fn keys_the_chain_refused_are_recognized() {
+        assert!(rejects_key("Transaction has a bad signature"));
+        assert!(rejects_key("Module error: INV4::NoPermission"));
+        assert!(!rejects_key("Module error: INV4::IpsNotFound"));
+        assert!(!rejects_key("connection refused"));
+    }
+
+    /// The refs of `repo`, as `(name, target)` with symbolic targets marked by `->`.
+    fn refs(repo: &Repository) -> Vec<(String, String)> {
+        let mut refs: Vec<_> = repo
+            .references()
+            .unwrap()
+            .map(|reference| {
+                let reference = reference.unwrap();
+                let target = match reference.symbolic_target() {
+                    Some(target) => format!("-> {}", target),
+                    None => reference.target().unwrap().to_string(),
+                };
+                (reference.name().unwrap().to_owned(), target)
+            })
+            .collect();
+        refs.sort();
+
+        refs
+    }
+
+    #[tokio::test]
+    async fn cloning_through_the_helper_leaves_the_refs_of_a_plain_clone() {
+        let (source_dir, source) = repository();
+        let base = commit(&source, Some("refs/heads/main"), &[], "base");
+        commit(&source, Some("refs/heads/main"), &[base], "tip");
+        commit(&source, Some("refs/heads/topic"), &[base], "topic");
+        source
+            .reference("refs/tags/v1", base, false, "tag")
+            .unwrap();
+        source.set_head("refs/heads/main").unwrap();
+
+        let plain_dir = TempDir::new().unwrap();
+        let plain = RepoBuilder::new()
+            .clone(source_dir.path().to_str().unwrap(), plain_dir.path())
+            .unwrap();
+
+        let (_dir, repo) = repository();
+        let mut backend = Published {
+            remote: published(&source),
+            repo,
+        };
+
+        // git lists the refs, then fetches every one its refspecs map
+        let listed = answers(&mut backend, "list\n").await;
+        let advertised: Vec<(&str, &str)> = listed
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.split_once(' ').unwrap())
+            .collect();
+        let fetches: String = advertised
+            .iter()
+            .filter(|(value, _)| !value.starts_with('@'))
+            .map(|(value, name)| format!("fetch {} {}\n", value, name))
+            .collect();
+        assert_eq!(answers(&mut backend, &format!("{}\n", fetches)).await, "\n");
+
+        // The objects are in, and updating refs is left to git
+        let repo = &backend.repo;
+        assert!(refs(repo).is_empty());
+
+        // What git then does for a clone: map the fetched refs to remote-tracking ones, point
+        // origin/HEAD at the default branch, and check that branch out
+        let origin = repo.remote("origin", "inv4://7").unwrap();
+        let refspec = origin.get_refspec(0).unwrap();
+        let tracking = |name: &str| {
+            refspec
+                .transform(name)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_owned()
+        };
+        for &(value, name) in &advertised {
+            match value.strip_prefix('@') {
+                Some(target) => {
+                    assert_eq!(name, "HEAD");
+                    repo.reference_symbolic(
+                        "refs/remotes/origin/HEAD",
+                        &tracking(target),
+                        false,
+                        "clone",
+                    )
+                    .unwrap();
+
+                    let tip = repo.refname_to_id(&tracking(target)).unwrap();
+                    repo.reference(target, tip, false, "clone").unwrap();
+                    repo.set_head(target).unwrap();
+                }
+                None if refspec.src_matches(name) => {
+                    let oid = Oid::from_str(value).unwrap();
+                    repo.reference(&tracking(name), oid, false, "clone")
+                        .unwrap();
+                }
+                None => {
+                    let oid = Oid::from_str(value).unwrap();
+                    repo.reference(name, oid, false, "clone").unwrap();
+                }
+            }
+        }
+
+        assert_eq!(
+            refs(&plain)
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "refs/heads/main",
+                "refs/remotes/origin/HEAD",
+                "refs/remotes/origin/main",
+                "refs/remotes/origin/topic",
+                "refs/tags/v1",
+            ]
+        );
+        assert_eq!(refs(repo), refs(&plain));
+        assert_eq!(repo.head().unwrap().name(), plain.head().unwrap().name());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{
+    chain::Chain,
+    error,
+    filter::BlobFilter,
+    meta::RepoMeta,
+    output,
+    primitives::{BoxResult, Config, IpsId, SubassetId},
+    store::ObjectStore,
+};
+use log::debug;
+use std::{error::Error, str::FromStr};
+
+/// The address of an on-chain repository, as given to git:
+/// `inv4://<ips_id>[/<subasset_id>][?branch=<ref>][&filter=blob-limit:<size>]`.
+///
+/// A name may stand in for the IPS id, as in `inv4://team/infra`, looked up in the `aliases`
+/// of the config and then in the registry of `name_registry_ips` by `resolve`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteUrl {
+    /// 0 for a named URL until it is resolved
+    pub ips_id: IpsId,
+    /// The name given in place of the IPS id
+    pub name: Option<String>,
+    pub subasset_id: Option<SubassetId>,
+    /// Only this ref is advertised and accepted for pushes
+    pub branch: Option<String>,
+    /// Fetches leave out blobs of at least this many bytes
+    pub blob_limit: Option<u64>,
+}
+
+impl FromStr for RemoteUrl {
+    type Err = Box<dyn Error>;
+
+    fn from_str(raw_url: &str) -> Result<Self, Self::Err> {
+        // git strips the scheme for `inv4::<address>` style remotes
+        let (address, scheme) = match raw_url.strip_prefix("inv4://") {
+            Some(address) => (address, true),
+            None => (raw_url, false),
+        };
+        let (path, query) = address.split_once('?').unwrap_or((address, ""));
+
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+        let first = components
+            .first()
+            .ok_or("Missing IPS id. Expected: 'inv4://>ips_id<'")?;
+
+        // Ids come first, so a name that is also a number can't shadow an IPS
+        let (ips_id, name, subasset_id) = match first.parse::<IpsId>() {
+            Ok(ips_id) => {
+                let subasset_id = components
+                    .get(1)
+                    .map(|c| c.parse::<SubassetId>())
+                    .transpose()?;
+
+                (ips_id, None, subasset_id)
+            }
+            // Otherwise `inv4-git <command> <argument>` would be taken for a remote
+            Err(_) if !scheme => return Err("Repository names need the inv4:// scheme".into()),
+            Err(_) => {
+                let subasset_id = match components.last().map(|c| c.parse::<SubassetId>()) {
+                    Some(Ok(subasset_id)) => {
+                        components.pop();
+                        Some(subasset_id)
+                    }
+                    _ => None,
+                };
+                let name = components.join("/");
+                check_name(&name)?;
+
+                (IpsId(0), Some(name), subasset_id)
+            }
+        };
+
+        let mut branch = None;
+        let mut blob_limit = None;
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=') {
+                Some(("branch", name)) if !name.is_empty() => {
+                    // Short names refer to branches, like in `git clone --branch`
+                    branch = Some(if name.starts_with("refs/") {
+                        name.to_owned()
+                    } else {
+                        format!("refs/heads/{}", name)
+                    });
+                }
+                Some(("filter", spec)) => blob_limit = Some(BlobFilter::parse_limit(spec)?),
+                _ => return Err(format!("Unsupported URL parameter {:?}", param).into()),
+            }
+        }
+
+        Ok(Self {
+            ips_id,
+            name,
+            subasset_id,
+            branch,
+            blob_limit,
+        })
+    }
+}
+
+impl RemoteUrl {
+    /// Look up the IPS id of a named URL: in the config's `aliases`, then in the registry of
+    /// `name_registry_ips`. An id that is also the name of an alias is kept, with a warning.
+    pub async fn resolve(
+        &mut self,
+        config: &Config,
+        chain: &Chain,
+        store: &dyn ObjectStore,
+    ) -> BoxResult<()> {
+        if let Some(ips_id) = self.aliased(config) {
+            self.ips_id = ips_id;
+            return Ok(());
+        }
+        let name = self.name.as_deref().unwrap_or_default();
+
+        let registry = match config.name_registry_ips {
+            Some(registry) => registry,
+            None => error!(format!(
+                "No alias {}; add one with `inv4-git alias add {} <ips id>`",
+                name, name
+            )),
+        };
+
+        let names = RepoMeta::load(chain, store, registry)
+            .await
+            .map_err(|e| format!("Could not read the name registry IPS {}: {}", registry, e))?
+            .map(|meta| meta.names)
+            .unwrap_or_default();
+
+        match names.get(name) {
+            Some(&ips_id) => {
+                debug!(
+                    "{} is registered in IPS {} as IPS {}",
+                    name, registry, ips_id
+                );
+                self.ips_id = ips_id;
+                Ok(())
+            }
+            None => error!(format!(
+                "No alias {}, and the name registry IPS {} doesn't list it",
+                name, registry
+            )),
+        }
+    }
+
+    /// The IPS id of the URL without asking the registry: the one given, or the alias of the
+    /// name given. `None` for a name without an alias.
+    fn aliased(&self, config: &Config) -> Option<IpsId> {
+        let name = match &self.name {
+            Some(name) => name,
+            None => {
+                if let Some(alias) = config
+                    .aliases
+                    .keys()
+                    .find(|alias| alias.parse::<IpsId>().ok() == Some(self.ips_id))
+                {
+                    output::warn(format!(
+                        "{} is an alias, but IPS ids take precedence; using IPS {}",
+                        alias, self.ips_id
+                    ));
+                }
+                return Some(self.ips_id);
+            }
+        };
+
+        let ips_id = config.aliases.get(name).copied();
+        if let Some(ips_id) = ips_id {
+            debug!("Alias {} is IPS {}", name, ips_id);
+        }
+        ips_id
+    }
+}
+
+/// Check that `name` can be used as a repository name in URLs: a path without numeric
+/// components, which would be read as IPS or subasset ids.
+pub fn check_name(name: &str) -> BoxResult<()> {
+    if name.contains(['?', '&', '=']) {
+        error!(format!("Repository name {:?} contains ?, & or =", name))
+    }
+
+    let mut components = name.split('/');
+    if components.any(|c| c.is_empty() || c.parse::<IpsId>().is_ok()) {
+        error!(format!(
+            "Repository name {:?} must not have empty or numeric path components",
+            name
+        ))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_parsed_with_or_without_the_scheme() {
+        let url: RemoteUrl = "inv4://12/3".parse().unwrap();
+        assert_eq!(url.ips_id, IpsId(12));
+        assert_eq!(url.subasset_id, Some(SubassetId(3)));
+        assert_eq!(url.branch, None);
+
+        assert_eq!("12".parse::<RemoteUrl>().unwrap().ips_id, IpsId(12));
+        assert!("inv4://".parse::<RemoteUrl>().is_err());
+    }
+
+    #[test]
+    fn branch_filter_takes_short_and_full_names() {
+        let url: RemoteUrl = "inv4://0?branch=main".parse().unwrap();
+        assert_eq!(url.branch.as_deref(), Some("refs/heads/main"));
+
+        let url: RemoteUrl = "inv4://0/1?branch=refs/tags/v1".parse().unwrap();
+        assert_eq!(url.subasset_id, Some(SubassetId(1)));
+        assert_eq!(url.branch.as_deref(), Some("refs/tags/v1"));
+
+        assert!("inv4://0?branch=main&depth=1".parse::<RemoteUrl>().is_err());
+    }
+
+    #[test]
+    fn blob_limit_comes_from_the_filter_parameter() {
+        let url: RemoteUrl = "inv4://0?branch=main&filter=blob-limit:1MiB"
+            .parse()
+            .unwrap();
+        assert_eq!(url.blob_limit, Some(1 << 20));
+        assert_eq!("inv4://0".parse::<RemoteUrl>().unwrap().blob_limit, None);
+
+        assert!("inv4://0?filter=tree:0".parse::<RemoteUrl>().is_err());
+    }
+
+    #[test]
+    fn names_stand_in_for_ips_ids_with_the_scheme() {
+        let url: RemoteUrl = "inv4://team/infra/2?branch=main".parse().unwrap();
+        assert_eq!(url.name.as_deref(), Some("team/infra"));
+        assert_eq!(url.subasset_id, Some(SubassetId(2)));
+        assert_eq!(url.branch.as_deref(), Some("refs/heads/main"));
+
+        assert_eq!("inv4://12".parse::<RemoteUrl>().unwrap().name, None);
+        assert!("team/infra".parse::<RemoteUrl>().is_err());
+        assert!("inv4://team/1/infra".parse::<RemoteUrl>().is_err());
+    }
+
+    #[test]
+    fn names_must_not_look_like_ids_or_parameters() {
+        assert!(check_name("team/infra").is_ok());
+        assert!(check_name("website").is_ok());
+
+        assert!(check_name("team/7").is_err());
+        assert!(check_name("team//infra").is_err());
+        assert!(check_name("infra?branch=main").is_err());
+    }
+
+    #[test]
+    fn aliases_resolve_names_but_never_ids() {
+        let mut config = Config::default();
+        config.aliases.insert("team/infra".to_owned(), IpsId(42));
+        config.aliases.insert("7".to_owned(), IpsId(43));
+
+        let url: RemoteUrl = "inv4://team/infra".parse().unwrap();
+        assert_eq!(url.aliased(&config), Some(IpsId(42)));
+        let url: RemoteUrl = "inv4://7".parse().unwrap();
+        assert_eq!(url.aliased(&config), Some(IpsId(7)));
+
+        // Left to the registry
+        let url: RemoteUrl = "inv4://website".parse().unwrap();
+        assert_eq!(url.aliased(&config), None);
+    }
+}
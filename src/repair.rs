@@ -0,0 +1,112 @@
+//! Fetches that check objects the odb already has instead of trusting them, and replace the
+//! corrupt ones.
+
+use crate::{output, primitives::BoxResult};
+use git2::{Oid, Repository};
+use log::debug;
+use std::{fmt, fs};
+
+/// What a refetch found among the objects it walked.
+#[derive(Default, Debug)]
+pub struct Refetch {
+    /// Local objects whose data matched their hash
+    pub verified: usize,
+    /// Corrupt loose objects removed to be downloaded again
+    pub repaired: usize,
+    /// Objects that weren't there at all
+    pub downloaded: usize,
+    /// Corrupt objects in packs, which can't be replaced one at a time
+    pub unrepairable: Vec<Oid>,
+}
+
+impl fmt::Display for Refetch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} verified, {} repaired, {} downloaded new",
+            self.verified, self.repaired, self.downloaded
+        )
+    }
+}
+
+impl Refetch {
+    /// Summarize what was found, warning about what couldn't be repaired.
+    pub fn report(&self) {
+        output::info(format!("Refetched objects: {}", self));
+
+        if !self.unrepairable.is_empty() {
+            output::warn(format!(
+                "{} corrupt objects are in packs and can't be replaced one at a time, e.g. {}; run `git fsck` for the full list",
+                self.unrepairable.len(),
+                self.unrepairable[0]
+            ));
+        }
+    }
+}
+
+/// Whether the odb can read `oid` and its data hashes to it.
+pub fn is_intact(repo: &Repository, oid: Oid) -> bool {
+    let odb = match repo.odb() {
+        Ok(odb) => odb,
+        Err(_) => return false,
+    };
+
+    match odb.read(oid) {
+        Ok(object) => Oid::hash_object(object.kind(), object.data()).ok() == Some(oid),
+        Err(e) => {
+            debug!("Could not read {}: {}", oid, e);
+            false
+        }
+    }
+}
+
+/// Remove the loose object `oid`, so it can be written again. Returns false when it isn't loose,
+/// i.e. is in a pack.
+pub fn remove_loose_object(repo: &Repository, oid: Oid) -> BoxResult<bool> {
+    let hex = oid.to_string();
+    let path = repo.path().join("objects").join(&hex[..2]).join(&hex[2..]);
+
+    if !path.is_file() {
+        return Ok(false);
+    }
+
+    fs::remove_file(&path)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::repository;
+
+    fn loose_path(repo: &Repository, oid: Oid) -> std::path::PathBuf {
+        let hex = oid.to_string();
+        repo.path().join("objects").join(&hex[..2]).join(&hex[2..])
+    }
+
+    #[test]
+    fn corrupt_loose_objects_are_found_and_removed() {
+        let (_dir, repo) = repository();
+        let good = repo.blob(b"good").unwrap();
+        let bad = repo.blob(b"bad").unwrap();
+        let missing = Oid::hash_object(git2::ObjectType::Blob, b"missing").unwrap();
+
+        assert!(is_intact(&repo, good));
+        assert!(is_intact(&repo, bad));
+        assert!(!is_intact(&repo, missing));
+
+        // Give `bad` the data of `good`, so it no longer hashes to its name.
+        fs::remove_file(loose_path(&repo, bad)).unwrap();
+        fs::copy(loose_path(&repo, good), loose_path(&repo, bad)).unwrap();
+
+        let repo = Repository::open(repo.path()).unwrap();
+        assert!(is_intact(&repo, good));
+        assert!(!is_intact(&repo, bad));
+
+        assert!(remove_loose_object(&repo, bad).unwrap());
+        assert!(!loose_path(&repo, bad).exists());
+        assert!(!remove_loose_object(&repo, bad).unwrap());
+        assert!(!remove_loose_object(&repo, missing).unwrap());
+    }
+}
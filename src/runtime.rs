@@ -0,0 +1,913 @@
+//! The pallets the helper uses, reached through whichever runtime the chain endpoint runs.
+//!
+//! Bundled runtimes go through the static bindings generated from their metadata. Any other
+//! runtime carrying the INV4, Ipf and Utility pallets goes through dynamic calls and storage
+//! queries, given its metadata in `metadata_path`.
+
+use crate::{
+    chain::{Chain, Included, IpfRecord, IpsFlags},
+    error,
+    primitives::{BoxResult, Config, IpfId, IpsId, SubassetId},
+    tinkernet::{
+        self,
+        runtime_types::{
+            invarch_primitives::{IpsType, OneOrPercent, Parentage},
+            pallet_inv4::pallet::{AnyId, Call as INV4Call},
+            pallet_utility::pallet::Call as UtilityCall,
+            sp_arithmetic::per_things::Percent,
+            tinkernet_runtime::{Call, InvArchLicenses},
+        },
+    },
+};
+use async_trait::async_trait;
+use codec::Decode;
+use std::fs;
+use subxt::{
+    dynamic::{self, DecodedValue, Value},
+    ext::{
+        frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed},
+        scale_value::{Composite, ValueDef},
+        sp_core::H256,
+        sp_runtime::AccountId32,
+    },
+    tx::TxPayload,
+    Metadata, OnlineClient, PolkadotConfig,
+};
+
+/// Calls the helper submits, which must be laid out the same on the node as in the metadata used.
+const CALLS: &[(&str, &str)] = &[
+    ("Ipf", "mint"),
+    ("Ipf", "burn"),
+    ("INV4", "operate_multisig"),
+    ("INV4", "append"),
+    ("INV4", "remove"),
+    ("Utility", "batch_all"),
+];
+
+/// Storage entries the helper reads, likewise.
+const STORAGE: &[(&str, &str)] = &[
+    ("System", "Account"),
+    ("INV4", "IpStorage"),
+    ("Ipf", "IpfStorage"),
+];
+
+/// Metadata compiled into the helper, along with the bindings generated from it.
+pub struct Bundle {
+    pub name: &'static str,
+    /// `specName`s of the runtimes it describes
+    pub spec_names: &'static [&'static str],
+    pub metadata: &'static [u8],
+    runtime: fn() -> Box<dyn Runtime>,
+}
+
+pub const BUNDLES: &[Bundle] = &[Bundle {
+    name: "tinkernet",
+    spec_names: &["tinkernet_node"],
+    metadata: include_bytes!("../tinkernet_metadata.scale"),
+    runtime: || Box::new(Tinkernet),
+}];
+
+impl Bundle {
+    /// The `specName` and `spec_version` of the runtime the metadata was exported from.
+    pub fn runtime_version(&self) -> BoxResult<(String, u32)> {
+        let pallets = match RuntimeMetadataPrefixed::decode(&mut &self.metadata[..])?.1 {
+            RuntimeMetadata::V14(metadata) => metadata.pallets,
+            _ => error!(format!("The bundled {} metadata is not V14", self.name)),
+        };
+
+        let version = pallets
+            .iter()
+            .find(|pallet| pallet.name == "System")
+            .and_then(|pallet| {
+                pallet
+                    .constants
+                    .iter()
+                    .find(|constant| constant.name == "Version")
+            })
+            .ok_or("System.Version missing from the bundled metadata")?;
+
+        // RuntimeVersion starts with spec_name, impl_name, authoring_version and spec_version
+        let (spec_name, _, _, spec_version) =
+            <(String, String, u32, u32)>::decode(&mut &version.value[..])?;
+
+        Ok((spec_name, spec_version))
+    }
+}
+
+/// The multisig batch a push ends with: swap in the new RepoData and append the new packs.
+///
+/// `meta set` swaps RepoMeta IPFs with it the same way, without packs.
+pub struct Publish {
+    pub ips_id: IpsId,
+    pub subasset_id: Option<SubassetId>,
+    /// The pusher, who holds the minted IPFs until they are appended
+    pub caller: AccountId32,
+    pub pack_ipf_ids: Vec<IpfId>,
+    /// Appended with the packs; `None` for a call appending packs ahead of the one that swaps
+    /// the RepoData, see `batching`
+    pub new_repo_data: Option<IpfId>,
+    /// Removed from the IPS, if the repository had been pushed to before
+    pub old_repo_data: Option<IpfId>,
+    /// Other IPFs removed from the IPS along with it, e.g. ones `inv4-git migrate` superseded
+    pub removed_ipf_ids: Vec<IpfId>,
+    /// Multisig metadata describing the push, see `push_metadata`
+    pub metadata: Vec<u8>,
+}
+
+impl Publish {
+    /// The calls the multisig batch is made of, in order.
+    pub fn parts(&self) -> Vec<PublishPart> {
+        let removed: Vec<_> = self
+            .old_repo_data
+            .iter()
+            .chain(&self.removed_ipf_ids)
+            .copied()
+            .collect();
+        let appended = self
+            .pack_ipf_ids
+            .iter()
+            .chain(&self.new_repo_data)
+            .copied()
+            .collect();
+
+        let mut parts = vec![];
+        if !removed.is_empty() {
+            parts.push(PublishPart::Remove(removed));
+        }
+        parts.push(PublishPart::Append(appended));
+        parts
+    }
+}
+
+/// A call inside the batch of a `Publish`, kept as data until a runtime builds it. That way each
+/// can also be built on its own, e.g. to estimate its fee.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PublishPart {
+    /// Remove these IPFs from the IPS, back to the pusher
+    Remove(Vec<IpfId>),
+    /// Append these IPFs, held by the pusher, to the IPS
+    Append(Vec<IpfId>),
+}
+
+impl PublishPart {
+    /// The call's name in the INV4 pallet.
+    pub fn call(&self) -> &'static str {
+        match self {
+            Self::Remove(_) => "remove",
+            Self::Append(_) => "append",
+        }
+    }
+
+    /// The call's name as `ChainCall::name` gives it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Remove(_) => "INV4.remove",
+            Self::Append(_) => "INV4.append",
+        }
+    }
+}
+
+/// The share of votes a multisig call of a new IPS needs, in percent. New IPSes get GPLv3 as
+/// their license, no default permission and no default asset weight, as the README's example.
+const NEW_IPS_EXECUTION_THRESHOLD: u8 = 50;
+
+/// A call built by a `Runtime`, submitted like any other payload.
+pub struct ChainCall {
+    name: &'static str,
+    payload: Box<dyn TxPayload>,
+}
+
+impl ChainCall {
+    fn new(name: &'static str, payload: impl TxPayload + 'static) -> Self {
+        Self {
+            name,
+            payload: Box::new(payload),
+        }
+    }
+
+    /// A call encoded already, e.g. exported for someone else to sign, submitted as it is.
+    pub fn encoded(name: &'static str, call_data: Vec<u8>) -> Self {
+        Self::new(name, Encoded(call_data))
+    }
+
+    /// `<pallet>.<call>`, e.g. `Ipf.mint`, to tell calls apart in fee accounting.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl TxPayload for ChainCall {
+    fn encode_call_data(&self, metadata: &Metadata, out: &mut Vec<u8>) -> Result<(), subxt::Error> {
+        self.payload.encode_call_data(metadata, out)
+    }
+}
+
+/// SCALE-encoded call data, written out verbatim.
+struct Encoded(Vec<u8>);
+
+impl TxPayload for Encoded {
+    fn encode_call_data(&self, _: &Metadata, out: &mut Vec<u8>) -> Result<(), subxt::Error> {
+        out.extend_from_slice(&self.0);
+        Ok(())
+    }
+}
+
+/// Builds the calls and performs the storage reads that depend on the runtime's layout.
+#[async_trait(?Send)]
+pub trait Runtime {
+    /// Free balance of `account`, zero for accounts that don't exist.
+    async fn free_balance(&self, chain: &Chain, account: &AccountId32) -> BoxResult<u128>;
+
+    /// The ids of all IPFs in the IPS's data set at block `at` (the best block when `None`), or
+    /// `None` if the IPS doesn't exist.
+    async fn ipf_ids(
+        &self,
+        chain: &Chain,
+        ips_id: IpsId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<Vec<IpfId>>>;
+
+    async fn ipf(&self, chain: &Chain, id: IpfId, at: Option<H256>)
+        -> BoxResult<Option<IpfRecord>>;
+
+    /// The flags of the IPS, or `None` if the IPS doesn't exist.
+    async fn ips_flags(&self, chain: &Chain, ips_id: IpsId) -> BoxResult<Option<IpsFlags>>;
+
+    fn mint_ipf(&self, metadata: Vec<u8>, data: H256) -> ChainCall;
+
+    fn burn_ipf(&self, id: IpfId) -> ChainCall;
+
+    fn publish(&self, publish: &Publish) -> ChainCall;
+
+    /// `part` of `publish`'s batch as a call of its own.
+    fn publish_part(&self, publish: &Publish, part: &PublishPart) -> ChainCall;
+
+    /// The id of the IPF minted by an included mint call.
+    fn minted_ipf(&self, included: &Included) -> BoxResult<Option<IpfId>>;
+
+    /// Create an empty IPS, owned by the signer.
+    fn create_ips(&self, metadata: Vec<u8>) -> ChainCall;
+
+    /// The id of the IPS created by an included `create_ips` call.
+    fn created_ips(&self, included: &Included) -> BoxResult<Option<IpsId>>;
+}
+
+/// The runtime to use with the chain `api` is connected to.
+///
+/// `metadata_path` takes precedence over the bundles. Either way, the metadata has to agree with
+/// the node's on every call and storage entry the helper uses, so a runtime upgrade is reported
+/// on connecting rather than halfway through a push.
+pub fn select(config: &Config, api: &OnlineClient<PolkadotConfig>) -> BoxResult<Box<dyn Runtime>> {
+    let node = api.metadata();
+    let version = api.runtime_version();
+    let spec_name = version
+        .other
+        .get("specName")
+        .and_then(|name| name.as_str())
+        .unwrap_or("unknown");
+    let chain = format!(
+        "runtime {} v{} (genesis {:?})",
+        spec_name,
+        version.spec_version,
+        api.genesis_hash()
+    );
+
+    if let Some(path) = &config.metadata_path {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("Could not read metadata_path {}: {}", path.display(), e))?;
+        let metadata = decode_metadata(&bytes)
+            .map_err(|e| format!("{} is not SCALE-encoded metadata: {}", path.display(), e))?;
+
+        let differing = mismatches(&metadata, &node);
+        if !differing.is_empty() {
+            error!(format!(
+                "{} does not describe {}: {} differ; export the chain's current metadata to it",
+                path.display(),
+                chain,
+                differing.join(", ")
+            ))
+        }
+
+        return Ok(Box::new(Dynamic));
+    }
+
+    let bundled: Vec<_> = BUNDLES
+        .iter()
+        .map(|bundle| format!("{} ({})", bundle.name, bundle.spec_names.join(", ")))
+        .collect();
+
+    let bundle = BUNDLES
+        .iter()
+        .find(|bundle| bundle.spec_names.contains(&spec_name))
+        .ok_or_else(|| {
+            format!(
+                "No metadata is bundled for {}, only for {}; set metadata_path to the chain's metadata",
+                chain,
+                bundled.join(", ")
+            )
+        })?;
+
+    let differing = mismatches(&decode_metadata(bundle.metadata)?, &node);
+    if !differing.is_empty() {
+        error!(format!(
+            "The bundled {} metadata does not match {}: {} differ; set metadata_path to the chain's current metadata",
+            bundle.name,
+            chain,
+            differing.join(", ")
+        ))
+    }
+
+    Ok((bundle.runtime)())
+}
+
+fn decode_metadata(bytes: &[u8]) -> BoxResult<Metadata> {
+    Ok(RuntimeMetadataPrefixed::decode(&mut &bytes[..])?.try_into()?)
+}
+
+/// The calls and storage entries in `CALLS` and `STORAGE` laid out differently in `expected` than
+/// in `node`, or missing from either.
+fn mismatches(expected: &Metadata, node: &Metadata) -> Vec<String> {
+    let calls = CALLS.iter().filter(|(pallet, call)| {
+        match (
+            expected.call_hash(pallet, call),
+            node.call_hash(pallet, call),
+        ) {
+            (Ok(expected), Ok(node)) => expected != node,
+            _ => true,
+        }
+    });
+
+    let storage = STORAGE.iter().filter(|(pallet, entry)| {
+        match (
+            expected.storage_hash(pallet, entry),
+            node.storage_hash(pallet, entry),
+        ) {
+            (Ok(expected), Ok(node)) => expected != node,
+            _ => true,
+        }
+    });
+
+    calls
+        .chain(storage)
+        .map(|(pallet, item)| format!("{}.{}", pallet, item))
+        .collect()
+}
+
+/// Tinkernet, through the bindings generated from the bundled metadata.
+pub struct Tinkernet;
+
+#[async_trait(?Send)]
+impl Runtime for Tinkernet {
+    async fn free_balance(&self, chain: &Chain, account: &AccountId32) -> BoxResult<u128> {
+        let info = chain
+            .fetch(&tinkernet::storage().system().account(account))
+            .await?;
+
+        Ok(info.map(|info| info.data.free).unwrap_or_default())
+    }
+
+    async fn ipf_ids(
+        &self,
+        chain: &Chain,
+        ips_id: IpsId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<Vec<IpfId>>> {
+        let ips_info = chain
+            .fetch_at(&tinkernet::storage().inv4().ip_storage(&ips_id.0), at)
+            .await?;
+
+        Ok(ips_info.map(|info| {
+            info.data
+                .0
+                .into_iter()
+                .filter_map(|file| match file {
+                    AnyId::IpfId(id) => Some(IpfId(id)),
+                    _ => None,
+                })
+                .collect()
+        }))
+    }
+
+    async fn ipf(
+        &self,
+        chain: &Chain,
+        id: IpfId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<IpfRecord>> {
+        let ipf_info = chain
+            .fetch_at(&tinkernet::storage().ipf().ipf_storage(&id.0), at)
+            .await?;
+
+        Ok(ipf_info.map(|info| IpfRecord {
+            id,
+            metadata: info.metadata.0,
+            data: info.data,
+        }))
+    }
+
+    async fn ips_flags(&self, chain: &Chain, ips_id: IpsId) -> BoxResult<Option<IpsFlags>> {
+        let ips_info = chain
+            .fetch(&tinkernet::storage().inv4().ip_storage(&ips_id.0))
+            .await?;
+
+        Ok(ips_info.map(|info| IpsFlags {
+            replica_of: match info.ips_type {
+                IpsType::Replica(original) => Some(IpsId(original)),
+                IpsType::Normal => None,
+            },
+            parent: match info.parentage {
+                Parentage::Child(parent, _) => Some(IpsId(parent)),
+                Parentage::Parent(_) => None,
+            },
+            allow_replica: info.allow_replica,
+            default_permission: info.default_permission,
+            unknown: vec![],
+            raw: format!(
+                "parentage {:?}, ips_type {:?}, allow_replica {}, default_permission {}",
+                info.parentage, info.ips_type, info.allow_replica, info.default_permission
+            ),
+        }))
+    }
+
+    fn mint_ipf(&self, metadata: Vec<u8>, data: H256) -> ChainCall {
+        ChainCall::new("Ipf.mint", tinkernet::tx().ipf().mint(metadata, data))
+    }
+
+    fn burn_ipf(&self, id: IpfId) -> ChainCall {
+        ChainCall::new("Ipf.burn", tinkernet::tx().ipf().burn(id.0))
+    }
+
+    fn publish(&self, publish: &Publish) -> ChainCall {
+        let calls = publish
+            .parts()
+            .iter()
+            .map(|part| Call::INV4(tinkernet_part(publish, part)))
+            .collect();
+
+        let batch_call = Call::Utility(UtilityCall::batch_all { calls });
+
+        ChainCall::new(
+            "INV4.operate_multisig",
+            tinkernet::tx().inv4().operate_multisig(
+                true,
+                (publish.ips_id.0, publish.subasset_id.map(|id| id.0)),
+                Some(publish.metadata.clone()),
+                batch_call,
+            ),
+        )
+    }
+
+    fn publish_part(&self, publish: &Publish, part: &PublishPart) -> ChainCall {
+        match tinkernet_part(publish, part) {
+            INV4Call::remove {
+                ips_id,
+                original_caller,
+                assets,
+                new_metadata,
+            } => ChainCall::new(
+                part.name(),
+                tinkernet::tx()
+                    .inv4()
+                    .remove(ips_id, original_caller, assets, new_metadata),
+            ),
+            INV4Call::append {
+                ips_id,
+                original_caller,
+                assets,
+                new_metadata,
+            } => ChainCall::new(
+                part.name(),
+                tinkernet::tx()
+                    .inv4()
+                    .append(ips_id, original_caller, assets, new_metadata),
+            ),
+            _ => unreachable!("tinkernet_part only builds remove and append calls"),
+        }
+    }
+
+    fn minted_ipf(&self, included: &Included) -> BoxResult<Option<IpfId>> {
+        Ok(included
+            .find_first::<tinkernet::ipf::events::Minted>()?
+            .map(|minted| IpfId(minted.1)))
+    }
+
+    fn create_ips(&self, metadata: Vec<u8>) -> ChainCall {
+        ChainCall::new(
+            "INV4.create_ips",
+            tinkernet::tx().inv4().create_ips(
+                metadata,
+                vec![],
+                false,
+                InvArchLicenses::GPLv3,
+                OneOrPercent::ZeroPoint(Percent(NEW_IPS_EXECUTION_THRESHOLD)),
+                OneOrPercent::ZeroPoint(Percent(0)),
+                false,
+            ),
+        )
+    }
+
+    fn created_ips(&self, included: &Included) -> BoxResult<Option<IpsId>> {
+        Ok(included
+            .find_first::<tinkernet::inv4::events::IPSCreated>()?
+            .map(|created| IpsId(created.ips_id)))
+    }
+}
+
+/// `part` of `publish`'s batch as a Tinkernet INV4 call.
+fn tinkernet_part(publish: &Publish, part: &PublishPart) -> INV4Call {
+    let caller = &publish.caller;
+
+    match part {
+        PublishPart::Remove(ids) => INV4Call::remove {
+            ips_id: publish.ips_id.0,
+            original_caller: Some(caller.clone()),
+            assets: ids
+                .iter()
+                .map(|&id| (AnyId::IpfId(id.0), caller.clone()))
+                .collect(),
+            new_metadata: None,
+        },
+        PublishPart::Append(ids) => INV4Call::append {
+            ips_id: publish.ips_id.0,
+            original_caller: Some(caller.clone()),
+            assets: ids.iter().map(|&id| AnyId::IpfId(id.0)).collect(),
+            new_metadata: None,
+        },
+    }
+}
+
+/// Any runtime, through calls and storage queries encoded with the node's metadata.
+pub struct Dynamic;
+
+#[async_trait(?Send)]
+impl Runtime for Dynamic {
+    async fn free_balance(&self, chain: &Chain, account: &AccountId32) -> BoxResult<u128> {
+        let address = dynamic::storage("System", "Account", vec![account_value(account)]);
+
+        match chain.fetch(&address).await? {
+            Some(info) => number(field(field(&info, "data")?, "free")?),
+            None => Ok(0),
+        }
+    }
+
+    async fn ipf_ids(
+        &self,
+        chain: &Chain,
+        ips_id: IpsId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<Vec<IpfId>>> {
+        let address = dynamic::storage("INV4", "IpStorage", vec![Value::u128(ips_id.0.into())]);
+
+        let info = match chain.fetch_at(&address, at).await? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+
+        let mut ids = vec![];
+        for file in items(field(&info, "data")?)? {
+            if let ValueDef::Variant(variant) = &file.value {
+                if variant.name == "IpfId" {
+                    let id = variant.values.values().next().ok_or("IpfId without id")?;
+                    ids.push(IpfId(number(id)? as u64));
+                }
+            }
+        }
+
+        Ok(Some(ids))
+    }
+
+    async fn ipf(
+        &self,
+        chain: &Chain,
+        id: IpfId,
+        at: Option<H256>,
+    ) -> BoxResult<Option<IpfRecord>> {
+        let address = dynamic::storage("Ipf", "IpfStorage", vec![Value::u128(id.0.into())]);
+
+        let info = match chain.fetch_at(&address, at).await? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+
+        let data = bytes(field(&info, "data")?)?;
+        if data.len() != 32 {
+            error!(format!(
+                "IPF {} data is {} bytes, not a hash",
+                id,
+                data.len()
+            ))
+        }
+
+        Ok(Some(IpfRecord {
+            id,
+            metadata: bytes(field(&info, "metadata")?)?,
+            data: H256::from_slice(&data),
+        }))
+    }
+
+    async fn ips_flags(&self, chain: &Chain, ips_id: IpsId) -> BoxResult<Option<IpsFlags>> {
+        let address = dynamic::storage("INV4", "IpStorage", vec![Value::u128(ips_id.0.into())]);
+
+        let info = match chain.fetch(&address).await? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+
+        let parentage = field(&info, "parentage")?;
+        let ips_type = field(&info, "ips_type")?;
+        let allow_replica = field(&info, "allow_replica")?;
+        let default_permission = field(&info, "default_permission")?;
+        let mut flags = IpsFlags {
+            replica_of: None,
+            parent: None,
+            allow_replica: boolean(allow_replica)?,
+            default_permission: boolean(default_permission)?,
+            unknown: vec![],
+            raw: format!(
+                "parentage {}, ips_type {}, allow_replica {}, default_permission {}",
+                parentage, ips_type, allow_replica, default_permission
+            ),
+        };
+
+        // Variants are matched by name, so ones added by a later runtime are kept as unknown
+        match &parentage.value {
+            ValueDef::Variant(variant) if variant.name == "Parent" => {}
+            ValueDef::Variant(variant) if variant.name == "Child" => {
+                let parent = variant
+                    .values
+                    .values()
+                    .next()
+                    .ok_or("Child without parent")?;
+                flags.parent = Some(IpsId(number(parent)? as u32));
+            }
+            _ => flags.unknown.push(format!("parentage {}", parentage)),
+        }
+
+        match &ips_type.value {
+            ValueDef::Variant(variant) if variant.name == "Normal" => {}
+            ValueDef::Variant(variant) if variant.name == "Replica" => {
+                let original = variant
+                    .values
+                    .values()
+                    .next()
+                    .ok_or("Replica without IPS")?;
+                flags.replica_of = Some(IpsId(number(original)? as u32));
+            }
+            _ => flags.unknown.push(format!("ips_type {}", ips_type)),
+        }
+
+        Ok(Some(flags))
+    }
+
+    fn mint_ipf(&self, metadata: Vec<u8>, data: H256) -> ChainCall {
+        ChainCall::new(
+            "Ipf.mint",
+            dynamic::tx(
+                "Ipf",
+                "mint",
+                vec![
+                    Value::from_bytes(metadata),
+                    Value::unnamed_composite([Value::from_bytes(data)]),
+                ],
+            ),
+        )
+    }
+
+    fn burn_ipf(&self, id: IpfId) -> ChainCall {
+        ChainCall::new(
+            "Ipf.burn",
+            dynamic::tx("Ipf", "burn", vec![Value::u128(id.0.into())]),
+        )
+    }
+
+    fn publish(&self, publish: &Publish) -> ChainCall {
+        let calls = publish.parts().into_iter().map(|part| {
+            Value::unnamed_variant(
+                "INV4",
+                [Value::named_variant(
+                    part.call(),
+                    dynamic_part(publish, &part),
+                )],
+            )
+        });
+
+        let batch_call = Value::unnamed_variant(
+            "Utility",
+            [Value::named_variant(
+                "batch_all",
+                [("calls", Value::unnamed_composite(calls))],
+            )],
+        );
+
+        let subasset_id = match publish.subasset_id {
+            Some(id) => some(Value::u128(id.0.into())),
+            None => none(),
+        };
+
+        ChainCall::new(
+            "INV4.operate_multisig",
+            dynamic::tx(
+                "INV4",
+                "operate_multisig",
+                vec![
+                    Value::bool(true),
+                    Value::unnamed_composite([Value::u128(publish.ips_id.0.into()), subasset_id]),
+                    some(Value::from_bytes(&publish.metadata)),
+                    batch_call,
+                ],
+            ),
+        )
+    }
+
+    fn publish_part(&self, publish: &Publish, part: &PublishPart) -> ChainCall {
+        ChainCall::new(
+            part.name(),
+            dynamic::tx(
+                "INV4",
+                part.call(),
+                Composite::named(dynamic_part(publish, part)),
+            ),
+        )
+    }
+
+    fn minted_ipf(&self, included: &Included) -> BoxResult<Option<IpfId>> {
+        for event in &included.events {
+            if event.pallet_name() == "Ipf" && event.variant_name() == "Minted" {
+                // (owner, ipf_id, data)
+                let fields = event.field_values()?;
+                let id = fields.values().nth(1).ok_or("Minted event without id")?;
+                return Ok(Some(IpfId(number(id)? as u64)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn create_ips(&self, metadata: Vec<u8>) -> ChainCall {
+        let percent = |percent: u8| {
+            Value::unnamed_variant(
+                "ZeroPoint",
+                [Value::unnamed_composite([Value::u128(percent.into())])],
+            )
+        };
+
+        ChainCall::new(
+            "INV4.create_ips",
+            dynamic::tx(
+                "INV4",
+                "create_ips",
+                vec![
+                    Value::from_bytes(metadata),
+                    Value::unnamed_composite([]),
+                    Value::bool(false),
+                    Value::unnamed_variant("GPLv3", []),
+                    percent(NEW_IPS_EXECUTION_THRESHOLD),
+                    percent(0),
+                    Value::bool(false),
+                ],
+            ),
+        )
+    }
+
+    fn created_ips(&self, included: &Included) -> BoxResult<Option<IpsId>> {
+        for event in &included.events {
+            if event.pallet_name() == "INV4" && event.variant_name() == "IPSCreated" {
+                // (ips_account, ips_id, assets)
+                let fields = event.field_values()?;
+                let id = fields
+                    .values()
+                    .nth(1)
+                    .ok_or("IPSCreated event without id")?;
+                return Ok(Some(IpsId(number(id)? as u32)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The fields of `part` of `publish`'s batch as an INV4 call.
+fn dynamic_part(publish: &Publish, part: &PublishPart) -> Vec<(&'static str, Value)> {
+    let caller = || account_value(&publish.caller);
+    let assets = match part {
+        PublishPart::Remove(ids) => ids
+            .iter()
+            .map(|&id| Value::unnamed_composite([ipf_id_value(id), caller()]))
+            .collect::<Vec<_>>(),
+        PublishPart::Append(ids) => ids.iter().map(|&id| ipf_id_value(id)).collect(),
+    };
+
+    vec![
+        ("ips_id", Value::u128(publish.ips_id.0.into())),
+        ("original_caller", some(caller())),
+        ("assets", Value::unnamed_composite(assets)),
+        ("new_metadata", none()),
+    ]
+}
+
+fn account_value(account: &AccountId32) -> Value {
+    Value::unnamed_composite([Value::from_bytes(account)])
+}
+
+fn ipf_id_value(id: IpfId) -> Value {
+    Value::unnamed_variant("IpfId", [Value::u128(id.0.into())])
+}
+
+fn some(value: Value) -> Value {
+    Value::unnamed_variant("Some", [value])
+}
+
+fn none() -> Value {
+    Value::unnamed_variant("None", [])
+}
+
+/// The named field `name` of a decoded struct.
+fn field<'a>(value: &'a DecodedValue, name: &str) -> BoxResult<&'a DecodedValue> {
+    match &value.value {
+        ValueDef::Composite(Composite::Named(fields)) => fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("Field {} missing from {}", name, value).into()),
+        _ => error!(format!(
+            "Expected a struct with field {}, got {}",
+            name, value
+        )),
+    }
+}
+
+/// `value` with single-field wrappers like `BoundedVec` and `H256` looked through.
+fn unwrap_newtypes(mut value: &DecodedValue) -> &DecodedValue {
+    while let ValueDef::Composite(composite) = &value.value {
+        match composite.values().collect::<Vec<_>>()[..] {
+            [inner] if matches!(inner.value, ValueDef::Composite(_)) => value = inner,
+            _ => break,
+        }
+    }
+
+    value
+}
+
+/// The elements of a decoded sequence.
+fn items(value: &DecodedValue) -> BoxResult<Vec<&DecodedValue>> {
+    match &unwrap_newtypes(value).value {
+        ValueDef::Composite(composite) => Ok(composite.values().collect()),
+        _ => error!(format!("Expected a sequence, got {}", value)),
+    }
+}
+
+fn bytes(value: &DecodedValue) -> BoxResult<Vec<u8>> {
+    items(value)?
+        .into_iter()
+        .map(|byte| {
+            byte.as_u128()
+                .and_then(|byte| u8::try_from(byte).ok())
+                .ok_or_else(|| format!("Expected bytes, got {}", value).into())
+        })
+        .collect()
+}
+
+fn boolean(value: &DecodedValue) -> BoxResult<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("Expected a bool, got {}", value).into())
+}
+
+fn number(value: &DecodedValue) -> BoxResult<u128> {
+    let value = match &value.value {
+        ValueDef::Composite(composite) if composite.len() == 1 => {
+            composite.values().next().unwrap_or(value)
+        }
+        _ => value,
+    };
+
+    value
+        .as_u128()
+        .ok_or_else(|| format!("Expected a number, got {}", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_describe_the_runtimes_they_are_picked_for() {
+        for bundle in BUNDLES {
+            let (spec_name, _) = bundle.runtime_version().unwrap();
+            assert!(
+                bundle.spec_names.contains(&spec_name.as_str()),
+                "{} metadata is from {}",
+                bundle.name,
+                spec_name
+            );
+
+            let metadata = decode_metadata(bundle.metadata).unwrap();
+            assert!(mismatches(&metadata, &metadata).is_empty());
+        }
+    }
+
+    #[test]
+    fn garbage_is_not_metadata() {
+        assert!(decode_metadata(b"not metadata").is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! Pacing uploads to the object store, shared by every upload in the process.
+//!
+//! At most `upload_concurrency` uploads run at once. One failing because the node is busy is
+//! retried after a growing backoff. Repeated busy failures halve how many may run at once, and
+//! each run of successes lets one more back in, up to the limit again. Small IPFS daemons answer
+//! too many concurrent adds with 500s or repo lock errors rather than queueing them.
+
+use crate::{
+    output,
+    primitives::{BoxResult, Config},
+};
+use log::debug;
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::sleep};
+
+/// Attempts of a single upload before its busy error is given up on.
+const MAX_ATTEMPTS: u32 = 6;
+
+/// Backoff before the first retry, doubled for each further one.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Busy failures in a row that halve the concurrency.
+const FAILURES_BEFORE_BACKING_OFF: usize = 3;
+
+/// Successes in a row that let one more upload run at once.
+const SUCCESSES_BEFORE_RAISING: usize = 8;
+
+static SHARED: Mutex<Option<Arc<UploadScheduler>>> = Mutex::new(None);
+
+/// The scheduler all uploads of this process share, created with `config`'s limit by the first
+/// store asking for it.
+pub fn shared(config: &Config) -> Arc<UploadScheduler> {
+    SHARED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(|| Arc::new(UploadScheduler::new(config.upload_concurrency)))
+        .clone()
+}
+
+/// Whether `e` means the node is overloaded rather than the upload itself being wrong.
+pub fn is_busy(e: &dyn Error) -> bool {
+    let msg = e.to_string().to_lowercase();
+
+    [
+        "500",
+        "502",
+        "503",
+        "429",
+        "too many",
+        "busy",
+        "has the lock",
+        "connection reset",
+        "connection refused",
+        "broken pipe",
+        "timed out",
+    ]
+    .iter()
+    .any(|pattern| msg.contains(pattern))
+}
+
+pub struct UploadScheduler {
+    /// The most uploads that may ever run at once
+    limit: usize,
+    state: Mutex<State>,
+    /// Woken when a slot frees up or more are allowed
+    slots: Notify,
+}
+
+struct State {
+    running: usize,
+    /// How many may run at once right now
+    allowed: usize,
+    failures_in_a_row: usize,
+    successes_in_a_row: usize,
+    stats: SchedulerStats,
+}
+
+/// What the scheduler went through, for the summary at the end.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub limit: usize,
+    /// How many were allowed to run at once when backed off the furthest
+    pub lowest: usize,
+    /// Uploads retried after a busy error
+    pub retries: usize,
+    /// Uploads given up on after `MAX_ATTEMPTS`
+    pub gave_up: usize,
+}
+
+impl fmt::Display for SchedulerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} uploads retried after the store was busy, {} given up on; concurrency went down to {} of {}",
+            self.retries, self.gave_up, self.lowest, self.limit
+        )
+    }
+}
+
+/// A running upload's slot, freed when dropped.
+struct Slot<'a>(&'a UploadScheduler);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.lock().running -= 1;
+        self.0.slots.notify_one();
+    }
+}
+
+impl UploadScheduler {
+    /// A scheduler running at most `limit` uploads at once; 0 counts as 1.
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+
+        Self {
+            limit,
+            state: Mutex::new(State {
+                running: 0,
+                allowed: limit,
+                failures_in_a_row: 0,
+                successes_in_a_row: 0,
+                stats: SchedulerStats {
+                    limit,
+                    lowest: limit,
+                    ..Default::default()
+                },
+            }),
+            slots: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// How many uploads may run at once right now.
+    pub fn concurrency(&self) -> usize {
+        self.lock().allowed
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        self.lock().stats
+    }
+
+    async fn acquire(&self) -> Slot<'_> {
+        loop {
+            {
+                let mut state = self.lock();
+                if state.running < state.allowed {
+                    state.running += 1;
+                    return Slot(self);
+                }
+            }
+
+            self.slots.notified().await;
+        }
+    }
+
+    /// Run `operation` in a slot, retrying it after a backoff while it fails because the store is
+    /// busy. Other errors are returned right away.
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> BoxResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = BoxResult<T>>,
+    {
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let result = {
+                let _slot = self.acquire().await;
+                operation().await
+            };
+
+            let e = match result {
+                Ok(value) => {
+                    self.succeeded();
+                    return Ok(value);
+                }
+                Err(e) if is_busy(e.as_ref()) => e,
+                Err(e) => return Err(e),
+            };
+
+            self.failed_busy();
+
+            if attempt >= MAX_ATTEMPTS {
+                self.lock().stats.gave_up += 1;
+                return Err(e);
+            }
+
+            debug!(
+                "Upload failed with the store busy ({}), retrying in {:?}",
+                e, backoff
+            );
+            self.lock().stats.retries += 1;
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.lock();
+        state.failures_in_a_row = 0;
+        state.successes_in_a_row += 1;
+
+        if state.successes_in_a_row >= SUCCESSES_BEFORE_RAISING && state.allowed < self.limit {
+            state.allowed += 1;
+            state.successes_in_a_row = 0;
+            debug!("Allowing {} uploads at once again", state.allowed);
+            drop(state);
+            self.slots.notify_one();
+        }
+    }
+
+    fn failed_busy(&self) {
+        let mut state = self.lock();
+        state.successes_in_a_row = 0;
+        state.failures_in_a_row += 1;
+
+        if state.failures_in_a_row >= FAILURES_BEFORE_BACKING_OFF && state.allowed > 1 {
+            state.allowed /= 2;
+            state.failures_in_a_row = 0;
+            state.stats.lowest = state.stats.lowest.min(state.allowed);
+            debug!("Store is busy, allowing {} uploads at once", state.allowed);
+        }
+    }
+
+    /// Say how uploads were held back, if they were.
+    pub fn report(&self) {
+        let stats = self.stats();
+
+        if stats.retries > 0 || stats.gave_up > 0 {
+            output::info(stats);
+        } else {
+            debug!("Uploads: {}", stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn busy(message: &str) -> bool {
+        is_busy(Box::<dyn Error>::from(message).as_ref())
+    }
+
+    #[test]
+    fn overload_errors_are_busy() {
+        assert!(busy("HTTP 503 Service Unavailable"));
+        assert!(busy("someone else has the lock"));
+        assert!(busy("Connection reset by peer"));
+        assert!(!busy("invalid multihash"));
+    }
+
+    #[test]
+    fn busy_runs_halve_concurrency_and_successes_raise_it_again() {
+        let scheduler = UploadScheduler::new(8);
+
+        for _ in 0..FAILURES_BEFORE_BACKING_OFF * 2 {
+            scheduler.failed_busy();
+        }
+        assert_eq!(scheduler.concurrency(), 2);
+
+        for _ in 0..SUCCESSES_BEFORE_RAISING {
+            scheduler.succeeded();
+        }
+        assert_eq!(scheduler.concurrency(), 3);
+
+        for _ in 0..FAILURES_BEFORE_BACKING_OFF * 4 {
+            scheduler.failed_busy();
+        }
+        assert_eq!(scheduler.concurrency(), 1);
+        assert_eq!(scheduler.stats().lowest, 1);
+        assert_eq!(UploadScheduler::new(0).concurrency(), 1);
+    }
+
+    #[tokio::test]
+    async fn busy_uploads_are_retried_and_others_fail_at_once() {
+        let scheduler = UploadScheduler::new(2);
+        let attempts = Cell::new(0);
+
+        let result = scheduler
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    match attempt {
+                        1 => Err("503 Service Unavailable".into()),
+                        _ => Ok(attempt),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(scheduler.stats().retries, 1);
+
+        attempts.set(0);
+        let result: BoxResult<()> = scheduler
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                async { Err("invalid multihash".into()) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_more_than_the_allowed_uploads_run_at_once() {
+        let scheduler = UploadScheduler::new(2);
+        let (running, most) = (&Cell::new(0), &Cell::new(0));
+
+        let upload = move || async move {
+            running.set(running.get() + 1);
+            most.set(most.get().max(running.get()));
+            tokio::task::yield_now().await;
+            running.set(running.get() - 1);
+            Ok(())
+        };
+
+        let results = futures::future::join_all((0..5).map(|_| scheduler.run(upload))).await;
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(most.get(), 2);
+    }
+}
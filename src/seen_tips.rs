@@ -0,0 +1,86 @@
+//! The tips of a remote's refs as last fetched or pushed, kept to notice when someone rewrote
+//! the remote's history since.
+
+use crate::primitives::BoxResult;
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub struct SeenTips {
+    path: PathBuf,
+    /// A {name -> sha1} map
+    pub tips: BTreeMap<String, String>,
+}
+
+impl SeenTips {
+    /// The tips kept in a remote's `state_dir`, none if they can't be read.
+    pub fn load(state_dir: &Path) -> Self {
+        let path = state_dir.join("seen-tips");
+
+        let tips = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(git_hash, name)| (name.trim().to_owned(), git_hash.to_owned()))
+                .collect(),
+            Err(e) => {
+                debug!("No seen tips in {}: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+
+        Self { path, tips }
+    }
+
+    /// Remember `name` at `git_hash`, or forget it when it was deleted.
+    pub fn set(&mut self, name: &str, git_hash: Option<&str>) {
+        match git_hash {
+            Some(git_hash) => self.tips.insert(name.to_owned(), git_hash.to_owned()),
+            None => self.tips.remove(name),
+        };
+    }
+
+    pub fn save(&self) -> BoxResult<()> {
+        fs::create_dir_all(self.path.parent().ok_or("Seen tips path has no parent")?)?;
+        fs::write(
+            &self.path,
+            self.tips
+                .iter()
+                .map(|(name, git_hash)| format!("{} {}\n", git_hash, name))
+                .collect::<String>(),
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn tips_are_kept_across_loads() {
+        let dir = TempDir::new().unwrap();
+        let state_dir = dir.path().join("origin");
+        assert!(SeenTips::load(&state_dir).tips.is_empty());
+
+        let mut seen = SeenTips::load(&state_dir);
+        seen.set("refs/heads/main", Some(&"a".repeat(40)));
+        seen.set("refs/heads/topic", Some(&"b".repeat(40)));
+        seen.save().unwrap();
+
+        let mut seen = SeenTips::load(&state_dir);
+        assert_eq!(seen.tips["refs/heads/main"], "a".repeat(40));
+        seen.set("refs/heads/topic", None);
+        seen.save().unwrap();
+
+        assert_eq!(
+            SeenTips::load(&state_dir).tips,
+            BTreeMap::from([("refs/heads/main".to_owned(), "a".repeat(40))])
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! `inv4-git serve-http`: a repository served read-only over git's dumb HTTP protocol, for
+//! clients that can't run the helper, e.g. build containers with an old git.
+//!
+//! The repository is fetched into a bare mirror in the IPS's cache directory, with the fetch and
+//! object cache a clone uses, after which `git update-server-info` writes the files dumb clients
+//! start from. They then download the objects as static files. Only `HEAD`, `info/` and
+//! `objects/` are served.
+//!
+//! The mirror is refreshed from the chain every `--refresh` seconds, and before a client starts
+//! a clone or fetch when the last refresh is older than `--max-staleness`. A client is refused
+//! rather than served a mirror older than that. Pushing is refused with a pointer to the helper.
+
+use crate::{
+    cache,
+    chain::Chain,
+    error, output,
+    primitives::{BoxResult, Config, IpsId, MultiObjectIndex, RepoData},
+    store::{default_store, ObjectStore},
+};
+use git2::{Oid, Repository};
+use hyper::{
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use log::debug;
+use std::{
+    convert::Infallible,
+    fs,
+    future::Future,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    process::Command,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpListener,
+    sync::Mutex,
+    task::{self, LocalSet},
+    time,
+};
+
+/// Where to listen unless `--listen` says otherwise.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8417";
+
+/// How often the mirror is refreshed unless `--refresh` says otherwise.
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// How old a mirror clients are served unless `--max-staleness` says otherwise.
+pub const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(60);
+
+/// Runs what hyper spawns on the `LocalSet`, as the mirror isn't `Send`.
+#[derive(Clone, Copy)]
+struct LocalExec;
+
+impl<F: Future + 'static> hyper::rt::Executor<F> for LocalExec {
+    fn execute(&self, future: F) {
+        task::spawn_local(future);
+    }
+}
+
+/// Serve IPS `ips_id` on `listen` until killed.
+pub async fn run(
+    ips_id: IpsId,
+    listen: SocketAddr,
+    refresh: Duration,
+    max_staleness: Duration,
+) -> BoxResult<()> {
+    let config = Config::load()?;
+    let store = default_store(&config).await?;
+    let chain = Chain::connect(&config).await?;
+
+    let dir = cache::http_mirror_dir(&chain.genesis_hash().await, ips_id)?;
+    if Repository::open_bare(&dir).is_err() {
+        Repository::init_bare(&dir)?;
+    }
+
+    let mirror = Rc::new(Mirror {
+        ips_id,
+        config,
+        chain,
+        store,
+        dir,
+        refreshed: Mutex::new(Instant::now()),
+    });
+
+    output::status(format!("Mirroring IPS {}", ips_id));
+    mirror.refresh().await?;
+    output::clear_status();
+
+    let listener = TcpListener::bind(listen).await?;
+    output::info(format!(
+        "Serving IPS {} read-only on http://{}/",
+        ips_id,
+        listener.local_addr()?
+    ));
+
+    LocalSet::new()
+        .run_until(accept(listener, mirror, refresh, max_staleness))
+        .await
+}
+
+async fn accept(
+    listener: TcpListener,
+    mirror: Rc<Mirror>,
+    refresh: Duration,
+    max_staleness: Duration,
+) -> BoxResult<()> {
+    task::spawn_local(refresh_every(mirror.clone(), refresh));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let mirror = mirror.clone();
+
+        task::spawn_local(async move {
+            let service = service_fn(move |request| {
+                let mirror = mirror.clone();
+                async move { Ok::<_, Infallible>(mirror.respond(request, max_staleness).await) }
+            });
+
+            if let Err(e) = Http::new()
+                .http1_only(true)
+                .with_executor(LocalExec)
+                .serve_connection(stream, service)
+                .await
+            {
+                debug!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Refresh `mirror` every `period`, unless a client had it refreshed in the meantime.
+async fn refresh_every(mirror: Rc<Mirror>, period: Duration) {
+    loop {
+        time::sleep(period).await;
+
+        if let Err(e) = mirror.refresh_within(period / 2).await {
+            output::warn(format!("Refreshing the mirror failed: {}", e));
+        }
+    }
+}
+
+struct Mirror {
+    ips_id: IpsId,
+    config: Config,
+    chain: Chain,
+    store: Box<dyn ObjectStore>,
+    /// The bare repository served
+    dir: PathBuf,
+    /// When the mirror was last refreshed, locked while it is being refreshed
+    refreshed: Mutex<Instant>,
+}
+
+impl Mirror {
+    /// Refresh the mirror unless that was done within `max_age`.
+    async fn refresh_within(&self, max_age: Duration) -> BoxResult<()> {
+        let mut refreshed = self.refreshed.lock().await;
+        if refreshed.elapsed() <= max_age {
+            return Ok(());
+        }
+
+        self.refresh().await?;
+        *refreshed = Instant::now();
+        Ok(())
+    }
+
+    /// Fetch what the repository has now, and make the refs and server info match it.
+    async fn refresh(&self) -> BoxResult<()> {
+        let repo_data = RepoData::chain_get(
+            self.ips_id,
+            self.store.as_ref(),
+            &self.chain,
+            self.config.repodata_lookback_blocks,
+            None,
+        )
+        .await?;
+        let index = MultiObjectIndex::Chain {
+            chain: &self.chain,
+            ips_id: self.ips_id,
+            at: None,
+        };
+
+        let mut repo = Repository::open_bare(&self.dir)?;
+        self.store.connect_hints(&repo_data.swarm_hints).await;
+
+        // Objects the mirror has already are skipped, so this only downloads what's new
+        for (name, git_hash) in &repo_data.refs {
+            repo_data
+                .fetch_to_ref_from_str(
+                    git_hash,
+                    name,
+                    &mut repo,
+                    self.store.as_ref(),
+                    &index,
+                    None,
+                    None,
+                    None,
+                    &self.config,
+                )
+                .await?;
+            repo.reference(name, Oid::from_str(git_hash)?, true, "inv4-git serve-http")?;
+        }
+
+        let deleted: Vec<_> = repo
+            .references()?
+            .filter_map(|reference| Some(reference.ok()?.name()?.to_owned()))
+            .filter(|name| {
+                !repo_data.refs.contains_key(name) && !repo_data.symrefs.contains_key(name)
+            })
+            .collect();
+        for name in deleted {
+            repo.find_reference(&name)?.delete()?;
+        }
+
+        for (name, target) in &repo_data.symrefs {
+            if name != "HEAD" && repo_data.refs.contains_key(target) {
+                repo.reference_symbolic(name, target, true, "inv4-git serve-http")?;
+            }
+        }
+        if let Some(head) = repo_data.default_branch() {
+            repo.set_head(head)?;
+        }
+
+        update_server_info(&self.dir)?;
+        debug!(
+            "Mirror of IPS {} refreshed, {} refs",
+            self.ips_id,
+            repo_data.refs.len()
+        );
+
+        Ok(())
+    }
+
+    async fn respond(&self, request: Request<Body>, max_staleness: Duration) -> Response<Body> {
+        let path = request.uri().path().trim_start_matches('/');
+
+        if writes(&request) {
+            return reply(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "This mirror is read-only; push with the helper instead, to inv4://{}\n",
+                    self.ips_id
+                ),
+            );
+        }
+
+        // Every clone and fetch starts by listing the refs
+        if path == "info/refs" {
+            if let Err(e) = self.refresh_within(max_staleness).await {
+                output::warn(format!("Refreshing the mirror failed: {}", e));
+                return reply(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("The mirror could not be refreshed: {}\n", e),
+                );
+            }
+        }
+
+        let contents = match served(path).and_then(|path| fs::read(self.dir.join(path)).ok()) {
+            Some(contents) => contents,
+            None => return reply(StatusCode::NOT_FOUND, "Not found\n".to_owned()),
+        };
+
+        let (content_type, cache_control) = file_type(path);
+        let mut response = Response::new(match *request.method() {
+            Method::HEAD => Body::empty(),
+            _ => Body::from(contents),
+        });
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+
+        response
+    }
+}
+
+/// Whether `request` is part of a push, which the mirror refuses.
+fn writes(request: &Request<Body>) -> bool {
+    let pushing = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .contains("git-receive-pack")
+        || request.uri().path().ends_with("git-receive-pack");
+
+    // Dumb HTTP pushes go through WebDAV, with methods of their own
+    pushing || !matches!(*request.method(), Method::GET | Method::HEAD)
+}
+
+/// Have git write the files listing refs and packs that dumb clients read.
+fn update_server_info(dir: &Path) -> BoxResult<()> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(dir)
+        .arg("update-server-info")
+        .output()
+        .map_err(|e| format!("Could not run git update-server-info: {}", e))?;
+
+    if !output.status.success() {
+        error!(format!(
+            "git update-server-info failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+
+    Ok(())
+}
+
+/// `path` in the mirror, when it is one clients may read.
+fn served(path: &str) -> Option<&Path> {
+    let served = path == "HEAD" || path.starts_with("info/") || path.starts_with("objects/");
+    let path = Path::new(path);
+
+    (served && path.components().all(|c| matches!(c, Component::Normal(_)))).then(|| path)
+}
+
+/// The content type of the file at `path` and how long clients may cache it, as
+/// `git http-backend` serves them.
+fn file_type(path: &str) -> (&'static str, &'static str) {
+    const NEVER: &str = "no-cache, max-age=0, must-revalidate";
+    const FOREVER: &str = "public, max-age=31536000";
+
+    if path == "HEAD" || path.starts_with("info/") || path.starts_with("objects/info/") {
+        ("text/plain", NEVER)
+    } else if path.ends_with(".pack") {
+        ("application/x-git-packed-objects", FOREVER)
+    } else if path.ends_with(".idx") {
+        ("application/x-git-packed-objects-toc", FOREVER)
+    } else {
+        ("application/x-git-loose-object", FOREVER)
+    }
+}
+
+fn reply(status: StatusCode, message: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::commit;
+    use temp_dir::TempDir;
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn pushes_are_refused() {
+        assert!(!writes(&request(Method::GET, "/info/refs")));
+        assert!(!writes(&request(Method::HEAD, "/objects/info/packs")));
+        assert!(writes(&request(
+            Method::GET,
+            "/info/refs?service=git-receive-pack"
+        )));
+        assert!(writes(&request(Method::POST, "/git-receive-pack")));
+        assert!(writes(&request(Method::PUT, "/objects/ab/cdef")));
+        assert!(writes(&request(
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            "/"
+        )));
+    }
+
+    #[test]
+    fn only_what_dumb_clients_read_is_served() {
+        for path in ["HEAD", "info/refs", "objects/info/packs", "objects/ab/cdef"] {
+            assert_eq!(served(path), Some(Path::new(path)), "{}", path);
+        }
+        for path in [
+            "config",
+            "hooks/post-update",
+            "info/../config",
+            "objects/../config",
+            "/etc/passwd",
+            "",
+        ] {
+            assert_eq!(served(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn listings_are_never_cached_and_objects_forever() {
+        assert_eq!(file_type("info/refs").0, "text/plain");
+        assert!(file_type("objects/info/packs").1.starts_with("no-cache"));
+        assert_eq!(
+            file_type("objects/pack/pack-1.pack"),
+            (
+                "application/x-git-packed-objects",
+                "public, max-age=31536000"
+            )
+        );
+        assert_eq!(
+            file_type("objects/pack/pack-1.idx").0,
+            "application/x-git-packed-objects-toc"
+        );
+        assert_eq!(
+            file_type("objects/ab/cdef").0,
+            "application/x-git-loose-object"
+        );
+    }
+
+    #[test]
+    fn server_info_lists_the_refs() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let tip = commit(&repo, Some("refs/heads/main"), &[], "tip");
+
+        update_server_info(dir.path()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("info/refs")).unwrap(),
+            format!("{}\trefs/heads/main\n", tip)
+        );
+        assert!(update_server_info(&dir.child("missing")).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Cleanup that has to happen however the helper exits: by returning, on Ctrl-C or on a panic.
+//!
+//! Anything that leaves state behind registers a closure dealing with it. Dropping the returned
+//! `Cleanup` runs the closure, dismissing it forgets the closure. A `SessionGuard` held by `main`
+//! runs whatever is still registered when the process is interrupted or panics, where
+//! destructors don't get to run.
+//!
+//! While a `Cancellable` operation runs, the first Ctrl-C only asks it to stop at the next point
+//! where what it wrote so far is consistent, and it fails with `Cancelled`. A second one exits at
+//! once as usual.
+
+use crate::{
+    events::{self, Event},
+    output,
+};
+use log::debug;
+use std::{
+    fmt, panic, process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+type Closure = Box<dyn FnOnce() + Send>;
+
+static CLEANUPS: Mutex<Vec<(u64, Closure)>> = Mutex::new(vec![]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How many `Cancellable` operations are running.
+static CANCELLABLE: AtomicU64 = AtomicU64::new(0);
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Exit status of a process killed by SIGINT, as shells report it.
+const INTERRUPTED_STATUS: i32 = 130;
+
+/// A registered cleanup, run when this is dropped unless it was dismissed.
+#[must_use]
+pub struct Cleanup(u64);
+
+/// Run `closure` when the returned `Cleanup` is dropped, or when the process is interrupted or
+/// panics before that.
+pub fn register(closure: impl FnOnce() + Send + 'static) -> Cleanup {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CLEANUPS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((id, Box::new(closure)));
+
+    Cleanup(id)
+}
+
+impl Cleanup {
+    /// Forget the cleanup without running it, once the state it undoes was dealt with.
+    pub fn dismiss(self) {
+        take(self.0);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Some(closure) = take(self.0) {
+            closure();
+        }
+    }
+}
+
+fn take(id: u64) -> Option<Closure> {
+    let mut cleanups = CLEANUPS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = cleanups.iter().position(|(other, _)| *other == id)?;
+
+    Some(cleanups.remove(index).1)
+}
+
+/// An operation that stops cleanly on Ctrl-C, for as long as this is held.
+#[must_use]
+pub struct Cancellable(());
+
+/// Make Ctrl-C ask the running operation to stop rather than exit, until the returned
+/// `Cancellable` is dropped. The operation polls `cancelled`.
+pub fn cancellable() -> Cancellable {
+    CANCELLED.store(false, Ordering::SeqCst);
+    CANCELLABLE.fetch_add(1, Ordering::SeqCst);
+
+    Cancellable(())
+}
+
+impl Drop for Cancellable {
+    fn drop(&mut self) {
+        CANCELLABLE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether Ctrl-C asked the running `Cancellable` operation to stop.
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// The error of a fetch stopped by Ctrl-C, with how far it got. Everything written so far is
+/// kept, so fetching again picks up from there.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cancelled {
+    pub multi_objects_downloaded: usize,
+    pub multi_objects: usize,
+    pub objects_written: usize,
+    pub objects: usize,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Cancelled after downloading {} of {} MultiObjects and writing {} of {} objects; fetch again to resume",
+            self.multi_objects_downloaded, self.multi_objects, self.objects_written, self.objects
+        )
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Run every registered cleanup, latest first.
+fn run_all() {
+    // A panic while the registry was locked must not turn into a deadlock here
+    let cleanups = match CLEANUPS.try_lock() {
+        Ok(mut cleanups) => std::mem::take(&mut *cleanups),
+        Err(e) => {
+            debug!("Skipping cleanups: {}", e);
+            return;
+        }
+    };
+
+    for (_, closure) in cleanups.into_iter().rev() {
+        closure();
+    }
+}
+
+/// Whether a Ctrl-C only asks the running `Cancellable` operation to stop, per how many are
+/// `running` and whether it was `cancelled` already, marking it so. Otherwise the process exits.
+fn asks_to_stop(running: &AtomicU64, cancelled: &AtomicBool) -> bool {
+    running.load(Ordering::SeqCst) > 0 && !cancelled.swap(true, Ordering::SeqCst)
+}
+
+/// Runs registered cleanups on Ctrl-C and on panics, and on drop for anything left over.
+pub struct SessionGuard;
+
+impl SessionGuard {
+    pub fn install() -> Self {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            run_all();
+            previous(info);
+        }));
+
+        tokio::spawn(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if asks_to_stop(&CANCELLABLE, &CANCELLED) {
+                    output::warn("Stopping once what was written so far is consistent; press Ctrl-C again to quit at once");
+                    continue;
+                }
+
+                run_all();
+                events::emit(Event::Interrupted);
+                process::exit(INTERRUPTED_STATUS);
+            }
+        });
+
+        Self
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        run_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn cleanups_run_once_unless_dismissed() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        let cleanup = |name: &'static str| {
+            let ran = ran.clone();
+            register(move || ran.lock().unwrap().push(name))
+        };
+
+        drop(cleanup("dropped"));
+        cleanup("dismissed").dismiss();
+
+        let first = cleanup("first");
+        let second = cleanup("second");
+        run_all();
+        drop((first, second));
+
+        assert_eq!(*ran.lock().unwrap(), vec!["dropped", "second", "first"]);
+    }
+
+    #[test]
+    fn only_the_first_interrupt_of_a_cancellable_operation_is_a_request() {
+        let (running, cancelled) = (AtomicU64::new(0), AtomicBool::new(false));
+        assert!(!asks_to_stop(&running, &cancelled));
+
+        running.store(1, Ordering::SeqCst);
+        assert!(asks_to_stop(&running, &cancelled));
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert!(!asks_to_stop(&running, &cancelled));
+    }
+
+    #[test]
+    fn cancelled_fetches_say_how_far_they_got() {
+        let e: Box<dyn std::error::Error> = Box::new(Cancelled {
+            multi_objects_downloaded: 2,
+            multi_objects: 5,
+            objects_written: 40,
+            objects: 100,
+        });
+
+        assert_eq!(
+            e.to_string(),
+            "Cancelled after downloading 2 of 5 MultiObjects and writing 40 of 100 objects; fetch again to resume"
+        );
+        assert_eq!(e.downcast_ref::<Cancelled>().unwrap().objects_written, 40);
+    }
+}
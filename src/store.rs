@@ -0,0 +1,619 @@
+use crate::{
+    object_cache::{ObjectCache, Source},
+    primitives::{BoxResult, Config, SwarmHint},
+    scheduler::{self, UploadScheduler},
+    throttle::{self, Bandwidth},
+};
+use async_trait::async_trait;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+use subxt::{ext::sp_core::sr25519::Pair, tx::PairSigner, PolkadotConfig};
+use tokio::time::Instant;
+
+#[cfg(not(feature = "crust"))]
+use crate::ipfs::{self, Daemon};
+#[cfg(not(feature = "crust"))]
+use crate::throttle::ThrottledReader;
+#[cfg(not(feature = "crust"))]
+use crate::{output, util::human_size};
+#[cfg(not(feature = "crust"))]
+use futures::{future::join_all, io::AllowStdIo, ready, AsyncRead, TryStreamExt};
+#[cfg(not(feature = "crust"))]
+use ipfs_api::{IpfsApi, IpfsClient};
+#[cfg(not(feature = "crust"))]
+use log::debug;
+#[cfg(not(feature = "crust"))]
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+#[cfg(not(feature = "crust"))]
+use tokio::time::timeout;
+
+/// UnixFS chunker for files added with `put_file`: 1MiB blocks, so huge blobs don't turn into
+/// millions of the default 256KiB blocks.
+#[cfg(not(feature = "crust"))]
+const FILE_CHUNKER: &str = "size-1048576";
+
+/// How long `block stat` may look for a block before it counts as gone. Blocks added through
+/// this node are answered for at once; anything slower is being searched for on the network.
+#[cfg(not(feature = "crust"))]
+const BLOCK_STAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connecting to one address of a swarm hint may take.
+#[cfg(not(feature = "crust"))]
+const SWARM_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the content behind on-chain IPFs lives.
+#[async_trait(?Send)]
+pub trait ObjectStore {
+    /// Store `data`, returning the CID it can be retrieved with.
+    async fn put(
+        &self,
+        data: Vec<u8>,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String>;
+
+    /// Retrieve the data stored under `cid`.
+    async fn get(&self, cid: &str) -> BoxResult<Vec<u8>>;
+
+    /// Retrieve at most `len` bytes of the data stored under `cid`, starting at `offset`.
+    async fn get_range(&self, cid: &str, offset: usize, len: usize) -> BoxResult<Vec<u8>> {
+        let data = self.get(cid).await?;
+        let start = offset.min(data.len());
+
+        Ok(data[start..data.len().min(start.saturating_add(len))].to_vec())
+    }
+
+    /// Store the file at `path`, streaming it where the store allows rather than reading it
+    /// into memory, and showing progress under `label`.
+    async fn put_file(
+        &self,
+        path: &Path,
+        _label: &str,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.put(fs::read(path)?, signer).await
+    }
+
+    /// Whether the data under `cid` is still held, so an interrupted upload needn't be repeated.
+    async fn has(&self, _cid: &str) -> BoxResult<bool> {
+        Ok(false)
+    }
+
+    /// Write the data stored under `cid` to `file` as it arrives, returning its size.
+    async fn get_to_file(&self, cid: &str, file: &mut File) -> BoxResult<u64> {
+        let data = self.get(cid).await?;
+        file.write_all(&data)?;
+
+        Ok(data.len() as u64)
+    }
+
+    /// Check that the store can be reached, so a dead node is reported up front.
+    async fn check(&self) -> BoxResult<()> {
+        Ok(())
+    }
+
+    /// How others can reach the node holding what this store uploads, `None` if they can't.
+    async fn swarm_hint(&self) -> BoxResult<Option<SwarmHint>> {
+        Ok(None)
+    }
+
+    /// Connect to the nodes of `hints`, so their content is found without searching the
+    /// network for it. Best-effort: failures are only logged.
+    async fn connect_hints(&self, _hints: &[SwarmHint]) {}
+}
+
+/// Objects stored through the API of an IPFS node.
+#[cfg(not(feature = "crust"))]
+pub struct IpfsStore {
+    client: IpfsClient,
+    /// Keeps a daemon spawned for this store running until the store is dropped
+    _daemon: Option<Daemon>,
+    bandwidth: Bandwidth,
+    uploads: Arc<UploadScheduler>,
+}
+
+#[cfg(not(feature = "crust"))]
+impl IpfsStore {
+    async fn add(&self, data: Vec<u8>) -> BoxResult<String> {
+        let limiter = match &self.bandwidth.upload {
+            Some(limiter) => limiter.clone(),
+            None => {
+                let options = ipfs::add_options(ipfs::DEFAULT_CHUNKER);
+                let response = self
+                    .client
+                    .add_with_options(std::io::Cursor::new(data), options)
+                    .await?;
+                return Ok(response.hash);
+            }
+        };
+
+        let started = Instant::now();
+        let len = data.len();
+        let reader = ThrottledReader::new(futures::io::Cursor::new(data), limiter);
+        let hash = self
+            .client
+            .add_async_with_options(reader, ipfs::add_options(ipfs::DEFAULT_CHUNKER))
+            .await?
+            .hash;
+        throttle::report("Uploaded", len, started);
+
+        Ok(hash)
+    }
+
+    async fn add_file(&self, path: &Path, label: &str) -> BoxResult<String> {
+        let file = File::open(path)?;
+        let reader = ProgressReader::new(AllowStdIo::new(file), label, fs::metadata(path)?.len());
+        let options = || ipfs::add_options(FILE_CHUNKER);
+
+        // The multipart body is sent with chunked transfer encoding as the file is read
+        let response = match &self.bandwidth.upload {
+            Some(limiter) => {
+                let reader = ThrottledReader::new(reader, limiter.clone());
+                self.client
+                    .add_async_with_options(reader, options())
+                    .await?
+            }
+            None => {
+                self.client
+                    .add_async_with_options(reader, options())
+                    .await?
+            }
+        };
+
+        Ok(response.hash)
+    }
+}
+
+#[cfg(not(feature = "crust"))]
+impl Drop for IpfsStore {
+    fn drop(&mut self) {
+        self.uploads.report();
+    }
+}
+
+#[cfg(not(feature = "crust"))]
+#[async_trait(?Send)]
+impl ObjectStore for IpfsStore {
+    async fn put(
+        &self,
+        data: Vec<u8>,
+        _signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.uploads.run(|| self.add(data.clone())).await
+    }
+
+    async fn get(&self, cid: &str) -> BoxResult<Vec<u8>> {
+        let limiter = match &self.bandwidth.download {
+            Some(limiter) => limiter,
+            None => {
+                return Ok(self
+                    .client
+                    .cat(cid)
+                    .map_ok(|c| c.to_vec())
+                    .try_concat()
+                    .await?)
+            }
+        };
+
+        let started = Instant::now();
+        let mut chunks = self.client.cat(cid);
+        let mut data = vec![];
+
+        while let Some(chunk) = chunks.try_next().await? {
+            limiter.acquire(chunk.len()).await;
+            data.extend_from_slice(&chunk);
+        }
+        throttle::report("Downloaded", data.len(), started);
+
+        Ok(data)
+    }
+
+    async fn get_range(&self, cid: &str, offset: usize, len: usize) -> BoxResult<Vec<u8>> {
+        let mut chunks = self.client.cat_range(cid, offset, len);
+        let mut data = vec![];
+
+        while let Some(chunk) = chunks.try_next().await? {
+            if let Some(limiter) = &self.bandwidth.download {
+                limiter.acquire(chunk.len()).await;
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        Ok(data)
+    }
+
+    async fn put_file(
+        &self,
+        path: &Path,
+        label: &str,
+        _signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.uploads.run(|| self.add_file(path, label)).await
+    }
+
+    async fn has(&self, cid: &str) -> BoxResult<bool> {
+        Ok(matches!(
+            timeout(BLOCK_STAT_TIMEOUT, self.client.block_stat(cid)).await,
+            Ok(Ok(_))
+        ))
+    }
+
+    async fn swarm_hint(&self) -> BoxResult<Option<SwarmHint>> {
+        let id = self.client.id(None).await?;
+        let addresses: Vec<_> = id
+            .addresses
+            .into_iter()
+            .filter(|address| ipfs::is_public_address(address))
+            .map(|address| match address.contains("/p2p/") {
+                true => address,
+                false => format!("{}/p2p/{}", address, id.id),
+            })
+            .collect();
+
+        Ok((!addresses.is_empty()).then(|| SwarmHint {
+            peer_id: id.id,
+            addresses,
+        }))
+    }
+
+    async fn connect_hints(&self, hints: &[SwarmHint]) {
+        let own_id = self.client.id(None).await.ok().map(|id| id.id);
+
+        // One address per node is enough, but the nodes are tried side by side
+        let connects = hints
+            .iter()
+            .filter(|hint| Some(&hint.peer_id) != own_id.as_ref())
+            .map(|hint| async move {
+                for address in &hint.addresses {
+                    match timeout(SWARM_CONNECT_TIMEOUT, self.client.swarm_connect(address)).await {
+                        Ok(Ok(_)) => return debug!("Connected to {}", address),
+                        Ok(Err(e)) => debug!("Could not connect to {}: {}", address, e),
+                        Err(_) => debug!("Timed out connecting to {}", address),
+                    }
+                }
+            });
+
+        join_all(connects).await;
+    }
+
+    async fn get_to_file(&self, cid: &str, file: &mut File) -> BoxResult<u64> {
+        let started = Instant::now();
+        let mut chunks = self.client.cat(cid);
+        let mut size = 0;
+
+        while let Some(chunk) = chunks.try_next().await? {
+            if let Some(limiter) = &self.bandwidth.download {
+                limiter.acquire(chunk.len()).await;
+            }
+            file.write_all(&chunk)?;
+            size += chunk.len() as u64;
+        }
+
+        if self.bandwidth.download.is_some() {
+            throttle::report("Downloaded", size as usize, started);
+        }
+
+        Ok(size)
+    }
+
+    async fn check(&self) -> BoxResult<()> {
+        let version = self.client.version().await?;
+        ipfs::check_compatibility(&self.client, &version.version).await;
+
+        Ok(())
+    }
+}
+
+/// Shows how much of an upload `inner` has been read for, once per percent.
+#[cfg(not(feature = "crust"))]
+struct ProgressReader<R> {
+    inner: R,
+    label: String,
+    read: u64,
+    total: u64,
+}
+
+#[cfg(not(feature = "crust"))]
+impl<R> ProgressReader<R> {
+    fn new(inner: R, label: &str, total: u64) -> Self {
+        Self {
+            inner,
+            label: label.to_owned(),
+            read: 0,
+            total,
+        }
+    }
+}
+
+#[cfg(not(feature = "crust"))]
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        let percent_before = self.read * 100 / self.total.max(1);
+        self.read += read as u64;
+        let percent = self.read * 100 / self.total.max(1);
+
+        if percent != percent_before {
+            output::status(format!(
+                "{}: {}% ({}/{})",
+                self.label,
+                percent,
+                human_size(self.read as usize),
+                human_size(self.total as usize)
+            ));
+        }
+
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// Objects pinned through Crust and read back from its gateway.
+#[cfg(feature = "crust")]
+pub struct CrustStore {
+    bandwidth: Bandwidth,
+    uploads: Arc<UploadScheduler>,
+}
+
+#[cfg(feature = "crust")]
+impl Drop for CrustStore {
+    fn drop(&mut self) {
+        self.uploads.report();
+    }
+}
+
+#[cfg(feature = "crust")]
+#[async_trait(?Send)]
+impl ObjectStore for CrustStore {
+    async fn put(
+        &self,
+        data: Vec<u8>,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.uploads
+            .run(|| async {
+                // The upload is a single request body, so it can only be paced as a whole
+                if let Some(limiter) = &self.bandwidth.upload {
+                    limiter.acquire(data.len()).await;
+                }
+
+                crate::crust::send_to_crust(signer, data.clone()).await
+            })
+            .await
+    }
+
+    async fn get(&self, cid: &str) -> BoxResult<Vec<u8>> {
+        let started = Instant::now();
+        let limiter = self.bandwidth.download.as_deref();
+        let data = crate::crust::get_from_crust(cid.to_string(), limiter).await?;
+
+        if limiter.is_some() {
+            throttle::report("Downloaded", data.len(), started);
+        }
+
+        Ok(data)
+    }
+
+    async fn get_to_file(&self, cid: &str, file: &mut File) -> BoxResult<u64> {
+        let started = Instant::now();
+        let limiter = self.bandwidth.download.as_deref();
+        let mut size = 0;
+
+        crate::crust::stream_from_crust(cid, limiter, |chunk| {
+            file.write_all(chunk)?;
+            size += chunk.len() as u64;
+            Ok(())
+        })
+        .await?;
+
+        if limiter.is_some() {
+            throttle::report("Downloaded", size as usize, started);
+        }
+
+        Ok(size)
+    }
+}
+
+/// A store whose downloads are kept in an `ObjectCache`, and read from it while they last.
+struct CachedStore {
+    inner: Box<dyn ObjectStore>,
+    cache: ObjectCache,
+    /// Where `inner` fetches from, which makes its entries cheaper or dearer to evict
+    source: Source,
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for CachedStore {
+    async fn put(
+        &self,
+        data: Vec<u8>,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.inner.put(data, signer).await
+    }
+
+    async fn get(&self, cid: &str) -> BoxResult<Vec<u8>> {
+        if let Some(data) = self.cache.get(cid) {
+            return Ok(data);
+        }
+
+        let started = Instant::now();
+        let data = self.inner.get(cid).await?;
+        self.cache.put(cid, &data, started.elapsed(), self.source);
+
+        Ok(data)
+    }
+
+    async fn get_range(&self, cid: &str, offset: usize, len: usize) -> BoxResult<Vec<u8>> {
+        match self.cache.get(cid) {
+            Some(data) => {
+                let start = offset.min(data.len());
+                Ok(data[start..data.len().min(start.saturating_add(len))].to_vec())
+            }
+            None => self.inner.get_range(cid, offset, len).await,
+        }
+    }
+
+    async fn put_file(
+        &self,
+        path: &Path,
+        label: &str,
+        signer: &PairSigner<PolkadotConfig, Pair>,
+    ) -> BoxResult<String> {
+        self.inner.put_file(path, label, signer).await
+    }
+
+    async fn has(&self, cid: &str) -> BoxResult<bool> {
+        self.inner.has(cid).await
+    }
+
+    // Large blobs are streamed to avoid holding them in memory, and not cached
+    async fn get_to_file(&self, cid: &str, file: &mut File) -> BoxResult<u64> {
+        self.inner.get_to_file(cid, file).await
+    }
+
+    async fn check(&self) -> BoxResult<()> {
+        self.inner.check().await
+    }
+
+    async fn swarm_hint(&self) -> BoxResult<Option<SwarmHint>> {
+        self.inner.swarm_hint().await
+    }
+
+    async fn connect_hints(&self, hints: &[SwarmHint]) {
+        self.inner.connect_hints(hints).await
+    }
+}
+
+/// The store selected by the enabled build features, behind the object cache unless
+/// `cache_max_size` is 0.
+pub async fn default_store(config: &Config) -> BoxResult<Box<dyn ObjectStore>> {
+    #[cfg(not(feature = "crust"))]
+    let (store, source): (Box<dyn ObjectStore>, _) = {
+        let (client, daemon) = ipfs::connect(config).await?;
+        let source = match &config.ipfs_endpoint {
+            Some(endpoint) if !throttle::is_local_endpoint(endpoint) => Source::Gateway,
+            _ => Source::Local,
+        };
+
+        (
+            Box::new(IpfsStore {
+                client,
+                _daemon: daemon,
+                bandwidth: Bandwidth::from_config(config),
+                uploads: scheduler::shared(config),
+            }),
+            source,
+        )
+    };
+
+    #[cfg(feature = "crust")]
+    let (store, source): (Box<dyn ObjectStore>, _) = (
+        Box::new(CrustStore {
+            bandwidth: Bandwidth::from_config(config),
+            uploads: scheduler::shared(config),
+        }),
+        Source::Gateway,
+    );
+
+    let max_size = match config.cache_max_size {
+        Some(max_size) => max_size,
+        None => return Ok(store),
+    };
+
+    match ObjectCache::open(max_size) {
+        Ok(cache) => Ok(Box::new(CachedStore {
+            inner: store,
+            cache,
+            source,
+        })),
+        Err(e) => {
+            log::debug!("Going without the object cache: {}", e);
+            Ok(store)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::HashMap,
+    };
+
+    /// Keeps data in memory under made-up CIDs.
+    #[derive(Default)]
+    pub struct MemoryStore {
+        objects: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MemoryStore {
+        pub fn insert(&self, cid: &str, data: Vec<u8>) {
+            self.objects.borrow_mut().insert(cid.to_owned(), data);
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl ObjectStore for MemoryStore {
+        async fn put(
+            &self,
+            data: Vec<u8>,
+            _signer: &PairSigner<PolkadotConfig, Pair>,
+        ) -> BoxResult<String> {
+            let cid = format!("memory-{}", self.objects.borrow().len());
+            self.insert(&cid, data);
+
+            Ok(cid)
+        }
+
+        async fn get(&self, cid: &str) -> BoxResult<Vec<u8>> {
+            self.objects
+                .borrow()
+                .get(cid)
+                .cloned()
+                .ok_or_else(|| format!("No data under {}", cid).into())
+        }
+    }
+
+    /// A `MemoryStore` that counts the whole and ranged reads made through it.
+    #[derive(Default)]
+    pub struct CountingStore {
+        pub inner: MemoryStore,
+        pub gets: Cell<usize>,
+        pub ranges: Cell<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl ObjectStore for CountingStore {
+        async fn put(
+            &self,
+            data: Vec<u8>,
+            signer: &PairSigner<PolkadotConfig, Pair>,
+        ) -> BoxResult<String> {
+            self.inner.put(data, signer).await
+        }
+
+        async fn get(&self, cid: &str) -> BoxResult<Vec<u8>> {
+            self.gets.set(self.gets.get() + 1);
+            self.inner.get(cid).await
+        }
+
+        async fn get_range(&self, cid: &str, offset: usize, len: usize) -> BoxResult<Vec<u8>> {
+            self.ranges.set(self.ranges.get() + 1);
+            self.inner.get_range(cid, offset, len).await
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Capping the bandwidth object stores use, per direction and across concurrent transfers, and
+//! the rate of chain requests.
+
+use crate::{
+    output,
+    primitives::Config,
+    util::{human_size, parse_size},
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
+
+#[cfg(not(feature = "crust"))]
+use futures::{ready, AsyncRead, Future};
+#[cfg(not(feature = "crust"))]
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(not(feature = "crust"))]
+use tokio::time::Sleep;
+
+/// The most a throttled reader passes on at once, so transfers are paced smoothly.
+#[cfg(not(feature = "crust"))]
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chain requests per second allowed against public endpoints unless configured otherwise.
+const DEFAULT_CHAIN_RPS: u64 = 10;
+
+/// A token bucket holding at most one second's worth of bytes (or requests).
+///
+/// Transfers take tokens before moving data and may drive the bucket into debt, which every
+/// later transfer has to wait out. Concurrent transfers sharing a limiter therefore add up to
+/// the rate rather than each getting it.
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: u64,
+    bucket: Mutex<Bucket>,
+    /// Nanoseconds `acquire` has waited in total
+    waited: AtomicU64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+            waited: AtomicU64::new(0),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// How long `acquire` has waited so far, summed over all callers.
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.load(Ordering::Relaxed))
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait before moving them.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let rate = self.rate as f64;
+
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Wait until `bytes` may be moved.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+
+        if !wait.is_zero() {
+            self.waited
+                .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+            sleep(wait).await;
+        }
+    }
+}
+
+/// The limiters of an object store, shared by all of its transfers.
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    pub upload: Option<Arc<RateLimiter>>,
+    pub download: Option<Arc<RateLimiter>>,
+}
+
+impl Bandwidth {
+    pub fn from_config(config: &Config) -> Self {
+        let limiter = |rate: Option<u64>| rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+
+        Self {
+            upload: limiter(config.upload_rate_limit),
+            download: limiter(config.download_rate_limit),
+        }
+    }
+}
+
+/// The chain requests per second to allow at most, `None` for no limit.
+///
+/// `GIT_INV4_CHAIN_RPS` overrides `chain_rps`, where 0 means no limit. When neither is set,
+/// public endpoints get `DEFAULT_CHAIN_RPS` and local nodes no limit.
+pub fn chain_rps(config: &Config) -> Option<u64> {
+    let rps = std::env::var("GIT_INV4_CHAIN_RPS")
+        .ok()
+        .and_then(|rps| rps.trim().parse().ok())
+        .or(config.chain_rps)
+        .unwrap_or_else(|| match is_local_endpoint(&config.chain_endpoint) {
+            true => 0,
+            false => DEFAULT_CHAIN_RPS,
+        });
+
+    (rps > 0).then_some(rps)
+}
+
+/// Whether `endpoint` is a node on this machine, e.g. `ws://127.0.0.1:9944`.
+pub fn is_local_endpoint(endpoint: &str) -> bool {
+    let authority = endpoint
+        .split_once("://")
+        .map_or(endpoint, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => host == "localhost",
+    }
+}
+
+/// Show how fast a throttled transfer of `bytes` went, so the limit can be seen at work.
+pub fn report(action: &str, bytes: usize, started: Instant) {
+    let elapsed = started.elapsed().as_secs_f64().max(0.001);
+
+    output::status(format!(
+        "{} {} at {}/s",
+        action,
+        human_size(bytes),
+        human_size((bytes as f64 / elapsed) as usize)
+    ));
+}
+
+/// Reads from `inner` no faster than `limiter` allows.
+#[cfg(not(feature = "crust"))]
+pub struct ThrottledReader<R> {
+    inner: R,
+    limiter: Arc<RateLimiter>,
+    /// The wait for the bytes passed on last
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+#[cfg(not(feature = "crust"))]
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+#[cfg(not(feature = "crust"))]
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let len = buf.len().min(CHUNK_SIZE);
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len]))?;
+
+        let wait = self.limiter.reserve(read);
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// A rate limit in bytes per second, given as a number or a size like `"2MiB"`. Zero means no
+/// limit.
+pub fn deserialize_rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Size(String),
+    }
+
+    let rate = match Option::<Rate>::deserialize(deserializer)? {
+        Some(Rate::Bytes(bytes)) => bytes,
+        Some(Rate::Size(size)) => parse_size(size.trim_end_matches("/s"))
+            .map_err(|e| serde::de::Error::custom(e.to_string()))?,
+        None => 0,
+    };
+
+    Ok((rate > 0).then_some(rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Settings {
+        #[serde(default, deserialize_with = "deserialize_rate")]
+        rate: Option<u64>,
+    }
+
+    fn rate(toml: &str) -> Result<Option<u64>, toml::de::Error> {
+        toml::from_str::<Settings>(toml).map(|settings| settings.rate)
+    }
+
+    #[test]
+    fn rates_are_numbers_or_sizes() {
+        assert_eq!(rate("rate = 2048").unwrap(), Some(2048));
+        assert_eq!(rate("rate = \"2MiB\"").unwrap(), Some(2 << 20));
+        assert_eq!(rate("rate = \"500kB/s\"").unwrap(), Some(500_000));
+        assert_eq!(rate("rate = 0").unwrap(), None);
+        assert_eq!(rate("").unwrap(), None);
+        assert!(rate("rate = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn bucket_goes_into_debt_that_later_transfers_wait_out() {
+        let limiter = RateLimiter::new(1000);
+
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+
+        let wait = limiter.reserve(500);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "{:?}",
+            wait
+        );
+
+        let wait = limiter.reserve(500);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+    }
+
+    #[test]
+    fn local_nodes_are_told_apart_from_public_ones() {
+        assert!(is_local_endpoint("ws://127.0.0.1:9944"));
+        assert!(is_local_endpoint("ws://localhost:9944/"));
+        assert!(is_local_endpoint("ws://[::1]:9944"));
+        assert!(is_local_endpoint("localhost"));
+        assert!(!is_local_endpoint("wss://tinker.invarch.network:443"));
+        assert!(!is_local_endpoint("ws://127.example.com:9944"));
+    }
+
+    #[test]
+    fn only_public_endpoints_are_paced_by_default() {
+        let mut config = Config::default();
+        assert_eq!(chain_rps(&config), Some(DEFAULT_CHAIN_RPS));
+
+        config.chain_endpoint = "ws://127.0.0.1:9944".to_owned();
+        assert_eq!(chain_rps(&config), None);
+
+        config.chain_rps = Some(50);
+        assert_eq!(chain_rps(&config), Some(50));
+
+        config.chain_rps = Some(0);
+        assert_eq!(chain_rps(&config), None);
+    }
+}
@@ -0,0 +1,359 @@
+//! Recording what git and the helper say to each other, for bug reports, and replaying it.
+//!
+//! With `GIT_INV4_TRANSCRIPT=<path>`, each session appends to the file: a `# <ms> session` line,
+//! every line git sends as `> <line>`, everything the helper answers as `< <line>`, and
+//! `# <ms> <note>` lines for the calls to the chain and store and how long they took, `<ms>`
+//! counting from the start of the session. Only the protocol is recorded. It carries ref names
+//! and object ids; credentials are asked for on the terminal and never pass through it.
+//!
+//! `inv4-git replay-transcript` feeds what git sent back through the protocol handler, with the
+//! recorded answers standing in for the chain and store, and checks the helper answers the same.
+
+use crate::{
+    answers::Answers,
+    error,
+    primitives::BoxResult,
+    redact,
+    remote_helper::{
+        self, Backend, FetchReport, FetchRequest, PushError, PushOutcome, PushRequest, Updated,
+    },
+    util::unquote_c_style,
+};
+use async_trait::async_trait;
+use std::{
+    collections::VecDeque,
+    env,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::Instant,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+struct Recorder {
+    file: File,
+    started: Instant,
+    /// Answer bytes not ended by a newline yet
+    pending: Vec<u8>,
+}
+
+impl Recorder {
+    fn line(&mut self, prefix: &str, line: &str) {
+        // A transcript that can't be written is not worth failing the session over
+        let _ = writeln!(self.file, "{}{}", prefix, redact::text(line));
+    }
+}
+
+fn with_recorder(f: impl FnOnce(&mut Recorder)) {
+    if let Some(recorder) = RECORDER.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(recorder)
+    }
+}
+
+/// Start recording this session, when `GIT_INV4_TRANSCRIPT` names a file.
+pub fn start(remote_name: &str, url: &str) -> BoxResult<()> {
+    let path = match env::var_os("GIT_INV4_TRANSCRIPT") {
+        Some(path) if !path.is_empty() => path,
+        _ => return Ok(()),
+    };
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Could not open transcript {:?}: {}", path, e))?;
+
+    let mut recorder = Recorder {
+        file,
+        started: Instant::now(),
+        pending: vec![],
+    };
+    recorder.line("# 0 session ", &format!("{} {}", remote_name, url));
+
+    *RECORDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(recorder);
+
+    Ok(())
+}
+
+/// Record a line git sent.
+pub fn input(line: &str) {
+    with_recorder(|recorder| recorder.line("> ", line))
+}
+
+/// Record a note, such as how long a backend call took.
+pub fn note(note: impl AsRef<str>) {
+    with_recorder(|recorder| {
+        let ms = recorder.started.elapsed().as_millis();
+        recorder.line(&format!("# {} ", ms), note.as_ref())
+    })
+}
+
+/// Record answers to git, as `answers::Answers` sends them.
+pub fn output(bytes: &[u8]) {
+    with_recorder(|recorder| {
+        recorder.pending.extend_from_slice(bytes);
+
+        while let Some(end) = recorder.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = recorder.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).into_owned();
+            recorder.line("< ", &line);
+        }
+    })
+}
+
+/// One session of a transcript.
+#[derive(Debug, Default)]
+pub struct Recording {
+    /// The `<remote> <url>` the session was started with
+    pub session: String,
+    /// The lines git sent
+    pub input: Vec<String>,
+    /// The lines the helper answered
+    pub output: Vec<String>,
+    lists: VecDeque<Vec<(String, String)>>,
+    pushes: VecDeque<Vec<PushOutcome>>,
+}
+
+/// Read the sessions of a transcript.
+pub fn parse(text: &str) -> BoxResult<Vec<Recording>> {
+    let mut recordings: Vec<Recording> = vec![];
+    // The command whose answer is being read, and the lines of it so far
+    let mut answering: Option<(&str, Vec<&str>)> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        if let Some(rest) = line.strip_prefix("# ") {
+            if let Some((_, session)) = rest.split_once(" session ") {
+                recordings.push(Recording {
+                    session: session.to_owned(),
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+
+        let recording = recordings
+            .last_mut()
+            .ok_or(format!("Line {} comes before the first session", i + 1))?;
+
+        if let Some(line) = line.strip_prefix("> ").or(line.strip_prefix('>')) {
+            if line.starts_with("list") {
+                answering = Some(("list", vec![]));
+            } else if line.starts_with("push") {
+                answering = Some(("push", vec![]));
+            }
+            recording.input.push(line.to_owned());
+        } else if let Some(line) = line.strip_prefix("< ").or(line.strip_prefix('<')) {
+            recording.output.push(line.to_owned());
+
+            if let Some((command, lines)) = answering.as_mut() {
+                if !line.is_empty() {
+                    lines.push(line);
+                    continue;
+                }
+
+                match *command {
+                    "list" => recording.lists.push_back(parse_list(lines)?),
+                    _ => recording.pushes.push_back(parse_push(lines)?),
+                }
+                answering = None;
+            }
+        } else if !line.is_empty() {
+            error!(format!(
+                "Line {} of the transcript is not recognized",
+                i + 1
+            ))
+        }
+    }
+
+    Ok(recordings)
+}
+
+fn parse_list(lines: &[&str]) -> BoxResult<Vec<(String, String)>> {
+    lines
+        .iter()
+        .map(|line| match line.split_once(' ') {
+            Some((value, name)) => Ok((value.to_owned(), name.to_owned())),
+            None => Err(format!("Malformed list answer: {:?}", line).into()),
+        })
+        .collect()
+}
+
+fn parse_push(lines: &[&str]) -> BoxResult<Vec<PushOutcome>> {
+    lines
+        .iter()
+        .map(|line| {
+            if let Some(dst) = line.strip_prefix("ok ") {
+                return Ok(PushOutcome {
+                    dst: dst.to_owned(),
+                    result: Ok(Updated::default()),
+                });
+            }
+
+            match line
+                .strip_prefix("error ")
+                .and_then(|rest| rest.split_once(' '))
+            {
+                Some((dst, e)) => Ok(PushOutcome {
+                    dst: dst.to_owned(),
+                    result: Err(PushError::new(
+                        unquote_c_style(e).unwrap_or_else(|| e.to_owned()),
+                    )),
+                }),
+                None => Err(format!("Malformed push answer: {:?}", line).into()),
+            }
+        })
+        .collect()
+}
+
+/// Stands in for the chain and store during a replay, answering with what was recorded.
+#[async_trait(?Send)]
+impl Backend for Recording {
+    async fn list(&mut self, _for_push: bool) -> BoxResult<Vec<(String, String)>> {
+        Ok(self
+            .lists
+            .pop_front()
+            .ok_or("The transcript has no answer to this list")?)
+    }
+
+    async fn fetch_batch(&mut self, requests: &[FetchRequest]) -> BoxResult<Vec<FetchReport>> {
+        // git isn't told how each ref went, so there is nothing to go wrong
+        Ok(requests
+            .iter()
+            .map(|request| FetchReport {
+                name: request.name.clone(),
+                result: Ok(()),
+            })
+            .collect())
+    }
+
+    async fn push_batch(&mut self, requests: &[PushRequest]) -> BoxResult<Vec<PushOutcome>> {
+        let reports = self
+            .pushes
+            .pop_front()
+            .ok_or("The transcript has no answer to this push")?;
+
+        if reports.len() != requests.len() {
+            error!(format!(
+                "The transcript answers {} refs of a push of {}",
+                reports.len(),
+                requests.len()
+            ))
+        }
+
+        Ok(reports)
+    }
+}
+
+/// Replay `recording` through the protocol handler, returning the first line where the helper's
+/// answers differ from the recorded ones, if any.
+pub async fn replay(mut recording: Recording) -> BoxResult<Option<String>> {
+    let mut sent = recording.input.join("\n");
+    sent.push('\n');
+
+    let mut input = BufReader::new(sent.as_bytes()).lines();
+    let mut answers = vec![];
+
+    if let Some(first) = input.next_line().await? {
+        // Through `Answers`, so an answer git would have been left waiting for shows up
+        let mut out = Answers::new(&mut answers);
+        remote_helper::serve(&mut recording, first, &mut input, &mut out).await?;
+        if out.unflushed() > 0 {
+            error!(format!(
+                "The helper left {} bytes of its last answer unflushed",
+                out.unflushed()
+            ))
+        }
+    }
+
+    let answers = String::from_utf8(answers)?;
+    let answers: Vec<&str> = answers.lines().collect();
+
+    for i in 0..answers.len().max(recording.output.len()) {
+        let (expected, got) = (recording.output.get(i), answers.get(i));
+
+        if expected.map(String::as_str) != got.copied() {
+            return Ok(Some(format!(
+                "answer line {}: recorded {:?}, replayed {:?}",
+                i + 1,
+                expected,
+                got
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSCRIPT: &str = "\
+# 0 session origin inv4://1
+> capabilities
+< option
+< push
+< fetch
+< refspec refs/heads/*:refs/heads/*
+< refspec refs/tags/*:refs/tags/*
+< 
+> list for-push
+< 1111111111111111111111111111111111111111 refs/heads/main
+< @refs/heads/main HEAD
+< 
+# 15 list took 3ms
+> push refs/heads/main:refs/heads/main
+> 
+< ok refs/heads/main
+< 
+> 
+";
+
+    #[test]
+    fn sessions_are_read_with_their_answers() {
+        let recordings = parse(&format!("{}# 0 session other inv4://2\n", TRANSCRIPT)).unwrap();
+
+        assert_eq!(recordings.len(), 2);
+        assert_eq!(recordings[0].session, "origin inv4://1");
+        assert_eq!(recordings[0].input.len(), 5);
+        assert_eq!(recordings[0].output.len(), 11);
+        assert_eq!(
+            recordings[0].lists[0],
+            [
+                ("1".repeat(40), "refs/heads/main".to_owned()),
+                ("@refs/heads/main".to_owned(), "HEAD".to_owned()),
+            ]
+        );
+        assert!(recordings[1].input.is_empty());
+
+        assert!(parse("> list\n").is_err());
+        assert!(parse("# 0 session origin inv4://1\nlist\n").is_err());
+    }
+
+    #[test]
+    fn push_errors_are_unquoted() {
+        let outcomes = parse_push(&[r#"error refs/heads/main "rejected:\tstale""#]).unwrap();
+
+        assert_eq!(outcomes[0].dst, "refs/heads/main");
+        assert_eq!(
+            outcomes[0].result.as_ref().unwrap_err().to_string(),
+            "rejected:\tstale"
+        );
+        assert!(parse_push(&["maybe refs/heads/main"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_reproduces_the_recorded_answers() {
+        let recording = parse(TRANSCRIPT).unwrap().remove(0);
+        assert_eq!(replay(recording).await.unwrap(), None);
+
+        let mut recording = parse(TRANSCRIPT).unwrap().remove(0);
+        recording.output[0] = "options".to_owned();
+        assert_eq!(
+            replay(recording).await.unwrap().as_deref(),
+            Some("answer line 1: recorded Some(\"options\"), replayed Some(\"option\")")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Limits on walking object graphs, so malformed repository data can't make a walk endless.
+//!
+//! A fetch follows the references recorded in MultiObject headers before it has the objects to
+//! check them against. A corrupt or crafted header can make commits each other's parents, or
+//! chain up fabricated objects without end, each one costing a chain lookup and a download.
+//!
+//! Push and fetch enumeration, verifying a fetch and exporting an archive all walk through a
+//! `Walk`, which fails on a cycle, naming the objects in it, and on exceeding the `Limits` from
+//! the config: how many objects one walk expands, how deep it goes and how long it takes.
+
+use crate::{
+    error,
+    primitives::{BoxResult, Config},
+};
+use git2::Oid;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+/// Most objects one walk expands unless `traversal_max_objects` says otherwise; several times
+/// the largest public repositories.
+pub const DEFAULT_MAX_OBJECTS: usize = 50_000_000;
+
+/// Deepest one walk goes unless `traversal_max_depth` says otherwise. A walk follows the
+/// history depth-first, so this bounds the commits in a single line of history.
+pub const DEFAULT_MAX_DEPTH: usize = 10_000_000;
+
+/// Longest one walk runs unless `traversal_budget_secs` says otherwise.
+pub const DEFAULT_BUDGET_SECS: u64 = 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_objects: usize,
+    pub max_depth: usize,
+    pub budget: Duration,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_objects: config.traversal_max_objects,
+            max_depth: config.traversal_max_depth,
+            budget: Duration::from_secs(config.traversal_budget_secs),
+        }
+    }
+}
+
+/// A depth-first walk over objects carrying `T` for each, checked against `Limits`.
+///
+/// `next` hands out what's left to visit. Visiting an object's references starts with `expand`,
+/// after which every reference `push`ed counts as the object's, until the walk is back out of
+/// them. The objects being expanded form the walk's path, and a reference to one of them is a
+/// cycle.
+pub struct Walk<T> {
+    /// `None` marks where the walk leaves the object expanded before the items above it
+    stack: Vec<Option<T>>,
+    path: Vec<Oid>,
+    on_path: HashSet<Oid>,
+    expanded: usize,
+    started: Instant,
+    limits: Limits,
+    /// What the walk is for, e.g. "fetch", for the errors
+    purpose: &'static str,
+}
+
+impl<T> Walk<T> {
+    pub fn new(limits: Limits, purpose: &'static str) -> Self {
+        Self {
+            stack: vec![],
+            path: vec![],
+            on_path: HashSet::new(),
+            expanded: 0,
+            started: Instant::now(),
+            limits,
+            purpose,
+        }
+    }
+
+    /// The next item to visit, `None` once there are none left.
+    pub fn next(&mut self) -> Option<T> {
+        loop {
+            match self.stack.pop()? {
+                Some(item) => return Some(item),
+                None => {
+                    if let Some(left) = self.path.pop() {
+                        self.on_path.remove(&left);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queue `item`, for the object `oid` that the object last expanded references.
+    pub fn push(&mut self, oid: Oid, item: T) -> BoxResult<()> {
+        self.check_cycle(oid)?;
+        self.stack.push(Some(item));
+        Ok(())
+    }
+
+    /// Start on the references of `oid`, failing when that goes past the limits.
+    pub fn expand(&mut self, oid: Oid) -> BoxResult<()> {
+        self.check_cycle(oid)?;
+
+        self.expanded += 1;
+        if self.expanded > self.limits.max_objects {
+            error!(format!(
+                "Repository exceeds traversal limits: the {} walks more than {} objects; raise traversal_max_objects if intentional",
+                self.purpose, self.limits.max_objects
+            ))
+        }
+        if self.path.len() >= self.limits.max_depth {
+            error!(format!(
+                "Repository exceeds traversal limits: the {} goes more than {} objects deep; raise traversal_max_depth if intentional",
+                self.purpose, self.limits.max_depth
+            ))
+        }
+        if self.started.elapsed() > self.limits.budget {
+            error!(format!(
+                "Repository exceeds traversal limits: the {} took longer than {}s to walk; raise traversal_budget_secs if intentional",
+                self.purpose,
+                self.limits.budget.as_secs()
+            ))
+        }
+
+        self.stack.push(None);
+        self.path.push(oid);
+        self.on_path.insert(oid);
+        Ok(())
+    }
+
+    fn check_cycle(&self, oid: Oid) -> BoxResult<()> {
+        if !self.on_path.contains(&oid) {
+            return Ok(());
+        }
+
+        let start = self.path.iter().rposition(|&member| member == oid);
+        let members: Vec<_> = self.path[start.unwrap_or_default()..]
+            .iter()
+            .chain(std::iter::once(&oid))
+            .map(|member| member.to_string())
+            .collect();
+
+        error!(format!(
+            "The repository data is malformed: its objects reference each other in a cycle, {}",
+            members.join(" -> ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(byte: u8) -> Oid {
+        Oid::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            max_objects: 100,
+            max_depth: 100,
+            budget: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn references_back_into_the_path_are_cycles_naming_their_objects() {
+        let mut walk = Walk::new(limits(), "fetch");
+        walk.push(oid(1), ()).unwrap();
+        walk.next();
+        walk.expand(oid(1)).unwrap();
+        walk.push(oid(2), ()).unwrap();
+        walk.next();
+        walk.expand(oid(2)).unwrap();
+
+        let e = walk.push(oid(1), ()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "The repository data is malformed: its objects reference each other in a cycle, {} -> {} -> {}",
+                oid(1),
+                oid(2),
+                oid(1)
+            )
+        );
+    }
+
+    #[test]
+    fn objects_the_walk_left_are_no_cycle() {
+        let mut walk = Walk::new(limits(), "fetch");
+        walk.push(oid(1), ()).unwrap();
+        walk.next();
+        walk.expand(oid(1)).unwrap();
+        walk.push(oid(2), ()).unwrap();
+        walk.push(oid(3), ()).unwrap();
+
+        // Out of 3 and into 2, which references 3 again
+        walk.next();
+        walk.expand(oid(3)).unwrap();
+        walk.next();
+        walk.expand(oid(2)).unwrap();
+        assert!(walk.push(oid(3), ()).is_ok());
+    }
+
+    #[test]
+    fn walks_past_their_budget_fail() {
+        let mut walk: Walk<()> = Walk::new(
+            Limits {
+                budget: Duration::ZERO,
+                ..limits()
+            },
+            "push",
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        let e = walk.expand(oid(1)).unwrap_err().to_string();
+        assert!(e.contains("the push took longer than 0s"), "{}", e);
+    }
+}
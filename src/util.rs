@@ -1,4 +1,6 @@
-use cid::{multihash::MultihashGeneric, CidGeneric};
+use cid::{multihash::MultihashGeneric, Cid, CidGeneric};
+use git2::{ErrorCode, Oid, Repository};
+use std::{env, fmt::Write as _, path::PathBuf};
 use subxt::ext::sp_core::H256;
 
 use crate::primitives::BoxResult;
@@ -10,8 +12,467 @@ macro_rules! error {
     }};
 }
 
-pub fn generate_cid(hash: H256) -> BoxResult<CidGeneric<32>> {
-    Ok(CidGeneric::new_v0(MultihashGeneric::<32>::from_bytes(
-        hex::decode(format!("{:?}", hash).replace("0x", "1220"))?.as_slice(),
-    )?)?)
+/// The first file named `binary` in a `PATH` directory.
+pub fn find_on_path(binary: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(binary))
+        .find(|path| path.is_file())
+}
+
+/// `bytes` in the largest binary unit that keeps the number at least 1, e.g. `1.50 MiB`.
+pub fn human_size(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.2} {}", size, units[unit]),
+    }
+}
+
+/// Bytes in a size like `2MiB`, `1.5 GB` or `512`, case-insensitively. Binary units are powers
+/// of 1024 and decimal ones powers of 1000.
+pub fn parse_size(size: &str) -> BoxResult<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        _ => error!(format!("Unknown unit in size {:?}", size)),
+    };
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size {:?}", size))?;
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// `text` in double quotes, the way git's `unquote_c_style` reads it back, e.g. for the message of
+/// a remote helper's `error` line. `"`, `\` and control characters are backslash-escaped, so a
+/// message can't end the line early; anything else, UTF-8 included, is kept as it is.
+pub fn quote_c_style(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => {
+                let _ = write!(quoted, "\\{:03o}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// The text `quote_c_style` quoted, or `None` when `quoted` isn't a whole quoted string.
+pub fn unquote_c_style(quoted: &str) -> Option<String> {
+    let inner = quoted.strip_prefix('"')?.strip_suffix('"')?.as_bytes();
+    let mut text = Vec::with_capacity(inner.len());
+    let mut bytes = inner.iter().copied();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            text.push(byte);
+            continue;
+        }
+
+        text.push(match bytes.next()? {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            digit @ b'0'..=b'3' => {
+                let (second, third) = (bytes.next()?, bytes.next()?);
+                if !(b'0'..=b'7').contains(&second) || !(b'0'..=b'7').contains(&third) {
+                    return None;
+                }
+                (digit - b'0') << 6 | (second - b'0') << 3 | (third - b'0')
+            }
+            other => other,
+        });
+    }
+
+    String::from_utf8(text).ok()
+}
+
+/// Multicodec codes of the only content addresses an on-chain H256 can hold.
+const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+
+/// The CIDv0 of the content whose sha2-256 digest is stored on-chain as `hash`.
+pub fn generate_cid(hash: H256) -> Result<CidGeneric<32>, cid::Error> {
+    CidGeneric::new_v0(MultihashGeneric::wrap(SHA2_256, hash.as_bytes())?)
+}
+
+/// The digest to record on-chain for a CID returned by the object store.
+///
+/// Only dag-pb/sha2-256 content fits in an H256. A CIDv1 of that shape is accepted because it
+/// names the same content as the CIDv0 that `generate_cid` reconstructs.
+pub fn cid_digest(cid: &str) -> BoxResult<H256> {
+    let cid = Cid::try_from(cid)?;
+    let hash = cid.hash();
+
+    if cid.codec() != DAG_PB || hash.code() != SHA2_256 || hash.digest().len() != 32 {
+        error!(format!(
+            "The object store returned CID {} (codec 0x{:x}, hash 0x{:x}), which can't be recorded on-chain; configure your IPFS node to add content as CIDv0 with sha2-256 (e.g. `ipfs add --cid-version 0`)",
+            cid,
+            cid.codec(),
+            hash.code()
+        ))
+    }
+
+    Ok(H256::from_slice(hash.digest()))
+}
+
+/// Whether moving a ref from `old` to `new` is a fast-forward, using only local objects.
+///
+/// Both objects must be present in `repo`. Anything that doesn't peel to a commit (e.g. a tag
+/// being re-pointed) only counts as a fast-forward if nothing changed.
+pub fn is_fast_forward(repo: &Repository, old: Oid, new: Oid) -> BoxResult<bool> {
+    if old == new {
+        return Ok(true);
+    }
+
+    let old_commit = repo.find_object(old, None)?.peel_to_commit();
+    let new_commit = repo.find_object(new, None)?.peel_to_commit();
+
+    Ok(match (old_commit, new_commit) {
+        (Ok(old_commit), Ok(new_commit)) => {
+            old_commit.id() == new_commit.id()
+                || repo.graph_descendant_of(new_commit.id(), old_commit.id())?
+        }
+        _ => false,
+    })
+}
+
+/// Whether `oid` is one of `tips` or an ancestor of one, using only local objects.
+pub fn reachable_from_any(repo: &Repository, oid: Oid, tips: &[Oid]) -> bool {
+    tips.iter()
+        .any(|&tip| tip == oid || matches!(repo.graph_descendant_of(tip, oid), Ok(true)))
+}
+
+/// The commits at the edge of a shallow clone, whose parents were not fetched.
+pub fn shallow_boundary(repo: &Repository) -> BoxResult<Vec<Oid>> {
+    if !repo.is_shallow() {
+        return Ok(vec![]);
+    }
+
+    std::fs::read_to_string(repo.path().join("shallow"))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(line.trim().parse()?))
+        .collect()
+}
+
+/// What the source side of a push refspec points at, resolved the way git does: a full ref name,
+/// `HEAD` whether detached or not, an abbreviated branch or tag name, or an oid.
+pub fn resolve_push_source(repo: &Repository, src: &str) -> BoxResult<Oid> {
+    let found = match repo.find_reference(src) {
+        Ok(reference) => reference.resolve().map(|resolved| resolved.target()),
+        Err(_) => repo.revparse_single(src).map(|object| Some(object.id())),
+    };
+
+    match found {
+        Ok(Some(oid)) => Ok(oid),
+        Ok(None) => error!(format!("{} did not resolve to an object", src)),
+        Err(_) if is_unborn(repo, src) => {
+            error!("nothing to push: current branch has no commits")
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => error!(format!(
+            "src refspec {} does not match any ref or object",
+            src
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether `src` names `HEAD` or the branch it is on, and that branch has no commits yet.
+fn is_unborn(repo: &Repository, src: &str) -> bool {
+    let branch = match repo.find_reference("HEAD") {
+        Ok(head) => match head.symbolic_target() {
+            Some(branch) => branch.to_owned(),
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+
+    let names_head =
+        src == "HEAD" || src == branch || branch.strip_prefix("refs/heads/") == Some(src);
+
+    names_head && matches!(repo.head(), Err(e) if e.code() == ErrorCode::UnbornBranch)
+}
+
+/// Binary CIDs as their string form in serde formats, for `#[serde(with = "...")]`.
+pub mod cid_string {
+    use cid::Cid;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(cid: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let cid = Cid::try_from(cid).map_err(ser::Error::custom)?;
+        serializer.serialize_str(&cid.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let cid = String::deserialize(deserializer)?;
+        Ok(Cid::from_str(&cid).map_err(de::Error::custom)?.to_bytes())
+    }
+}
+
+/// Maps whose values are binary CIDs, with the values as in `cid_string`.
+pub mod cid_string_map {
+    use cid::Cid;
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+    use std::{collections::BTreeMap, str::FromStr};
+
+    pub fn serialize<S: Serializer>(
+        map: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        map.iter()
+            .map(|(key, cid)| Ok((key, Cid::try_from(&cid[..])?.to_string())))
+            .collect::<Result<BTreeMap<_, _>, cid::Error>>()
+            .map_err(ser::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, cid)| Ok((key, Cid::from_str(&cid)?.to_bytes())))
+            .collect::<Result<_, cid::Error>>()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use git2::Signature;
+    use temp_dir::TempDir;
+
+    /// A repository in a temporary directory, which goes away with the `TempDir`.
+    pub fn repository() -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+
+        (dir, repo)
+    }
+
+    /// A commit of an empty tree on top of `parents`, updating `update_ref` if given.
+    pub fn commit(
+        repo: &Repository,
+        update_ref: Option<&str>,
+        parents: &[Oid],
+        message: &str,
+    ) -> Oid {
+        let tree = repo.treebuilder(None).unwrap().write().unwrap();
+        commit_tree(repo, update_ref, parents, tree, message)
+    }
+
+    /// A commit of `tree` on top of `parents`, updating `update_ref` if given.
+    pub fn commit_tree(
+        repo: &Repository,
+        update_ref: Option<&str>,
+        parents: &[Oid],
+        tree: Oid,
+        message: &str,
+    ) -> Oid {
+        let tree = repo.find_tree(tree).unwrap();
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|&parent| repo.find_commit(parent).unwrap())
+            .collect();
+
+        repo.commit(
+            update_ref,
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn c_style_quoting_round_trips() {
+        for text in [
+            "plain",
+            "tab\tand \"quotes\"",
+            "back\\slash\nnewline",
+            "bell\u{7}",
+            "ünïcode ✓",
+        ] {
+            assert_eq!(unquote_c_style(&quote_c_style(text)).as_deref(), Some(text));
+        }
+
+        assert_eq!(quote_c_style("a\"b\\c\u{1}"), r#""a\"b\\c\001""#);
+        // git's own escapes and octal-escaped UTF-8 bytes
+        assert_eq!(
+            unquote_c_style(r#""\303\274\r\v""#).as_deref(),
+            Some("ü\r\u{b}")
+        );
+        assert_eq!(unquote_c_style("unquoted"), None);
+        assert_eq!(unquote_c_style(r#""dangling\""#), None);
+        assert_eq!(unquote_c_style(r#""\38x""#), None);
+    }
+
+    #[test]
+    fn fast_forward_is_decided_from_local_history() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let ahead = commit(&repo, None, &[base], "ahead");
+        let diverged = commit(&repo, None, &[base], "diverged");
+
+        assert!(is_fast_forward(&repo, base, ahead).unwrap());
+        assert!(is_fast_forward(&repo, ahead, ahead).unwrap());
+        assert!(!is_fast_forward(&repo, ahead, base).unwrap());
+        assert!(!is_fast_forward(&repo, ahead, diverged).unwrap());
+    }
+
+    #[test]
+    fn fast_forward_of_non_commits_needs_no_change() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let blob = repo.blob(b"not a commit").unwrap();
+
+        assert!(is_fast_forward(&repo, blob, blob).unwrap());
+        assert!(!is_fast_forward(&repo, base, blob).unwrap());
+        assert!(!is_fast_forward(&repo, blob, base).unwrap());
+    }
+
+    #[test]
+    fn shallow_boundary_lists_the_grafted_commits() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let tip = commit(&repo, Some("HEAD"), &[base], "tip");
+        assert!(shallow_boundary(&repo).unwrap().is_empty());
+
+        std::fs::write(repo.path().join("shallow"), format!("{}\n", tip)).unwrap();
+        assert_eq!(shallow_boundary(&repo).unwrap(), [tip]);
+    }
+
+    #[test]
+    fn on_chain_digest_round_trips_through_a_cid() {
+        let digest = H256::repeat_byte(0xab);
+        let cid = generate_cid(digest).unwrap();
+
+        assert!(cid.to_string().starts_with("Qm"));
+        assert_eq!(cid_digest(&cid.to_string()).unwrap(), digest);
+
+        // The CIDv1 of the same content names the same digest
+        let v1 = CidGeneric::new_v1(DAG_PB, *cid.hash());
+        assert_eq!(cid_digest(&v1.to_string()).unwrap(), digest);
+    }
+
+    #[test]
+    fn cids_that_dont_fit_on_chain_are_rejected() {
+        let cid = generate_cid(H256::repeat_byte(0xab)).unwrap();
+        let raw = CidGeneric::new_v1(0x55, *cid.hash());
+
+        assert!(cid_digest(&raw.to_string()).is_err());
+        assert!(cid_digest("not a cid").is_err());
+    }
+
+    #[test]
+    fn sizes_are_shown_in_binary_units() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.50 KiB");
+        assert_eq!(human_size(3 << 20), "3.00 MiB");
+        assert_eq!(human_size(5 << 40), "5120.00 GiB");
+    }
+
+    #[test]
+    fn reachability_from_known_tips() {
+        let (_dir, repo) = repository();
+        let base = commit(&repo, None, &[], "base");
+        let tip = commit(&repo, None, &[base], "tip");
+        let elsewhere = commit(&repo, None, &[base], "elsewhere");
+
+        assert!(reachable_from_any(&repo, base, &[tip]));
+        assert!(reachable_from_any(&repo, tip, &[elsewhere, tip]));
+        assert!(!reachable_from_any(&repo, tip, &[elsewhere]));
+        assert!(!reachable_from_any(&repo, base, &[]));
+    }
+
+    #[test]
+    fn sizes_are_parsed_in_binary_and_decimal_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("2MiB").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1.5 GB").unwrap(), 1_500_000_000);
+        assert_eq!(parse_size(" 4k ").unwrap(), 4096);
+        assert!(parse_size("4 parsecs").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+
+    #[test]
+    fn push_sources_resolve_like_git() {
+        let (_dir, repo) = repository();
+        let e = resolve_push_source(&repo, "HEAD").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "nothing to push: current branch has no commits"
+        );
+
+        // The default branch name depends on the git config of the machine
+        let branch = repo
+            .find_reference("HEAD")
+            .unwrap()
+            .symbolic_target()
+            .unwrap()
+            .to_owned();
+        let tip = commit(&repo, Some(&branch), &[], "tip");
+        repo.tag_lightweight("v1", &repo.find_object(tip, None).unwrap(), false)
+            .unwrap();
+
+        for src in [
+            "HEAD",
+            branch.as_str(),
+            branch.trim_start_matches("refs/heads/"),
+            "v1",
+            &tip.to_string()[..7],
+        ] {
+            assert_eq!(resolve_push_source(&repo, src).unwrap(), tip, "{}", src);
+        }
+
+        repo.set_head_detached(tip).unwrap();
+        assert_eq!(resolve_push_source(&repo, "HEAD").unwrap(), tip);
+
+        let e = resolve_push_source(&repo, "nope").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "src refspec nope does not match any ref or object"
+        );
+    }
 }